@@ -413,6 +413,7 @@ pub fn metrics(c: &mut Criterion) {
                 destination: None,
                 destination_service: None,
                 connection_security_policy: Default::default(),
+                connection_id: proxy::ConnectionId::next(),
             };
             let tl = proxy::CommonTrafficLabels::from(co);
             metrics.connection_opens.get_or_create(&tl).inc();
@@ -12,9 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::config::Config;
+use crate::config::{Config, ConfigReloader, ConfigSources};
 use crate::hyper_util::{empty_response, plaintext_response, Server};
-use crate::identity::SecretManager;
+use crate::identity::{Identity, SecretManager};
+use crate::proxy::connection_manager::{ConnectionManager, DrainSelector};
+use crate::state::workload::{NetworkAddress, Workload};
 use crate::state::DemandProxyState;
 use crate::tls::Certificate;
 use crate::version::BuildInfo;
@@ -30,10 +32,11 @@ use pprof::protos::Message;
 use std::borrow::Borrow;
 use std::collections::HashMap;
 
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use std::time::SystemTime;
-use std::{net::SocketAddr, time::Duration};
 
 use crate::drain::DrainWatcher;
 use tokio::time;
@@ -60,12 +63,25 @@ pub trait AdminHandler2: Sync + Send {
     fn handle(&self) -> anyhow::Result<serde_json::Value>;
 }
 
+/// ConnectionManagers lists the connection managers of the running proxies, so their connections can be drained.
+pub trait ConnectionManagers: Sync + Send {
+    fn connection_managers(&self) -> Vec<ConnectionManager>;
+}
+
+impl ConnectionManagers for ConnectionManager {
+    fn connection_managers(&self) -> Vec<ConnectionManager> {
+        vec![self.clone()]
+    }
+}
+
 struct State {
     proxy_state: DemandProxyState,
     config: Arc<Config>,
+    config_reloader: ConfigReloader,
     shutdown_trigger: signal::ShutdownTrigger,
     cert_manager: Arc<SecretManager>,
     handlers: Vec<Arc<dyn AdminHandler2>>,
+    connection_managers: Vec<Arc<dyn ConnectionManagers>>,
 }
 
 pub struct Service {
@@ -104,6 +120,7 @@ pub struct CertsDump {
 impl Service {
     pub async fn new(
         config: Arc<Config>,
+        config_reloader: ConfigReloader,
         proxy_state: DemandProxyState,
         shutdown_trigger: signal::ShutdownTrigger,
         drain_rx: DrainWatcher,
@@ -115,10 +132,12 @@ impl Service {
             drain_rx,
             State {
                 config,
+                config_reloader,
                 proxy_state,
                 shutdown_trigger,
                 cert_manager,
                 handlers: vec![],
+                connection_managers: vec![],
             },
         )
         .await
@@ -133,6 +152,10 @@ impl Service {
         self.s.state_mut().handlers.push(handler);
     }
 
+    pub fn add_connection_managers(&mut self, managers: Arc<dyn ConnectionManagers>) {
+        self.s.state_mut().connection_managers.push(managers);
+    }
+
     pub fn spawn(self) {
         self.s.spawn(|state, req| async move {
             match req.uri().path() {
@@ -151,13 +174,25 @@ impl Service {
                             proxy_state: state.proxy_state.clone(),
                             static_config: Default::default(),
                             version: BuildInfo::new(),
-                            config: state.config.clone(),
+                            config: state.config_reloader.current(),
                             certificates: dump_certs(state.cert_manager.borrow()).await,
                         },
                     )
                     .await
                 }
+                "/debug/config" => handle_effective_config(&state.config_reloader.current()),
+                "/debug/effective_route" => Ok(handle_effective_route(
+                    &state.proxy_state,
+                    &state.config_reloader.current(),
+                    req,
+                )
+                .await),
                 "/logging" => Ok(handle_logging(req).await),
+                "/logging/connections" => Ok(handle_connection_logging(req)),
+                "/drain_connections" => {
+                    Ok(handle_drain_connections(&state.connection_managers, req))
+                }
+                "/reload" => Ok(handle_reload(&state.config_reloader, req)),
                 "/" => Ok(handle_dashboard(req).await),
                 _ => Ok(empty_response(hyper::StatusCode::NOT_FOUND)),
             }
@@ -175,9 +210,29 @@ async fn handle_dashboard(_req: Request<Incoming>) -> Response<Full<Bytes>> {
             "debug/pprof/heap",
             "collect heap profiling data (if supported, requires jmalloc)",
         ),
+        (
+            "debug/config",
+            "the configuration in effect, including reloads, and where it came from",
+        ),
+        (
+            "debug/effective_route",
+            "dry run the route and identities used from a source to a destination",
+        ),
         ("quitquitquit", "shut down the server"),
         ("config_dump", "dump the current Ztunnel configuration"),
         ("logging", "query/changing logging levels"),
+        (
+            "logging/connections",
+            "query/change the connections logged verbosely",
+        ),
+        (
+            "drain_connections",
+            "gracefully close the connections matching a selector",
+        ),
+        (
+            "reload",
+            "reload the configuration from CONFIG_OVERRIDES_PATH, applying to new connections",
+        ),
     ];
 
     let mut api_rows = String::new();
@@ -283,6 +338,19 @@ async fn handle_server_shutdown(
     }
 }
 
+fn handle_reload(reloader: &ConfigReloader, req: Request<Incoming>) -> Response<Full<Bytes>> {
+    match *req.method() {
+        hyper::Method::POST => match reloader.reload() {
+            Ok(_) => plaintext_response(hyper::StatusCode::OK, "configuration reloaded\n".into()),
+            Err(e) => plaintext_response(
+                hyper::StatusCode::BAD_REQUEST,
+                format!("failed to reload configuration: {e}\n"),
+            ),
+        },
+        _ => empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
 async fn handle_config_dump(
     handlers: &[Arc<dyn AdminHandler2>],
     mut dump: ConfigDump,
@@ -306,6 +374,9 @@ async fn handle_config_dump(
     let serde_json::Value::Object(mut kv) = serde_json::to_value(&dump)? else {
         anyhow::bail!("config dump is not a key-value pair")
     };
+    if let Some(config) = kv.get_mut("config") {
+        redact_config(config);
+    }
 
     for h in handlers {
         let x = h.handle()?;
@@ -318,6 +389,275 @@ async fn handle_config_dump(
         .expect("builder with known status code should not fail"))
 }
 
+// Config fields that are reported by /config_dump and /debug/config. Every other field is redacted, so a new
+// field stays hidden until it is known not to hold secrets and added here. A nested field is listed by its path,
+// such as forwardProxy.authority, which exposes only that part of the field.
+const EXPOSED_CONFIG_FIELDS: &[&str] = &[
+    "proxy",
+    "dnsProxy",
+    "windowSize",
+    "connectionWindowSize",
+    "frameSize",
+    "hboneMaxHeaderSize",
+    "hboneDenialReason",
+    "accessLogRbacDecision",
+    "hpackTableSize",
+    "hboneHeaderMetrics",
+    "enforceGrpcTimeout",
+    "connectionMetadataHeaders",
+    "maxProxyHops",
+    "poolMaxStreamsPerConn",
+    "poolUnusedReleaseTimeout",
+    "poolBypassDestinations",
+    "poolH2KeepaliveInterval",
+    "poolH2KeepaliveTimeout",
+    "poolProfiles",
+    "poolMinConnectionsPerDestination",
+    "poolMinConnectionsIdleTimeout",
+    "connectionTimeout",
+    "namespaceConnectionTimeouts",
+    "egressSniAllowlist",
+    "egressTlsOrigination",
+    "staticHosts",
+    "bypassCidrs",
+    "warmDestinations",
+    "warmConnectionsPerDestination",
+    "ipFamilyPreferences",
+    "preferSameNode",
+    "tunnelOverrides",
+    "socks5Addr",
+    "socks5Listeners",
+    "enableHboneUdp",
+    "adminAddr",
+    "statsAddr",
+    "readinessAddr",
+    "inboundAddr",
+    "inboundExtraAddrs",
+    "inboundPlaintextAddr",
+    "outboundAddr",
+    "dnsProxyAddr",
+    "illegalPorts",
+    "network",
+    "localNode",
+    "proxyMode",
+    "localIp",
+    "clusterId",
+    "clusterDomain",
+    "caAddress",
+    "caRootCert",
+    "xdsAddress",
+    "xdsRootCert",
+    "secretTtl",
+    "xdsOnDemand",
+    "xdsMaxReconnectBackoff",
+    "fakeCa",
+    "fakeSelfInbound",
+    "configOverridesPath",
+    "selfTerminationDeadline",
+    "numWorkerThreads",
+    "requireOriginalSource",
+    "egressInterface",
+    "tcpFastOpen",
+    "outboundPortReuse",
+    "tcpMaxSegmentSize",
+    "pmtuDiscovery",
+    "hboneStallCheckInterval",
+    "connectionCorrelationMarkMask",
+    "forceFullClose",
+    "rejectionCloseMode",
+    "loopbackPassthrough",
+    "requireHboneInbound",
+    "doubleConnectionPolicy",
+    "stateUnavailablePolicy",
+    "passthroughHttpSniffing",
+    "passthroughSniffTimeout",
+    "forwardProxy.authority",
+    "maxConcurrentConnects",
+    "maxConcurrentConnectsPerDestination",
+    "maxConcurrentPerDestinationService",
+    "maxTotalConnections",
+    "reservedConnections",
+    "reservedConnectionSources",
+    "reservedConnectionPorts",
+    "policyChangeGrace",
+    "maxConnectionLifetime",
+    "waitForEndpointsTimeout",
+    "unknownSourcePolicy",
+    "selfConnectMode",
+    "originalSourceCidrs",
+    "sourceIpSelection",
+    "sourceIpSubnetPrefixes",
+    "startupConnectionPolicy",
+    "startupHoldTimeout",
+    "connectAuthorityResolution",
+    "connectAuthorityIpFamily",
+    "traceSamplingPercentage",
+    "identityLogMode",
+    "peerIdentitySanPolicy",
+    "tlsResumption",
+    "metricsNodeLabels",
+    "metricsTrafficScopeLabel",
+    "metricsDestinationWorkloadUidLabel",
+    "metricsCostAttribution",
+    "recordConnectionsDir",
+    "recordConnectionsMaxBytes",
+    "bandwidthLimits",
+    "dnsResolverCfg",
+    "dnsResolverOpts",
+    "inpodUds",
+    "inpodPortReuse",
+    "inpodMark",
+];
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EffectiveConfig<'a> {
+    config: serde_json::Value,
+    sources: &'a ConfigSources,
+}
+
+// handle_effective_config reports the configuration currently in effect, which reflects any reloads, with
+// sensitive fields redacted.
+fn handle_effective_config(cfg: &Config) -> anyhow::Result<Response<Full<Bytes>>> {
+    let mut config = serde_json::to_value(cfg)?;
+    redact_config(&mut config);
+    let body = serde_json::to_string_pretty(&EffectiveConfig {
+        config,
+        sources: &cfg.sources,
+    })?;
+    Ok(Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.into())
+        .expect("builder with known status code should not fail"))
+}
+
+// redact_config redacts the fields of a serialized config that are not in EXPOSED_CONFIG_FIELDS. Unset fields are
+// left as they are.
+fn redact_config(config: &mut serde_json::Value) {
+    redact_fields(config, "");
+}
+
+fn redact_fields(v: &mut serde_json::Value, path: &str) {
+    let serde_json::Value::Object(fields) = v else {
+        return;
+    };
+    for (name, value) in fields.iter_mut() {
+        let path = if path.is_empty() {
+            name.clone()
+        } else {
+            format!("{path}.{name}")
+        };
+        if value.is_null() || EXPOSED_CONFIG_FIELDS.contains(&path.as_str()) {
+            continue;
+        }
+        let exposes_nested = EXPOSED_CONFIG_FIELDS.iter().any(|f| {
+            f.strip_prefix(path.as_str())
+                .is_some_and(|rest| rest.starts_with('.'))
+        });
+        if exposes_nested && value.is_object() {
+            redact_fields(value, &path);
+        } else {
+            *value = serde_json::Value::String("<redacted>".to_string());
+        }
+    }
+}
+
+// handle_effective_route reports how a connection from a source workload to a destination would be sent, without
+// opening it. The source is a workload IP or UID. The destination is an ip:port, or a workload UID along with a
+// port, for example: /debug/effective_route?source=10.0.0.1&destination=10.0.0.2:8080
+async fn handle_effective_route(
+    proxy_state: &DemandProxyState,
+    cfg: &Config,
+    req: Request<Incoming>,
+) -> Response<Full<Bytes>> {
+    let qp = query_params(&req);
+    effective_route(proxy_state, cfg, &qp).await
+}
+
+async fn effective_route(
+    proxy_state: &DemandProxyState,
+    cfg: &Config,
+    qp: &HashMap<String, String>,
+) -> Response<Full<Bytes>> {
+    let bad_request = |msg: String| plaintext_response(hyper::StatusCode::BAD_REQUEST, msg + "\n");
+    let (Some(source), Some(destination)) = (qp.get("source"), qp.get("destination")) else {
+        return bad_request("source and destination are required".to_string());
+    };
+    let (source, downstream) = match lookup_workload(proxy_state, cfg, source).await {
+        Ok(found) => found,
+        Err(e) => return bad_request(e),
+    };
+    let target = match destination.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => {
+            let Some(Ok(port)) = qp.get("port").map(|p| p.parse::<u16>()) else {
+                return bad_request(
+                    "port is required when the destination is a workload".to_string(),
+                );
+            };
+            match lookup_workload(proxy_state, cfg, destination).await {
+                Ok((_, ip)) => SocketAddr::new(ip, port),
+                Err(e) => return bad_request(e),
+            }
+        }
+    };
+    match crate::proxy::effective_route(proxy_state, cfg, source, downstream, target).await {
+        Ok(route) => match serde_json::to_string_pretty(&route) {
+            Ok(body) => plaintext_response(hyper::StatusCode::OK, body),
+            Err(e) => plaintext_response(
+                hyper::StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to encode route: {e}\n"),
+            ),
+        },
+        Err(e) => plaintext_response(
+            hyper::StatusCode::NOT_FOUND,
+            format!("connection would fail: {e}\n"),
+        ),
+    }
+}
+
+// lookup_workload finds a workload by IP or UID, returning it along with the address its connections come from.
+async fn lookup_workload(
+    proxy_state: &DemandProxyState,
+    cfg: &Config,
+    key: &str,
+) -> Result<(Arc<Workload>, IpAddr), String> {
+    if let Ok(ip) = key.parse::<IpAddr>() {
+        let addr = NetworkAddress {
+            network: cfg.network.clone(),
+            address: ip,
+        };
+        return match proxy_state.fetch_workload(&addr).await {
+            Some(wl) => Ok((wl, ip)),
+            None => Err(format!("no workload with address {ip}")),
+        };
+    }
+    let wl = proxy_state
+        .read()
+        .workloads
+        .find_uid(&key.into())
+        .ok_or_else(|| format!("no workload with uid {key}"))?;
+    let ip = wl
+        .workload_ips
+        .first()
+        .copied()
+        .ok_or_else(|| format!("workload {key} has no addresses"))?;
+    Ok((wl, ip))
+}
+
+// query_params returns the query parameters of a request. If a parameter is repeated, the last value is used.
+fn query_params<B>(req: &Request<B>) -> HashMap<String, String> {
+    req.uri()
+        .query()
+        .map(|v| {
+            url::form_urlencoded::parse(v.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 //mirror envoy's behavior: https://www.envoyproxy.io/docs/envoy/latest/operations/admin#post--logging
 //NOTE: multiple query parameters is not supported, for example
 //curl -X POST http://127.0.0.1:15000/logging?"tap=debug&router=debug"
@@ -332,15 +672,7 @@ hint: mod_name:\tthe module name, i.e. ztunnel::proxy
 async fn handle_logging(req: Request<Incoming>) -> Response<Full<Bytes>> {
     match *req.method() {
         hyper::Method::POST => {
-            let qp: HashMap<String, String> = req
-                .uri()
-                .query()
-                .map(|v| {
-                    url::form_urlencoded::parse(v.as_bytes())
-                        .into_owned()
-                        .collect()
-                })
-                .unwrap_or_default();
+            let qp = query_params(&req);
             let level = qp.get("level").cloned();
             let reset = qp.get("reset").cloned();
             if level.is_some() || reset.is_some() {
@@ -356,6 +688,148 @@ async fn handle_logging(req: Request<Incoming>) -> Response<Full<Bytes>> {
     }
 }
 
+static CONNECTION_LOGGING_HELP_STRING: &str = "
+usage: GET /logging/connections						(To list current rules)
+usage: POST /logging/connections?source_identity=<spiffe id>&level=<level>	(To log matching connections verbosely)
+usage: POST /logging/connections?destination_service=<hostname>&cidr=<cidr>	(All given fields must match)
+usage: POST /logging/connections?reset					(To remove all rules)
+
+hint: level:	debug|trace, debug by default
+hint: cidr:	matches either the source or destination address
+";
+
+// handle_connection_logging manages the rules selecting connections to log verbosely. Rules only apply to
+// connections opened after they are added.
+fn handle_connection_logging(req: Request<Incoming>) -> Response<Full<Bytes>> {
+    match *req.method() {
+        hyper::Method::GET => list_connection_logging(),
+        hyper::Method::POST => {
+            let qp = query_params(&req);
+            change_connection_logging(&qp)
+        }
+        _ => plaintext_response(
+            hyper::StatusCode::METHOD_NOT_ALLOWED,
+            format!("Invalid HTTP method\n {CONNECTION_LOGGING_HELP_STRING}"),
+        ),
+    }
+}
+
+fn change_connection_logging(qp: &HashMap<String, String>) -> Response<Full<Bytes>> {
+    if qp.contains_key("reset") {
+        telemetry::targeted::set_rules(vec![]);
+        return list_connection_logging();
+    }
+    match parse_connection_logging_rule(qp) {
+        Ok(rule) => {
+            info!(%rule, "adding connection logging rule");
+            telemetry::targeted::add_rule(rule);
+            list_connection_logging()
+        }
+        Err(e) => plaintext_response(
+            hyper::StatusCode::BAD_REQUEST,
+            format!("Invalid rule: {e}\n{CONNECTION_LOGGING_HELP_STRING}"),
+        ),
+    }
+}
+
+fn parse_connection_logging_rule(
+    qp: &HashMap<String, String>,
+) -> anyhow::Result<telemetry::targeted::Rule> {
+    let level = match qp.get("level").map(String::as_str) {
+        None | Some("debug") => tracing::Level::DEBUG,
+        Some("trace") => tracing::Level::TRACE,
+        Some(level) => anyhow::bail!("level {level} is invalid"),
+    };
+    let rule = telemetry::targeted::Rule {
+        source_identity: qp
+            .get("source_identity")
+            .map(|id| Identity::from_str(id))
+            .transpose()?,
+        destination_service: qp.get("destination_service").cloned(),
+        cidr: qp.get("cidr").map(|c| c.parse()).transpose()?,
+        level,
+    };
+    if rule.is_empty() {
+        anyhow::bail!("one of source_identity, destination_service or cidr is required");
+    }
+    Ok(rule)
+}
+
+static DRAIN_CONNECTIONS_HELP_STRING: &str = "
+usage: POST /drain_connections?destination_service=<hostname>	(To drain connections to a service)
+usage: POST /drain_connections?source_identity=<spiffe id>&cidr=<cidr>	(All given fields must match)
+
+hint: cidr:	matches either the source or destination address
+hint: inbound and outbound connections are both drained; they are given the termination deadline to finish
+";
+
+// handle_drain_connections drains the open connections matching a selector, across all proxies, for
+// maintenance on a subset of them without restarting. It responds with how many matched right away, while they
+// are given the grace period to close in the background.
+fn handle_drain_connections(
+    managers: &[Arc<dyn ConnectionManagers>],
+    req: Request<Incoming>,
+) -> Response<Full<Bytes>> {
+    let qp = query_params(&req);
+    drain_connections(managers, req.method(), &qp)
+}
+
+fn drain_connections(
+    managers: &[Arc<dyn ConnectionManagers>],
+    method: &hyper::Method,
+    qp: &HashMap<String, String>,
+) -> Response<Full<Bytes>> {
+    if *method != hyper::Method::POST {
+        return plaintext_response(
+            hyper::StatusCode::METHOD_NOT_ALLOWED,
+            format!("Invalid HTTP method\n {DRAIN_CONNECTIONS_HELP_STRING}"),
+        );
+    }
+    let selector = match parse_drain_selector(qp) {
+        Ok(selector) => selector,
+        Err(e) => {
+            return plaintext_response(
+                hyper::StatusCode::BAD_REQUEST,
+                format!("Invalid selector: {e}\n{DRAIN_CONNECTIONS_HELP_STRING}"),
+            )
+        }
+    };
+    info!(?selector, "draining connections");
+    let drained: usize = managers
+        .iter()
+        .flat_map(|m| m.connection_managers())
+        .map(|cm| cm.drain_matching(&selector))
+        .sum();
+    plaintext_response(
+        hyper::StatusCode::OK,
+        format!("drained {drained} connections\n"),
+    )
+}
+
+fn parse_drain_selector(qp: &HashMap<String, String>) -> anyhow::Result<DrainSelector> {
+    let selector = DrainSelector {
+        source_identity: qp
+            .get("source_identity")
+            .map(|id| Identity::from_str(id))
+            .transpose()?,
+        destination_service: qp.get("destination_service").cloned(),
+        cidr: qp.get("cidr").map(|c| c.parse()).transpose()?,
+    };
+    if selector.is_empty() {
+        anyhow::bail!("one of source_identity, destination_service or cidr is required");
+    }
+    Ok(selector)
+}
+
+fn list_connection_logging() -> Response<Full<Bytes>> {
+    let rules = telemetry::targeted::rules();
+    let mut body = format!("{} connection logging rules\n", rules.len());
+    for rule in rules {
+        body.push_str(&format!("{rule}\n"));
+    }
+    plaintext_response(hyper::StatusCode::OK, body)
+}
+
 fn list_loggers() -> Response<Full<Bytes>> {
     match telemetry::get_current_loglevel() {
         Ok(loglevel) => plaintext_response(
@@ -450,9 +924,15 @@ fn base64_encode(data: String) -> String {
 #[cfg(test)]
 mod tests {
     use super::change_log_level;
+    use super::drain_connections;
     use super::dump_certs;
+    use super::effective_route;
     use super::handle_config_dump;
+    use super::handle_effective_config;
+    use super::parse_connection_logging_rule;
+    use super::parse_drain_selector;
     use super::ConfigDump;
+    use super::ConnectionManagers;
     use crate::admin::HELP_STRING;
     use crate::config::construct_config;
     use crate::config::ProxyConfig;
@@ -467,6 +947,8 @@ mod tests {
     use crate::xds::istio::security::Rule as XdsRule;
     use crate::xds::istio::security::StringMatch as XdsStringMatch;
     use crate::xds::istio::workload::gateway_address::Destination as XdsDestination;
+    use crate::xds::istio::workload::EgressPolicy as XdsEgressPolicy;
+    use crate::xds::istio::workload::EgressRule as XdsEgressRule;
     use crate::xds::istio::workload::GatewayAddress as XdsGatewayAddress;
     use crate::xds::istio::workload::LoadBalancing as XdsLoadBalancing;
     use crate::xds::istio::workload::Locality as XdsLocality;
@@ -603,6 +1085,112 @@ mod tests {
         pending_fetch.await.unwrap().unwrap();
     }
 
+    #[tokio::test]
+    async fn test_effective_config() {
+        let cfg = crate::config::Config {
+            forward_proxy: Some(crate::config::ForwardProxy {
+                authority: "proxy.example.com:3128".to_string(),
+                basic_auth: Some("user:hunter2".to_string()),
+            }),
+            ..construct_config(ProxyConfig {
+                concurrency: Some(4),
+                proxy_metadata: HashMap::from([("TOKEN".to_string(), "hunter2".to_string())]),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let resp = handle_effective_config(&cfg).unwrap();
+        let body = get_response_str(resp).await;
+        assert!(!body.contains("hunter2"), "{body}");
+
+        let v: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(v["config"]["proxyMetadata"], "<redacted>");
+        assert_eq!(v["config"]["proxyArgs"], "<redacted>");
+        // Only the listed part of the forward proxy is exposed.
+        assert_eq!(
+            v["config"]["forwardProxy"],
+            serde_json::json!({"authority": "proxy.example.com:3128"})
+        );
+        assert_eq!(v["config"]["numWorkerThreads"], 4);
+        assert_eq!(
+            v["sources"]["proxyConfig"],
+            serde_json::json!(["concurrency", "proxyMetadata"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_effective_route() {
+        let cfg = construct_config(ProxyConfig::default()).unwrap();
+        let state = new_proxy_state(
+            &[
+                XdsWorkload {
+                    uid: "cluster1//v1/Pod/default/client".to_string(),
+                    name: "client".to_string(),
+                    namespace: "default".to_string(),
+                    service_account: "client-sa".to_string(),
+                    addresses: vec![Bytes::copy_from_slice(&[10, 0, 0, 1])],
+                    ..Default::default()
+                },
+                XdsWorkload {
+                    uid: "cluster1//v1/Pod/default/server".to_string(),
+                    name: "server".to_string(),
+                    namespace: "default".to_string(),
+                    addresses: vec![Bytes::copy_from_slice(&[10, 0, 0, 2])],
+                    ..Default::default()
+                },
+            ],
+            &[],
+            &[],
+        );
+        let route = |query: &[(&str, &str)]| {
+            let qp = query
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>();
+            let (state, cfg) = (&state, &cfg);
+            async move {
+                let resp = effective_route(state, cfg, &qp).await;
+                (resp.status(), get_response_str(resp).await)
+            }
+        };
+
+        // The destination may be an address, or a workload UID along with a port.
+        for query in [
+            vec![("source", "10.0.0.1"), ("destination", "10.0.0.2:8080")],
+            vec![
+                ("source", "cluster1//v1/Pod/default/client"),
+                ("destination", "cluster1//v1/Pod/default/server"),
+                ("port", "8080"),
+            ],
+        ] {
+            let (status, body) = route(&query).await;
+            assert_eq!(status, hyper::StatusCode::OK, "{body}");
+            let v: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(v["sourceWorkload"], "cluster1//v1/Pod/default/client");
+            assert_eq!(
+                v["sourceIdentity"],
+                "spiffe://cluster.local/ns/default/sa/client-sa"
+            );
+            assert_eq!(v["protocol"], "TCP");
+            assert_eq!(v["viaWaypoint"], false);
+            assert_eq!(v["destinationWorkload"], "cluster1//v1/Pod/default/server");
+            assert_eq!(v["nextHop"], "10.0.0.2:8080");
+        }
+
+        let (status, _) = route(&[("source", "10.0.0.1")]).await;
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+        let (status, body) =
+            route(&[("source", "10.0.0.9"), ("destination", "10.0.0.2:8080")]).await;
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+        assert_eq!(body, "no workload with address 10.0.0.9\n");
+        let (status, _) = route(&[
+            ("source", "10.0.0.1"),
+            ("destination", "cluster1//v1/Pod/default/server"),
+        ])
+        .await;
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+    }
+
     #[tokio::test(start_paused = true)]
     async fn test_dump_config() {
         let manager = identity::mock::new_secret_manager_cfg(identity::mock::SecretManagerConfig {
@@ -656,6 +1244,7 @@ mod tests {
                     ports: vec![XdsPort {
                         service_port: 80,
                         target_port: 8080,
+                        name: "http".to_string(),
                     }],
                 },
             )]),
@@ -664,6 +1253,12 @@ mod tests {
                 zone: "zone".to_string(),
                 subzone: "subezone".to_string(),
             }),
+            egress_policy: Some(XdsEgressPolicy {
+                rules: vec![XdsEgressRule {
+                    hosts: vec!["*.example.com".to_string()],
+                    ports: vec![443],
+                }],
+            }),
             // ..Default::default() // intentionally don't default. we want all fields populated
         };
 
@@ -678,6 +1273,7 @@ mod tests {
             ports: vec![XdsPort {
                 service_port: 80,
                 target_port: 80,
+                name: "http".to_string(),
             }],
             subject_alt_names: vec!["SAN1".to_string(), "SAN2".to_string()],
             waypoint: None,
@@ -738,8 +1334,11 @@ mod tests {
 
         let proxy_state = new_proxy_state(&[wl], &[svc], &[auth]);
 
-        let default_config = construct_config(ProxyConfig::default())
-            .expect("could not build Config without ProxyConfig");
+        let default_config = construct_config(ProxyConfig {
+            proxy_metadata: HashMap::from([("TOKEN".to_string(), "hunter2".to_string())]),
+            ..Default::default()
+        })
+        .expect("could not build Config without ProxyConfig");
 
         let dump = ConfigDump {
             proxy_state,
@@ -771,6 +1370,10 @@ mod tests {
         // most of the value of this test is ensuring that we can serialize
         // the config dump at all from our internal types
         assert!(resp_str.contains("defaultnw/127.0.0.2"));
+        // The config is redacted, as in /debug/config.
+        assert!(!resp_str.contains("hunter2"));
+        let v: serde_json::Value = serde_json::from_str(&resp_str).unwrap();
+        assert_eq!(v["config"]["proxyMetadata"], "<redacted>");
         // Check a waypoint
         assert!(resp_str.contains(
             r#"waypoint": {
@@ -780,6 +1383,119 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_connection_logging_rule() {
+        let qp = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let rule = parse_connection_logging_rule(&qp(&[
+            (
+                "source_identity",
+                "spiffe://cluster.local/ns/default/sa/debugme",
+            ),
+            ("cidr", "10.0.0.0/8"),
+            ("level", "trace"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            rule.to_string(),
+            "source_identity=spiffe://cluster.local/ns/default/sa/debugme cidr=10.0.0.0/8 level=trace"
+        );
+
+        // A rule must select something, rather than every connection.
+        assert!(parse_connection_logging_rule(&qp(&[("level", "debug")])).is_err());
+        assert!(
+            parse_connection_logging_rule(&qp(&[("cidr", "10.0.0.0/8"), ("level", "info")]))
+                .is_err()
+        );
+        assert!(
+            parse_connection_logging_rule(&qp(&[("source_identity", "default/debugme")])).is_err()
+        );
+    }
+
+    #[test]
+    fn test_drain_selector() {
+        let qp = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let selector = parse_drain_selector(&qp(&[
+            ("destination_service", "reviews.default.svc.cluster.local"),
+            ("cidr", "10.0.0.0/8"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            selector.destination_service.as_deref(),
+            Some("reviews.default.svc.cluster.local")
+        );
+        assert_eq!(selector.cidr, Some("10.0.0.0/8".parse().unwrap()));
+        assert_eq!(selector.source_identity, None);
+
+        // Draining every connection is what a restart is for.
+        assert!(parse_drain_selector(&qp(&[])).is_err());
+        assert!(parse_drain_selector(&qp(&[("cidr", "10.0.0.0")])).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_connections() {
+        use crate::proxy::connection_manager::ConnectionManager;
+        use crate::proxy::ConnectionId;
+
+        let grace = Duration::from_secs(60);
+        let cm =
+            ConnectionManager::default().with_selector_drain(grace, &helpers::test_proxy_metrics());
+        let track = |dst: &str| {
+            cm.track_outbound(
+                "10.0.0.1:40000".parse().unwrap(),
+                dst.parse().unwrap(),
+                dst.parse().unwrap(),
+                ConnectionId::next(),
+                None,
+                None,
+            )
+        };
+        let (drained, kept) = (track("192.168.0.1:80"), track("172.16.0.1:80"));
+        let closed = tokio::spawn(drained.closing());
+        let managers: Vec<Arc<dyn ConnectionManagers>> = vec![Arc::new(cm.clone())];
+        let drain = |method: hyper::Method, query: &[(&str, &str)]| {
+            let qp = query
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>();
+            let resp = drain_connections(&managers, &method, &qp);
+            async move { (resp.status(), get_response_str(resp).await) }
+        };
+
+        let (status, _) = drain(hyper::Method::GET, &[("cidr", "192.168.0.0/16")]).await;
+        assert_eq!(status, hyper::StatusCode::METHOD_NOT_ALLOWED);
+        let (status, body) = drain(hyper::Method::POST, &[]).await;
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+        assert!(body.starts_with("Invalid selector"), "{body}");
+
+        // The response does not wait for the drained connection to close.
+        let start = tokio::time::Instant::now();
+        let (status, body) = drain(hyper::Method::POST, &[("cidr", "192.168.0.0/16")]).await;
+        assert_eq!(status, hyper::StatusCode::OK);
+        assert_eq!(body, "drained 1 connections\n");
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert!(!closed.is_finished());
+        let dump = serde_json::to_value(&cm).unwrap();
+        assert_eq!(dump["outbound"].as_array().unwrap().len(), 1);
+        assert_eq!(dump["outbound"][0]["actualDst"], "172.16.0.1:80");
+
+        assert!(matches!(
+            closed.await.unwrap(),
+            crate::proxy::Error::DrainedBySelector
+        ));
+        assert_eq!(start.elapsed(), grace);
+        drop((drained, kept));
+    }
+
     // each of these tests assert that we can change the log level and the
     // appropriate response string is returned.
     //
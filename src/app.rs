@@ -24,18 +24,29 @@ use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::Duration;
 use tokio::task::JoinSet;
 use tracing::{warn, Instrument};
 
 use crate::identity::SecretManager;
 use crate::state::ProxyStateManager;
-use crate::{admin, config, metrics, proxy, readiness, signal};
+use crate::{admin, config, identity, metrics, proxy, readiness, signal, tls};
 use crate::{dns, xds};
 
+// How long to wait, beyond the connection termination deadline, for components to finish draining.
+const FORCED_TERMINATION_GRACE: Duration = Duration::from_secs(1);
+
 pub async fn build_with_cert(
     config: Arc<config::Config>,
     cert_manager: Arc<SecretManager>,
 ) -> anyhow::Result<Bound> {
+    identity::set_log_format(
+        config.identity_log_mode,
+        config.identity_log_hash_salt.clone(),
+    );
+    tls::set_san_policy(config.peer_identity_san_policy);
+    tls::set_resumption(config.tls_resumption);
+
     // Start the data plane worker pool.
     let data_plane_pool = new_data_plane_pool(config.num_worker_threads);
 
@@ -80,7 +91,27 @@ pub async fn build_with_cert(
     let istio_registry = metrics::sub_registry(&mut registry);
     let _ = metrics::meta::Metrics::new(istio_registry);
     let xds_metrics = xds::Metrics::new(istio_registry);
-    let proxy_metrics = Arc::new(proxy::Metrics::new(istio_registry));
+    let proxy_metrics = Arc::new(
+        proxy::Metrics::new(istio_registry)
+            .with_node_labels(config.metrics_node_labels)
+            .with_traffic_scope_label(config.metrics_traffic_scope_label)
+            .with_destination_workload_uid_label(config.metrics_destination_workload_uid_label)
+            .with_cost_attribution(config.metrics_cost_attribution),
+    );
+    #[cfg(feature = "connection-recording")]
+    if let Some(dir) = &config.record_connections_dir {
+        proxy::recording::enable(proxy::recording::Recorder::new(
+            dir.clone(),
+            config.record_connections_max_bytes,
+            &proxy_metrics,
+        ));
+    }
+    if config.bandwidth_limits != Default::default() {
+        proxy::throttle::enable(proxy::throttle::Throttler::new(
+            config.bandwidth_limits,
+            &proxy_metrics,
+        ));
+    }
     let dns_metrics = if config.dns_proxy {
         Some(dns::Metrics::new(istio_registry))
     } else {
@@ -107,9 +138,20 @@ pub async fn build_with_cert(
     // Run the XDS state manager in the current tokio worker pool.
     tokio::spawn(state_mgr.run());
 
+    // Configuration reloads re-read CONFIG_OVERRIDES_PATH, apply to new connections, and are triggered by SIGHUP
+    // or the admin server.
+    let config_reloader = config::ConfigReloader::new(config.clone());
+    let reloader = config_reloader.clone();
+    tokio::spawn(signal::watch_reload(move || {
+        if let Err(e) = reloader.reload() {
+            warn!("failed to reload configuration: {e}");
+        }
+    }));
+
     // Create and start the admin server.
     let mut admin_server = admin::Service::new(
         config.clone(),
+        config_reloader.clone(),
         state.clone(),
         shutdown.trigger(),
         drain_rx.clone(),
@@ -132,7 +174,12 @@ pub async fn build_with_cert(
         dns_metrics,
         drain_rx.clone(),
     )
-    .map_err(|e| anyhow::anyhow!("failed to start proxy factory {:?}", e))?;
+    .map_err(|e| anyhow::anyhow!("failed to start proxy factory {:?}", e))?
+    .with_config_updates(config_reloader.subscribe());
+
+    // Unless configured otherwise, proxies only start accepting once the initial XDS sync completes. Otherwise,
+    // they start right away and apply the startup connection policy to each connection.
+    let await_sync = config.startup_connection_policy == config::StartupConnectionPolicy::Wait;
 
     if config.proxy_mode == config::ProxyMode::Shared {
         tracing::info!("shared proxy mode - in-pod mode enabled");
@@ -149,7 +196,9 @@ pub async fn build_with_cert(
         data_plane_pool.send(DataPlaneTask {
             block_shutdown: true,
             fut: Box::pin(async move {
-                let _ = xds_rx_for_proxy.changed().await;
+                if await_sync {
+                    let _ = xds_rx_for_proxy.changed().await;
+                }
                 run_future.in_current_span().await;
                 Ok(())
             }),
@@ -157,6 +206,9 @@ pub async fn build_with_cert(
     } else {
         tracing::info!("proxy mode enabled");
         let proxies = proxy_gen.new_proxies().await?;
+        if let Some(cm) = proxies.connection_manager {
+            admin_server.add_connection_managers(Arc::new(cm));
+        }
         match proxies.proxy {
             Some(proxy) => {
                 proxy_addresses = Some(proxy.addresses());
@@ -166,7 +218,9 @@ pub async fn build_with_cert(
                 data_plane_pool.send(DataPlaneTask {
                     block_shutdown: true,
                     fut: Box::pin(async move {
-                        let _ = xds_rx_for_proxy.changed().await;
+                        if await_sync {
+                            let _ = xds_rx_for_proxy.changed().await;
+                        }
                         proxy.run().in_current_span().await;
                         Ok(())
                     }),
@@ -217,6 +271,9 @@ pub async fn build_with_cert(
 
     Ok(Bound {
         drain_tx,
+        // Components force close their connections once self_termination_deadline passes; allow a little
+        // longer for that to complete.
+        drain_deadline: config.self_termination_deadline + FORCED_TERMINATION_GRACE,
         shutdown,
         readiness_address,
         admin_address,
@@ -224,6 +281,7 @@ pub async fn build_with_cert(
         proxy_addresses,
         tcp_dns_proxy_address,
         udp_dns_proxy_address,
+        config_reloader,
     })
 }
 
@@ -348,20 +406,23 @@ pub struct Bound {
     pub tcp_dns_proxy_address: Option<SocketAddr>,
     pub udp_dns_proxy_address: Option<SocketAddr>,
 
+    pub config_reloader: config::ConfigReloader,
     pub shutdown: signal::Shutdown,
     drain_tx: drain::DrainTrigger,
+    drain_deadline: Duration,
 }
 
 impl Bound {
     pub async fn wait_termination(self) -> anyhow::Result<()> {
-        // Wait for a signal to shutdown from explicit admin shutdown or signal
-        self.shutdown.wait().await;
-
-        // Start a drain; this will attempt to end all connections
-        // or itself be interrupted by a stronger TERM signal, whichever comes first.
-        self.drain_tx
-            .start_drain_and_wait(drain::DrainMode::Graceful)
-            .await;
+        // Wait for a signal to shutdown from explicit admin shutdown or signal, then start a drain; this will
+        // attempt to end all connections, or itself be interrupted by another signal, whichever comes first.
+        let mut requests = self.shutdown.listen();
+        let outcome =
+            signal::drain_on_shutdown(&mut requests, self.drain_tx, self.drain_deadline).await;
+        if outcome == signal::DrainOutcome::Forced {
+            // Don't wait on any remaining tasks, such as blocking threads, when tearing down the runtime.
+            std::process::exit(0);
+        }
 
         Ok(())
     }
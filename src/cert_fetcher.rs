@@ -68,11 +68,11 @@ impl CertFetcherImpl {
                             .await
                         {
                             Ok(_) => {
-                                debug!("prefetched cert for {:?}", workload_identity.to_string())
+                                debug!("prefetched cert for {}", workload_identity.log_display())
                             }
                             Err(e) => error!(
-                                "unable to prefetch cert for {:?}, skipping, {:?}",
-                                workload_identity.to_string(),
+                                "unable to prefetch cert for {}, skipping, {:?}",
+                                workload_identity.log_display(),
                                 e
                             ),
                         }
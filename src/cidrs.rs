@@ -0,0 +1,143 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// CidrSet is a set of CIDRs that IPs are matched against, such as the destinations that are outside the mesh.
+///
+/// The CIDRs are merged into sorted, disjoint ranges per family, so a lookup is a binary search no matter how
+/// many are configured.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CidrSet {
+    cidrs: Vec<IpNet>,
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl CidrSet {
+    pub fn new(cidrs: Vec<IpNet>) -> Self {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for cidr in &cidrs {
+            match cidr {
+                IpNet::V4(net) => v4.push((net.network().into(), net.broadcast().into())),
+                IpNet::V6(net) => v6.push((net.network().into(), net.broadcast().into())),
+            }
+        }
+        CidrSet {
+            cidrs,
+            v4: merge(v4),
+            v6: merge(v6),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cidrs.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 destinations reached over an IPv6 socket are matched against the IPv4 ranges.
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => contains(&self.v6, u128::from(ip)),
+        }
+    }
+}
+
+impl serde::Serialize for CidrSet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.cidrs.serialize(serializer)
+    }
+}
+
+// merge sorts inclusive ranges and joins those that overlap.
+fn merge<T: Ord + Copy>(mut ranges: Vec<(T, T)>) -> Vec<(T, T)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    // The last range starting at or before the IP is the only one that can contain it.
+    let i = ranges.partition_point(|(start, _)| *start <= ip);
+    i > 0 && ranges[i - 1].1 >= ip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidrs(cidrs: &[&str]) -> CidrSet {
+        CidrSet::new(cidrs.iter().map(|c| c.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn ipv4() {
+        let set = cidrs(&[
+            "169.254.0.0/16",
+            "10.0.0.0/8",
+            "10.1.0.0/16",
+            "192.168.1.1/32",
+        ]);
+        // The nested range is merged into the one containing it.
+        assert_eq!(set.v4.len(), 3);
+        for ip in [
+            "169.254.169.254",
+            "169.254.0.0",
+            "10.255.255.255",
+            "10.1.2.3",
+            "192.168.1.1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(set.contains(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "169.255.0.0",
+            "9.255.255.255",
+            "11.0.0.0",
+            "192.168.1.2",
+            "0.0.0.0",
+            "fe80::1",
+        ] {
+            assert!(!set.contains(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn ipv6() {
+        let set = cidrs(&["fe80::/10", "fd00:ec2::254/128", "fe80::/64"]);
+        assert_eq!(set.v6.len(), 2);
+        for ip in ["fe80::1", "febf:ffff::1", "fd00:ec2::254"] {
+            assert!(set.contains(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["fec0::1", "fd00:ec2::253", "::1", "169.254.169.254"] {
+            assert!(!set.contains(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let set = CidrSet::default();
+        assert!(set.is_empty());
+        assert!(!set.contains("169.254.169.254".parse().unwrap()));
+    }
+}
@@ -13,11 +13,12 @@
 // limitations under the License.
 
 use crate::proxy;
+use crate::proxy::recording::Direction;
 use crate::proxy::ConnectionResult;
 use crate::proxy::Error::{BackendDisconnected, ClientDisconnected, ReceiveError, SendError};
 use bytes::{Buf, Bytes, BytesMut};
 use pin_project_lite::pin_project;
-use std::future::Future;
+use std::future::{poll_fn, Future};
 use std::io::{Error, IoSlice};
 use std::marker::PhantomPinned;
 use std::pin::Pin;
@@ -26,6 +27,7 @@ use tokio::io;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 use tracing::trace;
 
 // BufferedSplitter is a trait to expose splitting an IO object into a buffered reader and a writer
@@ -64,6 +66,107 @@ impl BufferedSplitter for TcpStreamSplitter {
     }
 }
 
+// TeeSplitter is a BufferedSplitter that sends a copy of everything read from the inner splitter to a mirror.
+//
+// A mirror must never slow down the connection it copies, so chunks are handed to it through a bounded
+// queue without waiting; as chunks are reference counted, this does not copy the data. If the queue is full,
+// the mirror has fallen behind. Rather than skip data, which would corrupt the mirrored stream, we stop
+// mirroring and close the queue. The end of the stream is sent as an empty chunk, so the mirror can tell a
+// complete stream apart from an abandoned one.
+pub struct TeeSplitter<S> {
+    inner: S,
+    mirror: Option<mpsc::Sender<Bytes>>,
+}
+
+impl<S> TeeSplitter<S> {
+    pub fn new(inner: S, mirror: Option<mpsc::Sender<Bytes>>) -> Self {
+        Self { inner, mirror }
+    }
+}
+
+impl<S: BufferedSplitter> BufferedSplitter for TeeSplitter<S> {
+    type R = TeeReader<S::R>;
+    type W = S::W;
+
+    fn split_into_buffered_reader(self) -> (Self::R, Self::W) {
+        let (r, w) = self.inner.split_into_buffered_reader();
+        (
+            TeeReader {
+                inner: r,
+                mirror: self.mirror,
+            },
+            w,
+        )
+    }
+}
+
+pub struct TeeReader<R> {
+    inner: R,
+    mirror: Option<mpsc::Sender<Bytes>>,
+}
+
+impl<R: ResizeBufRead + Unpin> ResizeBufRead for TeeReader<R> {
+    fn poll_bytes(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<Bytes>> {
+        let bytes = ready!(Pin::new(&mut self.inner).poll_bytes(cx))?;
+        if let Some(mirror) = &self.mirror {
+            let eof = bytes.is_empty();
+            if mirror.try_send(bytes.clone()).is_err() || eof {
+                trace!(eof, "mirror complete");
+                self.mirror = None;
+            }
+        }
+        Poll::Ready(Ok(bytes))
+    }
+
+    fn resize(mut self: Pin<&mut Self>, new_size: usize) {
+        Pin::new(&mut self.inner).resize(new_size)
+    }
+}
+
+/// mirror writes the chunks from a TeeSplitter to `upstream`, discarding anything read from it.
+/// An error is returned if the mirror was abandoned before the end of the stream.
+pub async fn mirror<S: BufferedSplitter>(
+    upstream: S,
+    mut chunks: mpsc::Receiver<Bytes>,
+) -> Result<(), crate::proxy::Error> {
+    let (mut ru, mut wu) = upstream.split_into_buffered_reader();
+    let send = async {
+        loop {
+            let Some(mut chunk) = chunks.recv().await else {
+                return Err(proxy::Error::MirrorAbandoned);
+            };
+            if chunk.is_empty() {
+                break;
+            }
+            while !chunk.is_empty() {
+                let n = poll_fn(|cx| Pin::new(&mut wu).poll_write_buf(cx, chunk.clone())).await?;
+                if n == 0 {
+                    return Err(Error::from(io::ErrorKind::WriteZero).into());
+                }
+                chunk.advance(n);
+            }
+        }
+        poll_fn(|cx| Pin::new(&mut wu).poll_shutdown(cx)).await?;
+        Ok(())
+    };
+    let discard = async {
+        loop {
+            if poll_fn(|cx| Pin::new(&mut ru).poll_bytes(cx))
+                .await?
+                .is_empty()
+            {
+                return Ok::<_, Error>(());
+            }
+        }
+    };
+    tokio::pin!(send, discard);
+    // The mirror closing its side early does not stop us sending to it, but an error does.
+    tokio::select! {
+        res = &mut send => res,
+        Err(e) = &mut discard => Err(e.into()),
+    }
+}
+
 // AsyncWriteBuf is like AsyncWrite, but writes a Bytes instead of &[u8]. This allows avoiding copies.
 pub trait AsyncWriteBuf {
     fn poll_write_buf(
@@ -139,10 +242,39 @@ const RESIZE_THRESHOLD_LARGE: u64 = 128 * 1024;
 // After 10Mb of data we will trigger a resize from LARGE to JUMBO
 const RESIZE_THRESHOLD_JUMBO: u64 = 10 * 1024 * 1024;
 
+// copy_bidirectional relays data between downstream and upstream until both directions are done. When one side
+// closes its write half, the FIN is passed on to the other and the remaining direction keeps relaying, so
+// protocols that half-close work as expected. If full_close is set, the connection is instead closed entirely
+// once either direction finishes.
 pub async fn copy_bidirectional<A, B>(
     downstream: A,
     upstream: B,
     stats: &ConnectionResult,
+    full_close: bool,
+) -> Result<(), crate::proxy::Error>
+where
+    A: BufferedSplitter,
+    B: BufferedSplitter,
+{
+    copy_bidirectional_until(
+        downstream,
+        upstream,
+        stats,
+        full_close,
+        std::future::pending(),
+    )
+    .await
+}
+
+// copy_bidirectional_until is copy_bidirectional, but stops relaying once `close` completes. Both sides are then
+// sent a FIN, rather than having the connection dropped under them, and the error `close` completed with is
+// returned.
+pub async fn copy_bidirectional_until<A, B>(
+    downstream: A,
+    upstream: B,
+    stats: &ConnectionResult,
+    full_close: bool,
+    close: impl Future<Output = crate::proxy::Error>,
 ) -> Result<(), crate::proxy::Error>
 where
     A: BufferedSplitter,
@@ -150,49 +282,77 @@ where
 {
     let (mut rd, mut wd) = downstream.split_into_buffered_reader();
     let (mut ru, mut wu) = upstream.split_into_buffered_reader();
-    let downstream_to_upstream = async {
-        let translate_error = |e: io::Error| {
-            SendError(Box::new(match e.kind() {
-                io::ErrorKind::NotConnected => BackendDisconnected,
-                io::ErrorKind::WriteZero => BackendDisconnected,
-                io::ErrorKind::UnexpectedEof => ClientDisconnected,
-                _ => e.into(),
-            }))
-        };
-        let res = ignore_io_errors(copy_buf(&mut rd, &mut wu, stats, false).await)
-            .map_err(translate_error);
-        trace!(?res, "send");
-        ignore_shutdown_errors(shutdown(&mut wu).await)
-            .map_err(translate_error)
-            .map_err(|e| proxy::Error::ShutdownError(Box::new(e)))?;
-        res
-    };
+    let reason = {
+        let copy = async {
+            let downstream_to_upstream = async {
+                let translate_error = |e: io::Error| {
+                    SendError(Box::new(match e.kind() {
+                        io::ErrorKind::NotConnected => BackendDisconnected,
+                        io::ErrorKind::WriteZero => BackendDisconnected,
+                        io::ErrorKind::UnexpectedEof => ClientDisconnected,
+                        _ => e.into(),
+                    }))
+                };
+                let res = ignore_io_errors(copy_buf(&mut rd, &mut wu, stats, false).await)
+                    .map_err(translate_error);
+                trace!(?res, "send");
+                ignore_shutdown_errors(shutdown(&mut wu).await)
+                    .map_err(translate_error)
+                    .map_err(|e| proxy::Error::ShutdownError(Box::new(e)))?;
+                res
+            };
 
-    let upstream_to_downstream = async {
-        let translate_error = |e: io::Error| {
-            ReceiveError(Box::new(match e.kind() {
-                io::ErrorKind::NotConnected => ClientDisconnected,
-                io::ErrorKind::WriteZero => ClientDisconnected,
-                _ => e.into(),
-            }))
-        };
-        let res = ignore_io_errors(copy_buf(&mut ru, &mut wd, stats, true).await)
-            .map_err(translate_error);
-        trace!(?res, "receive");
-        ignore_shutdown_errors(shutdown(&mut wd).await)
-            .map_err(translate_error)
-            .map_err(|e| proxy::Error::ShutdownError(Box::new(e)))?;
-        res
-    };
+            let upstream_to_downstream = async {
+                let translate_error = |e: io::Error| {
+                    ReceiveError(Box::new(match e.kind() {
+                        io::ErrorKind::NotConnected => ClientDisconnected,
+                        io::ErrorKind::WriteZero => ClientDisconnected,
+                        _ => e.into(),
+                    }))
+                };
+                let res = ignore_io_errors(copy_buf(&mut ru, &mut wd, stats, true).await)
+                    .map_err(translate_error);
+                trace!(?res, "receive");
+                ignore_shutdown_errors(shutdown(&mut wd).await)
+                    .map_err(translate_error)
+                    .map_err(|e| proxy::Error::ShutdownError(Box::new(e)))?;
+                res
+            };
 
-    // join!() them rather than try_join!() so that we keep complete either end once one side is complete.
-    let (sent, received) = tokio::join!(downstream_to_upstream, upstream_to_downstream);
+            if full_close {
+                // The direction that finished has already shut down its writer; stop relaying the other, and shut it
+                // down too.
+                let sent_first = tokio::select! {
+                    sent = downstream_to_upstream => sent.map(|_| true),
+                    received = upstream_to_downstream => received.map(|_| false),
+                }?;
+                let res = if sent_first {
+                    ignore_shutdown_errors(shutdown(&mut wd).await)
+                } else {
+                    ignore_shutdown_errors(shutdown(&mut wu).await)
+                };
+                trace!(sent_first, "copy complete, closed both directions");
+                return res.map_err(|e| proxy::Error::ShutdownError(Box::new(e.into())));
+            }
+
+            // join!() them rather than try_join!() so that we keep complete either end once one side is complete.
+            let (sent, received) = tokio::join!(downstream_to_upstream, upstream_to_downstream);
 
-    // Convert some error messages to easier to understand
-    let sent = sent?;
-    let received = received?;
-    trace!(sent, received, "copy complete");
-    Ok(())
+            // Convert some error messages to easier to understand
+            let sent = sent?;
+            let received = received?;
+            trace!(sent, received, "copy complete");
+            Ok::<_, proxy::Error>(())
+        };
+        tokio::select! {
+            res = copy => return res,
+            reason = close => reason,
+        }
+    };
+    let sent = ignore_shutdown_errors(shutdown(&mut wu).await);
+    let received = ignore_shutdown_errors(shutdown(&mut wd).await);
+    trace!(?sent, ?received, "copy stopped, closed both directions");
+    Err(reason)
 }
 
 // During copying, we may encounter errors from either side closing their connection. Typically, we
@@ -231,7 +391,8 @@ fn ignore_shutdown_errors(res: Result<(), io::Error>) -> Result<(), io::Error> {
     }
 }
 
-// CopyBuf is a fork of Tokio's same struct, with additional support for resizing and metrics reporting.
+// CopyBuf is a fork of Tokio's same struct, with additional support for resizing, metrics reporting and
+// throttling.
 #[must_use = "futures do nothing unless you `.await` or poll them"]
 struct CopyBuf<'a, R: ?Sized, W: ?Sized> {
     send: bool,
@@ -240,6 +401,8 @@ struct CopyBuf<'a, R: ?Sized, W: ?Sized> {
     buf: Option<Bytes>,
     metrics: &'a ConnectionResult,
     amt: u64,
+    // Set while the connection is over its bandwidth limits; nothing more is read until it elapses.
+    pause: Option<Pin<Box<tokio::time::Sleep>>>,
 }
 
 async fn copy_buf<'a, R, W>(
@@ -259,6 +422,7 @@ where
         buf: None,
         metrics,
         amt: 0,
+        pause: None,
     }
     .await
 }
@@ -274,6 +438,11 @@ where
         loop {
             let me = &mut *self;
 
+            if let Some(pause) = &mut me.pause {
+                ready!(pause.as_mut().poll(cx));
+                me.pause = None;
+            }
+
             // Get our stored buffer if there is any remaining, or fetch some more.
             let buffer = if let Some(buffer) = me.buf.take() {
                 buffer
@@ -291,6 +460,12 @@ where
             if i == 0 {
                 return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
             }
+            let direction = if me.send {
+                Direction::Downstream
+            } else {
+                Direction::Upstream
+            };
+            me.metrics.record_data(direction, our_copy.slice(..i));
             if our_copy.len() < i {
                 // We only partially consumed it; store it back for a future call, skipping the number of bytes we did read.
                 our_copy.advance(i);
@@ -301,6 +476,10 @@ where
             } else {
                 me.metrics.increment_recv(i as u64);
             }
+            me.pause = me
+                .metrics
+                .throttle(i as u64)
+                .map(|pause| Box::pin(tokio::time::sleep(pause)));
             let old = self.amt;
             self.amt += i as u64;
 
@@ -435,6 +614,109 @@ mod tests {
     use tokio::io::AsyncReadExt;
     use tokio::io::AsyncWriteExt;
 
+    fn connection_result() -> ConnectionResult {
+        let mut registry = prometheus_client::registry::Registry::default();
+        let metrics = std::sync::Arc::new(crate::proxy::Metrics::new(
+            crate::metrics::sub_registry(&mut registry),
+        ));
+        let source_addr = "127.0.0.1:12345".parse().unwrap();
+        let dest_addr = "127.0.0.1:34567".parse().unwrap();
+        ConnectionResult::new(
+            source_addr,
+            dest_addr,
+            None,
+            std::time::Instant::now(),
+            crate::proxy::metrics::ConnectionOpen {
+                reporter: crate::proxy::Reporter::destination,
+                source: None,
+                derived_source: None,
+                destination: None,
+                connection_security_policy: crate::proxy::metrics::SecurityPolicy::unknown,
+                destination_service: None,
+                destination_service_port_name: None,
+                connection_id: crate::proxy::ConnectionId::next(),
+            },
+            metrics,
+        )
+    }
+
+    // half_close sends a request from the client, which then closes its write half, and returns the response the
+    // client reads once the server has seen the end of the request.
+    async fn half_close(full_close: bool) -> Vec<u8> {
+        let (mut client, ztunnel_downstream) = tokio::io::duplex(1024);
+        let (mut server, ztunnel_upstream) = tokio::io::duplex(1024);
+        let copy = tokio::task::spawn(async move {
+            copy_bidirectional(
+                ztunnel_downstream,
+                ztunnel_upstream,
+                &connection_result(),
+                full_close,
+            )
+            .await
+        });
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        server.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+        // With full close, the server may find the connection already closed.
+        let _ = server.write_all(b"response").await;
+        let _ = server.shutdown().await;
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        copy.await.unwrap().unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn copy_half_close() {
+        initialize_telemetry();
+        assert_eq!(half_close(false).await, b"response");
+    }
+
+    #[tokio::test]
+    async fn copy_full_close() {
+        initialize_telemetry();
+        assert_eq!(half_close(true).await, b"");
+    }
+
+    #[tokio::test]
+    async fn copy_until_closed() {
+        initialize_telemetry();
+        let (mut client, ztunnel_downstream) = tokio::io::duplex(1024);
+        let (mut server, ztunnel_upstream) = tokio::io::duplex(1024);
+        let (close_tx, close_rx) = tokio::sync::oneshot::channel::<()>();
+        let copy = tokio::task::spawn(async move {
+            let close = async {
+                let _ = close_rx.await;
+                crate::proxy::Error::MaxLifetimeReached
+            };
+            copy_bidirectional_until(
+                ztunnel_downstream,
+                ztunnel_upstream,
+                &connection_result(),
+                false,
+                close,
+            )
+            .await
+        });
+
+        client.write_all(b"request").await.unwrap();
+        let mut request = [0u8; 7];
+        server.read_exact(&mut request).await.unwrap();
+        close_tx.send(()).unwrap();
+        // Neither side has closed, but both see the end of the stream.
+        let mut rest = Vec::new();
+        assert_eq!(client.read_to_end(&mut rest).await.unwrap(), 0);
+        assert_eq!(server.read_to_end(&mut rest).await.unwrap(), 0);
+        assert!(matches!(
+            copy.await.unwrap(),
+            Err(crate::proxy::Error::MaxLifetimeReached)
+        ));
+    }
+
     #[tokio::test]
     async fn copy() {
         initialize_telemetry();
@@ -443,28 +725,8 @@ mod tests {
 
         // Spawn copy
         tokio::task::spawn(async move {
-            let mut registry = prometheus_client::registry::Registry::default();
-            let metrics = std::sync::Arc::new(crate::proxy::Metrics::new(
-                crate::metrics::sub_registry(&mut registry),
-            ));
-            let source_addr = "127.0.0.1:12345".parse().unwrap();
-            let dest_addr = "127.0.0.1:34567".parse().unwrap();
-            let cr = ConnectionResult::new(
-                source_addr,
-                dest_addr,
-                None,
-                std::time::Instant::now(),
-                crate::proxy::metrics::ConnectionOpen {
-                    reporter: crate::proxy::Reporter::destination,
-                    source: None,
-                    derived_source: None,
-                    destination: None,
-                    connection_security_policy: crate::proxy::metrics::SecurityPolicy::unknown,
-                    destination_service: None,
-                },
-                metrics.clone(),
-            );
-            copy_bidirectional(ztunnel_downsteam, ztunnel_upsteam, &cr).await
+            let cr = connection_result();
+            copy_bidirectional(ztunnel_downsteam, ztunnel_upsteam, &cr, false).await
         });
         const ITERS: usize = 1000;
         const REPEATS: usize = 6400;
@@ -479,4 +741,94 @@ mod tests {
             assert_eq!(res.as_slice(), body);
         }
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn copy_throttled() {
+        initialize_telemetry();
+        // 800kbps is 100KB/s, of which 10KB may be sent right away.
+        let throttler = crate::proxy::throttle::Throttler::new(
+            crate::config::BandwidthLimits {
+                connection: Some(100_000),
+                identity: None,
+            },
+            &crate::test_helpers::helpers::test_proxy_metrics(),
+        );
+        let (mut client, ztunnel_downstream) = tokio::io::duplex(32000);
+        let (mut server, ztunnel_upstream) = tokio::io::duplex(32000);
+        tokio::task::spawn(async move {
+            let cr = connection_result().with_throttle(throttler.start(None));
+            copy_bidirectional(ztunnel_downstream, ztunnel_upstream, &cr, false).await
+        });
+
+        let start = tokio::time::Instant::now();
+        let body = vec![7; 210_000];
+        let mut res = vec![0; body.len()];
+        tokio::try_join!(client.write_all(&body), server.read_exact(&mut res)).unwrap();
+        assert_eq!(res, body);
+        // The last chunk is relayed before its pause, so up to one buffer of data is not waited for.
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= std::time::Duration::from_millis(1800),
+            "{elapsed:?}"
+        );
+        assert!(
+            elapsed <= std::time::Duration::from_millis(2000),
+            "{elapsed:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn mirror_copy() {
+        initialize_telemetry();
+        let (mut client, downstream) = tokio::io::duplex(1024);
+        let (mirror_server, mirror_upstream) = tokio::io::duplex(1024);
+        let (tx, rx) = mpsc::channel(16);
+
+        let (mut rd, _wd) = TeeSplitter::new(downstream, Some(tx)).split_into_buffered_reader();
+        let mirrored = tokio::task::spawn(mirror(mirror_upstream, rx));
+
+        client.write_all(b"hello world").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut read = Vec::new();
+        loop {
+            let b = poll_fn(|cx| Pin::new(&mut rd).poll_bytes(cx))
+                .await
+                .unwrap();
+            if b.is_empty() {
+                break;
+            }
+            read.extend_from_slice(&b);
+        }
+        assert_eq!(read, b"hello world");
+
+        // The mirror gets the same bytes, and completes once the stream ends.
+        let (mut mirror_rd, _mirror_wr) = tokio::io::split(mirror_server);
+        let mut mirrored = Vec::new();
+        mirror_rd.read_to_end(&mut mirrored).await.unwrap();
+        assert_eq!(mirrored, b"hello world");
+        assert!(mirrored.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn mirror_abandoned() {
+        initialize_telemetry();
+        let (mut client, downstream) = tokio::io::duplex(1024);
+        let (_mirror_server, mirror_upstream) = tokio::io::duplex(1024);
+        let (tx, rx) = mpsc::channel(1);
+
+        let (mut rd, _wd) = TeeSplitter::new(downstream, Some(tx)).split_into_buffered_reader();
+        // Fill the queue before the mirror is reading from it; the next chunk should abandon the mirror
+        // rather than block reading from the client.
+        for chunk in [&b"first"[..], b"second"] {
+            client.write_all(chunk).await.unwrap();
+            let b = poll_fn(|cx| Pin::new(&mut rd).poll_bytes(cx))
+                .await
+                .unwrap();
+            assert_eq!(b, chunk);
+        }
+        assert!(matches!(
+            mirror(mirror_upstream, rx).await,
+            Err(proxy::Error::MirrorAbandoned)
+        ));
+    }
 }
@@ -27,7 +27,7 @@ pub use server::*;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("failed to bind to address {0}: {1}")]
+    #[error("failed to bind to address {0}: {}", crate::socket::describe("bind", .1))]
     Bind(SocketAddr, io::Error),
 
     #[error("io error: {0}")]
@@ -30,6 +30,11 @@ pub struct Metrics {
     pub forwarded_requests: Family<DnsLabels, Counter>,
     pub forwarded_failures: Family<DnsLabels, Counter>,
     pub forwarded_duration: Family<DnsLabels, Histogram>,
+
+    // Lookups through the resolver, whether answered from local state or forwarded upstream.
+    pub resolution_duration: Family<ResolutionLabels, Histogram>,
+    pub local_answers: Family<ResolutionLabels, Counter>,
+    pub upstream_answers: Family<ResolutionLabels, Counter>,
 }
 
 impl Metrics {
@@ -65,11 +70,39 @@ impl Metrics {
             forwarded_duration.clone(),
         );
 
+        let resolution_duration =
+            Family::<ResolutionLabels, Histogram>::new_with_constructor(|| {
+                Histogram::new(vec![0.0005f64, 0.001, 0.005, 0.01, 0.1, 1.0, 5.0].into_iter())
+            });
+        registry.register_with_unit(
+            "dns_resolution_duration",
+            "Time in seconds to resolve a DNS request, whether served locally or forwarded upstream (unstable)",
+            Unit::Seconds,
+            resolution_duration.clone(),
+        );
+
+        let local_answers = Family::default();
+        registry.register(
+            "dns_local_answers",
+            "Total number of DNS requests answered from ztunnel's own state (unstable)",
+            local_answers.clone(),
+        );
+
+        let upstream_answers = Family::default();
+        registry.register(
+            "dns_upstream_answers",
+            "Total number of DNS requests that were answered by the upstream resolver (unstable)",
+            upstream_answers.clone(),
+        );
+
         Self {
             requests,
             forwarded_requests,
             forwarded_failures,
             forwarded_duration,
+            resolution_duration,
+            local_answers,
+            upstream_answers,
         }
     }
 }
@@ -104,6 +137,21 @@ impl DnsLabels {
     }
 }
 
+/// ResolutionLabels are the labels of resolver metrics. Only the record type is included, so they stay cheap to
+/// record on every lookup.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct ResolutionLabels {
+    request_query_type: RichStrng,
+}
+
+impl ResolutionLabels {
+    pub fn new(r: &Request) -> Self {
+        Self {
+            request_query_type: r.query().query_type().to_string().to_lowercase().into(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct DnsRequest<'a> {
     pub request: &'a Request,
@@ -17,6 +17,11 @@ use hickory_resolver::lookup::Lookup;
 use hickory_server::authority::LookupError;
 use hickory_server::server::Request;
 use std::slice::Iter;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::debug;
+
+use crate::dns::metrics::{Metrics, ResolutionLabels};
 
 /// Similar to a TrustDNS `Authority`, although the resulting [Answer] indicates whether
 /// the response is authoritative. This makes the interface generally more composable and
@@ -62,6 +67,54 @@ impl From<Lookup> for Answer {
     }
 }
 
+/// A [Resolver] that records metrics around the lookups of another [Resolver]. Authoritative answers are served from
+/// ztunnel's own state, so count as local answers; any other answer came from the upstream resolver. Nothing is
+/// cached: every lookup goes to the inner [Resolver].
+pub struct MeteredResolver {
+    inner: Arc<dyn Resolver>,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredResolver {
+    pub fn new(inner: Arc<dyn Resolver>, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+#[async_trait::async_trait]
+impl Resolver for MeteredResolver {
+    async fn lookup(&self, request: &Request) -> Result<Answer, LookupError> {
+        let start = Instant::now();
+        let res = self.inner.lookup(request).await;
+        let elapsed = start.elapsed();
+
+        let labels = ResolutionLabels::new(request);
+        self.metrics
+            .resolution_duration
+            .get_or_create(&labels)
+            .observe(elapsed.as_secs_f64());
+        if let Ok(answer) = &res {
+            let counter = if answer.is_authoritative() {
+                &self.metrics.local_answers
+            } else {
+                &self.metrics.upstream_answers
+            };
+            counter.get_or_create(&labels).inc();
+            // The lowest TTL is how long the answer could be cached for.
+            let ttl = answer.record_iter().map(Record::ttl).min();
+            debug!(
+                name=%request.query().name(),
+                query_type=%request.query().query_type(),
+                authoritative=answer.is_authoritative(),
+                ttl,
+                duration=?elapsed,
+                "dns lookup complete"
+            );
+        }
+        res
+    }
+}
+
 /// Borrowed view of set of [`Record`]s returned from an [Answer].
 pub struct RecordIter<'a>(Iter<'a, Record>);
 
@@ -72,3 +125,47 @@ impl<'a> Iterator for RecordIter<'a> {
         self.0.next()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::dns::{a, a_request, aaaa_request, n, socket_addr};
+    use hickory_proto::rr::Name;
+    use hickory_server::server::Protocol;
+    use prometheus_client::registry::Registry;
+    use std::net::Ipv4Addr;
+
+    // FakeResolver is authoritative for names under cluster.local, and forwards everything else.
+    struct FakeResolver();
+
+    #[async_trait::async_trait]
+    impl Resolver for FakeResolver {
+        async fn lookup(&self, request: &Request) -> Result<Answer, LookupError> {
+            let name = Name::from(request.query().name().clone());
+            let authoritative = name.to_string().ends_with("cluster.local.");
+            let records = vec![a(name, Ipv4Addr::new(127, 0, 0, 1))];
+            Ok(Answer::new(records, authoritative))
+        }
+    }
+
+    #[tokio::test]
+    async fn metered_lookups() {
+        let metrics = Arc::new(Metrics::new(&mut Registry::default()));
+        let resolver = MeteredResolver::new(Arc::new(FakeResolver()), metrics.clone());
+        let client = socket_addr("1.1.1.1:80");
+
+        let local = a_request(n("svc.ns.svc.cluster.local."), client, Protocol::Udp);
+        let upstream = a_request(n("example.com."), client, Protocol::Udp);
+        let upstream_v6 = aaaa_request(n("example.com."), client, Protocol::Udp);
+        for req in [&local, &local, &upstream, &upstream_v6] {
+            resolver.lookup(req).await.unwrap();
+        }
+
+        let a = ResolutionLabels::new(&local);
+        let aaaa = ResolutionLabels::new(&upstream_v6);
+        assert_eq!(metrics.local_answers.get_or_create(&a).get(), 2);
+        assert_eq!(metrics.upstream_answers.get_or_create(&a).get(), 1);
+        assert_eq!(metrics.local_answers.get_or_create(&aaaa).get(), 0);
+        assert_eq!(metrics.upstream_answers.get_or_create(&aaaa).get(), 1);
+    }
+}
@@ -41,7 +41,7 @@ use crate::dns::metrics::{
     DnsRequest, ForwardedDuration, ForwardedFailure, ForwardedRequest, Metrics,
 };
 use crate::dns::name_util::{has_domain, trim_domain};
-use crate::dns::resolver::{Answer, Resolver};
+use crate::dns::resolver::{Answer, MeteredResolver, Resolver};
 use crate::drain::{DrainMode, DrainWatcher};
 use crate::metrics::{DeferRecorder, IncrementRecorder, Recorder};
 use crate::proxy::Error;
@@ -61,7 +61,7 @@ static SVC: Lazy<Name> = Lazy::new(|| as_name("svc"));
 /// A DNS server that serves known hostnames from ztunnel data structures.
 /// Unknown hosts are forwarded to an upstream resolver.
 pub struct Server {
-    store: Arc<Store>,
+    resolver: Arc<MeteredResolver>,
     tcp_addr: SocketAddr,
     udp_addr: SocketAddr,
     server: ServerFuture<dns::handler::Handler>,
@@ -99,11 +99,11 @@ impl Server {
             network.as_ref().to_string(),
             state,
             forwarder,
-            metrics,
+            metrics.clone(),
         );
         store.allow_unknown_source = allow_unknown_source;
-        let store = Arc::new(store);
-        let handler = dns::handler::Handler::new(store.clone());
+        let resolver = Arc::new(MeteredResolver::new(Arc::new(store), metrics));
+        let handler = dns::handler::Handler::new(resolver.clone());
         let mut server = ServerFuture::new(handler);
         info!(
             address=%address,
@@ -138,7 +138,7 @@ impl Server {
         }
 
         Ok(Self {
-            store,
+            resolver,
             tcp_addr: tcp_addr.expect("must have at least one address"),
             udp_addr: udp_addr.expect("must have at least one address"),
             server,
@@ -157,7 +157,7 @@ impl Server {
     }
 
     pub fn resolver(&self) -> Arc<dyn Resolver + Send + Sync> {
-        self.store.clone()
+        self.resolver.clone()
     }
 
     /// Runs this DNS server to completion.
@@ -1311,7 +1311,7 @@ mod tests {
         let state = state();
         let forwarder = forwarder();
         let (_signal, drain) = drain::new();
-        let factory = crate::proxy::DefaultSocketFactory;
+        let factory = crate::proxy::DefaultSocketFactory::default();
         let proxy = Server::new(
             domain,
             config::Address::Localhost(false, 0),
@@ -1429,7 +1429,7 @@ mod tests {
             .unwrap(),
         );
         let (_signal, drain) = drain::new();
-        let factory = crate::proxy::DefaultSocketFactory;
+        let factory = crate::proxy::DefaultSocketFactory::default();
         let server = Server::new(
             domain,
             config::Address::Localhost(false, 0),
@@ -1506,7 +1506,7 @@ mod tests {
         });
         let domain = "cluster.local".to_string();
         let (_signal, drain) = drain::new();
-        let factory = crate::proxy::DefaultSocketFactory;
+        let factory = crate::proxy::DefaultSocketFactory::default();
         let server = Server::new(
             domain,
             config::Address::Localhost(false, 0),
@@ -1767,6 +1767,7 @@ mod tests {
             ports: vec![XdsPort {
                 service_port: 80,
                 target_port: 80,
+                ..Default::default()
             }],
             ..Default::default()
         }
@@ -1811,6 +1812,7 @@ mod tests {
                             ports: vec![XdsPort {
                                 service_port: 80,
                                 target_port: 80,
+                                ..Default::default()
                             }],
                         },
                     );
@@ -42,6 +42,16 @@ pub fn tls_server<T: ServerCertProvider + Clone + 'static>(
     cert_provider: T,
     listener: TcpListener,
 ) -> impl Stream<Item = tokio_rustls::server::TlsStream<TcpStream>> {
+    tls_server_with_metrics(cert_provider, listener, None)
+}
+
+/// tls_server_with_metrics is like tls_server, but records failed handshakes in the proxy metrics.
+pub fn tls_server_with_metrics<T: ServerCertProvider + Clone + 'static>(
+    cert_provider: T,
+    listener: TcpListener,
+    metrics: Option<Arc<proxy::Metrics>>,
+) -> impl Stream<Item = tokio_rustls::server::TlsStream<TcpStream>> {
+    use proxy::metrics::{Reporter, TlsFailureReason};
     use tokio_stream::StreamExt;
 
     tls_listener::builder(crate::tls::InboundAcceptor::new(cert_provider))
@@ -49,15 +59,31 @@ pub fn tls_server<T: ServerCertProvider + Clone + 'static>(
         .take_while(|item| {
             !matches!(item, Err(tls_listener::Error::ListenerError(e)) if proxy::util::is_runtime_shutdown(e))
         })
-        .filter_map(|conn| {
+        .filter_map(move |conn| {
             // Avoid 'By default, if a client fails the TLS handshake, that is treated as an error, and the TlsListener will return an Err'
             match conn {
                 Err(err) => {
                     warn!("TLS handshake error: {}", err);
+                    let reason = match &err {
+                        tls_listener::Error::TlsAcceptError { error, .. } => {
+                            Some(TlsFailureReason::from_tls_error(error))
+                        }
+                        tls_listener::Error::HandshakeTimeout { .. } => {
+                            Some(TlsFailureReason::timeout)
+                        }
+                        // Failing to accept the TCP connection is not a handshake failure
+                        _ => None,
+                    };
+                    if let (Some(metrics), Some(reason)) = (&metrics, reason) {
+                        metrics.record_tls_handshake_failure(Reporter::destination, reason);
+                    }
                     None
                 }
                 Ok(s) => {
                     debug!("TLS handshake succeeded");
+                    if let Some(metrics) = &metrics {
+                        metrics.record_tls_handshake(Reporter::destination, s.0.get_ref().1);
+                    }
                     Some(s)
                 }
             }
@@ -42,15 +42,15 @@ pub enum Error {
     SigningRequest(#[from] tonic::Status),
     #[error("failed to process string: {0}")]
     Utf8(#[from] Utf8Error),
-    #[error("did not find expected SAN: {0}")]
+    #[error("did not find expected SAN: {}", .0.log_display())]
     SanError(Identity),
-    #[error("chain returned from CA is empty for: {0}")]
+    #[error("chain returned from CA is empty for: {}", .0.log_display())]
     EmptyResponse(Identity),
     #[error("invalid spiffe identity: {0}")]
     Spiffe(String),
     #[error("the identity is no longer needed")]
     Forgotten,
-    #[error("BUG: identity requested {0}, but only allowed {1:?}")]
+    #[error("BUG: identity requested {}, but only allowed {1:?}", .0.log_display())]
     BugInvalidIdentityRequest(Identity, Arc<WorkloadInfo>),
 }
 
@@ -95,7 +95,7 @@ impl CaClient {
         let chain = if resp.cert_chain.len() > 1 {
             resp.cert_chain[1..].iter().map(|s| s.as_bytes()).collect()
         } else {
-            warn!("no chain certs for: {}", id);
+            warn!("no chain certs for: {}", id.log_display());
             vec![]
         };
         let certs = tls::WorkloadCertificate::new(&private_key, leaf, chain)?;
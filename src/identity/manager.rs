@@ -20,8 +20,10 @@ use std::hash::{Hash, RandomState};
 use std::str::FromStr;
 use std::sync::Arc;
 
-use crate::config::ProxyMode;
+use crate::config::{IdentityLogMode, ProxyMode};
 use async_trait::async_trait;
+use itertools::Itertools;
+use once_cell::sync::OnceCell;
 
 use prometheus_client::encoding::{EncodeLabelValue, LabelValueEncoder};
 use tokio::sync::{mpsc, watch, Mutex};
@@ -49,7 +51,7 @@ pub enum Identity {
 
 impl EncodeLabelValue for Identity {
     fn encode(&self, writer: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
-        writer.write_str(&self.to_string())
+        write!(writer, "{}", self.log_display())
     }
 }
 
@@ -101,7 +103,65 @@ impl fmt::Display for Identity {
     }
 }
 
+// How identities are rendered in logs and metrics. This is set once, at startup; until then,
+// identities are rendered in full.
+static LOG_FORMAT: OnceCell<(IdentityLogMode, String)> = OnceCell::new();
+
+/// set_log_format sets how identities are rendered in logs and metrics, for the lifetime of the process.
+/// Only the first call has any effect.
+pub fn set_log_format(mode: IdentityLogMode, salt: String) {
+    let _ = LOG_FORMAT.set((mode, salt));
+}
+
+/// LoggedIdentity renders an identity for logs and metrics. See [Identity::log_display].
+pub struct LoggedIdentity<'a> {
+    identity: &'a Identity,
+    mode: IdentityLogMode,
+    salt: &'a str,
+}
+
+impl fmt::Display for LoggedIdentity<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.mode, self.identity) {
+            (IdentityLogMode::Full, id) => write!(f, "{id}"),
+            (IdentityLogMode::Redacted, Identity::Spiffe { namespace, .. }) => {
+                write!(f, "spiffe://*/ns/{namespace}/sa/*")
+            }
+            (IdentityLogMode::Hashed, id) => {
+                // The salt keeps identities from being recovered by hashing known ones. Half of the digest is
+                // plenty to correlate logs.
+                let digest = tls::sha256(format!("{}\0{id}", self.salt).as_bytes());
+                write!(f, "hash:")?;
+                digest[..16].iter().try_for_each(|b| write!(f, "{b:02x}"))
+            }
+        }
+    }
+}
+
+/// log_display_all renders a list of identities for logs, as with [Identity::log_display].
+pub fn log_display_all(ids: &[Identity]) -> String {
+    format!("[{}]", ids.iter().map(Identity::log_display).join(", "))
+}
+
 impl Identity {
+    /// log_display renders the identity for logs and metrics, according to the configured IdentityLogMode.
+    /// This should be used anywhere an identity is logged, rather than its Display implementation.
+    pub fn log_display(&self) -> LoggedIdentity<'_> {
+        match LOG_FORMAT.get() {
+            Some((mode, salt)) => self.display_as(*mode, salt),
+            None => self.display_as(IdentityLogMode::Full, ""),
+        }
+    }
+
+    /// display_as renders the identity with an explicit IdentityLogMode.
+    pub fn display_as<'a>(&'a self, mode: IdentityLogMode, salt: &'a str) -> LoggedIdentity<'a> {
+        LoggedIdentity {
+            identity: self,
+            mode,
+            salt,
+        }
+    }
+
     pub fn from_parts(td: Strng, ns: Strng, sa: Strng) -> Identity {
         Identity::Spiffe {
             trust_domain: td,
@@ -336,7 +396,7 @@ impl Worker {
 
                 // Handle fetch results.
                 Some((id, res)) = fetches.next() => {
-                    tracing::trace!(%id, "fetch complete");
+                    tracing::trace!(id=%id.log_display(), "fetch complete");
                     match processing.remove(&id) {
                         Some(Fetch::Processing) => (),
                         Some(Fetch::Forgetting) => continue 'main,
@@ -358,12 +418,12 @@ impl Worker {
                             // randomized interval =
                             //     retry_interval * (random value in range [1 - randomization_factor, 1 + randomization_factor])
                             let retry = cert_backoff.next_backoff().unwrap_or(CERT_REFRESH_FAILURE_RETRY_DELAY_MAX_INTERVAL);
-                            tracing::debug!(%id, "certificate fetch failed ({err}), retrying in {retry:?}");
+                            tracing::debug!(id=%id.log_display(), "certificate fetch failed ({err}), retrying in {retry:?}");
                             let refresh_at = Instant::now() + retry;
                             (CertState::Unavailable(err), refresh_at)
                         },
                         Ok(certs) => {
-                             tracing::debug!(%id, "certificate fetch succeeded");
+                             tracing::debug!(id=%id.log_display(), "certificate fetch succeeded");
                             // Reset the backoff on success.
                             // [`reset`](https://docs.rs/backoff/0.4.0/backoff/backoff/trait.Backoff.html#method.reset)
                             cert_backoff.reset();
@@ -686,6 +746,40 @@ mod tests {
 
     use super::{mock, *};
 
+    #[test]
+    fn identity_log_display() {
+        let id = Identity::from_parts("cluster.local".into(), "ns".into(), "sa".into());
+        assert_eq!(
+            id.display_as(IdentityLogMode::Full, "").to_string(),
+            "spiffe://cluster.local/ns/ns/sa/sa"
+        );
+        assert_eq!(
+            id.display_as(IdentityLogMode::Redacted, "").to_string(),
+            "spiffe://*/ns/ns/sa/*"
+        );
+
+        let hashed = id.display_as(IdentityLogMode::Hashed, "salt").to_string();
+        // SHA-256 of "salt\0spiffe://cluster.local/ns/ns/sa/sa", truncated to 16 bytes.
+        assert_eq!(hashed, "hash:3c5d84bc8425061761fdf9dcd76d166d");
+        assert!(!hashed.contains("spiffe"), "{hashed}");
+        // Stable for the same salt, but not across salts or identities.
+        assert_eq!(
+            hashed,
+            id.display_as(IdentityLogMode::Hashed, "salt").to_string()
+        );
+        assert_ne!(
+            hashed,
+            id.display_as(IdentityLogMode::Hashed, "other").to_string()
+        );
+        let other = Identity::from_parts("cluster.local".into(), "ns".into(), "other".into());
+        assert_ne!(
+            hashed,
+            other
+                .display_as(IdentityLogMode::Hashed, "salt")
+                .to_string()
+        );
+    }
+
     async fn stress_many_ids(sm: Arc<SecretManager>, iterations: u32) {
         for i in 0..iterations {
             let id = identity::Identity::Spiffe {
@@ -152,6 +152,16 @@ impl crate::admin::AdminHandler2 for WorkloadManagerAdminHandler {
     }
 }
 
+impl crate::admin::ConnectionManagers for WorkloadManagerAdminHandler {
+    fn connection_managers(&self) -> Vec<ConnectionManager> {
+        let state = self.state.read().unwrap();
+        state
+            .values()
+            .filter_map(|s| s.connections.clone())
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
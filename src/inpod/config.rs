@@ -22,6 +22,7 @@ pub struct InPodConfig {
     cur_netns: Arc<std::os::fd::OwnedFd>,
     mark: Option<std::num::NonZeroU32>,
     reuse_port: bool,
+    socket_options: socket::SocketOptions,
 }
 
 impl InPodConfig {
@@ -30,6 +31,7 @@ impl InPodConfig {
             cur_netns: Arc::new(InpodNetns::current()?),
             mark: std::num::NonZeroU32::new(cfg.inpod_mark),
             reuse_port: cfg.inpod_port_reuse,
+            socket_options: socket::SocketOptions::new(cfg),
         })
     }
     pub fn socket_factory(
@@ -55,14 +57,23 @@ impl InPodConfig {
 struct InPodSocketFactory {
     netns: InpodNetns,
     mark: Option<std::num::NonZeroU32>,
+    socket_options: socket::SocketOptions,
 }
 
 impl InPodSocketFactory {
     fn from_cfg(inpod_config: &InPodConfig, netns: InpodNetns) -> Self {
-        Self::new(netns, inpod_config.mark())
+        Self::new(netns, inpod_config.mark(), inpod_config.socket_options)
     }
-    fn new(netns: InpodNetns, mark: Option<std::num::NonZeroU32>) -> Self {
-        Self { netns, mark }
+    fn new(
+        netns: InpodNetns,
+        mark: Option<std::num::NonZeroU32>,
+        socket_options: socket::SocketOptions,
+    ) -> Self {
+        Self {
+            netns,
+            mark,
+            socket_options,
+        }
     }
 
     fn run_in_ns<S, F: FnOnce() -> std::io::Result<S>>(&self, f: F) -> std::io::Result<S> {
@@ -80,19 +91,31 @@ impl InPodSocketFactory {
         }
         Ok(socket)
     }
+
+    // configure_tcp is configure, plus the socket options that only apply to TCP.
+    fn configure_tcp<S: std::os::unix::io::AsFd, F: FnOnce() -> std::io::Result<S>>(
+        &self,
+        f: F,
+    ) -> std::io::Result<S> {
+        let socket = self.configure(f)?;
+        if !self.socket_options.is_empty() {
+            socket::set_socket_options(&socket, &self.socket_options)?;
+        }
+        Ok(socket)
+    }
 }
 
 impl crate::proxy::SocketFactory for InPodSocketFactory {
     fn new_tcp_v4(&self) -> std::io::Result<tokio::net::TcpSocket> {
-        self.configure(|| DefaultSocketFactory.new_tcp_v4())
+        self.configure_tcp(|| DefaultSocketFactory::default().new_tcp_v4())
     }
 
     fn new_tcp_v6(&self) -> std::io::Result<tokio::net::TcpSocket> {
-        self.configure(|| DefaultSocketFactory.new_tcp_v6())
+        self.configure_tcp(|| DefaultSocketFactory::default().new_tcp_v6())
     }
 
     fn tcp_bind(&self, addr: std::net::SocketAddr) -> std::io::Result<socket::Listener> {
-        let std_sock = self.configure(|| std::net::TcpListener::bind(addr))?;
+        let std_sock = self.configure_tcp(|| std::net::TcpListener::bind(addr))?;
         std_sock.set_nonblocking(true)?;
         tokio::net::TcpListener::from_std(std_sock).map(socket::Listener::new)
     }
@@ -104,7 +127,11 @@ impl crate::proxy::SocketFactory for InPodSocketFactory {
     }
 
     fn ipv6_enabled_localhost(&self) -> std::io::Result<bool> {
-        self.run_in_ns(|| DefaultSocketFactory.ipv6_enabled_localhost())
+        self.run_in_ns(|| DefaultSocketFactory::default().ipv6_enabled_localhost())
+    }
+
+    fn netns(&self) -> Option<String> {
+        Some(format!("inode {}", self.netns.workload_inode()))
     }
 }
 
@@ -179,6 +206,10 @@ impl crate::proxy::SocketFactory for InPodSocketPortReuseFactory {
     fn ipv6_enabled_localhost(&self) -> std::io::Result<bool> {
         self.sf.ipv6_enabled_localhost()
     }
+
+    fn netns(&self) -> Option<String> {
+        self.sf.netns()
+    }
 }
 
 #[cfg(test)]
@@ -324,4 +355,28 @@ mod test {
             assert_eq!(sock_ref.mark().unwrap(), 123);
         }
     }
+
+    #[tokio::test]
+    async fn test_inpod_bind_error_names_netns() {
+        let cfg = fixture!();
+
+        let inpod_cfg = InPodConfig::new(&cfg).unwrap();
+        let netns = InpodNetns::new(
+            Arc::new(crate::inpod::netns::InpodNetns::current().unwrap()),
+            new_netns(),
+        )
+        .unwrap();
+        let inode = netns.workload_inode();
+        let sf = inpod_cfg.socket_factory(netns);
+        assert_eq!(sf.netns(), Some(format!("inode {inode}")));
+
+        // The new namespace only has loopback, so any other address cannot be bound
+        let sock_addr: std::net::SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let err = sf.tcp_bind(sock_addr).err().unwrap();
+        let err = crate::proxy::bind_error(sf.as_ref(), sock_addr, err);
+        assert!(matches!(
+            err,
+            crate::proxy::Error::BindAddressNotAssignable(addr, ref ns) if addr == sock_addr && *ns == format!("inode {inode}")
+        ));
+    }
 }
@@ -17,6 +17,7 @@ pub mod app;
 pub mod assertions;
 pub mod baggage;
 pub mod cert_fetcher;
+pub mod cidrs;
 pub mod config;
 pub mod copy;
 pub mod dns;
@@ -14,8 +14,10 @@
 
 use std::fmt::Debug;
 use std::fs::File;
+use std::future::Future;
 use std::io::Read;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 use std::{fmt, io};
@@ -25,36 +27,53 @@ use hickory_proto::error::ProtoError;
 use rand::Rng;
 
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::watch;
 use tokio::time::timeout;
-use tracing::{debug, trace, warn, Instrument};
+use tracing::{debug, info, trace, warn, Instrument};
 
 use inbound::Inbound;
 pub use metrics::*;
+pub use outbound::{effective_route, EffectiveRoute};
 
 use crate::identity::{Identity, SecretManager};
 
 use crate::dns::resolver::Resolver;
 use crate::drain::DrainWatcher;
-use crate::proxy::connection_manager::{ConnectionManager, PolicyWatcher};
+use crate::proxy::connection_manager::{ConnectionBudget, ConnectionManager, PolicyWatcher};
+use crate::proxy::destination_limiter::DestinationLimiter;
+use crate::proxy::hops::HopTracker;
 use crate::proxy::inbound_passthrough::InboundPassthrough;
 use crate::proxy::outbound::Outbound;
+use crate::proxy::port_affinity::PortAffinity;
 use crate::proxy::socks5::Socks5;
 use crate::rbac::Connection;
 use crate::state::service::{endpoint_uid, Service, ServiceDescription};
 use crate::state::workload::address::Address;
-use crate::state::workload::{network_addr, GatewayAddress, Workload};
+use crate::state::workload::{network_addr, GatewayAddress, TrafficClass, Workload};
 use crate::state::{DemandProxyState, WorkloadInfo};
 use crate::{config, identity, socket, tls};
 
+pub mod connect_limiter;
+mod connect_udp;
 pub mod connection_manager;
+pub mod connection_metadata;
+pub mod destination_limiter;
+mod egress;
+mod forward_proxy;
 mod h2;
+mod happy_eyeballs;
+pub mod hops;
 mod inbound;
 mod inbound_passthrough;
 #[allow(non_camel_case_types)]
 pub mod metrics;
 mod outbound;
 pub mod pool;
+pub mod port_affinity;
+pub mod recording;
+mod sniff;
 mod socks5;
+pub mod throttle;
 pub mod util;
 
 pub trait SocketFactory {
@@ -67,29 +86,326 @@ pub trait SocketFactory {
     fn udp_bind(&self, addr: SocketAddr) -> std::io::Result<tokio::net::UdpSocket>;
 
     fn ipv6_enabled_localhost(&self) -> std::io::Result<bool>;
+
+    /// tcp_connect connects a socket created by this factory. Most factories do not need to override this;
+    /// it exists so wrappers can observe or alter outbound connection attempts.
+    fn tcp_connect(
+        &self,
+        socket: TcpSocket,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = std::io::Result<TcpStream>> + Send + '_>> {
+        Box::pin(socket.connect(addr))
+    }
+
+    /// netns describes the network namespace listeners are bound in, for diagnostics. None means ztunnel's
+    /// own namespace.
+    fn netns(&self) -> Option<String> {
+        None
+    }
+
+    /// set_traffic_class tunes an established connection for the traffic class of the workloads it serves.
+    /// Sockets are created for interactive traffic; the class is only known once the workloads are looked up.
+    fn set_traffic_class(&self, stream: &TcpStream, class: TrafficClass) -> std::io::Result<()> {
+        stream.set_nodelay(class.nodelay())
+    }
+
+    /// set_transparent allows a listener to accept connections for addresses that are not its own.
+    fn set_transparent(&self, listener: &socket::Listener) -> std::io::Result<()> {
+        listener.set_transparent()
+    }
+
+    /// set_freebind allows a socket to bind a source IP that is not assigned to this host, so connections can
+    /// keep the original source of the traffic they proxy.
+    fn set_freebind(&self, socket: &TcpSocket) -> std::io::Result<()> {
+        socket::set_freebind_and_transparent(socket)
+    }
+
+    /// bind_egress_device binds a socket for a connection leaving the node to the egress interface, if one is
+    /// configured. Connections to local workloads are not bound, as they do not leave through it.
+    fn bind_egress_device(&self, _socket: &TcpSocket) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// set_correlation_mark stamps the ID of the connection a socket is created for into its mark.
+    fn set_correlation_mark(
+        &self,
+        socket: &TcpSocket,
+        mark: socket::CorrelationMark,
+        id: u64,
+    ) -> std::io::Result<()> {
+        mark.apply(socket, id)
+    }
 }
 
-#[derive(Clone, Copy, Default)]
-pub struct DefaultSocketFactory;
+/// DelegatingSocketFactory is implemented by socket factories that wrap another one. Every SocketFactory
+/// operation is delegated to the wrapped factory, so a wrapper only overrides the operations it changes.
+pub trait DelegatingSocketFactory {
+    fn inner(&self) -> &(dyn SocketFactory + Send + Sync);
+
+    fn new_tcp_v4(&self) -> std::io::Result<TcpSocket> {
+        self.inner().new_tcp_v4()
+    }
+
+    fn new_tcp_v6(&self) -> std::io::Result<TcpSocket> {
+        self.inner().new_tcp_v6()
+    }
+
+    fn tcp_bind(&self, addr: SocketAddr) -> std::io::Result<socket::Listener> {
+        self.inner().tcp_bind(addr)
+    }
+
+    fn udp_bind(&self, addr: SocketAddr) -> std::io::Result<tokio::net::UdpSocket> {
+        self.inner().udp_bind(addr)
+    }
+
+    fn ipv6_enabled_localhost(&self) -> std::io::Result<bool> {
+        self.inner().ipv6_enabled_localhost()
+    }
+
+    fn tcp_connect(
+        &self,
+        socket: TcpSocket,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = std::io::Result<TcpStream>> + Send + '_>> {
+        self.inner().tcp_connect(socket, addr)
+    }
+
+    fn netns(&self) -> Option<String> {
+        self.inner().netns()
+    }
+
+    fn set_traffic_class(&self, stream: &TcpStream, class: TrafficClass) -> std::io::Result<()> {
+        self.inner().set_traffic_class(stream, class)
+    }
+
+    fn set_transparent(&self, listener: &socket::Listener) -> std::io::Result<()> {
+        self.inner().set_transparent(listener)
+    }
+
+    fn set_freebind(&self, socket: &TcpSocket) -> std::io::Result<()> {
+        self.inner().set_freebind(socket)
+    }
+
+    fn bind_egress_device(&self, socket: &TcpSocket) -> std::io::Result<()> {
+        self.inner().bind_egress_device(socket)
+    }
+
+    fn set_correlation_mark(
+        &self,
+        socket: &TcpSocket,
+        mark: socket::CorrelationMark,
+        id: u64,
+    ) -> std::io::Result<()> {
+        self.inner().set_correlation_mark(socket, mark, id)
+    }
+}
+
+impl<T: DelegatingSocketFactory> SocketFactory for T {
+    fn new_tcp_v4(&self) -> std::io::Result<TcpSocket> {
+        DelegatingSocketFactory::new_tcp_v4(self)
+    }
+
+    fn new_tcp_v6(&self) -> std::io::Result<TcpSocket> {
+        DelegatingSocketFactory::new_tcp_v6(self)
+    }
+
+    fn tcp_bind(&self, addr: SocketAddr) -> std::io::Result<socket::Listener> {
+        DelegatingSocketFactory::tcp_bind(self, addr)
+    }
+
+    fn udp_bind(&self, addr: SocketAddr) -> std::io::Result<tokio::net::UdpSocket> {
+        DelegatingSocketFactory::udp_bind(self, addr)
+    }
+
+    fn ipv6_enabled_localhost(&self) -> std::io::Result<bool> {
+        DelegatingSocketFactory::ipv6_enabled_localhost(self)
+    }
+
+    fn tcp_connect(
+        &self,
+        socket: TcpSocket,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = std::io::Result<TcpStream>> + Send + '_>> {
+        DelegatingSocketFactory::tcp_connect(self, socket, addr)
+    }
+
+    fn netns(&self) -> Option<String> {
+        DelegatingSocketFactory::netns(self)
+    }
+
+    fn set_traffic_class(&self, stream: &TcpStream, class: TrafficClass) -> std::io::Result<()> {
+        DelegatingSocketFactory::set_traffic_class(self, stream, class)
+    }
+
+    fn set_transparent(&self, listener: &socket::Listener) -> std::io::Result<()> {
+        DelegatingSocketFactory::set_transparent(self, listener)
+    }
+
+    fn set_freebind(&self, socket: &TcpSocket) -> std::io::Result<()> {
+        DelegatingSocketFactory::set_freebind(self, socket)
+    }
+
+    fn bind_egress_device(&self, socket: &TcpSocket) -> std::io::Result<()> {
+        DelegatingSocketFactory::bind_egress_device(self, socket)
+    }
+
+    fn set_correlation_mark(
+        &self,
+        socket: &TcpSocket,
+        mark: socket::CorrelationMark,
+        id: u64,
+    ) -> std::io::Result<()> {
+        DelegatingSocketFactory::set_correlation_mark(self, socket, mark, id)
+    }
+}
+
+// bind_error describes a failure to bind a listener on `addr`. When the socket factory is scoped to another
+// network namespace, such as a pod's in inpod mode, the error names it; an address that is not assigned in that
+// namespace usually means the listener addresses do not match the pod.
+pub(super) fn bind_error(
+    socket_factory: &(dyn SocketFactory + Send + Sync),
+    addr: SocketAddr,
+    err: io::Error,
+) -> Error {
+    match socket_factory.netns() {
+        Some(netns) if crate::socket::raw_os_error(&err) == Some(libc::EADDRNOTAVAIL) => {
+            Error::BindAddressNotAssignable(addr, netns)
+        }
+        Some(netns) => Error::BindInNetns(addr, netns, err),
+        None => Error::Bind(addr, err),
+    }
+}
+
+// set_traffic_class applies the traffic class to the plaintext sockets of a proxied connection. This is only
+// tuning, so failures are logged rather than failing the connection.
+pub(super) fn set_traffic_class(
+    socket_factory: &(dyn SocketFactory + Send + Sync),
+    class: TrafficClass,
+    streams: &[&TcpStream],
+) {
+    if class == TrafficClass::default() {
+        // Already the case for new and accepted sockets
+        return;
+    }
+    for stream in streams {
+        if let Err(err) = socket_factory.set_traffic_class(stream, class) {
+            debug!(?class, "failed to set traffic class: {err}");
+        }
+    }
+}
+
+// set_rejection_close arranges for the client's connection to be closed as configured once it is dropped, if
+// `err` rejected it. Errors are only logged; the connection is closed either way.
+pub(super) fn set_rejection_close(cfg: &config::Config, stream: &TcpStream, err: &Error) {
+    if cfg.rejection_close_mode != config::RejectionCloseMode::Rst || !err.is_rejection() {
+        return;
+    }
+    if let Err(e) = socket::set_abortive_close(stream) {
+        debug!("failed to set rejection close mode: {e}");
+    }
+}
+
+// for_connection returns the socket factory to connect upstream for a single connection. If correlation marks
+// are configured, the sockets it creates carry the connection's ID in their mark.
+pub(super) fn for_connection<'a>(
+    pi: &'a ProxyInputs,
+    conn_id: ConnectionId,
+) -> CorrelatedSocketFactory<'a> {
+    CorrelatedSocketFactory {
+        inner: pi.socket_factory.as_ref(),
+        mark: socket::CorrelationMark::new(pi.cfg.connection_correlation_mark_mask),
+        conn_id,
+    }
+}
+
+// mark_accepted stamps the connection ID into the mark of a socket we accepted, so both sides of the connection
+// can be correlated. This only aids debugging, so failures are logged rather than failing the connection.
+pub(super) fn mark_accepted(cfg: &config::Config, stream: &TcpStream, conn_id: ConnectionId) {
+    if let Some(mark) = socket::CorrelationMark::new(cfg.connection_correlation_mark_mask) {
+        if let Err(err) = mark.apply(stream, conn_id.as_u64()) {
+            debug!(%conn_id, "failed to set correlation mark: {err}");
+        }
+    }
+}
+
+/// CorrelatedSocketFactory wraps a SocketFactory, stamping a connection's ID into the mark of the TCP sockets it
+/// creates. See socket::CorrelationMark for the layout of the mark.
+pub struct CorrelatedSocketFactory<'a> {
+    inner: &'a (dyn SocketFactory + Send + Sync),
+    mark: Option<socket::CorrelationMark>,
+    conn_id: ConnectionId,
+}
+
+impl CorrelatedSocketFactory<'_> {
+    // stamp sets the correlation mark of a new socket. As for accepted sockets, a failure, such as when running
+    // without CAP_NET_ADMIN, is only logged; the connection is made without the mark.
+    fn stamp(&self, socket: TcpSocket) -> TcpSocket {
+        if let Some(mark) = self.mark {
+            let conn_id = self.conn_id;
+            if let Err(err) = self
+                .inner
+                .set_correlation_mark(&socket, mark, conn_id.as_u64())
+            {
+                debug!(%conn_id, "failed to set correlation mark: {err}");
+            }
+        }
+        socket
+    }
+}
+
+impl DelegatingSocketFactory for CorrelatedSocketFactory<'_> {
+    fn inner(&self) -> &(dyn SocketFactory + Send + Sync) {
+        self.inner
+    }
+
+    fn new_tcp_v4(&self) -> std::io::Result<TcpSocket> {
+        self.inner.new_tcp_v4().map(|s| self.stamp(s))
+    }
+
+    fn new_tcp_v6(&self) -> std::io::Result<TcpSocket> {
+        self.inner.new_tcp_v6().map(|s| self.stamp(s))
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct DefaultSocketFactory {
+    // If set, sockets for connections leaving the node are bound to this interface with SO_BINDTODEVICE.
+    egress_interface: Option<String>,
+    options: socket::SocketOptions,
+}
+
+impl DefaultSocketFactory {
+    pub fn new(cfg: &config::Config) -> Self {
+        Self {
+            egress_interface: cfg.egress_interface.clone(),
+            options: socket::SocketOptions::new(cfg),
+        }
+    }
+
+    fn setup_socket(&self, s: TcpSocket) -> std::io::Result<TcpSocket> {
+        s.set_nodelay(true)?;
+        if !self.options.is_empty() {
+            socket::set_socket_options(&s, &self.options)?;
+        }
+        Ok(s)
+    }
+}
 
 impl SocketFactory for DefaultSocketFactory {
     fn new_tcp_v4(&self) -> std::io::Result<TcpSocket> {
-        TcpSocket::new_v4().and_then(|s| {
-            s.set_nodelay(true)?;
-            Ok(s)
-        })
+        TcpSocket::new_v4().and_then(|s| self.setup_socket(s))
     }
 
     fn new_tcp_v6(&self) -> std::io::Result<TcpSocket> {
-        TcpSocket::new_v6().and_then(|s| {
-            s.set_nodelay(true)?;
-            Ok(s)
-        })
+        TcpSocket::new_v6().and_then(|s| self.setup_socket(s))
     }
 
     fn tcp_bind(&self, addr: SocketAddr) -> std::io::Result<socket::Listener> {
         let std_sock = std::net::TcpListener::bind(addr)?;
         std_sock.set_nonblocking(true)?;
+        if !self.options.is_empty() {
+            socket::set_socket_options(&std_sock, &self.options)?;
+        }
         TcpListener::from_std(std_sock).map(socket::Listener::new)
     }
 
@@ -102,13 +418,29 @@ impl SocketFactory for DefaultSocketFactory {
     fn ipv6_enabled_localhost(&self) -> io::Result<bool> {
         ipv6_disabled_on_localhost()
     }
+
+    fn bind_egress_device(&self, socket: &TcpSocket) -> std::io::Result<()> {
+        let Some(interface) = &self.egress_interface else {
+            return Ok(());
+        };
+        // Binding to a device requires elevated permissions; rather than failing the connection we
+        // continue with the default routing.
+        match socket::set_bind_device(socket, interface) {
+            Ok(()) => trace!(device = %interface, "bound socket to device"),
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                warn!(device = %interface, "failed to bind socket to device: {err}")
+            }
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
 }
 
 pub struct Proxy {
     inbound: Inbound,
     inbound_passthrough: InboundPassthrough,
     outbound: Outbound,
-    socks5: Option<Socks5>,
+    socks5: Vec<Socks5>,
     policy_watcher: PolicyWatcher,
 }
 
@@ -167,6 +499,14 @@ pub(super) struct ProxyInputs {
     socket_factory: Arc<dyn SocketFactory + Send + Sync>,
     proxy_workload_info: Option<Arc<WorkloadInfo>>,
     resolver: Option<Arc<dyn Resolver + Send + Sync>>,
+    // If set, notifies of configuration reloads, which apply to new connections.
+    config_updates: Option<watch::Receiver<Arc<config::Config>>>,
+    destination_limiter: Arc<DestinationLimiter>,
+    // Source ports of closed passthrough connections, for OUTBOUND_PORT_REUSE. These are only meaningful within
+    // one network namespace, so each proxy has its own.
+    port_affinity: Arc<PortAffinity>,
+    // How many ztunnels the connections inbound delivered locally have traversed, to detect proxy loops.
+    hops: Arc<HopTracker>,
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -180,11 +520,14 @@ impl ProxyInputs {
         socket_factory: Arc<dyn SocketFactory + Send + Sync>,
         proxy_workload_info: Option<WorkloadInfo>,
         resolver: Option<Arc<dyn Resolver + Send + Sync>>,
+        config_updates: Option<watch::Receiver<Arc<config::Config>>>,
+        destination_limiter: Arc<DestinationLimiter>,
     ) -> Arc<Self> {
         let proxy_workload_info = proxy_workload_info.map(Arc::new);
+        let port_affinity = Arc::new(PortAffinity::new(&metrics));
         Arc::new(Self {
+            state: state.with_static_hosts(cfg.static_hosts.clone()),
             cfg,
-            state,
             cert_manager: ScopedSecretManager {
                 cert_manager,
                 allowed: proxy_workload_info.clone(),
@@ -194,8 +537,51 @@ impl ProxyInputs {
             socket_factory,
             proxy_workload_info,
             resolver,
+            config_updates,
+            destination_limiter,
+            port_affinity,
+            hops: Default::default(),
         })
     }
+
+    /// resolver returns the resolver for hostname destinations. It is only available with the DNS proxy.
+    pub(super) fn resolver(&self) -> Result<Arc<dyn Resolver + Send + Sync>, Error> {
+        self.resolver
+            .clone()
+            .ok_or_else(|| Error::UnsupportedFeature("DNS resolution disabled".to_string()))
+    }
+
+    /// refresh updates `pi` to use the latest configuration, if it was reloaded.
+    /// Connections capture the ProxyInputs when they are accepted, so in-flight connections keep the
+    /// configuration they started with.
+    pub(super) fn refresh(pi: &mut Arc<ProxyInputs>) {
+        let Some(updates) = &pi.config_updates else {
+            return;
+        };
+        if !updates.has_changed().unwrap_or(false) {
+            return;
+        }
+        let mut updates = updates.clone();
+        let cfg = updates.borrow_and_update().clone();
+        debug!("using reloaded configuration for new connections");
+        *pi = Arc::new(ProxyInputs {
+            state: pi.state.clone().with_static_hosts(cfg.static_hosts.clone()),
+            cfg,
+            config_updates: Some(updates),
+            ..(**pi).clone()
+        });
+    }
+
+    /// wait_for_state holds a new connection until the initial XDS sync completes, or rejects it, according to
+    /// the startup connection policy.
+    pub(super) async fn wait_for_state(&self) -> Result<(), Error> {
+        self.state
+            .wait_for_sync(
+                self.cfg.startup_connection_policy,
+                self.cfg.startup_hold_timeout,
+            )
+            .await
+    }
 }
 
 impl Proxy {
@@ -208,17 +594,22 @@ impl Proxy {
         resolver: Option<Arc<dyn Resolver + Send + Sync>>,
     ) -> Result<Proxy, Error> {
         let metrics = Arc::new(metrics);
-        let socket_factory = Arc::new(DefaultSocketFactory);
+        let socket_factory = Arc::new(DefaultSocketFactory::new(&cfg));
+        let destination_limiter = Arc::new(DestinationLimiter::new(&metrics));
+        let connection_manager = ConnectionManager::default()
+            .with_connection_budget(ConnectionBudget::from_config(&cfg, &metrics));
 
         let pi = ProxyInputs::new(
             cfg,
             cert_manager,
-            ConnectionManager::default(),
+            connection_manager,
             state,
             metrics,
             socket_factory,
             None,
             resolver,
+            None,
+            destination_limiter,
         );
         Self::from_inputs(pi, drain).await
     }
@@ -228,8 +619,12 @@ impl Proxy {
         mut pi: Arc<ProxyInputs>,
         drain: DrainWatcher,
     ) -> Result<Self, Error> {
+        pi.cfg.validate().map_err(config::Error::Invalid)?;
         // We setup all the listeners first so we can capture any errors that should block startup
-        let inbound = Inbound::new(pi.clone(), drain.clone()).await?;
+        let inbound_addrs: Vec<SocketAddr> = std::iter::once(pi.cfg.inbound_addr)
+            .chain(pi.cfg.inbound_extra_addrs.iter().copied())
+            .collect();
+        let inbound = Inbound::new(pi.clone(), &inbound_addrs, drain.clone()).await?;
 
         // This exists for `direct` integ tests, no other reason
         #[cfg(any(test, feature = "testing"))]
@@ -245,14 +640,24 @@ impl Proxy {
 
         let inbound_passthrough = InboundPassthrough::new(pi.clone(), drain.clone()).await?;
         let outbound = Outbound::new(pi.clone(), drain.clone()).await?;
-        let socks5 = if pi.cfg.socks5_addr.is_some() {
-            let socks5 = Socks5::new(pi.clone(), drain.clone()).await?;
-            Some(socks5)
-        } else {
-            None
-        };
+        let mut socks5 = Vec::new();
+        if let Some(addr) = pi.cfg.socks5_addr {
+            socks5.push(Socks5::new(pi.clone(), addr, Default::default(), drain.clone()).await?);
+        }
+        for listener in &pi.cfg.socks5_listeners {
+            socks5.push(
+                Socks5::new(
+                    pi.clone(),
+                    listener.addr,
+                    listener.scope.clone(),
+                    drain.clone(),
+                )
+                .await?,
+            );
+        }
         let policy_watcher =
-            PolicyWatcher::new(pi.state.clone(), drain, pi.connection_manager.clone());
+            PolicyWatcher::new(pi.state.clone(), drain, pi.connection_manager.clone())
+                .with_grace_period(pi.cfg.policy_change_grace, &pi.metrics);
 
         Ok(Proxy {
             inbound,
@@ -271,9 +676,9 @@ impl Proxy {
             tokio::spawn(self.outbound.run().in_current_span()),
         ];
 
-        if let Some(socks5) = self.socks5 {
+        for socks5 in self.socks5 {
             tasks.push(tokio::spawn(socks5.run().in_current_span()));
-        };
+        }
 
         futures::future::join_all(tasks).await;
     }
@@ -282,23 +687,38 @@ impl Proxy {
         Addresses {
             outbound: self.outbound.address(),
             inbound: self.inbound.address(),
-            socks5: self.socks5.as_ref().map(|s| s.address()),
+            inbound_extra: self.inbound.extra_addresses(),
+            socks5: self.socks5.iter().map(|s| s.address()).collect(),
         }
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct Addresses {
     pub outbound: SocketAddr,
     pub inbound: SocketAddr,
-    pub socks5: Option<SocketAddr>,
+    /// Addresses of the additional inbound listeners, if any.
+    pub inbound_extra: Vec<SocketAddr>,
+    /// Addresses of the SOCKS5 listeners, if any. The unscoped listener, if enabled, is first.
+    pub socks5: Vec<SocketAddr>,
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("failed to bind to address {0}: {1}")]
+    #[error("failed to bind to address {0}: {}", socket::describe("bind", .1))]
     Bind(SocketAddr, io::Error),
 
+    #[error("failed to bind to address {0} in network namespace {1}: {}", socket::describe("bind", .2))]
+    BindInNetns(SocketAddr, String, io::Error),
+
+    #[error(
+        "failed to bind to address {0}: not assigned to any interface in network namespace {1}"
+    )]
+    BindAddressNotAssignable(SocketAddr, String),
+
+    #[error("{0}")]
+    InvalidConfig(#[from] config::Error),
+
     #[error("io error: {0}")]
     Io(#[from] io::Error),
 
@@ -315,14 +735,27 @@ pub enum Error {
     #[error("send: {0}")]
     SendError(Box<Error>),
 
-    #[error("connection failed: {0}")]
+    #[error("connection failed: {}", socket::describe("connect", .0))]
     ConnectionFailed(io::Error),
 
+    #[error("failed to connect to forward proxy: {}", socket::describe("connect", .0))]
+    ForwardProxyConnect(io::Error),
+    #[error("forward proxy rejected CONNECT with status {0}")]
+    ForwardProxyRejected(u16),
+    #[error("invalid response from forward proxy: {0}")]
+    ForwardProxyResponse(String),
+
     #[error("connection closed due to policy change")]
     AuthorizationPolicyLateRejection,
 
+    #[error("connection closed after reaching its maximum lifetime")]
+    MaxLifetimeReached,
+
+    #[error("connection closed by an operator drain")]
+    DrainedBySelector,
+
     #[error("connection closed due to policy rejection")]
-    AuthorizationPolicyRejection,
+    AuthorizationPolicyRejection(crate::rbac::RbacDenial),
 
     #[error("pool is already connecting")]
     WorkloadHBONEPoolAlreadyConnecting,
@@ -354,14 +787,57 @@ pub enum Error {
     #[error("tls error: {0}")]
     Tls(#[from] tls::Error),
 
+    #[error("tls handshake failed: {0}")]
+    TlsHandshake(io::Error),
+
     #[error("identity error: {0}")]
     Identity(#[from] identity::Error),
 
     #[error("unknown source: {0}")]
     UnknownSource(IpAddr),
 
-    #[error("invalid source: {0}, should match {1:?}")]
-    MismatchedSource(IpAddr, Arc<WorkloadInfo>),
+    #[error("anonymous source {0} cannot reach {1}, which is behind a waypoint")]
+    AnonymousSourceToWaypoint(IpAddr, SocketAddr),
+
+    #[error("HBONE is required for destination workload {0}, but it does not support HBONE")]
+    HboneRequired(Strng),
+
+    #[error(
+        "inbound traffic to workload {0} must arrive over HBONE, rejecting plaintext connection"
+    )]
+    PlaintextInboundRejected(Strng),
+
+    #[error("request deadline exceeded")]
+    DeadlineExceeded,
+
+    #[error("proxy state is not yet synced from XDS; retry later")]
+    NotReady,
+
+    #[error(
+        "proxy loop detected: connection has traversed {0} ztunnels, exceeding the limit of {1}"
+    )]
+    ProxyLoopDetected(u8, u8),
+
+    #[error("invalid TLS ClientHello: {0}")]
+    InvalidClientHello(&'static str),
+
+    #[error("egress to {0} is not allowed")]
+    EgressDenied(String),
+
+    #[error("failed to resolve {0}")]
+    ResolveHostname(String),
+
+    #[error("tls origination to {0} failed: server certificate is invalid: {1}")]
+    TlsOriginationCertificate(String, rustls::CertificateError),
+
+    #[error("tls origination to {0} failed: {1}")]
+    TlsOrigination(String, tls::OriginationError),
+
+    #[error("mirror fell behind or the client disconnected before the end of the stream")]
+    MirrorAbandoned,
+
+    #[error("invalid source: {0} ({}), should match {}", .1.log_display(), .2.log_display())]
+    MismatchedSource(IpAddr, Identity, Identity),
 
     #[error("unknown waypoint: {0}")]
     UnknownWaypoint(String),
@@ -375,6 +851,12 @@ pub enum Error {
     #[error("no healthy upstream: {0}")]
     NoHealthyUpstream(SocketAddr),
 
+    #[error("connection budget exhausted")]
+    ConnectionBudgetExhausted,
+
+    #[error("destination service {0} is overloaded: {1} connections already open")]
+    DestinationOverloaded(String, usize),
+
     #[error("no ip addresses were resolved for workload: {0}")]
     NoResolvedAddresses(String),
 
@@ -392,6 +874,9 @@ pub enum Error {
     #[error("unsupported feature: {0}")]
     UnsupportedFeature(String),
 
+    #[error("invalid capsule")]
+    InvalidCapsule,
+
     #[error("ip mismatch: {0} != {1}")]
     IPMismatch(IpAddr, IpAddr),
 
@@ -403,6 +888,9 @@ pub enum Error {
     #[error("connection closed due to connection drain")]
     ClosedFromDrain,
 
+    #[error("client reset the connection during setup")]
+    ClientGone,
+
     #[error("dns: {0}")]
     Dns(#[from] ProtoError),
     #[error("dns lookup: {0}")]
@@ -411,29 +899,152 @@ pub enum Error {
     DnsEmpty,
 }
 
+impl Error {
+    /// setup_failure_stage classifies an error that failed an outbound connection's setup by the stage it failed
+    /// in. Errors that do not say where setup failed, such as policy denials, are classified as other.
+    pub fn setup_failure_stage(&self) -> SetupFailureStage {
+        match self {
+            Error::NoResolvedAddresses(_)
+            | Error::EmptyResolvedAddresses(_)
+            | Error::ResolveHostname(_)
+            | Error::Dns(_)
+            | Error::DnsLookup(_)
+            | Error::DnsEmpty => SetupFailureStage::dns_resolution,
+            Error::NoHealthyUpstream(_)
+            | Error::NoValidDestination(_)
+            | Error::UnknownDestination(_)
+            | Error::UnknownWaypoint(_)
+            | Error::NoGatewayAddress(_) => SetupFailureStage::endpoint_selection,
+            Error::ConnectionFailed(_)
+            | Error::ForwardProxyConnect(_)
+            | Error::ForwardProxyRejected(_)
+            | Error::ForwardProxyResponse(_) => SetupFailureStage::tcp_connect,
+            Error::TlsHandshake(_)
+            | Error::TlsOriginationCertificate(..)
+            | Error::TlsOrigination(..) => SetupFailureStage::tls_handshake,
+            Error::Http2Handshake(_)
+            | Error::H2(_)
+            | Error::HttpStatus(_)
+            | Error::WorkloadHBONEPoolAlreadyConnecting
+            | Error::WorkloadHBONEPoolConnStreamsMaxed
+            | Error::WorkloadHBONEPoolDraining => SetupFailureStage::hbone_connect,
+            _ => SetupFailureStage::other,
+        }
+    }
+
+    /// is_rejection reports whether the connection was refused on purpose, by our policy or limits or by the
+    /// destination's ztunnel, rather than failing.
+    pub fn is_rejection(&self) -> bool {
+        matches!(
+            self,
+            Error::AuthorizationPolicyRejection(_)
+                | Error::PlaintextInboundRejected(_)
+                | Error::EgressDenied(_)
+                | Error::ConnectionBudgetExhausted
+                | Error::DestinationOverloaded(..)
+                | Error::HttpStatus(http::StatusCode::FORBIDDEN)
+        )
+    }
+}
+
 const PROXY_PROTOCOL_AUTHORITY_TLV: u8 = 0xD0;
+// The hostname of the service the client targeted. Only sent if the client addressed a service, rather than
+// the workload directly.
+const PROXY_PROTOCOL_SERVICE_TLV: u8 = 0xD1;
 
 pub async fn write_proxy_protocol<T>(
     stream: &mut TcpStream,
     addresses: T,
     src_id: Option<Identity>,
+    dst_service: Option<&str>,
 ) -> io::Result<()>
 where
     T: Into<ppp::v2::Addresses> + std::fmt::Debug,
 {
-    use ppp::v2::{Builder, Command, Protocol, Version};
     use tokio::io::AsyncWriteExt;
 
     debug!("writing proxy protocol addresses: {:?}", addresses);
+    let header = proxy_protocol_header(addresses, src_id, dst_service)?;
+    stream.write_all(&header).await
+}
+
+fn proxy_protocol_header<T>(
+    addresses: T,
+    src_id: Option<Identity>,
+    dst_service: Option<&str>,
+) -> io::Result<Vec<u8>>
+where
+    T: Into<ppp::v2::Addresses>,
+{
+    use ppp::v2::{Builder, Command, Protocol, Version};
+
     let mut builder =
         Builder::with_addresses(Version::Two | Command::Proxy, Protocol::Stream, addresses);
 
     if let Some(id) = src_id {
-        builder = builder.write_tlv(PROXY_PROTOCOL_AUTHORITY_TLV, id.to_string().as_bytes())?;
+        let id = id.to_string();
+        check_proxy_protocol_tlv("source identity", id.as_bytes())?;
+        builder = builder.write_tlv(PROXY_PROTOCOL_AUTHORITY_TLV, id.as_bytes())?;
+    }
+    if let Some(svc) = dst_service {
+        check_proxy_protocol_tlv("destination service", svc.as_bytes())?;
+        builder = builder.write_tlv(PROXY_PROTOCOL_SERVICE_TLV, svc.as_bytes())?;
     }
 
     let header = builder.build()?;
-    stream.write_all(&header).await
+    // The length field covers the addresses and all TLVs, so they must fit together as well as individually.
+    if header.len() - PROXY_PROTOCOL_V2_PREFIX_LEN > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "PROXY protocol header of {} bytes exceeds the maximum length",
+                header.len()
+            ),
+        ));
+    }
+    Ok(header)
+}
+
+// The signature, version/command, family/protocol and length, which are not counted in the length field.
+const PROXY_PROTOCOL_V2_PREFIX_LEN: usize = 16;
+
+// check_proxy_protocol_tlv ensures a TLV value fits in its 16 bit length field. The backend trusts these
+// values, so we fail the connection rather than send a truncated or misframed header.
+fn check_proxy_protocol_tlv(name: &str, value: &[u8]) -> io::Result<()> {
+    if value.len() > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{name} of {} bytes is too long for a PROXY protocol TLV",
+                value.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// ConnectionId identifies an accepted connection in the logs, access log and connection dump, so everything
+/// recorded about it can be correlated. IDs are assigned from a counter, so they are unique for the life of the
+/// process and cost a single atomic increment.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, serde::Serialize)]
+#[serde(transparent)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    pub fn next() -> Self {
+        static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+        ConnectionId(NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
 /// Represents a traceparent, as defined by https://www.w3.org/TR/trace-context/
@@ -447,12 +1058,16 @@ pub struct TraceParent {
 
 pub const BAGGAGE_HEADER: &str = "baggage";
 pub const TRACEPARENT_HEADER: &str = "traceparent";
+// The number of ztunnels a connection has traversed, including the sender. Only trusted over HBONE.
+pub const HOPS_HEADER: &str = "ztunnel-hops";
 
 impl TraceParent {
     pub fn header(&self) -> hyper::header::HeaderValue {
         hyper::header::HeaderValue::from_bytes(format!("{self:?}").as_bytes()).unwrap()
     }
 }
+const TRACE_FLAG_SAMPLED: u8 = 0x01;
+
 impl TraceParent {
     fn new() -> Self {
         let mut rng = rand::thread_rng();
@@ -463,6 +1078,18 @@ impl TraceParent {
             flags: 0,
         }
     }
+
+    /// with_sampling marks the trace as sampled for the given percentage of calls.
+    fn with_sampling(mut self, percentage: u8) -> Self {
+        if percentage > 0 && rand::thread_rng().gen_ratio(percentage.min(100).into(), 100) {
+            self.flags |= TRACE_FLAG_SAMPLED;
+        }
+        self
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & TRACE_FLAG_SAMPLED != 0
+    }
 }
 
 impl fmt::Debug for TraceParent {
@@ -507,7 +1134,7 @@ pub(super) fn maybe_set_transparent(
     Ok(match pi.cfg.require_original_source {
         Some(true) => {
             // Explicitly enabled. Return error if we cannot set it.
-            listener.set_transparent()?;
+            pi.socket_factory.set_transparent(listener)?;
             true
         }
         Some(false) => {
@@ -515,8 +1142,18 @@ pub(super) fn maybe_set_transparent(
             false
         }
         None => {
-            // Best effort
-            listener.set_transparent().is_ok()
+            // Best effort. The result decides whether connections from this listener keep their original
+            // source, so without it we never attempt to bind one, which would only fail again for every connection.
+            match pi.socket_factory.set_transparent(listener) {
+                Ok(()) => true,
+                Err(err) => {
+                    info!(
+                        address=%listener.local_addr(),
+                        "original source disabled, listener cannot be made transparent: {err}"
+                    );
+                    false
+                }
+            }
         }
     })
 }
@@ -527,105 +1164,329 @@ pub fn get_original_src_from_stream(stream: &TcpStream) -> Option<IpAddr> {
         .map_or(None, |sa| Some(socket::to_canonical(sa).ip()))
 }
 
-const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+// original_source_ip is the IP a connection from peer to dest keeps as its source. Usually that is just the peer IP,
+// but a source workload with several IPs may be configured to select one by the destination instead.
+pub fn original_source_ip(
+    cfg: &config::Config,
+    source: Option<&Workload>,
+    peer: IpAddr,
+    dest: IpAddr,
+) -> IpAddr {
+    match (cfg.source_ip_selection, source) {
+        // Only select among the IPs of the workload the connection actually came from.
+        (config::SourceIpSelection::MatchDestination, Some(wl))
+            if wl.workload_ips.contains(&peer.to_canonical()) =>
+        {
+            select_source_ip(&wl.workload_ips, dest, cfg.source_ip_subnet_prefixes).unwrap_or(peer)
+        }
+        _ => peer,
+    }
+}
 
+// select_source_ip picks the candidate to connect to dest from: the first one in dest's subnet, otherwise the
+// first in dest's family. If none are in dest's family, the first candidate is returned, which freebind_connect
+// cannot bind, so it connects from our own address instead.
+fn select_source_ip(
+    candidates: &[IpAddr],
+    dest: IpAddr,
+    prefixes: config::SubnetPrefixes,
+) -> Option<IpAddr> {
+    let dest = dest.to_canonical();
+    let same_subnet = |ip: &IpAddr| match (ip.to_canonical(), dest) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            (u32::from(a) ^ u32::from(b)).leading_zeros() >= prefixes.v4 as u32
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            (u128::from(a) ^ u128::from(b)).leading_zeros() >= prefixes.v6 as u32
+        }
+        _ => false,
+    };
+    let same_family = |ip: &IpAddr| ip.to_canonical().is_ipv4() == dest.is_ipv4();
+    candidates
+        .iter()
+        .find(|ip| same_subnet(ip))
+        .or_else(|| candidates.iter().find(|ip| same_family(ip)))
+        .or_else(|| candidates.first())
+        .copied()
+}
+
+/// ConnectOptions are how freebind_connect makes a connection, beyond its source and destination. By default,
+/// TCP Fast Open and port reuse are not used, the original source applies to every destination, self connects
+/// are made from our own address, and the connection is not bound to the egress interface.
+#[derive(Clone, Copy, Default)]
+pub struct ConnectOptions<'a> {
+    // If set, TCP Fast Open is enabled, and failures to enable it are recorded in these metrics.
+    fast_open: Option<&'a Metrics>,
+    // If set, the source port of a previous connection to the destination is reused, if there is one.
+    port_reuse: Option<&'a PortAffinity>,
+    // What happens if the source IP is the destination IP.
+    self_connect: config::SelfConnectMode,
+    // The destinations the original source IP is kept for; all of them if unset.
+    original_src: Option<&'a config::OriginalSourceCidrs>,
+    // Whether the connection leaves the node, and so is bound to the egress interface.
+    egress: bool,
+}
+
+impl<'a> ConnectOptions<'a> {
+    /// from_config applies the configured self connect mode and original source CIDRs.
+    pub fn from_config(cfg: &'a config::Config) -> Self {
+        ConnectOptions {
+            self_connect: cfg.self_connect_mode,
+            original_src: Some(&cfg.original_source_cidrs),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_fast_open(mut self, metrics: Option<&'a Metrics>) -> Self {
+        self.fast_open = metrics;
+        self
+    }
+
+    pub fn with_port_reuse(mut self, port_reuse: Option<&'a PortAffinity>) -> Self {
+        self.port_reuse = port_reuse;
+        self
+    }
+
+    pub fn with_self_connect(mut self, self_connect: config::SelfConnectMode) -> Self {
+        self.self_connect = self_connect;
+        self
+    }
+
+    pub fn with_original_src(mut self, original_src: &'a config::OriginalSourceCidrs) -> Self {
+        self.original_src = Some(original_src);
+        self
+    }
+
+    /// egress marks the connection as leaving the node, rather than going to a local workload.
+    pub fn egress(mut self) -> Self {
+        self.egress = true;
+        self
+    }
+}
+
+// freebind_connect connects to addr, using local as the source IP if possible and the original source applies to
+// addr. See ConnectOptions for the rest of how the connection is made.
 pub async fn freebind_connect(
     local: Option<IpAddr>,
     addr: SocketAddr,
+    connect_timeout: Duration,
     socket_factory: &(dyn SocketFactory + Send + Sync),
-) -> io::Result<TcpStream> {
-    async fn connect(
+    opts: ConnectOptions<'_>,
+) -> io::Result<(TcpStream, SourceBinding)> {
+    // connect_from_port connects from port, when reusing one; otherwise the kernel picks the source port.
+    async fn connect_from_port(
         local: Option<IpAddr>,
         addr: SocketAddr,
         socket_factory: &(dyn SocketFactory + Send + Sync),
-    ) -> io::Result<TcpStream> {
+        opts: &ConnectOptions<'_>,
+        port: Option<u16>,
+    ) -> io::Result<(TcpStream, SourceBinding)> {
         let create_socket = |is_ipv4: bool| {
-            if is_ipv4 {
+            let socket = if is_ipv4 {
                 socket_factory.new_tcp_v4()
             } else {
                 socket_factory.new_tcp_v6()
+            }?;
+            if opts.egress {
+                socket_factory.bind_egress_device(&socket)?;
+            }
+            if let Some(metrics) = opts.fast_open {
+                // TFO is only an optimization, so connect normally if the kernel does not support it.
+                if let Err(err) = socket::set_fastopen_connect(&socket) {
+                    debug!(dest=%addr, "failed to enable TCP fast open: {err}");
+                    metrics.tfo_fallbacks.inc();
+                }
             }
+            if port.is_some() {
+                // The previous connection from the port may still be in TIME_WAIT.
+                socket.set_reuseaddr(true)?;
+            }
+            Ok::<_, io::Error>(socket)
+        };
+        // bind_port binds the port to reuse, if any, when not binding a source IP.
+        let bind_port = |socket: &TcpSocket| match port {
+            Some(port) if addr.is_ipv4() => {
+                socket.bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+            }
+            Some(port) => socket.bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))),
+            None => Ok(()),
         };
 
         // we don't need original src with inpod outbound mode.
         // we do need it in inbound and inbound passthrough TODO: refactor so this is derived from config
         // local = None; // commented out for now as we only want to disable this in inpod + outbound mode
 
+        // The workload was load balanced to itself. Unless configured otherwise, we use the ztunnel addr instead,
+        // otherwise the app side will be confused.
+        if let Some(src) = local.filter(|src| *src == socket::to_canonical(addr).ip()) {
+            match opts.self_connect {
+                config::SelfConnectMode::ZtunnelAddr => {
+                    let socket = create_socket(addr.is_ipv4())?;
+                    bind_port(&socket)?;
+                    trace!(%src, dest=%addr, "dest and source are the same, connect directly");
+                    return Ok((
+                        socket_factory.tcp_connect(socket, addr).await?,
+                        SourceBinding::not_configured,
+                    ));
+                }
+                config::SelfConnectMode::Reject => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        format!(
+                            "destination {addr} is the same as the source, rejecting self connect"
+                        ),
+                    ));
+                }
+                config::SelfConnectMode::OriginalSrc => {}
+            }
+        }
+
         match local {
             None => {
                 let socket = create_socket(addr.is_ipv4())?;
+                bind_port(&socket)?;
                 trace!(dest=%addr, "no local address, connect directly");
-                Ok(socket.connect(addr).await?)
+                Ok((
+                    socket_factory.tcp_connect(socket, addr).await?,
+                    SourceBinding::none,
+                ))
             }
-            // TODO: Need figure out how to handle case of loadbalancing to itself.
-            //       We use ztunnel addr instead, otherwise app side will be confused.
-            Some(src) if src == socket::to_canonical(addr).ip() => {
+            // The destination may be in a different IP family than the source, such as when a workload prefers
+            // IPv6 destinations but connected to us over IPv4. We cannot bind the source IP then.
+            Some(src) if src.is_ipv4() != socket::to_canonical(addr).ip().is_ipv4() => {
                 let socket = create_socket(addr.is_ipv4())?;
-                trace!(%src, dest=%addr, "dest and source are the same, connect directly");
-                Ok(socket.connect(addr).await?)
+                bind_port(&socket)?;
+                trace!(%src, dest=%addr, "dest and source IP families differ, connect directly");
+                Ok((
+                    socket_factory.tcp_connect(socket, addr).await?,
+                    SourceBinding::not_configured,
+                ))
             }
             Some(src) => {
+                // Note: if the socket was bound to the egress interface, that still applies;
+                // the source IP binding below only selects the address used on that interface.
                 let socket = create_socket(src.is_ipv4())?;
-                let local_addr = SocketAddr::new(src, 0);
-                match socket::set_freebind_and_transparent(&socket) {
-                    Err(err) => warn!("failed to set freebind: {:?}", err),
-                    _ => {
-                        if let Err(err) = socket.bind(local_addr) {
-                            warn!("failed to bind local addr: {:?}", err)
-                        }
+                let local_addr = SocketAddr::new(src, port.unwrap_or(0));
+                let binding = match socket_factory.set_freebind(&socket) {
+                    Err(err) => {
+                        warn!("failed to set freebind: {err}");
+                        bind_port(&socket)?;
+                        SourceBinding::bind_failed
                     }
+                    _ => match socket.bind(local_addr) {
+                        Ok(()) => SourceBinding::original,
+                        // The port to reuse is taken; we retry with a fresh one.
+                        Err(err) if port.is_some() => return Err(err),
+                        Err(err) => {
+                            warn!(
+                                "failed to bind local addr: {}",
+                                socket::describe("bind", &err)
+                            );
+                            SourceBinding::bind_failed
+                        }
+                    },
                 };
-                trace!(%src, dest=%addr, "connect with source IP");
-                Ok(socket.connect(addr).await?)
+                trace!(%src, dest=%addr, ?binding, "connect with source IP");
+                Ok((socket_factory.tcp_connect(socket, addr).await?, binding))
             }
         }
     }
+    // The destination may not be able to route back to the original source, so we connect from our own IP.
+    let excluded = local.is_some() && !opts.original_src.map_or(true, |c| c.applies(addr.ip()));
+    if excluded {
+        trace!(src=?local, dest=%addr, "original source does not apply to dest, connect directly");
+    }
+    let local = local.filter(|_| !excluded);
+    let attempt = async {
+        if let Some((affinity, port)) = opts
+            .port_reuse
+            .and_then(|a| Some((a, a.take(local, addr)?)))
+        {
+            match connect_from_port(local, addr, socket_factory, &opts, Some(port)).await {
+                // The port is in use, or its previous connection is still in TIME_WAIT and the kernel does not
+                // allow reusing it.
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+                    ) =>
+                {
+                    debug!(dest=%addr, port, "failed to reuse source port: {err}");
+                    affinity.record_reuse(false);
+                }
+                res => {
+                    if res.is_ok() {
+                        affinity.record_reuse(true);
+                    }
+                    return res;
+                }
+            }
+        }
+        connect_from_port(local, addr, socket_factory, &opts, None).await
+    };
     // Wrap the entire connect function in a timeout
-    timeout(CONNECTION_TIMEOUT, connect(local, addr, socket_factory))
+    let (stream, binding) = timeout(connect_timeout, attempt)
         .await
-        .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))?
+        .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))??;
+    if excluded {
+        return Ok((stream, SourceBinding::not_configured));
+    }
+    Ok((stream, binding))
+}
+
+/// InboundService is the service an inbound connection is attributed to, along with the name of the service port
+/// it matched, if the port is named.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InboundService {
+    pub service: ServiceDescription,
+    pub port_name: Option<Strng>,
 }
 
 // guess_inbound_service selects an upstream service for inbound metrics.
 // There may be many services for a single workload. We find the the first one with an applicable port
 // as a best guess.
+// `conn.dst` is the address the backend is connected on, so its port is the resolved target port, not the
+// service port the client requested; named ports are matched through the endpoint's port mapping.
 pub fn guess_inbound_service(
     conn: &Connection,
     for_host_header: &Option<String>,
     upstream_service: Vec<Arc<Service>>,
     dest: &Workload,
-) -> Option<ServiceDescription> {
+) -> Option<InboundService> {
+    let dport = conn.dst.port();
+    let netaddr = network_addr(dest.network.clone(), conn.dst.ip());
+    let euid = endpoint_uid(&dest.uid, Some(&netaddr));
+    // matched_port finds the service port that targets dport. If several do, named ports are preferred, then the
+    // lowest, so the choice does not depend on the order of the ports.
+    let matched_port = |s: &Service| {
+        s.ports
+            .iter()
+            .filter(|(sport, tport)| {
+                // TargetPort directly matches, or the service itself didn't have a explicit TargetPort match, but
+                // an endpoint does. This happens when there is a named port (in Kubernetes, anyways).
+                **tport == dport
+                    || s.endpoints.get(&euid).and_then(|e| e.port.get(sport)) == Some(&dport)
+            })
+            .map(|(sport, _)| *sport)
+            .min_by_key(|sport| (!s.port_names.contains_key(sport), *sport))
+    };
+    let describe = |s: &Service, sport: Option<u16>| InboundService {
+        service: ServiceDescription::from(s),
+        port_name: sport.and_then(|p| s.port_names.get(&p).cloned()),
+    };
     // First, if the client told us what Service they were reaching, look for that
     // Note: the set of Services we look for is bounded, so we won't blindly trust bogus info.
     if let Some(found) = upstream_service
         .iter()
+        .map(AsRef::as_ref)
         .find(|s| for_host_header.as_deref() == Some(s.hostname.as_ref()))
-        .map(|s| ServiceDescription::from(s.as_ref()))
     {
-        return Some(found);
+        return Some(describe(found, matched_port(found)));
     }
-    let dport = conn.dst.port();
-    let netaddr = network_addr(dest.network.clone(), conn.dst.ip());
-    let euid = endpoint_uid(&dest.uid, Some(&netaddr));
     upstream_service
         .iter()
-        .find(|s| {
-            for (sport, tport) in s.ports.iter() {
-                if tport == &dport {
-                    // TargetPort directly matches
-                    return true;
-                }
-                // The service itself didn't have a explicit TargetPort match, but an endpoint might.
-                // This happens when there is a named port (in Kubernetes, anyways).
-                if s.endpoints.get(&euid).and_then(|e| e.port.get(sport)) == Some(&dport) {
-                    // Named port matched
-                    return true;
-                }
-                // no match
-            }
-            false
-        })
-        .map(|s| ServiceDescription::from(s.as_ref()))
+        .map(AsRef::as_ref)
+        .find_map(|s| matched_port(s).map(|sport| describe(s, Some(sport))))
 }
 
 // Checks that the source identiy and address match the upstream's waypoint
@@ -636,7 +1497,8 @@ async fn check_from_waypoint(
     src_ip: &IpAddr,
 ) -> bool {
     let is_waypoint = |wl: &Workload| {
-        Some(wl.identity()).as_ref() == src_identity && wl.workload_ips.contains(src_ip)
+        Some(wl.identity()).as_ref() == src_identity
+            && wl.workload_ips.contains(&src_ip.to_canonical())
     };
     check_gateway_address(state, upstream.waypoint.as_ref(), is_waypoint).await
 }
@@ -713,10 +1575,10 @@ mod tests {
         },
     };
     use prometheus_client::registry::Registry;
+    use std::str::FromStr;
     use std::{collections::HashMap, net::Ipv4Addr, sync::RwLock};
 
-    #[tokio::test]
-    async fn check_gateway() {
+    fn mock_gateway_state() -> state::DemandProxyState {
         let w = mock_default_gateway_workload();
         let s = mock_default_gateway_service();
         let mut state = state::ProxyState::default();
@@ -724,13 +1586,18 @@ mod tests {
         state.services.insert(s);
         let mut registry = Registry::default();
         let metrics = Arc::new(crate::proxy::Metrics::new(&mut registry));
-        let state = state::DemandProxyState::new(
+        state::DemandProxyState::new(
             Arc::new(RwLock::new(state)),
             None,
             ResolverConfig::default(),
             ResolverOpts::default(),
             metrics,
-        );
+        )
+    }
+
+    #[tokio::test]
+    async fn check_gateway() {
+        let state = mock_gateway_state();
 
         let gateawy_id = Identity::Spiffe {
             trust_domain: "cluster.local".into(),
@@ -763,6 +1630,94 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn check_waypoint_mapped_source() {
+        let state = mock_gateway_state();
+        let waypoint_id = Identity::Spiffe {
+            trust_domain: "cluster.local".into(),
+            namespace: "gatewayns".into(),
+            service_account: "default".into(),
+        };
+        let mut upstream = mock_wokload_with_gateway(None);
+        upstream.waypoint = Some(mock_default_gateway_address());
+
+        // A waypoint connecting over IPv6 with a mapped address is the same waypoint.
+        let mapped = IpAddr::V6(mock_default_gateway_ipaddr().to_ipv6_mapped());
+        assert!(state
+            .fetch_workload(&NetworkAddress {
+                network: "".into(),
+                address: mapped,
+            })
+            .await
+            .is_some());
+        assert!(check_from_waypoint(&state, &upstream, Some(&waypoint_id), &mapped).await);
+        let other = IpAddr::V6(Ipv4Addr::new(127, 0, 0, 101).to_ipv6_mapped());
+        assert!(!check_from_waypoint(&state, &upstream, Some(&waypoint_id), &other).await);
+    }
+
+    #[test]
+    fn guess_inbound_service_named_port() {
+        let dest = mock_default_gateway_workload();
+        let mut svc = mock_default_gateway_service();
+        // Service port 80 targets a named port, which the endpoint resolves to 1234
+        svc.ports = HashMap::from([(80, 0)]);
+        for ep in svc.endpoints.values_mut() {
+            ep.port = HashMap::from([(80, 1234)]);
+        }
+        let svc = Arc::new(svc);
+        let conn = |port| Connection {
+            src_identity: None,
+            src: "127.0.0.1:12345".parse().unwrap(),
+            dst_network: "".into(),
+            dst: SocketAddr::new(mock_default_gateway_ipaddr().into(), port),
+        };
+
+        let found = guess_inbound_service(&conn(1234), &None, vec![svc.clone()], &dest);
+        assert_eq!(found.map(|s| s.service.hostname), Some("gateway".into()));
+        assert!(guess_inbound_service(&conn(80), &None, vec![svc], &dest).is_none());
+    }
+
+    #[test]
+    fn guess_inbound_service_port_name() {
+        let dest = mock_default_gateway_workload();
+        let mut svc = mock_default_gateway_service();
+        // Port 80 targets the named port "http", which the endpoint resolves to 1234; 9090 targets itself.
+        svc.ports = HashMap::from([(80, 0), (9090, 9090)]);
+        svc.port_names = HashMap::from([(80, "http".into()), (9090, "metrics".into())]);
+        for ep in svc.endpoints.values_mut() {
+            ep.port = HashMap::from([(80, 1234)]);
+        }
+        let svc = Arc::new(svc);
+        let conn = |port| Connection {
+            src_identity: None,
+            src: "127.0.0.1:12345".parse().unwrap(),
+            dst_network: "".into(),
+            dst: SocketAddr::new(mock_default_gateway_ipaddr().into(), port),
+        };
+        let port_name = |port, host: Option<&str>| {
+            guess_inbound_service(
+                &conn(port),
+                &host.map(String::from),
+                vec![svc.clone()],
+                &dest,
+            )
+            .and_then(|s| s.port_name)
+        };
+
+        assert_eq!(port_name(1234, None), Some("http".into()));
+        assert_eq!(port_name(9090, None), Some("metrics".into()));
+        // The host header selects the service even if no port matches, so the port name is unknown.
+        assert_eq!(port_name(1234, Some("gateway")), Some("http".into()));
+        assert_eq!(port_name(8080, Some("gateway")), None);
+
+        // Several service ports target 9090. Named ports are preferred, then the lowest.
+        let mut shared = (*svc).clone();
+        shared.ports.extend([(81, 9090), (9000, 9090)]);
+        shared.port_names.insert(9000, "admin".into());
+        let found = guess_inbound_service(&conn(9090), &None, vec![Arc::new(shared)], &dest);
+        assert_eq!(found.and_then(|s| s.port_name), Some("admin".into()));
+    }
+
     // private helpers
     fn mock_wokload_with_gateway(gw: Option<GatewayAddress>) -> Workload {
         Workload {
@@ -789,6 +1744,9 @@ mod tests {
             native_tunnel: false,
             application_tunnel: None,
             locality: Default::default(),
+            tls_sni_override: None,
+            traffic_class: Default::default(),
+            egress_rules: Default::default(),
         }
     }
 
@@ -817,6 +1775,9 @@ mod tests {
             native_tunnel: false,
             application_tunnel: None,
             locality: Default::default(),
+            tls_sni_override: None,
+            traffic_class: Default::default(),
+            egress_rules: Default::default(),
         }
     }
 
@@ -856,7 +1817,182 @@ mod tests {
             waypoint: None,
             load_balancer: None,
             ip_families: None,
+            subset_weights: None,
+            mirror: None,
+            port_names: Default::default(),
+        }
+    }
+
+    /// ProxyProtocolTlvs are the ztunnel specific TLVs sent in a PROXY protocol header.
+    #[derive(Debug, Default, PartialEq, Eq)]
+    struct ProxyProtocolTlvs {
+        src_identity: Option<String>,
+        dst_service: Option<String>,
+    }
+
+    // read_proxy_protocol_tlvs extracts the ztunnel TLVs from a parsed PROXY protocol header, as a backend would.
+    fn read_proxy_protocol_tlvs(header: &ppp::v2::Header) -> io::Result<ProxyProtocolTlvs> {
+        let mut tlvs = ProxyProtocolTlvs::default();
+        for tlv in header.tlvs() {
+            let tlv = tlv.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let field = match tlv.kind {
+                PROXY_PROTOCOL_AUTHORITY_TLV => &mut tlvs.src_identity,
+                PROXY_PROTOCOL_SERVICE_TLV => &mut tlvs.dst_service,
+                _ => continue,
+            };
+            let value = std::str::from_utf8(&tlv.value)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            *field = Some(value.to_string());
         }
+        Ok(tlvs)
+    }
+
+    #[test]
+    fn proxy_protocol_tlvs() {
+        let src: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let dst: SocketAddr = "127.0.0.2:8080".parse().unwrap();
+        let id = Identity::from_str("spiffe://cluster.local/ns/default/sa/client").unwrap();
+        let read = |header: Vec<u8>| {
+            let header = ppp::v2::Header::try_from(header.as_slice()).unwrap();
+            read_proxy_protocol_tlvs(&header).unwrap()
+        };
+
+        let header = proxy_protocol_header(
+            (src, dst),
+            Some(id.clone()),
+            Some("svc.ns.svc.cluster.local"),
+        )
+        .unwrap();
+        assert_eq!(
+            read(header),
+            ProxyProtocolTlvs {
+                src_identity: Some(id.to_string()),
+                dst_service: Some("svc.ns.svc.cluster.local".to_string()),
+            }
+        );
+
+        // Direct connections to a workload have no service
+        let header = proxy_protocol_header((src, dst), Some(id.clone()), None).unwrap();
+        assert_eq!(
+            read(header),
+            ProxyProtocolTlvs {
+                src_identity: Some(id.to_string()),
+                dst_service: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn traffic_class_socket_option() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let factory = DefaultSocketFactory::default();
+        let socket = factory.new_tcp_v4().unwrap();
+        let stream = socket
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert!(stream.nodelay().unwrap(), "sockets start out interactive");
+
+        set_traffic_class(&factory, TrafficClass::Bulk, &[&stream]);
+        assert!(!stream.nodelay().unwrap(), "bulk connections use Nagle");
+
+        factory
+            .set_traffic_class(&stream, TrafficClass::Interactive)
+            .unwrap();
+        assert!(stream.nodelay().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn socket_options_applied() {
+        let factory = DefaultSocketFactory {
+            options: socket::SocketOptions {
+                max_segment_size: Some(1200),
+                pmtu_discovery: Some(config::PmtuDiscovery::Do),
+            },
+            ..Default::default()
+        };
+        let listener = factory.tcp_bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let socket = factory.new_tcp_v4().unwrap();
+        assert_eq!(socket2::SockRef::from(&socket).mss().unwrap(), 1200);
+
+        let stream = socket.connect(listener.local_addr()).await.unwrap();
+        let diagnostics = socket::tcp_diagnostics(&stream).unwrap();
+        assert!(
+            diagnostics.snd_mss > 0 && diagnostics.snd_mss <= 1200,
+            "MSS should be clamped: {diagnostics:?}"
+        );
+        assert!(!diagnostics.stalled());
+    }
+
+    #[test]
+    fn traffic_class_for_connection() {
+        let interactive = crate::test_helpers::test_default_workload();
+        let bulk = Workload {
+            traffic_class: TrafficClass::Bulk,
+            ..crate::test_helpers::test_default_workload()
+        };
+        assert_eq!(
+            TrafficClass::for_connection(Some(&interactive), Some(&interactive)),
+            TrafficClass::Interactive
+        );
+        assert_eq!(
+            TrafficClass::for_connection(None, Some(&bulk)),
+            TrafficClass::Bulk
+        );
+        assert_eq!(
+            TrafficClass::for_connection(Some(&bulk), Some(&interactive)),
+            TrafficClass::Bulk
+        );
+    }
+
+    #[test]
+    fn proxy_protocol_identity_round_trip() {
+        let src: SocketAddr = "[::1]:1234".parse().unwrap();
+        let dst: SocketAddr = "[::2]:8080".parse().unwrap();
+        let id = Identity::Spiffe {
+            trust_domain: "cluster.local".into(),
+            namespace: "default".into(),
+            service_account: "client".into(),
+        };
+        let read = |header: Vec<u8>| {
+            let header = ppp::v2::Header::try_from(header.as_slice()).unwrap();
+            read_proxy_protocol_tlvs(&header).unwrap()
+        };
+
+        let header = proxy_protocol_header((src, dst), Some(id.clone()), None).unwrap();
+        let tlvs = read(header);
+        let read_id = Identity::from_str(tlvs.src_identity.as_deref().unwrap()).unwrap();
+        assert_eq!(read_id, id);
+
+        // Without an identity, no authority TLV is written at all.
+        let header = proxy_protocol_header((src, dst), None, None).unwrap();
+        let parsed = ppp::v2::Header::try_from(header.as_slice()).unwrap();
+        assert_eq!(parsed.tlvs().count(), 0);
+        assert_eq!(read(header), ProxyProtocolTlvs::default());
+    }
+
+    #[test]
+    fn proxy_protocol_tlv_too_long() {
+        let src: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let dst: SocketAddr = "127.0.0.2:8080".parse().unwrap();
+        let id = Identity::Spiffe {
+            trust_domain: "cluster.local".into(),
+            namespace: "default".into(),
+            service_account: "a".repeat(u16::MAX as usize).into(),
+        };
+
+        let err = proxy_protocol_header((src, dst), Some(id), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Each TLV fits, but together they overflow the header length.
+        let id = Identity::Spiffe {
+            trust_domain: "cluster.local".into(),
+            namespace: "default".into(),
+            service_account: "a".repeat(40_000).into(),
+        };
+        let svc = "s".repeat(40_000);
+        assert!(proxy_protocol_header((src, dst), Some(id), Some(&svc)).is_err());
     }
 
     fn mock_default_gateway_address() -> GatewayAddress {
@@ -882,4 +2018,450 @@ mod tests {
     fn mock_default_gateway_ipaddr() -> Ipv4Addr {
         Ipv4Addr::new(127, 0, 0, 100)
     }
+
+    // NamespacedSocketFactory reports a network namespace, as the inpod socket factories do.
+    struct NamespacedSocketFactory(DefaultSocketFactory);
+
+    impl DelegatingSocketFactory for NamespacedSocketFactory {
+        fn inner(&self) -> &(dyn SocketFactory + Send + Sync) {
+            &self.0
+        }
+
+        fn netns(&self) -> Option<String> {
+            Some("test-ns".to_string())
+        }
+    }
+
+    async fn namespaced_proxy(inbound_addr: SocketAddr) -> Result<Proxy, Error> {
+        let cfg = config::Config {
+            inbound_addr,
+            ..crate::test_helpers::test_config()
+        };
+        let pi = crate::test_helpers::test_proxy_inputs(
+            cfg,
+            crate::test_helpers::new_proxy_state(&[], &[], &[]),
+            Arc::new(NamespacedSocketFactory(DefaultSocketFactory::default())),
+        );
+        let (_drain_trigger, drain) = crate::drain::new();
+        Proxy::from_inputs(pi, drain).await
+    }
+
+    #[tokio::test]
+    async fn bind_error_names_netns() {
+        // 192.0.2.0/24 is reserved for documentation, so it is never assigned to an interface
+        let addr: SocketAddr = "192.0.2.1:0".parse().unwrap();
+        let Err(err) = namespaced_proxy(addr).await else {
+            panic!("bind should fail");
+        };
+        assert!(
+            matches!(err, Error::BindAddressNotAssignable(a, ref ns) if a == addr && ns == "test-ns"),
+            "unexpected error: {err}"
+        );
+        assert_eq!(
+            err.to_string(),
+            "failed to bind to address 192.0.2.1:0: not assigned to any interface in network namespace test-ns"
+        );
+
+        // Other failures keep the underlying error
+        let err = bind_error(
+            &NamespacedSocketFactory(DefaultSocketFactory::default()),
+            addr,
+            io::Error::from_raw_os_error(libc::EADDRINUSE),
+        );
+        assert!(matches!(err, Error::BindInNetns(a, ref ns, _) if a == addr && ns == "test-ns"));
+        let err = bind_error(
+            &DefaultSocketFactory::default(),
+            addr,
+            io::Error::from_raw_os_error(libc::EADDRNOTAVAIL),
+        );
+        assert!(matches!(err, Error::Bind(a, _) if a == addr));
+        assert_eq!(
+            err.to_string(),
+            "failed to bind to address 192.0.2.1:0: bind: Cannot assign requested address (os error 99) [EADDRNOTAVAIL]"
+        );
+    }
+
+    #[tokio::test]
+    async fn namespaced_bind_with_fake_self_inbound() {
+        // The inbound address is latebound, and then used as the proxy's own inbound address
+        let proxy = namespaced_proxy("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let inbound = proxy.addresses().inbound;
+        assert_eq!(inbound.ip(), IpAddr::from([127, 0, 0, 1]));
+        assert_ne!(inbound.port(), 0);
+    }
+
+    // FreebindSocketFactory pretends to set freebind, so binding a local source address works without
+    // CAP_NET_ADMIN.
+    struct FreebindSocketFactory(DefaultSocketFactory);
+
+    impl DelegatingSocketFactory for FreebindSocketFactory {
+        fn inner(&self) -> &(dyn SocketFactory + Send + Sync) {
+            &self.0
+        }
+
+        fn set_freebind(&self, _: &TcpSocket) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // self_connect connects from 127.0.0.1 to a listener on 127.0.0.1 with the given mode, returning the
+    // binding used.
+    async fn self_connect(mode: config::SelfConnectMode) -> io::Result<SourceBinding> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_, binding) = freebind_connect(
+            Some(addr.ip()),
+            addr,
+            Duration::from_secs(1),
+            &FreebindSocketFactory(DefaultSocketFactory::default()),
+            ConnectOptions::default().with_self_connect(mode),
+        )
+        .await?;
+        listener.accept().await.unwrap();
+        Ok(binding)
+    }
+
+    #[tokio::test]
+    async fn self_connect_ztunnel_addr() {
+        let binding = self_connect(config::SelfConnectMode::ZtunnelAddr)
+            .await
+            .unwrap();
+        assert_eq!(binding, SourceBinding::not_configured);
+    }
+
+    #[tokio::test]
+    async fn self_connect_original_src() {
+        let binding = self_connect(config::SelfConnectMode::OriginalSrc)
+            .await
+            .unwrap();
+        assert_eq!(binding, SourceBinding::original);
+    }
+
+    #[tokio::test]
+    async fn self_connect_reject() {
+        let err = self_connect(config::SelfConnectMode::Reject)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+        // Only connections to the source itself are rejected.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        freebind_connect(
+            Some(IpAddr::from([127, 0, 0, 2])),
+            addr,
+            Duration::from_secs(1),
+            &DefaultSocketFactory::default(),
+            ConnectOptions::default().with_self_connect(config::SelfConnectMode::Reject),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_timeout() {
+        // The connect timeout is measured with tokio::time, so paused time skips ahead to it without waiting.
+        let faults = crate::test_helpers::faults::FaultInjectingSocketFactory::new(Arc::new(
+            DefaultSocketFactory::default(),
+        ));
+        let addr = "127.0.0.1:1".parse().unwrap();
+        faults.delay_connect(addr, Duration::from_secs(3600));
+        let start = tokio::time::Instant::now();
+        let err = freebind_connect(
+            None,
+            addr,
+            Duration::from_secs(5),
+            &faults,
+            ConnectOptions::default(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn source_port_reused() {
+        let metrics = crate::test_helpers::helpers::test_proxy_metrics();
+        let affinity = Arc::new(PortAffinity::new(&metrics));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let factory = DefaultSocketFactory::default();
+        let connect = || {
+            freebind_connect(
+                None,
+                addr,
+                Duration::from_secs(1),
+                &factory,
+                ConnectOptions::default().with_port_reuse(Some(affinity.as_ref())),
+            )
+        };
+
+        let (mut first, _) = connect().await.unwrap();
+        let port = first.local_addr().unwrap().port();
+        let lease = affinity.lease(None, addr, &first).unwrap();
+        // The server closes first, so the client port does not go into TIME_WAIT.
+        drop(listener.accept().await.unwrap());
+        assert_eq!(
+            tokio::io::AsyncReadExt::read(&mut first, &mut [0u8; 1])
+                .await
+                .unwrap(),
+            0
+        );
+        drop(first);
+        drop(lease);
+
+        let (second, _) = connect().await.unwrap();
+        assert_eq!(second.local_addr().unwrap().port(), port);
+        assert_eq!(metrics.source_port_reused.get(), 1);
+        assert_eq!(metrics.source_port_reuse_failed.get(), 0);
+    }
+
+    // EgressRecordingSocketFactory counts the sockets bound to the egress interface.
+    #[derive(Default)]
+    struct EgressRecordingSocketFactory {
+        inner: DefaultSocketFactory,
+        egress_binds: std::sync::atomic::AtomicUsize,
+    }
+
+    impl DelegatingSocketFactory for EgressRecordingSocketFactory {
+        fn inner(&self) -> &(dyn SocketFactory + Send + Sync) {
+            &self.inner
+        }
+
+        fn bind_egress_device(&self, _: &TcpSocket) -> io::Result<()> {
+            self.egress_binds
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn egress_device_only_for_egress() {
+        let factory = EgressRecordingSocketFactory::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let binds = || {
+            factory
+                .egress_binds
+                .load(std::sync::atomic::Ordering::SeqCst)
+        };
+
+        // Connections to local workloads, such as inbound ones, are not bound.
+        freebind_connect(
+            None,
+            addr,
+            Duration::from_secs(1),
+            &factory,
+            ConnectOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(binds(), 0);
+
+        freebind_connect(
+            None,
+            addr,
+            Duration::from_secs(1),
+            &factory,
+            ConnectOptions::default().egress(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(binds(), 1);
+    }
+
+    // MarkRejectingSocketFactory fails to set correlation marks, as without CAP_NET_ADMIN, and counts the attempts.
+    #[derive(Default)]
+    struct MarkRejectingSocketFactory {
+        inner: DefaultSocketFactory,
+        marks: std::sync::atomic::AtomicUsize,
+    }
+
+    impl DelegatingSocketFactory for MarkRejectingSocketFactory {
+        fn inner(&self) -> &(dyn SocketFactory + Send + Sync) {
+            &self.inner
+        }
+
+        fn set_correlation_mark(
+            &self,
+            _: &TcpSocket,
+            _: socket::CorrelationMark,
+            _: u64,
+        ) -> io::Result<()> {
+            self.marks.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        }
+    }
+
+    #[tokio::test]
+    async fn correlation_mark_failure_keeps_connecting() {
+        let inner = MarkRejectingSocketFactory::default();
+        let factory = CorrelatedSocketFactory {
+            inner: &inner,
+            mark: socket::CorrelationMark::new(0xffff_0000),
+            conn_id: ConnectionId::next(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        freebind_connect(
+            None,
+            addr,
+            Duration::from_secs(1),
+            &factory,
+            ConnectOptions::default(),
+        )
+        .await
+        .unwrap();
+        listener.accept().await.unwrap();
+        assert_eq!(inner.marks.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    // TransparencySocketFactory simulates whether we may make sockets transparent, as with CAP_NET_ADMIN, and
+    // counts attempts to bind a source IP. Those always fail, as the tests run without it.
+    #[derive(Default)]
+    pub(super) struct TransparencySocketFactory {
+        inner: DefaultSocketFactory,
+        pub(super) transparent: bool,
+        pub(super) freebind_attempts: std::sync::atomic::AtomicUsize,
+    }
+
+    impl DelegatingSocketFactory for TransparencySocketFactory {
+        fn inner(&self) -> &(dyn SocketFactory + Send + Sync) {
+            &self.inner
+        }
+
+        fn set_transparent(&self, _: &socket::Listener) -> io::Result<()> {
+            if self.transparent {
+                Ok(())
+            } else {
+                Err(io::Error::from_raw_os_error(libc::EPERM))
+            }
+        }
+
+        fn set_freebind(&self, _: &TcpSocket) -> io::Result<()> {
+            self.freebind_attempts
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(io::Error::from_raw_os_error(libc::EPERM))
+        }
+    }
+
+    #[tokio::test]
+    async fn original_source_excluded_destination() {
+        let factory = TransparencySocketFactory::default();
+        let excluded = factory
+            .inner
+            .tcp_bind("127.0.0.3:0".parse().unwrap())
+            .unwrap();
+        let other = factory
+            .inner
+            .tcp_bind("127.0.0.4:0".parse().unwrap())
+            .unwrap();
+        let original_src = config::OriginalSourceCidrs {
+            include: None,
+            exclude: crate::cidrs::CidrSet::new(vec!["127.0.0.3/32".parse().unwrap()]),
+        };
+        let connect = |addr| {
+            freebind_connect(
+                Some(IpAddr::from([127, 0, 0, 2])),
+                addr,
+                Duration::from_secs(1),
+                &factory,
+                ConnectOptions::default().with_original_src(&original_src),
+            )
+        };
+        let attempts = || {
+            factory
+                .freebind_attempts
+                .load(std::sync::atomic::Ordering::SeqCst)
+        };
+
+        // The excluded destination is connected to from our own IP, without attempting the original source.
+        let (_, binding) = connect(excluded.local_addr()).await.unwrap();
+        assert_eq!(binding, SourceBinding::not_configured);
+        assert_eq!(attempts(), 0);
+
+        // Other destinations still attempt the original source, which fails here.
+        let (_, binding) = connect(other.local_addr()).await.unwrap();
+        assert_eq!(binding, SourceBinding::bind_failed);
+        assert_eq!(attempts(), 1);
+    }
+
+    #[test]
+    fn original_source_cidrs() {
+        let cidrs = |s: &str| {
+            crate::cidrs::CidrSet::new(s.split(',').map(|c| c.parse().unwrap()).collect())
+        };
+        let applies =
+            |cfg: &config::OriginalSourceCidrs, ip: &str| cfg.applies(ip.parse().unwrap());
+
+        let everywhere = config::OriginalSourceCidrs::default();
+        assert!(applies(&everywhere, "10.0.0.1"));
+        assert!(applies(&everywhere, "2001:db8::1"));
+
+        let cfg = config::OriginalSourceCidrs {
+            include: Some(cidrs("10.0.0.0/8")),
+            exclude: cidrs("10.1.0.0/16"),
+        };
+        assert!(applies(&cfg, "10.0.0.1"));
+        assert!(applies(&cfg, "::ffff:10.0.0.1"));
+        assert!(!applies(&cfg, "10.1.0.1"));
+        assert!(!applies(&cfg, "192.168.0.1"));
+    }
+
+    fn ips(ips: &[&str]) -> Vec<IpAddr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn select_source_ip_by_destination() {
+        let prefixes = config::SubnetPrefixes::default();
+        let select = |candidates: &[&str], dest: &str| {
+            select_source_ip(&ips(candidates), dest.parse().unwrap(), prefixes)
+                .map(|ip| ip.to_string())
+        };
+        let mixed = ["10.0.0.1", "fd00::1", "10.1.0.1", "fd01::1"];
+
+        // Same family as the destination, preferring its subnet.
+        assert_eq!(select(&mixed, "10.1.0.9").as_deref(), Some("10.1.0.1"));
+        assert_eq!(select(&mixed, "fd01::9").as_deref(), Some("fd01::1"));
+        // No subnet match: the first in the destination's family.
+        assert_eq!(select(&mixed, "192.168.0.1").as_deref(), Some("10.0.0.1"));
+        assert_eq!(select(&mixed, "fd02::9").as_deref(), Some("fd00::1"));
+        // IPv4-mapped destinations are treated as IPv4.
+        assert_eq!(
+            select(&mixed, "::ffff:10.1.0.9").as_deref(),
+            Some("10.1.0.1")
+        );
+        // No candidate in the destination's family: the first.
+        assert_eq!(
+            select(&["fd00::1", "fd01::1"], "10.0.0.9").as_deref(),
+            Some("fd00::1")
+        );
+        assert_eq!(select(&[], "10.0.0.9"), None);
+    }
+
+    #[test]
+    fn original_source_ip_selection() {
+        let mut wl = crate::test_helpers::test_default_workload();
+        wl.workload_ips = ips(&["10.0.0.1", "fd00::1"]);
+        let peer = IpAddr::from([10, 0, 0, 1]);
+        let dest: IpAddr = "fd00::9".parse().unwrap();
+
+        let mut cfg = crate::test_helpers::test_config();
+        assert_eq!(original_source_ip(&cfg, Some(&wl), peer, dest), peer);
+
+        cfg.source_ip_selection = config::SourceIpSelection::MatchDestination;
+        assert_eq!(
+            original_source_ip(&cfg, Some(&wl), peer, dest),
+            "fd00::1".parse::<IpAddr>().unwrap()
+        );
+        // The peer must be one of the workload's IPs, otherwise it is kept as is.
+        let other = IpAddr::from([10, 0, 0, 2]);
+        assert_eq!(original_source_ip(&cfg, Some(&wl), other, dest), other);
+        assert_eq!(original_source_ip(&cfg, None, peer, dest), peer);
+    }
 }
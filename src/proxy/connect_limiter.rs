@@ -0,0 +1,253 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::gauge::Gauge;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config;
+use crate::proxy::{DelegatingSocketFactory, Metrics, SocketFactory};
+
+/// ConnectLimiter bounds the number of outgoing TCP connects in flight, overall and to each destination.
+/// Connects over the limit wait for a slot, rather than all being sent at once; this smooths out bursts
+/// such as a mass reconnection, without affecting steady state behavior.
+pub struct ConnectLimiter {
+    global: Option<Arc<Semaphore>>,
+    per_destination: Option<PerDestination>,
+    in_flight: Gauge,
+    waited: Counter,
+}
+
+struct PerDestination {
+    limit: usize,
+    // Semaphores are only kept while a connect to the destination is in flight.
+    semaphores: Mutex<HashMap<SocketAddr, Arc<Semaphore>>>,
+}
+
+impl ConnectLimiter {
+    /// from_config returns a limiter for the configured limits, or None if connects are unlimited.
+    pub fn from_config(cfg: &config::Config, metrics: &Metrics) -> Option<Self> {
+        Self::new(
+            cfg.max_concurrent_connects,
+            cfg.max_concurrent_connects_per_destination,
+            metrics,
+        )
+    }
+
+    fn new(
+        global: Option<usize>,
+        per_destination: Option<usize>,
+        metrics: &Metrics,
+    ) -> Option<Self> {
+        if global.is_none() && per_destination.is_none() {
+            return None;
+        }
+        Some(ConnectLimiter {
+            global: global.map(|limit| Arc::new(Semaphore::new(limit))),
+            per_destination: per_destination.map(|limit| PerDestination {
+                limit,
+                semaphores: Mutex::new(HashMap::new()),
+            }),
+            in_flight: metrics.connects_in_flight.clone(),
+            waited: metrics.connects_waited.clone(),
+        })
+    }
+
+    /// acquire waits until a connect to `dest` is allowed. The connect is counted as in flight until the
+    /// returned permit is dropped.
+    pub async fn acquire(&self, dest: SocketAddr) -> ConnectPermit<'_> {
+        let mut waited = false;
+        // Wait for the destination first, so a single busy destination does not hold global slots.
+        let destination = match &self.per_destination {
+            Some(pd) => {
+                let semaphore = pd
+                    .semaphores
+                    .lock()
+                    .unwrap()
+                    .entry(dest)
+                    .or_insert_with(|| Arc::new(Semaphore::new(pd.limit)))
+                    .clone();
+                Some(acquire_owned(semaphore, &mut waited).await)
+            }
+            None => None,
+        };
+        let global = match &self.global {
+            Some(semaphore) => Some(acquire_owned(semaphore.clone(), &mut waited).await),
+            None => None,
+        };
+        if waited {
+            self.waited.inc();
+        }
+        self.in_flight.inc();
+        ConnectPermit {
+            limiter: self,
+            dest,
+            destination,
+            _global: global,
+        }
+    }
+}
+
+async fn acquire_owned(semaphore: Arc<Semaphore>, waited: &mut bool) -> OwnedSemaphorePermit {
+    match semaphore.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            *waited = true;
+            semaphore
+                .acquire_owned()
+                .await
+                .expect("connect semaphores are never closed")
+        }
+    }
+}
+
+pub struct ConnectPermit<'a> {
+    limiter: &'a ConnectLimiter,
+    dest: SocketAddr,
+    destination: Option<OwnedSemaphorePermit>,
+    _global: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for ConnectPermit<'_> {
+    fn drop(&mut self) {
+        self.limiter.in_flight.dec();
+        let Some(permit) = self.destination.take() else {
+            return;
+        };
+        drop(permit);
+        if let Some(pd) = &self.limiter.per_destination {
+            let mut semaphores = pd.semaphores.lock().unwrap();
+            // If nothing else holds the semaphore, no connect to the destination is in flight or waiting.
+            if semaphores
+                .get(&self.dest)
+                .is_some_and(|s| Arc::strong_count(s) == 1)
+            {
+                semaphores.remove(&self.dest);
+            }
+        }
+    }
+}
+
+/// ConnectLimitingSocketFactory wraps a SocketFactory, holding each connect until the ConnectLimiter allows it.
+pub struct ConnectLimitingSocketFactory {
+    inner: Arc<dyn SocketFactory + Send + Sync>,
+    limiter: Arc<ConnectLimiter>,
+}
+
+impl ConnectLimitingSocketFactory {
+    pub fn new(inner: Arc<dyn SocketFactory + Send + Sync>, limiter: Arc<ConnectLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+impl DelegatingSocketFactory for ConnectLimitingSocketFactory {
+    fn inner(&self) -> &(dyn SocketFactory + Send + Sync) {
+        self.inner.as_ref()
+    }
+
+    fn tcp_connect(
+        &self,
+        socket: TcpSocket,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send + '_>> {
+        Box::pin(async move {
+            let _permit = self.limiter.acquire(addr).await;
+            self.inner.tcp_connect(socket, addr).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_helpers::helpers::test_proxy_metrics;
+
+    // run_connects runs `connects` concurrent fake connects to the destinations in turn, returning the most
+    // that were in flight at once.
+    async fn run_connects(
+        limiter: Arc<ConnectLimiter>,
+        connects: usize,
+        destinations: &[SocketAddr],
+    ) -> usize {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max = Arc::new(AtomicUsize::new(0));
+        let mut tasks = Vec::new();
+        for i in 0..connects {
+            let (limiter, in_flight, max) = (limiter.clone(), in_flight.clone(), max.clone());
+            let dest = destinations[i % destinations.len()];
+            tasks.push(tokio::spawn(async move {
+                let _permit = limiter.acquire(dest).await;
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            }));
+        }
+        for t in tasks {
+            t.await.unwrap();
+        }
+        max.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caps_concurrent_connects() {
+        let metrics = test_proxy_metrics();
+        let limiter = Arc::new(ConnectLimiter::new(Some(3), None, &metrics).unwrap());
+        let dest: SocketAddr = "127.0.0.1:80".parse().unwrap();
+
+        assert_eq!(run_connects(limiter, 10, &[dest]).await, 3);
+        assert_eq!(metrics.connects_in_flight.get(), 0);
+        assert_eq!(metrics.connects_waited.get(), 7);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caps_concurrent_connects_per_destination() {
+        let metrics = test_proxy_metrics();
+        let limiter = Arc::new(ConnectLimiter::new(None, Some(2), &metrics).unwrap());
+        let destinations: [SocketAddr; 2] = [
+            "127.0.0.1:80".parse().unwrap(),
+            "127.0.0.2:80".parse().unwrap(),
+        ];
+
+        // Each destination is limited separately.
+        assert_eq!(run_connects(limiter.clone(), 10, &destinations).await, 4);
+        assert!(
+            limiter
+                .per_destination
+                .as_ref()
+                .unwrap()
+                .semaphores
+                .lock()
+                .unwrap()
+                .is_empty(),
+            "idle destinations are cleaned up"
+        );
+    }
+
+    #[test]
+    fn unlimited() {
+        assert!(ConnectLimiter::new(None, None, &test_proxy_metrics()).is_none());
+    }
+}
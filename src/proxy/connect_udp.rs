@@ -0,0 +1,382 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Experimental support for tunneling UDP over HBONE.
+//
+// This follows the datagram semantics of CONNECT-UDP (RFC 9298): the client sends an extended CONNECT
+// (RFC 8441) with the `connect-udp` protocol, and the target encoded in the path. As HTTP/2 has no
+// native datagram frames, each UDP payload is carried in a DATAGRAM capsule (RFC 9297) on the stream.
+
+use std::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::{Response, StatusCode};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::trace;
+
+use crate::copy::{AsyncWriteBuf, BufferedSplitter, ResizeBufRead};
+use crate::proxy::metrics::ConnectionResult;
+use crate::proxy::{Error, SocketFactory};
+
+pub const CONNECT_UDP_PROTOCOL: &str = "connect-udp";
+pub const CAPSULE_PROTOCOL_HEADER: &str = "capsule-protocol";
+
+// The default URI template from RFC 9298: /.well-known/masque/udp/{target_host}/{target_port}/
+const TARGET_PATH_PREFIX: &str = "/.well-known/masque/udp/";
+
+const DATAGRAM_CAPSULE_TYPE: u64 = 0x00;
+// Context ID 0 indicates the datagram payload is a UDP payload.
+const UDP_PAYLOAD_CONTEXT_ID: u64 = 0x00;
+// The largest possible UDP payload. We do not accept capsules larger than this (plus a context ID).
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// target_path encodes the UDP target into the request path.
+pub fn target_path(target: SocketAddr) -> String {
+    let host = match target.ip() {
+        IpAddr::V4(ip) => ip.to_string(),
+        // Colons must be percent-encoded in the path.
+        IpAddr::V6(ip) => ip.to_string().replace(':', "%3A"),
+    };
+    format!("{TARGET_PATH_PREFIX}{host}/{}/", target.port())
+}
+
+/// parse_target_path decodes the UDP target from the request path. Only IP addresses are supported.
+pub fn parse_target_path(path: &str) -> Option<SocketAddr> {
+    let rest = path.strip_prefix(TARGET_PATH_PREFIX)?;
+    let rest = rest.strip_suffix('/').unwrap_or(rest);
+    let (host, port) = rest.split_once('/')?;
+    let ip: IpAddr = host.replace("%3A", ":").replace("%3a", ":").parse().ok()?;
+    Some(SocketAddr::new(ip, port.parse().ok()?))
+}
+
+/// response builds the successful response to a CONNECT-UDP request.
+pub fn response() -> Response<()> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CAPSULE_PROTOCOL_HEADER, "?1")
+        .body(())
+        .expect("builder with known status code should not fail")
+}
+
+// put_varint writes a variable-length integer, as defined in RFC 9000 section 16.
+fn put_varint(buf: &mut BytesMut, v: u64) {
+    if v < 1 << 6 {
+        buf.put_u8(v as u8);
+    } else if v < 1 << 14 {
+        buf.put_u16(0x4000 | v as u16);
+    } else if v < 1 << 30 {
+        buf.put_u32(0x8000_0000 | v as u32);
+    } else {
+        buf.put_u64(0xc000_0000_0000_0000 | v);
+    }
+}
+
+// get_varint reads a variable-length integer, returning its value and encoded length.
+// None is returned if the buffer does not contain the complete integer.
+fn get_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let len = 1usize << (first >> 6);
+    if buf.len() < len {
+        return None;
+    }
+    let v = buf[1..len]
+        .iter()
+        .fold(u64::from(first & 0x3f), |v, b| (v << 8) | u64::from(*b));
+    Some((v, len))
+}
+
+fn encode_datagram(payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(payload.len() + 16);
+    put_varint(&mut buf, DATAGRAM_CAPSULE_TYPE);
+    put_varint(&mut buf, payload.len() as u64 + 1);
+    put_varint(&mut buf, UDP_PAYLOAD_CONTEXT_ID);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+#[derive(Default)]
+struct CapsuleDecoder {
+    buf: BytesMut,
+}
+
+impl CapsuleDecoder {
+    fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    // next_datagram returns the next complete UDP payload, if we have buffered one.
+    // Capsules of other types, and datagrams with other context IDs, are skipped as the RFCs require.
+    fn next_datagram(&mut self) -> Result<Option<Bytes>, Error> {
+        loop {
+            let Some((typ, type_len)) = get_varint(&self.buf) else {
+                return Ok(None);
+            };
+            let Some((len, len_len)) = get_varint(&self.buf[type_len..]) else {
+                return Ok(None);
+            };
+            let len = usize::try_from(len)
+                .ok()
+                .filter(|len| *len <= MAX_DATAGRAM_SIZE + 8)
+                .ok_or(Error::InvalidCapsule)?;
+            if self.buf.len() < type_len + len_len + len {
+                return Ok(None);
+            }
+            self.buf.advance(type_len + len_len);
+            let mut capsule = self.buf.split_to(len).freeze();
+            if typ != DATAGRAM_CAPSULE_TYPE {
+                trace!("skipping capsule type {typ}");
+                continue;
+            }
+            let (context_id, context_len) = get_varint(&capsule).ok_or(Error::InvalidCapsule)?;
+            if context_id != UDP_PAYLOAD_CONTEXT_ID {
+                trace!("skipping datagram with context id {context_id}");
+                continue;
+            }
+            capsule.advance(context_len);
+            return Ok(Some(capsule));
+        }
+    }
+}
+
+/// DatagramReader reads UDP payloads from a tunnel stream.
+pub struct DatagramReader<R> {
+    read: R,
+    decoder: CapsuleDecoder,
+}
+
+impl<R: ResizeBufRead + Unpin> DatagramReader<R> {
+    /// recv returns the next datagram, or None once the stream is closed.
+    /// This is cancel safe: no data is lost if the future is dropped before completion.
+    pub async fn recv(&mut self) -> Result<Option<Bytes>, Error> {
+        loop {
+            if let Some(datagram) = self.decoder.next_datagram()? {
+                return Ok(Some(datagram));
+            }
+            let buf = poll_fn(|cx| Pin::new(&mut self.read).poll_bytes(cx)).await?;
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            self.decoder.extend(&buf);
+        }
+    }
+}
+
+/// DatagramWriter writes UDP payloads to a tunnel stream.
+pub struct DatagramWriter<W> {
+    write: W,
+}
+
+impl<W: AsyncWriteBuf + Unpin> DatagramWriter<W> {
+    pub async fn send(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let mut capsule = encode_datagram(payload);
+        while !capsule.is_empty() {
+            let n =
+                poll_fn(|cx| Pin::new(&mut self.write).poll_write_buf(cx, capsule.clone())).await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+            capsule.advance(n);
+        }
+        Ok(())
+    }
+
+    pub async fn close(&mut self) -> Result<(), Error> {
+        poll_fn(|cx| Pin::new(&mut self.write).poll_shutdown(cx)).await?;
+        Ok(())
+    }
+}
+
+/// split turns a tunnel stream into halves for reading and writing datagrams.
+pub fn split<S: BufferedSplitter>(stream: S) -> (DatagramReader<S::R>, DatagramWriter<S::W>) {
+    let (read, write) = stream.split_into_buffered_reader();
+    (
+        DatagramReader {
+            read,
+            decoder: CapsuleDecoder::default(),
+        },
+        DatagramWriter { write },
+    )
+}
+
+/// connect_upstream creates a UDP socket connected to the target.
+pub async fn connect_upstream(
+    socket_factory: &(dyn SocketFactory + Send + Sync),
+    target: SocketAddr,
+) -> Result<UdpSocket, Error> {
+    let bind = match target {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = socket_factory.udp_bind(bind)?;
+    // Connecting a UDP socket only sets the default destination, and filters what we receive.
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+/// relay_socket proxies datagrams between the tunnel (downstream) and a connected UDP socket (upstream)
+/// until the tunnel is closed.
+pub async fn relay_socket<S: BufferedSplitter>(
+    stream: S,
+    socket: UdpSocket,
+    stats: &ConnectionResult,
+) -> Result<(), Error> {
+    let (mut reader, mut writer) = split(stream);
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            datagram = reader.recv() => {
+                let Some(datagram) = datagram? else {
+                    return Ok(());
+                };
+                stats.increment_recv(datagram.len() as u64);
+                ignore_refused(socket.send(&datagram).await)?;
+            }
+            n = socket.recv(&mut buf) => {
+                let Some(n) = ignore_refused(n)? else {
+                    continue;
+                };
+                writer.send(&buf[..n]).await?;
+                stats.increment_send(n as u64);
+            }
+        }
+    }
+}
+
+/// relay_channel proxies datagrams between a client (downstream) and the tunnel (upstream).
+/// Datagrams from the client are read from `datagrams` until it is closed, and replies are passed to `reply`.
+pub async fn relay_channel<S: BufferedSplitter>(
+    stream: S,
+    mut datagrams: mpsc::Receiver<Bytes>,
+    reply: impl Fn(Bytes),
+    stats: &ConnectionResult,
+) -> Result<(), Error> {
+    let (mut reader, mut writer) = split(stream);
+    loop {
+        tokio::select! {
+            datagram = datagrams.recv() => {
+                let Some(datagram) = datagram else {
+                    return writer.close().await;
+                };
+                writer.send(&datagram).await?;
+                stats.increment_recv(datagram.len() as u64);
+            }
+            datagram = reader.recv() => {
+                let Some(datagram) = datagram? else {
+                    return Ok(());
+                };
+                stats.increment_send(datagram.len() as u64);
+                reply(datagram);
+            }
+        }
+    }
+}
+
+// ignore_refused ignores ICMP errors from a previous send, which are reported on a connected UDP socket.
+// For UDP these are not fatal; the datagram is simply lost.
+fn ignore_refused<T>(res: io::Result<T>) -> io::Result<Option<T>> {
+    match res {
+        Ok(t) => Ok(Some(t)),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_round_trip() {
+        for v in [
+            0,
+            37,
+            63,
+            64,
+            15293,
+            16383,
+            16384,
+            494878333,
+            151288809941952652,
+        ] {
+            let mut buf = BytesMut::new();
+            put_varint(&mut buf, v);
+            assert_eq!(get_varint(&buf), Some((v, buf.len())), "{v}");
+        }
+        // Examples from RFC 9000 appendix A.1
+        assert_eq!(get_varint(&[0x7b, 0xbd]), Some((15293, 2)));
+        assert_eq!(get_varint(&[0x9d, 0x7f, 0x3e, 0x7d]), Some((494878333, 4)));
+        assert_eq!(get_varint(&[0x9d, 0x7f]), None);
+    }
+
+    #[test]
+    fn target_path_round_trip() {
+        for target in ["10.0.0.1:53", "[2001:db8::1]:443"] {
+            let target: SocketAddr = target.parse().unwrap();
+            assert_eq!(parse_target_path(&target_path(target)), Some(target));
+        }
+        assert_eq!(
+            target_path("[2001:db8::1]:443".parse().unwrap()),
+            "/.well-known/masque/udp/2001%3Adb8%3A%3A1/443/"
+        );
+        assert_eq!(
+            parse_target_path("/.well-known/masque/udp/example.com/53/"),
+            None
+        );
+        assert_eq!(parse_target_path("/10.0.0.1/53/"), None);
+    }
+
+    #[test]
+    fn decode_capsules() {
+        let mut decoder = CapsuleDecoder::default();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&encode_datagram(b"hello"));
+        // Unknown capsule type, which must be skipped
+        put_varint(&mut buf, 0x1234);
+        put_varint(&mut buf, 3);
+        buf.extend_from_slice(b"abc");
+        buf.extend_from_slice(&encode_datagram(b"world"));
+
+        // Feed the data in one byte at a time, to exercise partial capsules
+        let mut got = vec![];
+        for b in buf.iter() {
+            decoder.extend(&[*b]);
+            while let Some(d) = decoder.next_datagram().unwrap() {
+                got.push(d);
+            }
+        }
+        assert_eq!(got, vec![Bytes::from("hello"), Bytes::from("world")]);
+    }
+
+    #[tokio::test]
+    async fn datagrams_over_stream() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (_, mut client) = split(client);
+        let (mut server, _) = split(server);
+        client.send(b"one").await.unwrap();
+        client.send(b"").await.unwrap();
+        client.send(&[7u8; 2000]).await.unwrap();
+        client.close().await.unwrap();
+        assert_eq!(server.recv().await.unwrap(), Some(Bytes::from("one")));
+        assert_eq!(server.recv().await.unwrap(), Some(Bytes::new()));
+        assert_eq!(
+            server.recv().await.unwrap(),
+            Some(Bytes::from(vec![7u8; 2000]))
+        );
+        assert_eq!(server.recv().await.unwrap(), None);
+    }
+}
@@ -12,21 +12,32 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::proxy::Error;
+use crate::config;
+use crate::identity::Identity;
+use crate::proxy::connection_metadata::ConnectionMetadata;
+use crate::proxy::{ConnectionId, Error, Metrics};
+use crate::rbac::{RbacDecision, RbacDenial};
 
 use crate::state::DemandProxyState;
 use crate::state::ProxyRbacContext;
+use crate::tls::HandshakeSummary;
 use serde::{Serialize, Serializer};
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Formatter;
 use std::future::Future;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 use crate::drain;
-use crate::drain::{DrainTrigger, DrainWatcher};
+use crate::drain::{DrainMode, DrainTrigger, DrainWatcher};
+use prometheus_client::metrics::counter::Counter;
 use std::sync::Arc;
+use std::sync::OnceLock;
 use std::sync::RwLock;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
 use tracing::{debug, error, info, warn};
 
 struct ConnectionDrain {
@@ -36,29 +47,156 @@ struct ConnectionDrain {
     tx: DrainTrigger,
     rx: DrainWatcher,
     count: usize,
+    // What the TLS handshake of the connection negotiated, for the connection dump
+    tls: Option<HandshakeSummary>,
 }
 
 impl ConnectionDrain {
-    fn new() -> Self {
+    fn new(tls: Option<HandshakeSummary>) -> Self {
         let (tx, rx) = drain::new();
-        ConnectionDrain { tx, rx, count: 1 }
+        ConnectionDrain {
+            tx,
+            rx,
+            count: 1,
+            tls,
+        }
     }
 
     /// drain drops the internal reference to rx and then signals drain on the tx
     // always inline, this is for convenience so that we don't forget to drop the rx but there's really no reason it needs to grow the stack
     #[inline(always)]
-    async fn drain(self) {
+    async fn drain(self, mode: DrainMode) {
         drop(self.rx); // very important, drain cannot complete if there are outstand rx
-        self.tx
-            .start_drain_and_wait(drain::DrainMode::Immediate)
-            .await;
+        self.tx.start_drain_and_wait(mode).await;
     }
 }
 
+// OutboundConnectionState is what is tracked for an open outbound connection, beyond its addresses.
+struct OutboundConnectionState {
+    // The identity of the source workload and the service it addressed, if known, for `DrainSelector`
+    source_identity: Option<Identity>,
+    destination_service: Option<String>,
+    drain: DrainTrigger,
+    // The handshake is filled in by the connection's guard once known, without taking the lock again.
+    tls: Arc<OnceLock<HandshakeSummary>>,
+}
+
 #[derive(Clone)]
 pub struct ConnectionManager {
     drains: Arc<RwLock<HashMap<InboundConnection, ConnectionDrain>>>,
-    outbound_connections: Arc<RwLock<HashSet<OutboundConnection>>>,
+    outbound_connections: Arc<RwLock<HashMap<OutboundConnection, OutboundConnectionState>>>,
+    budget: Option<Arc<ConnectionBudget>>,
+    // Connections are closed once they have been open this long, if set
+    max_lifetime: Option<Duration>,
+    streams_closed_max_lifetime: Counter,
+    // Inbound passthrough connections by their source and destination addresses. Unlike HBONE streams, which
+    // share the addresses of their tunnel, these identify a single TCP connection.
+    tuples: Arc<RwLock<HashMap<(SocketAddr, SocketAddr), ConnectionId>>>,
+    double_connection_policy: config::DoubleConnectionPolicy,
+    double_connection: Counter,
+    // How long connections drained by `drain_matching` are given to finish before they are closed
+    drain_grace_period: Duration,
+    drained_by_selector: Counter,
+}
+
+/// ConnectionBudget caps the total number of connections handled at once, inbound and outbound, so a flood of
+/// connections cannot exhaust file descriptors. Once the budget is used up, new connections, inbound or
+/// outbound, are rejected until enough connections close.
+///
+/// A number of connections may be reserved for traffic such as health checks, which then keeps working when
+/// the rest of the budget is exhausted.
+pub struct ConnectionBudget {
+    semaphore: Arc<Semaphore>,
+    reserve: Option<ReservedBudget>,
+    inbound_rejected: Counter,
+    outbound_rejected: Counter,
+}
+
+/// ReservedBudget is the part of the budget only available to connections from one of `sources`, or to one
+/// of `ports`.
+struct ReservedBudget {
+    semaphore: Arc<Semaphore>,
+    sources: Vec<ipnet::IpNet>,
+    ports: Arc<[u16]>,
+    admitted: Counter,
+}
+
+impl ReservedBudget {
+    fn matches(&self, src: SocketAddr, dst: SocketAddr) -> bool {
+        self.ports.contains(&dst.port()) || self.matches_source(src)
+    }
+
+    fn matches_source(&self, src: SocketAddr) -> bool {
+        let src = crate::socket::to_canonical(src).ip();
+        self.sources.iter().any(|net| net.contains(&src))
+    }
+}
+
+/// BudgetPermit counts a connection against the connection budget until it is dropped.
+#[derive(Debug)]
+pub struct BudgetPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// TunnelPermit counts an inbound HBONE connection against the connection budget until it is dropped.
+/// The ports a stream targets are only known once it is opened, so a connection admitted to the reserved
+/// budget for its ports, rather than its source, may only carry streams to one of those ports.
+#[derive(Debug, Default)]
+pub struct TunnelPermit {
+    _permit: Option<BudgetPermit>,
+    reserved_ports: Option<Arc<[u16]>>,
+}
+
+impl TunnelPermit {
+    /// allows returns whether a stream to `dst` may be carried over the connection.
+    pub fn allows(&self, dst: SocketAddr) -> bool {
+        match &self.reserved_ports {
+            Some(ports) => ports.contains(&dst.port()),
+            None => true,
+        }
+    }
+}
+
+impl ConnectionBudget {
+    /// from_config returns the configured budget, or None if connections are unlimited.
+    pub fn from_config(cfg: &config::Config, metrics: &Metrics) -> Option<Arc<Self>> {
+        let limit = cfg.max_total_connections?;
+        let mut budget = Self::new(limit, metrics);
+        if cfg.reserved_connections > 0 {
+            budget = budget.with_reserve(
+                cfg.reserved_connections,
+                cfg.reserved_connection_sources.clone(),
+                cfg.reserved_connection_ports.clone(),
+                metrics,
+            );
+        }
+        Some(Arc::new(budget))
+    }
+
+    fn new(limit: usize, metrics: &Metrics) -> Self {
+        ConnectionBudget {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            reserve: None,
+            inbound_rejected: metrics.connection_budget_inbound_rejected.clone(),
+            outbound_rejected: metrics.connection_budget_outbound_rejected.clone(),
+        }
+    }
+
+    fn with_reserve(
+        mut self,
+        limit: usize,
+        sources: Vec<ipnet::IpNet>,
+        ports: Vec<u16>,
+        metrics: &Metrics,
+    ) -> Self {
+        self.reserve = Some(ReservedBudget {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            sources,
+            ports: ports.into(),
+            admitted: metrics.connection_budget_reserved_admitted.clone(),
+        });
+        self
+    }
 }
 
 impl std::fmt::Debug for ConnectionManager {
@@ -71,7 +209,15 @@ impl Default for ConnectionManager {
     fn default() -> Self {
         ConnectionManager {
             drains: Arc::new(RwLock::new(HashMap::new())),
-            outbound_connections: Arc::new(RwLock::new(HashSet::new())),
+            outbound_connections: Arc::new(RwLock::new(HashMap::new())),
+            budget: None,
+            max_lifetime: None,
+            streams_closed_max_lifetime: Counter::default(),
+            tuples: Arc::new(RwLock::new(HashMap::new())),
+            double_connection_policy: Default::default(),
+            double_connection: Counter::default(),
+            drain_grace_period: Duration::ZERO,
+            drained_by_selector: Counter::default(),
         }
     }
 }
@@ -80,21 +226,78 @@ pub struct ConnectionGuard {
     cm: ConnectionManager,
     conn: InboundConnection,
     watch: Option<DrainWatcher>,
+    // When the connection reaches its maximum lifetime, if there is one
+    deadline: Option<Instant>,
+    // The authorization decision that admitted the connection
+    decision: RbacDecision,
 }
 
 impl ConnectionGuard {
+    /// decision is the authorization decision that admitted the connection.
+    pub fn decision(&self) -> &RbacDecision {
+        &self.decision
+    }
+
+    /// closing completes, with the reason, once the connection should be closed gracefully: it reached its
+    /// maximum lifetime, or was drained by `ConnectionManager::drain_matching` and did not finish in time.
+    /// Rather than being dropped, the connection should then be closed with a FIN, such as by
+    /// `copy::copy_bidirectional_until`.
+    pub fn closing(&self) -> impl Future<Output = Error> + Send + 'static {
+        let deadline = self.deadline;
+        let drained = self
+            .watch
+            .clone()
+            .map(|watch| drain_closing(watch, self.cm.drain_grace_period));
+        async move {
+            let lifetime = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let drained = async {
+                match drained {
+                    Some(drained) => drained.await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = lifetime => Error::MaxLifetimeReached,
+                reason = drained => reason,
+            }
+        }
+    }
+
+    /// handle_connection runs `send` until it completes, or until the connection is no longer allowed. `send`
+    /// itself is expected to stop at `closing`.
     pub async fn handle_connection(
         mut self,
         send: impl Future<Output = Result<(), Error>> + Sized,
     ) -> Result<(), Error> {
         let watch = self.watch.take().expect("watch cannot be taken twice");
-        tokio::select! {
-            res = send => {
-                self.cm.release(&self.conn);
-                res
-            }
-            _signaled = watch.wait_for_drain() => Err(Error::AuthorizationPolicyLateRejection)
+        tokio::pin!(send);
+        let res = tokio::select! {
+            res = &mut send => res,
+            signaled = watch.wait_for_drain() => match signaled.mode() {
+                DrainMode::Immediate => return Err(Error::AuthorizationPolicyLateRejection),
+                // `send` closes the connection itself once `closing` completes. Holding on to `signaled` until
+                // then keeps the drain waiting for it.
+                DrainMode::Graceful => {
+                    let res = send.await;
+                    drop(signaled);
+                    res
+                }
+            },
+        };
+        self.cm.release(&self.conn);
+        if matches!(res, Err(Error::MaxLifetimeReached)) {
+            self.cm.streams_closed_max_lifetime.inc();
+            info!(
+                "connection {} closed after reaching its maximum lifetime",
+                self.conn.ctx
+            );
         }
+        res
     }
 }
 
@@ -110,6 +313,25 @@ impl Drop for ConnectionGuard {
 pub struct OutboundConnectionGuard {
     cm: ConnectionManager,
     conn: OutboundConnection,
+    watch: DrainWatcher,
+    tls: Arc<OnceLock<HandshakeSummary>>,
+}
+
+impl OutboundConnectionGuard {
+    /// closing completes, with the reason, once the connection was drained by
+    /// `ConnectionManager::drain_matching` and did not finish in time. As with `ConnectionGuard::closing`, the
+    /// connection should then be closed with a FIN.
+    pub fn closing(&self) -> impl Future<Output = Error> + Send + 'static {
+        drain_closing(self.watch.clone(), self.cm.drain_grace_period)
+    }
+
+    /// record_handshake records what the TLS handshake with the upstream negotiated, if there was one, so it is
+    /// shown in the connection dump.
+    pub fn record_handshake(&self, tls: Option<HandshakeSummary>) {
+        if let Some(tls) = tls {
+            let _ = self.tls.set(tls);
+        }
+    }
 }
 
 impl Drop for OutboundConnectionGuard {
@@ -118,20 +340,125 @@ impl Drop for OutboundConnectionGuard {
     }
 }
 
+pub struct TupleGuard {
+    cm: ConnectionManager,
+    tuple: (SocketAddr, SocketAddr),
+    connection_id: ConnectionId,
+}
+
+impl Drop for TupleGuard {
+    fn drop(&mut self) {
+        let mut tuples = self.cm.tuples.write().expect("mutex");
+        // The entry may have been taken over by a newer connection, if this one was closed in its favor.
+        if tuples.get(&self.tuple) == Some(&self.connection_id) {
+            tuples.remove(&self.tuple);
+        }
+    }
+}
+
+// drain_closing completes once the connection `watch` belongs to is drained gracefully and has had `grace` to finish
+// on its own, with the reason to close it. Until then, the drain is kept waiting. Closing a connection
+// immediately is left to whoever holds it, so this never completes for an immediate drain.
+async fn drain_closing(watch: DrainWatcher, grace: Duration) -> Error {
+    let signaled = watch.wait_for_drain().await;
+    if signaled.mode() == DrainMode::Immediate {
+        return std::future::pending().await;
+    }
+    tokio::time::sleep(grace).await;
+    drop(signaled);
+    Error::DrainedBySelector
+}
+
+/// DrainSelector selects the connections to drain, inbound and outbound. All of the fields that are set must
+/// match, and a selector with none set matches no connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DrainSelector {
+    /// The identity of the source: the peer of an inbound connection, or the local workload making an
+    /// outbound one.
+    pub source_identity: Option<Identity>,
+    /// The hostname of the destination service.
+    pub destination_service: Option<String>,
+    /// Matches either the source or destination address.
+    pub cidr: Option<ipnet::IpNet>,
+}
+
+impl DrainSelector {
+    pub fn is_empty(&self) -> bool {
+        self.source_identity.is_none() && self.destination_service.is_none() && self.cidr.is_none()
+    }
+
+    fn matches(
+        &self,
+        source_identity: Option<&Identity>,
+        destination_service: Option<&str>,
+        addrs: &[SocketAddr],
+    ) -> bool {
+        !self.is_empty()
+            && self
+                .source_identity
+                .as_ref()
+                .map_or(true, |id| Some(id) == source_identity)
+            && self
+                .destination_service
+                .as_deref()
+                .map_or(true, |svc| Some(svc) == destination_service)
+            && self.cidr.map_or(true, |cidr| {
+                addrs
+                    .iter()
+                    .any(|addr| cidr.contains(&addr.ip().to_canonical()))
+            })
+    }
+
+    fn matches_inbound(&self, c: &InboundConnection) -> bool {
+        self.matches(
+            c.ctx.conn.src_identity.as_ref(),
+            c.dest_service.as_deref(),
+            &[c.ctx.conn.src, c.ctx.conn.dst],
+        )
+    }
+
+    fn matches_outbound(&self, c: &OutboundConnection, state: &OutboundConnectionState) -> bool {
+        self.matches(
+            state.source_identity.as_ref(),
+            state.destination_service.as_deref(),
+            &[c.src, c.original_dst, c.actual_dst],
+        )
+    }
+}
+
 #[derive(Debug, Clone, Eq, Hash, Ord, PartialEq, PartialOrd, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct OutboundConnection {
+    pub connection_id: ConnectionId,
+    pub src: SocketAddr,
+    pub original_dst: SocketAddr,
+    pub actual_dst: SocketAddr,
+}
+
+#[derive(Debug, Clone, Eq, Hash, Ord, PartialEq, PartialOrd, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboundConnectionDump {
+    pub connection_id: ConnectionId,
     pub src: SocketAddr,
     pub original_dst: SocketAddr,
     pub actual_dst: SocketAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<HandshakeSummary>,
 }
 
 #[derive(Debug, Clone, Eq, Hash, Ord, PartialEq, PartialOrd, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct InboundConnectionDump {
+    pub connection_id: ConnectionId,
     pub src: SocketAddr,
     pub original_dst: Option<String>,
     pub actual_dst: SocketAddr,
+    #[serde(skip_serializing_if = "ConnectionMetadata::is_empty")]
+    pub metadata: ConnectionMetadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_identity: Option<Identity>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<HandshakeSummary>,
 }
 
 #[derive(Debug, Clone, Eq, Hash, Ord, PartialEq, PartialOrd, serde::Serialize)]
@@ -140,64 +467,267 @@ pub struct InboundConnection {
     #[serde(flatten)]
     pub ctx: ProxyRbacContext,
     pub dest_service: Option<String>,
+    pub connection_id: ConnectionId,
+    #[serde(skip_serializing_if = "ConnectionMetadata::is_empty")]
+    pub metadata: ConnectionMetadata,
 }
 
 impl ConnectionManager {
+    /// with_connection_budget counts connections against `budget`, which may be shared by several managers.
+    pub fn with_connection_budget(mut self, budget: Option<Arc<ConnectionBudget>>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// with_max_lifetime closes inbound connections once they have been open for `max_lifetime`, regardless of
+    /// activity. Policy and certificates are checked when a connection is opened, so this bounds how long a
+    /// connection can outlive a change to either.
+    pub fn with_max_lifetime(mut self, max_lifetime: Option<Duration>, metrics: &Metrics) -> Self {
+        self.max_lifetime = max_lifetime;
+        self.streams_closed_max_lifetime = metrics.streams_closed_max_lifetime.clone();
+        self
+    }
+
+    /// with_double_connection_policy sets what happens when an inbound passthrough connection arrives with the
+    /// addresses of one that is still tracked.
+    pub fn with_double_connection_policy(
+        mut self,
+        policy: config::DoubleConnectionPolicy,
+        metrics: &Metrics,
+    ) -> Self {
+        self.double_connection_policy = policy;
+        self.double_connection = metrics.double_connection.clone();
+        self
+    }
+
+    /// with_selector_drain gives connections drained by `drain_matching` up to `grace_period` to finish on their
+    /// own before they are closed, and counts them in `metrics`. Without a grace period, they are closed right
+    /// away.
+    pub fn with_selector_drain(mut self, grace_period: Duration, metrics: &Metrics) -> Self {
+        self.drain_grace_period = grace_period;
+        self.drained_by_selector = metrics.connections_drained_by_selector.clone();
+        self
+    }
+
+    /// acquire_inbound_budget counts a connection an inbound listener has accepted against the connection budget,
+    /// failing immediately if it is exhausted and the connection may not use the reserved budget. Listeners
+    /// accept before asking for budget, and close the connection if there is none, so an idle listener never
+    /// holds budget that other listeners, or outbound connections, could use.
+    pub fn acquire_inbound_budget(
+        &self,
+        src: SocketAddr,
+        dst: SocketAddr,
+    ) -> Result<Option<BudgetPermit>, Error> {
+        self.acquire_budget(src, dst, |b| &b.inbound_rejected)
+    }
+
+    /// acquire_tunnel_budget counts an inbound HBONE connection against the connection budget. The budget is
+    /// used as by `acquire_inbound_budget`, but as the tunnel's own address says nothing about the streams it
+    /// will carry, reserved ports are matched on each stream by `TunnelPermit::allows`.
+    pub fn acquire_tunnel_budget(&self, src: SocketAddr) -> Result<TunnelPermit, Error> {
+        let Some(budget) = &self.budget else {
+            return Ok(TunnelPermit::default());
+        };
+        if let Ok(permit) = budget.semaphore.clone().try_acquire_owned() {
+            return Ok(TunnelPermit {
+                _permit: Some(BudgetPermit { _permit: permit }),
+                reserved_ports: None,
+            });
+        }
+        if let Some(reserve) = &budget.reserve {
+            let by_source = reserve.matches_source(src);
+            if by_source || !reserve.ports.is_empty() {
+                if let Ok(permit) = reserve.semaphore.clone().try_acquire_owned() {
+                    reserve.admitted.inc();
+                    return Ok(TunnelPermit {
+                        _permit: Some(BudgetPermit { _permit: permit }),
+                        reserved_ports: (!by_source).then(|| reserve.ports.clone()),
+                    });
+                }
+            }
+        }
+        budget.inbound_rejected.inc();
+        debug!(%src, "connection budget exhausted");
+        Err(Error::ConnectionBudgetExhausted)
+    }
+
+    /// try_acquire_budget counts an outbound connection against the connection budget, failing immediately if
+    /// it is exhausted and the connection may not use the reserved budget.
+    pub fn try_acquire_budget(
+        &self,
+        src: SocketAddr,
+        dst: SocketAddr,
+    ) -> Result<Option<BudgetPermit>, Error> {
+        self.acquire_budget(src, dst, |b| &b.outbound_rejected)
+    }
+
+    fn acquire_budget(
+        &self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        rejected: impl Fn(&ConnectionBudget) -> &Counter,
+    ) -> Result<Option<BudgetPermit>, Error> {
+        let Some(budget) = &self.budget else {
+            return Ok(None);
+        };
+        if let Ok(permit) = budget.semaphore.clone().try_acquire_owned() {
+            return Ok(Some(BudgetPermit { _permit: permit }));
+        }
+        if let Some(reserve) = budget.reserve.as_ref().filter(|r| r.matches(src, dst)) {
+            if let Ok(permit) = reserve.semaphore.clone().try_acquire_owned() {
+                reserve.admitted.inc();
+                return Ok(Some(BudgetPermit { _permit: permit }));
+            }
+        }
+        rejected(budget).inc();
+        debug!(%src, %dst, "connection budget exhausted");
+        Err(Error::ConnectionBudgetExhausted)
+    }
+
+    /// track_outbound records an outbound connection, until the returned guard is dropped. `source_identity` and
+    /// `destination_service` are those of the workload making it and the service it addressed, if known.
     pub fn track_outbound(
         &self,
         src: SocketAddr,
         original_dst: SocketAddr,
         actual_dst: SocketAddr,
+        connection_id: ConnectionId,
+        source_identity: Option<Identity>,
+        destination_service: Option<String>,
     ) -> OutboundConnectionGuard {
         let c = OutboundConnection {
+            connection_id,
             src,
             original_dst,
             actual_dst,
         };
 
-        self.outbound_connections
-            .write()
-            .expect("mutex")
-            .insert(c.clone());
+        let (drain, watch) = drain::new();
+        let tls = Arc::new(OnceLock::new());
+        self.outbound_connections.write().expect("mutex").insert(
+            c.clone(),
+            OutboundConnectionState {
+                source_identity,
+                destination_service,
+                drain,
+                tls: tls.clone(),
+            },
+        );
 
         OutboundConnectionGuard {
             cm: self.clone(),
             conn: c,
+            watch,
+            tls,
+        }
+    }
+
+    /// track_tuple records an inbound passthrough connection by its source and destination addresses, until the
+    /// returned guard is dropped. If another connection with the same addresses is still tracked, the double
+    /// connection policy decides which one is kept.
+    pub async fn track_tuple(
+        &self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        connection_id: ConnectionId,
+    ) -> Result<TupleGuard, Error> {
+        let tuple = (src, dst);
+        let existing = {
+            let mut tuples = self.tuples.write().expect("mutex");
+            match tuples.entry(tuple) {
+                Entry::Occupied(entry) if *entry.get() != connection_id => Some(*entry.get()),
+                Entry::Occupied(_) => None,
+                Entry::Vacant(entry) => {
+                    entry.insert(connection_id);
+                    None
+                }
+            }
+        };
+        if let Some(existing) = existing {
+            self.double_connection.inc();
+            // The existing connection may no longer be tracked for policy, if it was released without its
+            // addresses being released too.
+            let active = self
+                .connections()
+                .into_iter()
+                .find(|c| c.connection_id == existing);
+            warn!(
+                %src,
+                %dst,
+                %existing,
+                new=%connection_id,
+                existing_active=active.is_some(),
+                policy=?self.double_connection_policy,
+                "connection seen twice"
+            );
+            match self.double_connection_policy {
+                config::DoubleConnectionPolicy::Reject => return Err(Error::DoubleConnection),
+                config::DoubleConnectionPolicy::CloseExisting => {
+                    if let Some(conn) = active {
+                        self.close(&conn).await;
+                    }
+                    self.tuples
+                        .write()
+                        .expect("mutex")
+                        .insert(tuple, connection_id);
+                }
+            }
         }
+        Ok(TupleGuard {
+            cm: self.clone(),
+            tuple,
+            connection_id,
+        })
     }
 
+    /// assert_rbac tracks a connection and checks that authorization policy allows it. `tls` is what the
+    /// handshake of a TLS connection negotiated, shown alongside the connection in the dump.
     pub async fn assert_rbac(
         &self,
         state: &DemandProxyState,
         ctx: &ProxyRbacContext,
+        connection_id: ConnectionId,
         dest_service: Option<String>,
+        metadata: ConnectionMetadata,
+        tls: Option<HandshakeSummary>,
     ) -> Result<ConnectionGuard, Error> {
         // Register before our initial assert. This prevents a race if policy changes between assert() and
         // track()
         let conn = InboundConnection {
             ctx: ctx.clone(),
             dest_service,
+            connection_id,
+            metadata,
         };
-        let Some(watch) = self.register(&conn) else {
+        let Some(watch) = self.register(&conn, tls) else {
             warn!("failed to track {conn:?}");
             debug_assert!(false, "failed to track {conn:?}");
-            return Err(Error::AuthorizationPolicyRejection);
+            return Err(Error::AuthorizationPolicyRejection(RbacDenial::Untracked));
+        };
+        let decision = match state.check_rbac(ctx).await {
+            Ok(decision) => decision,
+            Err(denial) => {
+                self.release(&conn);
+                return Err(Error::AuthorizationPolicyRejection(denial));
+            }
         };
-        if !state.assert_rbac(ctx).await {
-            self.release(&conn);
-            return Err(Error::AuthorizationPolicyRejection);
-        }
         Ok(ConnectionGuard {
             cm: self.clone(),
             conn,
             watch: Some(watch),
+            deadline: self.max_lifetime.map(|d| Instant::now() + d),
+            decision,
         })
     }
     // register a connection with the connection manager
     // this must be done before a connection can be tracked
     // allows policy to be asserted against the connection
     // even no tasks have a receiver channel yet
-    fn register(&self, c: &InboundConnection) -> Option<DrainWatcher> {
+    fn register(
+        &self,
+        c: &InboundConnection,
+        tls: Option<HandshakeSummary>,
+    ) -> Option<DrainWatcher> {
         match self.drains.write().expect("mutex").entry(c.clone()) {
             Entry::Occupied(mut cd) => {
                 cd.get_mut().count += 1;
@@ -205,7 +735,7 @@ impl ConnectionManager {
                 Some(rx)
             }
             Entry::Vacant(entry) => {
-                let drain = ConnectionDrain::new();
+                let drain = ConnectionDrain::new(tls);
                 let rx = drain.rx.clone();
                 entry.insert(drain);
                 Some(rx)
@@ -230,17 +760,75 @@ impl ConnectionManager {
         self.outbound_connections.write().expect("mutex").remove(c);
     }
 
+    /// drain_matching drains the connections matching `selector`, inbound and outbound, such as all of those to
+    /// a service under maintenance, and returns how many there were. Each connection is given the grace period
+    /// to finish on its own, and is then closed with a FIN and `Error::DrainedBySelector`. This returns right
+    /// away, while the connections close in the background. Connections opened afterwards are not affected.
+    pub fn drain_matching(&self, selector: &DrainSelector) -> usize {
+        let inbound: Vec<_> = {
+            let mut drains = self.drains.write().expect("mutex");
+            let matched: Vec<_> = drains
+                .keys()
+                .filter(|c| selector.matches_inbound(c))
+                .cloned()
+                .collect();
+            matched
+                .into_iter()
+                .filter_map(|c| drains.remove_entry(&c))
+                .collect()
+        };
+        let outbound: Vec<_> = {
+            let mut connections = self.outbound_connections.write().expect("mutex");
+            let matched: Vec<_> = connections
+                .iter()
+                .filter(|(c, state)| selector.matches_outbound(c, state))
+                .map(|(c, _)| c.clone())
+                .collect();
+            matched
+                .into_iter()
+                .filter_map(|c| connections.remove_entry(&c))
+                .collect()
+        };
+        let count = inbound.len() + outbound.len();
+        self.drained_by_selector.inc_by(count as u64);
+
+        for (c, drain) in inbound {
+            tokio::spawn(async move {
+                drain.drain(DrainMode::Graceful).await;
+                info!("connection {} drained by selector", c.ctx);
+            });
+        }
+        for (c, state) in outbound {
+            tokio::spawn(async move {
+                state.drain.start_drain_and_wait(DrainMode::Graceful).await;
+                info!(
+                    "outbound connection {} from {} to {} drained by selector",
+                    c.connection_id, c.src, c.actual_dst
+                );
+            });
+        }
+        count
+    }
+
     // signal all connections listening to this channel to take action (typically terminate traffic)
     async fn close(&self, c: &InboundConnection) {
+        self.signal(c, DrainMode::Immediate).await
+    }
+
+    async fn signal(&self, c: &InboundConnection, mode: DrainMode) {
         let drain = { self.drains.write().expect("mutex").remove(c) };
         if let Some(cd) = drain {
-            cd.drain().await;
+            cd.drain(mode).await;
         } else {
             // this is bad, possibly drain called twice
             error!("requested drain on a Connection which wasn't initialized");
         }
     }
 
+    fn is_tracked(&self, c: &InboundConnection) -> bool {
+        self.drains.read().expect("mutex").contains_key(c)
+    }
+
     //  get a list of all connections being tracked
     pub fn connections(&self) -> Vec<InboundConnection> {
         // potentially large copy under read lock, could require optimization
@@ -251,7 +839,7 @@ impl ConnectionManager {
 #[derive(serde::Serialize)]
 struct ConnectionManagerDump {
     inbound: Vec<InboundConnectionDump>,
-    outbound: Vec<OutboundConnection>,
+    outbound: Vec<OutboundConnectionDump>,
 }
 
 impl Serialize for ConnectionManager {
@@ -263,12 +851,15 @@ impl Serialize for ConnectionManager {
             .drains
             .read()
             .expect("mutex")
-            .keys()
-            .cloned()
-            .map(|c| InboundConnectionDump {
+            .iter()
+            .map(|(c, drain)| InboundConnectionDump {
+                connection_id: c.connection_id,
                 src: c.ctx.conn.src,
-                original_dst: c.dest_service,
+                original_dst: c.dest_service.clone(),
                 actual_dst: c.ctx.conn.dst,
+                metadata: c.metadata.clone(),
+                peer_identity: c.ctx.conn.src_identity.clone(),
+                tls: drain.tls,
             })
             .collect();
         let outbound: Vec<_> = self
@@ -276,7 +867,13 @@ impl Serialize for ConnectionManager {
             .read()
             .expect("mutex")
             .iter()
-            .cloned()
+            .map(|(c, state)| OutboundConnectionDump {
+                connection_id: c.connection_id,
+                src: c.src,
+                original_dst: c.original_dst,
+                actual_dst: c.actual_dst,
+                tls: state.tls.get().copied(),
+            })
             .collect();
         let dump = ConnectionManagerDump { inbound, outbound };
         dump.serialize(serializer)
@@ -287,6 +884,8 @@ pub struct PolicyWatcher {
     state: DemandProxyState,
     stop: DrainWatcher,
     connection_manager: ConnectionManager,
+    grace: Duration,
+    grace_applied: Counter,
 }
 
 impl PolicyWatcher {
@@ -299,11 +898,25 @@ impl PolicyWatcher {
             state,
             stop,
             connection_manager,
+            grace: Duration::ZERO,
+            grace_applied: Counter::default(),
         }
     }
 
+    /// with_grace_period keeps connections that are no longer allowed after a policy update open for `grace`
+    /// before closing them, so in-flight requests can complete. New streams on such a connection are still
+    /// rejected in the meantime, as each stream is authorized when it is opened.
+    pub fn with_grace_period(mut self, grace: Duration, metrics: &Metrics) -> Self {
+        self.grace = grace;
+        self.grace_applied = metrics.late_rejections_grace_applied.clone();
+        self
+    }
+
     pub async fn run(self) {
         let mut policies_changed = self.state.read().policies.subscribe();
+        // Connections in their grace period, and the timers which end it.
+        let mut pending = HashSet::new();
+        let mut grace_timers = JoinSet::new();
         loop {
             tokio::select! {
                 _ = self.stop.clone().wait_for_drain() => {
@@ -312,10 +925,30 @@ impl PolicyWatcher {
                 _ = policies_changed.changed() => {
                     let connections = self.connection_manager.connections();
                     for conn in connections {
-                        if !self.state.assert_rbac(&conn.ctx).await {
+                        if pending.contains(&conn) || self.state.assert_rbac(&conn.ctx).await {
+                            continue;
+                        }
+                        if self.grace.is_zero() {
                             self.connection_manager.close(&conn).await;
                             info!("connection {} closed because it's no longer allowed after a policy update", conn.ctx);
+                            continue;
                         }
+                        info!("connection {} is no longer allowed after a policy update, closing in {:?}", conn.ctx, self.grace);
+                        self.grace_applied.inc();
+                        pending.insert(conn.clone());
+                        let grace = self.grace;
+                        grace_timers.spawn(async move {
+                            tokio::time::sleep(grace).await;
+                            conn
+                        });
+                    }
+                }
+                Some(Ok(conn)) = grace_timers.join_next() => {
+                    pending.remove(&conn);
+                    // In the meantime, the connection may have completed, or been allowed again by another update.
+                    if self.connection_manager.is_tracked(&conn) && !self.state.assert_rbac(&conn.ctx).await {
+                        self.connection_manager.close(&conn).await;
+                        info!("connection {} closed because it's no longer allowed after a policy update", conn.ctx);
                     }
                 }
             }
@@ -329,16 +962,133 @@ mod tests {
     use crate::drain::DrainWatcher;
     use hickory_resolver::config::{ResolverConfig, ResolverOpts};
     use prometheus_client::registry::Registry;
-    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
     use std::sync::{Arc, RwLock};
     use std::time::Duration;
 
-    use crate::rbac::Connection;
+    use super::{
+        ConnectionBudget, ConnectionGuard, ConnectionId, ConnectionManager, DrainSelector,
+        InboundConnection, PolicyWatcher,
+    };
+    use crate::config;
+    use crate::proxy::Error;
+    use crate::rbac::{Connection, RbacAction, RbacDecision};
     use crate::state::{DemandProxyState, ProxyState};
+    use crate::test_helpers::helpers::test_proxy_metrics;
     use crate::xds::istio::security::{Action, Authorization, Scope};
     use crate::xds::ProxyStateUpdateMutator;
 
-    use super::{ConnectionGuard, ConnectionManager, InboundConnection, PolicyWatcher};
+    #[tokio::test]
+    async fn test_connection_budget() {
+        let metrics = test_proxy_metrics();
+        let budget = Arc::new(ConnectionBudget::new(2, &metrics));
+        // Managers share the budget, as they do across proxies.
+        let inbound = ConnectionManager::default().with_connection_budget(Some(budget.clone()));
+        let outbound = ConnectionManager::default().with_connection_budget(Some(budget));
+
+        let (src, dst) = (
+            "10.0.0.1:40000".parse().unwrap(),
+            "10.0.0.2:80".parse().unwrap(),
+        );
+        let first = inbound.acquire_inbound_budget(src, dst).unwrap();
+        let second = outbound.try_acquire_budget(src, dst).unwrap();
+        assert!(first.is_some() && second.is_some());
+
+        // With the budget exhausted, new connections in either direction are rejected...
+        assert!(matches!(
+            outbound.try_acquire_budget(src, dst),
+            Err(Error::ConnectionBudgetExhausted)
+        ));
+        assert!(matches!(
+            inbound.acquire_inbound_budget(src, dst),
+            Err(Error::ConnectionBudgetExhausted)
+        ));
+        assert_eq!(metrics.connection_budget_outbound_rejected.get(), 1);
+        assert_eq!(metrics.connection_budget_inbound_rejected.get(), 1);
+
+        // ...until a connection is released.
+        drop(second);
+        assert!(inbound.acquire_inbound_budget(src, dst).unwrap().is_some());
+
+        // Without a budget, connections are not limited.
+        let unlimited = ConnectionManager::default();
+        assert!(unlimited.try_acquire_budget(src, dst).unwrap().is_none());
+        assert!(unlimited
+            .acquire_inbound_budget(src, dst)
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reserved_connection_budget() {
+        let metrics = test_proxy_metrics();
+        let budget = Arc::new(ConnectionBudget::new(1, &metrics).with_reserve(
+            1,
+            vec!["169.254.7.127/32".parse().unwrap()],
+            vec![15021],
+            &metrics,
+        ));
+        let cm = ConnectionManager::default().with_connection_budget(Some(budget));
+        let app: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let probe: SocketAddr = "169.254.7.127:40000".parse().unwrap();
+        let (dst, health): (SocketAddr, SocketAddr) = (
+            "10.0.0.2:80".parse().unwrap(),
+            "10.0.0.2:15021".parse().unwrap(),
+        );
+
+        let _app = cm.try_acquire_budget(app, dst).unwrap();
+        // Other traffic is rejected...
+        assert!(cm.try_acquire_budget(app, dst).is_err());
+        // ...while reserved traffic, by port or by source, uses the reserve.
+        let reserved = cm.try_acquire_budget(app, health).unwrap();
+        assert!(reserved.is_some());
+        assert!(cm.try_acquire_budget(probe, dst).is_err());
+        drop(reserved);
+        assert!(cm.try_acquire_budget(probe, dst).unwrap().is_some());
+        assert_eq!(metrics.connection_budget_reserved_admitted.get(), 2);
+
+        // Inbound connections use the reserve in the same way.
+        assert!(cm.acquire_inbound_budget(app, dst).is_err());
+        let reserved = cm.acquire_inbound_budget(app, health).unwrap();
+        assert!(reserved.is_some());
+        assert!(cm.acquire_inbound_budget(probe, dst).is_err());
+        assert_eq!(metrics.connection_budget_inbound_rejected.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_connection_budget() {
+        let metrics = test_proxy_metrics();
+        let budget = Arc::new(ConnectionBudget::new(1, &metrics).with_reserve(
+            1,
+            vec!["169.254.7.127/32".parse().unwrap()],
+            vec![15021],
+            &metrics,
+        ));
+        let cm = ConnectionManager::default().with_connection_budget(Some(budget));
+        let app: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let probe: SocketAddr = "169.254.7.127:40000".parse().unwrap();
+        let (dst, health): (SocketAddr, SocketAddr) = (
+            "10.0.0.2:80".parse().unwrap(),
+            "10.0.0.2:15021".parse().unwrap(),
+        );
+
+        // Within the budget, a tunnel may carry any stream.
+        let first = cm.acquire_tunnel_budget(app).unwrap();
+        assert!(first.allows(dst));
+
+        // Once it is exhausted, a tunnel from any source may use the reserve, but only for reserved ports...
+        let reserved = cm.acquire_tunnel_budget(app).unwrap();
+        assert!(reserved.allows(health));
+        assert!(!reserved.allows(dst));
+        assert!(cm.acquire_tunnel_budget(probe).is_err());
+        drop(reserved);
+
+        // ...unless its source is reserved.
+        let reserved = cm.acquire_tunnel_budget(probe).unwrap();
+        assert!(reserved.allows(dst));
+        assert_eq!(metrics.connection_budget_reserved_admitted.get(), 2);
+        assert_eq!(metrics.connection_budget_inbound_rejected.get(), 1);
+    }
 
     #[tokio::test]
     async fn test_connection_manager_close() {
@@ -352,11 +1102,17 @@ mod tests {
             let cm = cm.clone();
             let c = c.clone();
 
-            let watch = cm.register(&c).unwrap();
+            let watch = cm.register(&c, None).unwrap();
             ConnectionGuard {
                 cm,
                 conn: c,
                 watch: Some(watch),
+                deadline: None,
+                decision: RbacDecision {
+                    action: RbacAction::Allow,
+                    policy: None,
+                    rule: None,
+                },
             }
         };
 
@@ -378,6 +1134,8 @@ mod tests {
                 dest_workload_info: None,
             },
             dest_service: None,
+            connection_id: ConnectionId::next(),
+            metadata: Default::default(),
         };
 
         // ensure drains contains exactly 1 item
@@ -412,6 +1170,8 @@ mod tests {
                 dest_workload_info: None,
             },
             dest_service: None,
+            connection_id: ConnectionId::next(),
+            metadata: Default::default(),
         };
 
         let mut close2 = register(&cm, &rbac_ctx2);
@@ -453,11 +1213,17 @@ mod tests {
             let cm = cm.clone();
             let c = c.clone();
 
-            let watch = cm.register(&c).unwrap();
+            let watch = cm.register(&c, None).unwrap();
             ConnectionGuard {
                 cm,
                 conn: c,
                 watch: Some(watch),
+                deadline: None,
+                decision: RbacDecision {
+                    action: RbacAction::Allow,
+                    policy: None,
+                    rule: None,
+                },
             }
         };
 
@@ -479,6 +1245,8 @@ mod tests {
                 dest_workload_info: None,
             },
             dest_service: None,
+            connection_id: ConnectionId::next(),
+            metadata: Default::default(),
         };
 
         // create a second connection
@@ -499,6 +1267,8 @@ mod tests {
                 dest_workload_info: None,
             },
             dest_service: None,
+            connection_id: ConnectionId::next(),
+            metadata: Default::default(),
         };
         let another_conn1 = conn1.clone();
 
@@ -594,10 +1364,12 @@ mod tests {
                 dest_workload_info: None,
             },
             dest_service: None,
+            connection_id: ConnectionId::next(),
+            metadata: Default::default(),
         };
         // watch the connection
         let close1 = connection_manager
-            .register(&conn1)
+            .register(&conn1, None)
             .expect("should not be None");
 
         // generate policy which denies everything
@@ -627,6 +1399,448 @@ mod tests {
         tx.start_drain_and_wait(drain::DrainMode::Immediate).await;
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_policy_watcher_grace_period() {
+        let state = Arc::new(RwLock::new(ProxyState::default()));
+        let mut registry = Registry::default();
+        let metrics = Arc::new(crate::proxy::Metrics::new(&mut registry));
+        let dstate = DemandProxyState::new(
+            state.clone(),
+            None,
+            ResolverConfig::default(),
+            ResolverOpts::default(),
+            metrics.clone(),
+        );
+        let connection_manager = ConnectionManager::default();
+        let (tx, stop) = drain::new();
+        let state_mutator = ProxyStateUpdateMutator::new_no_fetch();
+
+        let grace = Duration::from_secs(10);
+        let pw = PolicyWatcher::new(dstate.clone(), stop, connection_manager.clone())
+            .with_grace_period(grace, &metrics);
+        tokio::spawn(pw.run());
+
+        let conn1 = InboundConnection {
+            ctx: crate::state::ProxyRbacContext {
+                conn: Connection {
+                    src_identity: None,
+                    src: std::net::SocketAddr::new(
+                        std::net::Ipv4Addr::new(192, 168, 0, 1).into(),
+                        80,
+                    ),
+                    dst_network: "".into(),
+                    dst: std::net::SocketAddr::V4(SocketAddrV4::new(
+                        Ipv4Addr::new(192, 168, 0, 2),
+                        8080,
+                    )),
+                },
+                dest_workload_info: None,
+            },
+            dest_service: None,
+            connection_id: ConnectionId::next(),
+            metadata: Default::default(),
+        };
+        let close1 = connection_manager
+            .register(&conn1, None)
+            .expect("should not be None");
+
+        // deny everything
+        let auth = Authorization {
+            name: "allow-nothing".to_string(),
+            action: Action::Deny as i32,
+            scope: Scope::Global as i32,
+            namespace: "default".to_string(),
+            rules: vec![],
+        };
+        {
+            let mut s = state
+                .write()
+                .expect("test fails if we're unable to get a write lock on state");
+            assert!(state_mutator.insert_authorization(&mut s, auth).is_ok());
+        }
+
+        // within the grace period the connection stays open, but new streams are rejected
+        let closed = tokio::time::timeout(grace / 2, close1.clone().wait_for_drain()).await;
+        assert!(closed.is_err(), "connection closed during the grace period");
+        assert_eq!(metrics.late_rejections_grace_applied.get(), 1);
+        assert!(connection_manager.is_tracked(&conn1));
+        assert!(connection_manager
+            .assert_rbac(
+                &dstate,
+                &conn1.ctx,
+                conn1.connection_id,
+                None,
+                Default::default(),
+                None,
+            )
+            .await
+            .is_err());
+
+        // once the grace period ends, the connection is closed
+        // (the drain blocker is dropped right away, so the close can complete)
+        let closed = tokio::time::timeout(grace, close1.wait_for_drain())
+            .await
+            .is_ok();
+        assert!(closed, "connection not closed after the grace period");
+        assert!(!connection_manager.is_tracked(&conn1));
+
+        tx.start_drain_and_wait(drain::DrainMode::Immediate).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_connection_lifetime() {
+        let metrics = test_proxy_metrics();
+        let state = DemandProxyState::new(
+            Arc::new(RwLock::new(ProxyState::default())),
+            None,
+            ResolverConfig::default(),
+            ResolverOpts::default(),
+            metrics.clone(),
+        );
+        let lifetime = Duration::from_secs(60);
+        let cm = ConnectionManager::default().with_max_lifetime(Some(lifetime), &metrics);
+        let conn = InboundConnection {
+            ctx: crate::state::ProxyRbacContext {
+                conn: Connection {
+                    src_identity: None,
+                    src: "192.168.0.1:80".parse().unwrap(),
+                    dst_network: "".into(),
+                    dst: "192.168.0.2:8080".parse().unwrap(),
+                },
+                dest_workload_info: None,
+            },
+            dest_service: None,
+            connection_id: ConnectionId::next(),
+            metadata: Default::default(),
+        };
+        let guard = cm
+            .assert_rbac(
+                &state,
+                &conn.ctx,
+                conn.connection_id,
+                None,
+                Default::default(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        // Stays busy for longer than the lifetime, unless closed.
+        let busy = |guard: ConnectionGuard| {
+            let closing = guard.closing();
+            guard.handle_connection(async move {
+                tokio::select! {
+                    _ = tokio::time::sleep(lifetime * 2) => Ok(()),
+                    reason = closing => Err(reason),
+                }
+            })
+        };
+
+        // The connection is busy for longer than its lifetime, but is closed once it is reached.
+        let start = tokio::time::Instant::now();
+        let res = busy(guard).await;
+        assert!(matches!(res, Err(Error::MaxLifetimeReached)));
+        assert_eq!(start.elapsed(), lifetime);
+        assert!(!cm.is_tracked(&conn));
+        assert_eq!(metrics.streams_closed_max_lifetime.get(), 1);
+
+        // Without a maximum lifetime, connections stay open.
+        let cm = ConnectionManager::default();
+        let guard = cm
+            .assert_rbac(
+                &state,
+                &conn.ctx,
+                conn.connection_id,
+                None,
+                Default::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(busy(guard).await.is_ok());
+    }
+
+    #[test]
+    fn dump_includes_connection_id() {
+        let cm = ConnectionManager::default();
+        let id = ConnectionId::next();
+        let _guard = cm.track_outbound(
+            "127.0.0.1:1234".parse().unwrap(),
+            "127.0.0.2:80".parse().unwrap(),
+            "127.0.0.3:80".parse().unwrap(),
+            id,
+            None,
+            None,
+        );
+        let dump = serde_json::to_value(&cm).unwrap();
+        assert_eq!(
+            dump["outbound"][0]["connectionId"],
+            serde_json::json!(id.to_string().parse::<u64>().unwrap())
+        );
+        assert_ne!(ConnectionId::next(), id);
+    }
+
+    #[test]
+    fn dump_includes_outbound_handshake() {
+        let cm = ConnectionManager::default();
+        let guard = cm.track_outbound(
+            "127.0.0.1:1234".parse().unwrap(),
+            "127.0.0.2:80".parse().unwrap(),
+            "127.0.0.3:80".parse().unwrap(),
+            ConnectionId::next(),
+            None,
+            None,
+        );
+        let dump = serde_json::to_value(&cm).unwrap();
+        assert!(dump["outbound"][0].get("tls").is_none());
+
+        guard.record_handshake(Some(crate::tls::HandshakeSummary {
+            version: 0x0304,
+            cipher_suite: 0x1301,
+            alpn: crate::tls::Alpn::H2,
+        }));
+        let dump = serde_json::to_value(&cm).unwrap();
+        assert_eq!(
+            dump["outbound"][0]["tls"],
+            serde_json::json!({
+                "version": "TLSv1_3",
+                "cipherSuite": "TLS13_AES_128_GCM_SHA256",
+                "alpn": "h2",
+            })
+        );
+
+        drop(guard);
+        let dump = serde_json::to_value(&cm).unwrap();
+        assert_eq!(dump["outbound"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn dump_includes_handshake() {
+        let cm = ConnectionManager::default();
+        let ctx = crate::state::ProxyRbacContext {
+            conn: Connection {
+                src_identity: Some(crate::identity::Identity::default()),
+                src: "127.0.0.1:1234".parse().unwrap(),
+                dst_network: "".into(),
+                dst: "127.0.0.2:8080".parse().unwrap(),
+            },
+            dest_workload_info: None,
+        };
+        let handshake = crate::tls::HandshakeSummary {
+            version: 0x0304,
+            cipher_suite: 0x1302,
+            alpn: crate::tls::Alpn::H2,
+        };
+        let conn = InboundConnection {
+            ctx,
+            dest_service: None,
+            connection_id: ConnectionId::next(),
+            metadata: Default::default(),
+        };
+        let _watch = cm.register(&conn, Some(handshake)).unwrap();
+        let dump = serde_json::to_value(&cm).unwrap();
+        assert_eq!(
+            dump["inbound"][0]["tls"],
+            serde_json::json!({
+                "version": "TLSv1_3",
+                "cipherSuite": "TLS13_AES_256_GCM_SHA384",
+                "alpn": "h2",
+            })
+        );
+        assert_eq!(
+            dump["inbound"][0]["peerIdentity"],
+            serde_json::json!(crate::identity::Identity::default().to_string())
+        );
+
+        // Once the connection is released, the handshake goes with it.
+        cm.release(&conn);
+        let dump = serde_json::to_value(&cm).unwrap();
+        assert_eq!(dump["inbound"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_double_connection() {
+        let metrics = test_proxy_metrics();
+        let state = DemandProxyState::new(
+            Arc::new(RwLock::new(ProxyState::default())),
+            None,
+            ResolverConfig::default(),
+            ResolverOpts::default(),
+            metrics.clone(),
+        );
+        let (src, dst) = (
+            "10.0.0.1:40000".parse().unwrap(),
+            "10.0.0.2:80".parse().unwrap(),
+        );
+
+        // By default, the new connection is rejected and the existing one is kept.
+        let cm = ConnectionManager::default()
+            .with_double_connection_policy(config::DoubleConnectionPolicy::Reject, &metrics);
+        let existing = cm
+            .track_tuple(src, dst, ConnectionId::next())
+            .await
+            .unwrap();
+        let res = cm.track_tuple(src, dst, ConnectionId::next()).await;
+        assert!(matches!(res, Err(Error::DoubleConnection)));
+        assert_eq!(metrics.double_connection.get(), 1);
+        // Once the existing connection is gone, its addresses may be used again.
+        drop(existing);
+        cm.track_tuple(src, dst, ConnectionId::next())
+            .await
+            .unwrap();
+
+        // Otherwise, the existing connection is closed in favor of the new one.
+        let cm = ConnectionManager::default()
+            .with_double_connection_policy(config::DoubleConnectionPolicy::CloseExisting, &metrics);
+        let conn = InboundConnection {
+            ctx: crate::state::ProxyRbacContext {
+                conn: Connection {
+                    src_identity: None,
+                    src,
+                    dst_network: "".into(),
+                    dst,
+                },
+                dest_workload_info: None,
+            },
+            dest_service: None,
+            connection_id: ConnectionId::next(),
+            metadata: Default::default(),
+        };
+        let existing = cm.track_tuple(src, dst, conn.connection_id).await.unwrap();
+        let guard = cm
+            .assert_rbac(
+                &state,
+                &conn.ctx,
+                conn.connection_id,
+                None,
+                Default::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        let handled =
+            tokio::spawn(guard.handle_connection(std::future::pending::<Result<(), Error>>()));
+        let _new = cm
+            .track_tuple(src, dst, ConnectionId::next())
+            .await
+            .unwrap();
+        assert!(matches!(
+            handled.await.unwrap(),
+            Err(Error::AuthorizationPolicyLateRejection)
+        ));
+        assert!(!cm.is_tracked(&conn));
+        assert_eq!(metrics.double_connection.get(), 2);
+        // The closed connection releasing its addresses leaves the new one tracked.
+        drop(existing);
+        assert!(cm.tuples.read().unwrap().contains_key(&(src, dst)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_matching() {
+        let metrics = test_proxy_metrics();
+        let state = DemandProxyState::new(
+            Arc::new(RwLock::new(ProxyState::default())),
+            None,
+            ResolverConfig::default(),
+            ResolverOpts::default(),
+            metrics.clone(),
+        );
+        let grace = Duration::from_secs(10);
+        let cm = ConnectionManager::default().with_selector_drain(grace, &metrics);
+        let conn = |src: &str, service: &str| InboundConnection {
+            ctx: crate::state::ProxyRbacContext {
+                conn: Connection {
+                    src_identity: None,
+                    src: src.parse().unwrap(),
+                    dst_network: "".into(),
+                    dst: "192.168.0.2:8080".parse().unwrap(),
+                },
+                dest_workload_info: None,
+            },
+            dest_service: Some(service.to_string()),
+            connection_id: ConnectionId::next(),
+            metadata: Default::default(),
+        };
+        let reviews = [
+            conn("192.168.0.1:1000", "reviews.default.svc.cluster.local"),
+            conn("192.168.0.1:1001", "reviews.default.svc.cluster.local"),
+        ];
+        let ratings = conn("192.168.0.1:1002", "ratings.default.svc.cluster.local");
+        // Each connection is busy for `busy`, unless it is closed first.
+        let busy = [grace / 2, grace * 2, grace * 2];
+        let mut handled = Vec::new();
+        for (c, busy) in reviews.iter().chain([&ratings]).zip(busy) {
+            let guard = cm
+                .assert_rbac(
+                    &state,
+                    &c.ctx,
+                    c.connection_id,
+                    c.dest_service.clone(),
+                    Default::default(),
+                    None,
+                )
+                .await
+                .unwrap();
+            let closing = guard.closing();
+            handled.push(tokio::spawn(guard.handle_connection(async move {
+                tokio::select! {
+                    _ = tokio::time::sleep(busy) => Ok(()),
+                    reason = closing => Err(reason),
+                }
+            })));
+        }
+        let outbound = cm.track_outbound(
+            "192.168.0.2:2000".parse().unwrap(),
+            "10.0.0.1:80".parse().unwrap(),
+            "10.0.0.1:80".parse().unwrap(),
+            ConnectionId::next(),
+            None,
+            Some("reviews.default.svc.cluster.local".to_string()),
+        );
+        let outbound_closing = tokio::spawn(outbound.closing());
+
+        // An empty selector drains nothing.
+        assert_eq!(cm.drain_matching(&DrainSelector::default()), 0);
+
+        let selector = DrainSelector {
+            destination_service: Some("reviews.default.svc.cluster.local".to_string()),
+            ..Default::default()
+        };
+        let start = tokio::time::Instant::now();
+        // The matches are counted right away, while they are drained in the background.
+        assert_eq!(cm.drain_matching(&selector), 3);
+        assert_eq!(metrics.connections_drained_by_selector.get(), 3);
+        assert!(reviews.iter().all(|c| !cm.is_tracked(c)));
+        assert!(cm.outbound_connections.read().unwrap().is_empty());
+
+        // Matching connections are given the grace period to finish, and are closed once it passes.
+        let ratings_handled = handled.pop().unwrap();
+        let mut handled = handled.into_iter();
+        assert!(handled.next().unwrap().await.unwrap().is_ok());
+        assert_eq!(start.elapsed(), grace / 2);
+        assert!(matches!(
+            handled.next().unwrap().await.unwrap(),
+            Err(Error::DrainedBySelector)
+        ));
+        assert_eq!(start.elapsed(), grace);
+        assert!(matches!(
+            outbound_closing.await.unwrap(),
+            Error::DrainedBySelector
+        ));
+        drop(outbound);
+        assert!(cm.is_tracked(&ratings));
+        assert!(!ratings_handled.is_finished());
+
+        // The selector's fields must all match.
+        let selector = DrainSelector {
+            destination_service: Some("ratings.default.svc.cluster.local".to_string()),
+            cidr: Some("10.0.0.0/8".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(cm.drain_matching(&selector), 0);
+        assert!(cm.is_tracked(&ratings));
+    }
+
     // small helper to assert that the Watches are working in a timely manner
     async fn assert_close(c: DrainWatcher) {
         let result = tokio::time::timeout(Duration::from_secs(1), c.wait_for_drain()).await;
@@ -0,0 +1,82 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use crate::config::MetadataHeader;
+
+/// Longer values are truncated to this many bytes.
+pub const MAX_METADATA_VALUE_LEN: usize = 256;
+
+/// ConnectionMetadata is operator defined metadata attached to a connection, surfaced in access logs and the
+/// connection dump.
+pub type ConnectionMetadata = BTreeMap<String, String>;
+
+/// capture reads the allowlisted headers of an HBONE request into connection metadata. Only the first value
+/// of each header is used, and values that are not visible ASCII are skipped, as they cannot be logged as-is.
+/// The number of entries is bounded by the allowlist, whose length is limited by the config.
+pub fn capture(headers: &http::HeaderMap, allowlist: &[MetadataHeader]) -> ConnectionMetadata {
+    allowlist
+        .iter()
+        .filter_map(|h| {
+            let value = headers.get(h.header.as_str())?.to_str().ok()?;
+            let value = &value[..value.len().min(MAX_METADATA_VALUE_LEN)];
+            Some((h.key.clone(), value.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(entries: &[(&str, &str)]) -> Vec<MetadataHeader> {
+        entries
+            .iter()
+            .map(|(header, key)| MetadataHeader {
+                header: header.to_string(),
+                key: key.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn captures_allowlisted_headers() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-request-id", "abc123".parse().unwrap());
+        headers.insert("x-other", "ignored".parse().unwrap());
+        headers.insert("x-ticket", "a".repeat(1000).parse().unwrap());
+        headers.insert(
+            "x-binary",
+            http::HeaderValue::from_bytes(b"\xfe\xff").unwrap(),
+        );
+
+        let metadata = capture(
+            &headers,
+            &allowlist(&[
+                ("x-request-id", "request_id"),
+                ("x-ticket", "ticket"),
+                ("x-binary", "binary"),
+                ("x-missing", "missing"),
+            ]),
+        );
+        assert_eq!(
+            metadata,
+            ConnectionMetadata::from([
+                ("request_id".to_string(), "abc123".to_string()),
+                ("ticket".to_string(), "a".repeat(MAX_METADATA_VALUE_LEN)),
+            ])
+        );
+    }
+}
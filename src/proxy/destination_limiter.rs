@@ -0,0 +1,143 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+
+use crate::proxy::metrics::DestinationServiceLabels;
+use crate::proxy::{Error, Metrics};
+use crate::state::service::ServiceDescription;
+use crate::state::workload::NamespacedHostname;
+
+/// DestinationLimiter caps the number of outbound connections open to each destination service at once.
+/// This protects upstream services from overload in the steady state; connections over the limit are rejected
+/// immediately rather than queued.
+pub struct DestinationLimiter {
+    // Services are only kept while they have connections open.
+    open: Mutex<HashMap<NamespacedHostname, usize>>,
+    in_flight: Family<DestinationServiceLabels, Gauge>,
+}
+
+impl DestinationLimiter {
+    pub fn new(metrics: &Metrics) -> Self {
+        DestinationLimiter {
+            open: Mutex::new(HashMap::new()),
+            in_flight: metrics.destination_service_in_flight.clone(),
+        }
+    }
+
+    /// try_acquire counts a connection to `svc`, unless `limit` connections to it are already open. The
+    /// connection is counted until the returned permit is dropped.
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        svc: &ServiceDescription,
+        limit: usize,
+    ) -> Result<DestinationPermit, Error> {
+        let key = NamespacedHostname {
+            namespace: svc.namespace.clone(),
+            hostname: svc.hostname.clone(),
+        };
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(key.clone()).or_default();
+        if *count >= limit {
+            return Err(Error::DestinationOverloaded(
+                svc.hostname.to_string(),
+                limit,
+            ));
+        }
+        *count += 1;
+        let labels = DestinationServiceLabels::from(Some(svc));
+        self.in_flight.get_or_create(&labels).inc();
+        Ok(DestinationPermit {
+            limiter: self.clone(),
+            key,
+            labels,
+        })
+    }
+}
+
+pub struct DestinationPermit {
+    limiter: Arc<DestinationLimiter>,
+    key: NamespacedHostname,
+    labels: DestinationServiceLabels,
+}
+
+impl Drop for DestinationPermit {
+    fn drop(&mut self) {
+        self.limiter.in_flight.get_or_create(&self.labels).dec();
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::helpers::test_proxy_metrics;
+
+    fn service(name: &str) -> ServiceDescription {
+        ServiceDescription {
+            hostname: format!("{name}.default.svc.cluster.local").into(),
+            name: name.into(),
+            namespace: "default".into(),
+        }
+    }
+
+    #[test]
+    fn caps_open_connections() {
+        let metrics = test_proxy_metrics();
+        let limiter = Arc::new(DestinationLimiter::new(&metrics));
+        let (a, b) = (service("a"), service("b"));
+        let gauge = |svc: &ServiceDescription| {
+            metrics
+                .destination_service_in_flight
+                .get_or_create(&DestinationServiceLabels::from(Some(svc)))
+                .get()
+        };
+
+        let mut permits: Vec<_> = (0..3)
+            .map(|_| limiter.try_acquire(&a, 3).unwrap())
+            .collect();
+        assert_eq!(gauge(&a), 3);
+        assert!(matches!(
+            limiter.try_acquire(&a, 3),
+            Err(Error::DestinationOverloaded(host, 3)) if host == "a.default.svc.cluster.local"
+        ));
+        // Each service is limited separately.
+        let other = limiter.try_acquire(&b, 3).unwrap();
+        assert_eq!(gauge(&b), 1);
+
+        // Once a connection closes, another may open.
+        permits.pop();
+        assert_eq!(gauge(&a), 2);
+        permits.push(limiter.try_acquire(&a, 3).unwrap());
+
+        drop(permits);
+        drop(other);
+        assert_eq!(gauge(&a), 0);
+        assert_eq!(gauge(&b), 0);
+        assert!(
+            limiter.open.lock().unwrap().is_empty(),
+            "idle services are cleaned up"
+        );
+    }
+}
@@ -0,0 +1,356 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Egress to destinations outside the mesh, restricted by the TLS SNI.
+//
+// We do not terminate TLS; we only read the first record of the handshake to find the SNI, and replay it
+// to the upstream. As the client controls everything we parse here, any input that is not a well-formed
+// ClientHello is rejected rather than passed through.
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::proxy::Error;
+use crate::state::workload::EgressRule;
+
+const RECORD_HEADER_LEN: usize = 5;
+// The maximum length of a TLS plaintext record fragment.
+const MAX_RECORD_LEN: usize = 1 << 14;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const SERVER_NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// read_client_hello reads the first TLS record from the stream, which must hold a ClientHello.
+/// This returns the raw record, which must be sent on to the upstream, and the SNI.
+pub async fn read_client_hello<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<(Vec<u8>, String), Error> {
+    let mut record = vec![0u8; RECORD_HEADER_LEN];
+    stream.read_exact(&mut record).await?;
+    if record[0] != CONTENT_TYPE_HANDSHAKE {
+        return Err(Error::InvalidClientHello("not a TLS handshake"));
+    }
+    let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+    if len == 0 || len > MAX_RECORD_LEN {
+        return Err(Error::InvalidClientHello("invalid record length"));
+    }
+    record.resize(RECORD_HEADER_LEN + len, 0);
+    stream.read_exact(&mut record[RECORD_HEADER_LEN..]).await?;
+
+    let sni = parse_sni(&record[RECORD_HEADER_LEN..])?;
+    Ok((record, sni))
+}
+
+/// allowed checks if the SNI matches any of the allowlisted hosts. Hostnames are case-insensitive, so neither
+/// needs to be lowercase. A wildcard entry, such as "*.example.com", matches any subdomain, but not
+/// "example.com" itself.
+pub fn allowed(allowlist: &[String], sni: &str) -> bool {
+    allowlist.iter().any(|host| match host.strip_prefix("*.") {
+        Some(suffix) => sni
+            .len()
+            .checked_sub(suffix.len())
+            .filter(|&i| sni.is_char_boundary(i))
+            .map(|i| sni.split_at(i))
+            .filter(|(_, tail)| tail.eq_ignore_ascii_case(suffix))
+            .and_then(|(sub, _)| sub.strip_suffix('.'))
+            .is_some_and(|sub| !sub.is_empty()),
+        None => host.eq_ignore_ascii_case(sni),
+    })
+}
+
+/// authorize checks if a workload's egress rules allow it to connect to the destination, named by its service
+/// hostname, or by its IP address if it is not a service. A workload without egress rules may connect anywhere,
+/// while one with an empty list of rules may not connect at all.
+pub fn authorize(rules: Option<&[EgressRule]>, destination: &str, port: u16) -> bool {
+    let Some(rules) = rules else {
+        return true;
+    };
+    rules.iter().any(|rule| {
+        (rule.ports.is_empty() || rule.ports.contains(&port)) && allowed(&rule.hosts, destination)
+    })
+}
+
+// parse_sni finds the server name in a ClientHello handshake message.
+// The message must be contained in a single record; ClientHellos split across records are rejected.
+fn parse_sni(fragment: &[u8]) -> Result<String, Error> {
+    let mut r = Reader(fragment);
+    if r.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return Err(Error::InvalidClientHello("not a ClientHello"));
+    }
+    let mut hello = Reader(r.bytes(r.u24()?)?);
+    if !r.is_empty() {
+        return Err(Error::InvalidClientHello("trailing data after ClientHello"));
+    }
+
+    // legacy_version, random
+    hello.bytes(2 + 32)?;
+    // legacy_session_id
+    hello.vec8()?;
+    let cipher_suites = hello.vec16()?;
+    if cipher_suites.is_empty() || cipher_suites.len() % 2 != 0 {
+        return Err(Error::InvalidClientHello("invalid cipher suites"));
+    }
+    if hello.vec8()?.is_empty() {
+        return Err(Error::InvalidClientHello("invalid compression methods"));
+    }
+    if hello.is_empty() {
+        return Err(Error::InvalidClientHello("missing server name"));
+    }
+    let mut extensions = Reader(hello.vec16()?);
+    if !hello.is_empty() {
+        return Err(Error::InvalidClientHello("trailing data after extensions"));
+    }
+
+    let mut sni = None;
+    while !extensions.is_empty() {
+        let typ = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if typ != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        if sni.is_some() {
+            return Err(Error::InvalidClientHello("duplicate server name extension"));
+        }
+        sni = Some(parse_server_name(data)?);
+    }
+    sni.ok_or(Error::InvalidClientHello("missing server name"))
+}
+
+// parse_server_name parses the server_name extension (RFC 6066 section 3), which must have exactly one host name.
+fn parse_server_name(data: &[u8]) -> Result<String, Error> {
+    let mut ext = Reader(data);
+    let mut names = Reader(ext.vec16()?);
+    if !ext.is_empty() {
+        return Err(Error::InvalidClientHello(
+            "trailing data after server names",
+        ));
+    }
+    let mut host = None;
+    while !names.is_empty() {
+        let typ = names.u8()?;
+        let name = names.vec16()?;
+        if typ != SERVER_NAME_TYPE_HOST_NAME {
+            continue;
+        }
+        if host.is_some() {
+            return Err(Error::InvalidClientHello("duplicate host name"));
+        }
+        host = Some(name);
+    }
+    let host = host.ok_or(Error::InvalidClientHello("missing server name"))?;
+    if !valid_hostname(host) {
+        return Err(Error::InvalidClientHello("invalid server name"));
+    }
+    // valid_hostname ensures this is ASCII.
+    Ok(String::from_utf8_lossy(host).to_ascii_lowercase())
+}
+
+// valid_hostname checks the name is a DNS hostname. Per RFC 6066, it must not have a trailing dot.
+fn valid_hostname(name: &[u8]) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split(|b| *b == b'.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.first() != Some(&b'-')
+                && label.last() != Some(&b'-')
+                && label
+                    .iter()
+                    .all(|b| b.is_ascii_alphanumeric() || *b == b'-')
+        })
+}
+
+// Reader reads big-endian, length-prefixed TLS structures, failing rather than reading out of bounds.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < n {
+            return Err(Error::InvalidClientHello("truncated"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Result<usize, Error> {
+        let b = self.bytes(3)?;
+        Ok(u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+
+    fn vec8(&mut self) -> Result<&'a [u8], Error> {
+        let n = self.u8()? as usize;
+        self.bytes(n)
+    }
+
+    fn vec16(&mut self) -> Result<&'a [u8], Error> {
+        let n = self.u16()? as usize;
+        self.bytes(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        let mut v = (data.len() as u16).to_be_bytes().to_vec();
+        v.extend_from_slice(data);
+        v
+    }
+
+    fn sni_extension(host: &[u8]) -> Vec<u8> {
+        let mut name = vec![SERVER_NAME_TYPE_HOST_NAME];
+        name.extend(vec16(host));
+        let mut ext = EXTENSION_SERVER_NAME.to_be_bytes().to_vec();
+        ext.extend(vec16(&vec16(&name)));
+        ext
+    }
+
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend([0u8; 32]); // random
+        body.push(0); // session id
+        body.extend(vec16(&[0x13, 0x01])); // cipher suites
+        body.extend([1, 0]); // compression methods
+        body.extend(vec16(extensions));
+
+        let mut handshake = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(body);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend(vec16(&handshake));
+        record
+    }
+
+    async fn read(record: &[u8]) -> Result<String, Error> {
+        let mut stream = record;
+        let (read, sni) = read_client_hello(&mut stream).await?;
+        assert_eq!(read, record);
+        Ok(sni)
+    }
+
+    #[tokio::test]
+    async fn client_hello_sni() {
+        let mut extensions = vec![0x00, 0x0b, 0x00, 0x02, 0x01, 0x00]; // ec_point_formats
+        extensions.extend(sni_extension(b"API.example.com"));
+        assert_eq!(
+            read(&client_hello(&extensions)).await.unwrap(),
+            "api.example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn client_hello_malformed() {
+        let valid = client_hello(&sni_extension(b"example.com"));
+
+        // Not TLS at all
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        // Truncated anywhere
+        for n in 0..valid.len() {
+            assert!(read(&valid[..n]).await.is_err(), "truncated at {n}");
+        }
+        // Record length larger than its content
+        let mut long = valid.clone();
+        long[4] += 1;
+        long.push(0);
+        assert!(read(&long).await.is_err());
+        // No SNI
+        assert!(read(&client_hello(&[])).await.is_err());
+        // Duplicate SNI
+        let mut dup = sni_extension(b"example.com");
+        dup.extend(sni_extension(b"evil.com"));
+        assert!(read(&client_hello(&dup)).await.is_err());
+        // Invalid hostnames
+        for host in [
+            &b"example.com."[..],
+            b"",
+            b"exa mple.com",
+            b"-example.com",
+            b"a..com",
+        ] {
+            assert!(read(&client_hello(&sni_extension(host))).await.is_err());
+        }
+        // Extension lengths overflowing the extensions block
+        let mut overflow = sni_extension(b"example.com");
+        overflow[3] += 10;
+        assert!(read(&client_hello(&overflow)).await.is_err());
+    }
+
+    #[test]
+    fn allowlist() {
+        let allowlist = vec!["api.example.com".to_string(), "*.example.org".to_string()];
+        assert!(allowed(&allowlist, "api.example.com"));
+        assert!(allowed(&allowlist, "a.example.org"));
+        assert!(allowed(&allowlist, "a.b.example.org"));
+
+        assert!(!allowed(&allowlist, "example.com"));
+        assert!(!allowed(&allowlist, "other.example.com"));
+        assert!(!allowed(&allowlist, "example.org"));
+        assert!(!allowed(&allowlist, "badexample.org"));
+        assert!(!allowed(&[], "api.example.com"));
+    }
+
+    #[test]
+    fn allowlist_mixed_case() {
+        let allowlist = vec!["Api.Example.com".to_string(), "*.EXAMPLE.org".to_string()];
+        assert!(allowed(&allowlist, "API.example.COM"));
+        assert!(allowed(&allowlist, "A.Example.Org"));
+        assert!(allowed(&allowlist, "a.b.example.org"));
+
+        assert!(!allowed(&allowlist, "Example.Org"));
+        assert!(!allowed(&allowlist, "BadExample.org"));
+        // The suffix would start in the middle of the multibyte character.
+        assert!(!allowed(&allowlist, "éxample.org"));
+    }
+
+    #[test]
+    fn egress_rules() {
+        let rules = vec![
+            EgressRule {
+                hosts: vec!["db.ns.svc.cluster.local".to_string()],
+                ports: vec![5432],
+            },
+            EgressRule {
+                hosts: vec!["*.example.com".to_string(), "10.0.0.1".to_string()],
+                ports: vec![],
+            },
+        ];
+        let rules = Some(rules.as_slice());
+        assert!(authorize(rules, "db.ns.svc.cluster.local", 5432));
+        assert!(authorize(rules, "api.example.com", 443));
+        assert!(authorize(rules, "10.0.0.1", 80));
+
+        assert!(!authorize(rules, "db.ns.svc.cluster.local", 80));
+        assert!(!authorize(rules, "cache.ns.svc.cluster.local", 6379));
+        assert!(!authorize(rules, "10.0.0.2", 80));
+        // Without egress rules, everything is allowed, but an empty list allows nothing.
+        assert!(authorize(None, "cache.ns.svc.cluster.local", 6379));
+        assert!(!authorize(Some(&[]), "cache.ns.svc.cluster.local", 6379));
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::config::ForwardProxy;
+use crate::proxy::metrics::ForwardProxyFailure;
+use crate::proxy::{Error, Metrics, SocketFactory};
+use crate::state::DemandProxyState;
+
+// Bounds how much of the CONNECT response we will read, so a misbehaving proxy cannot make us buffer without limit.
+const MAX_RESPONSE_HEAD_SIZE: usize = 8 * 1024;
+
+/// connect establishes a TCP connection to `addr` through the forward proxy. Once the proxy accepts the CONNECT
+/// request, the returned stream carries bytes to and from `addr` as if it were connected directly.
+/// The timeout covers both connecting to the proxy and the CONNECT exchange.
+pub async fn connect(
+    proxy: &ForwardProxy,
+    addr: SocketAddr,
+    connect_timeout: Duration,
+    socket_factory: &(dyn SocketFactory + Send + Sync),
+    state: &DemandProxyState,
+    metrics: &Metrics,
+) -> Result<TcpStream, Error> {
+    let res = tokio::time::timeout(connect_timeout, async {
+        let proxy_addr = resolve(proxy, state).await?;
+        let (mut stream, _) = super::freebind_connect(
+            None,
+            proxy_addr,
+            connect_timeout,
+            socket_factory,
+            super::ConnectOptions::default().egress(),
+        )
+        .await
+        .map_err(Error::ForwardProxyConnect)?;
+        handshake(&mut stream, proxy, addr).await?;
+        Ok(stream)
+    })
+    .await
+    .unwrap_or_else(|_| {
+        Err(Error::ForwardProxyConnect(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "timed out connecting through the forward proxy",
+        )))
+    });
+    if let Err(e) = &res {
+        debug!(proxy=%proxy.authority, dest=%addr, "forward proxy connect failed: {e}");
+        let reason = match e {
+            Error::ForwardProxyRejected(_) => ForwardProxyFailure::rejected,
+            Error::ForwardProxyResponse(_) => ForwardProxyFailure::invalid_response,
+            _ => ForwardProxyFailure::connect,
+        };
+        metrics.record_forward_proxy_failure(reason);
+    }
+    res
+}
+
+// resolve returns the address of the proxy. Hostnames are looked up with our own resolver, as for other
+// destinations outside the mesh.
+async fn resolve(proxy: &ForwardProxy, state: &DemandProxyState) -> Result<SocketAddr, Error> {
+    if let Ok(addr) = proxy.authority.parse() {
+        return Ok(addr);
+    }
+    let (host, port) = proxy
+        .authority
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        .ok_or_else(|| {
+            Error::ForwardProxyConnect(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid proxy address {}", proxy.authority),
+            ))
+        })?;
+    let ip = state.resolve_hostname(host).await.map_err(|e| {
+        Error::ForwardProxyConnect(io::Error::new(io::ErrorKind::NotFound, e.to_string()))
+    })?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+// handshake sends the CONNECT request, and waits for the proxy to accept it.
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    proxy: &ForwardProxy,
+    addr: SocketAddr,
+) -> Result<(), Error> {
+    let mut request = format!("CONNECT {addr} HTTP/1.1\r\nHost: {addr}\r\n");
+    if let Some(credentials) = &proxy.basic_auth {
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            STANDARD.encode(credentials)
+        ));
+    }
+    request.push_str("\r\n");
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| Error::ForwardProxyResponse(e.to_string()))?;
+
+    let head = read_response_head(stream).await?;
+    match parse_status(&head) {
+        // Any successful response means the tunnel is established.
+        Some(status) if (200..300).contains(&status) => Ok(()),
+        Some(status) => Err(Error::ForwardProxyRejected(status)),
+        None => Err(Error::ForwardProxyResponse(format!(
+            "malformed status line {:?}",
+            head.lines().next().unwrap_or_default()
+        ))),
+    }
+}
+
+// read_response_head reads the response up to the blank line ending its headers. Anything after it belongs to the
+// tunnel, so this reads a byte at a time rather than risk consuming it; the head is small and read once per connection.
+async fn read_response_head<S: AsyncRead + Unpin>(stream: &mut S) -> Result<String, Error> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD_SIZE {
+            return Err(Error::ForwardProxyResponse(format!(
+                "response headers exceed {MAX_RESPONSE_HEAD_SIZE} bytes"
+            )));
+        }
+        let b = stream.read_u8().await.map_err(|e| {
+            Error::ForwardProxyResponse(if e.kind() == io::ErrorKind::UnexpectedEof {
+                "connection closed before the response was complete".to_string()
+            } else {
+                e.to_string()
+            })
+        })?;
+        head.push(b);
+    }
+    String::from_utf8(head).map_err(|_| Error::ForwardProxyResponse("response is not UTF-8".into()))
+}
+
+// parse_status returns the status code from a response such as "HTTP/1.1 200 Connection established".
+fn parse_status(head: &str) -> Option<u16> {
+    let mut parts = head.lines().next()?.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    fn proxy(basic_auth: Option<&str>) -> ForwardProxy {
+        ForwardProxy {
+            authority: "proxy.example.com:3128".to_string(),
+            basic_auth: basic_auth.map(str::to_string),
+        }
+    }
+
+    // run_handshake performs a handshake against a peer replying with `response`, returning the result, the
+    // request that was sent, and anything the peer sent after its response.
+    async fn run_handshake(
+        proxy: ForwardProxy,
+        response: &'static [u8],
+    ) -> (Result<(), Error>, String, Vec<u8>) {
+        let (mut client, mut server) = duplex(4096);
+        let peer = tokio::spawn(async move {
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(server.read_u8().await.unwrap());
+            }
+            server.write_all(response).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        let dest: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let res = handshake(&mut client, &proxy, dest).await;
+        let request = peer.await.unwrap();
+        let mut rest = Vec::new();
+        if res.is_ok() {
+            client.read_to_end(&mut rest).await.unwrap();
+        }
+        (res, request, rest)
+    }
+
+    #[tokio::test]
+    async fn handshake_established() {
+        let (res, request, rest) = run_handshake(
+            proxy(None),
+            b"HTTP/1.1 200 Connection established\r\nVia: test\r\n\r\ntunneled",
+        )
+        .await;
+        assert!(res.is_ok());
+        assert_eq!(
+            request,
+            "CONNECT 10.0.0.1:443 HTTP/1.1\r\nHost: 10.0.0.1:443\r\n\r\n"
+        );
+        // Bytes after the response are left for the tunnel
+        assert_eq!(rest, b"tunneled");
+    }
+
+    #[tokio::test]
+    async fn handshake_basic_auth() {
+        let (res, request, _) =
+            run_handshake(proxy(Some("user:secret")), b"HTTP/1.1 200 OK\r\n\r\n").await;
+        assert!(res.is_ok());
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+    }
+
+    #[tokio::test]
+    async fn handshake_failures() {
+        let (res, _, _) = run_handshake(
+            proxy(None),
+            b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n",
+        )
+        .await;
+        assert!(matches!(res, Err(Error::ForwardProxyRejected(407))));
+
+        let (res, _, _) = run_handshake(proxy(None), b"SSH-2.0-OpenSSH\r\n\r\n").await;
+        assert!(matches!(res, Err(Error::ForwardProxyResponse(_))));
+
+        let (res, _, _) = run_handshake(proxy(None), b"HTTP/1.1 200 OK\r\n").await;
+        assert!(matches!(res, Err(Error::ForwardProxyResponse(_))));
+    }
+
+    #[tokio::test]
+    async fn resolve_proxy() {
+        let state = DemandProxyState::new(
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+            crate::test_helpers::helpers::test_proxy_metrics(),
+        )
+        .with_static_hosts(std::collections::HashMap::from([(
+            "proxy.example.com".to_string(),
+            vec!["192.0.2.1".parse().unwrap()],
+        )]));
+
+        // Hostnames go through our resolver, which answers pinned hosts from the configuration.
+        assert_eq!(
+            resolve(&proxy(None), &state).await.unwrap(),
+            "192.0.2.1:3128".parse().unwrap()
+        );
+        let literal = ForwardProxy {
+            authority: "[fd00::1]:80".to_string(),
+            basic_auth: None,
+        };
+        assert_eq!(
+            resolve(&literal, &state).await.unwrap(),
+            "[fd00::1]:80".parse().unwrap()
+        );
+    }
+}
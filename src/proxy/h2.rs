@@ -13,46 +13,71 @@
 // limitations under the License.
 
 use crate::copy;
+use crate::proxy::metrics::ResetFrame;
 use bytes::Bytes;
 use futures_core::ready;
 use h2::Reason;
+use std::fmt;
 use std::io::Error;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::task::{Context, Poll};
 use std::time::Duration;
 use tokio::sync::oneshot;
 use tracing::trace;
 
 pub mod client;
+mod header_bytes;
 pub mod server;
 
+// How often the server side of HBONE connections pings the client, and how long it waits for an answer.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+const PING_TIMEOUT: Duration = Duration::from_secs(20);
+
+// PingFailure is why do_ping_pong gave up on a connection.
+#[derive(Debug, PartialEq, Eq)]
+enum PingFailure {
+    Timeout,
+    Error,
+}
+
+// do_ping_pong sends a PING every `interval`, notifying `tx` if the peer does not answer within `timeout`.
+// If `active_streams` is set, PINGs are skipped while any streams are open; they already exercise the
+// connection, and any failure will surface on them.
 async fn do_ping_pong(
     mut ping_pong: h2::PingPong,
-    tx: oneshot::Sender<()>,
+    tx: oneshot::Sender<PingFailure>,
     dropped: Arc<AtomicBool>,
+    interval: Duration,
+    timeout: Duration,
+    active_streams: Option<Arc<AtomicU16>>,
 ) {
-    const PING_INTERVAL: Duration = Duration::from_secs(10);
-    const PING_TIMEOUT: Duration = Duration::from_secs(20);
     // delay before sending the first ping, no need to race with the first request
-    tokio::time::sleep(PING_INTERVAL).await;
+    tokio::time::sleep(interval).await;
     loop {
         if dropped.load(Ordering::Relaxed) {
             return;
         }
+        if active_streams
+            .as_ref()
+            .is_some_and(|streams| streams.load(Ordering::Relaxed) > 0)
+        {
+            tokio::time::sleep(interval).await;
+            continue;
+        }
         let ping_fut = ping_pong.ping(h2::Ping::opaque());
         log::debug!("ping sent");
-        match tokio::time::timeout(PING_TIMEOUT, ping_fut).await {
+        match tokio::time::timeout(timeout, ping_fut).await {
             Err(_) => {
                 log::error!("ping timeout");
-                let _ = tx.send(());
+                let _ = tx.send(PingFailure::Timeout);
                 return;
             }
             Ok(r) => match r {
                 Ok(_) => {
                     log::debug!("pong received");
-                    tokio::time::sleep(PING_INTERVAL).await;
+                    tokio::time::sleep(interval).await;
                 }
                 Err(e) => {
                     if dropped.load(Ordering::Relaxed) {
@@ -60,7 +85,7 @@ async fn do_ping_pong(
                         return;
                     }
                     log::error!("ping error: {e}");
-                    let _ = tx.send(());
+                    let _ = tx.send(PingFailure::Error);
                     return;
                 }
             },
@@ -68,20 +93,73 @@ async fn do_ping_pong(
     }
 }
 
+/// StreamReset is how an HBONE stream was reset: by a RST_STREAM frame for the stream alone, or by a GOAWAY
+/// frame closing the whole connection, along with the HTTP/2 error code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamReset {
+    pub frame: ResetFrame,
+    pub code: Reason,
+}
+
+impl StreamReset {
+    /// from_error returns the reset an h2 error reports, if any. I/O errors and misuse of the h2 API carry no
+    /// error code, so they are not resets.
+    pub fn from_error(err: &h2::Error) -> Option<Self> {
+        let code = err.reason()?;
+        let frame = if err.is_go_away() {
+            ResetFrame::goaway
+        } else {
+            ResetFrame::rst_stream
+        };
+        Some(StreamReset { frame, code })
+    }
+}
+
+impl fmt::Display for StreamReset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = match self.frame {
+            ResetFrame::rst_stream => "RST_STREAM",
+            ResetFrame::goaway => "GOAWAY",
+        };
+        // Reason's Debug is the name of the code, such as CANCEL, or its number if it is not a known code.
+        write!(f, "{frame}({:?})", self.code)
+    }
+}
+
+// ResetTracker is where the halves of an H2Stream record the first reset they see.
+type ResetTracker = Option<Arc<OnceLock<StreamReset>>>;
+
 // H2Stream represents an active HTTP2 stream. Consumers can only Read/Write
 pub struct H2Stream {
     read: H2StreamReadHalf,
     write: H2StreamWriteHalf,
 }
 
+impl H2Stream {
+    /// track_resets records the first reset of the stream, if it is reset, in `reset`.
+    pub fn track_resets(mut self, reset: Arc<OnceLock<StreamReset>>) -> Self {
+        self.read.reset = Some(reset.clone());
+        self.write.reset = Some(reset);
+        self
+    }
+}
+
 pub struct H2StreamReadHalf {
     recv_stream: h2::RecvStream,
     _dropped: Option<DropCounter>,
+    reset: ResetTracker,
 }
 
 pub struct H2StreamWriteHalf {
     send_stream: h2::SendStream<Bytes>,
     _dropped: Option<DropCounter>,
+    reset: ResetTracker,
+}
+
+fn record_reset(tracker: &ResetTracker, reset: StreamReset) {
+    if let Some(tracker) = tracker {
+        let _ = tracker.set(reset);
+    }
 }
 
 struct DropCounter {
@@ -116,6 +194,20 @@ impl crate::copy::BufferedSplitter for H2Stream {
 }
 
 impl H2StreamWriteHalf {
+    // record_reset records the outcome of poll_reset: a reset the peer sent, or the error that ended the stream.
+    fn record_reset(&self, reset: &Result<Reason, h2::Error>) {
+        let reset = match reset {
+            Ok(code) => Some(StreamReset {
+                frame: ResetFrame::rst_stream,
+                code: *code,
+            }),
+            Err(e) => StreamReset::from_error(e),
+        };
+        if let Some(reset) = reset {
+            record_reset(&self.reset, reset);
+        }
+    }
+
     fn write_slice(&mut self, buf: Bytes, end_of_stream: bool) -> Result<(), std::io::Error> {
         self.send_stream
             .send_data(buf, end_of_stream)
@@ -153,6 +245,9 @@ impl copy::ResizeBufRead for H2StreamReadHalf {
                     return Poll::Ready(Ok(buf));
                 }
                 Some(Err(e)) => {
+                    if let Some(reset) = StreamReset::from_error(&e) {
+                        record_reset(&this.reset, reset);
+                    }
                     return Poll::Ready(match e.reason() {
                         Some(Reason::NO_ERROR) | Some(Reason::CANCEL) => {
                             return Poll::Ready(Ok(Bytes::new()))
@@ -161,7 +256,7 @@ impl copy::ResizeBufRead for H2StreamReadHalf {
                             Err(Error::new(std::io::ErrorKind::BrokenPipe, e))
                         }
                         _ => Err(h2_to_io_error(e)),
-                    })
+                    });
                 }
             }
         }
@@ -195,15 +290,15 @@ impl copy::AsyncWriteBuf for H2StreamWriteHalf {
             return Poll::Ready(Ok(cnt));
         }
 
-        Poll::Ready(Err(h2_to_io_error(
-            match ready!(self.send_stream.poll_reset(cx)) {
-                Ok(Reason::NO_ERROR) | Ok(Reason::CANCEL) | Ok(Reason::STREAM_CLOSED) => {
-                    return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
-                }
-                Ok(reason) => reason.into(),
-                Err(e) => e,
-            },
-        )))
+        let reset = ready!(self.send_stream.poll_reset(cx));
+        self.record_reset(&reset);
+        Poll::Ready(Err(h2_to_io_error(match reset {
+            Ok(Reason::NO_ERROR) | Ok(Reason::CANCEL) | Ok(Reason::STREAM_CLOSED) => {
+                return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+            }
+            Ok(reason) => reason.into(),
+            Err(e) => e,
+        })))
     }
 
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
@@ -219,16 +314,16 @@ impl copy::AsyncWriteBuf for H2StreamWriteHalf {
             return Poll::Ready(Ok(()));
         }
 
-        Poll::Ready(Err(h2_to_io_error(
-            match ready!(self.send_stream.poll_reset(cx)) {
-                Ok(Reason::NO_ERROR) => return Poll::Ready(Ok(())),
-                Ok(Reason::CANCEL) | Ok(Reason::STREAM_CLOSED) => {
-                    return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
-                }
-                Ok(reason) => reason.into(),
-                Err(e) => e,
-            },
-        )))
+        let reset = ready!(self.send_stream.poll_reset(cx));
+        self.record_reset(&reset);
+        Poll::Ready(Err(h2_to_io_error(match reset {
+            Ok(Reason::NO_ERROR) => return Poll::Ready(Ok(())),
+            Ok(Reason::CANCEL) | Ok(Reason::STREAM_CLOSED) => {
+                return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+            }
+            Ok(reason) => reason.into(),
+            Err(e) => e,
+        })))
     }
 }
 
@@ -239,3 +334,57 @@ fn h2_to_io_error(e: h2::Error) -> std::io::Error {
         std::io::Error::new(std::io::ErrorKind::Other, e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::copy::{BufferedSplitter, ResizeBufRead};
+
+    #[tokio::test]
+    async fn stream_reset_is_tracked() {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let mut conn = h2::server::handshake(server_io).await.unwrap();
+            let (_req, mut respond) = conn.accept().await.unwrap().unwrap();
+            let mut send = respond
+                .send_response(http::Response::new(()), false)
+                .unwrap();
+            send.send_reset(Reason::REFUSED_STREAM);
+            // Keep driving the connection, so the reset is sent.
+            while let Some(Ok(_)) = conn.accept().await {}
+        });
+        let (client, conn) = h2::client::handshake(client_io).await.unwrap();
+        tokio::spawn(conn);
+        let mut client = client.ready().await.unwrap();
+        let req = http::Request::get("http://example.com/").body(()).unwrap();
+        let (response, send_stream) = client.send_request(req, false).unwrap();
+        let recv_stream = response.await.unwrap().into_body();
+
+        let reset = Arc::new(OnceLock::new());
+        let stream = H2Stream {
+            read: H2StreamReadHalf {
+                recv_stream,
+                _dropped: None,
+                reset: None,
+            },
+            write: H2StreamWriteHalf {
+                send_stream,
+                _dropped: None,
+                reset: None,
+            },
+        }
+        .track_resets(reset.clone());
+        let (mut read, _write) = stream.split_into_buffered_reader();
+        let res = futures::future::poll_fn(|cx| Pin::new(&mut read).poll_bytes(cx)).await;
+        assert!(res.is_err());
+        let reset = *reset.get().unwrap();
+        assert_eq!(
+            reset,
+            StreamReset {
+                frame: ResetFrame::rst_stream,
+                code: Reason::REFUSED_STREAM,
+            }
+        );
+        assert_eq!(reset.to_string(), "RST_STREAM(REFUSED_STREAM)");
+    }
+}
@@ -13,18 +13,25 @@
 // limitations under the License.
 
 use crate::config;
-use crate::proxy::Error;
+use crate::proxy::h2::header_bytes::HeaderMeteredStream;
+use crate::proxy::metrics::Reporter;
+use crate::proxy::{Error, Metrics};
+use crate::socket;
+use crate::tls::session::{self, OnHandshake};
+use crate::tls::HandshakeSummary;
 use bytes::{Buf, Bytes};
 use h2::client::{Connection, SendRequest};
 use h2::SendStream;
 use http::Request;
+use prometheus_client::metrics::counter::Counter;
 use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio::sync::oneshot;
-use tokio::sync::watch::Receiver;
+use tokio::sync::watch::{self, Receiver};
 use tokio_rustls::client::TlsStream;
 use tracing::{debug, error, trace, warn, Instrument};
 
@@ -34,9 +41,18 @@ pub struct H2ConnectClient {
     sender: SendRequest<Bytes>,
     pub max_allowed_streams: u16,
     stream_count: Arc<AtomicU16>,
+    handshake: Arc<OnceLock<HandshakeSummary>>,
+    // Set once the peer's SETTINGS have been processed, if extended CONNECT may be used. Until then, whether the
+    // peer allows it is unknown.
+    settings_known: Option<Receiver<bool>>,
 }
 
 impl H2ConnectClient {
+    // handshake returns what the TLS handshake of the connection negotiated, once it has completed.
+    pub fn handshake(&self) -> Option<HandshakeSummary> {
+        self.handshake.get().copied()
+    }
+
     // will_be_at_max_streamcount checks if a stream will be maxed out if we send one more request on it
     pub fn will_be_at_max_streamcount(&self) -> bool {
         let future_count = self.stream_count.load(Ordering::Relaxed) + 1;
@@ -81,10 +97,12 @@ impl H2ConnectClient {
         let read = crate::proxy::h2::H2StreamReadHalf {
             recv_stream: recv,
             _dropped: dropped1,
+            reset: None,
         };
         let write = crate::proxy::h2::H2StreamWriteHalf {
             send_stream: send,
             _dropped: dropped2,
+            reset: None,
         };
         let h2 = crate::proxy::h2::H2Stream { read, write };
         Ok(h2)
@@ -98,6 +116,18 @@ impl H2ConnectClient {
         // "This function must return `Ready` before `send_request` is called"
         // We should always be ready though, because we make sure we don't go over the max stream limit out of band.
         futures::future::poll_fn(|cx| self.sender.poll_ready(cx)).await?;
+        if req.extensions().get::<h2::ext::Protocol>().is_some() {
+            // Only extended CONNECT requests wait for the peer's SETTINGS; usually they are long known.
+            // If the connection fails first, the check below rejects the request.
+            if let Some(settings_known) = &mut self.settings_known {
+                let _ = settings_known.wait_for(|known| *known).await;
+            }
+            if !self.sender.is_extended_connect_protocol_enabled() {
+                return Err(Error::UnsupportedFeature(
+                    "peer does not support extended CONNECT".to_string(),
+                ));
+            }
+        }
         let (response, stream) = self.sender.send_request(req, false)?;
         let response = response.await?;
         if response.status() != 200 {
@@ -107,75 +137,236 @@ impl H2ConnectClient {
     }
 }
 
+// spawn_connection establishes an HTTP/2 connection over `s`, driving it in the background until it is closed.
+// While idle, the connection is checked with PINGs as configured in `settings`; if the peer stops answering, the
+// connection is closed and counted in `pool_keepalive_timeouts`.
 pub async fn spawn_connection(
     cfg: Arc<config::Config>,
+    settings: config::PoolSettings,
     s: TlsStream<TcpStream>,
     driver_drain: Receiver<bool>,
+    metrics: &Metrics,
 ) -> Result<H2ConnectClient, Error> {
     let mut builder = h2::client::Builder::new();
     builder
+        .header_table_size(cfg.hpack_table_size)
         .initial_window_size(cfg.window_size)
         .initial_connection_window_size(cfg.connection_window_size)
         .max_frame_size(cfg.frame_size)
-        .initial_max_send_streams(cfg.pool_max_streams_per_conn as usize)
+        .initial_max_send_streams(settings.max_streams_per_conn as usize)
         .max_header_list_size(1024 * 16)
         // 4mb. Aligned with window_size such that we can fill up the buffer, then flush it all in one go, without buffering up too much.
         .max_send_buffer_size(cfg.window_size as usize)
         .enable_push(false);
 
-    let (send_req, connection) = builder
-        .handshake::<_, Bytes>(s)
+    let stall_check = if cfg.hbone_stall_check_interval.is_zero() {
+        None
+    } else {
+        match socket2::SockRef::from(s.get_ref().0).try_clone() {
+            Ok(socket) => Some(StallCheck {
+                socket,
+                interval: cfg.hbone_stall_check_interval,
+                stalls: metrics.hbone_connection_stalls.clone(),
+            }),
+            Err(err) => {
+                debug!("failed to set up HBONE stall check: {err}");
+                None
+            }
+        }
+    };
+
+    // With early data, the handshake is still in progress here, so it is counted once it completes.
+    let handshake = Arc::new(OnceLock::new());
+    let s = OnHandshake::new(s, {
+        let record = metrics.tls_handshake_recorder(Reporter::source);
+        let handshake = handshake.clone();
+        move |conn: &rustls::CommonState| {
+            record(session::is_resumed(conn).unwrap_or_default());
+            if let Some(summary) = HandshakeSummary::from_connection(conn) {
+                let _ = handshake.set(summary);
+            }
+        }
+    });
+    let header_bytes = cfg.hbone_header_metrics.then(|| metrics.header_bytes());
+    let (send_req, mut connection) = builder
+        .handshake::<_, Bytes>(HeaderMeteredStream::client(s, header_bytes))
         .await
         .map_err(Error::Http2Handshake)?;
+    let ping_pong = connection
+        .ping_pong()
+        .expect("ping_pong should only be called once");
+    // Whether the peer allows extended CONNECT is only known once its SETTINGS are processed, which is learned
+    // in the background, so that only UDP streams wait for it.
+    let (settings_tx, settings_known) = if cfg.enable_hbone_udp {
+        let (tx, rx) = watch::channel(false);
+        (Some(tx), Some(rx))
+    } else {
+        (None, None)
+    };
 
     // We store max as u16, so if they report above that max size we just cap at u16::MAX
     let max_allowed_streams = std::cmp::min(
-        cfg.pool_max_streams_per_conn,
+        settings.max_streams_per_conn,
         connection
             .max_concurrent_send_streams()
             .try_into()
             .unwrap_or(u16::MAX),
     );
+    let stream_count = Arc::new(AtomicU16::new(0));
+    let keepalive = Keepalive {
+        interval: settings.h2_keepalive_interval,
+        timeout: settings.h2_keepalive_timeout,
+        active_streams: stream_count.clone(),
+        timeouts: metrics.pool_keepalive_timeouts.clone(),
+    };
     // spawn a task to poll the connection and drive the HTTP state
     // if we got a drain for that connection, respect it in a race
     // it is important to have a drain here, or this connection will never terminate
     tokio::spawn(
         async move {
-            drive_connection(connection, driver_drain).await;
+            drive_connection(
+                connection,
+                ping_pong,
+                driver_drain,
+                keepalive,
+                stall_check,
+                settings_tx,
+            )
+            .await;
         }
         .in_current_span(),
     );
 
     let c = H2ConnectClient {
         sender: send_req,
-        stream_count: Arc::new(AtomicU16::new(0)),
+        stream_count,
         max_allowed_streams,
+        handshake,
+        settings_known,
     };
     Ok(c)
 }
 
-async fn drive_connection<S, B>(mut conn: Connection<S, B>, mut driver_drain: Receiver<bool>)
-where
+struct Keepalive {
+    interval: Duration,
+    timeout: Duration,
+    active_streams: Arc<AtomicU16>,
+    timeouts: Counter,
+}
+
+// StallCheck periodically reads TCP_INFO from the outer TCP connection of an HBONE connection. It holds a
+// duplicate of the socket's file descriptor, which is closed along with the connection's driver.
+struct StallCheck {
+    socket: socket2::Socket,
+    interval: Duration,
+    stalls: Counter,
+}
+
+impl StallCheck {
+    // run reports each stall once, when it is first seen. It never completes.
+    async fn run(self) {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut stalled = false;
+        loop {
+            ticker.tick().await;
+            let diagnostics = match socket::tcp_diagnostics(&self.socket) {
+                Ok(diagnostics) => diagnostics,
+                Err(err) => {
+                    debug!("stopping HBONE stall check: {err}");
+                    return std::future::pending().await;
+                }
+            };
+            if diagnostics.stalled() && !stalled {
+                self.stalls.inc();
+                warn!(
+                    pmtu = diagnostics.pmtu,
+                    mss = diagnostics.snd_mss,
+                    unacked = diagnostics.unacked,
+                    retransmits = diagnostics.retransmits,
+                    total_retransmits = diagnostics.total_retrans,
+                    "HBONE connection stalled with unacknowledged data; if this persists for large transfers only, \
+                    the path MTU may be lower than the MSS (see TCP_MAX_SEGMENT_SIZE)"
+                );
+            }
+            stalled = diagnostics.stalled();
+        }
+    }
+}
+
+// exchange_settings marks the peer's SETTINGS as known once it answers a PING: they are the first frame it sends,
+// so they have been processed by then. If the connection fails first, `settings_tx` is dropped instead.
+async fn exchange_settings(ping_pong: &mut h2::PingPong, settings_tx: watch::Sender<bool>) {
+    match ping_pong.ping(h2::Ping::opaque()).await {
+        Ok(_) => {
+            let _ = settings_tx.send(true);
+        }
+        Err(err) => debug!("failed to exchange settings: {err}"),
+    }
+}
+
+async fn drive_connection<S, B>(
+    mut conn: Connection<S, B>,
+    mut ping_pong: h2::PingPong,
+    mut driver_drain: Receiver<bool>,
+    keepalive: Keepalive,
+    stall_check: Option<StallCheck>,
+    settings_tx: Option<watch::Sender<bool>>,
+) where
     S: AsyncRead + AsyncWrite + Send + Unpin,
     B: Buf,
 {
-    let ping_pong = conn
-        .ping_pong()
-        .expect("ping_pong should only be called once");
+    let Keepalive {
+        interval,
+        timeout,
+        active_streams,
+        timeouts,
+    } = keepalive;
     // for ping to inform this fn to drop the connection
-    let (ping_drop_tx, ping_drop_rx) = oneshot::channel::<()>();
+    let (ping_drop_tx, ping_drop_rx) = oneshot::channel();
     // for this fn to inform ping to give up when it is already dropped
     let dropped = Arc::new(AtomicBool::new(false));
-    tokio::task::spawn(
-        super::do_ping_pong(ping_pong, ping_drop_tx, dropped.clone()).in_current_span(),
-    );
+    if settings_tx.is_some() || !interval.is_zero() {
+        // PINGs are answered while the connection is driven below, and fail once it is dropped.
+        let dropped = dropped.clone();
+        tokio::task::spawn(
+            async move {
+                if let Some(settings_tx) = settings_tx {
+                    exchange_settings(&mut ping_pong, settings_tx).await;
+                }
+                if !interval.is_zero() {
+                    super::do_ping_pong(
+                        ping_pong,
+                        ping_drop_tx,
+                        dropped,
+                        interval,
+                        timeout,
+                        Some(active_streams),
+                    )
+                    .await;
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    let stall_check = async {
+        match stall_check {
+            Some(check) => check.run().await,
+            None => std::future::pending().await,
+        }
+    };
 
     tokio::select! {
         _ = driver_drain.changed() => {
             debug!("draining outer HBONE connection");
         }
-        _ = ping_drop_rx => {
+        _ = stall_check => {}
+        Ok(failure) = ping_drop_rx => {
             warn!("HBONE ping timeout/error");
+            if failure == super::PingFailure::Timeout {
+                timeouts.inc();
+            }
         }
         res = conn => {
             match res {
@@ -191,3 +382,139 @@ where
     // Signal to the ping_pong it should also stop.
     dropped.store(true, Ordering::Relaxed);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    // unresponsive_connection returns a client connection whose peer completes the handshake, and then
+    // never reads again, so PINGs go unanswered. The peer must be kept alive for the duration of the test.
+    async fn unresponsive_connection() -> (
+        SendRequest<Bytes>,
+        Connection<DuplexStream, Bytes>,
+        h2::server::Connection<DuplexStream, Bytes>,
+    ) {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let (client, server) = tokio::join!(
+            h2::client::handshake(client_io),
+            h2::server::handshake(server_io)
+        );
+        let (sender, connection) = client.unwrap();
+        (sender, connection, server.unwrap())
+    }
+
+    fn ping_pong(connection: &mut Connection<DuplexStream, Bytes>) -> h2::PingPong {
+        connection.ping_pong().unwrap()
+    }
+
+    fn keepalive(active_streams: u16, timeouts: &Counter) -> Keepalive {
+        Keepalive {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(20),
+            active_streams: Arc::new(AtomicU16::new(active_streams)),
+            timeouts: timeouts.clone(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_timeout_closes_connection() {
+        let (mut sender, mut connection, _peer) = unresponsive_connection().await;
+        let ping_pong = ping_pong(&mut connection);
+        let (_drain_tx, drain_rx) = tokio::sync::watch::channel(false);
+        let timeouts = Counter::default();
+
+        let start = tokio::time::Instant::now();
+        drive_connection(
+            connection,
+            ping_pong,
+            drain_rx,
+            keepalive(0, &timeouts),
+            None,
+            None,
+        )
+        .await;
+        // The first PING is sent after one interval, and given up on after the timeout
+        assert!(start.elapsed() >= Duration::from_secs(30));
+        assert_eq!(timeouts.get(), 1);
+
+        // The connection is closed, so the pool will evict it rather than send another stream on it
+        let cx = &mut Context::from_waker(futures::task::noop_waker_ref());
+        assert!(matches!(sender.poll_ready(cx), Poll::Ready(Err(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_skipped_with_active_streams() {
+        let (_sender, mut connection, _peer) = unresponsive_connection().await;
+        let ping_pong = ping_pong(&mut connection);
+        let (_drain_tx, drain_rx) = tokio::sync::watch::channel(false);
+        let timeouts = Counter::default();
+
+        let driven = tokio::time::timeout(
+            Duration::from_secs(120),
+            drive_connection(
+                connection,
+                ping_pong,
+                drain_rx,
+                keepalive(1, &timeouts),
+                None,
+                None,
+            ),
+        )
+        .await;
+        assert!(driven.is_err(), "connection should stay open");
+        assert_eq!(timeouts.get(), 0);
+    }
+
+    // connect_udp sends an extended CONNECT request over a new connection to a peer that answers every request,
+    // and allows extended CONNECT if `extended_connect` is set.
+    async fn connect_udp(extended_connect: bool) -> Result<(), Error> {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let mut builder = h2::server::Builder::new();
+            if extended_connect {
+                builder.enable_connect_protocol();
+            }
+            let mut conn = builder.handshake::<_, Bytes>(server_io).await.unwrap();
+            while let Some(Ok((_req, mut respond))) = conn.accept().await {
+                respond
+                    .send_response(http::Response::new(()), false)
+                    .unwrap();
+            }
+        });
+        let (sender, mut connection) = h2::client::handshake(client_io).await.unwrap();
+        let ping_pong = ping_pong(&mut connection);
+        let (_drain_tx, drain_rx) = tokio::sync::watch::channel(false);
+        let (settings_tx, settings_known) = watch::channel(false);
+        tokio::spawn(drive_connection(
+            connection,
+            ping_pong,
+            drain_rx,
+            keepalive(0, &Counter::default()),
+            None,
+            Some(settings_tx),
+        ));
+        let mut client = H2ConnectClient {
+            sender,
+            max_allowed_streams: 100,
+            stream_count: Arc::new(AtomicU16::new(0)),
+            handshake: Arc::new(OnceLock::new()),
+            settings_known: Some(settings_known),
+        };
+        let req = Request::builder()
+            .method(http::Method::CONNECT)
+            .uri("https://127.0.0.1:15008/.well-known/masque/udp/127.0.0.1/53/")
+            .extension(h2::ext::Protocol::from_static("connect-udp"))
+            .body(())
+            .unwrap();
+        client.send_request(req).await.map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn extended_connect_waits_for_settings() {
+        // The request is sent right after the handshake, before the peer's SETTINGS can have been processed.
+        connect_udp(true).await.unwrap();
+        let err = connect_udp(false).await.unwrap_err();
+        assert!(matches!(err, Error::UnsupportedFeature(_)), "{err}");
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::ready;
+use prometheus_client::metrics::counter::Counter;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const CLIENT_PREFACE_LEN: usize = 24;
+const FRAME_HEADER_LEN: usize = 9;
+const FRAME_TYPE_HEADERS: u8 = 0x1;
+const FRAME_TYPE_CONTINUATION: u8 = 0x9;
+
+/// FrameScanner follows the HTTP/2 frame boundaries in one direction of a connection, counting the payload
+/// bytes of HEADERS and CONTINUATION frames. These carry the HPACK encoded header blocks, so the count is the
+/// size of headers on the wire, after compression.
+struct FrameScanner {
+    // Bytes of the client connection preface not yet seen, which precedes the first frame.
+    preface: usize,
+    // A frame header split across reads or writes is buffered until it is complete.
+    header: [u8; FRAME_HEADER_LEN],
+    header_len: usize,
+    // Bytes of the current frame's payload not yet seen, and whether they are a header block.
+    remaining: usize,
+    counting: bool,
+    counter: Counter,
+}
+
+impl FrameScanner {
+    fn new(preface: usize, counter: Counter) -> Self {
+        FrameScanner {
+            preface,
+            header: [0; FRAME_HEADER_LEN],
+            header_len: 0,
+            remaining: 0,
+            counting: false,
+            counter,
+        }
+    }
+
+    fn scan(&mut self, mut buf: &[u8]) {
+        while !buf.is_empty() {
+            if self.preface > 0 {
+                let n = self.preface.min(buf.len());
+                self.preface -= n;
+                buf = &buf[n..];
+            } else if self.remaining > 0 {
+                let n = self.remaining.min(buf.len());
+                if self.counting {
+                    self.counter.inc_by(n as u64);
+                }
+                self.remaining -= n;
+                buf = &buf[n..];
+            } else {
+                let n = (FRAME_HEADER_LEN - self.header_len).min(buf.len());
+                self.header[self.header_len..self.header_len + n].copy_from_slice(&buf[..n]);
+                self.header_len += n;
+                buf = &buf[n..];
+                if self.header_len == FRAME_HEADER_LEN {
+                    let h = self.header;
+                    self.header_len = 0;
+                    self.remaining = u32::from_be_bytes([0, h[0], h[1], h[2]]) as usize;
+                    self.counting = matches!(h[3], FRAME_TYPE_HEADERS | FRAME_TYPE_CONTINUATION);
+                }
+            }
+        }
+    }
+}
+
+/// HeaderMeteredStream wraps the transport of an HTTP/2 connection, counting the bytes of header blocks
+/// sent and received over it. Everything else is passed through untouched. Without counters, nothing is
+/// scanned, and the stream is a plain passthrough.
+pub struct HeaderMeteredStream<S> {
+    inner: S,
+    // The scanners for what is read and what is written, if header bytes are counted.
+    scanners: Option<(FrameScanner, FrameScanner)>,
+}
+
+impl<S> HeaderMeteredStream<S> {
+    /// client wraps the transport of a connection we initiated, so we send the connection preface.
+    /// `counters` are the sent and received counters.
+    pub fn client(inner: S, counters: Option<(Counter, Counter)>) -> Self {
+        HeaderMeteredStream {
+            inner,
+            scanners: counters.map(|(sent, received)| {
+                (
+                    FrameScanner::new(0, received),
+                    FrameScanner::new(CLIENT_PREFACE_LEN, sent),
+                )
+            }),
+        }
+    }
+
+    /// server wraps the transport of a connection we accepted, so we receive the connection preface.
+    /// `counters` are the sent and received counters.
+    pub fn server(inner: S, counters: Option<(Counter, Counter)>) -> Self {
+        HeaderMeteredStream {
+            inner,
+            scanners: counters.map(|(sent, received)| {
+                (
+                    FrameScanner::new(CLIENT_PREFACE_LEN, received),
+                    FrameScanner::new(0, sent),
+                )
+            }),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for HeaderMeteredStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some((read, _)) = &mut this.scanners {
+            read.scan(&buf.filled()[before..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for HeaderMeteredStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if let Some((_, write)) = &mut this.scanners {
+            write.scan(&buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write_vectored(cx, bufs))?;
+        if let Some((_, write)) = &mut this.scanners {
+            let mut n = written;
+            for buf in bufs {
+                if n == 0 {
+                    break;
+                }
+                let len = n.min(buf.len());
+                write.scan(&buf[..len]);
+                n -= len;
+            }
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: u8, payload_len: usize) -> Vec<u8> {
+        let len = (payload_len as u32).to_be_bytes();
+        let mut f = vec![len[1], len[2], len[3], kind, 0, 0, 0, 0, 1];
+        f.resize(FRAME_HEADER_LEN + payload_len, 0xAB);
+        f
+    }
+
+    #[test]
+    fn scanner_counts_header_blocks() {
+        let counter = Counter::default();
+        let mut scanner = FrameScanner::new(CLIENT_PREFACE_LEN, counter.clone());
+        let mut wire = vec![b'P'; CLIENT_PREFACE_LEN];
+        wire.extend(frame(0x4, 18)); // SETTINGS
+        wire.extend(frame(FRAME_TYPE_HEADERS, 40));
+        wire.extend(frame(FRAME_TYPE_CONTINUATION, 7));
+        wire.extend(frame(0x0, 1000)); // DATA
+        wire.extend(frame(FRAME_TYPE_HEADERS, 3));
+
+        // Frames may be split anywhere, including inside a frame header.
+        for chunk in wire.chunks(5) {
+            scanner.scan(chunk);
+        }
+        assert_eq!(counter.get(), 50);
+    }
+
+    #[tokio::test]
+    async fn repeated_headers_are_compressed() {
+        let (sent, received) = (Counter::default(), Counter::default());
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let client_io =
+            HeaderMeteredStream::client(client_io, Some((sent.clone(), Counter::default())));
+        let server_io =
+            HeaderMeteredStream::server(server_io, Some((Counter::default(), received.clone())));
+        let (client, server) = tokio::join!(
+            h2::client::handshake(client_io),
+            h2::server::handshake(server_io)
+        );
+        let (mut sender, connection) = client.unwrap();
+        tokio::spawn(connection);
+        let mut server = server.unwrap();
+
+        let mut sizes = Vec::new();
+        for _ in 0..2 {
+            let req = http::Request::builder()
+                .uri("http://example.com/")
+                .header(
+                    "baggage",
+                    "k8s.namespace.name=default,k8s.cluster.name=cluster1",
+                )
+                .body(())
+                .unwrap();
+            let before = received.get();
+            sender = sender.ready().await.unwrap();
+            let _ = sender.send_request(req, true).unwrap();
+            server.accept().await.unwrap().unwrap();
+            sizes.push(received.get() - before);
+        }
+        assert_eq!(sent.get(), received.get());
+        assert!(
+            sizes[1] < sizes[0],
+            "repeated headers should be indexed: {sizes:?}"
+        );
+    }
+}
@@ -14,6 +14,8 @@
 
 use crate::config;
 use crate::drain::DrainWatcher;
+use crate::proxy::h2::header_bytes::HeaderMeteredStream;
+use crate::proxy::metrics::Metrics;
 use crate::proxy::Error;
 use bytes::Bytes;
 use futures_util::FutureExt;
@@ -48,11 +50,26 @@ impl H2Request {
         &self.request.headers
     }
 
+    /// The request's :protocol, for extended CONNECT requests
+    pub fn protocol(&self) -> Option<&str> {
+        self.request
+            .extensions
+            .get::<h2::ext::Protocol>()
+            .map(|p| p.as_str())
+    }
+
     pub fn send_error(mut self, resp: Response<()>) -> Result<(), Error> {
         let _ = self.send.send_response(resp, true)?;
         Ok(())
     }
 
+    /// send_error_with_body sends an error response with a body, and then ends the stream.
+    pub fn send_error_with_body(mut self, resp: Response<()>, body: Bytes) -> Result<(), Error> {
+        let mut send = self.send.send_response(resp, false)?;
+        send.send_data(body, true)?;
+        Ok(())
+    }
+
     pub async fn send_response(
         self,
         resp: Response<()>,
@@ -62,18 +79,45 @@ impl H2Request {
         let read = crate::proxy::h2::H2StreamReadHalf {
             recv_stream: recv,
             _dropped: None, // We do not need to track on the server
+            reset: None,
         };
         let write = crate::proxy::h2::H2StreamWriteHalf {
             send_stream: send,
             _dropped: None, // We do not need to track on the server
+            reset: None,
         };
         let h2 = crate::proxy::h2::H2Stream { read, write };
         Ok(h2)
     }
 }
 
+/// server_builder configures the HTTP/2 server side of HBONE connections.
+fn server_builder(cfg: &config::Config) -> h2::server::Builder {
+    let mut builder = h2::server::Builder::new();
+    builder
+        .header_table_size(cfg.hpack_table_size)
+        .initial_window_size(cfg.window_size)
+        .initial_connection_window_size(cfg.connection_window_size)
+        .max_frame_size(cfg.frame_size)
+        // Default is 16MB driven from Golang's defaults.
+        // Since we know we are going to receive a bounded set of headers, more is overkill.
+        // h2 discards headers past the limit while decoding them, then answers the request with a 431 and
+        // resets the stream, so oversized requests never reach us.
+        .max_header_list_size(cfg.hbone_max_header_size)
+        // 400kb, default from hyper
+        .max_send_buffer_size(1024 * 400)
+        // default from hyper
+        .max_concurrent_streams(200);
+    if cfg.enable_hbone_udp {
+        // Allow extended CONNECT, which is used to tunnel UDP.
+        builder.enable_connect_protocol();
+    }
+    builder
+}
+
 pub async fn serve_connection<F, Fut>(
     cfg: Arc<config::Config>,
+    metrics: Arc<Metrics>,
     s: tokio_rustls::server::TlsStream<TcpStream>,
     drain: DrainWatcher,
     mut force_shutdown: watch::Receiver<()>,
@@ -83,35 +127,39 @@ where
     F: Fn(H2Request) -> Fut,
     Fut: Future + Send + 'static,
 {
-    let mut builder = h2::server::Builder::new();
+    let builder = server_builder(&cfg);
     let mut conn = builder
-        .initial_window_size(cfg.window_size)
-        .initial_connection_window_size(cfg.connection_window_size)
-        .max_frame_size(cfg.frame_size)
-        // 64KB max; default is 16MB driven from Golang's defaults
-        // Since we know we are going to receive a bounded set of headers, more is overkill.
-        .max_header_list_size(65536)
-        // 400kb, default from hyper
-        .max_send_buffer_size(1024 * 400)
-        // default from hyper
-        .max_concurrent_streams(200)
-        .handshake(s)
+        .handshake(HeaderMeteredStream::server(
+            // Resumed clients may send their first requests as early data, ahead of the handshake.
+            crate::tls::session::WithEarlyData::new(s),
+            cfg.hbone_header_metrics.then(|| metrics.header_bytes()),
+        ))
         .await?;
 
     let ping_pong = conn
         .ping_pong()
         .expect("new connection should have ping_pong");
     // for ping to inform this fn to drop the connection
-    let (ping_drop_tx, mut ping_drop_rx) = oneshot::channel::<()>();
+    let (ping_drop_tx, mut ping_drop_rx) = oneshot::channel();
     // for this fn to inform ping to give up when it is already dropped
     let dropped = Arc::new(AtomicBool::new(false));
     tokio::task::spawn(crate::proxy::h2::do_ping_pong(
         ping_pong,
         ping_drop_tx,
         dropped.clone(),
+        crate::proxy::h2::PING_INTERVAL,
+        crate::proxy::h2::PING_TIMEOUT,
+        None,
     ));
 
     let handler = |req| handler(req).map(|_| ());
+    let max_lifetime = async {
+        match cfg.max_connection_lifetime {
+            Some(lifetime) => tokio::time::sleep(lifetime).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(max_lifetime);
     loop {
         let drain = drain.clone();
         tokio::select! {
@@ -143,6 +191,12 @@ where
                 conn.graceful_shutdown();
                 break;
             }
+            _ = &mut max_lifetime => {
+                // New streams are refused, while those in flight complete.
+                debug!("connection reached its maximum lifetime, draining");
+                conn.graceful_shutdown();
+                break;
+            }
         }
     }
     // Signal to the ping_pong it should also stop.
@@ -158,3 +212,56 @@ where
     drop(drain);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn oversized_headers_rejected() {
+        let mut cfg = crate::test_helpers::test_config();
+        cfg.hbone_max_header_size = 4 * 1024;
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let (accepted_tx, mut accepted_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut conn = server_builder(&cfg).handshake(server_io).await.unwrap();
+            while let Some(Ok((req, mut send))) = conn.accept().await {
+                let _ = send.send_response(Response::new(()), true);
+                accepted_tx.send(req.uri().to_string()).unwrap();
+            }
+        });
+        let (client, conn) = h2::client::handshake(client_io).await.unwrap();
+        tokio::spawn(conn);
+        let request = |authority: &str, baggage_len: usize| {
+            http::Request::builder()
+                .method(http::Method::CONNECT)
+                .uri(authority)
+                .header("baggage", "a".repeat(baggage_len))
+                .body(())
+                .unwrap()
+        };
+
+        // A request over the limit is refused before it is handed to us.
+        let mut client = client.ready().await.unwrap();
+        let rejected = match client.send_request(request("127.0.0.1:8080", 8 * 1024), true) {
+            Ok((response, _)) => response.await.map(|r| r.status()),
+            Err(e) => Err(e),
+        };
+        assert!(
+            matches!(
+                rejected,
+                Ok(http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE) | Err(_)
+            ),
+            "{rejected:?}"
+        );
+
+        // The connection keeps serving requests within the limit.
+        let mut client = client.ready().await.unwrap();
+        let (response, _) = client
+            .send_request(request("127.0.0.1:8081", 1024), true)
+            .unwrap();
+        assert_eq!(response.await.unwrap().status(), http::StatusCode::OK);
+        assert_eq!(accepted_rx.recv().await.unwrap(), "127.0.0.1:8081");
+        assert!(accepted_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Connecting to a hostname that resolves to several addresses, as in happy eyeballs (RFC 8305).
+
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use tracing::debug;
+
+use crate::config::IpFamilyPreference;
+use crate::proxy::Error;
+
+/// ATTEMPT_DELAY is how long an attempt may be in progress before the next one is started alongside it.
+/// This is the recommended default from RFC 8305.
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// order drops addresses of a family the preference excludes, and interleaves the rest starting with the
+/// preferred family, so a broken family does not hold up every attempt. Without a preference, the resolver's
+/// order is kept.
+pub fn order(ips: Vec<IpAddr>, preference: Option<IpFamilyPreference>) -> Vec<IpAddr> {
+    let Some(preference) = preference else {
+        return ips;
+    };
+    let (preferred, other): (Vec<_>, Vec<_>) = ips
+        .into_iter()
+        .filter(|ip| preference.accepts_ip(*ip))
+        .partition(|ip| ip.is_ipv6() == preference.prefers_ipv6());
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// connect races connection attempts to each of the addresses, in order. An attempt is started once the
+/// previous one fails, or after `delay` if it is still in progress; attempts already in progress are kept.
+/// The first attempt to succeed wins, and the others are dropped. If every attempt fails, the last error is
+/// returned.
+pub async fn connect<T, F, Fut>(
+    host: &str,
+    addrs: Vec<SocketAddr>,
+    delay: Duration,
+    mut dial: F,
+) -> Result<T, Error>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut start = |addr| {
+        let attempt = dial(addr);
+        async move { (addr, attempt.await) }
+    };
+    let mut pending = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(start(addr)),
+                None => {
+                    return Err(
+                        last_err.unwrap_or_else(|| Error::EmptyResolvedAddresses(host.to_string()))
+                    )
+                }
+            }
+        }
+        tokio::select! {
+            Some((addr, res)) = attempts.next() => match res {
+                Ok(conn) => {
+                    debug!(%host, %addr, "connected");
+                    return Ok(conn);
+                }
+                Err(err) => {
+                    debug!(%host, %addr, "connection attempt failed: {err}");
+                    last_err = Some(err);
+                    if let Some(addr) = pending.next() {
+                        attempts.push(start(addr));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(delay), if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(start(addr));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+    use std::io;
+
+    use tokio::time::Instant;
+
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    fn refused() -> Error {
+        Error::ConnectionFailed(io::Error::from(io::ErrorKind::ConnectionRefused))
+    }
+
+    #[test]
+    fn test_order() {
+        let ips: Vec<IpAddr> = ["10.0.0.1", "10.0.0.2", "fd00::1", "fd00::2", "10.0.0.3"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        let addrs = |pref| {
+            order(ips.clone(), pref)
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            addrs(None),
+            vec!["10.0.0.1", "10.0.0.2", "fd00::1", "fd00::2", "10.0.0.3"]
+        );
+        assert_eq!(
+            addrs(Some(IpFamilyPreference::DualPreferV6)),
+            vec!["fd00::1", "10.0.0.1", "fd00::2", "10.0.0.2", "10.0.0.3"]
+        );
+        assert_eq!(
+            addrs(Some(IpFamilyPreference::DualPreferV4)),
+            vec!["10.0.0.1", "fd00::1", "10.0.0.2", "fd00::2", "10.0.0.3"]
+        );
+        assert_eq!(
+            addrs(Some(IpFamilyPreference::V4)),
+            vec!["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn first_failure_starts_next_attempt() {
+        let start = Instant::now();
+        let res = connect(
+            "example.com",
+            addrs(&["10.0.0.1:80", "10.0.0.2:80"]),
+            ATTEMPT_DELAY,
+            |addr| async move {
+                match addr.ip().to_string().as_str() {
+                    "10.0.0.1" => Err(refused()),
+                    _ => Ok(addr),
+                }
+            },
+        )
+        .await;
+        assert_eq!(res.unwrap(), "10.0.0.2:80".parse().unwrap());
+        // The second attempt did not wait for the delay
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_attempt_is_raced() {
+        let start = Instant::now();
+        let res = connect(
+            "example.com",
+            addrs(&["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]),
+            ATTEMPT_DELAY,
+            |addr| async move {
+                match addr.ip().to_string().as_str() {
+                    // Never connects, such as a blackholed address
+                    "10.0.0.1" => pending().await,
+                    _ => Ok(addr),
+                }
+            },
+        )
+        .await;
+        assert_eq!(res.unwrap(), "10.0.0.2:80".parse().unwrap());
+        assert_eq!(start.elapsed(), ATTEMPT_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_attempt_can_still_win() {
+        let res = connect(
+            "example.com",
+            addrs(&["10.0.0.1:80", "10.0.0.2:80"]),
+            ATTEMPT_DELAY,
+            |addr| async move {
+                match addr.ip().to_string().as_str() {
+                    "10.0.0.1" => {
+                        tokio::time::sleep(ATTEMPT_DELAY + Duration::from_millis(10)).await;
+                        Ok(addr)
+                    }
+                    _ => pending().await,
+                }
+            },
+        )
+        .await;
+        // The first attempt is kept running after the second starts
+        assert_eq!(res.unwrap(), "10.0.0.1:80".parse().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn all_attempts_fail() {
+        let res: Result<(), _> = connect(
+            "example.com",
+            addrs(&["10.0.0.1:80", "10.0.0.2:80"]),
+            ATTEMPT_DELAY,
+            |_| async { Err(refused()) },
+        )
+        .await;
+        assert!(matches!(res, Err(Error::ConnectionFailed(_))));
+
+        let res: Result<(), _> =
+            connect("example.com", vec![], ATTEMPT_DELAY, |_| async { Ok(()) }).await;
+        assert!(matches!(res, Err(Error::EmptyResolvedAddresses(_))));
+    }
+}
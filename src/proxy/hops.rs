@@ -0,0 +1,59 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+/// HopTracker remembers the local addresses of connections inbound has made to local destinations, and how
+/// many ztunnels they have traversed. If such a connection is redirected back to outbound, we know it is
+/// looping. Addresses are only meaningful within one network namespace, so each proxy has its own.
+#[derive(Default)]
+pub struct HopTracker {
+    hops: RwLock<HashMap<SocketAddr, u8>>,
+}
+
+impl HopTracker {
+    /// track records that the connection we made from `addr` has traversed `hops` ztunnels.
+    /// The record is kept until the returned guard is dropped.
+    pub fn track(self: &Arc<Self>, addr: SocketAddr, hops: u8) -> HopsGuard {
+        self.hops.write().expect("mutex").insert(addr, hops);
+        HopsGuard {
+            tracker: self.clone(),
+            addr,
+        }
+    }
+
+    /// hops returns how many ztunnels a connection from `addr` has already traversed. For connections
+    /// we did not make ourselves, this is 0.
+    pub fn hops(&self, addr: SocketAddr) -> u8 {
+        self.hops
+            .read()
+            .expect("mutex")
+            .get(&addr)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+pub struct HopsGuard {
+    tracker: Arc<HopTracker>,
+    addr: SocketAddr,
+}
+
+impl Drop for HopsGuard {
+    fn drop(&mut self) {
+        self.tracker.hops.write().expect("mutex").remove(&self.addr);
+    }
+}
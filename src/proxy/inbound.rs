@@ -12,28 +12,35 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::future::Future;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use bytes::Bytes;
 use futures::stream::StreamExt;
 
 use http::{Method, Response, StatusCode};
 
 use tokio::net::TcpStream;
 use tokio::sync::watch;
+use tokio::time;
 
-use tracing::{debug, info, instrument, trace_span, Instrument};
+use tracing::{debug, info, info_span, instrument, trace_span, Instrument};
 
 use super::{Error, ScopedSecretManager};
 use crate::baggage::parse_baggage_header;
 use crate::identity::Identity;
 
 use crate::drain::DrainWatcher;
+use crate::proxy::connection_manager::{ConnectionGuard, TunnelPermit};
 use crate::proxy::h2::server::H2Request;
-use crate::proxy::metrics::{ConnectionOpen, Reporter};
-use crate::proxy::{metrics, ProxyInputs, TraceParent, BAGGAGE_HEADER, TRACEPARENT_HEADER};
-use crate::rbac::Connection;
+use crate::proxy::metrics::{AcceptListener, ConnectionOpen, Reporter};
+use crate::proxy::{
+    connect_udp, connection_metadata, metrics, ConnectionId, ProxyInputs, TraceParent,
+    BAGGAGE_HEADER, HOPS_HEADER, TRACEPARENT_HEADER,
+};
+use crate::rbac::{Connection, RbacDenial};
 use crate::socket::to_canonical;
 use crate::state::service::Service;
 use crate::state::workload::address::Address;
@@ -43,101 +50,173 @@ use crate::{assertions, copy, proxy, socket, strng, tls};
 use crate::config::ProxyMode;
 use crate::drain::run_with_drain;
 use crate::proxy::h2;
-use crate::state::workload::{self, NetworkAddress, Workload};
+use crate::state::workload::{self, NetworkAddress, TrafficClass, Workload};
 use crate::state::DemandProxyState;
 use crate::strng::Strng;
 use crate::tls::TlsError;
 
+// The deadline of a gRPC request, which we may enforce on the connection to the application.
+const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
 pub(super) struct Inbound {
-    listener: socket::Listener,
+    listeners: Vec<InboundListener>,
     drain: DrainWatcher,
     pi: Arc<ProxyInputs>,
+}
+
+struct InboundListener {
+    listener: socket::Listener,
     enable_orig_src: bool,
 }
 
 impl Inbound {
-    pub(super) async fn new(pi: Arc<ProxyInputs>, drain: DrainWatcher) -> Result<Inbound, Error> {
-        let listener = pi
-            .socket_factory
-            .tcp_bind(pi.cfg.inbound_addr)
-            .map_err(|e| Error::Bind(pi.cfg.inbound_addr, e))?;
-        let enable_orig_src = super::maybe_set_transparent(&pi, &listener)?;
-
-        info!(
-            address=%listener.local_addr(),
-            component="inbound",
-            transparent=enable_orig_src,
-            "listener established",
-        );
+    /// new binds a listener on each of `addrs`. All listeners serve the same proxy; the first is the primary
+    /// address reported by `address`.
+    pub(super) async fn new(
+        pi: Arc<ProxyInputs>,
+        addrs: &[SocketAddr],
+        drain: DrainWatcher,
+    ) -> Result<Inbound, Error> {
+        let mut listeners = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let listener = pi
+                .socket_factory
+                .tcp_bind(*addr)
+                .map_err(|e| super::bind_error(pi.socket_factory.as_ref(), *addr, e))?;
+            let enable_orig_src = super::maybe_set_transparent(&pi, &listener)?;
+
+            info!(
+                address=%listener.local_addr(),
+                component="inbound",
+                transparent=enable_orig_src,
+                "listener established",
+            );
+            listeners.push(InboundListener {
+                listener,
+                enable_orig_src,
+            });
+        }
         Ok(Inbound {
-            listener,
+            listeners,
             drain,
             pi,
-            enable_orig_src,
         })
     }
 
     pub(super) fn address(&self) -> SocketAddr {
-        self.listener.local_addr()
+        self.listeners[0].listener.local_addr()
+    }
+
+    /// extra_addresses returns the addresses of all listeners but the primary.
+    pub(super) fn extra_addresses(&self) -> Vec<SocketAddr> {
+        self.listeners[1..]
+            .iter()
+            .map(|l| l.listener.local_addr())
+            .collect()
     }
 
     pub(super) async fn run(self) {
-        let pi = self.pi.clone();
         let acceptor = InboundCertProvider {
             state: self.pi.state.clone(),
             cert_manager: self.pi.cert_manager.clone(),
             network: strng::new(&self.pi.cfg.network),
         };
+        let deadline = self.pi.cfg.self_termination_deadline;
+        let pi = self.pi;
+        let listeners = self.listeners;
+
+        let accept = |drain: DrainWatcher, force_shutdown: watch::Receiver<()>| async move {
+            let loops = listeners.into_iter().map(|l| {
+                Self::accept_loop(
+                    pi.clone(),
+                    acceptor.clone(),
+                    l,
+                    drain.clone(),
+                    force_shutdown.clone(),
+                )
+            });
+            futures::future::join_all(loops).await;
+        };
 
+        run_with_drain("inbound".to_string(), self.drain, deadline, accept).await
+    }
+
+    async fn accept_loop(
+        pi: Arc<ProxyInputs>,
+        acceptor: InboundCertProvider,
+        listener: InboundListener,
+        drain: DrainWatcher,
+        force_shutdown: watch::Receiver<()>,
+    ) {
+        let enable_orig_src = listener.enable_orig_src;
         // Safety: we set nodelay directly in tls_server, so it is safe to convert to a normal listener.
         // Although, that is *after* the TLS handshake; in theory we may get some benefits to setting it earlier.
-        let mut stream = crate::hyper_util::tls_server(acceptor, self.listener.inner());
-
-        let accept = |drain: DrainWatcher, force_shutdown: watch::Receiver<()>| {
-            async move {
-                while let Some(tls) = stream.next().await {
-                    let pi = self.pi.clone();
-                    let (raw_socket, ssl) = tls.get_ref();
-                    let src_identity: Option<Identity> = tls::identity_from_connection(ssl);
-                    let dst = crate::socket::orig_dst_addr_or_default(raw_socket);
-                    let src = to_canonical(raw_socket.peer_addr().expect("peer_addr available"));
-                    let drain = drain.clone();
-                    let force_shutdown = force_shutdown.clone();
-                    let network = pi.cfg.network.clone();
-                    let serve_client = async move {
-                        let conn = Connection {
-                            src_identity,
-                            src,
-                            dst_network: strng::new(&network), // inbound request must be on our network
-                            dst,
-                        };
-                        debug!(%conn, "accepted connection");
-                        let cfg = pi.cfg.clone();
-                        let request_handler = move |req| {
-                            Self::serve_connect(pi.clone(), conn.clone(), self.enable_orig_src, req)
-                        };
-                        let serve = Box::pin(h2::server::serve_connection(
-                            cfg,
-                            tls,
-                            drain,
-                            force_shutdown,
-                            request_handler,
-                        ));
-                        serve.await
-                    };
-                    assertions::size_between_ref(1000, 1500, &serve_client);
-                    tokio::task::spawn(serve_client.in_current_span());
-                }
-            }
-        };
+        let mut stream = crate::hyper_util::tls_server_with_metrics(
+            acceptor,
+            listener.listener.inner(),
+            Some(pi.metrics.clone()),
+        );
 
-        run_with_drain(
-            "inbound".to_string(),
-            self.drain,
-            pi.cfg.self_termination_deadline,
-            accept,
-        )
-        .await
+        let accept_metrics = pi.metrics.accept_metrics(AcceptListener::inbound);
+        let mut current = pi;
+        loop {
+            let Some(tls) = stream.next().await else {
+                break;
+            };
+            let accepted = accept_metrics.accepted();
+            ProxyInputs::refresh(&mut current);
+            let pi = current.clone();
+            let (raw_socket, ssl) = tls.get_ref();
+            let src_identity: Option<Identity> = tls::identity_from_connection(ssl);
+            let handshake = tls::HandshakeSummary::from_connection(ssl);
+            let dst = crate::socket::orig_dst_addr_or_default(raw_socket);
+            let src = to_canonical(raw_socket.peer_addr().expect("peer_addr available"));
+            let Ok(budget) = pi.connection_manager.acquire_tunnel_budget(src) else {
+                continue;
+            };
+            let budget = Arc::new(budget);
+            let drain = drain.clone();
+            let force_shutdown = force_shutdown.clone();
+            let network = pi.cfg.network.clone();
+            // All HBONE streams on the TLS connection share its ID.
+            let conn_id = ConnectionId::next();
+            let serve_client = async move {
+                accepted.started();
+                let conn = Connection {
+                    src_identity,
+                    src,
+                    dst_network: strng::new(&network), // inbound request must be on our network
+                    dst,
+                };
+                debug!(%conn, "accepted connection");
+                let cfg = pi.cfg.clone();
+                let metrics = pi.metrics.clone();
+                let request_handler = move |req| {
+                    Self::serve_connect(
+                        pi.clone(),
+                        conn.clone(),
+                        conn_id,
+                        handshake,
+                        budget.clone(),
+                        enable_orig_src,
+                        req,
+                    )
+                };
+                let serve = Box::pin(h2::server::serve_connection(
+                    cfg,
+                    metrics,
+                    tls,
+                    drain,
+                    force_shutdown,
+                    request_handler,
+                ));
+                serve.await
+            };
+            assertions::size_between_ref(1000, 1500, &serve_client);
+            tokio::task::spawn(
+                serve_client.instrument(info_span!("inbound connection", conn_id=%conn_id)),
+            );
+        }
     }
 
     fn extract_traceparent(req: &H2Request) -> TraceParent {
@@ -156,6 +235,9 @@ impl Inbound {
     async fn serve_connect(
         pi: Arc<ProxyInputs>,
         conn: Connection,
+        conn_id: ConnectionId,
+        handshake: Option<tls::HandshakeSummary>,
+        budget: Arc<TunnelPermit>,
         enable_original_source: bool,
         req: H2Request,
     ) -> Result<(), Error> {
@@ -168,8 +250,33 @@ impl Inbound {
             );
             return req.send_error(build_response(StatusCode::NOT_FOUND));
         }
+        if let Err(e) = pi.wait_for_state().await {
+            metrics::log_early_deny(conn.src, conn.dst, Reporter::destination, e);
+            return req.send_error(build_not_ready_response());
+        }
         let start = Instant::now();
-        let Ok(hbone_addr) = req.uri().to_string().as_str().parse::<SocketAddr>() else {
+        let deadline = request_deadline(pi.cfg.enforce_grpc_timeout, req.headers());
+        // Plain CONNECT tunnels TCP; extended CONNECT with the connect-udp protocol tunnels UDP.
+        let (hbone_addr, udp) = match req.protocol() {
+            None => (
+                req.uri().to_string().as_str().parse::<SocketAddr>().ok(),
+                false,
+            ),
+            Some(connect_udp::CONNECT_UDP_PROTOCOL) => {
+                (connect_udp::parse_target_path(req.uri().path()), true)
+            }
+            Some(protocol) => {
+                metrics::log_early_deny(
+                    conn.src,
+                    conn.dst,
+                    Reporter::destination,
+                    Error::UnsupportedFeature(format!("CONNECT protocol {protocol}")),
+                );
+                return req.send_error(build_response(StatusCode::BAD_REQUEST));
+            }
+        };
+        // Clients may use the IPv4-mapped form of an address, but workloads are known by the IPv4 one.
+        let Some(hbone_addr) = hbone_addr.map(to_canonical) else {
             metrics::log_early_deny(
                 conn.src,
                 conn.dst,
@@ -178,6 +285,15 @@ impl Inbound {
             );
             return req.send_error(build_response(StatusCode::BAD_REQUEST));
         };
+        if !budget.allows(hbone_addr) {
+            metrics::log_early_deny(
+                conn.src,
+                hbone_addr,
+                Reporter::destination,
+                Error::ConnectionBudgetExhausted,
+            );
+            return req.send_error(build_response(StatusCode::SERVICE_UNAVAILABLE));
+        }
 
         // Determine the next hop.
         let (upstream_addr, inbound_protocol, upstream, upstream_service) =
@@ -205,6 +321,18 @@ impl Inbound {
             );
             return req.send_error(build_response(StatusCode::BAD_REQUEST));
         }
+        // This request arrived over authenticated HBONE, so we can trust the hop count the peer reports.
+        let hops = parse_hops(&req);
+        if hops > pi.cfg.max_proxy_hops {
+            pi.metrics.proxy_loops_detected.inc();
+            metrics::log_early_deny(
+                conn.src,
+                upstream_addr,
+                Reporter::destination,
+                Error::ProxyLoopDetected(hops, pi.cfg.max_proxy_hops),
+            );
+            return req.send_error(build_response(StatusCode::LOOP_DETECTED));
+        }
         // Connection has 15008, swap with the real port
         let conn = Connection {
             dst: upstream_addr,
@@ -241,6 +369,9 @@ impl Inbound {
                 pi.state.fetch_workload(&src_network_addr).await
             }
         };
+        let orig_src = enable_original_source.then(|| {
+            proxy::original_source_ip(&pi.cfg, source.as_deref(), source_ip, upstream_addr.ip())
+        });
 
         let derived_source = metrics::DerivedWorkload {
             identity: rbac_ctx.conn.src_identity.clone(),
@@ -250,64 +381,180 @@ impl Inbound {
             revision: baggage.revision,
             ..Default::default()
         };
-        let ds =
-            proxy::guess_inbound_service(&rbac_ctx.conn, &for_host, upstream_service, &upstream);
-        let result_tracker = Box::new(metrics::ConnectionResult::new(
-            rbac_ctx.conn.src,
-            rbac_ctx.conn.dst,
-            Some(hbone_addr),
-            start,
-            ConnectionOpen {
-                reporter: Reporter::destination,
-                source,
-                derived_source: Some(derived_source),
-                destination: Some(upstream),
-                connection_security_policy: metrics::SecurityPolicy::mutual_tls,
-                destination_service: ds,
-            },
-            pi.metrics.clone(),
-        ));
+        let (ds, port_name) = match proxy::guess_inbound_service(
+            &rbac_ctx.conn,
+            &for_host,
+            upstream_service,
+            &upstream,
+        ) {
+            Some(s) => (Some(s.service), s.port_name),
+            None => (None, None),
+        };
+        // Only pass the service on to the upstream if the client actually targeted it, rather than it being guessed.
+        let proxy_service = ds
+            .as_ref()
+            .filter(|s| for_host.as_deref() == Some(s.hostname.as_str()))
+            .map(|s| s.hostname.clone());
+        let traffic_class =
+            TrafficClass::for_connection(source.as_deref(), Some(upstream.as_ref()));
+        let metadata =
+            connection_metadata::capture(req.headers(), &pi.cfg.connection_metadata_headers);
+        let result_tracker = Box::new(
+            metrics::ConnectionResult::new(
+                rbac_ctx.conn.src,
+                rbac_ctx.conn.dst,
+                Some(hbone_addr),
+                start,
+                ConnectionOpen {
+                    reporter: Reporter::destination,
+                    source,
+                    derived_source: Some(derived_source),
+                    destination: Some(upstream),
+                    connection_security_policy: metrics::SecurityPolicy::mutual_tls,
+                    destination_service: ds,
+                    destination_service_port_name: port_name,
+                    connection_id: conn_id,
+                },
+                pi.metrics.clone(),
+            )
+            .with_metadata(metadata.clone()),
+        );
 
         let conn_guard = match pi
             .connection_manager
-            .assert_rbac(&pi.state, &rbac_ctx, for_host)
+            .assert_rbac(&pi.state, &rbac_ctx, conn_id, for_host, metadata, handshake)
             .await
         {
             Ok(cg) => cg,
             Err(e) => {
+                let (resp, body) = build_denial_response(&e, pi.cfg.hbone_denial_reason);
                 result_tracker
                     .record_with_flag(Err(e), metrics::ResponseFlags::AuthorizationPolicyDenied);
-                return req.send_error(build_response(StatusCode::UNAUTHORIZED));
+                return req.send_error_with_body(resp, body);
             }
         };
+        if pi.cfg.access_log_rbac_decision {
+            result_tracker.record_rbac(conn_guard.decision().clone());
+        }
+
+        if udp {
+            return Self::serve_connect_udp(
+                pi,
+                req,
+                upstream_addr,
+                inbound_protocol,
+                conn_guard,
+                result_tracker,
+            )
+            .await;
+        }
 
-        let orig_src = enable_original_source.then_some(source_ip);
-        let stream =
-            super::freebind_connect(orig_src, upstream_addr, pi.socket_factory.as_ref()).await;
+        let stream = super::freebind_connect(
+            orig_src,
+            upstream_addr,
+            connect_timeout(pi.cfg.connection_timeout, deadline),
+            &super::for_connection(&pi, conn_id),
+            super::ConnectOptions::from_config(&pi.cfg),
+        )
+        .await;
         let mut stream = match stream {
+            Err(_) if deadline.is_some_and(|d| time::Instant::now() >= d) => {
+                result_tracker.record(Err(Error::DeadlineExceeded));
+                return req.send_error(build_response(StatusCode::GATEWAY_TIMEOUT));
+            }
             Err(err) => {
                 result_tracker.record(Err(err));
                 return req.send_error(build_response(StatusCode::SERVICE_UNAVAILABLE));
             }
-            Ok(stream) => stream,
+            Ok((stream, binding)) => {
+                result_tracker.record_source_binding(binding);
+                // The downstream is an HBONE stream, so only the connection to the application is tuned.
+                super::set_traffic_class(pi.socket_factory.as_ref(), traffic_class, &[&stream]);
+                stream
+            }
         };
+        // If this connection is redirected back to our outbound, it can continue counting hops from here.
+        let _hops_guard = stream
+            .local_addr()
+            .ok()
+            .map(|addr| pi.hops.track(to_canonical(addr), hops));
 
         debug!("connected to: {upstream_addr}");
 
-        let h2_stream = req.send_response(build_response(StatusCode::OK)).await?;
+        let h2_stream = req
+            .send_response(build_response(StatusCode::OK))
+            .await?
+            .track_resets(result_tracker.h2_reset());
 
+        let closing = conn_guard.closing();
         let send = async {
             if inbound_protocol == AppProtocol::PROXY {
                 let Connection {
                     src, src_identity, ..
                 } = rbac_ctx.conn;
-                super::write_proxy_protocol(&mut stream, (src, hbone_addr), src_identity)
-                    .instrument(trace_span!("proxy protocol"))
-                    .await?;
+                super::write_proxy_protocol(
+                    &mut stream,
+                    (src, hbone_addr),
+                    src_identity,
+                    proxy_service.as_deref(),
+                )
+                .instrument(trace_span!("proxy protocol"))
+                .await?;
+            }
+            copy::copy_bidirectional_until(
+                h2_stream,
+                copy::TcpStreamSplitter(stream),
+                &result_tracker,
+                pi.cfg.force_full_close,
+                closing,
+            )
+            .instrument(trace_span!("hbone server"))
+            .await
+        };
+        // The response has already been sent, so when the deadline passes, the stream is reset.
+        let res = conn_guard
+            .handle_connection(until_deadline(deadline, send))
+            .await;
+        result_tracker.record(res);
+        Ok(())
+    }
+
+    async fn serve_connect_udp(
+        pi: Arc<ProxyInputs>,
+        req: H2Request,
+        upstream_addr: SocketAddr,
+        inbound_protocol: AppProtocol,
+        conn_guard: ConnectionGuard,
+        result_tracker: Box<metrics::ConnectionResult>,
+    ) -> Result<(), Error> {
+        if inbound_protocol == AppProtocol::PROXY {
+            // There is no way to convey the PROXY protocol header for datagrams.
+            result_tracker.record(Err(Error::UnsupportedFeature(
+                "UDP with PROXY protocol".to_string(),
+            )));
+            return req.send_error(build_response(StatusCode::BAD_REQUEST));
+        }
+        let socket =
+            match connect_udp::connect_upstream(pi.socket_factory.as_ref(), upstream_addr).await {
+                Ok(socket) => socket,
+                Err(err) => {
+                    result_tracker.record(Err(err));
+                    return req.send_error(build_response(StatusCode::SERVICE_UNAVAILABLE));
+                }
+            };
+
+        debug!("connected to: udp://{upstream_addr}");
+
+        let h2_stream = req.send_response(connect_udp::response()).await?;
+        let closing = conn_guard.closing();
+        let relay = connect_udp::relay_socket(h2_stream, socket, &result_tracker)
+            .instrument(trace_span!("hbone udp server"));
+        // Datagrams have no FIN to send, so the stream is simply reset.
+        let send = async {
+            tokio::select! {
+                res = relay => res,
+                reason = closing => Err(reason),
             }
-            copy::copy_bidirectional(h2_stream, copy::TcpStreamSplitter(stream), &result_tracker)
-                .instrument(trace_span!("hbone server"))
-                .await
         };
         let res = conn_guard.handle_connection(send).await;
         result_tracker.record(res);
@@ -480,6 +727,82 @@ pub fn parse_forwarded_host(req: &H2Request) -> Option<String> {
         .and_then(|ph| ph.host().map(|s| s.to_string()))
 }
 
+// parse_hops reads the number of ztunnels the request has traversed. Peers that do not report this
+// are counted as a single hop.
+fn parse_hops(req: &H2Request) -> u8 {
+    req.headers()
+        .get(HOPS_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse().ok())
+        .unwrap_or(1)
+}
+
+// grpc_deadline returns the deadline set by the grpc-timeout header, measured from when the request arrived.
+// A malformed timeout is ignored, as if the header was not set. The deadline is a tokio Instant, so it follows
+// paused time in tests.
+fn grpc_deadline(headers: &http::HeaderMap, start: time::Instant) -> Option<time::Instant> {
+    let value = headers.get(GRPC_TIMEOUT_HEADER)?;
+    match value.to_str().ok().and_then(parse_grpc_timeout) {
+        Some(timeout) => Some(start + timeout),
+        None => {
+            debug!(?value, "ignoring malformed grpc-timeout");
+            None
+        }
+    }
+}
+
+// request_deadline returns the deadline of a request arriving now, if grpc-timeout is enforced. It is measured
+// from tokio's clock rather than the real one, so it stays consistent with the timers that enforce it.
+fn request_deadline(enforce: bool, headers: &http::HeaderMap) -> Option<time::Instant> {
+    if !enforce {
+        return None;
+    }
+    grpc_deadline(headers, time::Instant::now())
+}
+
+// connect_timeout bounds the time to connect to the application by the request deadline, if there is one.
+fn connect_timeout(timeout: Duration, deadline: Option<time::Instant>) -> Duration {
+    match deadline {
+        Some(deadline) => timeout.min(deadline.saturating_duration_since(time::Instant::now())),
+        None => timeout,
+    }
+}
+
+// until_deadline runs the stream until it completes, or fails it with DeadlineExceeded once the deadline passes.
+async fn until_deadline(
+    deadline: Option<time::Instant>,
+    stream: impl Future<Output = Result<(), Error>>,
+) -> Result<(), Error> {
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, stream)
+            .await
+            .unwrap_or(Err(Error::DeadlineExceeded)),
+        None => stream.await,
+    }
+}
+
+// parse_grpc_timeout parses a gRPC TimeoutValue and TimeoutUnit: up to 8 digits, followed by one of H (hours),
+// M (minutes), S (seconds), m (milliseconds), u (microseconds) or n (nanoseconds).
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    if !amount.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 60 * 60)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
 fn build_response(status: StatusCode) -> Response<()> {
     Response::builder()
         .status(status)
@@ -487,14 +810,46 @@ fn build_response(status: StatusCode) -> Response<()> {
         .expect("builder with known status code should not fail")
 }
 
+// build_not_ready_response tells the client to retry a request that arrived before we were ready to serve it.
+fn build_not_ready_response() -> Response<()> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(http::header::RETRY_AFTER, "1")
+        .body(())
+        .expect("builder with known status code should not fail")
+}
+
+// build_denial_response builds the response to a request denied by authorization policy, with a JSON body for
+// HTTP aware clients. Unless include_reason is set, the body does not say why, so policy names are not revealed.
+fn build_denial_response(err: &Error, include_reason: bool) -> (Response<()>, Bytes) {
+    let mut body = serde_json::json!({"error": "denied by authorization policy"});
+    if let (true, Error::AuthorizationPolicyRejection(denial)) = (include_reason, err) {
+        body["reason"] = denial.to_string().into();
+        if let RbacDenial::DenyPolicy { policy, rule } = denial {
+            body["policy"] = policy.as_str().into();
+            body["rule"] = (*rule).into();
+        }
+    }
+    let resp = Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(())
+        .expect("builder with known status code should not fail");
+    (resp, Bytes::from(body.to_string()))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Inbound;
+    use super::{
+        build_denial_response, connect_timeout, grpc_deadline, parse_grpc_timeout,
+        request_deadline, until_deadline, Error, Inbound, RbacDenial, StatusCode,
+    };
     use crate::strng;
 
     use std::{
         net::SocketAddr,
         sync::{Arc, RwLock},
+        time::Duration,
     };
 
     use crate::{
@@ -580,6 +935,133 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_grpc_timeout() {
+        let cases = [
+            ("1H", Some(Duration::from_secs(3600))),
+            ("2M", Some(Duration::from_secs(120))),
+            ("30S", Some(Duration::from_secs(30))),
+            ("250m", Some(Duration::from_millis(250))),
+            ("100u", Some(Duration::from_micros(100))),
+            ("99999999n", Some(Duration::from_nanos(99_999_999))),
+            ("0S", Some(Duration::ZERO)),
+            // At most 8 digits are allowed.
+            ("123456789S", None),
+            ("S", None),
+            ("10", None),
+            ("10s", None),
+            ("-1S", None),
+            ("1.5S", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse_grpc_timeout(value), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn test_grpc_deadline() {
+        let start = tokio::time::Instant::now();
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(grpc_deadline(&headers, start), None);
+
+        headers.insert("grpc-timeout", http::HeaderValue::from_static("1500m"));
+        assert_eq!(
+            grpc_deadline(&headers, start),
+            Some(start + Duration::from_millis(1500))
+        );
+
+        headers.insert("grpc-timeout", http::HeaderValue::from_static("soon"));
+        assert_eq!(grpc_deadline(&headers, start), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_grpc_deadline_enforced() {
+        let start = tokio::time::Instant::now();
+        let mut headers = http::HeaderMap::new();
+        headers.insert("grpc-timeout", http::HeaderValue::from_static("250m"));
+        let deadline = grpc_deadline(&headers, start);
+
+        // Connecting may only take as long as is left until the deadline.
+        let configured = Duration::from_secs(10);
+        assert_eq!(
+            connect_timeout(configured, deadline),
+            Duration::from_millis(250)
+        );
+        assert_eq!(connect_timeout(configured, None), configured);
+
+        // A stream that outlives the deadline is cut off exactly when it passes.
+        let res = until_deadline(deadline, std::future::pending()).await;
+        assert!(matches!(res, Err(Error::DeadlineExceeded)), "{res:?}");
+        assert_eq!(start.elapsed(), Duration::from_millis(250));
+        // Once the deadline has passed, there is no time left to connect.
+        assert_eq!(connect_timeout(configured, deadline), Duration::ZERO);
+
+        // A stream that completes in time is unaffected.
+        let deadline = grpc_deadline(&headers, tokio::time::Instant::now());
+        let res = until_deadline(deadline, async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(())
+        })
+        .await;
+        assert!(res.is_ok(), "{res:?}");
+
+        // Without a deadline, the stream is never cut off.
+        let res = until_deadline(None, async {
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            Ok(())
+        })
+        .await;
+        assert!(res.is_ok(), "{res:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_request_deadline() {
+        // Move tokio's clock away from the real one, so a deadline measured from the wrong clock shows up.
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        let mut headers = http::HeaderMap::new();
+        headers.insert("grpc-timeout", http::HeaderValue::from_static("250m"));
+        assert_eq!(request_deadline(false, &headers), None);
+
+        let start = tokio::time::Instant::now();
+        let deadline = request_deadline(true, &headers);
+        assert_eq!(deadline, Some(start + Duration::from_millis(250)));
+        let res = until_deadline(deadline, std::future::pending()).await;
+        assert!(matches!(res, Err(Error::DeadlineExceeded)), "{res:?}");
+        assert_eq!(start.elapsed(), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_build_denial_response() {
+        let denied = Error::AuthorizationPolicyRejection(RbacDenial::DenyPolicy {
+            policy: strng::new("ns/deny"),
+            rule: 1,
+        });
+        let body = |include_reason| {
+            let (resp, body) = build_denial_response(&denied, include_reason);
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            assert_eq!(
+                resp.headers().get(http::header::CONTENT_TYPE).unwrap(),
+                "application/json"
+            );
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        // The reason is only shared when configured, as it reveals policy names to the client.
+        assert_eq!(
+            body(false),
+            serde_json::json!({"error": "denied by authorization policy"})
+        );
+        assert_eq!(
+            body(true),
+            serde_json::json!({
+                "error": "denied by authorization policy",
+                "reason": "denied by policy ns/deny rule 1",
+                "policy": "ns/deny",
+                "rule": 1,
+            })
+        );
+    }
+
     fn test_state(server_waypoint: Waypoint) -> anyhow::Result<state::DemandProxyState> {
         let mut state = state::ProxyState::default();
 
@@ -626,6 +1108,9 @@ mod tests {
                 waypoint: waypoint.service_attached(),
                 load_balancer: None,
                 ip_families: None,
+                subset_weights: None,
+                mirror: None,
+                port_names: Default::default(),
             }
         });
 
@@ -17,18 +17,18 @@ use std::sync::Arc;
 use std::time::Instant;
 
 use tokio::net::TcpStream;
-use tokio::sync::watch;
+use tokio::sync::{mpsc, watch};
 
-use tracing::{debug, error, info, trace, Instrument};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
-use crate::config::ProxyMode;
+use crate::config::{Config, ProxyMode};
 
 use crate::drain::run_with_drain;
 use crate::drain::DrainWatcher;
-use crate::proxy::metrics::Reporter;
+use crate::proxy::metrics::{AcceptListener, Reporter};
 use crate::proxy::Error;
-use crate::proxy::{metrics, util, ProxyInputs};
-use crate::state::workload::NetworkAddress;
+use crate::proxy::{metrics, sniff, util, ConnectionId, ProxyInputs};
+use crate::state::workload::{NetworkAddress, TrafficClass};
 use crate::{assertions, copy, rbac, strng};
 use crate::{proxy, socket};
 
@@ -47,7 +47,9 @@ impl InboundPassthrough {
         let listener = pi
             .socket_factory
             .tcp_bind(pi.cfg.inbound_plaintext_addr)
-            .map_err(|e| Error::Bind(pi.cfg.inbound_plaintext_addr, e))?;
+            .map_err(|e| {
+                super::bind_error(pi.socket_factory.as_ref(), pi.cfg.inbound_plaintext_addr, e)
+            })?;
 
         let enable_orig_src = super::maybe_set_transparent(&pi, &listener)?;
 
@@ -69,30 +71,48 @@ impl InboundPassthrough {
         let pi = self.pi.clone();
         let accept = |drain: DrainWatcher, force_shutdown: watch::Receiver<()>| {
             async move {
+                let mut current = self.pi.clone();
+                let accept_metrics = current
+                    .metrics
+                    .accept_metrics(AcceptListener::inbound_passthrough);
                 loop {
                     // Asynchronously wait for an inbound socket.
                     let socket = self.listener.accept().await;
                     let start = Instant::now();
                     let mut force_shutdown = force_shutdown.clone();
                     let drain = drain.clone();
-                    let pi = self.pi.clone();
+                    ProxyInputs::refresh(&mut current);
+                    let pi = current.clone();
                     match socket {
                         Ok((stream, remote)) => {
+                            let dst = socket::orig_dst_addr_or_default(&stream);
+                            let src = socket::to_canonical(remote);
+                            let budget = match pi.connection_manager.acquire_inbound_budget(src, dst) {
+                                Ok(budget) => budget,
+                                Err(e) => {
+                                    super::set_rejection_close(&pi.cfg, &stream, &e);
+                                    continue;
+                                }
+                            };
+                            let conn_id = ConnectionId::next();
+                            let accepted = accept_metrics.accepted();
                             let serve_client = async move {
+                                accepted.started();
+                                let _budget = budget;
                                 debug!(component="inbound passthrough", "connection started");
                                 // Since this task is spawned, make sure we are guaranteed to terminate
                                 tokio::select! {
                                     _ = force_shutdown.changed() => {
                                         debug!(component="inbound passthrough", "connection forcefully terminated");
                                     }
-                                    _ = Self::proxy_inbound_plaintext(pi, socket::to_canonical(remote), stream, self.enable_orig_src) => {
+                                    _ = Self::proxy_inbound_plaintext(pi, src, dst, stream, conn_id, self.enable_orig_src) => {
                                     }
                                 }
                                 // Mark we are done with the connection, so drain can complete
                                 drop(drain);
                                 debug!(component="inbound passthrough", dur=?start.elapsed(), "connection completed");
                             }
-                                .in_current_span();
+                                .instrument(info_span!("inbound passthrough", conn_id=%conn_id));
 
                             assertions::size_between_ref(1500, 3000, &serve_client);
                             tokio::spawn(serve_client);
@@ -120,11 +140,13 @@ impl InboundPassthrough {
     async fn proxy_inbound_plaintext(
         pi: Arc<ProxyInputs>,
         source_addr: SocketAddr,
+        dest_addr: SocketAddr,
         inbound_stream: TcpStream,
+        conn_id: ConnectionId,
         enable_orig_src: bool,
     ) {
         let start = Instant::now();
-        let dest_addr = socket::orig_dst_addr_or_default(&inbound_stream);
+        super::mark_accepted(&pi.cfg, &inbound_stream, conn_id);
         // Check if it is an illegal call to ourself, which could trampoline to illegal addresses or
         // lead to infinite loops
         let illegal_call = if pi.cfg.proxy_mode == ProxyMode::Shared {
@@ -145,6 +167,25 @@ impl InboundPassthrough {
             );
             return;
         }
+        if pi.cfg.loopback_passthrough && is_loopback(source_addr) && is_loopback(dest_addr) {
+            // Outside of shared mode, our listeners are reachable on loopback too. Passing a connection to one of
+            // them straight back would loop.
+            if is_own_listener(&pi.cfg, dest_addr) {
+                metrics::log_early_deny(
+                    source_addr,
+                    dest_addr,
+                    Reporter::destination,
+                    Error::SelfCall,
+                );
+                return;
+            }
+            Self::proxy_loopback(pi, source_addr, dest_addr, inbound_stream, conn_id, start).await;
+            return;
+        }
+        if let Err(err) = pi.wait_for_state().await {
+            metrics::log_early_deny(source_addr, dest_addr, Reporter::destination, err);
+            return;
+        }
         let network_addr = NetworkAddress {
             network: strng::new(&pi.cfg.network), // inbound request must be on our network
             address: dest_addr.ip(),
@@ -152,6 +193,18 @@ impl InboundPassthrough {
         let Some((upstream, upstream_service)) =
             pi.state.fetch_workload_services(&network_addr).await
         else {
+            // A destination missing from a complete state is unknown, rather than unavailable.
+            if !pi.state.is_available()
+                && pi.cfg.state_unavailable_policy.fails_open(dest_addr.ip())
+            {
+                warn!(
+                    %source_addr, %dest_addr,
+                    "destination not found in state, which is unavailable; relaying without policy, as the state unavailable policy fails open"
+                );
+                pi.metrics.state_unavailable_fail_open.inc();
+                Self::relay(pi, source_addr, dest_addr, inbound_stream, conn_id, start).await;
+                return;
+            }
             metrics::log_early_deny(
                 source_addr,
                 dest_addr,
@@ -160,6 +213,13 @@ impl InboundPassthrough {
             );
             return;
         };
+        if pi.cfg.require_hbone_inbound.applies(&upstream) {
+            pi.metrics.plaintext_inbound_rejected.inc();
+            let err = Error::PlaintextInboundRejected(upstream.uid.clone());
+            super::set_rejection_close(&pi.cfg, &inbound_stream, &err);
+            metrics::log_early_deny(source_addr, dest_addr, Reporter::destination, err);
+            return;
+        }
 
         let rbac_ctx = crate::state::ProxyRbacContext {
             conn: rbac::Connection {
@@ -189,7 +249,17 @@ impl InboundPassthrough {
             identity: rbac_ctx.conn.src_identity.clone(),
             ..Default::default()
         };
-        let ds = proxy::guess_inbound_service(&rbac_ctx.conn, &None, upstream_service, &upstream);
+        let (ds, port_name) = match proxy::guess_inbound_service(
+            &rbac_ctx.conn,
+            &None,
+            upstream_service,
+            &upstream,
+        ) {
+            Some(s) => (Some(s.service), s.port_name),
+            None => (None, None),
+        };
+        let traffic_class =
+            TrafficClass::for_connection(source_workload.as_deref(), Some(upstream.as_ref()));
         let result_tracker = Box::new(metrics::ConnectionResult::new(
             source_addr,
             dest_addr,
@@ -202,22 +272,46 @@ impl InboundPassthrough {
                 destination: Some(upstream),
                 connection_security_policy: metrics::SecurityPolicy::unknown,
                 destination_service: ds,
+                destination_service_port_name: port_name,
+                connection_id: conn_id,
             },
             pi.metrics.clone(),
         ));
 
+        let _tuple_guard = match pi
+            .connection_manager
+            .track_tuple(source_addr, dest_addr, conn_id)
+            .await
+        {
+            Ok(guard) => guard,
+            Err(e) => {
+                result_tracker.record(Err(e));
+                return;
+            }
+        };
         let conn_guard = match pi
             .connection_manager
-            .assert_rbac(&pi.state, &rbac_ctx, None)
+            .assert_rbac(
+                &pi.state,
+                &rbac_ctx,
+                conn_id,
+                None,
+                Default::default(),
+                None,
+            )
             .await
         {
             Ok(cg) => cg,
             Err(e) => {
+                super::set_rejection_close(&pi.cfg, &inbound_stream, &e);
                 result_tracker
                     .record_with_flag(Err(e), metrics::ResponseFlags::AuthorizationPolicyDenied);
                 return;
             }
         };
+        if pi.cfg.access_log_rbac_decision {
+            result_tracker.record_rbac(conn_guard.decision().clone());
+        }
 
         let orig_src = if enable_orig_src {
             Some(source_addr.ip())
@@ -225,23 +319,487 @@ impl InboundPassthrough {
             None
         };
 
+        let closing = conn_guard.closing();
         let send = async {
             trace!(%source_addr, %dest_addr, component="inbound plaintext", "connecting...");
 
-            let outbound = super::freebind_connect(orig_src, dest_addr, pi.socket_factory.as_ref())
-                .await
-                .map_err(Error::ConnectionFailed)?;
+            // Sniff while we connect, so it only adds latency if the client is slow to send its first bytes.
+            let sniff = async {
+                if pi.cfg.passthrough_http_sniffing {
+                    sniff::sniff_http(&inbound_stream, pi.cfg.passthrough_sniff_timeout).await
+                } else {
+                    None
+                }
+            };
+            let socket_factory = super::for_connection(&pi, conn_id);
+            let connect = super::freebind_connect(
+                orig_src,
+                dest_addr,
+                pi.cfg.connection_timeout,
+                &socket_factory,
+                super::ConnectOptions::from_config(&pi.cfg),
+            );
+            let (connected, http) = tokio::join!(connect, sniff);
+            let (outbound, binding) = connected.map_err(Error::ConnectionFailed)?;
+            result_tracker.record_source_binding(binding);
+            super::set_traffic_class(
+                pi.socket_factory.as_ref(),
+                traffic_class,
+                &[&inbound_stream, &outbound],
+            );
 
             trace!(%source_addr, destination=%dest_addr, component="inbound plaintext", "connected");
+            let Some(mut http) = http else {
+                return copy::copy_bidirectional_until(
+                    copy::TcpStreamSplitter(inbound_stream),
+                    copy::TcpStreamSplitter(outbound),
+                    &result_tracker,
+                    pi.cfg.force_full_close,
+                    closing,
+                )
+                .await;
+            };
+            // The response status is taken from the first chunk read from the upstream, which is tee'd off as
+            // the connection is copied. Once we have it, the tee is closed.
+            let (tx, mut rx) = mpsc::channel(1);
+            let copy = copy::copy_bidirectional_until(
+                copy::TcpStreamSplitter(inbound_stream),
+                copy::TeeSplitter::new(copy::TcpStreamSplitter(outbound), Some(tx)),
+                &result_tracker,
+                pi.cfg.force_full_close,
+                closing,
+            );
+            let status = async {
+                http.status = rx
+                    .recv()
+                    .await
+                    .and_then(|chunk| sniff::parse_status_line(&chunk));
+                drop(rx);
+                result_tracker.record_http(http);
+            };
+            let (res, _) = tokio::join!(copy, status);
+            res
+        };
+
+        let res = conn_guard.handle_connection(send).await;
+        result_tracker.record(res);
+    }
+
+    // proxy_loopback passes a loopback to loopback connection straight through. Both ends are in the pod, so
+    // neither is looked up, and no policy applies.
+    async fn proxy_loopback(
+        pi: Arc<ProxyInputs>,
+        source_addr: SocketAddr,
+        dest_addr: SocketAddr,
+        inbound_stream: TcpStream,
+        conn_id: ConnectionId,
+        start: Instant,
+    ) {
+        pi.metrics.loopback_connections.inc();
+        debug!(%source_addr, %dest_addr, "loopback connection");
+        Self::relay(pi, source_addr, dest_addr, inbound_stream, conn_id, start).await;
+    }
+
+    // relay connects to the destination from ztunnel's own address, and copies the connection as is, without
+    // looking up either end or applying policy.
+    async fn relay(
+        pi: Arc<ProxyInputs>,
+        source_addr: SocketAddr,
+        dest_addr: SocketAddr,
+        inbound_stream: TcpStream,
+        conn_id: ConnectionId,
+        start: Instant,
+    ) {
+        let result_tracker = metrics::ConnectionResult::new(
+            source_addr,
+            dest_addr,
+            None,
+            start,
+            metrics::ConnectionOpen {
+                reporter: Reporter::destination,
+                source: None,
+                derived_source: None,
+                destination: None,
+                destination_service: None,
+                destination_service_port_name: None,
+                connection_security_policy: metrics::SecurityPolicy::unknown,
+                connection_id: conn_id,
+            },
+            pi.metrics.clone(),
+        );
+        let res = async {
+            let outbound = super::freebind_connect(
+                None,
+                dest_addr,
+                pi.cfg.connection_timeout,
+                &super::for_connection(&pi, conn_id),
+                super::ConnectOptions::from_config(&pi.cfg),
+            )
+            .await
+            .map_err(Error::ConnectionFailed)?
+            .0;
             copy::copy_bidirectional(
                 copy::TcpStreamSplitter(inbound_stream),
                 copy::TcpStreamSplitter(outbound),
                 &result_tracker,
+                pi.cfg.force_full_close,
             )
             .await
+        }
+        .await;
+        result_tracker.record(res);
+    }
+}
+
+// is_loopback matches 127.0.0.0/8 and ::1, including IPv4 loopback addresses mapped into IPv6.
+fn is_loopback(addr: SocketAddr) -> bool {
+    addr.ip().to_canonical().is_loopback()
+}
+
+// is_own_listener matches our internal ports, and the addresses of our listeners. A listener bound to an
+// unspecified address matches any address on its port.
+fn is_own_listener(cfg: &Config, addr: SocketAddr) -> bool {
+    if cfg.illegal_ports.contains(&addr.port()) {
+        return true;
+    }
+    let addresses = [
+        &cfg.admin_addr,
+        &cfg.stats_addr,
+        &cfg.readiness_addr,
+        &cfg.dns_proxy_addr,
+    ];
+    [
+        cfg.inbound_addr,
+        cfg.inbound_plaintext_addr,
+        cfg.outbound_addr,
+    ]
+    .into_iter()
+    .chain(cfg.inbound_extra_addrs.iter().copied())
+    .chain(cfg.socks5_addr)
+    .chain(cfg.socks5_listeners.iter().map(|l| l.addr))
+    .chain(addresses.into_iter().flat_map(|a| a.clone()))
+    .any(|l| l.port() == addr.port() && (l.ip().is_unspecified() || l.ip() == addr.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::proxy::DefaultSocketFactory;
+    use crate::test_helpers::test_proxy_inputs;
+    use crate::xds::istio::workload::TunnelProtocol as XdsProtocol;
+    use crate::xds::istio::workload::Workload as XdsWorkload;
+
+    #[test]
+    fn loopback_ranges() {
+        for addr in [
+            "127.0.0.1:80",
+            "127.255.0.9:80",
+            "[::1]:80",
+            "[::ffff:127.0.0.2]:80",
+        ] {
+            assert!(is_loopback(addr.parse().unwrap()), "{addr}");
+        }
+        for addr in [
+            "128.0.0.1:80",
+            "10.0.0.1:80",
+            "[::2]:80",
+            "[::ffff:10.0.0.1]:80",
+            "[::]:80",
+        ] {
+            assert!(!is_loopback(addr.parse().unwrap()), "{addr}");
+        }
+    }
+
+    #[tokio::test]
+    async fn loopback_skips_identity() {
+        // The state is empty, so any lookup of the source or destination would fail the connection.
+        let pi = test_proxy_inputs(
+            crate::config::Config {
+                loopback_passthrough: true,
+                illegal_ports: HashSet::from([15006]),
+                stats_addr: crate::config::Address::Localhost(false, 15020),
+                inbound_extra_addrs: vec!["127.0.0.1:15009".parse().unwrap()],
+                socks5_listeners: vec![crate::config::Socks5Listener {
+                    addr: "127.0.0.1:15081".parse().unwrap(),
+                    scope: Default::default(),
+                }],
+                ..crate::test_helpers::test_config()
+            },
+            crate::test_helpers::new_proxy_state(&[], &[], &[]),
+            Arc::new(DefaultSocketFactory::default()),
+        );
+        let metrics = pi.metrics.clone();
+        // A server in the pod, and the socket connections redirected to it are accepted on.
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let captured = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let accept = || async {
+            let client = TcpStream::connect(captured.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (inbound, src) = captured.accept().await.unwrap();
+            (client, inbound, src)
         };
 
-        let res = conn_guard.handle_connection(send).await;
-        result_tracker.record(res);
+        let (mut client, inbound, src) = accept().await;
+        let proxied = tokio::spawn(InboundPassthrough::proxy_inbound_plaintext(
+            pi.clone(),
+            src,
+            server.local_addr().unwrap(),
+            inbound,
+            ConnectionId::next(),
+            false,
+        ));
+        let (mut upstream, _) = server.accept().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        drop(client);
+        drop(upstream);
+        proxied.await.unwrap();
+        assert_eq!(metrics.loopback_connections.get(), 1);
+
+        // Connections to our own ports and listeners are not passed back to them.
+        for dest in [
+            "127.0.0.1:15006",
+            "127.0.0.1:15020",
+            "127.0.0.1:15009",
+            "127.0.0.1:15081",
+        ] {
+            let (mut client, inbound, src) = accept().await;
+            InboundPassthrough::proxy_inbound_plaintext(
+                pi.clone(),
+                src,
+                dest.parse().unwrap(),
+                inbound,
+                ConnectionId::next(),
+                false,
+            )
+            .await;
+            assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0, "{dest}");
+        }
+        assert_eq!(metrics.loopback_connections.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn state_unavailable_policy() {
+        use crate::cidrs::CidrSet;
+        use crate::config::StateUnavailablePolicy;
+        use crate::state::StateSync;
+
+        // The state is empty, so the destination is never found. Unless the state is available, it is received
+        // from an XDS client that is not connected. Returns whether the connection was relayed.
+        let relayed = |policy: StateUnavailablePolicy, available: bool| async move {
+            let mut state = crate::test_helpers::new_proxy_state(&[], &[], &[]);
+            if !available {
+                state = state.with_state_sync(
+                    StateSync::default().with_connection_status(Default::default()),
+                );
+            }
+            let pi = test_proxy_inputs(
+                crate::config::Config {
+                    state_unavailable_policy: policy,
+                    ..crate::test_helpers::test_config()
+                },
+                state,
+                Arc::new(DefaultSocketFactory::default()),
+            );
+            let metrics = pi.metrics.clone();
+            // The listener serves as both the socket the connection is redirected to and its upstream.
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (inbound, src) = listener.accept().await.unwrap();
+            let proxied = tokio::spawn(InboundPassthrough::proxy_inbound_plaintext(
+                pi,
+                src,
+                listener.local_addr().unwrap(),
+                inbound,
+                ConnectionId::next(),
+                false,
+            ));
+            let upstream = tokio::select! {
+                accepted = listener.accept() => Some(accepted.unwrap().0),
+                _ = proxied => None,
+            };
+            let Some(mut upstream) = upstream else {
+                assert_eq!(metrics.state_unavailable_fail_open.get(), 0);
+                return false;
+            };
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            upstream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            assert_eq!(metrics.state_unavailable_fail_open.get(), 1);
+            true
+        };
+
+        let fail_open = |cidr: &str| {
+            StateUnavailablePolicy::FailOpenPassthrough(CidrSet::new(vec![cidr.parse().unwrap()]))
+        };
+        assert!(!relayed(StateUnavailablePolicy::FailClosed, false).await);
+        assert!(relayed(fail_open("127.0.0.0/8"), false).await);
+        assert!(!relayed(fail_open("10.0.0.0/8"), false).await);
+        // While the state is available, a destination missing from it is not failed open for.
+        assert!(!relayed(fail_open("127.0.0.0/8"), true).await);
+    }
+
+    #[tokio::test]
+    async fn listener_connection_budget() {
+        let cfg = crate::config::Config {
+            inbound_plaintext_addr: "127.0.0.1:0".parse().unwrap(),
+            max_total_connections: Some(1),
+            reserved_connections: 1,
+            reserved_connection_sources: vec!["127.0.0.2/32".parse().unwrap()],
+            ..crate::test_helpers::test_config()
+        };
+        let pi = test_proxy_inputs(
+            cfg,
+            crate::test_helpers::new_proxy_state(&[], &[], &[]),
+            Arc::new(DefaultSocketFactory::default()),
+        );
+        let metrics = pi.metrics.clone();
+        // Another connection holds the whole unreserved budget.
+        let _held = pi
+            .connection_manager
+            .try_acquire_budget(
+                "127.0.0.1:40000".parse().unwrap(),
+                "127.0.0.1:80".parse().unwrap(),
+            )
+            .unwrap();
+        let (_drain_tx, drain_rx) = crate::drain::new();
+        let passthrough = InboundPassthrough::new(pi, drain_rx).await.unwrap();
+        let addr = passthrough.listener.local_addr();
+        tokio::spawn(passthrough.run());
+
+        // Returns once the listener closed the connection, whether or not it was admitted.
+        let connect = move |src: &str| {
+            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            socket
+                .bind(SocketAddr::new(src.parse().unwrap(), 0))
+                .unwrap();
+            async move {
+                let mut client = socket.connect(addr).await.unwrap();
+                let _ = client.read(&mut [0u8; 1]).await;
+            }
+        };
+        connect("127.0.0.1").await;
+        assert_eq!(metrics.connection_budget_inbound_rejected.get(), 1);
+        assert_eq!(metrics.connection_budget_reserved_admitted.get(), 0);
+        connect("127.0.0.2").await;
+        assert_eq!(metrics.connection_budget_inbound_rejected.get(), 1);
+        assert_eq!(metrics.connection_budget_reserved_admitted.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn best_effort_transparency_unavailable() {
+        use crate::proxy::tests::TransparencySocketFactory;
+
+        // Proxies a connection through a listener set up by the factory, returning how many times a source IP
+        // was bound for it.
+        let freebind_attempts = |transparent| async move {
+            let factory = Arc::new(TransparencySocketFactory {
+                transparent,
+                ..Default::default()
+            });
+            let workload = XdsWorkload {
+                uid: "cluster1//v1/Pod/ns/server".to_string(),
+                name: "server".to_string(),
+                namespace: "ns".to_string(),
+                addresses: vec![bytes::Bytes::copy_from_slice(&[127, 0, 0, 1])],
+                ..Default::default()
+            };
+            let pi = test_proxy_inputs(
+                crate::config::Config {
+                    inbound_plaintext_addr: "127.0.0.1:0".parse().unwrap(),
+                    require_original_source: None,
+                    ..crate::test_helpers::test_config()
+                },
+                crate::test_helpers::new_proxy_state(&[workload], &[], &[]),
+                factory.clone(),
+            );
+            let (_drain_tx, drain_rx) = crate::drain::new();
+            let passthrough = InboundPassthrough::new(pi.clone(), drain_rx).await.unwrap();
+
+            // The listener serves as both the socket the connection is redirected to and its upstream. The
+            // client uses another address than the workload, so its source is kept if possible.
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+            let mut client = socket
+                .connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (inbound, src) = listener.accept().await.unwrap();
+            let proxied = tokio::spawn(InboundPassthrough::proxy_inbound_plaintext(
+                pi,
+                src,
+                listener.local_addr().unwrap(),
+                inbound,
+                ConnectionId::next(),
+                passthrough.enable_orig_src,
+            ));
+            let (mut upstream, _) = listener.accept().await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            upstream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            drop(client);
+            drop(upstream);
+            proxied.await.unwrap();
+            factory
+                .freebind_attempts
+                .load(std::sync::atomic::Ordering::SeqCst)
+        };
+
+        assert_eq!(freebind_attempts(true).await, 1);
+        // Best effort falls back to our own source, without attempting to bind the original one.
+        assert_eq!(freebind_attempts(false).await, 0);
+    }
+
+    #[tokio::test]
+    async fn plaintext_rejected_for_hbone_workload() {
+        let workload = XdsWorkload {
+            uid: "cluster1//v1/Pod/ns/protected".to_string(),
+            name: "protected".to_string(),
+            namespace: "ns".to_string(),
+            addresses: vec![bytes::Bytes::copy_from_slice(&[127, 0, 0, 1])],
+            tunnel_protocol: XdsProtocol::Hbone as i32,
+            ..Default::default()
+        };
+        let pi = test_proxy_inputs(
+            crate::config::Config {
+                require_hbone_inbound: crate::config::RequireHboneInbound::HboneWorkloads,
+                ..crate::test_helpers::test_config()
+            },
+            crate::test_helpers::new_proxy_state(&[workload], &[], &[]),
+            Arc::new(DefaultSocketFactory::default()),
+        );
+        let metrics = pi.metrics.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (inbound, src) = listener.accept().await.unwrap();
+        InboundPassthrough::proxy_inbound_plaintext(
+            pi,
+            src,
+            listener.local_addr().unwrap(),
+            inbound,
+            ConnectionId::next(),
+            false,
+        )
+        .await;
+
+        // The connection is closed without reaching the workload.
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(metrics.plaintext_inbound_rejected.get(), 1);
     }
 }
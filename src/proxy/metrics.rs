@@ -13,35 +13,179 @@
 // limitations under the License.
 
 use std::fmt::Write;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{atomic, Arc};
+use std::sync::{atomic, Arc, OnceLock};
 use std::time::Instant;
 
+use bytes::Bytes;
 use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder};
 use prometheus_client::metrics::counter::{Atomic, Counter};
+use prometheus_client::metrics::exemplar::HistogramWithExemplars;
 use prometheus_client::metrics::family::Family;
-use prometheus_client::registry::Registry;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::Histogram;
+use prometheus_client::registry::{Registry, Unit};
 
-use tracing::event;
+use tracing::{debug, debug_span, event, Instrument};
 use tracing_core::field::Value;
 
 use crate::identity::Identity;
 use crate::metrics::DefaultedUnknown;
 use crate::proxy;
+use crate::proxy::connection_metadata::ConnectionMetadata;
+use crate::proxy::recording;
+use crate::proxy::sniff::HttpRequest;
+use crate::proxy::throttle;
+use crate::rbac;
 
 use crate::state::service::ServiceDescription;
 use crate::state::workload::Workload;
 use crate::strng::{RichStrng, Strng};
+use crate::telemetry::targeted;
+use crate::tls;
 
 pub struct Metrics {
     pub connection_opens: Family<CommonTrafficLabels, Counter>,
     pub connection_close: Family<CommonTrafficLabels, Counter>,
     pub received_bytes: Family<CommonTrafficLabels, Counter>,
     pub sent_bytes: Family<CommonTrafficLabels, Counter>,
+    // Whether the metrics above are labeled with the source and destination node
+    node_labels: bool,
+    // Whether the metrics above are labeled with the traffic scope of the connection
+    traffic_scope_label: bool,
+    // Whether the metrics above are labeled with the uid of the destination workload
+    destination_workload_uid_label: bool,
+    // Outbound bytes by the source workload, for cost attribution. Only recorded if enabled.
+    pub outbound_bytes_by_source: Family<CostAttributionLabels, Counter>,
+    cost_attribution: bool,
 
     // on-demand DNS is not a part of DNS proxy, but part of ztunnel proxy itself
     pub on_demand_dns: Family<OnDemandDnsLabels, Counter>,
+
+    // HPACK encoded header bytes sent and received on HBONE connections
+    pub hbone_header_bytes: Family<HeaderBytesLabels, Counter>,
+
+    // Which source address upstream connections were established with
+    pub source_binding: Family<SourceBindingLabels, Counter>,
+    pub original_source_fallbacks: Counter,
+
+    // HTTP requests seen by sniffing inbound passthrough connections; only the first request on a connection is seen
+    pub sniffed_http_requests: Family<SniffedHttpLabels, Counter>,
+
+    // HBONE connections whose SYN carried data with TCP Fast Open, and those that could not enable it
+    pub tfo_connections: Counter,
+    pub tfo_fallbacks: Counter,
+
+    // Upstream connections that reused the source port of a closed connection, and attempts to that failed
+    pub source_port_reused: Counter,
+    pub source_port_reuse_failed: Counter,
+
+    // Bytes of recorded connections that were not written, as the recording fell behind
+    pub recording_dropped_bytes: Counter,
+
+    // Bytes relayed over a bandwidth limit, and the time connections were paused for to respect it
+    pub throttled_bytes: Counter,
+    pub throttled_seconds: Counter<f64, AtomicU64>,
+
+    // Upstream connects currently in flight, and those that had to wait for the connect concurrency limit
+    pub connects_in_flight: Gauge,
+    pub connects_waited: Counter,
+
+    // Outbound endpoint selections for services with weighted subsets
+    pub subset_requests: Family<SubsetLabels, Counter>,
+
+    // Outbound endpoint selections for services with consistent hash load balancing. Comparing the counts of a
+    // service's endpoints shows how evenly its clients are spread.
+    pub consistent_hash_selections: Family<ConsistentHashLabels, Counter>,
+
+    // Outbound connections from unknown sources, allowed by the unknown source policy
+    pub anonymous_source_connections: Counter,
+
+    // Connections no longer allowed after a policy update that were given a grace period before closing
+    pub late_rejections_grace_applied: Counter,
+    // Plaintext connections and HBONE streams closed as they reached the maximum connection lifetime
+    pub streams_closed_max_lifetime: Counter,
+    // Connections closed by an operator draining those matching a selector
+    pub connections_drained_by_selector: Counter,
+    // Inbound connections seen while another with the same addresses was still tracked
+    pub double_connection: Counter,
+
+    // Connections rejected for traversing too many ztunnels
+    pub proxy_loops_detected: Counter,
+
+    // Egress connections rejected by the SNI allowlist, or by the source workload's egress rules
+    pub egress_denied: Family<EgressDeniedLabels, Counter>,
+    // Outbound connections to bypass CIDRs, passed through without mesh processing
+    pub bypass_connections: Counter,
+    // Inbound loopback to loopback connections, passed through without mesh processing
+    pub loopback_connections: Counter,
+    // Inbound plaintext connections rejected, as their destination requires HBONE
+    pub plaintext_inbound_rejected: Counter,
+
+    // Inbound connections to destinations missing from the state that were relayed without policy
+    pub state_unavailable_fail_open: Counter,
+
+    // Outbound connection setups abandoned, as the client reset its connection before they completed
+    pub setup_cancelled_client_gone: Counter,
+
+    // Connections kept open ahead of time to warm destinations
+    pub warm_connections_active: Family<WarmConnectionLabels, Gauge>,
+
+    // Outbound connections mirrored to a secondary destination, and mirrors that failed
+    pub mirrored_connections: Counter,
+    pub mirror_errors: Counter,
+
+    // Time spent in each phase of outbound connection setup
+    pub setup_phase_duration: Family<SetupPhaseLabels, Histogram>,
+
+    // Time from accepting a connection until its handler starts running, and connections accepted but whose handler
+    // has not started yet, by listener. These grow when the runtime cannot keep up.
+    pub accept_to_handle_latency: Family<AcceptLabels, Histogram>,
+    pub accept_queue_depth: Family<AcceptLabels, Gauge>,
+
+    // End to end outbound connection setup time, and failed setups, by destination service
+    pub connection_setup_duration:
+        Family<ConnectionSetupLabels, HistogramWithExemplars<TraceExemplar>>,
+    pub connection_setup_failures: Family<ConnectionSetupFailureLabels, Counter>,
+
+    // Failed HBONE TLS handshakes, by which side we were and why they failed
+    pub tls_handshake_failures: Family<TlsHandshakeFailureLabels, Counter>,
+    // Completed HBONE TLS handshakes, by which side we were and whether they resumed an earlier session
+    pub tls_handshakes: Family<TlsHandshakeLabels, Counter>,
+    // TLS sessions originated to destinations outside the mesh, and failed attempts by why they failed
+    pub tls_originations: Counter,
+    pub tls_origination_failures: Family<TlsOriginationFailureLabels, Counter>,
+
+    // HBONE streams sent over an existing pooled connection, and those that needed a new connection, by destination
+    // service. Together, they give the pool's reuse ratio.
+    pub pool_stream_reuse: Family<DestinationServiceLabels, Counter>,
+    pub pool_new_connection: Family<DestinationServiceLabels, Counter>,
+    // HBONE streams that bypassed the pool, each over a dedicated connection, by destination service
+    pub pool_bypass_connection: Family<DestinationServiceLabels, Counter>,
+    // Pooled connections closed because the peer did not answer a keepalive PING in time
+    pub pool_keepalive_timeouts: Counter,
+    // Idle pooled connections kept open to meet the per destination minimum
+    pub pool_floor_connections: Gauge,
+    // HBONE connections whose outer TCP connection stopped making progress, as with a path MTU black hole
+    pub hbone_connection_stalls: Counter,
+    // HBONE streams that were reset, by the frame that reset them and its error code
+    pub h2_stream_resets: Family<H2StreamResetLabels, Counter>,
+
+    // Inbound connections closed as soon as they were accepted, and outbound connections rejected, because the
+    // connection budget was exhausted
+    pub connection_budget_inbound_rejected: Counter,
+    pub connection_budget_outbound_rejected: Counter,
+    // Connections admitted using the reserved connections
+    pub connection_budget_reserved_admitted: Counter,
+
+    // Outbound connections open to each destination service. This is only tracked when a per-service limit is
+    // configured.
+    pub destination_service_in_flight: Family<DestinationServiceLabels, Gauge>,
+
+    // Upstream connections that could not be established through the forward proxy
+    pub forward_proxy_failures: Family<ForwardProxyFailureLabels, Counter>,
 }
 
 #[derive(Clone, Copy, Default, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
@@ -77,6 +221,62 @@ impl EncodeLabelValue for ResponseFlags {
     }
 }
 
+/// HeaderDirection is whether HBONE headers were sent or received by this ztunnel.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum HeaderDirection {
+    sent,
+    received,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct HeaderBytesLabels {
+    pub direction: HeaderDirection,
+}
+
+/// ByteDirection is whether bytes were sent to the destination, or received from it.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum ByteDirection {
+    sent,
+    received,
+}
+
+/// CostAttributionLabels break outbound bytes down by the team they can be billed to. These are deliberately
+/// few, so the number of series is bounded by the number of source services rather than connections.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct CostAttributionLabels {
+    source_workload_namespace: DefaultedUnknown<RichStrng>,
+    source_canonical_service: DefaultedUnknown<RichStrng>,
+    traffic_scope: TrafficScope,
+    direction: ByteDirection,
+}
+
+/// SourceBinding describes which source address an upstream connection was established with.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum SourceBinding {
+    /// The connection was bound to the original source IP of the client.
+    original,
+    /// Original source was requested, but does not apply to the destination, so the connection used ztunnel's
+    /// own IP. This happens when the source and destination are the same or in different IP families, original
+    /// source is not configured for the destination, or the connection goes through a forward proxy.
+    not_configured,
+    /// Original source was requested, but binding to the source IP failed, so the connection used ztunnel's own IP.
+    bind_failed,
+    /// Original source was not requested.
+    none,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct SniffedHttpLabels {
+    request_method: RichStrng,
+    response_code: DefaultedUnknown<RichStrng>,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct SourceBindingLabels {
+    reporter: Reporter,
+    binding: SourceBinding,
+}
+
 #[derive(Default, Copy, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
 pub enum SecurityPolicy {
     #[default]
@@ -101,7 +301,46 @@ pub struct ConnectionOpen {
     pub derived_source: Option<DerivedWorkload>,
     pub destination: Option<Arc<Workload>>,
     pub destination_service: Option<ServiceDescription>,
+    // The name of the destination service port, if known
+    pub destination_service_port_name: Option<Strng>,
     pub connection_security_policy: SecurityPolicy,
+    // Only logged, as every connection has a distinct ID
+    pub connection_id: proxy::ConnectionId,
+}
+
+impl ConnectionOpen {
+    /// traffic_scope classifies the connection by which of its endpoints are known workloads. A source is also
+    /// known if it has a mesh identity, even if its workload is not.
+    pub fn traffic_scope(&self) -> TrafficScope {
+        let source_known = self.source.is_some() || self.derived_source.is_some();
+        match (source_known, self.destination.is_some()) {
+            (true, true) => TrafficScope::mesh_internal,
+            (false, true) => TrafficScope::ingress,
+            (_, false) => TrafficScope::egress,
+        }
+    }
+}
+
+/// TrafficScope is whether a connection stays within the mesh. It is computed once, when the connection opens.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum TrafficScope {
+    // Both the source and the destination are known workloads.
+    mesh_internal,
+    // The destination is not a known workload, such as a service outside the mesh.
+    #[default]
+    egress,
+    // The destination is a known workload, but the source is not, such as a client outside the mesh.
+    ingress,
+}
+
+impl TrafficScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrafficScope::mesh_internal => "mesh_internal",
+            TrafficScope::egress => "egress",
+            TrafficScope::ingress => "ingress",
+        }
+    }
 }
 
 impl CommonTrafficLabels {
@@ -120,7 +359,7 @@ impl CommonTrafficLabels {
         self.source_app = w.canonical_name.clone().into();
         self.source_version = w.canonical_revision.clone().into();
         self.source_cluster = w.cluster_id.to_string().into();
-        self
+        self.with_optional_label("src_node", node_label(w))
     }
 
     fn with_derived_source(mut self, w: Option<&DerivedWorkload>) -> Self {
@@ -147,6 +386,17 @@ impl CommonTrafficLabels {
         self.destination_app = w.canonical_name.clone().into();
         self.destination_version = w.canonical_revision.clone().into();
         self.destination_cluster = w.cluster_id.to_string().into();
+        self.with_optional_label("dst_node", node_label(w))
+    }
+
+    fn without_nodes(self) -> Self {
+        self.with_optional_label("src_node", None)
+            .with_optional_label("dst_node", None)
+    }
+
+    fn with_optional_label(mut self, key: &'static str, value: Option<RichStrng>) -> Self {
+        self.optional_labels.retain(|(k, _)| *k != key);
+        self.optional_labels.extend(value.map(|v| (key, v)));
         self
     }
 
@@ -166,6 +416,7 @@ impl From<ConnectionOpen> for CommonTrafficLabels {
             request_protocol: RequestProtocol::tcp,
             response_flags: ResponseFlags::None,
             connection_security_policy: c.connection_security_policy,
+            destination_service_port_name: c.destination_service_port_name.into(),
             ..CommonTrafficLabels::new()
                 // Intentionally before with_source; source is more reliable
                 .with_derived_source(c.derived_source.as_ref())
@@ -192,6 +443,8 @@ pub struct CommonTrafficLabels {
     destination_service: DefaultedUnknown<RichStrng>,
     destination_service_namespace: DefaultedUnknown<RichStrng>,
     destination_service_name: DefaultedUnknown<RichStrng>,
+    // The name of the destination service port the connection matched, if the port is named
+    destination_service_port_name: DefaultedUnknown<RichStrng>,
 
     destination_workload: DefaultedUnknown<RichStrng>,
     destination_canonical_service: DefaultedUnknown<RichStrng>,
@@ -205,6 +458,18 @@ pub struct CommonTrafficLabels {
     request_protocol: RequestProtocol,
     response_flags: ResponseFlags,
     connection_security_policy: SecurityPolicy,
+
+    // Labels that are left out of the series entirely while unset, rather than encoded as empty:
+    // src_node and dst_node are only set if node labels are enabled and the node is known,
+    // traffic_scope only if the traffic scope label is enabled, and destination_workload_uid only
+    // if that label is enabled and the destination workload is known.
+    // Flattening hands the encoder over, so this must stay the last field.
+    #[prometheus(flatten)]
+    optional_labels: Vec<(&'static str, RichStrng)>,
+}
+
+fn node_label(w: &Workload) -> Option<RichStrng> {
+    (!w.node.is_empty()).then(|| w.node.clone().into())
 }
 
 #[derive(Clone, Hash, Default, Debug, PartialEq, Eq, EncodeLabelSet)]
@@ -246,6 +511,362 @@ impl OnDemandDnsLabels {
     }
 }
 
+/// SetupPhase is a distinct step in establishing an outbound connection.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum SetupPhase {
+    // Finding the source workload, destination, and selecting an endpoint. Includes dns_resolution.
+    destination_lookup,
+    // On-demand DNS resolution of the destination workload
+    dns_resolution,
+    tcp_connect,
+    tls_handshake,
+    // Sending the HBONE CONNECT request and awaiting the response
+    hbone_connect,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct SetupPhaseLabels {
+    phase: SetupPhase,
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum AcceptListener {
+    inbound,
+    inbound_passthrough,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct AcceptLabels {
+    listener: AcceptListener,
+}
+
+/// AcceptMetrics are the accept loop metrics of one listener, looked up once so each accepted connection only
+/// pays for a clock read and a couple of atomic operations.
+#[derive(Clone)]
+pub struct AcceptMetrics {
+    latency: Histogram,
+    queued: Gauge,
+}
+
+impl AcceptMetrics {
+    /// accepted starts timing a connection that was just accepted.
+    pub fn accepted(&self) -> AcceptTimer {
+        self.queued.inc();
+        AcceptTimer {
+            accepted: Instant::now(),
+            metrics: self.clone(),
+        }
+    }
+}
+
+/// AcceptTimer is an accepted connection waiting for its handler. The connection leaves the queue when the
+/// handler calls `started`, or when the timer is dropped without it, such as on shutdown.
+pub struct AcceptTimer {
+    accepted: Instant,
+    metrics: AcceptMetrics,
+}
+
+impl AcceptTimer {
+    pub fn started(self) {
+        self.metrics
+            .latency
+            .observe(self.accepted.elapsed().as_secs_f64());
+    }
+}
+
+impl Drop for AcceptTimer {
+    fn drop(&mut self) {
+        self.metrics.queued.dec();
+    }
+}
+
+/// TlsFailureReason classifies a failed HBONE TLS handshake. The set of reasons is fixed, and new ones should
+/// only be added for causes an operator would act on differently.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum TlsFailureReason {
+    // The peer did not speak TLS at all, such as a port scanner or a plaintext client.
+    not_tls,
+    // The connection was closed before the handshake completed.
+    connection_closed,
+    // The handshake did not complete in time.
+    timeout,
+    // The peer did not present a certificate.
+    no_certificate,
+    // The peer's certificate has expired or is not yet valid; usually a certificate rotation problem.
+    certificate_expired,
+    // The peer's certificate is not signed by a trusted root; usually a trust bundle misconfiguration.
+    unknown_ca,
+    // The peer's certificate is valid, but not for the expected identity or trust domain.
+    identity_mismatch,
+    // There is no protocol version or cipher suite supported by both sides.
+    incompatible,
+    // The peer aborted the handshake with an alert, typically because it rejected our certificate.
+    peer_rejected,
+    // We could not get a certificate to present.
+    local_certificate,
+    other,
+}
+
+impl TlsFailureReason {
+    /// from_io_error classifies a handshake failure as returned by tokio-rustls, which wraps rustls errors.
+    pub fn from_io_error(err: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        if let Some(err) = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<rustls::Error>())
+        {
+            return Self::from_rustls_error(err);
+        }
+        match err.kind() {
+            ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe => TlsFailureReason::connection_closed,
+            ErrorKind::TimedOut => TlsFailureReason::timeout,
+            _ => TlsFailureReason::other,
+        }
+    }
+
+    /// from_tls_error classifies a failure to accept an inbound TLS connection.
+    pub fn from_tls_error(err: &tls::TlsError) -> Self {
+        match err {
+            tls::TlsError::Handshake(e) => Self::from_io_error(e),
+            tls::TlsError::CertificateLookup(_) | tls::TlsError::SigningError(_) => {
+                TlsFailureReason::local_certificate
+            }
+            tls::TlsError::SanError(..) | tls::TlsError::SanTrustDomainError(..) => {
+                TlsFailureReason::identity_mismatch
+            }
+            _ => TlsFailureReason::other,
+        }
+    }
+
+    fn from_rustls_error(err: &rustls::Error) -> Self {
+        use rustls::CertificateError;
+        match err {
+            // A plaintext client's first bytes do not parse as a TLS record.
+            rustls::Error::InvalidMessage(_) | rustls::Error::PeerSentOversizedRecord => {
+                TlsFailureReason::not_tls
+            }
+            rustls::Error::NoCertificatesPresented => TlsFailureReason::no_certificate,
+            rustls::Error::InvalidCertificate(e) => match e {
+                CertificateError::Expired | CertificateError::NotValidYet => {
+                    TlsFailureReason::certificate_expired
+                }
+                CertificateError::UnknownIssuer | CertificateError::BadSignature => {
+                    TlsFailureReason::unknown_ca
+                }
+                // Our verifiers report identity and trust domain mismatches this way.
+                CertificateError::ApplicationVerificationFailure
+                | CertificateError::NotValidForName
+                | CertificateError::Other(_) => TlsFailureReason::identity_mismatch,
+                _ => TlsFailureReason::other,
+            },
+            rustls::Error::PeerIncompatible(_) => TlsFailureReason::incompatible,
+            rustls::Error::AlertReceived(_) => TlsFailureReason::peer_rejected,
+            _ => TlsFailureReason::other,
+        }
+    }
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct TlsHandshakeFailureLabels {
+    // source for outbound handshakes, destination for inbound ones
+    reporter: Reporter,
+    reason: TlsFailureReason,
+}
+
+/// ResetFrame is the HTTP/2 frame that reset an HBONE stream.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum ResetFrame {
+    // The stream alone was reset
+    rst_stream,
+    // The whole connection was closed
+    goaway,
+}
+
+/// H2ErrorCode is the error code of an HTTP/2 reset, as defined in RFC 9113 section 7.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum H2ErrorCode {
+    no_error,
+    protocol_error,
+    internal_error,
+    flow_control_error,
+    settings_timeout,
+    stream_closed,
+    frame_size_error,
+    refused_stream,
+    cancel,
+    compression_error,
+    connect_error,
+    enhance_your_calm,
+    inadequate_security,
+    http_1_1_required,
+    // Codes outside the RFC; these are only seen from misbehaving peers.
+    unknown,
+}
+
+impl From<::h2::Reason> for H2ErrorCode {
+    fn from(reason: ::h2::Reason) -> Self {
+        use ::h2::Reason;
+        match reason {
+            Reason::NO_ERROR => H2ErrorCode::no_error,
+            Reason::PROTOCOL_ERROR => H2ErrorCode::protocol_error,
+            Reason::INTERNAL_ERROR => H2ErrorCode::internal_error,
+            Reason::FLOW_CONTROL_ERROR => H2ErrorCode::flow_control_error,
+            Reason::SETTINGS_TIMEOUT => H2ErrorCode::settings_timeout,
+            Reason::STREAM_CLOSED => H2ErrorCode::stream_closed,
+            Reason::FRAME_SIZE_ERROR => H2ErrorCode::frame_size_error,
+            Reason::REFUSED_STREAM => H2ErrorCode::refused_stream,
+            Reason::CANCEL => H2ErrorCode::cancel,
+            Reason::COMPRESSION_ERROR => H2ErrorCode::compression_error,
+            Reason::CONNECT_ERROR => H2ErrorCode::connect_error,
+            Reason::ENHANCE_YOUR_CALM => H2ErrorCode::enhance_your_calm,
+            Reason::INADEQUATE_SECURITY => H2ErrorCode::inadequate_security,
+            Reason::HTTP_1_1_REQUIRED => H2ErrorCode::http_1_1_required,
+            _ => H2ErrorCode::unknown,
+        }
+    }
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct H2StreamResetLabels {
+    // source for outbound connections, destination for inbound ones
+    reporter: Reporter,
+    frame: ResetFrame,
+    code: H2ErrorCode,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct TlsOriginationFailureLabels {
+    pub reason: TlsFailureReason,
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum TlsHandshakeKind {
+    full,
+    resumed,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct TlsHandshakeLabels {
+    // source for outbound handshakes, destination for inbound ones
+    reporter: Reporter,
+    kind: TlsHandshakeKind,
+}
+
+/// ForwardProxyFailure is why an upstream connection could not be tunneled through the forward proxy.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum ForwardProxyFailure {
+    // The forward proxy could not be resolved or connected to
+    connect,
+    // The forward proxy answered the CONNECT request with an error status
+    rejected,
+    // The forward proxy closed the connection or sent a malformed response
+    invalid_response,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct ForwardProxyFailureLabels {
+    reason: ForwardProxyFailure,
+}
+
+/// TraceExemplar links a metric observation to the trace of a sampled connection.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct TraceExemplar {
+    trace_id: String,
+}
+
+impl TraceExemplar {
+    /// for_trace returns an exemplar for the trace, if it was sampled. Unsampled traces are not recorded, to
+    /// keep the overhead bounded.
+    pub fn for_trace(trace: &proxy::TraceParent) -> Option<Self> {
+        trace.is_sampled().then(|| TraceExemplar {
+            trace_id: trace.to_string(),
+        })
+    }
+}
+
+#[derive(Clone, Hash, Default, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct ConnectionSetupLabels {
+    destination_service: DefaultedUnknown<RichStrng>,
+    destination_service_namespace: DefaultedUnknown<RichStrng>,
+    destination_service_name: DefaultedUnknown<RichStrng>,
+}
+
+/// SetupFailureStage is the stage an outbound connection failed to be established in.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum SetupFailureStage {
+    dns_resolution,
+    endpoint_selection,
+    tcp_connect,
+    tls_handshake,
+    hbone_connect,
+    other,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct ConnectionSetupFailureLabels {
+    destination_service: DefaultedUnknown<RichStrng>,
+    destination_service_namespace: DefaultedUnknown<RichStrng>,
+    destination_service_name: DefaultedUnknown<RichStrng>,
+    stage: SetupFailureStage,
+}
+
+#[derive(Clone, Hash, Default, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct DestinationServiceLabels {
+    destination_service: DefaultedUnknown<RichStrng>,
+    destination_service_namespace: DefaultedUnknown<RichStrng>,
+    destination_service_name: DefaultedUnknown<RichStrng>,
+}
+
+impl From<Option<&ServiceDescription>> for DestinationServiceLabels {
+    fn from(svc: Option<&ServiceDescription>) -> Self {
+        match svc {
+            Some(svc) => DestinationServiceLabels {
+                destination_service: svc.hostname.clone().into(),
+                destination_service_namespace: svc.namespace.clone().into(),
+                destination_service_name: svc.name.clone().into(),
+            },
+            None => DestinationServiceLabels::default(),
+        }
+    }
+}
+
+/// EgressDenyReason is why an egress connection was rejected.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum EgressDenyReason {
+    // The SNI is not in the allowlist
+    not_allowed,
+    // The client did not send a valid ClientHello with an SNI
+    invalid_client_hello,
+    // The source workload's egress rules do not allow the destination
+    egress_rules,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct EgressDeniedLabels {
+    pub reason: EgressDenyReason,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct WarmConnectionLabels {
+    // The configured destination, as hostname:port
+    pub destination: String,
+}
+
+#[derive(Clone, Hash, Default, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct SubsetLabels {
+    pub destination_service: DefaultedUnknown<RichStrng>,
+    pub subset: DefaultedUnknown<RichStrng>,
+}
+
+#[derive(Clone, Hash, Default, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct ConsistentHashLabels {
+    pub destination_service: DefaultedUnknown<RichStrng>,
+    pub destination_workload: DefaultedUnknown<RichStrng>,
+}
+
 impl Metrics {
     pub fn new(registry: &mut Registry) -> Self {
         let connection_opens = Family::default();
@@ -261,6 +882,12 @@ impl Metrics {
             connection_close.clone(),
         );
 
+        let outbound_bytes_by_source = Family::default();
+        registry.register(
+            "outbound_bytes_by_source",
+            "The total bytes of outbound connections by source namespace, canonical service and traffic scope, for cost attribution (unstable)",
+            outbound_bytes_by_source.clone(),
+        );
         let received_bytes = Family::default();
         registry.register(
             "tcp_received_bytes",
@@ -279,6 +906,340 @@ impl Metrics {
             "The total number of requests that used on-demand DNS (unstable)",
             on_demand_dns.clone(),
         );
+        let hbone_header_bytes = Family::default();
+        registry.register(
+            "hbone_header_bytes",
+            "The total size of HTTP/2 header blocks on HBONE connections, after HPACK compression, if HBONE_HEADER_METRICS is set (unstable)",
+            hbone_header_bytes.clone(),
+        );
+
+        let source_binding = Family::default();
+        registry.register(
+            "upstream_source_binding",
+            "The total number of upstream connections, by which source address they were established with (unstable)",
+            source_binding.clone(),
+        );
+        let tfo_connections = Counter::default();
+        registry.register(
+            "tfo_connections",
+            "The total number of HBONE connections that sent data in the SYN with TCP Fast Open (unstable)",
+            tfo_connections.clone(),
+        );
+        let tfo_fallbacks = Counter::default();
+        registry.register(
+            "tfo_fallbacks",
+            "The total number of HBONE connections that fell back to a normal connect as TCP Fast Open could not be enabled (unstable)",
+            tfo_fallbacks.clone(),
+        );
+        let source_port_reused = Counter::default();
+        registry.register(
+            "source_port_reused",
+            "The total number of upstream connections that reused the source port of a closed connection to the same destination (unstable)",
+            source_port_reused.clone(),
+        );
+        let source_port_reuse_failed = Counter::default();
+        registry.register(
+            "source_port_reuse_failed",
+            "The total number of upstream connections that could not reuse a source port, and used a fresh one (unstable)",
+            source_port_reuse_failed.clone(),
+        );
+        let recording_dropped_bytes = Counter::default();
+        registry.register(
+            "connection_recording_dropped_bytes",
+            "The total number of bytes left out of connection recordings, as writing them fell behind (unstable)",
+            recording_dropped_bytes.clone(),
+        );
+        let throttled_bytes = Counter::default();
+        registry.register(
+            "throttled_bytes",
+            "The total number of bytes relayed in excess of a bandwidth limit, which delayed the connection (unstable)",
+            throttled_bytes.clone(),
+        );
+        let throttled_seconds = Counter::<f64, AtomicU64>::default();
+        registry.register_with_unit(
+            "throttled",
+            "The total time connections were paused for to respect bandwidth limits (unstable)",
+            Unit::Seconds,
+            throttled_seconds.clone(),
+        );
+        let connects_in_flight = Gauge::default();
+        registry.register(
+            "upstream_connects_in_flight",
+            "The number of upstream TCP connects in flight, when connect concurrency is limited (unstable)",
+            connects_in_flight.clone(),
+        );
+        let connects_waited = Counter::default();
+        registry.register(
+            "upstream_connects_waited",
+            "The total number of upstream TCP connects that waited for the connect concurrency limit (unstable)",
+            connects_waited.clone(),
+        );
+        let late_rejections_grace_applied = Counter::default();
+        registry.register(
+            "late_rejections_grace_applied",
+            "The total number of connections no longer allowed after a policy update that were kept open for the policy change grace period before closing (unstable)",
+            late_rejections_grace_applied.clone(),
+        );
+        let streams_closed_max_lifetime = Counter::default();
+        registry.register(
+            "streams_closed_max_lifetime",
+            "The total number of proxied streams, each plaintext connection or HBONE stream, closed as they reached the configured maximum connection lifetime (unstable)",
+            streams_closed_max_lifetime.clone(),
+        );
+        let connections_drained_by_selector = Counter::default();
+        registry.register(
+            "connections_drained_by_selector",
+            "The total number of connections closed by the admin server draining those matching a selector (unstable)",
+            connections_drained_by_selector.clone(),
+        );
+        let double_connection = Counter::default();
+        registry.register(
+            "double_connection",
+            "The total number of inbound connections seen while a connection with the same source and destination addresses was still tracked (unstable)",
+            double_connection.clone(),
+        );
+        let original_source_fallbacks = Counter::default();
+        registry.register(
+            "original_source_fallbacks",
+            "The total number of upstream connections that failed to bind the original source and used ztunnel's IP instead (unstable)",
+            original_source_fallbacks.clone(),
+        );
+        let sniffed_http_requests = Family::default();
+        registry.register(
+            "sniffed_http_requests",
+            "The total number of HTTP requests detected on inbound passthrough connections, by method and response code (unstable)",
+            sniffed_http_requests.clone(),
+        );
+        let subset_requests = Family::default();
+        registry.register(
+            "subset_requests",
+            "The total number of outbound requests routed to each weighted subset of a service (unstable)",
+            subset_requests.clone(),
+        );
+        let consistent_hash_selections = Family::default();
+        registry.register(
+            "consistent_hash_selections",
+            "The total number of outbound requests routed to each endpoint of a service with consistent hash load balancing (unstable)",
+            consistent_hash_selections.clone(),
+        );
+        let anonymous_source_connections = Counter::default();
+        registry.register(
+            "anonymous_source_connections",
+            "The total number of outbound connections from unknown sources that were allowed as anonymous (unstable)",
+            anonymous_source_connections.clone(),
+        );
+        let proxy_loops_detected = Counter::default();
+        registry.register(
+            "proxy_loops_detected",
+            "The total number of connections rejected for exceeding the maximum number of ztunnel hops (unstable)",
+            proxy_loops_detected.clone(),
+        );
+        let egress_denied = Family::default();
+        registry.register(
+            "egress_denied",
+            "The total number of egress connections rejected by the SNI allowlist or the source workload's egress rules (unstable)",
+            egress_denied.clone(),
+        );
+        let bypass_connections = Counter::default();
+        registry.register(
+            "bypass_connections",
+            "The total number of outbound connections to bypass CIDRs, passed through without mesh processing (unstable)",
+            bypass_connections.clone(),
+        );
+        let loopback_connections = Counter::default();
+        registry.register(
+            "loopback_connections",
+            "The total number of inbound connections from and to loopback addresses, passed through without mesh processing (unstable)",
+            loopback_connections.clone(),
+        );
+        let plaintext_inbound_rejected = Counter::default();
+        registry.register(
+            "plaintext_inbound_rejected",
+            "The total number of inbound passthrough connections rejected, as their destination only accepts HBONE (unstable)",
+            plaintext_inbound_rejected.clone(),
+        );
+        let state_unavailable_fail_open = Counter::default();
+        registry.register(
+            "state_unavailable_fail_open",
+            "The total number of inbound connections to destinations missing from the state that were relayed without policy, as the state unavailable policy fails open (unstable)",
+            state_unavailable_fail_open.clone(),
+        );
+        let setup_cancelled_client_gone = Counter::default();
+        registry.register(
+            "setup_cancelled_client_gone",
+            "The total number of outbound connection setups abandoned, as the client reset its connection before the upstream connection was established (unstable)",
+            setup_cancelled_client_gone.clone(),
+        );
+        let warm_connections_active = Family::default();
+        registry.register(
+            "warm_connections_active",
+            "The number of warm connections currently held to each warm destination (unstable)",
+            warm_connections_active.clone(),
+        );
+        let mirrored_connections = Counter::default();
+        registry.register(
+            "mirrored_connections",
+            "The total number of outbound connections completely mirrored to a secondary destination (unstable)",
+            mirrored_connections.clone(),
+        );
+        let mirror_errors = Counter::default();
+        registry.register(
+            "mirror_errors",
+            "The total number of mirrored connections that failed or were abandoned (unstable)",
+            mirror_errors.clone(),
+        );
+        let setup_phase_duration =
+            Family::<SetupPhaseLabels, Histogram>::new_with_constructor(|| {
+                Histogram::new(
+                    vec![0.0005f64, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0].into_iter(),
+                )
+            });
+        registry.register_with_unit(
+            "connection_setup_phase_duration",
+            "Time spent in each phase of establishing an outbound connection (unstable)",
+            Unit::Seconds,
+            setup_phase_duration.clone(),
+        );
+        let accept_to_handle_latency =
+            Family::<AcceptLabels, Histogram>::new_with_constructor(|| {
+                Histogram::new(
+                    vec![
+                        0.00001f64, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+                    ]
+                    .into_iter(),
+                )
+            });
+        registry.register_with_unit(
+            "accept_to_handle_latency",
+            "Time from accepting a connection until its handler starts running, by listener (unstable)",
+            Unit::Seconds,
+            accept_to_handle_latency.clone(),
+        );
+        let accept_queue_depth = Family::default();
+        registry.register(
+            "accept_queue_depth",
+            "The number of accepted connections whose handler has not started running yet, by listener (unstable)",
+            accept_queue_depth.clone(),
+        );
+        let connection_setup_duration =
+            Family::<ConnectionSetupLabels, HistogramWithExemplars<_>>::new_with_constructor(
+                || {
+                    HistogramWithExemplars::new(
+                        vec![
+                            0.001f64, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+                        ]
+                        .into_iter(),
+                    )
+                },
+            );
+        registry.register_with_unit(
+            "connection_setup_duration",
+            "Time from selecting a destination until an outbound connection is ready to use, for successful setups. Sampled connections are recorded as exemplars (unstable)",
+            Unit::Seconds,
+            connection_setup_duration.clone(),
+        );
+        let connection_setup_failures = Family::default();
+        registry.register(
+            "connection_setup_failures",
+            "The total number of outbound connections that failed to be established, by the stage they failed in (unstable)",
+            connection_setup_failures.clone(),
+        );
+        let tls_handshake_failures = Family::default();
+        registry.register(
+            "tls_handshake_failures",
+            "The total number of failed HBONE TLS handshakes, by reason. Reasons are not_tls, connection_closed, timeout, no_certificate, certificate_expired, unknown_ca, identity_mismatch, incompatible, peer_rejected, local_certificate and other (unstable)",
+            tls_handshake_failures.clone(),
+        );
+        let tls_handshakes = Family::default();
+        registry.register(
+            "tls_handshakes",
+            "The total number of completed HBONE TLS handshakes, by whether they were full handshakes or resumed an earlier session (unstable)",
+            tls_handshakes.clone(),
+        );
+        let tls_originations = Counter::default();
+        registry.register(
+            "tls_originations",
+            "The total number of TLS sessions originated to destinations outside the mesh for plaintext clients (unstable)",
+            tls_originations.clone(),
+        );
+        let tls_origination_failures = Family::default();
+        registry.register(
+            "tls_origination_failures",
+            "The total number of failed attempts to originate TLS to destinations outside the mesh, by reason, as for tls_handshake_failures (unstable)",
+            tls_origination_failures.clone(),
+        );
+        let pool_stream_reuse = Family::default();
+        registry.register(
+            "pool_stream_reuse",
+            "The total number of HBONE streams sent over an existing pooled connection, by destination service (unstable)",
+            pool_stream_reuse.clone(),
+        );
+        let pool_new_connection = Family::default();
+        registry.register(
+            "pool_new_connection",
+            "The total number of HBONE streams that established a new pooled connection, by destination service (unstable)",
+            pool_new_connection.clone(),
+        );
+        let pool_bypass_connection = Family::default();
+        registry.register(
+            "pool_bypass_connection",
+            "The total number of HBONE streams sent over a dedicated, unpooled connection, by destination service (unstable)",
+            pool_bypass_connection.clone(),
+        );
+        let pool_keepalive_timeouts = Counter::default();
+        registry.register(
+            "pool_keepalive_timeouts",
+            "The total number of pooled HBONE connections closed because the peer did not answer a keepalive PING (unstable)",
+            pool_keepalive_timeouts.clone(),
+        );
+        let pool_floor_connections = Gauge::default();
+        registry.register(
+            "pool_floor_connections",
+            "The number of idle pooled HBONE connections kept open to meet the minimum connections per destination (unstable)",
+            pool_floor_connections.clone(),
+        );
+        let h2_stream_resets = Family::default();
+        registry.register(
+            "h2_stream_resets",
+            "The total number of HBONE streams reset by an HTTP/2 RST_STREAM or GOAWAY frame, by frame and error code (unstable)",
+            h2_stream_resets.clone(),
+        );
+        let hbone_connection_stalls = Counter::default();
+        registry.register(
+            "hbone_connection_stalls",
+            "The total number of times an HBONE connection's sent data went unacknowledged across several retransmissions (unstable)",
+            hbone_connection_stalls.clone(),
+        );
+        let connection_budget_inbound_rejected = Counter::default();
+        registry.register(
+            "connection_budget_inbound_rejected",
+            "The total number of inbound connections closed because MAX_TOTAL_CONNECTIONS was reached (unstable)",
+            connection_budget_inbound_rejected.clone(),
+        );
+        let connection_budget_outbound_rejected = Counter::default();
+        registry.register(
+            "connection_budget_outbound_rejected",
+            "The total number of outbound connections rejected because MAX_TOTAL_CONNECTIONS was reached (unstable)",
+            connection_budget_outbound_rejected.clone(),
+        );
+        let connection_budget_reserved_admitted = Counter::default();
+        registry.register(
+            "connection_budget_reserved_admitted",
+            "The total number of connections admitted using RESERVED_CONNECTIONS after MAX_TOTAL_CONNECTIONS was reached (unstable)",
+            connection_budget_reserved_admitted.clone(),
+        );
+        let destination_service_in_flight = Family::default();
+        registry.register(
+            "outbound_destination_service_in_flight",
+            "The number of outbound connections open to each destination service, when limited by MAX_CONCURRENT_PER_DESTINATION_SERVICE (unstable)",
+            destination_service_in_flight.clone(),
+        );
+        let forward_proxy_failures = Family::default();
+        registry.register(
+            "forward_proxy_failures",
+            "The total number of upstream connections that could not be established through the forward proxy, by reason (unstable)",
+            forward_proxy_failures.clone(),
+        );
 
         Self {
             connection_opens,
@@ -286,16 +1247,253 @@ impl Metrics {
             received_bytes,
             sent_bytes,
             on_demand_dns,
+            hbone_header_bytes,
+            source_binding,
+            tfo_connections,
+            tfo_fallbacks,
+            source_port_reused,
+            source_port_reuse_failed,
+            recording_dropped_bytes,
+            throttled_bytes,
+            throttled_seconds,
+            connects_in_flight,
+            connects_waited,
+            original_source_fallbacks,
+            sniffed_http_requests,
+            subset_requests,
+            consistent_hash_selections,
+            anonymous_source_connections,
+            late_rejections_grace_applied,
+            streams_closed_max_lifetime,
+            connections_drained_by_selector,
+            double_connection,
+            proxy_loops_detected,
+            egress_denied,
+            bypass_connections,
+            loopback_connections,
+            plaintext_inbound_rejected,
+            state_unavailable_fail_open,
+            setup_cancelled_client_gone,
+            warm_connections_active,
+            mirrored_connections,
+            mirror_errors,
+            setup_phase_duration,
+            accept_to_handle_latency,
+            accept_queue_depth,
+            connection_setup_duration,
+            connection_setup_failures,
+            tls_handshake_failures,
+            tls_handshakes,
+            tls_originations,
+            tls_origination_failures,
+            pool_stream_reuse,
+            pool_new_connection,
+            pool_bypass_connection,
+            pool_keepalive_timeouts,
+            pool_floor_connections,
+            hbone_connection_stalls,
+            h2_stream_resets,
+            connection_budget_inbound_rejected,
+            connection_budget_outbound_rejected,
+            connection_budget_reserved_admitted,
+            destination_service_in_flight,
+            forward_proxy_failures,
+            node_labels: false,
+            traffic_scope_label: false,
+            destination_workload_uid_label: false,
+            outbound_bytes_by_source,
+            cost_attribution: false,
         }
     }
+
+    /// with_node_labels labels connection and byte metrics with the node of the source and destination
+    /// workloads, where known. Each node pair is a separate series, so this multiplies the cardinality of these
+    /// metrics by up to the number of nodes the source and destination workloads run on.
+    pub fn with_node_labels(mut self, enabled: bool) -> Self {
+        self.node_labels = enabled;
+        self
+    }
+
+    /// with_traffic_scope_label labels connection and byte metrics with whether the connection stays within the
+    /// mesh. This multiplies the cardinality of these metrics by up to three.
+    pub fn with_traffic_scope_label(mut self, enabled: bool) -> Self {
+        self.traffic_scope_label = enabled;
+        self
+    }
+
+    /// with_destination_workload_uid_label labels connection and byte metrics with the uid of the destination
+    /// workload. Every destination pod is then a separate series, so this is only suitable for small meshes or
+    /// for debugging; the canonical service and revision labels are the bounded alternative.
+    pub fn with_destination_workload_uid_label(mut self, enabled: bool) -> Self {
+        self.destination_workload_uid_label = enabled;
+        self
+    }
+
+    /// with_cost_attribution counts the bytes of outbound connections by the namespace and canonical service of
+    /// their source workload, and whether they leave the mesh.
+    pub fn with_cost_attribution(mut self, enabled: bool) -> Self {
+        self.cost_attribution = enabled;
+        self
+    }
+
+    fn attributed_bytes(
+        &self,
+        tl: &CommonTrafficLabels,
+        traffic_scope: TrafficScope,
+    ) -> Option<(Counter, Counter)> {
+        if !self.cost_attribution || tl.reporter != Reporter::source {
+            return None;
+        }
+        let counter = |direction| {
+            self.outbound_bytes_by_source
+                .get_or_create(&CostAttributionLabels {
+                    source_workload_namespace: tl.source_workload_namespace.clone(),
+                    source_canonical_service: tl.source_canonical_service.clone(),
+                    traffic_scope,
+                    direction,
+                })
+                .clone()
+        };
+        Some((
+            counter(ByteDirection::sent),
+            counter(ByteDirection::received),
+        ))
+    }
+
+    pub fn record_pool_checkout(
+        &self,
+        destination_service: Option<&ServiceDescription>,
+        reused: bool,
+    ) {
+        let labels = DestinationServiceLabels::from(destination_service);
+        let counter = if reused {
+            &self.pool_stream_reuse
+        } else {
+            &self.pool_new_connection
+        };
+        counter.get_or_create(&labels).inc();
+    }
+
+    pub fn record_pool_bypass(&self, destination_service: Option<&ServiceDescription>) {
+        self.pool_bypass_connection
+            .get_or_create(&DestinationServiceLabels::from(destination_service))
+            .inc();
+    }
+
+    pub fn record_forward_proxy_failure(&self, reason: ForwardProxyFailure) {
+        self.forward_proxy_failures
+            .get_or_create(&ForwardProxyFailureLabels { reason })
+            .inc();
+    }
+
+    /// record_setup_failure counts an outbound connection that failed before a destination was selected, so has no
+    /// destination service.
+    pub fn record_setup_failure(&self, err: &proxy::Error) {
+        self.connection_setup_failures
+            .get_or_create(&ConnectionSetupFailureLabels {
+                destination_service: Default::default(),
+                destination_service_namespace: Default::default(),
+                destination_service_name: Default::default(),
+                stage: err.setup_failure_stage(),
+            })
+            .inc();
+    }
+
+    /// header_bytes returns the counters of HBONE header bytes sent and received.
+    pub fn header_bytes(&self) -> (Counter, Counter) {
+        let counter = |direction| {
+            self.hbone_header_bytes
+                .get_or_create(&HeaderBytesLabels { direction })
+                .clone()
+        };
+        (
+            counter(HeaderDirection::sent),
+            counter(HeaderDirection::received),
+        )
+    }
+
+    pub fn record_tls_handshake_failure(&self, reporter: Reporter, reason: TlsFailureReason) {
+        self.tls_handshake_failures
+            .get_or_create(&TlsHandshakeFailureLabels { reporter, reason })
+            .inc();
+    }
+
+    /// record_tls_handshake counts a completed handshake. Handshakes still in progress, as when a client sends
+    /// early data, are not counted; use `tls_handshake_recorder` for those.
+    pub fn record_tls_handshake(&self, reporter: Reporter, conn: &rustls::CommonState) {
+        if let Some(resumed) = tls::session::is_resumed(conn) {
+            self.tls_handshake_recorder(reporter)(resumed);
+        }
+    }
+
+    /// tls_handshake_recorder returns a function that counts a handshake, given whether it resumed a session, for
+    /// handshakes that complete after the connection is handed out.
+    pub fn tls_handshake_recorder(
+        &self,
+        reporter: Reporter,
+    ) -> impl FnOnce(bool) + Send + Unpin + 'static {
+        let handshakes = self.tls_handshakes.clone();
+        move |resumed| {
+            let kind = if resumed {
+                TlsHandshakeKind::resumed
+            } else {
+                TlsHandshakeKind::full
+            };
+            handshakes
+                .get_or_create(&TlsHandshakeLabels { reporter, kind })
+                .inc();
+        }
+    }
+
+    pub fn accept_metrics(&self, listener: AcceptListener) -> AcceptMetrics {
+        let labels = AcceptLabels { listener };
+        AcceptMetrics {
+            latency: self.accept_to_handle_latency.get_or_create(&labels).clone(),
+            queued: self.accept_queue_depth.get_or_create(&labels).clone(),
+        }
+    }
+
+    /// time_setup_phase runs one phase of connection setup in its own span, and records how long it took.
+    /// The span and completion event are at debug level, so only the histogram is paid for by default.
+    pub async fn time_setup_phase<F: Future>(&self, phase: SetupPhase, fut: F) -> F::Output {
+        let start = Instant::now();
+        let res = fut.instrument(debug_span!("setup", ?phase)).await;
+        let elapsed = start.elapsed();
+        debug!(?phase, ?elapsed, "connection setup phase complete");
+        self.setup_phase_duration
+            .get_or_create(&SetupPhaseLabels { phase })
+            .observe(elapsed.as_secs_f64());
+        res
+    }
 }
 
+/// ACCESS_LOG_SCHEMA_VERSION is the version of the structured access log, emitted on the `access_log`
+/// target once per connection when it closes. This target is disabled by default, and can be enabled
+/// with `RUST_LOG=access_log=info`; with `LOG_FORMAT=json` each entry is a single JSON line.
+///
+/// The set of fields for a given version is stable: fields may only be renamed, removed, or change meaning
+/// alongside a version bump. Version 1 contains:
+/// * `time`: the time the connection closed (added by the log formatter)
+/// * `schema_version`, `direction` (`inbound`/`outbound`), `protocol`
+/// * `conn_id`, which also appears on the connection's other log lines and in the connection dump
+/// * `src.addr`, `src.identity`, `dst.addr`, `dst.hbone_addr`, `dst.identity`
+/// * `dst.service`, `dst.service_name`, `dst.service_namespace`
+/// * `dst.workload` and `dst.workload_uid`, identifying the workload the connection was sent to. For a
+///   connection to a service, this is the selected endpoint.
+/// * `bytes_sent`, `bytes_recv`, `duration_ms`
+/// * `response_flags` and `close_reason`, which is unset when the connection closed without error.
+pub const ACCESS_LOG_SCHEMA_VERSION: u64 = 1;
+
 /// ConnectionResult abstracts recording a metric and emitting an access log upon a connection completion
 pub struct ConnectionResult {
+    connection_id: proxy::ConnectionId,
     // Src address and name
     src: (SocketAddr, Option<RichStrng>),
     // Dst address and name
     dst: (SocketAddr, Option<RichStrng>),
+    // Uid of the destination workload, if known. This is always logged, but only labels metrics if
+    // enabled, as by default they aggregate by the workload's canonical service and revision.
+    dst_uid: Option<Strng>,
     hbone_target: Option<SocketAddr>,
     start: Instant,
 
@@ -304,6 +1502,8 @@ pub struct ConnectionResult {
     // efficient representation for the fields we need to log. Ideally, this would even be optional
     // in case logs were disabled.
     tl: CommonTrafficLabels,
+    // Logged even when it is not a metric label
+    traffic_scope: TrafficScope,
     metrics: Arc<Metrics>,
 
     // sent records the number of bytes sent on this connection
@@ -314,6 +1514,22 @@ pub struct ConnectionResult {
     recv: AtomicU64,
     // recv_metric records the number of bytes received on this connection to the aggregated metric counter
     recv_metric: Counter,
+    // The counters of bytes sent and received by the source workload, if they are attributed
+    attributed: Option<(Counter, Counter)>,
+    // Which source address the upstream connection was established with, once known
+    source_binding: OnceLock<SourceBinding>,
+    // The first HTTP request on the connection, if it was sniffed
+    http: OnceLock<HttpRequest>,
+    // The authorization decision that allowed the connection, if it is logged
+    rbac: OnceLock<rbac::RbacDecision>,
+    // How the HBONE stream was reset, if it was
+    h2_reset: Arc<OnceLock<proxy::h2::StreamReset>>,
+    // Operator defined metadata captured from the request; only logged.
+    metadata: ConnectionMetadata,
+    // The recording of the relayed data, if this connection is recorded
+    recording: Option<recording::Recording>,
+    // The bandwidth limits of this connection, if any apply
+    throttle: Option<throttle::Throttle>,
     // Have we recorded yet?
     recorded: bool,
 }
@@ -389,10 +1605,37 @@ impl ConnectionResult {
             dst,
             conn.destination.as_ref().map(|wl| wl.name.clone().into()),
         );
-        let tl = CommonTrafficLabels::from(conn);
+        let dst_uid = conn.destination.as_ref().map(|wl| wl.uid.clone());
+        let connection_id = conn.connection_id;
+        let traffic_scope = conn.traffic_scope();
+        let mut tl = CommonTrafficLabels::from(conn);
+        if !metrics.node_labels {
+            tl = tl.without_nodes();
+        }
+        if metrics.traffic_scope_label {
+            tl = tl.with_optional_label("traffic_scope", Some(traffic_scope.as_str().into()));
+        }
+        if metrics.destination_workload_uid_label {
+            tl = tl.with_optional_label(
+                "destination_workload_uid",
+                dst_uid.as_ref().map(|uid| uid.clone().into()),
+            );
+        }
         metrics.connection_opens.get_or_create(&tl).inc();
 
         let mtls = tl.connection_security_policy == SecurityPolicy::mutual_tls;
+        // Raise the log level for the rest of this connection if it is targeted. Targeted connections are also
+        // recorded, if enabled.
+        let targeted = targeted::apply(&targeted::Connection {
+            source_identity: tl.source_principal.as_ref(),
+            destination_service: tl.destination_service.as_ref().map(|s| s.as_str()),
+            src: src.0.ip(),
+            dst: dst.0.ip(),
+        });
+        let recording = targeted
+            .then(|| recording::start(connection_id, src.0, dst.0))
+            .flatten();
+        let throttle = throttle::start(tl.source_principal.as_ref());
 
         src.1 = src.1.or(tl.source_canonical_service.clone().inner());
         dst.1 = dst.1.or(tl.destination_canonical_service.clone().inner());
@@ -401,23 +1644,27 @@ impl ConnectionResult {
             parent: None,
             tracing::Level::DEBUG,
 
+            conn_id = %connection_id,
             src.addr = %src.0,
             src.workload = src.1.as_deref().map(to_value),
             src.namespace = tl.source_workload_namespace.to_value(),
-            src.identity = tl.source_principal.as_ref().filter(|_| mtls).map(to_value_owned),
+            src.identity = tl.source_principal.as_ref().filter(|_| mtls).map(|id| to_value_owned(id.log_display())),
 
             dst.addr = %dst.0,
             dst.hbone_addr = hbone_target.map(display),
             dst.service = tl.destination_service.to_value(),
+            dst.service_port_name = tl.destination_service_port_name.to_value(),
             dst.workload = dst.1.as_deref().map(to_value),
+            dst.workload_uid = dst_uid.as_deref(),
             dst.namespace = tl.destination_workload_namespace.to_value(),
-            dst.identity = tl.destination_principal.as_ref().filter(|_| mtls).map(to_value_owned),
+            dst.identity = tl.destination_principal.as_ref().filter(|_| mtls).map(|id| to_value_owned(id.log_display())),
 
             direction = if tl.reporter == Reporter::source {
                 "outbound"
             } else {
                 "inbound"
             },
+            traffic_scope = traffic_scope.as_str(),
 
             "connection opened"
         );
@@ -428,32 +1675,155 @@ impl ConnectionResult {
         // add up.
         let sent_metric = metrics.sent_bytes.get_or_create(&tl).clone();
         let recv_metric = metrics.received_bytes.get_or_create(&tl).clone();
+        let attributed = metrics.attributed_bytes(&tl, traffic_scope);
         let sent = atomic::AtomicU64::new(0);
         let recv = atomic::AtomicU64::new(0);
         Self {
+            connection_id,
             src,
             dst,
+            dst_uid,
             hbone_target,
             start,
             tl,
+            traffic_scope,
             metrics,
 
             sent,
             sent_metric,
             recv,
             recv_metric,
+            attributed,
+            source_binding: OnceLock::new(),
+            http: OnceLock::new(),
+            rbac: OnceLock::new(),
+            h2_reset: Default::default(),
+            metadata: ConnectionMetadata::new(),
+            recording,
+            throttle,
             recorded: false,
         }
     }
 
+    /// with_metadata includes the connection's metadata in its access log.
+    pub fn with_metadata(mut self, metadata: ConnectionMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// with_throttle applies `throttle` to this connection, instead of the process-wide bandwidth limits.
+    pub fn with_throttle(mut self, throttle: Option<throttle::Throttle>) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    // Record which source address the upstream connection was established with.
+    pub fn record_source_binding(&self, binding: SourceBinding) {
+        if self.source_binding.set(binding).is_err() {
+            return;
+        }
+        self.metrics
+            .source_binding
+            .get_or_create(&SourceBindingLabels {
+                reporter: self.tl.reporter,
+                binding,
+            })
+            .inc();
+        if binding == SourceBinding::bind_failed {
+            self.metrics.original_source_fallbacks.inc();
+        }
+    }
+
+    // Record the authorization decision that allowed the connection, for the access log.
+    pub fn record_rbac(&self, decision: rbac::RbacDecision) {
+        let _ = self.rbac.set(decision);
+    }
+
+    // h2_reset returns where the connection's HBONE stream records how it was reset; see H2Stream::track_resets.
+    pub(crate) fn h2_reset(&self) -> Arc<OnceLock<proxy::h2::StreamReset>> {
+        self.h2_reset.clone()
+    }
+
+    // Record the HTTP request sniffed from the connection, for the access log and request metrics.
+    pub fn record_http(&self, req: HttpRequest) {
+        let labels = SniffedHttpLabels {
+            request_method: req.method.clone().into(),
+            response_code: req.status.map(|s| s.to_string()).unwrap_or_default().into(),
+        };
+        if self.http.set(req).is_ok() {
+            self.metrics
+                .sniffed_http_requests
+                .get_or_create(&labels)
+                .inc();
+        }
+    }
+
+    // Record that the upstream connection is set up, or failed to be with `err`. Setup is measured from when the
+    // connection started, so includes selecting the destination. If the connection's trace was sampled, it is
+    // attached as an exemplar. Failures are counted by the stage they occurred in.
+    pub fn record_setup(&self, err: Option<&proxy::Error>, trace: &proxy::TraceParent) {
+        match err {
+            None => {
+                let labels = ConnectionSetupLabels {
+                    destination_service: self.tl.destination_service.clone(),
+                    destination_service_namespace: self.tl.destination_service_namespace.clone(),
+                    destination_service_name: self.tl.destination_service_name.clone(),
+                };
+                self.metrics
+                    .connection_setup_duration
+                    .get_or_create(&labels)
+                    .observe(
+                        self.start.elapsed().as_secs_f64(),
+                        TraceExemplar::for_trace(trace),
+                    );
+            }
+            Some(err) => {
+                if let proxy::Error::H2(e) = err {
+                    if let Some(reset) = proxy::h2::StreamReset::from_error(e) {
+                        let _ = self.h2_reset.set(reset);
+                    }
+                }
+                let labels = ConnectionSetupFailureLabels {
+                    destination_service: self.tl.destination_service.clone(),
+                    destination_service_namespace: self.tl.destination_service_namespace.clone(),
+                    destination_service_name: self.tl.destination_service_name.clone(),
+                    stage: err.setup_failure_stage(),
+                };
+                self.metrics
+                    .connection_setup_failures
+                    .get_or_create(&labels)
+                    .inc();
+            }
+        }
+    }
+
     pub fn increment_send(&self, res: u64) {
         self.sent.inc_by(res);
         self.sent_metric.inc_by(res);
+        if let Some((sent, _)) = &self.attributed {
+            sent.inc_by(res);
+        }
     }
 
     pub fn increment_recv(&self, res: u64) {
         self.recv.inc_by(res);
         self.recv_metric.inc_by(res);
+        if let Some((_, recv)) = &self.attributed {
+            recv.inc_by(res);
+        }
+    }
+
+    /// record adds relayed data to the recording of this connection, if it is recorded.
+    pub fn record_data(&self, direction: recording::Direction, data: Bytes) {
+        if let Some(recording) = &self.recording {
+            recording.record(direction, data);
+        }
+    }
+
+    /// throttle accounts for relayed data against the bandwidth limits of this connection, returning how long to
+    /// pause relaying for, if it is over them.
+    pub fn throttle(&self, bytes: u64) -> Option<std::time::Duration> {
+        self.throttle.as_ref().and_then(|t| t.relayed(bytes))
     }
 
     // Record our final result, with more details as a response flag.
@@ -482,6 +1852,16 @@ impl ConnectionResult {
 
         // Unconditionally record the connection was closed
         self.metrics.connection_close.get_or_create(tl).inc();
+        if let Some(reset) = self.h2_reset.get() {
+            self.metrics
+                .h2_stream_resets
+                .get_or_create(&H2StreamResetLabels {
+                    reporter: tl.reporter,
+                    frame: reset.frame,
+                    code: reset.code.into(),
+                })
+                .inc();
+        }
 
         // Unconditionally write out an access log
         let mtls = tl.connection_security_policy == SecurityPolicy::mutual_tls;
@@ -489,35 +1869,83 @@ impl ConnectionResult {
             self.recv.load(Ordering::SeqCst),
             self.sent.load(Ordering::SeqCst),
         );
-        let dur = format!("{}ms", self.start.elapsed().as_millis());
+        let elapsed = self.start.elapsed();
+        let dur = format!("{}ms", elapsed.as_millis());
+
+        event!(
+            target: "access_log",
+            parent: None,
+            tracing::Level::INFO,
+
+            schema_version = ACCESS_LOG_SCHEMA_VERSION,
+            direction = if tl.reporter == Reporter::source {
+                "outbound"
+            } else {
+                "inbound"
+            },
+            protocol = ?tl.request_protocol,
+            traffic_scope = self.traffic_scope.as_str(),
+            conn_id = %self.connection_id,
+
+            src.addr = %self.src.0,
+            src.identity = tl.source_principal.as_ref().filter(|_| mtls).map(|id| display(id.log_display())),
+
+            dst.addr = %self.dst.0,
+            dst.hbone_addr = self.hbone_target.map(display),
+            dst.identity = tl.destination_principal.as_ref().filter(|_| mtls).map(|id| display(id.log_display())),
+            dst.service = tl.destination_service.to_value(),
+            dst.service_name = tl.destination_service_name.to_value(),
+            dst.service_namespace = tl.destination_service_namespace.to_value(),
+            dst.workload = self.dst.1.as_deref().map(to_value),
+            dst.workload_uid = self.dst_uid.as_deref(),
+
+            bytes_sent = if tl.reporter == Reporter::source {bytes.0} else {bytes.1},
+            bytes_recv = if tl.reporter == Reporter::source {bytes.1} else {bytes.0},
+            duration_ms = elapsed.as_millis() as u64,
+            response_flags = ?tl.response_flags,
+            close_reason = res.as_ref().err().map(display),
+
+            "connection closed"
+        );
 
         // We use our own macro to allow setting the level dynamically
         access_log!(
             res,
 
+            conn_id = %self.connection_id,
             src.addr = %self.src.0,
             src.workload = self.src.1.as_deref().map(to_value),
             src.namespace = tl.source_workload_namespace.to_value(),
-            src.identity = tl.source_principal.as_ref().filter(|_| mtls).map(to_value_owned),
+            src.identity = tl.source_principal.as_ref().filter(|_| mtls).map(|id| to_value_owned(id.log_display())),
 
             dst.addr = %self.dst.0,
             dst.hbone_addr = self.hbone_target.map(display),
             dst.service = tl.destination_service.to_value(),
+            dst.service_port_name = tl.destination_service_port_name.to_value(),
             dst.workload = self.dst.1.as_deref().map(to_value),
+            dst.workload_uid = self.dst_uid.as_deref(),
             dst.namespace = tl.destination_workload_namespace.to_value(),
-            dst.identity = tl.destination_principal.as_ref().filter(|_| mtls).map(to_value_owned),
+            dst.identity = tl.destination_principal.as_ref().filter(|_| mtls).map(|id| to_value_owned(id.log_display())),
 
             direction = if tl.reporter == Reporter::source {
                 "outbound"
             } else {
                 "inbound"
             },
+            traffic_scope = self.traffic_scope.as_str(),
 
             // Istio flips the metric for source: https://github.com/istio/istio/issues/32399
             // Unflip for logs
             bytes_sent = if tl.reporter == Reporter::source {bytes.0} else {bytes.1},
             bytes_recv = if tl.reporter == Reporter::source {bytes.1} else {bytes.0},
             duration = dur,
+            source_binding = self.source_binding.get().map(debug),
+            http.method = self.http.get().map(|h| h.method.as_str()),
+            http.path = self.http.get().map(|h| h.path.as_str()),
+            http.status = self.http.get().and_then(|h| h.status),
+            rbac = self.rbac.get().map(display),
+            h2_reset = self.h2_reset.get().map(display),
+            metadata = (!self.metadata.is_empty()).then(|| debug(&self.metadata)),
         );
     }
 }
@@ -538,3 +1966,444 @@ fn to_value<T: AsRef<str>>(t: &T) -> impl Value + '_ {
     let v: &str = t.as_ref();
     v
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::tls::mock::generate_test_certs;
+
+    fn test_certs(id: &Identity) -> tls::WorkloadCertificate {
+        generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        )
+    }
+
+    #[tokio::test]
+    async fn tls_failure_not_tls() {
+        let certs = test_certs(&Identity::default());
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(certs.server_config().unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let _ = stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n")
+                .await;
+            stream
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let err = acceptor.accept(stream).await.unwrap_err();
+        assert_eq!(
+            TlsFailureReason::from_io_error(&err),
+            TlsFailureReason::not_tls
+        );
+        drop(client.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn tls_failure_identity_mismatch() {
+        let id = Identity::default();
+        let certs = test_certs(&id);
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(certs.server_config().unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = acceptor.accept(stream).await;
+        });
+
+        let other = Identity::Spiffe {
+            trust_domain: "cluster.local".into(),
+            namespace: "other".into(),
+            service_account: "other".into(),
+        };
+        let connector = certs.outbound_connector(vec![other]).unwrap();
+        let err = connector
+            .connect(TcpStream::connect(addr).await.unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            TlsFailureReason::from_io_error(&err),
+            TlsFailureReason::identity_mismatch
+        );
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn tls_failure_reasons() {
+        let wrapped = |e: rustls::Error| {
+            TlsFailureReason::from_io_error(&io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+        assert_eq!(
+            wrapped(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::Expired
+            )),
+            TlsFailureReason::certificate_expired
+        );
+        assert_eq!(
+            wrapped(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::UnknownIssuer
+            )),
+            TlsFailureReason::unknown_ca
+        );
+        assert_eq!(
+            wrapped(rustls::Error::NoCertificatesPresented),
+            TlsFailureReason::no_certificate
+        );
+        assert_eq!(
+            TlsFailureReason::from_io_error(&io::ErrorKind::UnexpectedEof.into()),
+            TlsFailureReason::connection_closed
+        );
+        assert_eq!(
+            TlsFailureReason::from_tls_error(&tls::TlsError::SanError(vec![], vec![])),
+            TlsFailureReason::identity_mismatch
+        );
+    }
+
+    #[test]
+    fn setup_failure_stages() {
+        let metrics = crate::test_helpers::helpers::test_proxy_metrics();
+        let failures = [
+            (
+                proxy::Error::NoResolvedAddresses("svc".to_string()),
+                SetupFailureStage::dns_resolution,
+            ),
+            (
+                proxy::Error::NoHealthyUpstream("127.0.0.1:80".parse().unwrap()),
+                SetupFailureStage::endpoint_selection,
+            ),
+            (
+                proxy::Error::ConnectionFailed(io::ErrorKind::ConnectionRefused.into()),
+                SetupFailureStage::tcp_connect,
+            ),
+            (
+                proxy::Error::TlsHandshake(io::ErrorKind::UnexpectedEof.into()),
+                SetupFailureStage::tls_handshake,
+            ),
+            (
+                proxy::Error::HttpStatus(http::StatusCode::SERVICE_UNAVAILABLE),
+                SetupFailureStage::hbone_connect,
+            ),
+            (
+                proxy::Error::AuthorizationPolicyRejection(
+                    crate::rbac::RbacDenial::NoAllowPolicyMatched,
+                ),
+                SetupFailureStage::other,
+            ),
+        ];
+        for (err, _) in &failures {
+            metrics.record_setup_failure(err);
+        }
+        for (err, stage) in failures {
+            let count = metrics
+                .connection_setup_failures
+                .get_or_create(&ConnectionSetupFailureLabels {
+                    destination_service: Default::default(),
+                    destination_service_namespace: Default::default(),
+                    destination_service_name: Default::default(),
+                    stage,
+                })
+                .get();
+            assert_eq!(count, 1, "{err} should be counted once as {stage:?}");
+        }
+    }
+
+    #[test]
+    fn accept_queue() {
+        let mut registry = Registry::default();
+        let metrics = Metrics::new(&mut registry);
+        let accept = metrics.accept_metrics(AcceptListener::inbound);
+        let depth = || {
+            metrics
+                .accept_queue_depth
+                .get_or_create(&AcceptLabels {
+                    listener: AcceptListener::inbound,
+                })
+                .get()
+        };
+
+        let first = accept.accepted();
+        let second = accept.accepted();
+        assert_eq!(depth(), 2);
+        first.started();
+        assert_eq!(depth(), 1);
+        // A connection dropped before its handler ran leaves the queue, but is not timed.
+        drop(second);
+        assert_eq!(depth(), 0);
+
+        let mut text = String::new();
+        prometheus_client::encoding::text::encode(&mut text, &registry).unwrap();
+        assert!(
+            text.contains(r#"accept_to_handle_latency_seconds_count{listener="inbound"} 1"#),
+            "{text}"
+        );
+    }
+
+    #[test]
+    fn node_labels() {
+        let mut registry = Registry::default();
+        let opens = Family::<CommonTrafficLabels, Counter>::default();
+        registry.register("opens", "test", opens.clone());
+        let workload = |node: &str| {
+            Arc::new(Workload {
+                node: node.into(),
+                ..crate::test_helpers::test_default_workload()
+            })
+        };
+        let conn = ConnectionOpen {
+            reporter: Reporter::source,
+            source: Some(workload("node-a")),
+            derived_source: None,
+            destination: Some(workload("")),
+            destination_service: None,
+            destination_service_port_name: None,
+            connection_security_policy: SecurityPolicy::mutual_tls,
+            connection_id: proxy::ConnectionId::next(),
+        };
+        opens
+            .get_or_create(&CommonTrafficLabels::from(conn.clone()))
+            .inc();
+
+        let mut text = String::new();
+        prometheus_client::encoding::text::encode(&mut text, &registry).unwrap();
+        assert!(text.contains(r#"src_node="node-a""#), "{text}");
+        // The destination node is unknown, so rather than being empty, the label is left out.
+        assert!(!text.contains("dst_node"), "{text}");
+
+        let disabled = CommonTrafficLabels::from(conn).without_nodes();
+        assert!(disabled.optional_labels.is_empty());
+    }
+
+    #[test]
+    fn destination_service_port_name() {
+        let mut registry = Registry::default();
+        let opens = Family::<CommonTrafficLabels, Counter>::default();
+        registry.register("opens", "test", opens.clone());
+        for port_name in [Some("http".into()), None] {
+            let conn = ConnectionOpen {
+                reporter: Reporter::destination,
+                source: None,
+                derived_source: None,
+                destination: None,
+                destination_service: None,
+                destination_service_port_name: port_name,
+                connection_security_policy: SecurityPolicy::mutual_tls,
+                connection_id: proxy::ConnectionId::next(),
+            };
+            opens.get_or_create(&CommonTrafficLabels::from(conn)).inc();
+        }
+
+        let mut text = String::new();
+        prometheus_client::encoding::text::encode(&mut text, &registry).unwrap();
+        // Every series has the label, as with the other destination service labels.
+        assert!(
+            text.contains(r#"destination_service_port_name="http""#),
+            "{text}"
+        );
+        assert!(
+            text.contains(r#"destination_service_port_name="unknown""#),
+            "{text}"
+        );
+    }
+
+    #[tokio::test]
+    async fn traffic_scopes() {
+        use crate::state::workload::network_addr;
+
+        let known: std::net::IpAddr = "10.0.0.1".parse().unwrap();
+        let other_known: std::net::IpAddr = "10.0.0.2".parse().unwrap();
+        let unknown: std::net::IpAddr = "192.0.2.1".parse().unwrap();
+        let mut state = crate::state::ProxyState::default();
+        for (ip, name) in [(known, "a"), (other_known, "b")] {
+            state.workloads.insert(
+                Arc::new(Workload {
+                    workload_ips: vec![ip],
+                    uid: format!("cluster1//v1/Pod/default/{name}").into(),
+                    name: name.into(),
+                    ..crate::test_helpers::test_default_workload()
+                }),
+                true,
+            );
+        }
+        let state = crate::state::DemandProxyState::new(
+            Arc::new(std::sync::RwLock::new(state)),
+            None,
+            hickory_resolver::config::ResolverConfig::default(),
+            hickory_resolver::config::ResolverOpts::default(),
+            crate::test_helpers::helpers::test_proxy_metrics(),
+        );
+        let scope = |src, dst| {
+            let state = state.clone();
+            async move {
+                let conn = ConnectionOpen {
+                    reporter: Reporter::source,
+                    source: state.fetch_workload(&network_addr("".into(), src)).await,
+                    derived_source: None,
+                    destination: state.fetch_workload(&network_addr("".into(), dst)).await,
+                    destination_service: None,
+                    destination_service_port_name: None,
+                    connection_security_policy: SecurityPolicy::unknown,
+                    connection_id: proxy::ConnectionId::next(),
+                };
+                conn.traffic_scope()
+            }
+        };
+        assert_eq!(scope(known, other_known).await, TrafficScope::mesh_internal);
+        assert_eq!(scope(known, unknown).await, TrafficScope::egress);
+        assert_eq!(scope(unknown, known).await, TrafficScope::ingress);
+        assert_eq!(scope(unknown, unknown).await, TrafficScope::egress);
+    }
+
+    #[test]
+    fn traffic_scope_label() {
+        let opened = |enabled| {
+            let mut registry = Registry::default();
+            let metrics = Arc::new(Metrics::new(&mut registry).with_traffic_scope_label(enabled));
+            let addr: SocketAddr = "10.0.0.1:80".parse().unwrap();
+            let conn = ConnectionOpen {
+                reporter: Reporter::source,
+                source: None,
+                derived_source: None,
+                destination: None,
+                destination_service: None,
+                destination_service_port_name: None,
+                connection_security_policy: SecurityPolicy::unknown,
+                connection_id: proxy::ConnectionId::next(),
+            };
+            let _result = ConnectionResult::new(addr, addr, None, Instant::now(), conn, metrics);
+            let mut text = String::new();
+            prometheus_client::encoding::text::encode(&mut text, &registry).unwrap();
+            text
+        };
+
+        let text = opened(true);
+        assert!(text.contains(r#"traffic_scope="egress""#), "{text}");
+        // Without the label, connections are still classified in their logs, but no series has it.
+        let text = opened(false);
+        assert!(!text.contains("traffic_scope"), "{text}");
+    }
+
+    #[test]
+    fn destination_workload_uid_label() {
+        let opened = |enabled| {
+            let mut registry = Registry::default();
+            let metrics =
+                Arc::new(Metrics::new(&mut registry).with_destination_workload_uid_label(enabled));
+            let addr: SocketAddr = "10.0.0.1:80".parse().unwrap();
+            let conn = ConnectionOpen {
+                reporter: Reporter::source,
+                source: None,
+                derived_source: None,
+                destination: Some(Arc::new(Workload {
+                    uid: "cluster1//v1/Pod/default/server".into(),
+                    name: "server".into(),
+                    ..crate::test_helpers::test_default_workload()
+                })),
+                destination_service: None,
+                destination_service_port_name: None,
+                connection_security_policy: SecurityPolicy::unknown,
+                connection_id: proxy::ConnectionId::next(),
+            };
+            let _result = ConnectionResult::new(addr, addr, None, Instant::now(), conn, metrics);
+            let mut text = String::new();
+            prometheus_client::encoding::text::encode(&mut text, &registry).unwrap();
+            text
+        };
+
+        let text = opened(true);
+        assert!(
+            text.contains(r#"destination_workload_uid="cluster1//v1/Pod/default/server""#),
+            "{text}"
+        );
+        let text = opened(false);
+        assert!(!text.contains("destination_workload_uid"), "{text}");
+    }
+
+    #[test]
+    fn cost_attribution() {
+        let mut registry = Registry::default();
+        let metrics = Arc::new(Metrics::new(&mut registry).with_cost_attribution(true));
+        let source = Arc::new(Workload {
+            namespace: "team-a".into(),
+            canonical_name: "checkout".into(),
+            ..crate::test_helpers::test_default_workload()
+        });
+        let conn = |reporter| ConnectionOpen {
+            reporter,
+            source: Some(source.clone()),
+            derived_source: None,
+            destination: None,
+            destination_service: None,
+            destination_service_port_name: None,
+            connection_security_policy: SecurityPolicy::unknown,
+            connection_id: proxy::ConnectionId::next(),
+        };
+        let addr: SocketAddr = "10.0.0.1:80".parse().unwrap();
+        let result = ConnectionResult::new(
+            addr,
+            addr,
+            None,
+            Instant::now(),
+            conn(Reporter::source),
+            metrics.clone(),
+        );
+        result.increment_send(100);
+        result.increment_recv(40);
+        result.increment_send(1);
+
+        let labels = |direction| CostAttributionLabels {
+            source_workload_namespace: "team-a".to_string().into(),
+            source_canonical_service: "checkout".to_string().into(),
+            traffic_scope: TrafficScope::egress,
+            direction,
+        };
+        let bytes = |direction| {
+            metrics
+                .outbound_bytes_by_source
+                .get_or_create(&labels(direction))
+                .get()
+        };
+        assert_eq!(bytes(ByteDirection::sent), 101);
+        assert_eq!(bytes(ByteDirection::received), 40);
+        let mut text = String::new();
+        prometheus_client::encoding::text::encode(&mut text, &registry).unwrap();
+        assert!(
+            text.contains(r#"source_workload_namespace="team-a",source_canonical_service="checkout",traffic_scope="egress",direction="sent""#),
+            "{text}"
+        );
+
+        // Inbound connections are not counted, as the ztunnel of their source already counts them.
+        let inbound = ConnectionResult::new(
+            addr,
+            addr,
+            None,
+            Instant::now(),
+            conn(Reporter::destination),
+            metrics.clone(),
+        );
+        inbound.increment_send(1000);
+        assert_eq!(bytes(ByteDirection::sent), 101);
+
+        assert!(inbound.attributed.is_none());
+
+        // Nor is anything when disabled.
+        let result = ConnectionResult::new(
+            addr,
+            addr,
+            None,
+            Instant::now(),
+            conn(Reporter::source),
+            Arc::new(Metrics::new(&mut Registry::default())),
+        );
+        assert!(result.attributed.is_none());
+    }
+}
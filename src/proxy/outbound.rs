@@ -12,32 +12,63 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashSet;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 
 use std::time::{Duration, Instant};
 
+use bytes::Bytes;
 use hyper::header::FORWARDED;
+use rand::Rng;
 
+use tokio::io::{AsyncWriteExt, Interest};
 use tokio::net::TcpStream;
-use tokio::sync::watch;
+use tokio::sync::{mpsc, watch};
 
-use tracing::{debug, error, info, info_span, trace_span, Instrument};
+use tracing::{debug, error, info, info_span, trace_span, warn, Instrument};
 
-use crate::config::ProxyMode;
+use crate::config::{
+    ConnectAuthorityResolution, ProxyMode, TlsOrigination, TunnelOverride, TunnelOverrides,
+    UnknownSourcePolicy, WarmDestination,
+};
 use crate::identity::Identity;
 
-use crate::proxy::metrics::Reporter;
-use crate::proxy::{metrics, pool, ConnectionOpen, ConnectionResult, DerivedWorkload};
-use crate::proxy::{util, Error, ProxyInputs, TraceParent, BAGGAGE_HEADER, TRACEPARENT_HEADER};
+use crate::proxy::connection_manager::OutboundConnectionGuard;
+use crate::proxy::destination_limiter::DestinationPermit;
+use crate::proxy::hops::HopTracker;
+use crate::proxy::metrics::{
+    EgressDeniedLabels, EgressDenyReason, Reporter, SetupPhase, SourceBinding, TlsFailureReason,
+    TlsOriginationFailureLabels, WarmConnectionLabels,
+};
+use crate::proxy::port_affinity::{PortAffinity, PortLease};
+use crate::proxy::{
+    connect_udp, egress, metrics, pool, ConnectionOpen, ConnectionResult, DerivedWorkload,
+};
+use crate::proxy::{
+    util, ConnectionId, Error, ProxyInputs, TraceParent, BAGGAGE_HEADER, HOPS_HEADER,
+    TRACEPARENT_HEADER,
+};
 
 use crate::drain::run_with_drain;
 use crate::drain::DrainWatcher;
 use crate::proxy::h2::H2Stream;
 use crate::state::service::ServiceDescription;
-use crate::state::workload::{address::Address, NetworkAddress, Protocol, Workload};
-use crate::state::ServiceResolutionMode;
-use crate::{assertions, copy, proxy, socket};
+use crate::state::workload::{
+    address::Address, NamespacedHostname, NetworkAddress, Protocol, TrafficClass, Workload,
+};
+use crate::state::{DemandProxyState, ServiceResolutionMode, WorkloadInfo};
+use crate::strng::Strng;
+use crate::{assertions, copy, proxy, socket, strng, tls};
+
+// The number of chunks read from the client that may be queued for a mirror. Each chunk is at most one read buffer.
+const MIRROR_QUEUE_SIZE: usize = 64;
+
+// The initial delay before retrying to warm a destination. This doubles on each failure, up to the refresh interval.
+const WARM_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+// How often a client that already sent data is checked for having closed its connection, during setup.
+const CLIENT_GONE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
 pub struct Outbound {
     pi: Arc<ProxyInputs>,
@@ -51,7 +82,7 @@ impl Outbound {
         let listener = pi
             .socket_factory
             .tcp_bind(pi.cfg.outbound_addr)
-            .map_err(|e| Error::Bind(pi.cfg.outbound_addr, e))?;
+            .map_err(|e| super::bind_error(pi.socket_factory.as_ref(), pi.cfg.outbound_addr, e))?;
         let transparent = super::maybe_set_transparent(&pi, &listener)?;
 
         info!(
@@ -80,26 +111,52 @@ impl Outbound {
             self.enable_orig_src,
             self.pi.socket_factory.clone(),
             self.pi.cert_manager.clone(),
+            self.pi.metrics.clone(),
         );
+        if !self.pi.cfg.warm_destinations.is_empty() {
+            let warmer = OutboundConnection {
+                pi: self.pi.clone(),
+                id: TraceParent::new(),
+                conn_id: ConnectionId::next(),
+                pool: pool.clone(),
+                enable_orig_src: self.enable_orig_src,
+                hbone_port: self.pi.cfg.inbound_addr.port(),
+            };
+            let drain = self.drain.clone();
+            // Warming happens in the background, so it never holds up startup.
+            tokio::spawn(
+                async move {
+                    tokio::select! {
+                        _ = warmer.warm_destinations() => {}
+                        _ = drain.wait_for_drain() => {}
+                    }
+                }
+                .in_current_span(),
+            );
+        }
         let pi = self.pi.clone();
         let accept = |drain: DrainWatcher, force_shutdown: watch::Receiver<()>| {
             async move {
+                let mut current = self.pi.clone();
                 loop {
                     // Asynchronously wait for an inbound socket.
                     let socket = self.listener.accept().await;
+                    ProxyInputs::refresh(&mut current);
                     let start = Instant::now();
                     let drain = drain.clone();
                     let mut force_shutdown = force_shutdown.clone();
                     match socket {
                         Ok((stream, _remote)) => {
                             let mut oc = OutboundConnection {
-                                pi: self.pi.clone(),
-                                id: TraceParent::new(),
+                                pi: current.clone(),
+                                id: TraceParent::new()
+                                    .with_sampling(current.cfg.trace_sampling_percentage),
+                                conn_id: ConnectionId::next(),
                                 pool: pool.clone(),
                                 enable_orig_src: self.enable_orig_src,
                                 hbone_port: self.pi.cfg.inbound_addr.port(),
                             };
-                            let span = info_span!("outbound", id=%oc.id);
+                            let span = info_span!("outbound", id=%oc.id, conn_id=%oc.conn_id);
                             let serve_outbound_connection = (async move {
                                 debug!(component="outbound", "connection started");
                                 // Since this task is spawned, make sure we are guaranteed to terminate
@@ -142,6 +199,7 @@ impl Outbound {
 pub(super) struct OutboundConnection {
     pub(super) pi: Arc<ProxyInputs>,
     pub(super) id: TraceParent,
+    pub(super) conn_id: ConnectionId,
     pub(super) pool: proxy::pool::WorkloadHBONEPool,
     pub(super) enable_orig_src: bool,
     pub(super) hbone_port: u16,
@@ -152,6 +210,7 @@ impl OutboundConnection {
         let source_addr =
             socket::to_canonical(source_stream.peer_addr().expect("must receive peer addr"));
         let dst_addr = socket::orig_dst_addr_or_default(&source_stream);
+        super::mark_accepted(&self.pi.cfg, &source_stream, self.conn_id);
         self.proxy_to(source_stream, source_addr, dst_addr).await;
     }
 
@@ -163,19 +222,59 @@ impl OutboundConnection {
     ) {
         let start = Instant::now();
 
-        let req = match Box::pin(self.build_request(source_addr.ip(), dest_addr)).await {
+        let _budget = match self
+            .pi
+            .connection_manager
+            .try_acquire_budget(source_addr, dest_addr)
+        {
+            Ok(permit) => permit,
+            Err(err) => {
+                super::set_rejection_close(&self.pi.cfg, &source_stream, &err);
+                metrics::log_early_deny(source_addr, dest_addr, Reporter::source, err);
+                return;
+            }
+        };
+        if let Err(err) = self.check_hops(source_addr) {
+            metrics::log_early_deny(source_addr, dest_addr, Reporter::source, err);
+            return;
+        }
+        if self.pi.cfg.bypass_cidrs.contains(dest_addr.ip()) {
+            Box::pin(self.proxy_bypass(source_stream, source_addr, dest_addr, start)).await;
+            return;
+        }
+        if let Err(err) = self.pi.wait_for_state().await {
+            metrics::log_early_deny(source_addr, dest_addr, Reporter::source, err);
+            return;
+        }
+
+        let headers = self.hash_headers(&source_stream, dest_addr).await;
+        let lookup = self.pi.metrics.time_setup_phase(
+            SetupPhase::destination_lookup,
+            self.select_request(source_addr.ip(), dest_addr, true, headers.as_ref()),
+        );
+        let req = match Box::pin(lookup).await {
             Ok(req) => Box::new(req),
             Err(err) => {
+                self.pi.metrics.record_setup_failure(&err);
+                metrics::log_early_deny(source_addr, dest_addr, Reporter::source, err);
+                return;
+            }
+        };
+        if let Err(err) = self.authorize_egress(&req, dest_addr) {
+            super::set_rejection_close(&self.pi.cfg, &source_stream, &err);
+            metrics::log_early_deny(source_addr, dest_addr, Reporter::source, err);
+            return;
+        }
+        let _destination_permit = match self.limit_destination(&req) {
+            Ok(permit) => permit,
+            Err(err) => {
+                super::set_rejection_close(&self.pi.cfg, &source_stream, &err);
                 metrics::log_early_deny(source_addr, dest_addr, Reporter::source, err);
                 return;
             }
         };
         // TODO: should we use the original address or the actual address? Both seems nice!
-        let _conn_guard = self.pi.connection_manager.track_outbound(
-            source_addr,
-            dest_addr,
-            req.actual_destination,
-        );
+        let conn_guard = self.track_outbound(source_addr, dest_addr, &req);
 
         let metrics = self.pi.metrics.clone();
         let hbone_target = req.hbone_target_destination;
@@ -184,103 +283,853 @@ impl OutboundConnection {
             req.actual_destination,
             hbone_target,
             start,
-            Self::conn_metrics_from_request(&req),
+            Self::conn_metrics_from_request(&req, self.conn_id),
             metrics,
         ));
 
-        let res = match req.protocol {
-            Protocol::HBONE => {
-                self.proxy_to_hbone(source_stream, source_addr, &req, &result_tracker)
+        let origination = self.tls_origination(&req);
+        let res = match (req.protocol, origination) {
+            (Protocol::HBONE, _) => {
+                let mirror = self.start_mirror(source_addr, &req);
+                self.proxy_to_hbone(
+                    source_stream,
+                    source_addr,
+                    &req,
+                    mirror,
+                    &result_tracker,
+                    &conn_guard,
+                )
+                .await
+            }
+            (Protocol::TCP, Some(origination)) => {
+                Box::pin(self.proxy_to_tls_origination(
+                    source_stream,
+                    &req,
+                    &origination,
+                    &result_tracker,
+                    &conn_guard,
+                ))
+                .await
+            }
+            // With an egress allowlist, destinations we know nothing about are restricted by SNI.
+            (Protocol::TCP, None)
+                if !self.pi.cfg.egress_sni_allowlist.is_empty()
+                    && req.actual_destination_workload.is_none() =>
+            {
+                Box::pin(self.proxy_to_egress(source_stream, &req, &result_tracker, &conn_guard))
                     .await
             }
-            Protocol::TCP => {
-                self.proxy_to_tcp(source_stream, &req, &result_tracker)
+            (Protocol::TCP, None) => {
+                let mirror = self.start_mirror(source_addr, &req);
+                self.proxy_to_tcp(source_stream, &req, mirror, &result_tracker, &conn_guard)
                     .await
             }
         };
         result_tracker.record(res)
     }
 
+    /// proxy_udp_to tunnels datagrams from `source_addr` to `dest_addr` over HBONE.
+    /// Datagrams are read from `datagrams` until it is closed; replies from the destination are passed to `reply`.
+    pub async fn proxy_udp_to(
+        &mut self,
+        source_addr: SocketAddr,
+        dest_addr: SocketAddr,
+        datagrams: mpsc::Receiver<Bytes>,
+        reply: impl Fn(Bytes),
+    ) {
+        let start = Instant::now();
+
+        let _budget = match self
+            .pi
+            .connection_manager
+            .try_acquire_budget(source_addr, dest_addr)
+        {
+            Ok(permit) => permit,
+            Err(err) => {
+                metrics::log_early_deny(source_addr, dest_addr, Reporter::source, err);
+                return;
+            }
+        };
+        if let Err(err) = self.check_hops(source_addr) {
+            metrics::log_early_deny(source_addr, dest_addr, Reporter::source, err);
+            return;
+        }
+        if let Err(err) = self.pi.wait_for_state().await {
+            metrics::log_early_deny(source_addr, dest_addr, Reporter::source, err);
+            return;
+        }
+
+        let lookup = self.pi.metrics.time_setup_phase(
+            SetupPhase::destination_lookup,
+            self.build_request(source_addr.ip(), dest_addr),
+        );
+        let req = match Box::pin(lookup).await {
+            Ok(req) => Box::new(req),
+            Err(err) => {
+                self.pi.metrics.record_setup_failure(&err);
+                metrics::log_early_deny(source_addr, dest_addr, Reporter::source, err);
+                return;
+            }
+        };
+        if req.protocol != Protocol::HBONE {
+            // Without HBONE there is nothing to tunnel over.
+            metrics::log_early_deny(
+                source_addr,
+                dest_addr,
+                Reporter::source,
+                Error::UnsupportedFeature("UDP to a non-HBONE destination".to_string()),
+            );
+            return;
+        }
+        if let Err(err) = self.authorize_egress(&req, dest_addr) {
+            metrics::log_early_deny(source_addr, dest_addr, Reporter::source, err);
+            return;
+        }
+        let _destination_permit = match self.limit_destination(&req) {
+            Ok(permit) => permit,
+            Err(err) => {
+                metrics::log_early_deny(source_addr, dest_addr, Reporter::source, err);
+                return;
+            }
+        };
+        let conn_guard = self.track_outbound(source_addr, dest_addr, &req);
+
+        let result_tracker = Box::new(ConnectionResult::new(
+            source_addr,
+            req.actual_destination,
+            req.hbone_target_destination,
+            start,
+            Self::conn_metrics_from_request(&req, self.conn_id),
+            self.pi.metrics.clone(),
+        ));
+        let res = async {
+            let (upgraded, binding, handshake) =
+                Box::pin(self.send_hbone_udp_request(source_addr, &req)).await?;
+            result_tracker.record_source_binding(binding);
+            conn_guard.record_handshake(handshake);
+            // Datagrams have no FIN to send, so once drained, the stream is simply reset.
+            tokio::select! {
+                res = connect_udp::relay_channel(upgraded, datagrams, reply, &result_tracker) => res,
+                reason = conn_guard.closing() => Err(reason),
+            }
+        }
+        .await;
+        result_tracker.record(res)
+    }
+
     async fn proxy_to_hbone(
         &mut self,
         stream: TcpStream,
         remote_addr: SocketAddr,
         req: &Request,
+        mirror: Option<mpsc::Sender<Bytes>>,
         connection_stats: &ConnectionResult,
+        conn_guard: &OutboundConnectionGuard,
     ) -> Result<(), Error> {
-        let upgraded = Box::pin(self.send_hbone_request(remote_addr, req)).await?;
-        copy::copy_bidirectional(copy::TcpStreamSplitter(stream), upgraded, connection_stats).await
+        let metrics = self.pi.metrics.clone();
+        let upgraded = unless_client_gone(
+            &stream,
+            &metrics,
+            Box::pin(self.send_hbone_request(remote_addr, req)),
+        )
+        .await;
+        connection_stats.record_setup(upgraded.as_ref().err(), &self.id);
+        if let Err(err) = &upgraded {
+            super::set_rejection_close(&self.pi.cfg, &stream, err);
+        }
+        let (upgraded, binding, handshake) = upgraded?;
+        connection_stats.record_source_binding(binding);
+        conn_guard.record_handshake(handshake);
+        copy::copy_bidirectional_until(
+            copy::TeeSplitter::new(copy::TcpStreamSplitter(stream), mirror),
+            upgraded.track_resets(connection_stats.h2_reset()),
+            connection_stats,
+            self.pi.cfg.force_full_close,
+            conn_guard.closing(),
+        )
+        .await
     }
 
     async fn send_hbone_request(
         &mut self,
         remote_addr: SocketAddr,
         req: &Request,
-    ) -> Result<H2Stream, Error> {
+    ) -> Result<(H2Stream, SourceBinding, Option<tls::HandshakeSummary>), Error> {
+        let request = self
+            .hbone_request(remote_addr, req)
+            .uri(
+                &req.hbone_target_destination
+                    .expect("HBONE must have target")
+                    .to_string(),
+            )
+            .body(())
+            .expect("builder with known status code should not fail");
+        self.send_hbone_request_pooled(remote_addr, req, request)
+            .await
+    }
+
+    // send_hbone_udp_request opens a CONNECT-UDP tunnel to the HBONE target.
+    async fn send_hbone_udp_request(
+        &mut self,
+        remote_addr: SocketAddr,
+        req: &Request,
+    ) -> Result<(H2Stream, SourceBinding, Option<tls::HandshakeSummary>), Error> {
+        let target = req
+            .hbone_target_destination
+            .expect("HBONE must have target");
+        let request = self
+            .hbone_request(remote_addr, req)
+            .uri(format!(
+                "https://{target}{}",
+                connect_udp::target_path(target)
+            ))
+            .header(connect_udp::CAPSULE_PROTOCOL_HEADER, "?1")
+            .extension(h2::ext::Protocol::from_static(
+                connect_udp::CONNECT_UDP_PROTOCOL,
+            ))
+            .body(())
+            .expect("builder with known status code should not fail");
+        self.send_hbone_request_pooled(remote_addr, req, request)
+            .await
+    }
+
+    // hbone_request builds the common parts of an HBONE CONNECT request.
+    fn hbone_request(&self, remote_addr: SocketAddr, req: &Request) -> http::request::Builder {
         let mut f = http_types::proxies::Forwarded::new();
         f.add_for(remote_addr.to_string());
         if let Some(svc) = &req.intended_destination_service {
             f.set_host(svc.hostname.as_str());
         }
 
-        let request = http::Request::builder()
-            .uri(
-                &req.hbone_target_destination
-                    .expect("HBONE must have target")
-                    .to_string(),
-            )
+        http::Request::builder()
             .method(hyper::Method::CONNECT)
             .version(hyper::Version::HTTP_2)
             .header(BAGGAGE_HEADER, baggage(req, self.pi.cfg.cluster_id.clone()))
             .header(FORWARDED, f.value().expect("Forwarded value is infallible"))
             .header(TRACEPARENT_HEADER, self.id.header())
-            .body(())
-            .expect("builder with known status code should not fail");
+            .header(
+                HOPS_HEADER,
+                next_hops(&self.pi.hops, remote_addr).to_string(),
+            )
+    }
 
-        let pool_key = Box::new(pool::WorkloadKey {
-            src_id: req.source.identity(),
-            // Clone here shouldn't be needed ideally, we could just take ownership of Request.
-            // But that
-            dst_id: req.upstream_sans.clone(),
-            src: remote_addr.ip(),
-            dst: req.actual_destination,
-        });
-        let upgraded = Box::pin(self.pool.send_request_pooled(&pool_key, request))
-            .instrument(trace_span!("outbound connect"))
-            .await?;
+    // authorize_egress rejects connections the source workload's egress rules do not allow. The destination is
+    // the service the client addressed, if any, otherwise the address it connected to.
+    fn authorize_egress(&self, req: &Request, dest_addr: SocketAddr) -> Result<(), Error> {
+        let destination = match &req.intended_destination_service {
+            Some(svc) => svc.hostname.to_string(),
+            None => dest_addr.ip().to_string(),
+        };
+        if egress::authorize(
+            req.source.egress_rules.as_deref(),
+            &destination,
+            dest_addr.port(),
+        ) {
+            return Ok(());
+        }
+        self.pi
+            .metrics
+            .egress_denied
+            .get_or_create(&EgressDeniedLabels {
+                reason: EgressDenyReason::egress_rules,
+            })
+            .inc();
+        Err(Error::EgressDenied(destination))
+    }
+
+    // check_hops rejects connections that have already traversed the maximum number of ztunnels.
+    // This happens when a connection we delivered locally is redirected back to us, and indicates a loop.
+    fn check_hops(&self, source_addr: SocketAddr) -> Result<(), Error> {
+        let hops = next_hops(&self.pi.hops, source_addr);
+        if hops > self.pi.cfg.max_proxy_hops {
+            self.pi.metrics.proxy_loops_detected.inc();
+            return Err(Error::ProxyLoopDetected(hops, self.pi.cfg.max_proxy_hops));
+        }
+        Ok(())
+    }
+
+    // limit_destination counts the connection against the limit for its destination service, if one is
+    // configured. The permit must be held for as long as the connection is open.
+    fn limit_destination(&self, req: &Request) -> Result<Option<DestinationPermit>, Error> {
+        let (Some(limit), Some(svc)) = (
+            self.pi.cfg.max_concurrent_per_destination_service,
+            &req.intended_destination_service,
+        ) else {
+            return Ok(None);
+        };
+        let permit = self.pi.destination_limiter.try_acquire(svc, limit)?;
+        Ok(Some(permit))
+    }
+
+    async fn send_hbone_request_pooled(
+        &mut self,
+        remote_addr: SocketAddr,
+        req: &Request,
+        request: http::Request<()>,
+    ) -> Result<(H2Stream, SourceBinding, Option<tls::HandshakeSummary>), Error> {
+        let pool_key = Box::new(pool_key(&self.pi.cfg, remote_addr.ip(), req));
+        let service = req.intended_destination_service.as_ref();
+        let upgraded = if bypasses_pool(&self.pi.cfg.pool_bypass_destinations, req) {
+            Box::pin(self.pool.send_request_unpooled(&pool_key, service, request))
+                .instrument(trace_span!("outbound connect", pooled = false))
+                .await?
+        } else {
+            Box::pin(self.pool.send_request_pooled(&pool_key, service, request))
+                .instrument(trace_span!("outbound connect"))
+                .await?
+        };
         Ok(upgraded)
     }
 
+    // warm_destinations keeps connections open to each configured warm destination, until the proxy shuts down.
+    async fn warm_destinations(&self) {
+        let Some(info) = self.pi.proxy_workload_info.as_deref() else {
+            warn!("warm destinations are only supported by dedicated proxies; ignoring");
+            return;
+        };
+        let warmers = self
+            .pi
+            .cfg
+            .warm_destinations
+            .iter()
+            .map(|dest| self.warm_destination(info, dest));
+        futures::future::join_all(warmers).await;
+    }
+
+    async fn warm_destination(&self, info: &WorkloadInfo, dest: &WarmDestination) {
+        // Refresh well before the pool would evict the connections for being idle.
+        let refresh = self.pi.cfg.pool_unused_release_timeout / 2;
+        let want = usize::from(self.pi.cfg.warm_connections_per_destination);
+        let active = self
+            .pi
+            .metrics
+            .warm_connections_active
+            .get_or_create(&WarmConnectionLabels {
+                destination: dest.to_string(),
+            })
+            .clone();
+        let mut backoff = WARM_INITIAL_BACKOFF;
+        loop {
+            let warmed = self.warm_once(info, dest, want).await;
+            active.set(warmed as i64);
+            let wait = if warmed >= want {
+                backoff = WARM_INITIAL_BACKOFF;
+                refresh
+            } else {
+                let wait = backoff;
+                backoff = (backoff * 2).min(refresh);
+                wait
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // warm_once establishes (or refreshes) connections to up to `want` distinct endpoints of the destination,
+    // returning how many are held. Endpoints are selected exactly as they are for requests from this workload,
+    // so warm connections are only made to endpoints that requests could use.
+    async fn warm_once(&self, info: &WorkloadInfo, dest: &WarmDestination, want: usize) -> usize {
+        let target = {
+            let state = self.pi.state.read();
+            let source = state
+                .workloads
+                .find_info(info)
+                .and_then(|w| w.workload_ips.first().copied());
+            let vip = state
+                .services
+                .get_by_host(&strng::new(&dest.hostname))
+                .into_iter()
+                .flatten()
+                .flat_map(|svc| svc.vips)
+                .find(|vip| vip.network == self.pi.cfg.network)
+                .map(|vip| SocketAddr::new(vip.address, dest.port));
+            source.zip(vip)
+        };
+        let Some((source, target)) = target else {
+            warn!(destination=%dest, "unable to warm connections: source or destination not found");
+            return 0;
+        };
+
+        let mut pool = self.pool.clone();
+        let mut warmed = HashSet::new();
+        // Endpoints are picked at random, so allow some extra attempts to find distinct ones.
+        for _ in 0..want * 2 {
+            if warmed.len() >= want {
+                break;
+            }
+            let req = match Box::pin(self.select_request(source, target, false, None)).await {
+                Ok(req) if req.protocol == Protocol::HBONE => req,
+                Ok(_) => {
+                    warn!(destination=%dest, "unable to warm connections: destination does not use HBONE");
+                    break;
+                }
+                Err(err) => {
+                    warn!(destination=%dest, "unable to warm connections: {err}");
+                    break;
+                }
+            };
+            let key = pool_key(&self.pi.cfg, source, &req);
+            if warmed.contains(&key) {
+                continue;
+            }
+            if let Err(err) = pool.warm(&key).await {
+                warn!(destination=%dest, endpoint=%key.dst, "failed to warm connection: {err}");
+                break;
+            }
+            warmed.insert(key);
+        }
+        debug!(destination=%dest, warmed=warmed.len(), "warmed connections");
+        warmed.len()
+    }
+
     async fn proxy_to_tcp(
         &mut self,
         stream: TcpStream,
         req: &Request,
+        mirror: Option<mpsc::Sender<Bytes>>,
         connection_stats: &ConnectionResult,
+        conn_guard: &OutboundConnectionGuard,
     ) -> Result<(), Error> {
-        // Create a TCP connection to upstream
-        // We do not need spoofing for inbound
+        let outbound = unless_client_gone(
+            &stream,
+            &self.pi.metrics,
+            Box::pin(self.connect_tcp(&stream, req.actual_destination, req, connection_stats)),
+        )
+        .await;
+        connection_stats.record_setup(outbound.as_ref().err(), &self.id);
+        let (outbound, _lease) = outbound?;
+
+        // Proxying data between downstream and upstream
+        copy::copy_bidirectional_until(
+            copy::TeeSplitter::new(copy::TcpStreamSplitter(stream), mirror),
+            copy::TcpStreamSplitter(outbound),
+            connection_stats,
+            self.pi.cfg.force_full_close,
+            conn_guard.closing(),
+        )
+        .await
+    }
+
+    // proxy_bypass passes a connection to a bypass CIDR straight through. These are infrastructure endpoints
+    // outside the mesh, so neither the source nor the destination is looked up, and no policy applies.
+    async fn proxy_bypass(
+        &self,
+        stream: TcpStream,
+        source_addr: SocketAddr,
+        dest_addr: SocketAddr,
+        start: Instant,
+    ) {
+        self.pi.metrics.bypass_connections.inc();
+        let conn_guard = self.pi.connection_manager.track_outbound(
+            source_addr,
+            dest_addr,
+            dest_addr,
+            self.conn_id,
+            None,
+            None,
+        );
+        let result_tracker = ConnectionResult::new(
+            source_addr,
+            dest_addr,
+            None,
+            start,
+            ConnectionOpen {
+                reporter: Reporter::source,
+                source: None,
+                derived_source: None,
+                destination: None,
+                destination_service: None,
+                destination_service_port_name: None,
+                connection_security_policy: metrics::SecurityPolicy::unknown,
+                connection_id: self.conn_id,
+            },
+            self.pi.metrics.clone(),
+        );
+        debug!(%dest_addr, "destination is in a bypass CIDR");
+
         let local = if self.enable_orig_src && self.pi.cfg.proxy_mode != ProxyMode::Shared {
-            super::get_original_src_from_stream(&stream)
+            Some(source_addr.ip())
         } else {
             None
         };
-        let outbound = super::freebind_connect(
-            local,
-            req.actual_destination,
-            self.pi.socket_factory.as_ref(),
-        )
-        .await?;
+        let port_reuse = self.port_reuse();
+        let res = async {
+            let outbound = super::freebind_connect(
+                local,
+                dest_addr,
+                self.pi.cfg.connection_timeout,
+                &super::for_connection(&self.pi, self.conn_id),
+                super::ConnectOptions::from_config(&self.pi.cfg)
+                    .with_port_reuse(port_reuse.map(Arc::as_ref))
+                    .egress(),
+            )
+            .await
+            .map(|(s, _)| s)
+            .map_err(Error::ConnectionFailed);
+            result_tracker.record_setup(outbound.as_ref().err(), &self.id);
+            let outbound = outbound?;
+            let _lease = port_reuse.and_then(|a| a.lease(local, dest_addr, &outbound));
+            copy::copy_bidirectional_until(
+                copy::TcpStreamSplitter(stream),
+                copy::TcpStreamSplitter(outbound),
+                &result_tracker,
+                self.pi.cfg.force_full_close,
+                conn_guard.closing(),
+            )
+            .await
+        };
+        result_tracker.record(res.await)
+    }
 
-        // Proxying data between downstream and upstream
-        copy::copy_bidirectional(
+    // proxy_to_egress sends traffic to a destination outside the mesh, if its TLS SNI is allowlisted.
+    // Rather than trusting the destination address the client chose, we connect to what the SNI resolves to.
+    async fn proxy_to_egress(
+        &mut self,
+        mut stream: TcpStream,
+        req: &Request,
+        connection_stats: &ConnectionResult,
+        conn_guard: &OutboundConnectionGuard,
+    ) -> Result<(), Error> {
+        let deny = |reason, err| {
+            self.pi
+                .metrics
+                .egress_denied
+                .get_or_create(&EgressDeniedLabels { reason })
+                .inc();
+            Err(err)
+        };
+        let timeout = self.pi.cfg.connection_timeout_for(&req.source.namespace);
+        let (hello, sni) =
+            match tokio::time::timeout(timeout, egress::read_client_hello(&mut stream)).await {
+                Ok(Ok(res)) => res,
+                Ok(Err(err)) => return deny(EgressDenyReason::invalid_client_hello, err),
+                Err(_) => {
+                    return deny(
+                        EgressDenyReason::invalid_client_hello,
+                        Error::InvalidClientHello("timed out"),
+                    )
+                }
+            };
+        if !egress::allowed(&self.pi.cfg.egress_sni_allowlist, &sni) {
+            let err = Error::EgressDenied(sni);
+            super::set_rejection_close(&self.pi.cfg, &stream, &err);
+            return deny(EgressDenyReason::not_allowed, err);
+        }
+
+        let connect = async {
+            let destinations = self
+                .egress_destinations(&sni, req.actual_destination.port())
+                .await?;
+            debug!(%sni, ?destinations, "egress allowed");
+            let (this, stream) = (&*self, &stream);
+            super::happy_eyeballs::connect(
+                &sni,
+                destinations,
+                super::happy_eyeballs::ATTEMPT_DELAY,
+                |destination| {
+                    Box::pin(this.connect_tcp(stream, destination, req, connection_stats))
+                },
+            )
+            .await
+        };
+        let outbound = connect.await;
+        connection_stats.record_setup(outbound.as_ref().err(), &self.id);
+        let (mut outbound, _lease) = outbound?;
+        outbound.write_all(&hello).await?;
+        connection_stats.increment_recv(hello.len() as u64);
+
+        copy::copy_bidirectional_until(
             copy::TcpStreamSplitter(stream),
             copy::TcpStreamSplitter(outbound),
             connection_stats,
+            self.pi.cfg.force_full_close,
+            conn_guard.closing(),
+        )
+        .await
+    }
+
+    // egress_destinations resolves the hostname of an egress destination to the addresses to connect to, in the
+    // order they should be tried.
+    async fn egress_destinations(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        let ips = match self.pi.cfg.connect_authority_resolution {
+            ConnectAuthorityResolution::Disabled => {
+                vec![self.pi.state.resolve_hostname(host).await?]
+            }
+            resolution => {
+                let ips = self.pi.state.resolve_hostname_all(host).await?;
+                let mut ips =
+                    super::happy_eyeballs::order(ips, self.pi.cfg.connect_authority_ip_family);
+                if resolution == ConnectAuthorityResolution::First {
+                    ips.truncate(1);
+                }
+                if ips.is_empty() {
+                    // Every address was of a family we do not use.
+                    return Err(Error::EmptyResolvedAddresses(host.to_string()));
+                }
+                ips
+            }
+        };
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    // tls_origination returns how to originate TLS for a plaintext connection, if the client addressed a
+    // destination outside the mesh that is configured for it.
+    fn tls_origination(&self, req: &Request) -> Option<TlsOrigination> {
+        let svc = req.intended_destination_service.as_ref()?;
+        self.pi
+            .cfg
+            .egress_tls_origination
+            .iter()
+            .find(|o| svc.hostname.eq_ignore_ascii_case(&o.hostname))
+            .cloned()
+    }
+
+    // proxy_to_tls_origination relays a plaintext client to a destination outside the mesh over a TLS session we
+    // originate, so the client does not have to speak TLS itself.
+    async fn proxy_to_tls_origination(
+        &mut self,
+        stream: TcpStream,
+        req: &Request,
+        origination: &TlsOrigination,
+        connection_stats: &ConnectionResult,
+        conn_guard: &OutboundConnectionGuard,
+    ) -> Result<(), Error> {
+        let destination = SocketAddr::new(
+            req.actual_destination.ip(),
+            origination.port.unwrap_or(req.actual_destination.port()),
+        );
+        let connect = async {
+            let (outbound, lease) =
+                Box::pin(self.connect_tcp(&stream, destination, req, connection_stats)).await?;
+            let handshake = tls::originate(outbound, origination.ca.as_deref(), &origination.sni);
+            let tls = self
+                .pi
+                .metrics
+                .time_setup_phase(SetupPhase::tls_handshake, handshake)
+                .await
+                .map_err(|e| self.tls_origination_failed(&origination.sni, e))?;
+            self.pi.metrics.tls_originations.inc();
+            debug!(sni = origination.sni, %destination, "originated tls");
+            Ok((tls, lease))
+        };
+        let outbound = unless_client_gone(&stream, &self.pi.metrics, connect).await;
+        connection_stats.record_setup(outbound.as_ref().err(), &self.id);
+        let (outbound, _lease) = outbound?;
+        conn_guard.record_handshake(tls::HandshakeSummary::from_connection(outbound.get_ref().1));
+
+        copy::copy_bidirectional_until(
+            copy::TcpStreamSplitter(stream),
+            outbound,
+            connection_stats,
+            self.pi.cfg.force_full_close,
+            conn_guard.closing(),
         )
         .await
     }
 
-    fn conn_metrics_from_request(req: &Request) -> ConnectionOpen {
+    // tls_origination_failed counts a failure to originate TLS, and returns the error for it. Invalid server
+    // certificates get an error of their own, as they are usually a CA bundle or SNI misconfiguration.
+    fn tls_origination_failed(&self, sni: &str, err: tls::OriginationError) -> Error {
+        let rustls_err = match &err {
+            tls::OriginationError::Handshake(e) => {
+                e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>())
+            }
+            tls::OriginationError::Config(_) => None,
+        };
+        let reason = match &err {
+            tls::OriginationError::Handshake(e) => TlsFailureReason::from_io_error(e),
+            tls::OriginationError::Config(_) => TlsFailureReason::other,
+        };
+        self.pi
+            .metrics
+            .tls_origination_failures
+            .get_or_create(&TlsOriginationFailureLabels { reason })
+            .inc();
+        match rustls_err {
+            Some(rustls::Error::InvalidCertificate(cert_err)) => {
+                Error::TlsOriginationCertificate(sni.to_string(), cert_err.clone())
+            }
+            _ => Error::TlsOrigination(sni.to_string(), err),
+        }
+    }
+
+    // start_mirror starts mirroring the connection, if the destination service has a mirror and this connection
+    // is selected for it. The returned sender must be given to a TeeSplitter for the client's side of the connection.
+    // Mirroring is best effort: failures are counted, but never affect the connection being mirrored.
+    fn start_mirror(&self, source_addr: SocketAddr, req: &Request) -> Option<mpsc::Sender<Bytes>> {
+        let svc = req.intended_destination_service.as_ref()?;
+        let mirror = self
+            .pi
+            .state
+            .read()
+            .services
+            .get_by_namespaced_host(&NamespacedHostname {
+                namespace: svc.namespace.clone(),
+                hostname: svc.hostname.clone(),
+            })?
+            .mirror
+            .clone()?;
+        if !rand::thread_rng().gen_ratio(u32::from(mirror.percentage.min(100)), 100) {
+            return None;
+        }
+
+        let (tx, rx) = mpsc::channel(MIRROR_QUEUE_SIZE);
+        let mut oc = OutboundConnection {
+            pi: self.pi.clone(),
+            id: TraceParent::new(),
+            // The mirror is logged as part of the connection it copies.
+            conn_id: self.conn_id,
+            pool: self.pool.clone(),
+            enable_orig_src: self.enable_orig_src,
+            hbone_port: self.hbone_port,
+        };
+        let metrics = self.pi.metrics.clone();
+        tokio::spawn(
+            async move {
+                match oc.mirror_to(source_addr, mirror.destination, rx).await {
+                    Ok(()) => metrics.mirrored_connections.inc(),
+                    Err(err) => {
+                        debug!(destination=%mirror.destination, "mirror failed: {err}");
+                        metrics.mirror_errors.inc()
+                    }
+                };
+            }
+            .in_current_span(),
+        );
+        Some(tx)
+    }
+
+    async fn mirror_to(
+        &mut self,
+        source_addr: SocketAddr,
+        destination: SocketAddr,
+        chunks: mpsc::Receiver<Bytes>,
+    ) -> Result<(), Error> {
+        let req = Box::pin(self.build_request(source_addr.ip(), destination)).await?;
+        match req.protocol {
+            Protocol::HBONE => {
+                let (upgraded, _, _) = Box::pin(self.send_hbone_request(source_addr, &req)).await?;
+                copy::mirror(upgraded, chunks).await
+            }
+            Protocol::TCP => {
+                // The mirror is not visible to the destination, so there is no need to spoof the source.
+                let (stream, _) = super::freebind_connect(
+                    None,
+                    req.actual_destination,
+                    self.pi.cfg.connection_timeout_for(&req.source.namespace),
+                    &super::for_connection(&self.pi, self.conn_id),
+                    super::ConnectOptions::from_config(&self.pi.cfg).egress(),
+                )
+                .await?;
+                copy::mirror(copy::TcpStreamSplitter(stream), chunks).await
+            }
+        }
+    }
+
+    // connect_tcp creates a TCP connection to upstream on behalf of the downstream stream.
+    async fn connect_tcp(
+        &self,
+        stream: &TcpStream,
+        destination: SocketAddr,
+        req: &Request,
+        connection_stats: &ConnectionResult,
+    ) -> Result<(TcpStream, Option<PortLease>), Error> {
+        // We do not need spoofing for inbound
+        let local = if self.enable_orig_src && self.pi.cfg.proxy_mode != ProxyMode::Shared {
+            super::get_original_src_from_stream(stream).map(|peer| {
+                super::original_source_ip(&self.pi.cfg, Some(&req.source), peer, destination.ip())
+            })
+        } else {
+            None
+        };
+        let connect_timeout = self.pi.cfg.connection_timeout_for(&req.source.namespace);
+        // Only egress, to destinations outside the mesh, goes through the forward proxy.
+        let forward_proxy = self
+            .pi
+            .cfg
+            .forward_proxy
+            .as_ref()
+            .filter(|_| req.actual_destination_workload.is_none());
+        // Connections through the forward proxy are to the proxy, so there is no port to reuse.
+        let port_reuse = self.port_reuse().filter(|_| forward_proxy.is_none());
+        let connect = async {
+            match forward_proxy {
+                // The proxy opens the connection to the destination, so the original source cannot be kept.
+                Some(proxy) => super::forward_proxy::connect(
+                    proxy,
+                    destination,
+                    connect_timeout,
+                    self.pi.socket_factory.as_ref(),
+                    &self.pi.state,
+                    &self.pi.metrics,
+                )
+                .await
+                .map(|s| {
+                    let binding = match local {
+                        Some(_) => SourceBinding::not_configured,
+                        None => SourceBinding::none,
+                    };
+                    (s, binding)
+                }),
+                None => Ok(super::freebind_connect(
+                    local,
+                    destination,
+                    connect_timeout,
+                    &super::for_connection(&self.pi, self.conn_id),
+                    super::ConnectOptions::from_config(&self.pi.cfg)
+                        .with_port_reuse(port_reuse.map(Arc::as_ref))
+                        .egress(),
+                )
+                .await
+                .map_err(Error::ConnectionFailed)?),
+            }
+        };
+        let (outbound, binding) = self
+            .pi
+            .metrics
+            .time_setup_phase(SetupPhase::tcp_connect, connect)
+            .await?;
+        connection_stats.record_source_binding(binding);
+        let class = TrafficClass::for_connection(
+            Some(req.source.as_ref()),
+            req.actual_destination_workload.as_deref(),
+        );
+        super::set_traffic_class(self.pi.socket_factory.as_ref(), class, &[stream, &outbound]);
+        let lease = port_reuse.and_then(|a| a.lease(local, destination, &outbound));
+        Ok((outbound, lease))
+    }
+
+    // port_reuse returns the source ports to reuse for passthrough connections, if enabled.
+    fn port_reuse(&self) -> Option<&Arc<PortAffinity>> {
+        self.pi
+            .cfg
+            .outbound_port_reuse
+            .then_some(&self.pi.port_affinity)
+    }
+
+    // track_outbound tracks the connection made for `req` with the connection manager, along with the source
+    // identity and service it can be drained by.
+    fn track_outbound(
+        &self,
+        source_addr: SocketAddr,
+        dest_addr: SocketAddr,
+        req: &Request,
+    ) -> OutboundConnectionGuard {
+        self.pi.connection_manager.track_outbound(
+            source_addr,
+            dest_addr,
+            req.actual_destination,
+            self.conn_id,
+            (!req.anonymous_source).then(|| req.source.identity()),
+            req.intended_destination_service
+                .as_ref()
+                .map(|svc| svc.hostname.to_string()),
+        )
+    }
+
+    fn conn_metrics_from_request(req: &Request, connection_id: ConnectionId) -> ConnectionOpen {
         let derived_source = if req.protocol == Protocol::HBONE {
             Some(DerivedWorkload {
                 // We are going to do mTLS, so report our identity
@@ -293,7 +1142,7 @@ impl OutboundConnection {
         ConnectionOpen {
             reporter: Reporter::source,
             derived_source,
-            source: Some(req.source.clone()),
+            source: (!req.anonymous_source).then(|| req.source.clone()),
             destination: req.actual_destination_workload.clone(),
             connection_security_policy: if req.protocol == Protocol::HBONE {
                 metrics::SecurityPolicy::mutual_tls
@@ -301,9 +1150,39 @@ impl OutboundConnection {
                 metrics::SecurityPolicy::unknown
             },
             destination_service: req.intended_destination_service.clone(),
+            destination_service_port_name: None,
+            connection_id,
         }
     }
 
+    // hash_headers returns the headers of the connection's first HTTP request, if the destination is a service
+    // that selects its endpoint by hashing one of them. They are only peeked, so are still sent upstream.
+    async fn hash_headers(
+        &self,
+        stream: &TcpStream,
+        dest_addr: SocketAddr,
+    ) -> Option<http::HeaderMap> {
+        let hashes_header = self
+            .pi
+            .state
+            .read()
+            .services
+            .get_by_vip(&NetworkAddress {
+                network: self.pi.cfg.network.clone(),
+                address: dest_addr.ip(),
+            })
+            .is_some_and(|svc| {
+                svc.load_balancer
+                    .as_ref()
+                    .and_then(|lb| lb.consistent_hash.as_ref())
+                    .is_some_and(|hash| hash.header().is_some())
+            });
+        if !hashes_header {
+            return None;
+        }
+        super::sniff::sniff_headers(stream, self.pi.cfg.passthrough_sniff_timeout).await
+    }
+
     // build_request computes all information about the request we should send
     // TODO: Do we want a single lock for source and upstream...?
     async fn build_request(
@@ -311,155 +1190,490 @@ impl OutboundConnection {
         downstream: IpAddr,
         target: SocketAddr,
     ) -> Result<Request, Error> {
-        let state = &self.pi.state;
+        self.select_request(downstream, target, true, None).await
+    }
+
+    // select_request is build_request, but only counts the endpoint selection in the metrics if
+    // `record_selection` is set. `headers` are those of the connection's first HTTP request, for services that
+    // select the endpoint by hashing one of them.
+    async fn select_request(
+        &self,
+        downstream: IpAddr,
+        target: SocketAddr,
+        record_selection: bool,
+        headers: Option<&http::HeaderMap>,
+    ) -> Result<Request, Error> {
         // First find the source workload of this traffic. If we don't know where the request is from
-        // we will reject it.
-        let source_workload = self.fetch_source_workload(downstream).await?;
+        // we will reject it, unless configured to allow these as anonymous.
+        let source_workload = match self.fetch_source_workload(downstream).await {
+            Ok(wl) => wl,
+            Err(Error::UnknownSource(_))
+                if self.pi.cfg.unknown_source_policy == UnknownSourcePolicy::AllowAnonymous =>
+            {
+                return self
+                    .build_anonymous_request(downstream, target, headers)
+                    .await;
+            }
+            Err(e) => return Err(e),
+        };
+
+        // If the service has no healthy endpoints, we may wait for one to appear, such as when it scales up
+        // from zero. Subscribe before building the request, so an endpoint added in between is not missed.
+        // Without a wait, there is nothing to subscribe to.
+        let wait = self.pi.cfg.wait_for_endpoints_timeout;
+        let mut updates = (!wait.is_zero()).then(|| self.pi.state.subscribe_services());
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            let res = build_workload_request(
+                &self.pi.state,
+                &self.pi.cfg.network,
+                self.hbone_port,
+                &self.pi.cfg.tunnel_overrides,
+                source_workload.clone(),
+                downstream,
+                target,
+                record_selection,
+                headers,
+            )
+            .await;
+            match (res, updates.as_mut()) {
+                (Err(Error::NoHealthyUpstream(_)), Some(updates)) => {
+                    match tokio::time::timeout_at(deadline, updates.changed()).await {
+                        Ok(Ok(())) => {
+                            debug!(%target, "services changed, retrying endpoint selection")
+                        }
+                        _ => return Err(Error::NoHealthyUpstream(target)),
+                    }
+                }
+                (res, _) => return res,
+            }
+        }
+    }
+
+    // build_anonymous_request computes the request for a source we could not identify.
+    // Without an identity we cannot originate HBONE, so the request is always sent as plaintext TCP.
+    // Waypoints are only reachable over HBONE; rather than bypass them, such destinations are rejected.
+    async fn build_anonymous_request(
+        &self,
+        downstream: IpAddr,
+        target: SocketAddr,
+        headers: Option<&http::HeaderMap>,
+    ) -> Result<Request, Error> {
+        let state = &self.pi.state;
+        let source_workload = Arc::new(anonymous_workload(downstream, self.pi.cfg.network.clone()));
 
-        // If this is to-service traffic check for a service waypoint
-        // Capture result of whether this is svc addressed
-        let svc_addressed = if let Some(Address::Service(target_service)) = state
+        let svc_addressed = match state
             .fetch_address(&NetworkAddress {
                 network: self.pi.cfg.network.clone(),
                 address: target.ip(),
             })
             .await
         {
-            // if we have a waypoint for this svc, use it; otherwise route traffic normally
-            if let Some(waypoint) = state
-                .fetch_service_waypoint(&target_service, &source_workload)
-                .await?
-            {
-                let upstream_sans = waypoint.workload_and_services_san();
-                let actual_destination = waypoint.workload_socket_addr();
-                debug!("built request to service waypoint proxy");
-                return Ok(Request {
-                    protocol: Protocol::HBONE,
-                    source: source_workload,
-                    hbone_target_destination: Some(target),
-                    actual_destination_workload: Some(waypoint.workload),
-                    intended_destination_service: Some(ServiceDescription::from(&*target_service)),
-                    actual_destination,
-                    upstream_sans,
-                });
+            Some(Address::Service(svc)) if svc.waypoint.is_some() => {
+                return Err(Error::AnonymousSourceToWaypoint(downstream, target));
             }
-            // this was service addressed but we did not find a waypoint
-            true
-        } else {
-            // this wasn't service addressed
-            false
+            Some(Address::Service(_)) => true,
+            _ => false,
         };
-
-        let Some(us) = state
+        let us = state
             .fetch_upstream(
                 source_workload.network.clone(),
                 &source_workload,
                 target,
                 ServiceResolutionMode::Standard,
+                true,
+                headers,
             )
-            .await?
-        else {
-            if svc_addressed {
-                return Err(Error::NoHealthyUpstream(target));
+            .await?;
+        let (actual_destination_workload, intended_destination_service, actual_destination) =
+            match us {
+                Some(us) => {
+                    if state
+                        .fetch_workload_waypoint(&us.workload, &source_workload)
+                        .await?
+                        .is_some()
+                    {
+                        return Err(Error::AnonymousSourceToWaypoint(downstream, target));
+                    }
+                    let actual_destination = us.workload_socket_addr();
+                    (
+                        Some(us.workload),
+                        us.destination_service,
+                        actual_destination,
+                    )
+                }
+                None if svc_addressed => return Err(Error::NoHealthyUpstream(target)),
+                None => (None, None, target),
+            };
+
+        info!(source=%downstream, destination=%actual_destination, "allowing connection from unknown source as anonymous");
+        self.pi.metrics.anonymous_source_connections.inc();
+        Ok(Request {
+            protocol: Protocol::TCP,
+            source: source_workload,
+            anonymous_source: true,
+            hbone_target_destination: None,
+            actual_destination_workload,
+            intended_destination_service,
+            actual_destination,
+            upstream_sans: vec![],
+            via_waypoint: false,
+        })
+    }
+
+    async fn fetch_source_workload(&self, downstream: IpAddr) -> Result<Arc<Workload>, Error> {
+        let downstream_network_addr = NetworkAddress {
+            network: self.pi.cfg.network.clone(),
+            address: downstream,
+        };
+
+        let source_workload = match self
+            .pi
+            .state
+            .wait_for_workload(&downstream_network_addr, Duration::from_secs(5))
+            .await
+        {
+            Some(wl) => wl,
+            None => {
+                return Err(Error::UnknownSource(downstream));
             }
-            debug!("built request as passthrough; no upstream found");
+        };
+        if let Some(ref wl_info) = self.pi.proxy_workload_info {
+            // make sure that the workload we fetched matches the workload info we got over ZDS.
+            if !wl_info.matches(&source_workload) {
+                // The workload info has no trust domain; it is only compared within one.
+                let expected = Identity::from_parts(
+                    source_workload.trust_domain.clone(),
+                    wl_info.namespace.as_str().into(),
+                    wl_info.service_account.as_str().into(),
+                );
+                return Err(Error::MismatchedSource(
+                    downstream,
+                    source_workload.identity(),
+                    expected,
+                ));
+            }
+        }
+        Ok(source_workload)
+    }
+}
+
+// build_workload_request computes the request for traffic from a known source workload to target: whether it goes
+// through a waypoint, which endpoint is selected, and the identities we expect along the way. It only reads state.
+async fn build_workload_request(
+    state: &DemandProxyState,
+    network: &Strng,
+    hbone_port: u16,
+    tunnel_overrides: &TunnelOverrides,
+    source_workload: Arc<Workload>,
+    downstream: IpAddr,
+    target: SocketAddr,
+    record_selection: bool,
+    headers: Option<&http::HeaderMap>,
+) -> Result<Request, Error> {
+    // If this is to-service traffic check for a service waypoint
+    // Capture result of whether this is svc addressed
+    let svc_addressed = if let Some(Address::Service(target_service)) = state
+        .fetch_address(&NetworkAddress {
+            network: network.clone(),
+            address: target.ip(),
+        })
+        .await
+    {
+        // if we have a waypoint for this svc, use it; otherwise route traffic normally
+        if let Some(waypoint) = state
+            .fetch_service_waypoint(&target_service, &source_workload)
+            .await?
+        {
+            let upstream_sans = waypoint.workload_and_services_san();
+            let actual_destination = waypoint.workload_socket_addr();
+            debug!("built request to service waypoint proxy");
             return Ok(Request {
-                protocol: Protocol::TCP,
+                protocol: Protocol::HBONE,
                 source: source_workload,
-                hbone_target_destination: None,
-                actual_destination_workload: None,
-                intended_destination_service: None,
-                actual_destination: target,
-                upstream_sans: vec![],
+                anonymous_source: false,
+                hbone_target_destination: Some(target),
+                actual_destination_workload: Some(waypoint.workload),
+                intended_destination_service: Some(ServiceDescription::from(&*target_service)),
+                actual_destination,
+                upstream_sans,
+                via_waypoint: true,
             });
-        };
+        }
+        // this was service addressed but we did not find a waypoint
+        true
+    } else {
+        // this wasn't service addressed
+        false
+    };
 
-        let from_waypoint = proxy::check_from_waypoint(
-            state,
-            &us.workload,
-            Some(&source_workload.identity()),
-            &downstream,
+    let Some(us) = state
+        .fetch_upstream(
+            source_workload.network.clone(),
+            &source_workload,
+            target,
+            ServiceResolutionMode::Standard,
+            record_selection,
+            headers,
         )
-        .await;
+        .await?
+    else {
+        if svc_addressed {
+            return Err(Error::NoHealthyUpstream(target));
+        }
+        debug!("built request as passthrough; no upstream found");
+        return Ok(Request {
+            protocol: Protocol::TCP,
+            source: source_workload,
+            anonymous_source: false,
+            hbone_target_destination: None,
+            actual_destination_workload: None,
+            intended_destination_service: None,
+            actual_destination: target,
+            upstream_sans: vec![],
+            via_waypoint: false,
+        });
+    };
 
-        // Check if we need to go through a workload addressed waypoint.
-        // Don't traverse waypoint twice if the source is sandwich-outbound.
-        // Don't traverse waypoint if traffic was addressed to a service (handled before)
-        if !from_waypoint && !svc_addressed {
-            // For case upstream server has enabled waypoint
-            let waypoint = state
-                .fetch_workload_waypoint(&us.workload, &source_workload)
-                .await?;
-            if let Some(waypoint) = waypoint {
-                let actual_destination = waypoint.workload_socket_addr();
-                let upstream_sans = waypoint.workload_and_services_san();
-                debug!("built request to workload waypoint proxy");
-                return Ok(Request {
-                    // Always use HBONE here
-                    protocol: Protocol::HBONE,
-                    source: source_workload,
-                    // Use the original VIP, not translated
-                    hbone_target_destination: Some(target),
-                    actual_destination_workload: Some(waypoint.workload),
-                    intended_destination_service: us.destination_service.clone(),
-                    actual_destination,
-                    upstream_sans,
-                });
-            }
-            // Workload doesn't have a waypoint; send directly
+    let from_waypoint = proxy::check_from_waypoint(
+        state,
+        &us.workload,
+        Some(&source_workload.identity()),
+        &downstream,
+    )
+    .await;
+
+    // Check if we need to go through a workload addressed waypoint.
+    // Don't traverse waypoint twice if the source is sandwich-outbound.
+    // Don't traverse waypoint if traffic was addressed to a service (handled before)
+    if !from_waypoint && !svc_addressed {
+        // For case upstream server has enabled waypoint
+        let waypoint = state
+            .fetch_workload_waypoint(&us.workload, &source_workload)
+            .await?;
+        if let Some(waypoint) = waypoint {
+            let actual_destination = waypoint.workload_socket_addr();
+            let upstream_sans = waypoint.workload_and_services_san();
+            debug!(
+                target_port = us.port,
+                "built request to workload waypoint proxy"
+            );
+            return Ok(Request {
+                // Always use HBONE here
+                protocol: Protocol::HBONE,
+                source: source_workload,
+                anonymous_source: false,
+                // Use the original VIP, not translated
+                hbone_target_destination: Some(target),
+                actual_destination_workload: Some(waypoint.workload),
+                intended_destination_service: us.destination_service.clone(),
+                actual_destination,
+                upstream_sans,
+                via_waypoint: true,
+            });
+        }
+        // Workload doesn't have a waypoint; send directly
+    }
+
+    let protocol = match tunnel_overrides.override_for(&us.workload.namespace, &us.workload.name) {
+        Some(TunnelOverride::Passthrough) => Protocol::TCP,
+        Some(TunnelOverride::Hbone) if us.workload.protocol != Protocol::HBONE => {
+            return Err(Error::HboneRequired(us.workload.uid.clone()));
         }
+        _ => us.workload.protocol,
+    };
+
+    // only change the port if we're sending HBONE
+    let actual_destination = match protocol {
+        Protocol::HBONE => SocketAddr::from((us.selected_workload_ip, hbone_port)),
+        Protocol::TCP => us.workload_socket_addr(),
+    };
+    let hbone_target_destination = match protocol {
+        Protocol::HBONE => Some(us.workload_socket_addr()),
+        Protocol::TCP => None,
+    };
+
+    // For case no waypoint for both side and direct to remote node proxy
+    let upstream_sans = us.workload_and_services_san();
+    debug!("built request to workload");
+    Ok(Request {
+        protocol,
+        source: source_workload,
+        anonymous_source: false,
+        hbone_target_destination,
+        actual_destination_workload: Some(us.workload.clone()),
+        intended_destination_service: us.destination_service.clone(),
+        actual_destination,
+        upstream_sans,
+        via_waypoint: false,
+    })
+}
+
+/// EffectiveRoute describes how a connection from a workload to a destination would be sent, as computed by
+/// effective_route.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EffectiveRoute {
+    pub source_workload: Strng,
+    pub source_identity: Identity,
+    // HBONE, or TCP if the connection would be passed through as plaintext.
+    pub protocol: Protocol,
+    pub via_waypoint: bool,
+    // The workload we would connect to; when via a waypoint, this is the waypoint.
+    pub destination_workload: Option<Strng>,
+    pub destination_service: Option<ServiceDescription>,
+    pub next_hop: SocketAddr,
+    pub hbone_target: Option<SocketAddr>,
+    // The identities the next hop must present.
+    pub destination_identities: Vec<Identity>,
+}
+
+/// effective_route is a dry run of outbound request building: it resolves a connection from `source`, at address
+/// `downstream`, to `target`, without opening it. Nothing is recorded in the connection manager, and the
+/// selected endpoint is not counted in the metrics. It is not free of side effects, though: as with a real
+/// connection, workloads may be fetched on demand and hostnames resolved, which updates the proxy state and the
+/// DNS metrics, and picking a waypoint is counted as a selection. Where a service has several endpoints, the
+/// returned endpoint is one possible selection.
+pub async fn effective_route(
+    state: &DemandProxyState,
+    cfg: &crate::config::Config,
+    source: Arc<Workload>,
+    downstream: IpAddr,
+    target: SocketAddr,
+) -> Result<EffectiveRoute, Error> {
+    let req = build_workload_request(
+        state,
+        &cfg.network,
+        cfg.inbound_addr.port(),
+        &cfg.tunnel_overrides,
+        source,
+        downstream,
+        target,
+        false,
+        None,
+    )
+    .await?;
+    Ok(EffectiveRoute {
+        source_workload: req.source.uid.clone(),
+        source_identity: req.source.identity(),
+        protocol: req.protocol,
+        via_waypoint: req.via_waypoint,
+        destination_workload: req.actual_destination_workload.map(|w| w.uid.clone()),
+        destination_service: req.intended_destination_service,
+        next_hop: req.actual_destination,
+        hbone_target: req.hbone_target_destination,
+        destination_identities: req.upstream_sans,
+    })
+}
+
+// unless_client_gone runs a connection setup step, abandoning it if the client resets its connection first. Setup
+// can take up to the connection timeout, and completing it for a client that is gone only wastes an upstream
+// connection.
+async fn unless_client_gone<T>(
+    client: &TcpStream,
+    metrics: &metrics::Metrics,
+    setup: impl std::future::Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    tokio::select! {
+        res = setup => res,
+        _ = client_gone(client) => {
+            debug!("client reset the connection during setup");
+            metrics.setup_cancelled_client_gone.inc();
+            Err(Error::ClientGone)
+        }
+    }
+}
 
-        // only change the port if we're sending HBONE
-        let actual_destination = match us.workload.protocol {
-            Protocol::HBONE => SocketAddr::from((us.selected_workload_ip, self.hbone_port)),
-            Protocol::TCP => us.workload_socket_addr(),
-        };
-        let hbone_target_destination = match us.workload.protocol {
-            Protocol::HBONE => Some(us.workload_socket_addr()),
-            Protocol::TCP => None,
-        };
+// client_gone completes once the client has reset its connection, or it has failed otherwise. A client that only
+// closed its side of the connection, once it sent its request, still waits for the response, so it is not gone.
+// The connection is not read, so any data the client already sent is still relayed once setup completes. As long
+// as there is such data, or the client has closed its side, the connection stays readable, so it is checked
+// periodically rather than on every wakeup.
+async fn client_gone(client: &TcpStream) {
+    loop {
+        match client.ready(Interest::READABLE | Interest::ERROR).await {
+            Ok(ready) if !ready.is_error() => tokio::time::sleep(CLIENT_GONE_CHECK_INTERVAL).await,
+            _ => return,
+        }
+    }
+}
 
-        // For case no waypoint for both side and direct to remote node proxy
-        let upstream_sans = us.workload_and_services_san();
-        debug!("built request to workload");
-        Ok(Request {
-            protocol: us.workload.protocol,
-            source: source_workload,
-            hbone_target_destination,
-            actual_destination_workload: Some(us.workload.clone()),
-            intended_destination_service: us.destination_service.clone(),
-            actual_destination,
-            upstream_sans,
-        })
+// pool_key is the key in the connection pool for an HBONE request from the source IP.
+fn pool_key(cfg: &crate::config::Config, source: IpAddr, req: &Request) -> pool::WorkloadKey {
+    pool::WorkloadKey {
+        src_id: req.source.identity(),
+        // Clone here shouldn't be needed ideally, we could just take ownership of Request.
+        // But that
+        dst_id: req.upstream_sans.clone(),
+        src: super::original_source_ip(cfg, Some(&req.source), source, req.actual_destination.ip()),
+        dst: req.actual_destination,
+        sni: req
+            .actual_destination_workload
+            .as_ref()
+            .and_then(|w| w.tls_sni_override.clone()),
+        pool_profile: req
+            .hbone_target_destination
+            .and_then(|t| cfg.pool_profile(t.port()))
+            .map(|p| p.name.clone()),
     }
+}
 
-    async fn fetch_source_workload(&self, downstream: IpAddr) -> Result<Arc<Workload>, Error> {
-        let downstream_network_addr = NetworkAddress {
-            network: self.pi.cfg.network.clone(),
-            address: downstream,
-        };
+// bypasses_pool returns whether the request should get a connection of its own. Destinations are matched by the
+// intended service hostname, or by the IP of the destination workload.
+fn bypasses_pool(destinations: &[String], req: &Request) -> bool {
+    if destinations.is_empty() {
+        return false;
+    }
+    let service = req
+        .intended_destination_service
+        .as_ref()
+        .map(|s| s.hostname.as_str());
+    let workload_ip = req
+        .hbone_target_destination
+        .unwrap_or(req.actual_destination)
+        .ip()
+        .to_string();
+    destinations
+        .iter()
+        .any(|d| Some(d.as_str()) == service || *d == workload_ip)
+}
 
-        let source_workload = match self
-            .pi
-            .state
-            .wait_for_workload(&downstream_network_addr, Duration::from_secs(5))
-            .await
-        {
-            Some(wl) => wl,
-            None => {
-                return Err(Error::UnknownSource(downstream));
-            }
-        };
-        if let Some(ref wl_info) = self.pi.proxy_workload_info {
-            // make sure that the workload we fetched matches the workload info we got over ZDS.
-            if !wl_info.matches(&source_workload) {
-                return Err(Error::MismatchedSource(downstream, wl_info.clone()));
-            }
-        }
-        Ok(source_workload)
+// anonymous_workload is a placeholder for an unknown source; it has no identity or metadata beyond its address.
+fn anonymous_workload(ip: IpAddr, network: Strng) -> Workload {
+    Workload {
+        workload_ips: vec![ip],
+        waypoint: None,
+        network_gateway: None,
+        protocol: Protocol::TCP,
+        uid: Default::default(),
+        name: Default::default(),
+        namespace: Default::default(),
+        trust_domain: Default::default(),
+        service_account: Default::default(),
+        network,
+        workload_name: Default::default(),
+        workload_type: Default::default(),
+        canonical_name: Default::default(),
+        canonical_revision: Default::default(),
+        hostname: Default::default(),
+        node: Default::default(),
+        native_tunnel: false,
+        application_tunnel: None,
+        authorization_policies: vec![],
+        status: Default::default(),
+        cluster_id: Default::default(),
+        locality: Default::default(),
+        tls_sni_override: None,
+        traffic_class: Default::default(),
+        egress_rules: Default::default(),
     }
 }
 
+// next_hops returns the number of ztunnels a connection from `source_addr` will have traversed once we send it.
+fn next_hops(hops: &HopTracker, source_addr: SocketAddr) -> u8 {
+    hops.hops(source_addr).saturating_add(1)
+}
+
 fn baggage(r: &Request, cluster: String) -> String {
     format!("k8s.cluster.name={cluster},k8s.namespace.name={namespace},k8s.{workload_type}.name={workload_name},service.name={name},service.version={version}",
             namespace = r.source.namespace,
@@ -474,6 +1688,8 @@ struct Request {
     protocol: Protocol,
     // Source workload sending the request
     source: Arc<Workload>,
+    // Whether the source is unknown, and `source` is only a placeholder for its address.
+    anonymous_source: bool,
     // The actual destination workload we are targeting. When proxying through a waypoint, this is the waypoint,
     // not the original.
     // May be unset in case of passthrough.
@@ -487,23 +1703,32 @@ struct Request {
     // When using HBONE, the `hbone_target_destination` is the inner :authority and `actual_destination` is the TCP destination.
     actual_destination: SocketAddr,
     // If using HBONE, the inner (:authority) of the HBONE request.
+    // When going through a waypoint, this is always the original destination, with the port the client requested,
+    // so the waypoint can apply port based policy; the waypoint resolves the target port itself.
+    // Otherwise, it is the selected endpoint with the resolved target port (which may differ for named ports).
     hbone_target_destination: Option<SocketAddr>,
 
     // The identity we will assert for the next hop; this may not be the same as actual_destination_workload
     // in the case of proxies along the path.
     upstream_sans: Vec<Identity>,
+    // Whether the next hop is a waypoint, rather than the destination itself.
+    via_waypoint: bool,
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::net::Ipv6Addr;
     use std::time::Duration;
 
     use bytes::Bytes;
 
     use super::*;
-    use crate::config::Config;
+    use crate::config::{Config, IpFamilyPreference, IpFamilyPreferences, RejectionCloseMode};
     use crate::proxy::connection_manager::ConnectionManager;
+    use crate::proxy::destination_limiter::DestinationLimiter;
+    use crate::state::service::Endpoint;
+    use crate::state::workload::EgressRule;
     use crate::test_helpers::helpers::{initialize_telemetry, test_proxy_metrics};
     use crate::test_helpers::new_proxy_state;
     use crate::xds::istio::workload::address::Type as XdsAddressType;
@@ -533,6 +1758,16 @@ mod tests {
             local_node: Some("local-node".to_string()),
             ..crate::config::parse_config().unwrap()
         });
+        run_build_request_with_config(cfg, from, to, xds, expect).await;
+    }
+
+    async fn run_build_request_with_config(
+        cfg: Arc<Config>,
+        from: &str,
+        to: &str,
+        xds: Vec<XdsAddressType>,
+        expect: Option<ExpectedRequest<'_>>,
+    ) {
         let source = XdsWorkload {
             uid: "cluster1//v1/Pod/ns/source-workload".to_string(),
             name: "source-workload".to_string(),
@@ -561,14 +1796,39 @@ mod tests {
                 XdsAddressType::Service(svc) => services.push(svc),
             };
         }
-        let state = new_proxy_state(&workloads, &services, &[]);
+        let state = new_proxy_state(&workloads, &services, &[])
+            .with_ip_family_preferences(cfg.ip_family_preferences.clone());
+
+        let outbound = new_outbound(cfg, state);
+
+        let req = outbound
+            .build_request(from.parse().unwrap(), to.parse().unwrap())
+            .await
+            .ok();
+        if let Some(r) = req {
+            assert_eq!(
+                expect,
+                Some(ExpectedRequest {
+                    protocol: r.protocol,
+                    hbone_destination: &r
+                        .hbone_target_destination
+                        .map(|s| s.to_string())
+                        .unwrap_or_default(),
+                    destination: &r.actual_destination.to_string(),
+                })
+            );
+        } else {
+            assert_eq!(expect, None);
+        }
+    }
 
-        let sock_fact = std::sync::Arc::new(crate::proxy::DefaultSocketFactory);
+    fn new_outbound(cfg: Arc<Config>, state: DemandProxyState) -> OutboundConnection {
+        let sock_fact = std::sync::Arc::new(crate::proxy::DefaultSocketFactory::default());
         let cert_mgr = proxy::ScopedSecretManager::new(identity::mock::new_secret_manager(
             Duration::from_secs(10),
         ));
         let original_src = false; // for testing, not needed
-        let outbound = OutboundConnection {
+        OutboundConnection {
             pi: Arc::new(ProxyInputs {
                 cert_manager: cert_mgr.clone(),
                 state,
@@ -578,37 +1838,86 @@ mod tests {
                 proxy_workload_info: None,
                 connection_manager: ConnectionManager::default(),
                 resolver: None,
+                config_updates: None,
+                destination_limiter: Arc::new(DestinationLimiter::new(&test_proxy_metrics())),
+                port_affinity: Arc::new(PortAffinity::new(&test_proxy_metrics())),
+                hops: Default::default(),
             }),
             id: TraceParent::new(),
+            conn_id: ConnectionId::next(),
             pool: pool::WorkloadHBONEPool::new(
                 cfg.clone(),
                 original_src,
                 sock_fact,
                 cert_mgr.clone(),
+                test_proxy_metrics(),
             ),
             enable_orig_src: cfg.require_original_source.unwrap_or_default(),
             hbone_port: cfg.inbound_addr.port(),
+        }
+    }
+
+    #[tokio::test]
+    async fn setup_cancelled_when_client_gone() {
+        let metrics = test_proxy_metrics();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        // The client sends data before giving up, which must not hide the close. The upstream connect never
+        // completes, so only the close can end setup.
+        client.write_all(b"ping").await.unwrap();
+        let (aborted_tx, aborted_rx) = tokio::sync::oneshot::channel::<()>();
+        let slow_connect = async move {
+            let _aborted = aborted_tx;
+            std::future::pending::<Result<(), Error>>().await
         };
+        let setup = tokio::spawn(async move {
+            let res = unless_client_gone(&stream, &metrics, slow_connect).await;
+            (res, metrics)
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!setup.is_finished());
+        // Reset the connection, rather than closing it.
+        socket2::SockRef::from(&client)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+        drop(client);
 
-        let req = outbound
-            .build_request(from.parse().unwrap(), to.parse().unwrap())
+        let (res, metrics) = setup.await.unwrap();
+        assert!(matches!(res, Err(Error::ClientGone)), "{res:?}");
+        // Dropping the connect future is what aborts the upstream attempt.
+        assert!(aborted_rx.await.is_err());
+        assert_eq!(metrics.setup_cancelled_client_gone.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn setup_completes_after_client_half_close() {
+        let metrics = test_proxy_metrics();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
             .await
-            .ok();
-        if let Some(r) = req {
-            assert_eq!(
-                expect,
-                Some(ExpectedRequest {
-                    protocol: r.protocol,
-                    hbone_destination: &r
-                        .hbone_target_destination
-                        .map(|s| s.to_string())
-                        .unwrap_or_default(),
-                    destination: &r.actual_destination.to_string(),
-                })
-            );
-        } else {
-            assert_eq!(expect, None);
-        }
+            .unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        // The client sends its request and closes its side, but still waits for the response.
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        let connect = async {
+            tokio::time::sleep(CLIENT_GONE_CHECK_INTERVAL * 3).await;
+            Ok(())
+        };
+        let res = unless_client_gone(&stream, &metrics, connect).await;
+        assert!(res.is_ok(), "{res:?}");
+        assert_eq!(metrics.setup_cancelled_client_gone.get(), 0);
+        // The request is still there to be relayed.
+        let mut buf = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut stream, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, b"ping");
     }
 
     #[tokio::test]
@@ -718,20 +2027,431 @@ mod tests {
                 hbone_destination: "127.0.0.2:80",
                 destination: "127.0.0.2:15008",
             }),
-        )
-        .await;
+        )
+        .await;
+    }
+
+    fn tunnel_override_workload(protocol: XdsProtocol) -> Vec<XdsAddressType> {
+        vec![XdsAddressType::Workload(XdsWorkload {
+            uid: "cluster1//v1/Pod/ns/legacy".to_string(),
+            name: "legacy".to_string(),
+            namespace: "ns".to_string(),
+            addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+            tunnel_protocol: protocol as i32,
+            node: "remote-node".to_string(),
+            ..Default::default()
+        })]
+    }
+
+    #[tokio::test]
+    async fn build_request_tunnel_override_passthrough() {
+        let cfg = Config {
+            local_node: Some("local-node".to_string()),
+            tunnel_overrides: TunnelOverrides(HashMap::from([(
+                "ns/legacy".to_string(),
+                TunnelOverride::Passthrough,
+            )])),
+            ..crate::config::parse_config().unwrap()
+        };
+        run_build_request_with_config(
+            Arc::new(cfg),
+            "127.0.0.1",
+            "127.0.0.2:80",
+            tunnel_override_workload(XdsProtocol::Hbone),
+            Some(ExpectedRequest {
+                protocol: Protocol::TCP,
+                hbone_destination: "",
+                destination: "127.0.0.2:80",
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn build_request_tunnel_override_hbone() {
+        let cfg = || Config {
+            local_node: Some("local-node".to_string()),
+            tunnel_overrides: TunnelOverrides(HashMap::from([(
+                "ns".to_string(),
+                TunnelOverride::Hbone,
+            )])),
+            ..crate::config::parse_config().unwrap()
+        };
+        run_build_request_with_config(
+            Arc::new(cfg()),
+            "127.0.0.1",
+            "127.0.0.2:80",
+            tunnel_override_workload(XdsProtocol::Hbone),
+            Some(ExpectedRequest {
+                protocol: Protocol::HBONE,
+                hbone_destination: "127.0.0.2:80",
+                destination: "127.0.0.2:15008",
+            }),
+        )
+        .await;
+        // The workload does not support HBONE, so rather than fall back to plaintext, the request is rejected.
+        run_build_request_with_config(
+            Arc::new(cfg()),
+            "127.0.0.1",
+            "127.0.0.2:80",
+            tunnel_override_workload(XdsProtocol::None),
+            None,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn build_request_unknown_source() {
+        run_build_request(
+            "1.2.3.4",
+            "127.0.0.2:80",
+            XdsAddressType::Workload(XdsWorkload {
+                uid: "cluster1//v1/Pod/default/my-pod".to_string(),
+                addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+                ..Default::default()
+            }),
+            None,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn build_request_unknown_source_allow_anonymous() {
+        let cfg = Config {
+            local_node: Some("local-node".to_string()),
+            unknown_source_policy: UnknownSourcePolicy::AllowAnonymous,
+            ..crate::config::parse_config().unwrap()
+        };
+        run_build_request_with_config(
+            Arc::new(cfg),
+            "1.2.3.4",
+            "127.0.0.2:80",
+            vec![XdsAddressType::Workload(XdsWorkload {
+                uid: "cluster1//v1/Pod/default/my-pod".to_string(),
+                addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+                tunnel_protocol: XdsProtocol::Hbone as i32,
+                ..Default::default()
+            })],
+            // We have no identity to use for HBONE, so send plaintext
+            Some(ExpectedRequest {
+                protocol: Protocol::TCP,
+                hbone_destination: "",
+                destination: "127.0.0.2:80",
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn egress_destinations() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let state = || {
+            new_proxy_state(&[], &[], &[]).with_static_hosts(HashMap::from([
+                (
+                    "api.example.com".to_string(),
+                    vec![ip("192.0.2.1"), ip("192.0.2.2"), ip("2001:db8::1")],
+                ),
+                ("v4.example.com".to_string(), vec![ip("192.0.2.3")]),
+            ]))
+        };
+        let destinations = |host: &'static str, resolution, family| async move {
+            let cfg = Arc::new(Config {
+                connect_authority_resolution: resolution,
+                connect_authority_ip_family: family,
+                ..crate::config::parse_config().unwrap()
+            });
+            new_outbound(cfg, state())
+                .egress_destinations(host, 443)
+                .await
+                .map(|d| d.iter().map(|d| d.to_string()).collect::<Vec<_>>())
+        };
+
+        let picked = destinations(
+            "api.example.com",
+            ConnectAuthorityResolution::Disabled,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(picked.len(), 1);
+        assert_eq!(
+            destinations(
+                "api.example.com",
+                ConnectAuthorityResolution::All,
+                Some(IpFamilyPreference::DualPreferV6)
+            )
+            .await
+            .unwrap(),
+            vec!["[2001:db8::1]:443", "192.0.2.1:443", "192.0.2.2:443"]
+        );
+        assert_eq!(
+            destinations(
+                "api.example.com",
+                ConnectAuthorityResolution::First,
+                Some(IpFamilyPreference::DualPreferV6)
+            )
+            .await
+            .unwrap(),
+            vec!["[2001:db8::1]:443"]
+        );
+        assert_eq!(
+            destinations(
+                "api.example.com",
+                ConnectAuthorityResolution::All,
+                Some(IpFamilyPreference::V4)
+            )
+            .await
+            .unwrap(),
+            vec!["192.0.2.1:443", "192.0.2.2:443"]
+        );
+        // Every address is of a family we do not use
+        assert!(matches!(
+            destinations(
+                "v4.example.com",
+                ConnectAuthorityResolution::All,
+                Some(IpFamilyPreference::V6)
+            )
+            .await,
+            Err(Error::EmptyResolvedAddresses(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn bypass_cidrs() {
+        let cfg = Arc::new(Config {
+            bypass_cidrs: crate::cidrs::CidrSet::new(vec!["127.0.0.1/32".parse().unwrap()]),
+            ..crate::config::parse_config().unwrap()
+        });
+        let sock_fact = Arc::new(crate::proxy::DefaultSocketFactory::default());
+        let cert_mgr = proxy::ScopedSecretManager::new(identity::mock::new_secret_manager(
+            Duration::from_secs(10),
+        ));
+        let metrics = test_proxy_metrics();
+        let outbound = OutboundConnection {
+            pi: Arc::new(ProxyInputs {
+                cert_manager: cert_mgr.clone(),
+                // Neither the source nor the destination is known
+                state: new_proxy_state(&[], &[], &[]),
+                cfg: cfg.clone(),
+                metrics: metrics.clone(),
+                socket_factory: sock_fact.clone(),
+                proxy_workload_info: None,
+                connection_manager: ConnectionManager::default(),
+                resolver: None,
+                config_updates: None,
+                destination_limiter: Arc::new(DestinationLimiter::new(&metrics)),
+                port_affinity: Arc::new(PortAffinity::new(&metrics)),
+                hops: Default::default(),
+            }),
+            id: TraceParent::new(),
+            conn_id: ConnectionId::next(),
+            pool: pool::WorkloadHBONEPool::new(
+                cfg.clone(),
+                false,
+                sock_fact,
+                cert_mgr,
+                metrics.clone(),
+            ),
+            enable_orig_src: false,
+            hbone_port: cfg.inbound_addr.port(),
+        };
+
+        let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connect = |dest: SocketAddr| {
+            let mut oc = OutboundConnection {
+                pi: outbound.pi.clone(),
+                id: TraceParent::new(),
+                conn_id: ConnectionId::next(),
+                pool: outbound.pool.clone(),
+                enable_orig_src: false,
+                hbone_port: outbound.hbone_port,
+            };
+            let listener = &listener;
+            async move {
+                let client = TcpStream::connect(listener.local_addr().unwrap())
+                    .await
+                    .unwrap();
+                let (stream, peer) = listener.accept().await.unwrap();
+                tokio::spawn(async move { oc.proxy_to(stream, peer, dest).await });
+                client
+            }
+        };
+
+        // The destination is in a bypass CIDR, so it is reached without knowing anything about it.
+        let mut client = connect(echo_addr).await;
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tokio::io::AsyncReadExt::read_exact(&mut client, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(metrics.bypass_connections.get(), 1);
+
+        // Other destinations are processed as usual, so the unknown source is rejected.
+        let mut client = connect(SocketAddr::new([127, 0, 0, 2].into(), echo_addr.port())).await;
+        let mut buf = Vec::new();
+        let read = tokio::io::AsyncReadExt::read_to_end(&mut client, &mut buf).await;
+        assert!(read.map_or(true, |n| n == 0));
+        assert_eq!(metrics.bypass_connections.get(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn passthrough_without_fast_open() {
+        let state = Arc::new(std::sync::RwLock::new(crate::state::ProxyState::default()));
+        state
+            .write()
+            .unwrap()
+            .workloads
+            .insert(Arc::new(crate::test_helpers::test_default_workload()), true);
+        let mut outbound = new_outbound(
+            Arc::new(Config {
+                tcp_fast_open: true,
+                ..crate::test_helpers::test_config()
+            }),
+            DemandProxyState::new(
+                state,
+                None,
+                Default::default(),
+                Default::default(),
+                test_proxy_metrics(),
+            ),
+        );
+        let sf = Arc::new(
+            crate::test_helpers::faults::FaultInjectingSocketFactory::new(Arc::new(
+                crate::proxy::DefaultSocketFactory::default(),
+            )),
+        );
+        outbound.pi = Arc::new(ProxyInputs {
+            socket_factory: sf.clone(),
+            ..(*outbound.pi).clone()
+        });
+
+        let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut r, mut w) = stream.split();
+            let _ = tokio::io::copy(&mut r, &mut w).await;
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        tokio::spawn(async move { outbound.proxy_to(stream, peer, echo_addr).await });
+
+        // The destination is unknown, so the connection is passed through. Server-first protocols would wait
+        // on a TFO connect for data the client never sends, so TFO is left for HBONE.
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tokio::io::AsyncReadExt::read_exact(&mut client, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(sf.fast_open_connects(), 0);
+    }
+
+    #[tokio::test]
+    async fn check_hops_two_hop_loop() {
+        let cfg = Arc::new(Config {
+            max_proxy_hops: 2,
+            ..crate::config::parse_config().unwrap()
+        });
+        let sock_fact = Arc::new(crate::proxy::DefaultSocketFactory::default());
+        let cert_mgr = proxy::ScopedSecretManager::new(identity::mock::new_secret_manager(
+            Duration::from_secs(10),
+        ));
+        let outbound = OutboundConnection {
+            pi: Arc::new(ProxyInputs {
+                cert_manager: cert_mgr.clone(),
+                state: new_proxy_state(&[], &[], &[]),
+                cfg: cfg.clone(),
+                metrics: test_proxy_metrics(),
+                socket_factory: sock_fact.clone(),
+                proxy_workload_info: None,
+                connection_manager: ConnectionManager::default(),
+                resolver: None,
+                config_updates: None,
+                destination_limiter: Arc::new(DestinationLimiter::new(&test_proxy_metrics())),
+                port_affinity: Arc::new(PortAffinity::new(&test_proxy_metrics())),
+                hops: Default::default(),
+            }),
+            id: TraceParent::new(),
+            conn_id: ConnectionId::next(),
+            pool: pool::WorkloadHBONEPool::new(
+                cfg.clone(),
+                false,
+                sock_fact,
+                cert_mgr,
+                test_proxy_metrics(),
+            ),
+            enable_orig_src: false,
+            hbone_port: cfg.inbound_addr.port(),
+        };
+        let hops = &outbound.pi.hops;
+        let client: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let delivered: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+
+        // A client connection is the first hop.
+        outbound.check_hops(client).unwrap();
+        assert_eq!(next_hops(hops, client), 1);
+
+        // The peer delivers it locally, but it is redirected back out to us: the second hop.
+        let first = hops.track(delivered, 1);
+        outbound.check_hops(delivered).unwrap();
+        assert_eq!(next_hops(hops, delivered), 2);
+        drop(first);
+
+        // We deliver it locally, and it is redirected back out again. This exceeds the limit.
+        let second = hops.track(delivered, 2);
+        assert!(matches!(
+            outbound.check_hops(delivered),
+            Err(Error::ProxyLoopDetected(3, 2))
+        ));
+        assert_eq!(outbound.pi.metrics.proxy_loops_detected.get(), 1);
+
+        // Once the connection closes, the address is no longer tracked.
+        drop(second);
+        outbound.check_hops(delivered).unwrap();
     }
 
     #[tokio::test]
-    async fn build_request_unknown_source() {
-        run_build_request(
+    async fn build_request_unknown_source_anonymous_waypoint() {
+        let cfg = Config {
+            local_node: Some("local-node".to_string()),
+            unknown_source_policy: UnknownSourcePolicy::AllowAnonymous,
+            ..crate::config::parse_config().unwrap()
+        };
+        run_build_request_with_config(
+            Arc::new(cfg),
             "1.2.3.4",
             "127.0.0.2:80",
-            XdsAddressType::Workload(XdsWorkload {
+            vec![XdsAddressType::Workload(XdsWorkload {
                 uid: "cluster1//v1/Pod/default/my-pod".to_string(),
                 addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+                waypoint: Some(xds::istio::workload::GatewayAddress {
+                    destination: Some(xds::istio::workload::gateway_address::Destination::Address(
+                        XdsNetworkAddress {
+                            network: "".to_string(),
+                            address: [127, 0, 0, 10].to_vec(),
+                        },
+                    )),
+                    hbone_mtls_port: 15008,
+                }),
                 ..Default::default()
-            }),
+            })],
+            // Anonymous traffic must not bypass the waypoint
             None,
         )
         .await;
@@ -794,6 +2514,64 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn effective_route_via_waypoint() {
+        let cfg = crate::config::parse_config().unwrap();
+        let source = XdsWorkload {
+            uid: "cluster1//v1/Pod/ns/source-workload".to_string(),
+            name: "source-workload".to_string(),
+            namespace: "ns".to_string(),
+            addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 1])],
+            ..Default::default()
+        };
+        let waypoint = XdsWorkload {
+            uid: "cluster1//v1/Pod/ns/waypoint-workload".to_string(),
+            name: "waypoint-workload".to_string(),
+            namespace: "ns".to_string(),
+            addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 10])],
+            service_account: "waypoint-sa".to_string(),
+            ..Default::default()
+        };
+        let dest = XdsWorkload {
+            uid: "cluster1//v1/Pod/default/my-pod".to_string(),
+            addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+            waypoint: Some(xds::istio::workload::GatewayAddress {
+                destination: Some(xds::istio::workload::gateway_address::Destination::Address(
+                    XdsNetworkAddress {
+                        network: "".to_string(),
+                        address: [127, 0, 0, 10].to_vec(),
+                    },
+                )),
+                hbone_mtls_port: 15008,
+            }),
+            ..Default::default()
+        };
+        let state = new_proxy_state(&[source, waypoint, dest], &[], &[]);
+        let find = |uid: &str| state.read().workloads.find_uid(&uid.into()).unwrap();
+        let source = find("cluster1//v1/Pod/ns/source-workload");
+        let waypoint = find("cluster1//v1/Pod/ns/waypoint-workload");
+
+        let route = effective_route(
+            &state,
+            &cfg,
+            source.clone(),
+            "127.0.0.1".parse().unwrap(),
+            "127.0.0.2:80".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(route.source_identity, source.identity());
+        assert_eq!(route.protocol, Protocol::HBONE);
+        assert!(route.via_waypoint);
+        assert_eq!(
+            route.destination_workload.as_deref(),
+            Some("cluster1//v1/Pod/ns/waypoint-workload")
+        );
+        assert_eq!(route.next_hop, "127.0.0.10:15008".parse().unwrap());
+        assert_eq!(route.hbone_target, Some("127.0.0.2:80".parse().unwrap()));
+        assert_eq!(route.destination_identities, vec![waypoint.identity()]);
+    }
+
     #[tokio::test]
     async fn build_request_destination_svc_waypoint() {
         run_build_request(
@@ -807,6 +2585,7 @@ mod tests {
                 ports: vec![Port {
                     service_port: 80,
                     target_port: 8080,
+                    ..Default::default()
                 }],
                 waypoint: Some(xds::istio::workload::GatewayAddress {
                     destination: Some(xds::istio::workload::gateway_address::Destination::Address(
@@ -842,6 +2621,7 @@ mod tests {
                 ports: vec![Port {
                     service_port: 80,
                     target_port: 8080,
+                    ..Default::default()
                 }],
                 ..Default::default()
             }),
@@ -851,6 +2631,315 @@ mod tests {
         .await;
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn build_request_waits_for_endpoints() {
+        let state = Arc::new(std::sync::RwLock::new(crate::state::ProxyState::default()));
+        {
+            let mut state = state.write().unwrap();
+            state.workloads.insert(
+                Arc::new(Workload {
+                    uid: "source".into(),
+                    ..crate::test_helpers::test_default_workload()
+                }),
+                true,
+            );
+            state
+                .services
+                .insert(crate::test_helpers::mock_default_service());
+        }
+        let cfg = Config {
+            wait_for_endpoints_timeout: Duration::from_secs(5),
+            ..crate::test_helpers::test_config()
+        };
+        let outbound = new_outbound(
+            Arc::new(cfg),
+            DemandProxyState::new(
+                state.clone(),
+                None,
+                Default::default(),
+                Default::default(),
+                test_proxy_metrics(),
+            ),
+        );
+        let outbound = Arc::new(outbound);
+        let build = |outbound: Arc<OutboundConnection>| {
+            tokio::spawn(async move {
+                outbound
+                    .build_request(
+                        "127.0.0.1".parse().unwrap(),
+                        "127.0.10.1:8080".parse().unwrap(),
+                    )
+                    .await
+            })
+        };
+
+        // Without endpoints, the request fails once the timeout passes.
+        let err = build(outbound.clone()).await.unwrap().unwrap_err();
+        assert!(matches!(err, Error::NoHealthyUpstream(_)));
+
+        // An endpoint appearing mid-wait is selected.
+        let req = build(outbound);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!req.is_finished());
+        {
+            let mut state = state.write().unwrap();
+            let ip = IpAddr::from([127, 0, 0, 4]);
+            state.workloads.insert(
+                Arc::new(Workload {
+                    uid: "endpoint".into(),
+                    workload_ips: vec![ip],
+                    ..crate::test_helpers::test_default_workload()
+                }),
+                true,
+            );
+            state.services.insert_endpoint(Endpoint {
+                workload_uid: "endpoint".into(),
+                service: crate::test_helpers::mock_default_service().namespaced_hostname(),
+                address: Some(NetworkAddress {
+                    network: "".into(),
+                    address: ip,
+                }),
+                port: HashMap::from([(8080, 80)]),
+            });
+        }
+        let req = req.await.unwrap().unwrap();
+        assert_eq!(req.actual_destination, "127.0.0.4:80".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn tls_origination() {
+        // A server outside the mesh, which only accepts TLS connections for api.example.com.
+        let server = tokio::net::TcpListener::bind("127.0.0.2:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(
+            crate::tls::mock::hostname_server_config("api.example.com", &[&rustls::version::TLS13]),
+        ));
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = server.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let mut buf = [0u8; 5];
+                    if tokio::io::AsyncReadExt::read_exact(&mut stream, &mut buf)
+                        .await
+                        .is_ok()
+                    {
+                        let _ = stream.write_all(&buf).await;
+                        let _ = stream.shutdown().await;
+                    }
+                });
+            }
+        });
+
+        let ca = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/tls/root-cert.pem");
+        let cfg = Arc::new(Config {
+            egress_tls_origination: vec![
+                TlsOrigination {
+                    hostname: "api.example.com".to_string(),
+                    sni: "api.example.com".to_string(),
+                    ca: Some(ca.clone()),
+                    port: None,
+                },
+                TlsOrigination {
+                    hostname: "mismatch.example.com".to_string(),
+                    sni: "mismatch.example.com".to_string(),
+                    ca: Some(ca),
+                    port: None,
+                },
+            ],
+            ..crate::config::parse_config().unwrap()
+        });
+        let service = |hostname: &str, vip: u8| XdsService {
+            hostname: hostname.to_string(),
+            addresses: vec![XdsNetworkAddress {
+                network: "".to_string(),
+                address: vec![127, 0, 0, vip],
+            }],
+            ports: vec![Port {
+                service_port: u32::from(port),
+                target_port: u32::from(port),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let ports = || PortList {
+            ports: vec![Port {
+                service_port: u32::from(port),
+                target_port: u32::from(port),
+                ..Default::default()
+            }],
+        };
+        let workloads = [
+            XdsWorkload {
+                uid: "cluster1//v1/Pod/ns/source-workload".to_string(),
+                name: "source-workload".to_string(),
+                namespace: "ns".to_string(),
+                addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 1])],
+                ..Default::default()
+            },
+            XdsWorkload {
+                uid: "cluster1//v1/Pod/default/external".to_string(),
+                addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+                services: HashMap::from([
+                    ("/api.example.com".to_string(), ports()),
+                    ("/mismatch.example.com".to_string(), ports()),
+                ]),
+                ..Default::default()
+            },
+        ];
+        let state = new_proxy_state(
+            &workloads,
+            &[
+                service("api.example.com", 3),
+                service("mismatch.example.com", 4),
+            ],
+            &[],
+        );
+        let outbound = new_outbound(cfg, state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connect = |dest: SocketAddr| {
+            let mut oc = OutboundConnection {
+                pi: outbound.pi.clone(),
+                id: TraceParent::new(),
+                conn_id: ConnectionId::next(),
+                pool: outbound.pool.clone(),
+                enable_orig_src: false,
+                hbone_port: outbound.hbone_port,
+            };
+            let listener = &listener;
+            async move {
+                let client = TcpStream::connect(listener.local_addr().unwrap())
+                    .await
+                    .unwrap();
+                let (stream, peer) = listener.accept().await.unwrap();
+                tokio::spawn(async move { oc.proxy_to(stream, peer, dest).await });
+                client
+            }
+        };
+        let metrics = outbound.pi.metrics.clone();
+        let failures = |reason| {
+            metrics
+                .tls_origination_failures
+                .get_or_create(&TlsOriginationFailureLabels { reason })
+                .get()
+        };
+
+        // The client speaks plaintext, and the server only sees TLS.
+        let mut client = connect(SocketAddr::new([127, 0, 0, 3].into(), port)).await;
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut client, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, b"hello");
+        assert_eq!(metrics.tls_originations.get(), 1);
+
+        // The server's certificate is not valid for the SNI, so the connection is refused.
+        let mut client = connect(SocketAddr::new([127, 0, 0, 4].into(), port)).await;
+        let mut buf = Vec::new();
+        let read = tokio::io::AsyncReadExt::read_to_end(&mut client, &mut buf).await;
+        assert!(read.map_or(true, |n| n == 0));
+        assert_eq!(metrics.tls_originations.get(), 1);
+        assert_eq!(failures(TlsFailureReason::identity_mismatch), 1);
+    }
+
+    #[tokio::test]
+    async fn egress_rules_authorize() {
+        let state = Arc::new(std::sync::RwLock::new(crate::state::ProxyState::default()));
+        state.write().unwrap().workloads.insert(
+            Arc::new(Workload {
+                uid: "source".into(),
+                egress_rules: Some(vec![EgressRule {
+                    hosts: vec!["10.0.0.1".to_string()],
+                    ports: vec![],
+                }]),
+                ..crate::test_helpers::test_default_workload()
+            }),
+            true,
+        );
+        let outbound = new_outbound(
+            Arc::new(crate::test_helpers::test_config()),
+            DemandProxyState::new(
+                state,
+                None,
+                Default::default(),
+                Default::default(),
+                test_proxy_metrics(),
+            ),
+        );
+
+        for (dest, allowed) in [("10.0.0.1:80", true), ("10.0.0.2:80", false)] {
+            let dest = dest.parse().unwrap();
+            let req = outbound
+                .build_request("127.0.0.1".parse().unwrap(), dest)
+                .await
+                .unwrap();
+            let res = outbound.authorize_egress(&req, dest);
+            assert_eq!(res.is_ok(), allowed, "{dest}");
+        }
+        let denied = outbound
+            .pi
+            .metrics
+            .egress_denied
+            .get_or_create(&EgressDeniedLabels {
+                reason: EgressDenyReason::egress_rules,
+            })
+            .get();
+        assert_eq!(denied, 1);
+    }
+
+    #[tokio::test]
+    async fn rejection_close_mode() {
+        for mode in [RejectionCloseMode::Fin, RejectionCloseMode::Rst] {
+            let state = Arc::new(std::sync::RwLock::new(crate::state::ProxyState::default()));
+            state.write().unwrap().workloads.insert(
+                Arc::new(Workload {
+                    uid: "source".into(),
+                    egress_rules: Some(vec![EgressRule {
+                        hosts: vec!["10.0.0.1".to_string()],
+                        ports: vec![],
+                    }]),
+                    ..crate::test_helpers::test_default_workload()
+                }),
+                true,
+            );
+            let mut outbound = new_outbound(
+                Arc::new(Config {
+                    rejection_close_mode: mode,
+                    ..crate::test_helpers::test_config()
+                }),
+                DemandProxyState::new(
+                    state,
+                    None,
+                    Default::default(),
+                    Default::default(),
+                    test_proxy_metrics(),
+                ),
+            );
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (stream, peer) = listener.accept().await.unwrap();
+            // The egress rules do not allow the destination, so the connection is rejected.
+            outbound
+                .proxy_to(stream, peer, "10.0.0.2:80".parse().unwrap())
+                .await;
+
+            let read = tokio::io::AsyncReadExt::read_to_end(&mut client, &mut Vec::new()).await;
+            match mode {
+                RejectionCloseMode::Fin => assert_eq!(read.unwrap(), 0),
+                RejectionCloseMode::Rst => assert_eq!(
+                    read.unwrap_err().kind(),
+                    std::io::ErrorKind::ConnectionReset
+                ),
+            }
+        }
+    }
+
     #[tokio::test]
     async fn build_request_target_port() {
         run_build_request_multi(
@@ -884,6 +2973,7 @@ mod tests {
                             ports: vec![Port {
                                 service_port: 80,
                                 target_port: 1234,
+                                ..Default::default()
                             }],
                         },
                     )]),
@@ -899,6 +2989,7 @@ mod tests {
                             ports: vec![Port {
                                 service_port: 8080,
                                 target_port: 9999,
+                                ..Default::default()
                             }],
                         },
                     )]),
@@ -914,6 +3005,75 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn build_request_named_port_authority() {
+        let service = |waypoint| {
+            XdsAddressType::Service(XdsService {
+                hostname: "example.com".to_string(),
+                addresses: vec![XdsNetworkAddress {
+                    network: "".to_string(),
+                    address: vec![127, 0, 0, 3],
+                }],
+                ports: vec![Port {
+                    service_port: 80,
+                    target_port: 0, // named port
+                }],
+                waypoint,
+                ..Default::default()
+            })
+        };
+        let workload = XdsAddressType::Workload(XdsWorkload {
+            uid: "cluster1//v1/Pod/default/my-pod".to_string(),
+            addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+            tunnel_protocol: XdsProtocol::Hbone as i32,
+            services: std::collections::HashMap::from([(
+                "/example.com".to_string(),
+                PortList {
+                    ports: vec![Port {
+                        service_port: 80,
+                        target_port: 1234,
+                        ..Default::default()
+                    }],
+                },
+            )]),
+            ..Default::default()
+        });
+        let waypoint = xds::istio::workload::GatewayAddress {
+            destination: Some(xds::istio::workload::gateway_address::Destination::Address(
+                XdsNetworkAddress {
+                    network: "".to_string(),
+                    address: [127, 0, 0, 10].to_vec(),
+                },
+            )),
+            hbone_mtls_port: 15008,
+        };
+
+        // Through a waypoint, the authority keeps the port the client requested
+        run_build_request_multi(
+            "127.0.0.1",
+            "127.0.0.3:80",
+            vec![service(Some(waypoint)), workload.clone()],
+            Some(ExpectedRequest {
+                protocol: Protocol::HBONE,
+                hbone_destination: "127.0.0.3:80",
+                destination: "127.0.0.10:15008",
+            }),
+        )
+        .await;
+        // Directly to the endpoint, the authority has the resolved target port
+        run_build_request_multi(
+            "127.0.0.1",
+            "127.0.0.3:80",
+            vec![service(None), workload],
+            Some(ExpectedRequest {
+                protocol: Protocol::HBONE,
+                hbone_destination: "127.0.0.2:1234",
+                destination: "127.0.0.2:15008",
+            }),
+        )
+        .await;
+    }
+
     #[tokio::test]
     async fn multiple_address_workload() {
         let workload = XdsAddressType::Workload(XdsWorkload {
@@ -986,6 +3146,7 @@ mod tests {
                 ports: vec![Port {
                     service_port: 80,
                     target_port: 80,
+                    ..Default::default()
                 }],
                 ..Default::default()
             };
@@ -1042,6 +3203,88 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn workload_ip_family_preference() {
+        initialize_telemetry();
+        let workload = XdsAddressType::Workload(XdsWorkload {
+            uid: "cluster1//v1/Pod/default/dual".to_string(),
+            addresses: vec![
+                Bytes::copy_from_slice(&[127, 0, 0, 2]),
+                Bytes::copy_from_slice("ff06::c3".parse::<Ipv6Addr>().unwrap().octets().as_slice()),
+            ],
+            tunnel_protocol: 1,
+            services: std::collections::HashMap::from([(
+                "/example.com".to_string(),
+                PortList { ports: vec![] },
+            )]),
+            ..Default::default()
+        });
+        let svc = |f: IpFamilies| {
+            let mut s = XdsService {
+                hostname: "example.com".to_string(),
+                addresses: vec![XdsNetworkAddress {
+                    network: "".to_string(),
+                    address: vec![127, 0, 0, 3],
+                }],
+                ports: vec![Port {
+                    service_port: 80,
+                    target_port: 80,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            s.set_ip_families(f);
+            XdsAddressType::Service(s)
+        };
+        let cfg = |preference: IpFamilyPreference| Config {
+            local_node: Some("local-node".to_string()),
+            ip_family_preferences: IpFamilyPreferences(std::collections::HashMap::from([(
+                "ns/source-workload".to_string(),
+                preference,
+            )])),
+            ..crate::config::parse_config().unwrap()
+        };
+        // A v6 preferred workload uses the v6 IP, even though it connected over v4
+        run_build_request_with_config(
+            Arc::new(cfg(IpFamilyPreference::DualPreferV6)),
+            "127.0.0.1",
+            "127.0.0.3:80",
+            vec![svc(IpFamilies::Dual), workload.clone()],
+            Some(ExpectedRequest {
+                protocol: Protocol::HBONE,
+                hbone_destination: "[ff06::c3]:80",
+                destination: "[ff06::c3]:15008",
+            }),
+        )
+        .await;
+        // Without a service restriction, the preference still applies
+        run_build_request_with_config(
+            Arc::new(cfg(IpFamilyPreference::V6)),
+            "127.0.0.1",
+            "127.0.0.3:80",
+            vec![svc(IpFamilies::Automatic), workload.clone()],
+            Some(ExpectedRequest {
+                protocol: Protocol::HBONE,
+                hbone_destination: "[ff06::c3]:80",
+                destination: "[ff06::c3]:15008",
+            }),
+        )
+        .await;
+        // The service restriction still applies; a v6 preferred workload falls back to v4 if it must
+        run_build_request_with_config(
+            Arc::new(cfg(IpFamilyPreference::DualPreferV6)),
+            "127.0.0.1",
+            "127.0.0.3:80",
+            vec![svc(IpFamilies::Ipv4Only), workload.clone()],
+            Some(ExpectedRequest {
+                protocol: Protocol::HBONE,
+                hbone_destination: "127.0.0.2:80",
+                destination: "127.0.0.2:15008",
+            }),
+        )
+        .await;
+    }
+
     #[derive(PartialEq, Debug)]
     struct ExpectedRequest<'a> {
         protocol: Protocol,
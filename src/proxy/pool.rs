@@ -13,20 +13,22 @@
 // limitations under the License.
 
 #![warn(clippy::cast_lossless)]
+use super::metrics::{Reporter, SourceBinding, TlsFailureReason};
 use super::{h2, ScopedSecretManager};
-use super::{Error, SocketFactory};
-use std::time::Duration;
+use super::{Error, Metrics, SetupPhase, SocketFactory};
 
 use std::collections::hash_map::DefaultHasher;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 
 use std::net::IpAddr;
 use std::net::SocketAddr;
 
-use std::sync::atomic::{AtomicI32, Ordering};
+use prometheus_client::metrics::gauge::Gauge;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
 use std::sync::Arc;
 
 use tokio::sync::watch;
@@ -36,6 +38,11 @@ use tracing::{debug, trace, Instrument};
 
 use crate::config;
 use crate::identity::Identity;
+use crate::socket;
+use crate::state::service::ServiceDescription;
+use crate::strng::Strng;
+use crate::tls;
+use crate::tls::HandshakeSummary;
 
 use flurry;
 
@@ -67,19 +74,91 @@ struct PoolState {
     connected_pool: Arc<pingora_pool::ConnectionPool<ConnClient>>,
     // this must be an atomic/concurrent-safe list-of-locks, so we can lock per-key, not globally, and avoid holding up all conn attempts
     established_conn_writelock: flurry::HashMap<u64, Option<Arc<Mutex<()>>>>,
-    pool_unused_release_timeout: Duration,
     // This is merely a counter to track the overall number of conns this pool spawns
     // to ensure we get unique poolkeys-per-new-conn, it is not a limit
     pool_global_conn_count: AtomicI32,
+    floor: Arc<PoolFloor>,
     spawner: ConnSpawner,
 }
 
+// PoolFloor tracks the pooled connections that count towards the minimum connections of their destination, which
+// get the longer floor timeout. A connection keeps its place for as long as it keeps being checked back in, and
+// gives it up once an idle timeout ends without a newer checkin, such as when it is evicted or timed out, or when
+// it leaves the pool after being used.
+struct PoolFloor {
+    min: usize,
+    // The checkin counter, and by pool key, the connections at the floor with the number of their latest checkin
+    held: std::sync::Mutex<(u64, HashMap<u64, HashMap<u64, u64>>)>,
+    // The pool keys a connection is being opened for, to take the place of one that left the floor
+    replenishing: std::sync::Mutex<HashSet<u64>>,
+    connections: Gauge,
+}
+
+impl PoolFloor {
+    // checkin records a checkin of a connection, returning its number if the connection is at the floor.
+    fn checkin(&self, key: u64, conn: u64) -> Option<u64> {
+        if self.min == 0 {
+            return None;
+        }
+        let mut held = self.held.lock().unwrap();
+        let (counter, keys) = &mut *held;
+        let conns = keys.entry(key).or_default();
+        if !conns.contains_key(&conn) {
+            if conns.len() >= self.min {
+                return None;
+            }
+            self.connections.inc();
+        }
+        *counter += 1;
+        conns.insert(conn, *counter);
+        Some(*counter)
+    }
+
+    // release gives up the place of a connection, unless it was checked in again since `checkin`.
+    fn release(&self, key: u64, conn: u64, checkin: u64) {
+        let mut held = self.held.lock().unwrap();
+        let Some(conns) = held.1.get_mut(&key) else {
+            return;
+        };
+        if conns.get(&conn) == Some(&checkin) {
+            conns.remove(&conn);
+            self.connections.dec();
+            if conns.is_empty() {
+                held.1.remove(&key);
+            }
+        }
+    }
+
+    // leave gives up the place of a connection that is not checked back in, returning whether its destination
+    // is now short of the floor.
+    fn leave(&self, key: u64, conn: u64) -> bool {
+        if self.min == 0 {
+            return false;
+        }
+        let mut held = self.held.lock().unwrap();
+        let Some(conns) = held.1.get_mut(&key) else {
+            return true;
+        };
+        if conns.remove(&conn).is_some() {
+            self.connections.dec();
+        }
+        let short = conns.len() < self.min;
+        if conns.is_empty() {
+            held.1.remove(&key);
+        }
+        short
+    }
+}
+
 struct ConnSpawner {
     cfg: Arc<config::Config>,
     original_source: bool,
     socket_factory: Arc<dyn SocketFactory + Send + Sync>,
     cert_manager: ScopedSecretManager,
     timeout_rx: watch::Receiver<bool>,
+    metrics: Arc<Metrics>,
+    // Identifies each connection spawned, so the floor can tell them apart across checkins
+    conn_count: AtomicU64,
 }
 
 // Does nothing but spawn new conns when asked
@@ -89,18 +168,63 @@ impl ConnSpawner {
 
         let local = self.original_source.then_some(key.src);
         let cert = self.cert_manager.fetch_certificate(&key.src_id).await?;
-        let connector = cert.outbound_connector(key.dst_id.clone())?;
-        let tcp_stream =
-            super::freebind_connect(local, key.dst, self.socket_factory.as_ref()).await?;
+        let mut connector = cert.outbound_connector(key.dst_id.clone())?;
+        if let Some(sni) = &key.sni {
+            connector = connector.with_server_name(tls::sni_server_name(sni)?);
+        }
+        let Identity::Spiffe { namespace, .. } = &key.src_id;
+        let connect = async {
+            super::freebind_connect(
+                local,
+                key.dst,
+                self.cfg.connection_timeout_for(namespace),
+                self.socket_factory.as_ref(),
+                super::ConnectOptions::from_config(&self.cfg)
+                    .with_fast_open(self.cfg.tcp_fast_open.then_some(self.metrics.as_ref()))
+                    .egress(),
+            )
+            .await
+            .map_err(Error::ConnectionFailed)
+        };
+        let (tcp_stream, source_binding) = self
+            .metrics
+            .time_setup_phase(SetupPhase::tcp_connect, connect)
+            .await?;
 
-        let tls_stream = connector.connect(tcp_stream).await?;
+        let tls_stream = self
+            .metrics
+            .time_setup_phase(SetupPhase::tls_handshake, connector.connect(tcp_stream))
+            .await
+            .map_err(|e| {
+                self.metrics.record_tls_handshake_failure(
+                    Reporter::source,
+                    TlsFailureReason::from_io_error(&e),
+                );
+                Error::TlsHandshake(e)
+            })?;
+        // Whether the SYN carried the ClientHello is only known once the peer has answered it.
+        if self.cfg.tcp_fast_open && socket::fastopen_used(tls_stream.get_ref().0).unwrap_or(false)
+        {
+            self.metrics.tfo_connections.inc();
+        }
         trace!("connector connected, handshaking");
-        let sender =
-            h2::client::spawn_connection(self.cfg.clone(), tls_stream, self.timeout_rx.clone())
-                .await?;
+        let sender = h2::client::spawn_connection(
+            self.cfg.clone(),
+            self.cfg.pool_settings(key.pool_profile.as_deref()),
+            tls_stream,
+            self.timeout_rx.clone(),
+            &self.metrics,
+        )
+        .await?;
         let client = ConnClient {
             sender,
+            id: self.conn_count.fetch_add(1, Ordering::Relaxed),
             wl_key: key,
+            source_binding,
+            expires: self
+                .cfg
+                .max_connection_lifetime
+                .map(|lifetime| tokio::time::Instant::now() + lifetime),
         };
         Ok(client)
     }
@@ -127,25 +251,50 @@ impl PoolState {
     //
     // Note that this simply removes the client ref from this pool - if other things hold client/streamrefs refs,
     // they must also drop those before the underlying connection is fully closed.
-    fn maybe_checkin_conn(&self, conn: ConnClient, pool_key: pingora_pool::ConnectionMeta) {
+    //
+    // Returns whether the connection was checked in.
+    fn maybe_checkin_conn(&self, conn: ConnClient, pool_key: pingora_pool::ConnectionMeta) -> bool {
         if conn.sender.will_be_at_max_streamcount() {
             debug!(
                 "checked out connection for {:?} is now at max streamcount; removing from pool",
                 pool_key
             );
-            return;
+            return false;
+        }
+        if conn.expired() {
+            debug!(
+                "checked out connection for {:?} reached its maximum lifetime; removing from pool",
+                pool_key
+            );
+            return false;
+        }
+        let mut release_timeout = self
+            .spawner
+            .cfg
+            .pool_settings(conn.wl_key.pool_profile.as_deref())
+            .unused_release_timeout;
+        // Connections at the floor stay open (and keepalive-probed) for as long as the destination keeps being
+        // used; every checkout starts their timeout over.
+        let conn_id = conn.id;
+        let floor_checkin = self.floor.checkin(pool_key.key, conn_id);
+        if floor_checkin.is_some() {
+            release_timeout =
+                release_timeout.max(self.spawner.cfg.pool_min_connections_idle_timeout);
         }
         let (evict, pickup) = self.connected_pool.put(&pool_key, conn);
         let rx = self.spawner.timeout_rx.clone();
         let pool_ref = self.connected_pool.clone();
         let pool_key_ref = pool_key.clone();
-        let release_timeout = self.pool_unused_release_timeout;
+        let floor = self.floor.clone();
         tokio::spawn(
             async move {
                 debug!("starting an idle timeout for connection {:?}", pool_key_ref);
                 pool_ref
                     .idle_timeout(&pool_key_ref, release_timeout, evict, rx, pickup)
                     .await;
+                if let Some(checkin) = floor_checkin {
+                    floor.release(pool_key_ref.key, conn_id, checkin);
+                }
                 debug!(
                     "connection {:?} was removed/checked out/timed out of the pool",
                     pool_key_ref
@@ -154,6 +303,50 @@ impl PoolState {
             .in_current_span(),
         );
         let _ = self.pool_notifier.send(true);
+        true
+    }
+
+    // checkin_or_replace checks a reused connection back in. If it can not be, as it reached its maximum streams or
+    // lifetime, it no longer counts towards the floor of its destination, so another connection is opened in the
+    // background to take its place. The destination was just used, so the floor is still wanted.
+    fn checkin_or_replace(
+        self: &Arc<Self>,
+        conn: ConnClient,
+        pool_key: pingora_pool::ConnectionMeta,
+    ) {
+        let (workload_key, conn_id) = (conn.wl_key.clone(), conn.id);
+        if self.maybe_checkin_conn(conn, pool_key.clone())
+            || !self.floor.leave(pool_key.key, conn_id)
+        {
+            return;
+        }
+        if !self.floor.replenishing.lock().unwrap().insert(pool_key.key) {
+            // A replacement is already on its way
+            return;
+        }
+        let state = self.clone();
+        tokio::spawn(
+            async move {
+                debug!("replenishing the pool floor for {}", workload_key);
+                match state.spawner.new_pool_conn(workload_key).await {
+                    Ok(conn) => {
+                        let pool_key = pingora_pool::ConnectionMeta::new(
+                            pool_key.key,
+                            state.pool_global_conn_count.fetch_add(1, Ordering::SeqCst),
+                        );
+                        state.maybe_checkin_conn(conn, pool_key);
+                    }
+                    Err(err) => debug!("failed to replenish the pool floor: {err}"),
+                }
+                state
+                    .floor
+                    .replenishing
+                    .lock()
+                    .unwrap()
+                    .remove(&pool_key.key);
+            }
+            .in_current_span(),
+        );
     }
 
     // Since we are using a hash key to do lookup on the inner pingora pool, do a get guard
@@ -267,11 +460,13 @@ impl PoolState {
     //
     // This is so we can backpressure correctly if 1000 tasks all demand a new connection
     // to the same key at once, and not eagerly open 1000 tunnel connections.
+    //
+    // The returned flag is true if the connection was reused, rather than newly established.
     async fn checkout_conn_under_writelock(
-        &self,
+        self: &Arc<Self>,
         workload_key: &WorkloadKey,
         pool_key: &pingora_pool::ConnectionMeta,
-    ) -> Result<Option<ConnClient>, Error> {
+    ) -> Result<Option<(ConnClient, bool)>, Error> {
         let found_conn = {
             trace!("pool connect outer map - take guard");
             let guard = self.established_conn_writelock.guard();
@@ -294,7 +489,7 @@ impl PoolState {
             workload_key,
             pool_key.key
         );
-        let returned_connection = loop {
+        let (returned_connection, reused) = loop {
             match self.guarded_get(&pool_key.key, workload_key)? {
                 Some(mut existing) => {
                     if !existing.sender.ready_to_use() {
@@ -306,20 +501,35 @@ impl PoolState {
                         );
                         continue;
                     }
+                    if existing.expired() {
+                        // No new streams are opened on it, so it closes once those in flight complete.
+                        debug!(
+                            "checked out connection for {} reached its maximum lifetime, dropping it",
+                            workload_key
+                        );
+                        continue;
+                    }
                     debug!("re-using connection for {}", workload_key);
-                    break existing;
+                    break (existing, true);
                 }
                 None => {
                     debug!("new connection needed for {}", workload_key);
-                    break self.spawner.new_pool_conn(workload_key.clone()).await?;
+                    break (
+                        self.spawner.new_pool_conn(workload_key.clone()).await?,
+                        false,
+                    );
                 }
             };
         };
 
         // For any connection, we will check in a copy and return the other unless its already maxed out
         // TODO: in the future, we can keep track of these and start to use them once they finish some streams.
-        self.maybe_checkin_conn(returned_connection.clone(), pool_key.clone());
-        Ok(Some(returned_connection))
+        if reused {
+            self.checkin_or_replace(returned_connection.clone(), pool_key.clone());
+        } else {
+            self.maybe_checkin_conn(returned_connection.clone(), pool_key.clone());
+        }
+        Ok(Some((returned_connection, reused)))
     }
 }
 
@@ -341,17 +551,25 @@ impl WorkloadHBONEPool {
         original_source: bool,
         socket_factory: Arc<dyn SocketFactory + Send + Sync>,
         cert_manager: ScopedSecretManager,
+        metrics: Arc<Metrics>,
     ) -> WorkloadHBONEPool {
         let (timeout_tx, timeout_rx) = watch::channel(false);
         let (timeout_send, timeout_recv) = watch::channel(false);
-        let pool_duration = cfg.pool_unused_release_timeout;
 
+        let floor = Arc::new(PoolFloor {
+            min: cfg.pool_min_connections_per_destination,
+            held: Default::default(),
+            replenishing: Default::default(),
+            connections: metrics.pool_floor_connections.clone(),
+        });
         let spawner = ConnSpawner {
             cfg,
             original_source,
             socket_factory,
             cert_manager,
             timeout_rx: timeout_recv.clone(),
+            metrics,
+            conn_count: AtomicU64::new(0),
         };
 
         Self {
@@ -363,22 +581,79 @@ impl WorkloadHBONEPool {
                 // the pool is expected to track before the inner hashmap resizes.
                 connected_pool: Arc::new(pingora_pool::ConnectionPool::new(500)),
                 established_conn_writelock: flurry::HashMap::new(),
-                pool_unused_release_timeout: pool_duration,
                 pool_global_conn_count: AtomicI32::new(0),
+                floor,
                 spawner,
             }),
             pool_watcher: timeout_rx,
         }
     }
 
+    /// send_request_pooled sends the request over a pooled connection, establishing one if needed, and returns
+    /// the stream along with the source address the connection was established with, and what its TLS handshake
+    /// negotiated. Whether the connection was reused is recorded against `destination_service`, which keeps the
+    /// metric's cardinality bounded regardless of how many endpoints back the service.
     pub async fn send_request_pooled(
         &mut self,
         workload_key: &WorkloadKey,
+        destination_service: Option<&ServiceDescription>,
         request: http::Request<()>,
-    ) -> Result<H2Stream, Error> {
-        let mut connection = self.connect(workload_key).await?;
+    ) -> Result<(H2Stream, SourceBinding, Option<HandshakeSummary>), Error> {
+        let (mut connection, reused) = self.connect(workload_key).await?;
+        self.state
+            .spawner
+            .metrics
+            .record_pool_checkout(destination_service, reused);
+
+        self.state
+            .spawner
+            .metrics
+            .time_setup_phase(
+                SetupPhase::hbone_connect,
+                connection.sender.send_request(request),
+            )
+            .await
+            .map(|stream| {
+                (
+                    stream,
+                    connection.source_binding,
+                    connection.sender.handshake(),
+                )
+            })
+    }
 
-        connection.sender.send_request(request).await
+    /// send_request_unpooled sends the request over a new connection of its own, which is never added to the
+    /// pool. The connection is closed once the returned stream is done.
+    pub async fn send_request_unpooled(
+        &self,
+        workload_key: &WorkloadKey,
+        destination_service: Option<&ServiceDescription>,
+        request: http::Request<()>,
+    ) -> Result<(H2Stream, SourceBinding, Option<HandshakeSummary>), Error> {
+        let spawner = &self.state.spawner;
+        let mut connection = spawner.new_pool_conn(workload_key.clone()).await?;
+        spawner.metrics.record_pool_bypass(destination_service);
+        // Dropping our handle once the request is sent leaves the stream as the last user of the connection.
+        spawner
+            .metrics
+            .time_setup_phase(
+                SetupPhase::hbone_connect,
+                connection.sender.send_request(request),
+            )
+            .await
+            .map(|stream| {
+                (
+                    stream,
+                    connection.source_binding,
+                    connection.sender.handshake(),
+                )
+            })
+    }
+
+    /// warm ensures a connection for the key is established and in the pool, without sending a request.
+    /// As this checks the connection out and back in, it also restarts its idle timeout.
+    pub async fn warm(&mut self, workload_key: &WorkloadKey) -> Result<(), Error> {
+        self.connect(workload_key).await.map(|_| ())
     }
 
     // Obtain a pooled connection. Will prefer to retrieve an existing conn from the pool, but
@@ -387,7 +662,9 @@ impl WorkloadHBONEPool {
     //
     // If many `connects` request a connection to the same dest at once, all will wait until exactly
     // one connection is created, before deciding if they should create more or just use that one.
-    async fn connect(&mut self, workload_key: &WorkloadKey) -> Result<ConnClient, Error> {
+    //
+    // Returns the connection, and whether it was reused rather than newly established.
+    async fn connect(&mut self, workload_key: &WorkloadKey) -> Result<(ConnClient, bool), Error> {
         trace!("pool connect START");
         // TODO BML this may not be collision resistant, or a fast hash. It should be resistant enough for workloads tho.
         // We are doing a deep-equals check at the end to mitigate any collisions, will see about bumping Pingora
@@ -464,7 +741,7 @@ impl WorkloadHBONEPool {
             .start_conn_if_win_writelock(workload_key, &pool_key)
             .await?
         {
-            Some(client) => client,
+            Some(client) => (client, false),
             None => {
                 debug!("we didn't win the lock, something else is creating a conn, wait for it");
                 // If we get here, it means the following are true:
@@ -513,11 +790,23 @@ impl WorkloadHBONEPool {
 // send requests over some underlying stream using some underlying http/2 client
 struct ConnClient {
     sender: H2ConnectClient,
+    // Identifies the underlying connection, which every clone of this client shares
+    id: u64,
     // A WL key may have many clients, but every client has no more than one WL key
     wl_key: WorkloadKey, // the WL key associated with this client.
+    // Which source address the underlying connection was established with. Every stream on the connection
+    // shares it, so requests reusing the connection report it as well.
+    source_binding: SourceBinding,
+    // When the connection reaches its maximum lifetime, after which it is no longer used for new streams
+    expires: Option<tokio::time::Instant>,
 }
 
 impl ConnClient {
+    fn expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= tokio::time::Instant::now())
+    }
+
     pub fn is_for_workload(&self, wl_key: &WorkloadKey) -> Result<(), crate::proxy::Error> {
         if !(self.wl_key == *wl_key) {
             Err(crate::proxy::Error::Generic(
@@ -546,6 +835,10 @@ pub struct WorkloadKey {
     // Because we spoof the source IP, we need to key on this as well. Note: for in-pod its already per-pod
     // pools anyways.
     pub src: IpAddr,
+    // The SNI to present, if the destination requires one.
+    pub sni: Option<Strng>,
+    // The pool profile the connection is tuned with, if the destination port has one.
+    pub pool_profile: Option<Strng>,
 }
 
 impl Display for WorkloadKey {
@@ -581,7 +874,7 @@ mod test {
 
     use tracing::{error, Instrument};
 
-    use crate::test_helpers::helpers::initialize_telemetry;
+    use crate::test_helpers::helpers::{initialize_telemetry, test_proxy_metrics};
 
     use crate::drain::DrainWatcher;
     use ztunnel::test_helpers::*;
@@ -629,11 +922,80 @@ mod test {
         spawn_clients_concurrently(pool.clone(), key.clone(), srv.addr, 2).await;
         assert_opens_drops!(srv, 1, 0);
 
+        let metrics = pool.state.spawner.metrics.clone();
+        let labels = Default::default();
+        assert_eq!(metrics.pool_new_connection.get_or_create(&labels).get(), 1);
+        assert_eq!(metrics.pool_stream_reuse.get_or_create(&labels).get(), 3);
+
         // Once we drop the pool, we should drop the connections as well
         drop(pool);
         assert_opens_drops!(srv, 1, 1);
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn unpooled_requests_get_dedicated_connections() {
+        let (pool, mut srv) = setup_test(3).await;
+
+        let key = key(&srv, 1);
+        let req = || {
+            hyper::Request::builder()
+                .uri(format!("{}", srv.addr))
+                .method(hyper::Method::CONNECT)
+                .version(hyper::Version::HTTP_2)
+                .body(())
+                .unwrap()
+        };
+        let c1 = pool
+            .send_request_unpooled(&key, None, req())
+            .await
+            .expect("connect should succeed");
+        let c2 = pool
+            .send_request_unpooled(&key, None, req())
+            .await
+            .expect("connect should succeed");
+        assert_opens_drops!(srv, 2, 0);
+
+        // The connections close with their stream, without waiting for the pool to evict them
+        drop(c1);
+        drop(c2);
+        assert_opens_drops!(srv, 2, 2);
+
+        // Nothing was left in the pool for later requests to reuse
+        test_client(pool.clone(), key, srv.addr).await;
+        assert_opens_drops!(srv, 3, 0);
+
+        let metrics = pool.state.spawner.metrics.clone();
+        let labels = Default::default();
+        assert_eq!(
+            metrics.pool_bypass_connection.get_or_create(&labels).get(),
+            2
+        );
+        assert_eq!(metrics.pool_new_connection.get_or_create(&labels).get(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn warmed_connections_are_used_by_requests() {
+        let (mut pool, mut srv) = setup_test(3).await;
+
+        let key = key(&srv, 1);
+
+        // Warming opens a connection without sending a request, and warming again keeps the same one
+        pool.warm(&key).await.expect("warm should succeed");
+        pool.warm(&key).await.expect("warm should succeed");
+        assert_opens_drops!(srv, 1, 0);
+
+        // A request then finds the warm connection in the pool
+        test_client(pool.clone(), key, srv.addr).await;
+        assert_opens_drops!(srv, 1, 0);
+
+        let metrics = pool.state.spawner.metrics.clone();
+        let labels = Default::default();
+        assert_eq!(metrics.pool_new_connection.get_or_create(&labels).get(), 1);
+
+        drop(pool);
+        assert_opens_drops!(srv, 1, 1);
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn unique_keys_have_unique_connections() {
         let (pool, mut srv) = setup_test(3).await;
@@ -671,6 +1033,71 @@ mod test {
         assert_opens_drops!(srv, 5, 1);
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn retry_after_connect_failure() {
+        let sf = Arc::new(faults::FaultInjectingSocketFactory::new(Arc::new(
+            crate::proxy::DefaultSocketFactory::default(),
+        )));
+        let (mut pool, mut srv) =
+            setup_test_with_socket_factory(3, Duration::from_secs(100), sf.clone()).await;
+
+        let key1 = key(&srv, 1);
+        let req = || {
+            hyper::Request::builder()
+                .uri(format!("{}", srv.addr))
+                .method(hyper::Method::CONNECT)
+                .version(hyper::Version::HTTP_2)
+                .body(())
+                .unwrap()
+        };
+
+        // The upstream refuses connections, so the request fails without anything being opened
+        sf.fail_connect(srv.addr, libc::ECONNREFUSED);
+        let Err(Error::ConnectionFailed(err)) = pool.send_request_pooled(&key1, None, req()).await
+        else {
+            panic!("connect should fail");
+        };
+        assert_eq!(err.raw_os_error(), Some(libc::ECONNREFUSED));
+        assert_opens_drops!(srv, 0, 0);
+
+        // Once the upstream recovers, retrying on the same key should open a fresh connection
+        sf.clear();
+        test_client(pool.clone(), key1.clone(), srv.addr).await;
+        assert_opens_drops!(srv, 1, 0);
+
+        // With every other connection attempt dropped, a retry after a failure succeeds
+        sf.drop_every(2);
+        let key2 = key(&srv, 2);
+        assert!(pool.send_request_pooled(&key2, None, req()).await.is_ok());
+        let key3 = key(&srv, 3);
+        assert!(pool.send_request_pooled(&key3, None, req()).await.is_err());
+        assert!(pool.send_request_pooled(&key3, None, req()).await.is_ok());
+        assert_opens_drops!(srv, 3, 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn fast_open_connect() {
+        let sf = Arc::new(faults::FaultInjectingSocketFactory::new(Arc::new(
+            crate::proxy::DefaultSocketFactory::default(),
+        )));
+        let cfg = crate::config::Config {
+            tcp_fast_open: true,
+            ..crate::config::parse_config().unwrap()
+        };
+        let (pool, mut srv) = setup_test_with_config(cfg, sf.clone()).await;
+
+        // The connection is made with TFO enabled, and the request over it goes through as usual
+        test_client(pool.clone(), key(&srv, 1), srv.addr).await;
+        assert_opens_drops!(srv, 1, 0);
+        assert_eq!(sf.fast_open_connects(), 1);
+
+        // The test server does not accept TFO, so no data went in the SYN
+        let metrics = pool.state.spawner.metrics.clone();
+        assert_eq!(metrics.tfo_connections.get(), 0);
+        assert_eq!(metrics.tfo_fallbacks.get(), 0);
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn server_goaway() {
         let (pool, mut srv) = setup_test(2).await;
@@ -760,6 +1187,155 @@ mod test {
         assert_opens_drops!(srv, 1, 1);
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn max_connection_lifetime() {
+        let cfg = crate::config::Config {
+            pool_max_streams_per_conn: 3,
+            pool_unused_release_timeout: Duration::from_secs(100),
+            max_connection_lifetime: Some(Duration::from_millis(100)),
+            ..crate::config::parse_config().unwrap()
+        };
+        let sock_fact = Arc::new(crate::proxy::DefaultSocketFactory::default());
+        let (pool, mut srv) = setup_test_with_config(cfg, sock_fact).await;
+        let key = key(&srv, 1);
+
+        spawn_clients_concurrently(pool.clone(), key.clone(), srv.addr, 2).await;
+        assert_opens_drops!(srv, 1, 0);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // Although it is not idle long enough to be evicted, the connection is past its lifetime, so a new one is
+        // opened, and the old one closes.
+        spawn_clients_concurrently(pool.clone(), key.clone(), srv.addr, 2).await;
+        assert_opens_drops!(srv, 2, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_eviction_with_floor() {
+        let cfg = crate::config::Config {
+            pool_max_streams_per_conn: 3,
+            pool_unused_release_timeout: Duration::from_millis(100),
+            pool_min_connections_per_destination: 1,
+            pool_min_connections_idle_timeout: Duration::from_secs(1),
+            ..crate::config::parse_config().unwrap()
+        };
+        let (pool, mut srv) =
+            setup_test_with_config(cfg, Arc::new(crate::proxy::DefaultSocketFactory::default()))
+                .await;
+        let key = key(&srv, 1);
+        let floor = pool.state.spawner.metrics.pool_floor_connections.clone();
+
+        // The connection is at the floor, so it outlives the unused release timeout.
+        spawn_clients_concurrently(pool.clone(), key.clone(), srv.addr, 2).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_opens_drops!(srv, 1, 0);
+        assert_eq!(floor.get(), 1);
+
+        // Using it again starts the floor timeout over.
+        spawn_clients_concurrently(pool.clone(), key.clone(), srv.addr, 2).await;
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        assert_opens_drops!(srv, 1, 0);
+
+        // Without further traffic, it is released after the floor timeout.
+        assert_opens_drops!(srv, 1, 1);
+        assert_eq!(floor.get(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn floor_replenished() {
+        let cfg = crate::config::Config {
+            pool_max_streams_per_conn: 2,
+            pool_unused_release_timeout: Duration::from_millis(100),
+            pool_min_connections_per_destination: 1,
+            pool_min_connections_idle_timeout: Duration::from_secs(1),
+            ..crate::config::parse_config().unwrap()
+        };
+        let (mut pool, mut srv) =
+            setup_test_with_config(cfg, Arc::new(crate::proxy::DefaultSocketFactory::default()))
+                .await;
+        let key = key(&srv, 1);
+        let floor = pool.state.spawner.metrics.pool_floor_connections.clone();
+        let req = || {
+            hyper::Request::builder()
+                .uri(format!("{}", srv.addr))
+                .method(hyper::Method::CONNECT)
+                .version(hyper::Version::HTTP_2)
+                .body(())
+                .unwrap()
+        };
+
+        let first = pool.send_request_pooled(&key, None, req()).await.unwrap();
+        assert_eq!(floor.get(), 1);
+        // The connection reaches its maximum streams with this one, so it leaves the pool, and another is opened in
+        // the background to take its place at the floor.
+        let second = pool.send_request_pooled(&key, None, req()).await.unwrap();
+        while floor.get() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_opens_drops!(srv, 2, 0);
+
+        // The first connection closes once its streams are done, while the replacement is kept.
+        drop((first, second));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_opens_drops!(srv, 2, 1);
+        assert_eq!(floor.get(), 1);
+
+        // New streams use the replacement.
+        let third = pool.send_request_pooled(&key, None, req()).await.unwrap();
+        drop(third);
+        assert_opens_drops!(srv, 2, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn pool_profiles() {
+        let profile = |name: &str, port, max_streams, idle| crate::config::PoolProfile {
+            name: name.into(),
+            ports: port..=port,
+            max_streams_per_conn: max_streams,
+            unused_release_timeout: idle,
+            h2_keepalive_interval: None,
+            h2_keepalive_timeout: None,
+        };
+        let cfg = crate::config::Config {
+            pool_max_streams_per_conn: 3,
+            pool_unused_release_timeout: Duration::from_secs(100),
+            pool_profiles: vec![
+                profile("short", 6379, None, Some(Duration::from_millis(100))),
+                profile("single", 6380, Some(1), None),
+            ],
+            ..crate::config::parse_config().unwrap()
+        };
+        let keys = [443, 6379, 6380].map(|port| cfg.pool_profile(port).map(|p| p.name.clone()));
+        assert_eq!(
+            keys,
+            [
+                None,
+                Some(Strng::from("short")),
+                Some(Strng::from("single"))
+            ]
+        );
+        let (pool, mut srv) =
+            setup_test_with_config(cfg, Arc::new(crate::proxy::DefaultSocketFactory::default()))
+                .await;
+        let [default_key, short_key, single_key] = keys.map(|pool_profile| WorkloadKey {
+            pool_profile,
+            ..key(&srv, 1)
+        });
+
+        // Both streams share a connection, which stays in the pool.
+        spawn_clients_concurrently(pool.clone(), default_key, srv.addr, 2).await;
+        assert_opens_drops!(srv, 1, 0);
+
+        // Profiles get connections of their own. This one is evicted after the profile's shorter idle timeout.
+        spawn_clients_concurrently(pool.clone(), short_key, srv.addr, 2).await;
+        assert_opens_drops!(srv, 2, 1);
+
+        // This profile allows a single stream per connection, so each stream gets its own, closed with it.
+        spawn_clients_concurrently(pool.clone(), single_key, srv.addr, 2).await;
+        assert_opens_drops!(srv, 4, 2);
+
+        drop(pool);
+        assert_opens_drops!(srv, 4, 1);
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn idle_eviction_with_persistent() {
         let (pool, mut srv) = setup_test_with_idle(4, Duration::from_millis(100)).await;
@@ -804,7 +1380,7 @@ mod test {
             let start = Instant::now();
 
             let c1 = pool
-                .send_request_pooled(&key.clone(), req())
+                .send_request_pooled(&key.clone(), None, req())
                 .instrument(tracing::debug_span!("client", request = req_num))
                 .await
                 .expect("connect should succeed");
@@ -835,10 +1411,15 @@ mod test {
 
         let start = Instant::now();
 
-        let _c1 = pool
-            .send_request_pooled(&key.clone(), req())
+        let (_c1, binding, handshake) = pool
+            .send_request_pooled(&key.clone(), None, req())
             .await
             .expect("connect should succeed");
+        // Original source is not used by the test pools.
+        assert_eq!(binding, SourceBinding::none);
+        let handshake = handshake.expect("handshake completes before the response is read");
+        assert_eq!(handshake.version(), "TLSv1_3");
+        assert_eq!(handshake.alpn, tls::Alpn::H2);
         debug!(
             "client spent {}ms waiting for conn",
             start.elapsed().as_millis()
@@ -862,7 +1443,10 @@ mod test {
 
         let start = Instant::now();
 
-        let c1 = pool.send_request_pooled(&key.clone(), req()).await.unwrap();
+        let c1 = pool
+            .send_request_pooled(&key.clone(), None, req())
+            .await
+            .unwrap();
         debug!(
             "client spent {}ms waiting for conn",
             start.elapsed().as_millis()
@@ -984,23 +1568,44 @@ mod test {
         max_conns: u16,
         idle: Duration,
     ) -> (WorkloadHBONEPool, TestServer) {
-        initialize_telemetry();
-        let conn_counter: Arc<AtomicU32> = Arc::new(AtomicU32::new(0));
-        let (drop_tx, drop_rx) = tokio::sync::mpsc::unbounded_channel::<()>();
-        let (goaway_tx, goaway_rx) = oneshot::channel::<()>();
-        let addr = spawn_server(conn_counter.clone(), drop_tx, goaway_rx).await;
+        let sock_fact = Arc::new(crate::proxy::DefaultSocketFactory::default());
+        setup_test_with_socket_factory(max_conns, idle, sock_fact).await
+    }
 
+    async fn setup_test_with_socket_factory(
+        max_conns: u16,
+        idle: Duration,
+        sock_fact: Arc<dyn SocketFactory + Send + Sync>,
+    ) -> (WorkloadHBONEPool, TestServer) {
         let cfg = crate::config::Config {
             pool_max_streams_per_conn: max_conns,
             pool_unused_release_timeout: idle,
             ..crate::config::parse_config().unwrap()
         };
-        let sock_fact = Arc::new(crate::proxy::DefaultSocketFactory);
+        setup_test_with_config(cfg, sock_fact).await
+    }
+
+    async fn setup_test_with_config(
+        cfg: crate::config::Config,
+        sock_fact: Arc<dyn SocketFactory + Send + Sync>,
+    ) -> (WorkloadHBONEPool, TestServer) {
+        initialize_telemetry();
+        let conn_counter: Arc<AtomicU32> = Arc::new(AtomicU32::new(0));
+        let (drop_tx, drop_rx) = tokio::sync::mpsc::unbounded_channel::<()>();
+        let (goaway_tx, goaway_rx) = oneshot::channel::<()>();
+        let addr = spawn_server(conn_counter.clone(), drop_tx, goaway_rx).await;
+
         let cert_mgr = proxy::ScopedSecretManager::new(identity::mock::new_secret_manager(
             Duration::from_secs(10),
         ));
         let original_src = false; // for testing, not needed
-        let pool = WorkloadHBONEPool::new(Arc::new(cfg), original_src, sock_fact, cert_mgr);
+        let pool = WorkloadHBONEPool::new(
+            Arc::new(cfg),
+            original_src,
+            sock_fact,
+            cert_mgr,
+            test_proxy_metrics(),
+        );
         let server = TestServer {
             conn_counter,
             drop_rx,
@@ -1023,6 +1628,8 @@ mod test {
             dst_id: vec![Identity::default()],
             src: IpAddr::from([127, 0, 0, ip]),
             dst: srv.addr,
+            sni: None,
+            pool_profile: None,
         }
     }
 }
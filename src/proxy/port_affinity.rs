@@ -0,0 +1,136 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use prometheus_client::metrics::counter::Counter;
+use tokio::net::TcpStream;
+
+use crate::proxy::Metrics;
+
+// Bounds on how many ports are remembered. Once reached, further ports are simply not reused.
+const MAX_DESTINATIONS: usize = 4096;
+const MAX_PORTS_PER_DESTINATION: usize = 16;
+
+// Connections are keyed by the source IP they are bound to, if any, and the destination.
+type Key = (Option<IpAddr>, SocketAddr);
+
+/// PortAffinity remembers the source ports of closed passthrough connections, so a new connection to the same
+/// destination can reuse one. The new connection then has the same 5-tuple as a closed one, and takes over its
+/// conntrack entry rather than adding another, which keeps conntrack tables small under many short connections.
+///
+/// Reusing a port while its previous connection is in TIME_WAIT requires `net.ipv4.tcp_tw_reuse`; otherwise
+/// the connect fails and we fall back to a fresh port. The tradeoff is that a late packet of the previous
+/// connection may be taken as part of the new one by conntrack and other middleboxes.
+pub struct PortAffinity {
+    released: Mutex<HashMap<Key, Vec<u16>>>,
+    reused: Counter,
+    failed: Counter,
+}
+
+impl PortAffinity {
+    pub fn new(metrics: &Metrics) -> Self {
+        PortAffinity {
+            released: Mutex::new(HashMap::new()),
+            reused: metrics.source_port_reused.clone(),
+            failed: metrics.source_port_reuse_failed.clone(),
+        }
+    }
+
+    /// take returns a port released by a previous connection from `local` to `dest`, if there is one.
+    pub fn take(&self, local: Option<IpAddr>, dest: SocketAddr) -> Option<u16> {
+        let mut released = self.released.lock().unwrap();
+        let ports = released.get_mut(&(local, dest))?;
+        let port = ports.pop();
+        if ports.is_empty() {
+            released.remove(&(local, dest));
+        }
+        port
+    }
+
+    /// record_reuse counts whether connecting with a port from `take` succeeded.
+    pub fn record_reuse(&self, ok: bool) {
+        if ok {
+            self.reused.inc()
+        } else {
+            self.failed.inc()
+        }
+    }
+
+    /// lease holds the source port of `stream`, releasing it for reuse once the lease is dropped, when the
+    /// connection is closed.
+    pub fn lease(
+        self: &Arc<Self>,
+        local: Option<IpAddr>,
+        dest: SocketAddr,
+        stream: &TcpStream,
+    ) -> Option<PortLease> {
+        let port = stream.local_addr().ok()?.port();
+        Some(PortLease {
+            affinity: self.clone(),
+            key: (local, dest),
+            port,
+        })
+    }
+
+    fn release(&self, key: Key, port: u16) {
+        let mut released = self.released.lock().unwrap();
+        if released.len() >= MAX_DESTINATIONS && !released.contains_key(&key) {
+            return;
+        }
+        let ports = released.entry(key).or_default();
+        if ports.len() < MAX_PORTS_PER_DESTINATION && !ports.contains(&port) {
+            ports.push(port);
+        }
+    }
+}
+
+pub struct PortLease {
+    affinity: Arc<PortAffinity>,
+    key: Key,
+    port: u16,
+}
+
+impl Drop for PortLease {
+    fn drop(&mut self) {
+        self.affinity.release(self.key, self.port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::helpers::test_proxy_metrics;
+
+    #[tokio::test]
+    async fn released_ports_are_reused() {
+        let metrics = test_proxy_metrics();
+        let affinity = Arc::new(PortAffinity::new(&metrics));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(dest).await.unwrap();
+        let port = stream.local_addr().unwrap().port();
+
+        let lease = affinity.lease(None, dest, &stream).unwrap();
+        // Nothing to reuse while the connection is open.
+        assert_eq!(affinity.take(None, dest), None);
+        drop(lease);
+        // Ports are only reused for the same destination and source.
+        assert_eq!(affinity.take(Some(dest.ip()), dest), None);
+        assert_eq!(affinity.take(None, dest), Some(port));
+        assert_eq!(affinity.take(None, dest), None);
+    }
+}
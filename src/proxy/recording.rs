@@ -0,0 +1,225 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connection recording writes the raw bytes relayed on a connection to a file, to reproduce data path
+//! issues offline.
+//!
+//! # Privacy
+//!
+//! THIS IS A DEBUGGING TOOL, AND IS NOT SAFE TO USE WITH SENSITIVE DATA. ztunnel terminates HBONE, so a
+//! recording holds the decrypted payload of the connection: passwords, tokens, personal data and anything else
+//! the applications exchange ends up in plain form on the node's disk, readable by anyone with access to it.
+//! Nothing is redacted. Recordings are never removed by ztunnel.
+//!
+//! Only connections matching the targeted logging rules (see `telemetry::targeted`) are recorded, so anyone
+//! able to change those rules through the admin endpoint decides which connections are captured. Recording is
+//! only available in builds with the `connection-recording` feature, and must additionally be enabled with
+//! `DANGEROUS_RECORD_CONNECTIONS_DIR`.
+//!
+//! # Format
+//!
+//! Each recording is a file named after the connection ID. The first line describes the connection, and is
+//! followed by the chunks of data in the order they were relayed, each a header line and then the raw bytes:
+//!
+//! ```text
+//! > <offset> <length>   data from the downstream, sent to the upstream
+//! < <offset> <length>   data from the upstream, sent to the downstream
+//! ```
+//!
+//! Recording never holds up the connection. Chunks are queued for a writer task, and dropped if it falls
+//! behind; the offset of each chunk within its direction shows where data is missing.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use bytes::Bytes;
+use prometheus_client::metrics::counter::Counter;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::{info, warn};
+
+use crate::proxy::{ConnectionId, Metrics};
+
+// The number of chunks that may be queued for the writer. Each chunk is at most one read buffer.
+const QUEUE_SIZE: usize = 64;
+
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+
+/// enable turns on recording of targeted connections, for the rest of the process' lifetime.
+pub fn enable(recorder: Recorder) {
+    warn!(
+        dir=%recorder.dir.display(),
+        "connection recording is enabled; the payload of targeted connections is written to disk unencrypted"
+    );
+    let _ = RECORDER.set(recorder);
+}
+
+/// start begins recording a connection, if recording is enabled.
+pub fn start(id: ConnectionId, src: SocketAddr, dst: SocketAddr) -> Option<Recording> {
+    RECORDER.get().map(|r| r.start(id, src, dst))
+}
+
+/// Recorder creates recordings in a directory.
+pub struct Recorder {
+    dir: PathBuf,
+    // The number of bytes recorded per connection, in both directions combined
+    max_bytes: u64,
+    dropped: Counter,
+}
+
+impl Recorder {
+    pub fn new(dir: PathBuf, max_bytes: u64, metrics: &Metrics) -> Self {
+        Recorder {
+            dir,
+            max_bytes,
+            dropped: metrics.recording_dropped_bytes.clone(),
+        }
+    }
+
+    pub fn start(&self, id: ConnectionId, src: SocketAddr, dst: SocketAddr) -> Recording {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let path = self.dir.join(format!("{id}.rec"));
+        let header = format!("connection {id} from {src} to {dst}\n");
+        tokio::spawn(async move {
+            match write(&path, header, rx).await {
+                Ok(()) => info!(path=%path.display(), "connection recorded"),
+                Err(e) => warn!(path=%path.display(), "failed to record connection: {e}"),
+            }
+        });
+        Recording {
+            chunks: tx,
+            remaining: AtomicU64::new(self.max_bytes),
+            offsets: Default::default(),
+            dropped: self.dropped.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Data from the downstream, sent to the upstream.
+    Upstream,
+    /// Data from the upstream, sent to the downstream.
+    Downstream,
+}
+
+struct Chunk {
+    direction: Direction,
+    offset: u64,
+    data: Bytes,
+}
+
+/// Recording records a single connection. The recording is complete once this is dropped.
+pub struct Recording {
+    chunks: mpsc::Sender<Chunk>,
+    // The number of bytes that may still be recorded
+    remaining: AtomicU64,
+    // The offset of the next chunk in each direction, indexed by Direction
+    offsets: [AtomicU64; 2],
+    dropped: Counter,
+}
+
+impl Recording {
+    /// record queues a chunk of relayed data. As chunks are reference counted, this does not copy the data.
+    pub fn record(&self, direction: Direction, mut data: Bytes) {
+        let len = data.len() as u64;
+        let offset = self.offsets[direction as usize].fetch_add(len, Ordering::Relaxed);
+        let Ok(remaining) =
+            self.remaining
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+                    (remaining > 0).then(|| remaining.saturating_sub(len))
+                })
+        else {
+            return;
+        };
+        if len > remaining {
+            data.truncate(remaining as usize);
+        }
+        let chunk = Chunk {
+            direction,
+            offset,
+            data,
+        };
+        if let Err(TrySendError::Full(chunk)) = self.chunks.try_send(chunk) {
+            self.dropped.inc_by(chunk.data.len() as u64);
+        }
+    }
+}
+
+async fn write(
+    path: &Path,
+    header: String,
+    mut chunks: mpsc::Receiver<Chunk>,
+) -> std::io::Result<()> {
+    // Never overwrite an earlier recording, such as one from before a restart.
+    let file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await?;
+    let mut out = tokio::io::BufWriter::new(file);
+    out.write_all(header.as_bytes()).await?;
+    while let Some(chunk) = chunks.recv().await {
+        let marker = match chunk.direction {
+            Direction::Upstream => '>',
+            Direction::Downstream => '<',
+        };
+        let line = format!("{marker} {} {}\n", chunk.offset, chunk.data.len());
+        out.write_all(line.as_bytes()).await?;
+        out.write_all(&chunk.data).await?;
+    }
+    out.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::helpers::test_proxy_metrics;
+
+    #[tokio::test]
+    async fn records_both_directions_up_to_limit() {
+        let dir = std::env::temp_dir().join(format!("ztunnel-recording-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let metrics = test_proxy_metrics();
+        let recorder = Recorder::new(dir.clone(), 12, &metrics);
+        let id = ConnectionId::next();
+        let recording = recorder.start(
+            id,
+            "127.0.0.1:1234".parse().unwrap(),
+            "127.0.0.2:80".parse().unwrap(),
+        );
+        recording.record(Direction::Upstream, Bytes::from_static(b"ping"));
+        recording.record(Direction::Downstream, Bytes::from_static(b"pong"));
+        // Only the first 4 bytes fit within the limit.
+        recording.record(Direction::Upstream, Bytes::from_static(b"truncated"));
+        recording.record(Direction::Upstream, Bytes::from_static(b"dropped"));
+        drop(recording);
+
+        let path = dir.join(format!("{id}.rec"));
+        let expected = format!(
+            "connection {id} from 127.0.0.1:1234 to 127.0.0.2:80\n> 0 4\nping< 0 4\npong> 4 4\ntrun"
+        );
+        crate::test_helpers::assert_eventually(
+            std::time::Duration::from_secs(1),
+            || async { std::fs::read_to_string(&path).unwrap_or_default() },
+            expected,
+        )
+        .await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(metrics.recording_dropped_bytes.get(), 0);
+    }
+}
@@ -0,0 +1,191 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+
+// Enough for the request line of all but the most unusual requests; longer ones are treated as opaque.
+const SNIFF_BUFFER_SIZE: usize = 1024;
+
+// Headers take more room than the request line; those past this are not seen.
+const SNIFF_HEADERS_BUFFER_SIZE: usize = 8192;
+
+const METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+/// HttpRequest is what we learned about the first HTTP/1 request on a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    /// The request target, without its query string, which often carries credentials.
+    pub path: String,
+    /// The status of the response, if one was seen.
+    pub status: Option<u16>,
+}
+
+/// sniff_http peeks at the first bytes the client sent, without consuming them, and returns the request if they
+/// are an HTTP/1 request line. If nothing arrives within the timeout, as with protocols where the server speaks
+/// first, the connection is treated as opaque.
+pub async fn sniff_http(stream: &TcpStream, timeout: Duration) -> Option<HttpRequest> {
+    let mut buf = [0u8; SNIFF_BUFFER_SIZE];
+    let n = tokio::time::timeout(timeout, stream.peek(&mut buf))
+        .await
+        .ok()?
+        .ok()?;
+    parse_request_line(&buf[..n])
+}
+
+/// sniff_headers peeks at the first bytes the client sent, like sniff_http, and returns the headers of the
+/// request if they are an HTTP/1 request. Only the headers the client has sent by the time they are read are
+/// seen.
+pub async fn sniff_headers(stream: &TcpStream, timeout: Duration) -> Option<http::HeaderMap> {
+    let mut buf = vec![0u8; SNIFF_HEADERS_BUFFER_SIZE];
+    let n = tokio::time::timeout(timeout, stream.peek(&mut buf))
+        .await
+        .ok()?
+        .ok()?;
+    parse_headers(&buf[..n])
+}
+
+// parse_headers parses the header lines following the request line. A header line is only used once it is
+// complete, and malformed lines are skipped.
+fn parse_headers(buf: &[u8]) -> Option<http::HeaderMap> {
+    parse_request_line(buf)?;
+    let mut headers = http::HeaderMap::new();
+    let lines: Vec<_> = buf.split(|b| *b == b'\n').collect();
+    // Skip the request line, and the last line, which is either empty or incomplete.
+    for line in &lines[1..lines.len() - 1] {
+        let Some(line) = line.strip_suffix(b"\r") else {
+            break;
+        };
+        if line.is_empty() {
+            break;
+        }
+        let Some(colon) = line.iter().position(|b| *b == b':') else {
+            continue;
+        };
+        let name = http::HeaderName::from_bytes(&line[..colon]);
+        let value = std::str::from_utf8(&line[colon + 1..])
+            .ok()
+            .and_then(|v| http::HeaderValue::from_str(v.trim()).ok());
+        if let (Ok(name), Some(value)) = (name, value) {
+            headers.append(name, value);
+        }
+    }
+    Some(headers)
+}
+
+// parse_request_line is deliberately strict, so binary protocols that happen to start with the bytes of a
+// method are not mistaken for HTTP: the whole request line, including the version and CRLF, must be present
+// and well formed.
+fn parse_request_line(buf: &[u8]) -> Option<HttpRequest> {
+    let end = buf.windows(2).position(|w| w == b"\r\n")?;
+    let line = std::str::from_utf8(&buf[..end]).ok()?;
+    let mut parts = line.split(' ');
+    let (method, target, version) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some()
+        || !METHODS.contains(&method)
+        || !matches!(version, "HTTP/1.0" | "HTTP/1.1")
+        || target.is_empty()
+        || !target.bytes().all(|b| b.is_ascii_graphic())
+    {
+        return None;
+    }
+    let path = target.split('?').next().unwrap_or_default();
+    Some(HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        status: None,
+    })
+}
+
+/// parse_status_line returns the status code of an HTTP/1 response, given its first bytes.
+pub fn parse_status_line(buf: &[u8]) -> Option<u16> {
+    let rest = buf
+        .strip_prefix(b"HTTP/1.1 ")
+        .or_else(|| buf.strip_prefix(b"HTTP/1.0 "))?;
+    let code = rest.get(..3)?;
+    if !code.iter().all(u8::is_ascii_digit) || !matches!(rest.get(3), Some(b' ' | b'\r')) {
+        return None;
+    }
+    std::str::from_utf8(code).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_line() {
+        assert_eq!(
+            parse_request_line(b"GET /api/v1/items?token=secret HTTP/1.1\r\nHost: a\r\n\r\n"),
+            Some(HttpRequest {
+                method: "GET".to_string(),
+                path: "/api/v1/items".to_string(),
+                status: None,
+            })
+        );
+        assert!(parse_request_line(b"OPTIONS * HTTP/1.0\r\n").is_some());
+
+        for opaque in [
+            // Incomplete request line.
+            &b"GET / HTTP/1.1"[..],
+            // Binary data starting with the bytes of a method.
+            b"GET\x00\x01\x02\x03\r\n",
+            b"POST \xff\xfe HTTP/1.1\r\n",
+            // Unknown method or version.
+            b"get / HTTP/1.1\r\n",
+            b"BREW / HTTP/1.1\r\n",
+            b"GET / HTTP/2.0\r\n",
+            b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n",
+            // Extra fields.
+            b"GET / / HTTP/1.1\r\n",
+            b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03",
+        ] {
+            assert_eq!(parse_request_line(opaque), None, "{opaque:?}");
+        }
+    }
+
+    #[test]
+    fn headers() {
+        let headers = parse_headers(
+            b"GET / HTTP/1.1\r\nHost: a\r\nX-Session:  alice \r\nbad line\r\nx-session: bob\r\n\r\nbody",
+        )
+        .unwrap();
+        assert_eq!(headers.get("host").unwrap(), "a");
+        let sessions: Vec<_> = headers.get_all("x-session").iter().collect();
+        assert_eq!(sessions, ["alice", "bob"]);
+
+        // Only complete lines are used.
+        let headers = parse_headers(b"GET / HTTP/1.1\r\nHost: a\r\nX-Session: al").unwrap();
+        assert_eq!(headers.get("host").unwrap(), "a");
+        assert_eq!(headers.get("x-session"), None);
+
+        assert_eq!(
+            parse_headers(b"\x16\x03\x01\x02\x00\r\nHost: a\r\n\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn status_line() {
+        assert_eq!(parse_status_line(b"HTTP/1.1 200 OK\r\n"), Some(200));
+        assert_eq!(parse_status_line(b"HTTP/1.0 404\r\n"), Some(404));
+        assert_eq!(parse_status_line(b"HTTP/1.1 20"), None);
+        assert_eq!(parse_status_line(b"HTTP/1.1 2000 OK\r\n"), None);
+        assert_eq!(parse_status_line(b"SSH-2.0-OpenSSH\r\n"), None);
+    }
+}
@@ -15,42 +15,60 @@
 use anyhow::Result;
 use byteorder::{BigEndian, ByteOrder};
 
+use bytes::Bytes;
 use hickory_proto::op::{Message, MessageType, Query};
 use hickory_proto::rr::{Name, RecordType};
 use hickory_proto::serialize::binary::BinDecodable;
 use hickory_server::authority::MessageRequest;
 use hickory_server::server::{Protocol, Request};
-use std::net::{IpAddr, SocketAddr};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::Instant;
 
-use crate::dns::resolver::Resolver;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
-use tokio::sync::watch;
-use tracing::{debug, error, info, info_span, Instrument};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, info_span, trace, Instrument};
 
 use crate::config;
 use crate::drain::run_with_drain;
 use crate::drain::DrainWatcher;
 use crate::proxy::outbound::OutboundConnection;
-use crate::proxy::{util, Error, ProxyInputs, TraceParent};
-use crate::{assertions, socket};
+use crate::proxy::{util, ConnectionId, Error, ProxyInputs, TraceParent};
+use crate::state::workload::address::Address;
+use crate::state::workload::NetworkAddress;
+use crate::{assertions, socket, strng};
+
+// How many datagrams to buffer, per direction, for each UDP destination.
+const UDP_CHANNEL_SIZE: usize = 64;
+// The most destinations a single UDP association may have tunnels open to at once. Datagrams to further
+// destinations are dropped until a tunnel closes.
+const MAX_UDP_DESTINATIONS: usize = 64;
 
 pub(super) struct Socks5 {
     pi: Arc<ProxyInputs>,
     listener: socket::Listener,
+    // The destinations clients of this listener may reach
+    scope: Arc<config::Socks5Scope>,
     drain: DrainWatcher,
     enable_orig_src: bool,
 }
 
 impl Socks5 {
-    pub(super) async fn new(pi: Arc<ProxyInputs>, drain: DrainWatcher) -> Result<Socks5, Error> {
+    pub(super) async fn new(
+        pi: Arc<ProxyInputs>,
+        addr: SocketAddr,
+        scope: config::Socks5Scope,
+        drain: DrainWatcher,
+    ) -> Result<Socks5, Error> {
         let listener = pi
             .socket_factory
-            .tcp_bind(pi.cfg.socks5_addr.unwrap())
-            .map_err(|e| Error::Bind(pi.cfg.socks5_addr.unwrap(), e))?;
+            .tcp_bind(addr)
+            .map_err(|e| super::bind_error(pi.socket_factory.as_ref(), addr, e))?;
 
         let transparent = super::maybe_set_transparent(&pi, &listener)?;
 
@@ -58,6 +76,7 @@ impl Socks5 {
             address=%listener.local_addr(),
             component="socks5",
             transparent,
+            scoped=!scope.is_unrestricted(),
             "listener established",
         );
 
@@ -65,6 +84,7 @@ impl Socks5 {
         Ok(Socks5 {
             pi,
             listener,
+            scope: Arc::new(scope),
             drain,
             // Do not need to spoof with inpod mode for outbound
             enable_orig_src: transparent && mode != config::ProxyMode::Shared,
@@ -82,25 +102,30 @@ impl Socks5 {
             self.enable_orig_src,
             self.pi.socket_factory.clone(),
             self.pi.cert_manager.clone(),
+            self.pi.metrics.clone(),
         );
         let accept = |drain: DrainWatcher, force_shutdown: watch::Receiver<()>| {
             async move {
+                let mut current = self.pi.clone();
                 loop {
                     // Asynchronously wait for an inbound socket.
                     let socket = self.listener.accept().await;
+                    ProxyInputs::refresh(&mut current);
                     let start = Instant::now();
                     let drain = drain.clone();
                     let mut force_shutdown = force_shutdown.clone();
                     match socket {
                         Ok((stream, _remote)) => {
                             let oc = OutboundConnection {
-                                pi: self.pi.clone(),
+                                pi: current.clone(),
                                 id: TraceParent::new(),
+                                conn_id: ConnectionId::next(),
                                 pool: pool.clone(),
                                 enable_orig_src: self.enable_orig_src,
                                 hbone_port: self.pi.cfg.inbound_addr.port(),
                             };
-                            let span = info_span!("socks5", id=%oc.id);
+                            let span = info_span!("socks5", id=%oc.id, conn_id=%oc.conn_id);
+                            let scope = self.scope.clone();
                             let serve = (async move {
                                 debug!(component="socks5", "connection started");
                                 // Since this task is spawned, make sure we are guaranteed to terminate
@@ -108,7 +133,7 @@ impl Socks5 {
                                     _ = force_shutdown.changed() => {
                                         debug!(component="socks5", "connection forcefully terminated");
                                     }
-                                    _ = handle(oc, stream) => {}
+                                    _ = handle(oc, stream, &scope) => {}
                                 }
                                 // Mark we are done with the connection, so drain can complete
                                 drop(drain);
@@ -143,7 +168,13 @@ impl Socks5 {
 // sufficient to integrate with common clients:
 // - only unauthenticated requests
 // - only CONNECT, with IPv4 or IPv6
-async fn handle(mut oc: OutboundConnection, mut stream: TcpStream) -> Result<(), anyhow::Error> {
+// - UDP ASSOCIATE, if UDP over HBONE is enabled. Datagrams must target an IPv4 or IPv6 address,
+//   and fragmentation is not supported.
+async fn handle(
+    mut oc: OutboundConnection,
+    mut stream: TcpStream,
+    scope: &config::Socks5Scope,
+) -> Result<(), anyhow::Error> {
     let remote_addr = socket::to_canonical(stream.peer_addr().expect("must receive peer addr"));
 
     // Version(5), Number of auth methods
@@ -172,7 +203,7 @@ async fn handle(mut oc: OutboundConnection, mut stream: TcpStream) -> Result<(),
     // Select 'unauthenticated' (0).
     stream.write_all(&[0x05, 0x00]).await?;
 
-    // Version(5), Command - only support CONNECT (1)
+    // Version(5), Command - only support CONNECT (1), and UDP ASSOCIATE (3) if enabled
     let mut version_command = [0u8; 2];
     stream.read_exact(&mut version_command).await?;
     let version = version_command[0];
@@ -181,9 +212,11 @@ async fn handle(mut oc: OutboundConnection, mut stream: TcpStream) -> Result<(),
         return Err(anyhow::anyhow!("unsupported version"));
     }
 
-    if version_command[1] != 1 {
-        return Err(anyhow::anyhow!("unsupported command"));
-    }
+    let udp = match version_command[1] {
+        0x01 => false,
+        0x03 if oc.pi.cfg.enable_hbone_udp => true,
+        _ => return Err(anyhow::anyhow!("unsupported command")),
+    };
 
     // Skip RSV
     stream.read_exact(&mut [0]).await?;
@@ -213,13 +246,7 @@ async fn handle(mut oc: OutboundConnection, mut stream: TcpStream) -> Result<(),
             // TODO: DNS lookup, if we want to integrate with HTTP-based apps without
             // a DNS server.
             let ds = std::str::from_utf8(&domain)?;
-            let Some(resolver) = &oc.pi.resolver else {
-                return Err(anyhow::anyhow!(
-                    "unsupported hostname lookup, requires DNS enabled"
-                ));
-            };
-
-            ip = dns_lookup(resolver.clone(), remote_addr, ds).await?;
+            ip = dns_lookup(&oc.pi, remote_addr, ds).await?;
             // oc.pi.resolver.lookup()
             // oc.pi.lookup_service_or_query(ds)
             // return Err(anyhow::anyhow!("unsupported host {ds:?}"));
@@ -235,6 +262,29 @@ async fn handle(mut oc: OutboundConnection, mut stream: TcpStream) -> Result<(),
 
     let host = SocketAddr::new(ip, port);
 
+    if udp {
+        // For UDP ASSOCIATE, the address is the client's expected source, which we do not need.
+        return Box::pin(handle_udp_associate(oc, stream, remote_addr, scope)).await;
+    }
+
+    if !in_scope(&oc.pi, scope, ip).await {
+        info!(
+            %remote_addr, %host, component="socks5",
+            "rejected connection to a destination out of the listener's scope"
+        );
+        let mut buf = vec![
+            0x05u8, // version
+            0x02,   // Connection not allowed by ruleset.
+            0x00,   // reserved
+        ];
+        put_socks_addr(
+            &mut buf,
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        );
+        stream.write_all(&buf).await?;
+        return Err(anyhow::anyhow!("destination {host} is out of scope"));
+    }
+
     // Send dummy values - the client generally ignores it.
     let buf = [
         0x05u8, // version
@@ -253,8 +303,186 @@ async fn handle(mut oc: OutboundConnection, mut stream: TcpStream) -> Result<(),
     Ok(())
 }
 
+// handle_udp_associate relays datagrams between the client and any destinations it sends to.
+// Each destination gets its own HBONE tunnel. The association ends when the control connection closes.
+async fn handle_udp_associate(
+    oc: OutboundConnection,
+    mut stream: TcpStream,
+    remote_addr: SocketAddr,
+    scope: &config::Socks5Scope,
+) -> Result<(), anyhow::Error> {
+    let local_addr = socket::to_canonical(stream.local_addr()?);
+    let udp = oc
+        .pi
+        .socket_factory
+        .udp_bind(SocketAddr::new(local_addr.ip(), 0))?;
+    let relay_addr = socket::to_canonical(udp.local_addr()?);
+
+    let mut buf = vec![
+        0x05u8, // version
+        0x00,   // Success.
+        0x00,   // reserved
+    ];
+    put_socks_addr(&mut buf, relay_addr);
+    stream.write_all(&buf).await?;
+    debug!("accepted UDP association from {remote_addr} on {relay_addr}");
+
+    let (reply_tx, mut reply_rx) = mpsc::channel::<(SocketAddr, Bytes)>(UDP_CHANNEL_SIZE);
+    let mut destinations: HashMap<SocketAddr, mpsc::Sender<Bytes>> = HashMap::new();
+    let mut tunnels = JoinSet::new();
+    // The client's UDP address; we learn this from the first datagram it sends.
+    let mut client = None;
+    let mut recv_buf = vec![0u8; 65_535];
+    let mut control = [0u8; 1];
+    loop {
+        tokio::select! {
+            res = stream.read(&mut control) => {
+                if !matches!(res, Ok(n) if n > 0) {
+                    break;
+                }
+            }
+            res = udp.recv_from(&mut recv_buf) => {
+                let (n, from) = res?;
+                let from = socket::to_canonical(from);
+                if from.ip() != remote_addr.ip() {
+                    trace!("dropping datagram from {from}, not the client");
+                    continue;
+                }
+                client = Some(from);
+                let Some((dest, payload)) = parse_udp_datagram(&recv_buf[..n]) else {
+                    trace!("dropping unsupported datagram from {from}");
+                    continue;
+                };
+                if !destinations.contains_key(&dest) {
+                    if destinations.len() >= MAX_UDP_DESTINATIONS {
+                        debug!("dropping datagram to {dest}, too many destinations");
+                        continue;
+                    }
+                    if !in_scope(&oc.pi, scope, dest.ip()).await {
+                        debug!("dropping datagram to {dest}, out of the listener's scope");
+                        continue;
+                    }
+                }
+                let tx = destinations.entry(dest).or_insert_with(|| {
+                    let (tx, rx) = mpsc::channel(UDP_CHANNEL_SIZE);
+                    let mut oc = OutboundConnection {
+                        pi: oc.pi.clone(),
+                        id: TraceParent::new(),
+                        conn_id: ConnectionId::next(),
+                        pool: oc.pool.clone(),
+                        enable_orig_src: oc.enable_orig_src,
+                        hbone_port: oc.hbone_port,
+                    };
+                    let replies = reply_tx.clone();
+                    let span = info_span!("socks5 udp", id=%oc.id, conn_id=%oc.conn_id);
+                    tunnels.spawn(
+                        async move {
+                            let reply = |payload| {
+                                // UDP is lossy; if the client is not keeping up, drop the datagram.
+                                let _ = replies.try_send((dest, payload));
+                            };
+                            oc.proxy_udp_to(remote_addr, dest, rx, reply).await;
+                            dest
+                        }
+                        .instrument(span),
+                    );
+                    tx
+                });
+                match tx.try_send(Bytes::copy_from_slice(payload)) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => trace!("dropping datagram to {dest}, tunnel is full"),
+                    Err(TrySendError::Closed(_)) => {
+                        // The tunnel failed; the next datagram will open a new one.
+                        destinations.remove(&dest);
+                    }
+                }
+            }
+            Some((from, payload)) = reply_rx.recv() => {
+                let Some(client) = client else {
+                    continue;
+                };
+                let mut datagram = vec![0x00u8, 0x00, 0x00]; // reserved, fragment
+                put_socks_addr(&mut datagram, from);
+                datagram.extend_from_slice(&payload);
+                udp.send_to(&datagram, client).await?;
+            }
+            Some(res) = tunnels.join_next() => {
+                // The tunnel is done, so its destination no longer counts against the limit. A new tunnel may
+                // already have replaced it.
+                if let Ok(dest) = res {
+                    if destinations.get(&dest).is_some_and(|tx| tx.is_closed()) {
+                        destinations.remove(&dest);
+                    }
+                }
+            }
+        }
+    }
+
+    // Closing the channels lets the tunnels shut down cleanly.
+    drop(destinations);
+    while tunnels.join_next().await.is_some() {}
+    Ok(())
+}
+
+// in_scope checks whether a listener with `scope` may reach `dest`. When the listener is scoped, destinations
+// that are not known workloads or services are out of scope.
+async fn in_scope(pi: &ProxyInputs, scope: &config::Socks5Scope, dest: IpAddr) -> bool {
+    if scope.is_unrestricted() {
+        return true;
+    }
+    let addr = NetworkAddress {
+        network: strng::new(&pi.cfg.network),
+        address: dest,
+    };
+    match pi.state.fetch_address(&addr).await {
+        Some(Address::Workload(wl)) => {
+            scope.namespaces.contains(&wl.namespace)
+                || scope.identities.contains(&wl.identity().to_strng())
+        }
+        Some(Address::Service(svc)) => scope.namespaces.contains(&svc.namespace),
+        None => false,
+    }
+}
+
+// parse_udp_datagram parses the SOCKS5 UDP request header, returning the destination and payload.
+// Fragmented datagrams and hostnames are not supported.
+fn parse_udp_datagram(buf: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    // RSV(2), FRAG(1), ATYP(1)
+    if buf.len() < 4 || buf[2] != 0x00 {
+        return None;
+    }
+    let (ip, rest): (IpAddr, _) = match buf[3] {
+        0x01 if buf.len() >= 4 + 4 + 2 => {
+            let ip: [u8; 4] = buf[4..8].try_into().ok()?;
+            (ip.into(), &buf[8..])
+        }
+        0x04 if buf.len() >= 4 + 16 + 2 => {
+            let ip: [u8; 16] = buf[4..20].try_into().ok()?;
+            (ip.into(), &buf[20..])
+        }
+        _ => return None,
+    };
+    let port = BigEndian::read_u16(&rest[..2]);
+    Some((SocketAddr::new(ip, port), &rest[2..]))
+}
+
+// put_socks_addr writes the SOCKS5 encoding (ATYP, address, port) of addr.
+fn put_socks_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(0x01);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(0x04);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
 async fn dns_lookup(
-    resolver: Arc<dyn Resolver + Send + Sync>,
+    pi: &ProxyInputs,
     client_addr: SocketAddr,
     hostname: &str,
 ) -> Result<IpAddr, Error> {
@@ -284,6 +512,14 @@ async fn dns_lookup(
         server_request(&new_message(name, RecordType::AAAA), client_addr, protocol)
     }
 
+    // As with the resolver, only addresses of the client's family are used.
+    if let Some(ips) = pi.state.static_host(hostname) {
+        return ips
+            .into_iter()
+            .find(|ip| ip.is_ipv4() == client_addr.is_ipv4())
+            .ok_or(Error::DnsEmpty);
+    }
+    let resolver = pi.resolver()?;
     // TODO: do we need to do the search?
     let name = Name::from_utf8(hostname)?;
 
@@ -302,3 +538,122 @@ async fn dns_lookup(
 
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::DefaultSocketFactory;
+    use crate::test_helpers::test_proxy_inputs;
+    use crate::xds::istio::workload::Workload as XdsWorkload;
+
+    #[tokio::test]
+    async fn hostname_without_resolver() {
+        let pi = test_proxy_inputs(
+            crate::test_helpers::test_config(),
+            crate::test_helpers::new_proxy_state(&[], &[], &[]),
+            Arc::new(DefaultSocketFactory::default()),
+        );
+        let err = dns_lookup(&pi, "127.0.0.1:1234".parse().unwrap(), "example.com")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::UnsupportedFeature(ref f) if f == "DNS resolution disabled"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn scope() {
+        let workload = XdsWorkload {
+            uid: "cluster1//v1/Pod/team-a/api".to_string(),
+            name: "api".to_string(),
+            namespace: "team-a".to_string(),
+            service_account: "api".to_string(),
+            addresses: vec![bytes::Bytes::copy_from_slice(&[10, 0, 0, 1])],
+            ..Default::default()
+        };
+        let pi = test_proxy_inputs(
+            crate::test_helpers::test_config(),
+            crate::test_helpers::new_proxy_state(&[workload], &[], &[]),
+            Arc::new(DefaultSocketFactory::default()),
+        );
+        let workload_ip = "10.0.0.1".parse().unwrap();
+        let unknown_ip = "10.0.0.2".parse().unwrap();
+        let scope = |namespaces: &[&str], identities: &[&str]| config::Socks5Scope {
+            namespaces: namespaces.iter().map(|n| strng::new(n)).collect(),
+            identities: identities.iter().map(|i| strng::new(i)).collect(),
+        };
+
+        // An unscoped listener reaches anything, even destinations that are not known.
+        assert!(in_scope(&pi, &scope(&[], &[]), unknown_ip).await);
+        assert!(in_scope(&pi, &scope(&["team-a"], &[]), workload_ip).await);
+        assert!(!in_scope(&pi, &scope(&["team-b"], &[]), workload_ip).await);
+        assert!(!in_scope(&pi, &scope(&["team-a"], &[]), unknown_ip).await);
+        assert!(
+            in_scope(
+                &pi,
+                &scope(&[], &["spiffe://cluster.local/ns/team-a/sa/api"]),
+                workload_ip
+            )
+            .await
+        );
+        assert!(
+            !in_scope(
+                &pi,
+                &scope(&[], &["spiffe://cluster.local/ns/team-a/sa/web"]),
+                workload_ip
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn out_of_scope_rejected() {
+        let pi = test_proxy_inputs(
+            crate::test_helpers::test_config(),
+            crate::test_helpers::new_proxy_state(&[], &[], &[]),
+            Arc::new(DefaultSocketFactory::default()),
+        );
+        let cfg = pi.cfg.clone();
+        let oc = OutboundConnection {
+            pi: pi.clone(),
+            id: TraceParent::new(),
+            conn_id: ConnectionId::next(),
+            pool: crate::proxy::pool::WorkloadHBONEPool::new(
+                cfg.clone(),
+                false,
+                pi.socket_factory.clone(),
+                pi.cert_manager.clone(),
+                pi.metrics.clone(),
+            ),
+            enable_orig_src: false,
+            hbone_port: cfg.inbound_addr.port(),
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        // The destination is not known, so it cannot be in the namespace the listener is scoped to.
+        let scope = config::Socks5Scope {
+            namespaces: vec![strng::new("team-a")],
+            identities: vec![],
+        };
+        let handled = tokio::spawn(async move { handle(oc, server, &scope).await });
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x00]);
+        client
+            .write_all(&[0x05, 0x01, 0x00, 0x01, 10, 0, 0, 2, 0, 80])
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        // Connection not allowed by ruleset
+        assert_eq!(reply, [0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+        let err = handled.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "destination 10.0.0.2:80 is out of scope");
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Throttling caps the rate at which data is relayed, so a single connection or source identity cannot use up
+//! the bandwidth of the node.
+//!
+//! Each limit is a token bucket of bytes, refilled at the configured rate and holding up to a tenth of a second
+//! of data. Relaying a chunk takes its size from every bucket that applies, even if that leaves a bucket in
+//! debt; the copy then pauses until the debt is repaid. Data is only ever delayed, never dropped. Both
+//! directions of a connection count towards the same limits.
+
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::Duration;
+
+use prometheus_client::metrics::counter::Counter;
+use tokio::time::Instant;
+
+use crate::config::BandwidthLimits;
+use crate::identity::Identity;
+use crate::proxy::Metrics;
+
+static THROTTLER: OnceLock<Throttler> = OnceLock::new();
+
+/// enable turns on throttling of connections, for the rest of the process' lifetime.
+pub fn enable(throttler: Throttler) {
+    let _ = THROTTLER.set(throttler);
+}
+
+/// start begins throttling a connection from `identity`, if any limit applies to it.
+pub fn start(identity: Option<&Identity>) -> Option<Throttle> {
+    THROTTLER.get().and_then(|t| t.start(identity))
+}
+
+/// Throttler hands out the buckets for new connections.
+pub struct Throttler {
+    limits: BandwidthLimits,
+    // The shared bucket of each source identity with connections open
+    identities: Mutex<HashMap<Identity, Weak<Bucket>>>,
+    throttled_bytes: Counter,
+    throttled_seconds: Counter<f64, AtomicU64>,
+}
+
+impl Throttler {
+    pub fn new(limits: BandwidthLimits, metrics: &Metrics) -> Self {
+        Throttler {
+            limits,
+            identities: Default::default(),
+            throttled_bytes: metrics.throttled_bytes.clone(),
+            throttled_seconds: metrics.throttled_seconds.clone(),
+        }
+    }
+
+    pub fn start(&self, identity: Option<&Identity>) -> Option<Throttle> {
+        let mut buckets = Vec::new();
+        if let Some(rate) = self.limits.connection {
+            buckets.push(Arc::new(Bucket::new(rate)));
+        }
+        if let (Some(rate), Some(identity)) = (self.limits.identity, identity) {
+            let mut identities = self.identities.lock().unwrap();
+            let bucket = match identities.get(identity).and_then(Weak::upgrade) {
+                Some(bucket) => bucket,
+                None => {
+                    // Drop the buckets of identities without connections, so this does not grow unbounded.
+                    identities.retain(|_, b| b.strong_count() > 0);
+                    let bucket = Arc::new(Bucket::new(rate));
+                    identities.insert(identity.clone(), Arc::downgrade(&bucket));
+                    bucket
+                }
+            };
+            buckets.push(bucket);
+        }
+        if buckets.is_empty() {
+            return None;
+        }
+        Some(Throttle {
+            buckets,
+            throttled_bytes: self.throttled_bytes.clone(),
+            throttled_seconds: self.throttled_seconds.clone(),
+        })
+    }
+}
+
+/// Throttle applies the limits of a single connection.
+pub struct Throttle {
+    buckets: Vec<Arc<Bucket>>,
+    throttled_bytes: Counter,
+    throttled_seconds: Counter<f64, AtomicU64>,
+}
+
+impl Throttle {
+    /// relayed accounts for `bytes` of relayed data, returning how long to pause before relaying more.
+    pub fn relayed(&self, bytes: u64) -> Option<Duration> {
+        let now = Instant::now();
+        let pause = self
+            .buckets
+            .iter()
+            .map(|b| b.take(bytes, now))
+            .max()
+            .unwrap_or_default();
+        if pause.is_zero() {
+            return None;
+        }
+        self.throttled_bytes.inc_by(bytes);
+        self.throttled_seconds.inc_by(pause.as_secs_f64());
+        Some(pause)
+    }
+}
+
+struct Bucket {
+    // Bytes per second
+    rate: f64,
+    // Bytes available, negative when in debt, as of `updated`
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        Bucket {
+            rate,
+            state: Mutex::new((rate / 10.0, Instant::now())),
+        }
+    }
+
+    // take removes `bytes` from the bucket, returning how long until it is out of debt.
+    fn take(&self, bytes: u64, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (available, updated) = *state;
+        let refilled = now.saturating_duration_since(updated).as_secs_f64() * self.rate;
+        let available = (available + refilled).min(self.rate / 10.0) - bytes as f64;
+        *state = (available, now);
+        if available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-available / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::helpers::test_proxy_metrics;
+
+    #[tokio::test(start_paused = true)]
+    async fn identity_bucket_is_shared() {
+        let metrics = test_proxy_metrics();
+        let throttler = Throttler::new(
+            BandwidthLimits {
+                connection: None,
+                identity: Some(1000),
+            },
+            &metrics,
+        );
+        let id = Identity::Spiffe {
+            trust_domain: "cluster.local".into(),
+            namespace: "default".into(),
+            service_account: "tenant".into(),
+        };
+        assert!(throttler.start(None).is_none());
+        let a = throttler.start(Some(&id)).unwrap();
+        let b = throttler.start(Some(&id)).unwrap();
+        // The initial burst is a tenth of a second; everything past it is paid for by both connections.
+        assert_eq!(a.relayed(100), None);
+        assert_eq!(b.relayed(500), Some(Duration::from_millis(500)));
+        assert_eq!(metrics.throttled_bytes.get(), 500);
+        drop((a, b));
+        let c = throttler.start(Some(&id)).unwrap();
+        assert_eq!(c.relayed(100), None);
+    }
+}
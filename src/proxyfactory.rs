@@ -16,12 +16,15 @@ use crate::config;
 use crate::identity::SecretManager;
 use crate::state::{DemandProxyState, WorkloadInfo};
 use std::sync::Arc;
-use tracing::error;
+use tokio::sync::watch;
+use tracing::{error, warn};
 
 use crate::dns;
 use crate::drain::DrainWatcher;
 
-use crate::proxy::connection_manager::ConnectionManager;
+use crate::proxy::connect_limiter::{ConnectLimiter, ConnectLimitingSocketFactory};
+use crate::proxy::connection_manager::{ConnectionBudget, ConnectionManager};
+use crate::proxy::destination_limiter::DestinationLimiter;
 use crate::proxy::{Error, Metrics};
 
 use crate::proxy::Proxy;
@@ -35,6 +38,11 @@ pub struct ProxyFactory {
     proxy_metrics: Arc<Metrics>,
     dns_metrics: Option<Arc<dns::Metrics>>,
     drain: DrainWatcher,
+    config_updates: Option<watch::Receiver<Arc<config::Config>>>,
+    // Shared by all proxies, so the limits apply across every pod in inpod mode.
+    connect_limiter: Option<Arc<ConnectLimiter>>,
+    destination_limiter: Arc<DestinationLimiter>,
+    connection_budget: Option<Arc<ConnectionBudget>>,
 }
 
 impl ProxyFactory {
@@ -56,6 +64,10 @@ impl ProxyFactory {
             }
         };
 
+        let connect_limiter = ConnectLimiter::from_config(&config, &proxy_metrics).map(Arc::new);
+        let destination_limiter = Arc::new(DestinationLimiter::new(&proxy_metrics));
+        let connection_budget = ConnectionBudget::from_config(&config, &proxy_metrics);
+
         Ok(ProxyFactory {
             config,
             state,
@@ -63,12 +75,26 @@ impl ProxyFactory {
             proxy_metrics,
             dns_metrics,
             drain,
+            config_updates: None,
+            connect_limiter,
+            destination_limiter,
+            connection_budget,
         })
     }
 
+    /// with_config_updates makes proxies pick up reloaded configuration for new connections.
+    pub fn with_config_updates(mut self, updates: watch::Receiver<Arc<config::Config>>) -> Self {
+        self.config_updates = Some(updates);
+        self
+    }
+
     pub async fn new_proxies(&self) -> Result<ProxyResult, Error> {
-        self.new_proxies_from_factory(None, None, Arc::new(crate::proxy::DefaultSocketFactory))
-            .await
+        self.new_proxies_from_factory(
+            None,
+            None,
+            Arc::new(crate::proxy::DefaultSocketFactory::new(&self.config)),
+        )
+        .await
     }
 
     pub async fn new_proxies_from_factory(
@@ -79,6 +105,14 @@ impl ProxyFactory {
     ) -> Result<ProxyResult, Error> {
         let mut result: ProxyResult = Default::default();
         let drain = proxy_drain.unwrap_or_else(|| self.drain.clone());
+        let socket_factory: Arc<dyn crate::proxy::SocketFactory + Send + Sync> =
+            match &self.connect_limiter {
+                Some(limiter) => Arc::new(ConnectLimitingSocketFactory::new(
+                    socket_factory,
+                    limiter.clone(),
+                )),
+                None => socket_factory,
+            };
 
         let mut resolver = None;
         // Optionally create the DNS proxy.
@@ -102,9 +136,21 @@ impl ProxyFactory {
             result.dns_proxy = Some(server);
         }
 
+        let socks5 = self.config.socks5_addr.is_some() || !self.config.socks5_listeners.is_empty();
+        if self.config.proxy && socks5 && resolver.is_none() {
+            warn!("SOCKS5 is enabled without the DNS proxy; requests for hostname destinations will be rejected");
+        }
+
         // Optionally create the HBONE proxy.
         if self.config.proxy {
-            let cm = ConnectionManager::default();
+            let cm = ConnectionManager::default()
+                .with_connection_budget(self.connection_budget.clone())
+                .with_max_lifetime(self.config.max_connection_lifetime, &self.proxy_metrics)
+                .with_double_connection_policy(
+                    self.config.double_connection_policy,
+                    &self.proxy_metrics,
+                )
+                .with_selector_drain(self.config.self_termination_deadline, &self.proxy_metrics);
             let pi = crate::proxy::ProxyInputs::new(
                 self.config.clone(),
                 self.cert_manager.clone(),
@@ -114,6 +160,8 @@ impl ProxyFactory {
                 socket_factory.clone(),
                 proxy_workload_info,
                 resolver,
+                self.config_updates.clone(),
+                self.destination_limiter.clone(),
             );
             result.connection_manager = Some(cm);
             result.proxy = Some(Proxy::from_inputs(pi, drain).await?);
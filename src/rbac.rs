@@ -17,7 +17,7 @@ use ipnet::IpNet;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::net::SocketAddr;
-use tracing::{instrument, trace};
+use tracing::{debug, instrument, trace};
 use xds::istio::security::string_match::MatchType;
 use xds::istio::security::Address as XdsAddress;
 use xds::istio::security::Authorization as XdsRbac;
@@ -65,12 +65,134 @@ impl Display for Connection {
             f,
             "{}({})->{}",
             self.src,
-            OptionDisplay(&self.src_identity),
+            OptionDisplay(&self.src_identity.as_ref().map(Identity::log_display)),
             self.dst
         )
     }
 }
 
+/// RbacDenial is why a connection was denied by authorization policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RbacDenial {
+    // The destination workload is not known, so its policies cannot be found.
+    UnknownDestination,
+    // The destination workload is not the one this proxy serves.
+    WorkloadMismatch,
+    // A DENY policy matched. The policy is identified as namespace/name, and the rule by its index in the policy.
+    DenyPolicy { policy: Strng, rule: usize },
+    // There are ALLOW policies for the destination, but none matched.
+    NoAllowPolicyMatched,
+    // The connection could not be tracked, so could not be re-evaluated when policies change.
+    Untracked,
+}
+
+impl Display for RbacDenial {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RbacDenial::UnknownDestination => write!(f, "destination workload not found"),
+            RbacDenial::WorkloadMismatch => write!(f, "destination workload does not match"),
+            RbacDenial::DenyPolicy { policy, rule } => {
+                write!(f, "denied by policy {policy} rule {rule}")
+            }
+            RbacDenial::NoAllowPolicyMatched => write!(f, "no allow policy matched"),
+            RbacDenial::Untracked => write!(f, "connection could not be tracked"),
+        }
+    }
+}
+
+/// RbacDecision is the outcome of evaluating authorization policies against a connection, along with the policy
+/// and rule that decided it. There is no deciding policy if the connection was allowed because there are no ALLOW
+/// policies, or denied because none of them matched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RbacDecision {
+    pub action: RbacAction,
+    // The policy that decided, as namespace/name
+    pub policy: Option<Strng>,
+    // The index of the rule that matched within the policy
+    pub rule: Option<usize>,
+}
+
+impl RbacDecision {
+    fn matched(pol: &Authorization, rule: usize) -> Self {
+        RbacDecision {
+            action: pol.action,
+            policy: Some(pol.to_key()),
+            rule: Some(rule),
+        }
+    }
+
+    fn unmatched(action: RbacAction) -> Self {
+        RbacDecision {
+            action,
+            policy: None,
+            rule: None,
+        }
+    }
+
+    /// into_result is the decision as a denial, if it denies the connection.
+    pub fn into_result(self) -> Result<RbacDecision, RbacDenial> {
+        if self.action == RbacAction::Allow {
+            return Ok(self);
+        }
+        match (self.policy, self.rule) {
+            (Some(policy), Some(rule)) => Err(RbacDenial::DenyPolicy { policy, rule }),
+            _ => Err(RbacDenial::NoAllowPolicyMatched),
+        }
+    }
+}
+
+impl Display for RbacDecision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            RbacAction::Allow => "allow",
+            RbacAction::Deny => "deny",
+        };
+        match (&self.policy, self.rule) {
+            (Some(policy), Some(rule)) => write!(f, "{action} by policy {policy} rule {rule}"),
+            _ if self.action == RbacAction::Allow => write!(f, "{action}: no allow policies"),
+            _ => write!(f, "{action}: no allow policy matched"),
+        }
+    }
+}
+
+/// evaluate decides whether the DENY and ALLOW policies that apply to a destination permit a connection to it.
+/// This follows https://istio.io/latest/docs/reference/config/security/authorization-policy/: the first
+/// matching DENY policy denies, then the first matching ALLOW policy allows, and if there are ALLOW policies but
+/// none match, the connection is denied.
+pub fn evaluate(
+    deny: &[&Authorization],
+    allow: &[&Authorization],
+    conn: &Connection,
+) -> RbacDecision {
+    // "If there are any DENY policies that match the request, deny the request."
+    for pol in deny {
+        if let Some(rule) = pol.matching_rule(conn) {
+            debug!(policy = pol.to_key().as_str(), rule, "deny policy match");
+            return RbacDecision::matched(pol, rule);
+        }
+        trace!(policy = pol.to_key().as_str(), "deny policy does not match");
+    }
+    // "If there are no ALLOW policies for the workload, allow the request."
+    if allow.is_empty() {
+        debug!("no allow policies, allow");
+        return RbacDecision::unmatched(RbacAction::Allow);
+    }
+    // "If any of the ALLOW policies match the request, allow the request."
+    for pol in allow {
+        if let Some(rule) = pol.matching_rule(conn) {
+            debug!(policy = pol.to_key().as_str(), rule, "allow policy match");
+            return RbacDecision::matched(pol, rule);
+        }
+        trace!(
+            policy = pol.to_key().as_str(),
+            "allow policy does not match"
+        );
+    }
+    // "Deny the request."
+    debug!("no allow policies matched");
+    RbacDecision::unmatched(RbacAction::Deny)
+}
+
 impl Authorization {
     pub fn to_key(&self) -> Strng {
         let mut res = String::with_capacity(1 + self.namespace.len() + self.name.len());
@@ -80,8 +202,16 @@ impl Authorization {
         res.into()
     }
 
-    #[instrument(level = "trace", skip_all, fields(policy=self.to_key().as_str()))]
     pub fn matches(&self, conn: &Connection) -> bool {
+        self.matching_rule(conn).is_some()
+    }
+
+    /// matching_rule returns the index of the first rule that matches the connection, if any does.
+    #[instrument(level = "trace", skip_all, fields(policy=self.to_key().as_str()))]
+    pub fn matching_rule(&self, conn: &Connection) -> Option<usize> {
+        // Policies list IPv4 addresses as such, so a client connecting over IPv6 with a mapped address must
+        // be matched by the address it maps.
+        let (src_ip, dst_ip) = (conn.src.ip().to_canonical(), conn.dst.ip().to_canonical());
         let id = conn
             .src_identity
             .as_ref()
@@ -96,11 +226,11 @@ impl Authorization {
             .unwrap_or_default();
         if self.rules.is_empty() {
             trace!(matches = false, "empty rules");
-            return false;
+            return None;
         }
         // An Authorization Policy can have multiple rules
         // If ANY rule matches it's a match...
-        for rule in self.rules.iter() {
+        for (i, rule) in self.rules.iter().enumerate() {
             // Rule typically has 1-3 clauses (from,to,when)
             // If ALL clauses match, it is a match...
             let mut rule_match = true;
@@ -119,13 +249,13 @@ impl Authorization {
                         "destination_ip",
                         &mg.destination_ips,
                         &mg.not_destination_ips,
-                        |i| i.contains(&conn.dst.ip()),
+                        |i| i.contains(&dst_ip),
                     );
                     m &= Self::matches_internal(
                         "source_ips",
                         &mg.source_ips,
                         &mg.not_source_ips,
-                        |i| i.contains(&conn.src.ip()),
+                        |i| i.contains(&src_ip),
                     );
                     m &= Self::matches_internal(
                         "destination_ports",
@@ -166,10 +296,10 @@ impl Authorization {
             }
             trace!(matches = rule_match, "rule");
             if rule_match {
-                return true;
+                return Some(i);
             }
         }
-        false
+        None
     }
 
     #[instrument(name= "match", level = "trace", skip_all, fields(%desc))]
@@ -445,6 +575,15 @@ mod tests {
         }
     }
 
+    fn mapped_conn() -> Connection {
+        Connection {
+            src_identity: None,
+            src: "[::ffff:127.0.0.1]:1234".parse().unwrap(),
+            dst_network: "".into(),
+            dst: "[::ffff:127.0.0.2]:8080".parse().unwrap(),
+        }
+    }
+
     fn tls_conn() -> Connection {
         Connection {
             src_identity: Some(Identity::Spiffe {
@@ -471,6 +610,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn connection_display_logs_identity() {
+        // Connections are logged with their Display, such as when denied, so it must render the identity for
+        // logs rather than in full.
+        let conn = tls_conn();
+        let id = conn.src_identity.as_ref().unwrap();
+        assert_eq!(
+            conn.to_string(),
+            format!("127.0.0.1:1234({})->127.0.0.2:8080", id.log_display())
+        );
+        assert_eq!(
+            plaintext_conn().to_string(),
+            "[::ffff:127.0.0.1]:1234(None)->[::ffff:127.0.0.2]:8080"
+        );
+    }
+
     #[test]
     fn rbac_empty_policy() {
         assert!(!allow_policy(
@@ -611,6 +766,61 @@ mod tests {
         }));
     }
 
+    #[test]
+    fn rbac_evaluate() {
+        let ns = |ns: &str| {
+            vec![vec![RbacMatch {
+                namespaces: vec![StringMatch::Exact(ns.into())],
+                ..Default::default()
+            }]]
+        };
+        let deny = Authorization {
+            action: RbacAction::Deny,
+            ..allow_policy("deny", vec![ns("ns-alt"), ns("namespace")])
+        };
+        let allow = allow_policy("allow", vec![ns("other"), ns("namespace")]);
+        let unrelated = allow_policy("unrelated", vec![ns("other")]);
+
+        // The first matching DENY policy decides, with the rule that matched.
+        assert_eq!(
+            evaluate(&[&deny], &[&allow], &tls_conn()),
+            RbacDecision {
+                action: RbacAction::Deny,
+                policy: Some("namespace/deny".into()),
+                rule: Some(1),
+            }
+        );
+        assert_eq!(
+            evaluate(&[&deny], &[&allow], &tls_conn()).into_result(),
+            Err(RbacDenial::DenyPolicy {
+                policy: "namespace/deny".into(),
+                rule: 1,
+            })
+        );
+        // Otherwise the first matching ALLOW policy does.
+        assert_eq!(
+            evaluate(&[], &[&unrelated, &allow], &tls_conn()),
+            RbacDecision {
+                action: RbacAction::Allow,
+                policy: Some("namespace/allow".into()),
+                rule: Some(1),
+            }
+        );
+        // Without ALLOW policies, any connection is allowed; with them, one must match.
+        assert_eq!(
+            evaluate(&[], &[], &tls_conn()).into_result(),
+            Ok(RbacDecision {
+                action: RbacAction::Allow,
+                policy: None,
+                rule: None,
+            })
+        );
+        assert_eq!(
+            evaluate(&[], &[&unrelated], &tls_conn()).into_result(),
+            Err(RbacDenial::NoAllowPolicyMatched)
+        );
+    }
+
     rbac_test!(namespaces, vec![StringMatch::Exact("namespace".into())],
         &plaintext_conn() => false,
         &tls_conn() => true,
@@ -646,6 +856,10 @@ mod tests {
         &plaintext_conn() => false,
         &tls_conn() => false,
         &tls_conn_alt() => true);
+    rbac_test!(mapped_source_ips, source_ips, vec![IpNet::new("127.0.0.1".parse().unwrap(), 32).unwrap()],
+        &mapped_conn() => true);
+    rbac_test!(mapped_destination_ips, destination_ips, vec![IpNet::new("127.0.0.2".parse().unwrap(), 32).unwrap()],
+        &mapped_conn() => true);
     rbac_test!(cidr_range, destination_ips, vec![IpNet::new("127.0.0.1".parse().unwrap(), 24).unwrap()],
         &plaintext_conn() => true,
         &tls_conn() => true,
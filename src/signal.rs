@@ -17,7 +17,12 @@
 //     async fn shutdown();
 // }
 
+use std::time::Duration;
+
 use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::drain;
 
 pub struct Shutdown {
     shutdown_tx: mpsc::Sender<()>,
@@ -40,10 +45,54 @@ impl Shutdown {
         }
     }
 
-    /// Wait completes when the shutdown as been triggered
-    pub async fn wait(mut self) {
-        imp::shutdown(&mut self.shutdown_rx).await
+    /// listen starts forwarding SIGTERM and SIGINT as shutdown requests, returning the receiver of all
+    /// shutdown requests, whether from signals or triggers.
+    pub fn listen(self) -> mpsc::Receiver<()> {
+        tokio::spawn(imp::forward_signals(self.shutdown_tx));
+        self.shutdown_rx
+    }
+}
+
+/// DrainOutcome describes how a shutdown completed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrainOutcome {
+    // All components finished draining.
+    Drained,
+    // The deadline passed before all components finished draining.
+    DeadlineExceeded,
+    // A second shutdown request arrived while draining.
+    Forced,
+}
+
+/// drain_on_shutdown waits for a shutdown request, then starts a graceful drain. It returns once the drain
+/// completes, `deadline` passes, or another shutdown request arrives, whichever comes first.
+pub async fn drain_on_shutdown(
+    requests: &mut mpsc::Receiver<()>,
+    drain_tx: drain::DrainTrigger,
+    deadline: Duration,
+) -> DrainOutcome {
+    // If all senders are gone, nothing can request a shutdown anymore; treat that as a request.
+    let _ = requests.recv().await;
+    info!(?deadline, "shutdown requested, draining connections");
+    let drain = tokio::time::timeout(
+        deadline,
+        drain_tx.start_drain_and_wait(drain::DrainMode::Graceful),
+    );
+    let outcome = tokio::select! {
+        res = drain => match res {
+            Ok(()) => DrainOutcome::Drained,
+            Err(_) => DrainOutcome::DeadlineExceeded,
+        },
+        Some(()) = requests.recv() => DrainOutcome::Forced,
+    };
+    match outcome {
+        DrainOutcome::Drained => info!("drain complete"),
+        DrainOutcome::DeadlineExceeded => warn!("drain deadline exceeded, exiting"),
+        DrainOutcome::Forced => {
+            warn!("shutdown requested again while draining, exiting immediately")
+        }
     }
+    outcome
 }
 
 impl Default for Shutdown {
@@ -52,6 +101,11 @@ impl Default for Shutdown {
     }
 }
 
+/// watch_reload calls `reload` each time a configuration reload is requested with SIGHUP.
+pub async fn watch_reload<F: Fn()>(reload: F) {
+    imp::watch_reload(reload).await
+}
+
 #[derive(Clone, Debug)]
 pub struct ShutdownTrigger {
     shutdown_tx: mpsc::Sender<()>,
@@ -65,43 +119,102 @@ impl ShutdownTrigger {
 
 #[cfg(unix)]
 mod imp {
-    use std::process;
     use tokio::signal::unix::{signal, SignalKind};
-    use tokio::sync::mpsc::Receiver;
+    use tokio::sync::mpsc::Sender;
     use tracing::info;
 
-    pub(super) async fn shutdown(receiver: &mut Receiver<()>) {
-        tokio::select! {
-            _ = watch_signal(SignalKind::interrupt(), "SIGINT") => {
-                tokio::spawn(async move{
-                    watch_signal(SignalKind::interrupt(), "SIGINT").await;
-                    info!("Double Ctrl+C, exit immediately");
-                    process::exit(0);
-                });
+    pub(super) async fn forward_signals(sender: Sender<()>) {
+        let mut interrupt =
+            signal(SignalKind::interrupt()).expect("Failed to register signal handler");
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("Failed to register signal handler");
+        loop {
+            let name = tokio::select! {
+                _ = interrupt.recv() => "SIGINT",
+                _ = terminate.recv() => "SIGTERM",
+            };
+            info!("received signal {}", name);
+            if sender.send(()).await.is_err() {
+                return;
             }
-            _ = watch_signal(SignalKind::terminate(), "SIGTERM") => {}
-            _ = receiver.recv() => { info!("received explicit shutdown signal")}
-        };
+        }
     }
 
-    async fn watch_signal(kind: SignalKind, name: &'static str) {
-        signal(kind)
-            .expect("Failed to register signal handler")
-            .recv()
-            .await;
-        info!("received signal {}, starting shutdown", name,);
+    pub(super) async fn watch_reload<F: Fn()>(reload: F) {
+        let mut hangup = signal(SignalKind::hangup()).expect("Failed to register signal handler");
+        while hangup.recv().await.is_some() {
+            info!("received signal SIGHUP, reloading configuration");
+            reload();
+        }
     }
 }
 
 #[cfg(not(unix))]
 mod imp {
-    use tokio::sync::mpsc::Receiver;
+    use tokio::sync::mpsc::Sender;
 
-    pub(super) async fn shutdown(receiver: Receiver<()>) {
+    pub(super) async fn watch_reload<F: Fn()>(_reload: F) {
+        // There is no SIGHUP equivalent; reloads can only be triggered via the admin server.
+        std::future::pending::<()>().await
+    }
+
+    pub(super) async fn forward_signals(sender: Sender<()>) {
         // This isn't quite right, but close enough for windows...
-        tokio::signal::windows::ctrl_c()
-            .expect("Failed to register signal handler")
-            .recv()
-            .await;
+        let mut ctrl_c =
+            tokio::signal::windows::ctrl_c().expect("Failed to register signal handler");
+        while ctrl_c.recv().await.is_some() {
+            if sender.send(()).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_on_shutdown_drains() {
+        let (tx, mut requests) = mpsc::channel(1);
+        let (drain_tx, drain_rx) = drain::new();
+        let watcher = tokio::spawn(async move {
+            let blocker = drain_rx.wait_for_drain().await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            drop(blocker);
+        });
+        tx.send(()).await.unwrap();
+        let outcome = drain_on_shutdown(&mut requests, drain_tx, Duration::from_secs(5)).await;
+        assert_eq!(outcome, DrainOutcome::Drained);
+        watcher.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_on_shutdown_deadline() {
+        let (tx, mut requests) = mpsc::channel(1);
+        let (drain_tx, drain_rx) = drain::new();
+        let start = tokio::time::Instant::now();
+        tx.send(()).await.unwrap();
+        // drain_rx is never released, so the drain cannot complete.
+        let outcome = drain_on_shutdown(&mut requests, drain_tx, Duration::from_secs(5)).await;
+        assert_eq!(outcome, DrainOutcome::DeadlineExceeded);
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        drop(drain_rx);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_on_shutdown_forced() {
+        let (tx, mut requests) = mpsc::channel(1);
+        let (drain_tx, drain_rx) = drain::new();
+        tx.send(()).await.unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            tx.send(()).await.unwrap();
+        });
+        let start = tokio::time::Instant::now();
+        let outcome = drain_on_shutdown(&mut requests, drain_tx, Duration::from_secs(5)).await;
+        assert_eq!(outcome, DrainOutcome::Forced);
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        drop(drain_rx);
     }
 }
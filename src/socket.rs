@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::fmt;
 use std::io::Error;
 use std::net::SocketAddr;
 
@@ -20,6 +21,9 @@ use tokio::io;
 use tokio::net::TcpSocket;
 use tokio::net::{TcpListener, TcpStream};
 
+use crate::config;
+use crate::config::PmtuDiscovery;
+
 #[cfg(target_os = "linux")]
 use {
     socket2::{Domain, SockRef},
@@ -27,17 +31,113 @@ use {
     tracing::warn,
 };
 
+/// SocketError is a failed socket operation, such as setting an option. It keeps the underlying error as its
+/// source, and is displayed with the operation and the name of the OS error code.
+#[derive(Debug)]
+pub struct SocketError {
+    op: &'static str,
+    err: io::Error,
+}
+
+impl SocketError {
+    /// wrap attaches the operation to an error. The result has the same kind; its OS error code is available
+    /// through `raw_os_error`.
+    pub fn wrap(op: &'static str, err: io::Error) -> io::Error {
+        io::Error::new(err.kind(), SocketError { op, err })
+    }
+
+    /// raw_os_error returns the OS error code the operation failed with, if any.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.err.raw_os_error()
+    }
+}
+
+/// raw_os_error returns the OS error code of an error, including one wrapped in a SocketError.
+pub fn raw_os_error(err: &io::Error) -> Option<i32> {
+    err.raw_os_error().or_else(|| {
+        err.get_ref()
+            .and_then(|e| e.downcast_ref::<SocketError>())
+            .and_then(SocketError::raw_os_error)
+    })
+}
+
+impl fmt::Display for SocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&describe(self.op, &self.err), f)
+    }
+}
+
+impl std::error::Error for SocketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.err)
+    }
+}
+
+/// describe formats a failure of a socket operation as "<op>: <error> [<errno>]", for example
+/// "bind: Address already in use (os error 98) [EADDRINUSE]", so logs can be searched by errno. If the error
+/// is a SocketError, it already names a more specific operation, which is kept.
+pub fn describe(op: &'static str, err: &io::Error) -> impl fmt::Display + '_ {
+    Describe(op, err)
+}
+
+struct Describe<'a>(&'static str, &'a io::Error);
+
+impl fmt::Display for Describe<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Describe(op, err) = self;
+        if let Some(inner) = err.get_ref().and_then(|e| e.downcast_ref::<SocketError>()) {
+            return fmt::Display::fmt(inner, f);
+        }
+        match err.raw_os_error().and_then(errno_name) {
+            Some(name) => write!(f, "{op}: {err} [{name}]"),
+            None => write!(f, "{op}: {err}"),
+        }
+    }
+}
+
+// errno_name returns the symbolic name of the OS error codes socket operations commonly fail with.
+fn errno_name(code: i32) -> Option<&'static str> {
+    Some(match code {
+        libc::EPERM => "EPERM",
+        libc::EACCES => "EACCES",
+        libc::EINVAL => "EINVAL",
+        libc::EMFILE => "EMFILE",
+        libc::ENFILE => "ENFILE",
+        libc::ENOBUFS => "ENOBUFS",
+        libc::ENOPROTOOPT => "ENOPROTOOPT",
+        libc::EOPNOTSUPP => "EOPNOTSUPP",
+        libc::EADDRINUSE => "EADDRINUSE",
+        libc::EADDRNOTAVAIL => "EADDRNOTAVAIL",
+        libc::ENETUNREACH => "ENETUNREACH",
+        libc::EHOSTUNREACH => "EHOSTUNREACH",
+        libc::ECONNREFUSED => "ECONNREFUSED",
+        libc::ECONNRESET => "ECONNRESET",
+        libc::ECONNABORTED => "ECONNABORTED",
+        libc::ETIMEDOUT => "ETIMEDOUT",
+        libc::EPIPE => "EPIPE",
+        libc::ENODEV => "ENODEV",
+        _ => return None,
+    })
+}
+
 #[cfg(target_os = "linux")]
 pub fn set_freebind_and_transparent(socket: &TcpSocket) -> io::Result<()> {
     let socket = SockRef::from(socket);
     match socket.domain()? {
         Domain::IPV4 => {
-            socket.set_ip_transparent(true)?;
-            socket.set_freebind(true)?;
+            socket
+                .set_ip_transparent(true)
+                .map_err(|e| SocketError::wrap("setsockopt IP_TRANSPARENT", e))?;
+            socket
+                .set_freebind(true)
+                .map_err(|e| SocketError::wrap("setsockopt IP_FREEBIND", e))?;
         }
         Domain::IPV6 => {
-            linux::set_ipv6_transparent(&socket)?;
-            socket.set_freebind_ipv6(true)?
+            linux::set_ipv6_transparent(&socket)
+                .map_err(|e| SocketError::wrap("setsockopt IPV6_TRANSPARENT", e))?;
+            socket
+                .set_freebind_ipv6(true)
+                .map_err(|e| SocketError::wrap("setsockopt IPV6_FREEBIND", e))?
         }
         _ => return Err(Error::new(ErrorKind::Unsupported, "unsupported domain")),
     };
@@ -96,10 +196,72 @@ pub fn set_freebind_and_transparent(_: &TcpSocket) -> io::Result<()> {
     ))
 }
 
+// set_bind_device binds the socket to a specific network interface (SO_BINDTODEVICE), so traffic
+// egresses through it regardless of the routing table. This requires CAP_NET_RAW.
+#[cfg(target_os = "linux")]
+pub fn set_bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    let socket = SockRef::from(socket);
+    socket
+        .bind_device(Some(interface.as_bytes()))
+        .map_err(|e| SocketError::wrap("setsockopt SO_BINDTODEVICE", e))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_bind_device(_socket: &TcpSocket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "SO_BINDTODEVICE not supported on this operating system",
+    ))
+}
+
+// set_fastopen_connect enables TCP Fast Open on a socket that has not connected yet. The connection is
+// then established when data is first written, which is sent with the SYN if the peer allows it.
+#[cfg(target_os = "linux")]
+pub fn set_fastopen_connect(socket: &TcpSocket) -> io::Result<()> {
+    linux::set_fastopen_connect(&SockRef::from(socket))
+        .map_err(|e| SocketError::wrap("setsockopt TCP_FASTOPEN_CONNECT", e))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_fastopen_connect(_socket: &TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "TCP_FASTOPEN_CONNECT not supported on this operating system",
+    ))
+}
+
+// fastopen_connect_enabled reports whether TCP Fast Open was enabled on a socket with set_fastopen_connect.
+#[cfg(target_os = "linux")]
+pub fn fastopen_connect_enabled(socket: &TcpSocket) -> io::Result<bool> {
+    linux::fastopen_connect_enabled(&SockRef::from(socket))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn fastopen_connect_enabled(_socket: &TcpSocket) -> io::Result<bool> {
+    Ok(false)
+}
+
+// fastopen_used reports whether the SYN of an established connection carried data that the peer accepted, so
+// TCP Fast Open saved a round trip. This is only known once data has been exchanged.
+#[cfg(target_os = "linux")]
+pub fn fastopen_used<S: std::os::unix::io::AsFd>(socket: &S) -> io::Result<bool> {
+    // From linux/tcp.h; libc does not define it.
+    const TCPI_OPT_SYN_DATA: u8 = 32;
+    let info = linux::tcp_info(&SockRef::from(socket))?;
+    Ok(info.tcpi_options & TCPI_OPT_SYN_DATA != 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn fastopen_used<S>(_socket: &S) -> io::Result<bool> {
+    Ok(false)
+}
+
 #[cfg(target_os = "linux")]
 pub fn set_mark<S: std::os::unix::io::AsFd>(socket: &S, mark: u32) -> io::Result<()> {
     let socket = SockRef::from(socket);
-    socket.set_mark(mark)
+    socket
+        .set_mark(mark)
+        .map_err(|e| SocketError::wrap("setsockopt SO_MARK", e))
 }
 
 #[cfg(not(target_os = "linux"))]
@@ -110,14 +272,175 @@ pub fn set_mark(_socket: &TcpSocket, _mark: u32) -> io::Result<()> {
     ))
 }
 
+// set_abortive_close makes the socket send a RST, rather than a FIN, once it is closed, by setting a zero
+// SO_LINGER timeout. Any data that has not been sent yet is discarded.
+pub fn set_abortive_close(stream: &TcpStream) -> io::Result<()> {
+    socket2::SockRef::from(stream)
+        .set_linger(Some(std::time::Duration::ZERO))
+        .map_err(|e| SocketError::wrap("setsockopt SO_LINGER", e))
+}
+
+/// CorrelationMark stamps a connection's ID into the SO_MARK of its sockets, so kernel tooling such as eBPF
+/// programs can tie packets and socket events back to the connection in ztunnel's logs and dumps.
+///
+/// The mark is shared with routing, so only the bits of a configured mask are used:
+///
+/// ```text
+///   31                                                0
+///  +------------------------+-----------------+-------+
+///  |   routing (untouched)  |  connection ID  | rout. |
+///  +------------------------+-----------------+-------+
+///                           ^ mask (contiguous bits)
+/// ```
+///
+/// The low bits of the connection ID are shifted into the masked bits; bits outside the mask, such as the
+/// inpod mark, are kept as they were. As the ID is truncated to the width of the mask, it repeats once that
+/// many connections have been made, so the mask should be wide enough to tell apart concurrent connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CorrelationMark {
+    mask: u32,
+}
+
+impl CorrelationMark {
+    /// new returns the mark for `mask`, or None if it is zero, meaning correlation is disabled.
+    pub fn new(mask: u32) -> Option<Self> {
+        (mask != 0).then_some(CorrelationMark { mask })
+    }
+
+    /// mark is `current` with the masked bits replaced by the low bits of `id`.
+    pub fn mark(&self, current: u32, id: u64) -> u32 {
+        let shift = self.mask.trailing_zeros();
+        let id = (id & u64::from(self.mask >> shift)) as u32;
+        (current & !self.mask) | (id << shift)
+    }
+
+    /// apply stamps `id` into the mark of a socket, keeping the bits that are already set outside the mask.
+    #[cfg(target_os = "linux")]
+    pub fn apply<S: std::os::unix::io::AsFd>(&self, socket: &S, id: u64) -> io::Result<()> {
+        let sock = SockRef::from(socket);
+        let current = sock
+            .mark()
+            .map_err(|e| SocketError::wrap("getsockopt SO_MARK", e))?;
+        set_mark(socket, self.mark(current, id))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply<S>(&self, _socket: &S, _id: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "SO_MARK not supported on this operating system",
+        ))
+    }
+}
+
+/// SocketOptions are the operator configured options applied to the TCP sockets ztunnel creates and listens on.
+/// Options that are unset are left as the kernel sets them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub max_segment_size: Option<u32>,
+    pub pmtu_discovery: Option<PmtuDiscovery>,
+}
+
+impl SocketOptions {
+    pub fn new(cfg: &config::Config) -> Self {
+        SocketOptions {
+            max_segment_size: cfg.tcp_max_segment_size,
+            pmtu_discovery: cfg.pmtu_discovery,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max_segment_size.is_none() && self.pmtu_discovery.is_none()
+    }
+}
+
+// set_socket_options applies the options to a socket. On a listener, they are inherited by accepted connections.
+#[cfg(target_os = "linux")]
+pub fn set_socket_options<S: std::os::unix::io::AsFd>(
+    socket: &S,
+    options: &SocketOptions,
+) -> io::Result<()> {
+    let socket = SockRef::from(socket);
+    if let Some(mss) = options.max_segment_size {
+        socket
+            .set_mss(mss)
+            .map_err(|e| SocketError::wrap("setsockopt TCP_MAXSEG", e))?;
+    }
+    if let Some(mode) = options.pmtu_discovery {
+        linux::set_mtu_discover(&socket, mode)
+            .map_err(|e| SocketError::wrap("setsockopt IP_MTU_DISCOVER", e))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_socket_options<S>(_socket: &S, options: &SocketOptions) -> io::Result<()> {
+    if options.is_empty() {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP_MAXSEG and IP_MTU_DISCOVER are not supported on this operating system",
+    ))
+}
+
+/// TcpDiagnostics is the kernel's view of a TCP connection's path and delivery, read with TCP_INFO.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TcpDiagnostics {
+    /// The path MTU the kernel is using.
+    pub pmtu: u32,
+    /// The maximum segment size we send.
+    pub snd_mss: u32,
+    /// Segments sent but not yet acknowledged.
+    pub unacked: u32,
+    /// Consecutive retransmission timeouts for the oldest unacknowledged segment; reset once it is acknowledged.
+    pub retransmits: u8,
+    /// Retransmissions over the life of the connection.
+    pub total_retrans: u32,
+}
+
+impl TcpDiagnostics {
+    /// The consecutive retransmission timeouts after which a connection is considered stalled. A lone timeout is
+    /// common on lossy links; several in a row without any progress are not.
+    pub const STALL_RETRANSMITS: u8 = 3;
+
+    /// stalled reports whether data we sent has gone unacknowledged across several retransmissions.
+    pub fn stalled(&self) -> bool {
+        self.unacked > 0 && self.retransmits >= Self::STALL_RETRANSMITS
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn tcp_diagnostics<S: std::os::unix::io::AsFd>(socket: &S) -> io::Result<TcpDiagnostics> {
+    let info = linux::tcp_info(&SockRef::from(socket))?;
+    Ok(TcpDiagnostics {
+        pmtu: info.tcpi_pmtu,
+        snd_mss: info.tcpi_snd_mss,
+        unacked: info.tcpi_unacked,
+        retransmits: info.tcpi_retransmits,
+        total_retrans: info.tcpi_total_retrans,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_diagnostics<S>(_socket: &S) -> io::Result<TcpDiagnostics> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP_INFO not supported on this operating system",
+    ))
+}
+
 #[cfg(target_os = "linux")]
 #[allow(unsafe_code)]
 mod linux {
     use std::os::unix::io::AsRawFd;
 
-    use socket2::{SockAddr, SockRef};
+    use socket2::{Domain, SockAddr, SockRef};
+    use std::io::ErrorKind;
     use tokio::io;
 
+    use crate::config::PmtuDiscovery;
+
     pub fn set_ipv6_transparent(sock: &SockRef) -> io::Result<()> {
         unsafe {
             let optval: libc::c_int = 1;
@@ -135,6 +458,98 @@ mod linux {
         Ok(())
     }
 
+    pub fn set_fastopen_connect(sock: &SockRef) -> io::Result<()> {
+        unsafe {
+            let optval: libc::c_int = 1;
+            let ret = libc::setsockopt(
+                sock.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_FASTOPEN_CONNECT,
+                &optval as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&optval) as libc::socklen_t,
+            );
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn fastopen_connect_enabled(sock: &SockRef) -> io::Result<bool> {
+        unsafe {
+            let mut optval: libc::c_int = 0;
+            let mut len = std::mem::size_of_val(&optval) as libc::socklen_t;
+            let ret = libc::getsockopt(
+                sock.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_FASTOPEN_CONNECT,
+                &mut optval as *mut _ as *mut libc::c_void,
+                &mut len,
+            );
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(optval != 0)
+        }
+    }
+
+    pub fn set_mtu_discover(sock: &SockRef, mode: PmtuDiscovery) -> io::Result<()> {
+        let (level, name, optval) = match sock.domain()? {
+            Domain::IPV4 => (
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                match mode {
+                    PmtuDiscovery::Dont => libc::IP_PMTUDISC_DONT,
+                    PmtuDiscovery::Want => libc::IP_PMTUDISC_WANT,
+                    PmtuDiscovery::Do => libc::IP_PMTUDISC_DO,
+                    PmtuDiscovery::Probe => libc::IP_PMTUDISC_PROBE,
+                },
+            ),
+            Domain::IPV6 => (
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                match mode {
+                    PmtuDiscovery::Dont => libc::IPV6_PMTUDISC_DONT,
+                    PmtuDiscovery::Want => libc::IPV6_PMTUDISC_WANT,
+                    PmtuDiscovery::Do => libc::IPV6_PMTUDISC_DO,
+                    PmtuDiscovery::Probe => libc::IPV6_PMTUDISC_PROBE,
+                },
+            ),
+            _ => return Err(io::Error::new(ErrorKind::Unsupported, "unsupported domain")),
+        };
+        unsafe {
+            let ret = libc::setsockopt(
+                sock.as_raw_fd(),
+                level,
+                name,
+                &optval as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&optval) as libc::socklen_t,
+            );
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn tcp_info(sock: &SockRef) -> io::Result<libc::tcp_info> {
+        unsafe {
+            let mut info: libc::tcp_info = std::mem::zeroed();
+            let mut len = std::mem::size_of_val(&info) as libc::socklen_t;
+            let ret = libc::getsockopt(
+                sock.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut _ as *mut libc::c_void,
+                &mut len,
+            );
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(info)
+        }
+    }
+
     pub fn original_dst(sock: &SockRef) -> io::Result<SockAddr> {
         sock.original_dst()
     }
@@ -180,3 +595,84 @@ impl Listener {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn correlation_mark_layout() {
+        assert_eq!(CorrelationMark::new(0), None);
+        let cm = CorrelationMark::new(0x00ff_ff00).unwrap();
+        // The ID fills the masked bits; routing bits on either side are kept.
+        assert_eq!(cm.mark(0x0000_0539, 0x1234), 0x0012_3439);
+        assert_eq!(cm.mark(0xff00_0000, 1), 0xff00_0100);
+        // Existing bits within the mask are replaced, and IDs wider than the mask are truncated.
+        assert_eq!(cm.mark(0x00ff_ff00, 0x12_3456), 0x0034_5600);
+        let cm = CorrelationMark::new(u32::MAX).unwrap();
+        assert_eq!(cm.mark(1337, u64::from(u32::MAX) + 7), 7);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn correlation_mark_is_set() {
+        let socket = TcpSocket::new_v4().unwrap();
+        // Setting a mark requires CAP_NET_ADMIN, which unit tests usually run without.
+        match set_mark(&socket, 1337) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return,
+            res => res.unwrap(),
+        }
+        let cm = CorrelationMark::new(0xffff_0000).unwrap();
+        cm.apply(&socket, 42).unwrap();
+        assert_eq!(SockRef::from(&socket).mark().unwrap(), (42 << 16) | 1337);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn fastopen_connect() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let socket = TcpSocket::new_v4().unwrap();
+        assert!(!fastopen_connect_enabled(&socket).unwrap());
+        set_fastopen_connect(&socket).unwrap();
+        assert!(fastopen_connect_enabled(&socket).unwrap());
+
+        // The connection is only established once data is written, which must still be delivered.
+        let mut client = socket.connect(addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        // The listener does not accept TFO, so the SYN carried no data.
+        assert!(!fastopen_used(&client).unwrap());
+    }
+
+    #[test]
+    fn describe_keeps_operation() {
+        let err = SocketError::wrap(
+            "setsockopt SO_MARK",
+            io::Error::from_raw_os_error(libc::EPERM),
+        );
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(raw_os_error(&err), Some(libc::EPERM));
+        let source = std::error::Error::source(err.get_ref().unwrap()).unwrap();
+        assert_eq!(
+            source.downcast_ref::<io::Error>().unwrap().raw_os_error(),
+            Some(libc::EPERM)
+        );
+        // The operation of a wrapped error is more specific than the one it failed as part of.
+        assert_eq!(
+            describe("bind", &err).to_string(),
+            "setsockopt SO_MARK: Operation not permitted (os error 1) [EPERM]"
+        );
+        let err = io::Error::new(io::ErrorKind::Other, "unsupported");
+        assert_eq!(
+            describe("connect", &err).to_string(),
+            "connect: unsupported"
+        );
+    }
+}
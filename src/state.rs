@@ -14,15 +14,15 @@
 
 use crate::identity::{Identity, SecretManager};
 use crate::proxy;
-use crate::proxy::{Error, OnDemandDnsLabels};
+use crate::proxy::{ConsistentHashLabels, Error, OnDemandDnsLabels, SubsetLabels};
 use crate::rbac::Authorization;
 use crate::state::policy::PolicyStore;
 use crate::state::service::{
-    Endpoint, IpFamily, LoadBalancerMode, LoadBalancerScopes, ServiceStore,
+    Endpoint, IpFamily, LoadBalancerMode, LoadBalancerScopes, ServiceStore, SubsetWeights,
 };
 use crate::state::service::{Service, ServiceDescription};
 use crate::state::workload::{
-    address::Address, gatewayaddress::Destination, network_addr, GatewayAddress,
+    address::Address, gatewayaddress::Destination, network_addr, GatewayAddress, HealthStatus,
     NamespacedHostname, NetworkAddress, Workload, WorkloadStore,
 };
 use crate::strng::Strng;
@@ -36,7 +36,7 @@ use hickory_resolver::config::*;
 use hickory_resolver::name_server::TokioConnectionProvider;
 use hickory_resolver::TokioAsyncResolver;
 use itertools::Itertools;
-use rand::prelude::IteratorRandom;
+use rand::prelude::{IteratorRandom, SliceRandom};
 use serde::Serializer;
 use std::collections::HashMap;
 use std::convert::Into;
@@ -46,7 +46,7 @@ use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::{Arc, RwLock, RwLockReadGuard};
 use std::time::Duration;
-use tracing::{debug, error, trace, warn};
+use tracing::{debug, error, info, trace, warn};
 
 use self::workload::ApplicationTunnel;
 
@@ -249,6 +249,8 @@ impl ProxyState {
         source_workload: &Workload,
         addr: SocketAddr,
         resolution_mode: ServiceResolutionMode,
+        prefer_same_node: bool,
+        headers: Option<&http::HeaderMap>,
     ) -> Option<(Arc<Workload>, u16, Option<Arc<Service>>)> {
         if let Some(svc) = self
             .services
@@ -256,8 +258,14 @@ impl ProxyState {
         {
             // Randomly pick an upstream
             // TODO: do this more efficiently, and not just randomly
-            let Some((ep, wl)) = self.load_balance(source_workload, &svc, addr, resolution_mode)
-            else {
+            let Some((ep, wl)) = self.load_balance(
+                source_workload,
+                &svc,
+                addr,
+                resolution_mode,
+                prefer_same_node,
+                headers,
+            ) else {
                 debug!("VIP {} has no healthy endpoints", addr);
                 return None;
             };
@@ -299,6 +307,8 @@ impl ProxyState {
         svc: &'a Service,
         svc_addr: SocketAddr,
         resolution_mode: ServiceResolutionMode,
+        prefer_same_node: bool,
+        headers: Option<&http::HeaderMap>,
     ) -> Option<(&'a Endpoint, Arc<Workload>)> {
         let target_port = svc.ports.get(&svc_addr.port()).copied();
 
@@ -347,9 +357,31 @@ impl ProxyState {
             }
             Some((ep, wl))
         });
+        // Terminating endpoints are only used if there is nothing else, to avoid an outage.
+        let mut endpoints: Vec<_> = endpoints.collect();
+        if endpoints
+            .iter()
+            .any(|(_, wl)| wl.status != HealthStatus::Terminating)
+        {
+            endpoints.retain(|(_, wl)| wl.status != HealthStatus::Terminating);
+        } else if !endpoints.is_empty() {
+            debug!(
+                "service {} only has terminating endpoints, selecting from them",
+                svc.hostname
+            );
+        }
+        // Endpoints on our own node avoid a network hop, so are preferred over any others, ahead of the locality
+        // preferences of the service.
+        if prefer_same_node
+            && !src.node.is_empty()
+            && endpoints.iter().any(|(_, wl)| wl.node == src.node)
+        {
+            endpoints.retain(|(_, wl)| wl.node == src.node);
+        }
+        let endpoints = endpoints.into_iter();
 
-        match svc.load_balancer {
-            None => endpoints.choose(&mut rand::thread_rng()),
+        let candidates: Vec<_> = match svc.load_balancer {
+            None => endpoints.collect(),
             Some(ref lb) => {
                 let ranks = endpoints
                     .filter_map(|(ep, wl)| {
@@ -392,7 +424,50 @@ impl ProxyState {
                     .into_iter()
                     .filter(|(rank, _ep, _wl)| *rank == max)
                     .map(|(_, ep, wl)| (ep, wl))
-                    .choose(&mut rand::thread_rng())
+                    .collect()
+            }
+        };
+        if let Some(hash) = svc
+            .load_balancer
+            .as_ref()
+            .and_then(|lb| lb.consistent_hash.as_ref())
+        {
+            return hash.choose(
+                src,
+                headers,
+                candidates.iter().map(|(ep, wl)| ((*ep, wl.clone()), wl)),
+            );
+        }
+        match svc.subset_weights {
+            None => candidates.into_iter().choose(&mut rand::thread_rng()),
+            Some(ref subsets) => Self::choose_weighted_subset(subsets, candidates),
+        }
+    }
+
+    /// choose_weighted_subset groups the candidate endpoints into subsets, picks a subset according to
+    /// the configured weights, and then picks a random endpoint within it.
+    /// If no weighted subset has a candidate, we fall back to picking from all of them.
+    fn choose_weighted_subset<'a>(
+        subsets: &SubsetWeights,
+        candidates: Vec<(&'a Endpoint, Arc<Workload>)>,
+    ) -> Option<(&'a Endpoint, Arc<Workload>)> {
+        let mut groups: HashMap<Strng, (u32, Vec<(&'a Endpoint, Arc<Workload>)>)> = HashMap::new();
+        for (ep, wl) in &candidates {
+            let Some(weight) = subsets.weight(wl) else {
+                continue;
+            };
+            groups
+                .entry(subsets.key.subset(wl).clone())
+                .or_insert_with(|| (weight, Vec::new()))
+                .1
+                .push((*ep, wl.clone()));
+        }
+        let groups: Vec<_> = groups.into_values().collect();
+        match groups.choose_weighted(&mut rand::thread_rng(), |(weight, _)| *weight) {
+            Ok((_, group)) => group.iter().cloned().choose(&mut rand::thread_rng()),
+            Err(_) => {
+                trace!("no weighted subset has endpoints, selecting from all endpoints");
+                candidates.into_iter().choose(&mut rand::thread_rng())
             }
         }
     }
@@ -414,6 +489,65 @@ pub struct DemandProxyState {
 
     #[serde(skip_serializing)]
     dns_resolver: TokioAsyncResolver,
+
+    #[serde(skip_serializing)]
+    ip_family_preferences: Arc<config::IpFamilyPreferences>,
+
+    /// Addresses of hostnames that are used instead of DNS.
+    #[serde(skip_serializing)]
+    static_hosts: Arc<HashMap<String, Vec<IpAddr>>>,
+
+    /// If set, service endpoints on the same node as the source workload are selected when there are any.
+    #[serde(skip_serializing)]
+    prefer_same_node: bool,
+
+    #[serde(skip_serializing)]
+    sync: StateSync,
+}
+
+/// StateSync reports whether the initial sync of state from XDS has completed. The XDS client drops its end of
+/// the channel once every expected type has been received, which closes it. If set, the connection status of the
+/// XDS client also reports whether the state has gone stale since.
+#[derive(Clone, Debug, Default)]
+pub struct StateSync {
+    initial: Option<tokio::sync::watch::Receiver<()>>,
+    connection: Option<xds::ConnectionStatus>,
+}
+
+impl StateSync {
+    pub fn new(rx: tokio::sync::watch::Receiver<()>) -> Self {
+        StateSync {
+            initial: Some(rx),
+            connection: None,
+        }
+    }
+
+    /// with_connection_status sets the connection status of the XDS client the state is received from.
+    pub fn with_connection_status(mut self, status: xds::ConnectionStatus) -> Self {
+        self.connection = Some(status);
+        self
+    }
+
+    pub fn is_synced(&self) -> bool {
+        match &self.initial {
+            Some(rx) => rx.has_changed().is_err(),
+            None => true,
+        }
+    }
+
+    /// is_stale returns whether the connection to XDS has been lost, so updates to the state are being missed.
+    pub fn is_stale(&self) -> bool {
+        self.connection
+            .as_ref()
+            .map_or(false, |status| !status.is_connected())
+    }
+
+    pub async fn wait(&self) {
+        if let Some(rx) = &self.initial {
+            let mut rx = rx.clone();
+            while rx.changed().await.is_ok() {}
+        }
+    }
 }
 
 impl DemandProxyState {
@@ -434,7 +568,78 @@ impl DemandProxyState {
             demand,
             dns_resolver,
             metrics,
+            ip_family_preferences: Default::default(),
+            static_hosts: Default::default(),
+            prefer_same_node: false,
+            sync: Default::default(),
+        }
+    }
+
+    /// with_state_sync sets the signal for the initial sync of state from XDS. Without it, state is always
+    /// considered synced.
+    pub fn with_state_sync(mut self, sync: StateSync) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// is_available returns whether the state can be relied on to be complete: the initial sync has completed,
+    /// and the connection to XDS has not been lost since.
+    pub fn is_available(&self) -> bool {
+        self.sync.is_synced() && !self.sync.is_stale()
+    }
+
+    /// wait_for_sync applies the startup connection policy to a new connection. Once the initial sync has
+    /// completed it returns immediately; before then, the connection is rejected with Error::NotReady, or held
+    /// until the sync completes, for at most the hold timeout.
+    pub async fn wait_for_sync(
+        &self,
+        policy: config::StartupConnectionPolicy,
+        hold_timeout: Duration,
+    ) -> Result<(), Error> {
+        if self.sync.is_synced() {
+            return Ok(());
+        }
+        match policy {
+            // The proxy is not run before the sync completes, so we should never get here.
+            config::StartupConnectionPolicy::Wait => Ok(()),
+            config::StartupConnectionPolicy::Reject => Err(Error::NotReady),
+            config::StartupConnectionPolicy::Hold => {
+                tokio::time::timeout(hold_timeout, self.sync.wait())
+                    .await
+                    .map_err(|_| Error::NotReady)
+            }
+        }
+    }
+
+    /// with_prefer_same_node sets whether service endpoints on the same node as the source workload are preferred.
+    pub fn with_prefer_same_node(mut self, prefer_same_node: bool) -> Self {
+        self.prefer_same_node = prefer_same_node;
+        self
+    }
+
+    /// with_ip_family_preferences sets the per workload overrides of which IP family to use for destinations.
+    pub fn with_ip_family_preferences(mut self, preferences: config::IpFamilyPreferences) -> Self {
+        self.ip_family_preferences = Arc::new(preferences);
+        self
+    }
+
+    /// with_static_hosts sets the addresses of hostnames that are resolved without DNS. It is cheap to call on a
+    /// clone, so a reloaded configuration can be applied to new connections.
+    pub fn with_static_hosts(mut self, hosts: HashMap<String, Vec<IpAddr>>) -> Self {
+        self.static_hosts = Arc::new(hosts);
+        self
+    }
+
+    /// static_host returns the configured addresses of a hostname, if it is pinned.
+    pub fn static_host(&self, hostname: &str) -> Option<Vec<IpAddr>> {
+        if self.static_hosts.is_empty() {
+            return None;
         }
+        let ips = self
+            .static_hosts
+            .get(hostname.trim_end_matches('.').to_ascii_lowercase().as_str())?;
+        trace!(%hostname, ?ips, "using static host");
+        Some(ips.clone())
     }
 
     pub fn read(&self) -> RwLockReadGuard<'_, ProxyState> {
@@ -442,16 +647,25 @@ impl DemandProxyState {
     }
 
     pub async fn assert_rbac(&self, ctx: &ProxyRbacContext) -> bool {
+        self.check_rbac(ctx).await.is_ok()
+    }
+
+    /// check_rbac evaluates authorization policy for a connection, returning the policy and rule that allowed it,
+    /// if any, or why it was denied.
+    pub async fn check_rbac(
+        &self,
+        ctx: &ProxyRbacContext,
+    ) -> Result<rbac::RbacDecision, rbac::RbacDenial> {
         let nw_addr = network_addr(ctx.conn.dst_network.clone(), ctx.conn.dst.ip());
         let Some(wl) = self.fetch_workload(&nw_addr).await else {
             debug!("destination workload not found {}", nw_addr);
-            return false;
+            return Err(rbac::RbacDenial::UnknownDestination);
         };
         if let Some(ref wl_info) = ctx.dest_workload_info {
             // make sure that the workload we fetched matches the workload info we got over ZDS.
             if !wl_info.matches(&wl) {
                 error!("workload does not match proxy workload uid. this is probably a bug. please report an issue");
-                return false;
+                return Err(rbac::RbacDenial::WorkloadMismatch);
             }
         }
         let conn = &ctx.conn;
@@ -483,38 +697,13 @@ impl DemandProxyState {
             deny = deny.len(),
             "checking connection"
         );
-
-        // Allow and deny logic follows https://istio.io/latest/docs/reference/config/security/authorization-policy/
-
-        // "If there are any DENY policies that match the request, deny the request."
-        for pol in deny.iter() {
-            if pol.matches(conn) {
-                debug!(policy = pol.to_key().as_str(), "deny policy match");
-                return false;
-            } else {
-                trace!(policy = pol.to_key().as_str(), "deny policy does not match");
-            }
+        let decision = rbac::evaluate(&deny, &allow, conn);
+        match decision.action {
+            rbac::RbacAction::Allow => debug!(%conn, %decision, "connection authorized"),
+            // The source identity is rendered according to the identity log mode, like anywhere it is logged.
+            rbac::RbacAction::Deny => info!(%conn, %decision, "connection denied"),
         }
-        // "If there are no ALLOW policies for the workload, allow the request."
-        if allow.is_empty() {
-            debug!("no allow policies, allow");
-            return true;
-        }
-        // "If any of the ALLOW policies match the request, allow the request."
-        for pol in allow.iter() {
-            if pol.matches(conn) {
-                debug!(policy = pol.to_key().as_str(), "allow policy match");
-                return true;
-            } else {
-                trace!(
-                    policy = pol.to_key().as_str(),
-                    "allow policy does not match"
-                );
-            }
-        }
-        // "Deny the request."
-        debug!("no allow policies matched");
-        false
+        decision.into_result()
     }
 
     // Select a workload IP, with DNS resolution if needed
@@ -533,8 +722,15 @@ impl DemandProxyState {
             return Ok(original_target_address.ip());
         }
         // They may have 1 or 2 IPs (single/dual stack)
-        // Ensure we are meeting the Service family restriction (if any is defined).
-        // Otherwise, prefer the same IP family as the original request.
+        // Ensure we are meeting the Service family restriction (if any is defined), and the source workload's
+        // family preference (if any is configured).
+        // Prefer the family the source workload asked for, otherwise the same IP family as the original request.
+        let preference = self
+            .ip_family_preferences
+            .preference_for(&src_workload.namespace, &src_workload.name);
+        let prefer_ipv6 = preference
+            .map(|p| p.prefers_ipv6())
+            .unwrap_or(original_target_address.is_ipv6());
         if let Some(ip) = dst_workload
             .workload_ips
             .iter()
@@ -542,8 +738,9 @@ impl DemandProxyState {
                 ip_family_restriction
                     .map(|f| f.accepts_ip(**ip))
                     .unwrap_or(true)
+                    && preference.map(|p| p.accepts_ip(**ip)).unwrap_or(true)
             })
-            .find_or_first(|ip| ip.is_ipv6() == original_target_address.is_ipv6())
+            .find_or_first(|ip| ip.is_ipv6() == prefer_ipv6)
         {
             return Ok(*ip);
         }
@@ -554,7 +751,8 @@ impl DemandProxyState {
             );
             return Err(Error::NoValidDestination(Box::new(dst_workload.clone())));
         }
-        let ip = Box::pin(self.resolve_workload_address(dst_workload, src_workload)).await?;
+        let ip =
+            Box::pin(self.resolve_workload_address(dst_workload, src_workload, preference)).await?;
         Ok(ip)
     }
 
@@ -562,6 +760,7 @@ impl DemandProxyState {
         &self,
         workload: &Workload,
         src_workload: &Workload,
+        preference: Option<config::IpFamilyPreference>,
     ) -> Result<IpAddr, Error> {
         let labels = OnDemandDnsLabels::new()
             .with_destination(workload)
@@ -571,26 +770,54 @@ impl DemandProxyState {
             .on_demand_dns
             .get_or_create(&labels)
             .inc();
-        self.resolve_on_demand_dns(workload).await
+        self.metrics
+            .time_setup_phase(
+                proxy::SetupPhase::dns_resolution,
+                self.resolve_on_demand_dns(workload, preference),
+            )
+            .await
     }
 
-    async fn resolve_on_demand_dns(&self, workload: &Workload) -> Result<IpAddr, Error> {
+    async fn resolve_on_demand_dns(
+        &self,
+        workload: &Workload,
+        preference: Option<config::IpFamilyPreference>,
+    ) -> Result<IpAddr, Error> {
         let workload_uid = workload.uid.clone();
         let hostname = workload.hostname.clone();
-        trace!(%hostname, "starting DNS lookup");
-
-        let resp = match self.dns_resolver.lookup_ip(hostname.as_str()).await {
-            Err(err) => {
-                warn!(?err,%hostname,"dns lookup failed");
-                return Err(Error::NoResolvedAddresses(workload_uid.to_string()));
+        let resolved = match self.static_host(&hostname) {
+            Some(ips) => ips,
+            None => {
+                trace!(%hostname, "starting DNS lookup");
+                let resp = match self.dns_resolver.lookup_ip(hostname.as_str()).await {
+                    Err(err) => {
+                        warn!(?err,%hostname,"dns lookup failed");
+                        return Err(Error::NoResolvedAddresses(workload_uid.to_string()));
+                    }
+                    Ok(resp) => resp,
+                };
+                trace!(%hostname, "dns lookup complete {resp:?}");
+                resp.as_lookup()
+                    .record_iter()
+                    .filter_map(|record| record.data().and_then(|d| d.ip_addr()))
+                    .collect_vec()
             }
-            Ok(resp) => resp,
         };
-        trace!(%hostname, "dns lookup complete {resp:?}");
 
-        resp.as_lookup()
-            .record_iter()
-            .filter_map(|record| record.data().and_then(|d| d.ip_addr()))
+        let ips = resolved
+            .into_iter()
+            .filter(|ip| preference.map(|p| p.accepts_ip(*ip)).unwrap_or(true))
+            .collect_vec();
+        // If the source workload prefers a family, only fall back to the other one when there is no choice.
+        let candidates = match preference {
+            Some(p) if ips.iter().any(|ip| ip.is_ipv6() == p.prefers_ipv6()) => ips
+                .into_iter()
+                .filter(|ip| ip.is_ipv6() == p.prefers_ipv6())
+                .collect_vec(),
+            _ => ips,
+        };
+        candidates
+            .into_iter()
             // TODO: add more sophisticated routing logic, perhaps based on ipv4/ipv6 support underneath us.
             // if/when we support that, this function may need to move to get access to the necessary metadata.
             // Randomly pick an IP
@@ -599,6 +826,52 @@ impl DemandProxyState {
             .ok_or_else(|| Error::EmptyResolvedAddresses(workload_uid.to_string()))
     }
 
+    /// resolve_hostname looks up a hostname that is not a known workload, such as an egress destination.
+    pub async fn resolve_hostname(&self, hostname: &str) -> Result<IpAddr, Error> {
+        if let Some(ips) = self.static_host(hostname) {
+            return ips
+                .into_iter()
+                .choose(&mut rand::thread_rng())
+                .ok_or(Error::DnsEmpty);
+        }
+        trace!(%hostname, "starting DNS lookup");
+        let lookup = async {
+            let resp = self.dns_resolver.lookup_ip(hostname).await.map_err(|err| {
+                warn!(?err, %hostname, "dns lookup failed");
+                Error::ResolveHostname(hostname.to_string())
+            })?;
+            resp.iter()
+                .choose(&mut rand::thread_rng())
+                .ok_or(Error::DnsEmpty)
+        };
+        self.metrics
+            .time_setup_phase(proxy::SetupPhase::dns_resolution, lookup)
+            .await
+    }
+
+    /// resolve_hostname_all looks up all addresses of a hostname that is not a known workload, in the order
+    /// the resolver returned them.
+    pub async fn resolve_hostname_all(&self, hostname: &str) -> Result<Vec<IpAddr>, Error> {
+        if let Some(ips) = self.static_host(hostname) {
+            return Ok(ips);
+        }
+        trace!(%hostname, "starting DNS lookup");
+        let lookup = async {
+            let resp = self.dns_resolver.lookup_ip(hostname).await.map_err(|err| {
+                warn!(?err, %hostname, "dns lookup failed");
+                Error::NoResolvedAddresses(hostname.to_string())
+            })?;
+            let ips = resp.iter().collect_vec();
+            if ips.is_empty() {
+                return Err(Error::EmptyResolvedAddresses(hostname.to_string()));
+            }
+            Ok(ips)
+        };
+        self.metrics
+            .time_setup_phase(proxy::SetupPhase::dns_resolution, lookup)
+            .await
+    }
+
     pub async fn fetch_workload_services(
         &self,
         addr: &NetworkAddress,
@@ -661,6 +934,12 @@ impl DemandProxyState {
         }
     }
 
+    // Returns a subscriber that is woken when a service or its endpoints change. Take it *before* checking state,
+    // so no change is missed.
+    pub fn subscribe_services(&self) -> tokio::sync::watch::Receiver<()> {
+        self.state.read().unwrap().services.new_subscriber()
+    }
+
     // only support workload
     pub async fn fetch_workload(&self, addr: &NetworkAddress) -> Option<Arc<Workload>> {
         // Wait for it on-demand, *if* needed
@@ -689,12 +968,19 @@ impl DemandProxyState {
         self.state.read().unwrap().workloads.find_uid(uid)
     }
 
+    /// fetch_upstream selects the upstream for a connection from `source_workload` to `addr`. If
+    /// `record_selection` is set, the choice of subset or hashed endpoint is counted in the metrics;
+    /// selections that do not carry a request, such as for warming connections, should not be counted.
+    /// `headers` are those of the first HTTP request on the connection, if known, for services that hash
+    /// on a request header.
     pub async fn fetch_upstream(
         &self,
         network: Strng,
         source_workload: &Workload,
         addr: SocketAddr,
         resolution_mode: ServiceResolutionMode,
+        record_selection: bool,
+        headers: Option<&http::HeaderMap>,
     ) -> Result<Option<Upstream>, Error> {
         self.fetch_address(&network_addr(network.clone(), addr.ip()))
             .await;
@@ -703,9 +989,33 @@ impl DemandProxyState {
             source_workload,
             addr,
             resolution_mode,
+            self.prefer_same_node,
+            headers,
         ) else {
             return Ok(None);
         };
+        if let Some(s) = svc.as_ref().filter(|_| record_selection) {
+            if let Some(subsets) = &s.subset_weights {
+                let labels = SubsetLabels {
+                    destination_service: s.hostname.clone().into(),
+                    subset: subsets.key.subset(&wl).clone().into(),
+                };
+                self.metrics.subset_requests.get_or_create(&labels).inc();
+            }
+            if s.load_balancer
+                .as_ref()
+                .is_some_and(|lb| lb.consistent_hash.is_some())
+            {
+                let labels = ConsistentHashLabels {
+                    destination_service: s.hostname.clone().into(),
+                    destination_workload: wl.name.clone().into(),
+                };
+                self.metrics
+                    .consistent_hash_selections
+                    .get_or_create(&labels)
+                    .inc();
+            }
+        }
         let svc_desc = svc.clone().map(|s| ServiceDescription::from(s.as_ref()));
         let ip_family_restriction = svc.as_ref().and_then(|s| s.ip_families);
         let selected_workload_ip = self
@@ -739,6 +1049,8 @@ impl DemandProxyState {
             source_workload,
             wp_socket_addr,
             ServiceResolutionMode::Waypoint,
+            true,
+            None,
         )
         .await?
         .ok_or_else(|| Error::UnknownWaypoint(format!("waypoint {} not found", wp_nw_addr.address)))
@@ -858,6 +1170,7 @@ impl ProxyStateManager {
         cert_manager: Arc<SecretManager>,
     ) -> anyhow::Result<ProxyStateManager> {
         let cert_fetcher = cert_fetcher::new(&config, cert_manager);
+        let sync = StateSync::new(awaiting_ready.subscribe());
         let state: Arc<RwLock<ProxyState>> = Arc::new(RwLock::new(ProxyState::default()));
         let xds_client = if config.xds_address.is_some() {
             let updater = ProxyStateUpdater::new(state.clone(), cert_fetcher.clone());
@@ -882,6 +1195,10 @@ impl ProxyStateManager {
             local_client.run().await?;
         }
         let demand = xds_client.as_ref().and_then(AdsClient::demander);
+        let sync = match &xds_client {
+            Some(xds) => sync.with_connection_status(xds.connection_status()),
+            None => sync,
+        };
         Ok(ProxyStateManager {
             xds_client,
             state: DemandProxyState::new(
@@ -890,7 +1207,10 @@ impl ProxyStateManager {
                 config.dns_resolver_cfg.clone(),
                 config.dns_resolver_opts.clone(),
                 proxy_metrics,
-            ),
+            )
+            .with_ip_family_preferences(config.ip_family_preferences.clone())
+            .with_prefer_same_node(config.prefer_same_node)
+            .with_state_sync(sync),
         })
     }
 
@@ -908,9 +1228,10 @@ impl ProxyStateManager {
 
 #[cfg(test)]
 mod tests {
-    use crate::state::service::LoadBalancer;
+    use crate::state::service::{ConsistentHash, HashKey, LoadBalancer, SubsetKey};
     use crate::state::workload::Locality;
     use prometheus_client::registry::Registry;
+    use std::collections::HashSet;
     use std::{net::Ipv4Addr, net::SocketAddrV4, time::Duration};
 
     use self::workload::{application_tunnel::Protocol as AppProtocol, ApplicationTunnel};
@@ -951,6 +1272,171 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn test_fetch_upstream_records_selection() {
+        let mut state = ProxyState::default();
+        let uid: Strng = "cluster1//v1/Pod/default/pod".into();
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+        state.workloads.insert(
+            Arc::new(Workload {
+                uid: uid.clone(),
+                name: "pod".into(),
+                workload_ips: vec![ip],
+                canonical_revision: "v1".into(),
+                ..test_helpers::test_default_workload()
+            }),
+            true,
+        );
+        let mut svc = Service {
+            ports: HashMap::from([(80u16, 80u16)]),
+            subset_weights: Some(SubsetWeights {
+                key: SubsetKey::CanonicalRevision,
+                weights: HashMap::from([("v1".into(), 100)]),
+            }),
+            ..test_helpers::mock_default_service()
+        };
+        svc.endpoints.insert(
+            uid.clone(),
+            Endpoint {
+                workload_uid: uid,
+                service: NamespacedHostname {
+                    namespace: svc.namespace.clone(),
+                    hostname: svc.hostname.clone(),
+                },
+                address: Some(NetworkAddress {
+                    address: ip,
+                    network: "".into(),
+                }),
+                port: HashMap::from([(80u16, 80u16)]),
+            },
+        );
+        let vip = SocketAddr::new(svc.vips[0].address, 80);
+        state.services.insert(svc);
+
+        let mut registry = Registry::default();
+        let metrics = Arc::new(crate::proxy::Metrics::new(&mut registry));
+        let state = DemandProxyState::new(
+            Arc::new(RwLock::new(state)),
+            None,
+            ResolverConfig::default(),
+            ResolverOpts::default(),
+            metrics.clone(),
+        );
+        let src = test_helpers::test_default_workload();
+
+        // Only the selection made for a request is counted, though both select the same subset.
+        for record_selection in [true, false] {
+            let us = state
+                .fetch_upstream(
+                    strng::EMPTY,
+                    &src,
+                    vip,
+                    ServiceResolutionMode::Standard,
+                    record_selection,
+                    None,
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(us.selected_workload_ip, ip);
+        }
+        let labels = SubsetLabels {
+            destination_service: strng::literal!("defaulthost").into(),
+            subset: strng::literal!("v1").into(),
+        };
+        assert_eq!(metrics.subset_requests.get_or_create(&labels).get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_sync() {
+        use config::StartupConnectionPolicy::{Hold, Reject};
+
+        let mut registry = Registry::default();
+        let metrics = Arc::new(crate::proxy::Metrics::new(&mut registry));
+        let (tx, rx) = tokio::sync::watch::channel(());
+        let state = DemandProxyState::new(
+            Arc::new(RwLock::new(ProxyState::default())),
+            None,
+            ResolverConfig::default(),
+            ResolverOpts::default(),
+            metrics,
+        )
+        .with_state_sync(StateSync::new(rx));
+        let hold = Duration::from_millis(50);
+
+        // Before the initial sync, connections are rejected, or held and then rejected once the hold expires.
+        assert!(matches!(
+            state.wait_for_sync(Reject, hold).await,
+            Err(Error::NotReady)
+        ));
+        assert!(matches!(
+            state.wait_for_sync(Hold, hold).await,
+            Err(Error::NotReady)
+        ));
+
+        // A held connection proceeds as soon as the sync completes.
+        let held = {
+            let state = state.clone();
+            tokio::spawn(async move { state.wait_for_sync(Hold, Duration::from_secs(5)).await })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(tx);
+        assert!(held.await.unwrap().is_ok());
+
+        // After the sync, connections are let through right away.
+        assert!(state.wait_for_sync(Reject, hold).await.is_ok());
+        assert!(state.wait_for_sync(Hold, hold).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_static_hosts() {
+        use crate::test_helpers::dns::{ip, n, run_dns};
+
+        let dns = run_dns(HashMap::from([(
+            n("resolved.example.com."),
+            vec![ip("10.0.0.1")],
+        )]))
+        .await
+        .unwrap();
+        let mut registry = Registry::default();
+        let metrics = Arc::new(crate::proxy::Metrics::new(&mut registry));
+        let state = DemandProxyState::new(
+            Arc::new(RwLock::new(ProxyState::default())),
+            None,
+            dns.resolver_config(),
+            ResolverOpts::default(),
+            metrics,
+        )
+        .with_static_hosts(HashMap::from([(
+            "pinned.example.com".to_string(),
+            vec![ip("192.0.2.1"), ip("192.0.2.2")],
+        )]));
+
+        // A pinned hostname is answered from the configuration, however it is written.
+        assert_eq!(
+            state
+                .resolve_hostname_all("Pinned.example.com.")
+                .await
+                .unwrap(),
+            vec![ip("192.0.2.1"), ip("192.0.2.2")]
+        );
+        let pinned = state.resolve_hostname("pinned.example.com").await.unwrap();
+        assert!([ip("192.0.2.1"), ip("192.0.2.2")].contains(&pinned));
+
+        // Other hostnames fall through to the resolver.
+        assert_eq!(
+            state
+                .resolve_hostname_all("resolved.example.com.")
+                .await
+                .unwrap(),
+            vec![ip("10.0.0.1")]
+        );
+        assert!(state
+            .resolve_hostname_all("unknown.example.com.")
+            .await
+            .is_err());
+    }
+
     #[tokio::test]
     async fn test_wait_for_workload_delay_fails() {
         let state = ProxyState::default();
@@ -1196,7 +1682,14 @@ mod tests {
         };
 
         let (_, port, _) = state
-            .find_upstream("".into(), &wl, "10.0.0.1:80".parse().unwrap(), mode)
+            .find_upstream(
+                "".into(),
+                &wl,
+                "10.0.0.1:80".parse().unwrap(),
+                mode,
+                false,
+                None,
+            )
             .expect("upstream to be found");
         assert_eq!(port, tc.expected_port());
     }
@@ -1397,6 +1890,7 @@ mod tests {
                     LoadBalancerScopes::Region,
                     LoadBalancerScopes::Zone,
                 ],
+                consistent_hash: None,
             }),
             ports: HashMap::from([(80u16, 80u16)]),
             ..test_helpers::mock_default_service()
@@ -1410,6 +1904,7 @@ mod tests {
                     LoadBalancerScopes::Region,
                     LoadBalancerScopes::Zone,
                 ],
+                consistent_hash: None,
             }),
             ports: HashMap::from([(80u16, 80u16)]),
             ..test_helpers::mock_default_service()
@@ -1429,6 +1924,8 @@ mod tests {
                     svc,
                     "0.0.0.0:80".parse().unwrap(),
                     ServiceResolutionMode::Standard,
+                    false,
+                    None,
                 )
                 .and_then(|(ep, _)| ep.address.clone())
                 .map(|addr| addr.address.to_string());
@@ -1473,4 +1970,352 @@ mod tests {
             "failover full match selects closest match",
         );
     }
+
+    #[test]
+    fn test_load_balance_weighted_subsets() {
+        initialize_telemetry();
+        let mut state = ProxyState::default();
+        let mut endpoints = HashMap::new();
+        for (i, revision) in ["v1", "v1", "v2", "v2", "v3"].into_iter().enumerate() {
+            let uid: Strng = format!("cluster1//v1/Pod/default/pod-{i}").into();
+            let ip = Ipv4Addr::new(192, 168, 0, i as u8 + 1);
+            state.workloads.insert(
+                Arc::new(Workload {
+                    uid: uid.clone(),
+                    name: format!("pod-{i}").into(),
+                    workload_ips: vec![IpAddr::V4(ip)],
+                    canonical_revision: revision.into(),
+                    ..test_helpers::test_default_workload()
+                }),
+                true,
+            );
+            endpoints.insert(
+                uid.clone(),
+                Endpoint {
+                    workload_uid: uid,
+                    service: NamespacedHostname {
+                        namespace: TEST_SERVICE_NAMESPACE.into(),
+                        hostname: "example.com".into(),
+                    },
+                    address: Some(NetworkAddress {
+                        address: IpAddr::V4(ip),
+                        network: "".into(),
+                    }),
+                    port: HashMap::from([(80u16, 80u16)]),
+                },
+            );
+        }
+        let mut svc = Service {
+            endpoints,
+            ports: HashMap::from([(80u16, 80u16)]),
+            subset_weights: Some(SubsetWeights {
+                key: SubsetKey::CanonicalRevision,
+                weights: HashMap::from([("v1".into(), 90), ("v2".into(), 10)]),
+            }),
+            ..test_helpers::mock_default_service()
+        };
+        let src = test_helpers::test_default_workload();
+
+        let selections = |svc: &Service| {
+            let mut counts: HashMap<Strng, usize> = HashMap::new();
+            for _ in 0..10000 {
+                let (_, wl) = state
+                    .load_balance(
+                        &src,
+                        svc,
+                        "0.0.0.0:80".parse().unwrap(),
+                        ServiceResolutionMode::Standard,
+                        false,
+                        None,
+                    )
+                    .unwrap();
+                *counts.entry(wl.canonical_revision.clone()).or_default() += 1;
+            }
+            counts
+        };
+
+        let counts = selections(&svc);
+        let v1 = counts.get("v1").copied().unwrap_or_default();
+        assert!((8700..=9300).contains(&v1), "v1 selected {v1} times");
+        assert_eq!(
+            counts.get("v3"),
+            None,
+            "subsets without a weight are not selected"
+        );
+
+        // No weighted subset has endpoints; fall back to any endpoint.
+        svc.subset_weights = Some(SubsetWeights {
+            key: SubsetKey::CanonicalRevision,
+            weights: HashMap::from([("v4".into(), 100)]),
+        });
+        assert!(selections(&svc).contains_key("v3"));
+    }
+
+    #[test]
+    fn test_load_balance_consistent_hash() {
+        initialize_telemetry();
+        let mut state = ProxyState::default();
+        let hashed = |key| {
+            Some(LoadBalancer {
+                routing_preferences: vec![],
+                mode: LoadBalancerMode::Failover,
+                consistent_hash: Some(ConsistentHash { key }),
+            })
+        };
+        let mut svc = Service {
+            ports: HashMap::from([(80u16, 80u16)]),
+            load_balancer: hashed(HashKey::SourceIp),
+            ..test_helpers::mock_default_service()
+        };
+        for i in 1..=5 {
+            let uid: Strng = format!("cluster1//v1/Pod/default/pod-{i}").into();
+            let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 0, i));
+            state.workloads.insert(
+                Arc::new(Workload {
+                    uid: uid.clone(),
+                    name: format!("pod-{i}").into(),
+                    workload_ips: vec![ip],
+                    ..test_helpers::test_default_workload()
+                }),
+                true,
+            );
+            svc.endpoints.insert(
+                uid.clone(),
+                Endpoint {
+                    workload_uid: uid,
+                    service: NamespacedHostname {
+                        namespace: TEST_SERVICE_NAMESPACE.into(),
+                        hostname: "example.com".into(),
+                    },
+                    address: Some(NetworkAddress {
+                        address: ip,
+                        network: "".into(),
+                    }),
+                    port: HashMap::from([(80u16, 80u16)]),
+                },
+            );
+        }
+        let client = |i: u8| Workload {
+            workload_ips: vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, i))],
+            ..test_helpers::test_default_workload()
+        };
+        let select_with = |svc: &Service, src: &Workload, headers: Option<&http::HeaderMap>| {
+            let (_, wl) = state
+                .load_balance(
+                    src,
+                    svc,
+                    "0.0.0.0:80".parse().unwrap(),
+                    ServiceResolutionMode::Standard,
+                    false,
+                    headers,
+                )
+                .unwrap();
+            wl.name.clone()
+        };
+        let select = |svc: &Service, src: &Workload| select_with(svc, src, None);
+
+        // The same client always lands on the same endpoint.
+        let selected: Vec<_> = (0..20).map(|i| select(&svc, &client(i))).collect();
+        for _ in 0..10 {
+            let again: Vec<_> = (0..20).map(|i| select(&svc, &client(i))).collect();
+            assert_eq!(selected, again);
+        }
+        // Different clients are spread across endpoints.
+        assert!(selected.iter().collect::<HashSet<_>>().len() > 1);
+
+        // Removing an endpoint only moves the clients that were on it.
+        svc.endpoints.remove("cluster1//v1/Pod/default/pod-1");
+        for (i, before) in selected.iter().enumerate() {
+            let after = select(&svc, &client(i as u8));
+            if before.as_str() != "pod-1" {
+                assert_eq!(*before, after);
+            } else {
+                assert_ne!(after.as_str(), "pod-1");
+            }
+        }
+
+        // The hash is stable across releases, so the endpoint each client selects is too.
+        let pinned: Vec<_> = (0..5)
+            .map(|i| select(&svc, &client(i)).to_string())
+            .collect();
+        assert_eq!(pinned, vec!["pod-3", "pod-4", "pod-4", "pod-3", "pod-3"]);
+
+        // Hashing on a header keys on its value, whichever client sends it.
+        svc.load_balancer = hashed(HashKey::Header("x-session".into()));
+        let session = |value: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert("x-session", value.parse().unwrap());
+            headers
+        };
+        let alice = session("alice");
+        let expected = select_with(&svc, &client(0), Some(&alice));
+        for i in 1..20 {
+            assert_eq!(select_with(&svc, &client(i), Some(&alice)), expected);
+        }
+        let sessions: HashSet<_> = (0..20)
+            .map(|i| select_with(&svc, &client(0), Some(&session(&format!("user-{i}")))))
+            .collect();
+        assert!(sessions.len() > 1);
+        // Without the header, the source IP is used instead.
+        let without_header = select_with(&svc, &client(3), Some(&http::HeaderMap::new()));
+        let without_request = select(&svc, &client(3));
+        svc.load_balancer = hashed(HashKey::SourceIp);
+        let by_source_ip = select(&svc, &client(3));
+        assert_eq!(without_header, by_source_ip);
+        assert_eq!(without_request, by_source_ip);
+    }
+
+    #[test]
+    fn test_load_balance_same_node() {
+        initialize_telemetry();
+        let mut state = ProxyState::default();
+        let mut svc = Service {
+            ports: HashMap::from([(80u16, 80u16)]),
+            ..test_helpers::mock_default_service()
+        };
+        let mut add_endpoint = |i: u8, node: &str| {
+            let uid: Strng = format!("cluster1//v1/Pod/default/pod-{i}").into();
+            let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 0, i));
+            state.workloads.insert(
+                Arc::new(Workload {
+                    uid: uid.clone(),
+                    name: format!("pod-{i}").into(),
+                    workload_ips: vec![ip],
+                    node: node.into(),
+                    ..test_helpers::test_default_workload()
+                }),
+                true,
+            );
+            svc.endpoints.insert(
+                uid.clone(),
+                Endpoint {
+                    workload_uid: uid,
+                    service: NamespacedHostname {
+                        namespace: TEST_SERVICE_NAMESPACE.into(),
+                        hostname: "example.com".into(),
+                    },
+                    address: Some(NetworkAddress {
+                        address: ip,
+                        network: "".into(),
+                    }),
+                    port: HashMap::from([(80u16, 80u16)]),
+                },
+            );
+        };
+        add_endpoint(1, "node-a");
+        add_endpoint(2, "node-b");
+        add_endpoint(3, "node-b");
+        let src = Workload {
+            node: "node-a".into(),
+            ..test_helpers::test_default_workload()
+        };
+
+        let selected = |state: &ProxyState, svc: &Service, prefer_same_node: bool| {
+            let mut selected = HashSet::new();
+            for _ in 0..100 {
+                let (_, wl) = state
+                    .load_balance(
+                        &src,
+                        svc,
+                        "0.0.0.0:80".parse().unwrap(),
+                        ServiceResolutionMode::Standard,
+                        prefer_same_node,
+                        None,
+                    )
+                    .unwrap();
+                selected.insert(wl.name.clone());
+            }
+            selected
+        };
+        assert_eq!(
+            selected(&state, &svc, true),
+            HashSet::from(["pod-1".into()]),
+            "same node endpoints are preferred"
+        );
+        assert_eq!(
+            selected(&state, &svc, false),
+            HashSet::from(["pod-1".into(), "pod-2".into(), "pod-3".into()]),
+            "without the preference, any endpoint is selected"
+        );
+
+        // Once the same node endpoint is gone, the others are used.
+        svc.endpoints.remove("cluster1//v1/Pod/default/pod-1");
+        assert_eq!(
+            selected(&state, &svc, true),
+            HashSet::from(["pod-2".into(), "pod-3".into()]),
+        );
+    }
+
+    #[test]
+    fn test_load_balance_terminating() {
+        initialize_telemetry();
+        let mut state = ProxyState::default();
+        let mut svc = Service {
+            ports: HashMap::from([(80u16, 80u16)]),
+            ..test_helpers::mock_default_service()
+        };
+        let mut add_endpoint = |i: u8, status: HealthStatus| {
+            let uid: Strng = format!("cluster1//v1/Pod/default/pod-{i}").into();
+            let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 0, i));
+            state.workloads.insert(
+                Arc::new(Workload {
+                    uid: uid.clone(),
+                    name: format!("pod-{i}").into(),
+                    workload_ips: vec![ip],
+                    status,
+                    ..test_helpers::test_default_workload()
+                }),
+                true,
+            );
+            svc.endpoints.insert(
+                uid.clone(),
+                Endpoint {
+                    workload_uid: uid,
+                    service: NamespacedHostname {
+                        namespace: TEST_SERVICE_NAMESPACE.into(),
+                        hostname: "example.com".into(),
+                    },
+                    address: Some(NetworkAddress {
+                        address: ip,
+                        network: "".into(),
+                    }),
+                    port: HashMap::from([(80u16, 80u16)]),
+                },
+            );
+        };
+        add_endpoint(1, HealthStatus::Terminating);
+        add_endpoint(2, HealthStatus::Healthy);
+        add_endpoint(3, HealthStatus::Terminating);
+        let src = test_helpers::test_default_workload();
+
+        let selected = |state: &ProxyState, svc: &Service| {
+            let mut selected = HashSet::new();
+            for _ in 0..100 {
+                let (_, wl) = state
+                    .load_balance(
+                        &src,
+                        svc,
+                        "0.0.0.0:80".parse().unwrap(),
+                        ServiceResolutionMode::Standard,
+                        false,
+                        None,
+                    )
+                    .unwrap();
+                selected.insert(wl.name.clone());
+            }
+            selected
+        };
+        assert_eq!(
+            selected(&state, &svc),
+            HashSet::from(["pod-2".into()]),
+            "ready endpoints are preferred"
+        );
+
+        // Once the ready endpoint is gone, the terminating ones are used as a last resort.
+        svc.endpoints.remove("cluster1//v1/Pod/default/pod-2");
+        assert_eq!(
+            selected(&state, &svc),
+            HashSet::from(["pod-1".into(), "pod-3".into()]),
+        );
+    }
 }
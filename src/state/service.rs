@@ -13,11 +13,12 @@
 // limitations under the License.
 
 use std::collections::{HashMap, HashSet};
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
 use std::sync::Arc;
 
 use bytes::Bytes;
+use tokio::sync::watch;
 use tracing::trace;
 
 use xds::istio::workload::Service as XdsService;
@@ -55,11 +56,27 @@ pub struct Service {
 
     #[serde(default, skip_serializing_if = "is_default")]
     pub ip_families: Option<IpFamily>,
+
+    /// If set, traffic is split across subsets of the service's endpoints by weight.
+    /// This is not (yet) part of the XDS API, and can only be set with local configuration.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub subset_weights: Option<SubsetWeights>,
+
+    /// If set, a share of outbound connections to the service are mirrored to a secondary destination.
+    /// This is not (yet) part of the XDS API, and can only be set with local configuration.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub mirror: Option<Mirror>,
+
+    /// Names of the service ports, such as "http" or "grpc", keyed by service port. Inbound connections are
+    /// attributed to the named port they matched. Unnamed ports are left out.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub port_names: HashMap<u16, Strng>,
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Default, Debug, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
 pub enum LoadBalancerMode {
     Strict,
+    #[default]
     Failover,
 }
 
@@ -103,8 +120,136 @@ impl TryFrom<XdsScope> for LoadBalancerScopes {
 #[derive(Debug, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct LoadBalancer {
+    #[serde(default)]
     pub routing_preferences: Vec<LoadBalancerScopes>,
+    #[serde(default)]
     pub mode: LoadBalancerMode,
+    /// If set, the endpoint is selected among those with the most preferred locality by hashing an attribute
+    /// of the client, rather than at random, so connections from the same client keep landing on the same
+    /// endpoint. This takes precedence over subset_weights.
+    /// This is not (yet) part of the XDS API, and can only be set with local configuration.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub consistent_hash: Option<ConsistentHash>,
+}
+
+/// SubsetKey is the workload attribute used to group a service's endpoints into subsets.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub enum SubsetKey {
+    CanonicalRevision,
+    CanonicalName,
+}
+
+impl SubsetKey {
+    /// subset returns the subset the workload belongs to.
+    pub fn subset<'a>(&self, wl: &'a Workload) -> &'a Strng {
+        match self {
+            SubsetKey::CanonicalRevision => &wl.canonical_revision,
+            SubsetKey::CanonicalName => &wl.canonical_name,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SubsetWeights {
+    pub key: SubsetKey,
+    /// Relative weight of each subset. Endpoints in a subset without a weight are only selected
+    /// when none of the weighted subsets have any endpoints.
+    pub weights: HashMap<Strng, u32>,
+}
+
+impl SubsetWeights {
+    /// weight returns the configured weight for the workload's subset, if any.
+    pub fn weight(&self, wl: &Workload) -> Option<u32> {
+        self.weights.get(self.key.subset(wl)).copied()
+    }
+}
+
+/// HashKey is the client attribute that consistent hash load balancing is keyed on.
+#[derive(Debug, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub enum HashKey {
+    /// The source IP. For a workload with several IPs, its first one is used.
+    SourceIp,
+    /// The source workload, which stays the same whichever of its IPs it connects from.
+    SourceWorkload,
+    /// The value of an HTTP request header, such as a session cookie. Only the first request of a connection
+    /// is seen, and connections without the header, such as those that are not HTTP, are keyed on the source IP.
+    Header(Strng),
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ConsistentHash {
+    pub key: HashKey,
+}
+
+impl ConsistentHash {
+    /// header returns the request header that is hashed, if any.
+    pub fn header(&self) -> Option<&Strng> {
+        match &self.key {
+            HashKey::Header(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// choose picks the candidate with the highest hash of the client's key and the candidate's workload.
+    /// This is rendezvous hashing: when an endpoint is added or removed, only the clients that selected it,
+    /// or now select it, move, and there is no ring to rebuild as the endpoint set changes.
+    pub fn choose<'a, T>(
+        &self,
+        src: &Workload,
+        headers: Option<&http::HeaderMap>,
+        candidates: impl IntoIterator<Item = (T, &'a Arc<Workload>)>,
+    ) -> Option<T> {
+        let source_ip = || match src.workload_ips.first() {
+            Some(IpAddr::V4(ip)) => fnv1a(FNV_OFFSET_BASIS, &ip.octets()),
+            Some(IpAddr::V6(ip)) => fnv1a(FNV_OFFSET_BASIS, &ip.octets()),
+            None => FNV_OFFSET_BASIS,
+        };
+        let key = match &self.key {
+            HashKey::SourceIp => source_ip(),
+            HashKey::SourceWorkload => fnv1a(FNV_OFFSET_BASIS, src.uid.as_bytes()),
+            HashKey::Header(name) => match headers.and_then(|h| h.get(name.as_str())) {
+                Some(value) => fnv1a(FNV_OFFSET_BASIS, value.as_bytes()),
+                None => source_ip(),
+            },
+        };
+        candidates
+            .into_iter()
+            .max_by_key(|(_, wl)| mix(fnv1a(key, wl.uid.as_bytes())))
+            .map(|(c, _)| c)
+    }
+}
+
+// Endpoint selections must not change when ztunnel is upgraded, so rather than DefaultHasher, whose algorithm
+// is unspecified, keys are hashed with 64 bit FNV-1a.
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+// mix is the splitmix64 finalizer. FNV-1a spreads the last bytes it hashes poorly, and those are the ones that
+// tell endpoints apart, so without it some endpoints would be selected far more often than others.
+fn mix(mut hash: u64) -> u64 {
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// Mirror configures copying the client side of connections to a secondary destination, such as a new
+/// version of a service under test. Responses from the mirror are discarded.
+#[derive(Debug, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Mirror {
+    pub destination: SocketAddr,
+    /// The percentage of connections to mirror, from 0 to 100.
+    pub percentage: u8,
 }
 
 impl From<xds::istio::workload::IpFamilies> for Option<IpFamily> {
@@ -220,6 +365,7 @@ impl TryFrom<&XdsService> for Service {
                     })
                     .collect::<Result<Vec<LoadBalancerScopes>, WorkloadError>>()?,
                 mode: xds::istio::workload::load_balancing::Mode::try_from(lb.mode)?.into(),
+                consistent_hash: None,
             })
         } else {
             None
@@ -239,13 +385,21 @@ impl TryFrom<&XdsService> for Service {
             waypoint,
             load_balancer: lb,
             ip_families,
+            subset_weights: None,
+            mirror: None,
+            port_names: s
+                .ports
+                .iter()
+                .filter(|p| !p.name.is_empty())
+                .map(|p| (p.service_port as u16, strng::new(&p.name)))
+                .collect(),
         };
         Ok(svc)
     }
 }
 
 /// Data store for service information.
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct ServiceStore {
     /// Maintains a mapping of service key -> (endpoint UID -> workload endpoint)
     /// this is used to handle ordering issues if workloads are received before services.
@@ -263,12 +417,33 @@ pub struct ServiceStore {
     /// service for a given hostname. However, `ServiceEntry` allows hostnames to be overridden
     /// on a per-namespace basis.
     pub(super) by_host: HashMap<Strng, Vec<Arc<Service>>>,
+
+    // Notifies watchers whenever a service is inserted, which includes any change to its endpoints.
+    insert_notifier: watch::Sender<()>,
+}
+
+impl Default for ServiceStore {
+    fn default() -> Self {
+        ServiceStore {
+            staged_services: Default::default(),
+            workload_to_services: Default::default(),
+            by_vip: Default::default(),
+            by_host: Default::default(),
+            insert_notifier: watch::Sender::new(()),
+        }
+    }
 }
 
 impl ServiceStore {
+    /// Returns a new subscriber, woken when a service or its endpoints change. As for workloads, only changes
+    /// after the subscriber is created are seen, so callers should subscribe before checking the current state.
+    pub fn new_subscriber(&self) -> watch::Receiver<()> {
+        self.insert_notifier.subscribe()
+    }
+
     /// Returns the [Service] matching the given VIP.
     pub fn get_by_vip(&self, vip: &NetworkAddress) -> Option<Arc<Service>> {
-        self.by_vip.get(vip).cloned()
+        self.by_vip.get(&vip.to_canonical()).cloned()
     }
 
     /// Returns the list of [Service]s matching the given hostname. Istio `ServiceEntry`
@@ -422,6 +597,8 @@ impl ServiceStore {
                 .or_default()
                 .insert(namespaced_hostname.clone());
         }
+
+        self.insert_notifier.send_replace(());
     }
 
     /// Removes the service for the given host and namespace.
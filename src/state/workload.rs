@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use crate::identity::Identity;
+use crate::state::WorkloadInfo;
 
 use crate::strng::Strng;
 use crate::xds::istio::workload::{Port, PortList};
@@ -64,6 +65,44 @@ pub enum HealthStatus {
     #[default]
     Healthy,
     Unhealthy,
+    // The workload is shutting down, but may still serve traffic. Like Kubernetes' terminating endpoints, it is
+    // only selected for a Service when no healthy endpoint is available.
+    // xDS has no way to express this yet, so it can only be set through local configuration.
+    Terminating,
+}
+
+/// TrafficClass selects the tradeoff between latency and efficiency for a workload's plaintext TCP connections.
+#[derive(
+    Default, Debug, Hash, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize,
+)]
+pub enum TrafficClass {
+    /// Writes are sent immediately (TCP_NODELAY), which suits request/response traffic.
+    #[default]
+    Interactive,
+    /// Nagle's algorithm is left enabled, so small writes are coalesced into full segments. For bulk
+    /// transfers, such as backups or object storage, this improves throughput and reduces packet counts, at the
+    /// cost of delaying small writes by up to a round trip.
+    Bulk,
+}
+
+impl TrafficClass {
+    /// for_connection returns the class for a connection between two workloads. Bulk wins, as either side
+    /// streaming large amounts of data benefits from batching.
+    pub fn for_connection(src: Option<&Workload>, dst: Option<&Workload>) -> TrafficClass {
+        if [src, dst]
+            .iter()
+            .flatten()
+            .any(|w| w.traffic_class == TrafficClass::Bulk)
+        {
+            TrafficClass::Bulk
+        } else {
+            TrafficClass::Interactive
+        }
+    }
+
+    pub fn nodelay(self) -> bool {
+        self == TrafficClass::Interactive
+    }
 }
 
 #[derive(Default, Debug, Hash, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
@@ -204,6 +243,43 @@ pub struct Workload {
 
     #[serde(default, skip_serializing_if = "is_default")]
     pub locality: Locality,
+
+    /// The SNI to present when connecting to this workload over TLS, for upstreams that route on it, such as
+    /// a gateway shared by many services. By default, no SNI is sent.
+    /// This is not part of the xDS API, so can only be set through local configuration.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub tls_sni_override: Option<Strng>,
+
+    /// How connections to and from this workload are tuned at the TCP level.
+    /// Like the SNI override, this can only be set through local configuration.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub traffic_class: TrafficClass,
+
+    /// If set, the destinations this workload may connect to. Other outbound connections are rejected before
+    /// they are established, so an empty list rejects every outbound connection. If unset, outbound connections
+    /// are not restricted.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub egress_rules: Option<Vec<EgressRule>>,
+}
+
+/// EgressRule allows a workload to connect to the given hosts: destination service hostnames, or IP addresses for
+/// destinations that are not a service. As in the egress SNI allowlist, hosts may be wildcards such as
+/// "*.example.com". If ports are set, only those ports are allowed.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct EgressRule {
+    pub hosts: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<u16>,
+}
+
+impl From<xds::istio::workload::EgressRule> for EgressRule {
+    fn from(rule: xds::istio::workload::EgressRule) -> Self {
+        EgressRule {
+            hosts: rule.hosts,
+            ports: rule.ports.into_iter().map(|p| p as u16).collect(),
+        }
+    }
 }
 
 pub fn is_default<T: Default + PartialEq>(t: &T) -> bool {
@@ -273,6 +349,7 @@ impl From<HashMap<u16, u16>> for PortList {
                 .map(|(k, v)| Port {
                     service_port: *k as u32,
                     target_port: *v as u32,
+                    ..Default::default()
                 })
                 .collect(),
         }
@@ -419,6 +496,12 @@ impl TryFrom<XdsWorkload> for (Workload, HashMap<String, PortList>) {
                     result.into()
                 }
             },
+
+            tls_sni_override: None,
+            traffic_class: Default::default(),
+            egress_rules: resource
+                .egress_policy
+                .map(|p| p.rules.into_iter().map(EgressRule::from).collect()),
         };
         // Return back part we did not use (service) so it can be consumed without cloning
         Ok((wl, resource.services))
@@ -504,6 +587,17 @@ pub struct NetworkAddress {
     pub address: IpAddr,
 }
 
+impl NetworkAddress {
+    /// to_canonical converts an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) to the IPv4 address it maps, which
+    /// is how workloads and services are keyed.
+    pub fn to_canonical(&self) -> NetworkAddress {
+        NetworkAddress {
+            network: self.network.clone(),
+            address: self.address.to_canonical(),
+        }
+    }
+}
+
 // we need custom serde serialization since NetworkAddress is keying maps
 impl Serialize for NetworkAddress {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -653,7 +747,7 @@ impl WorkloadStore {
 
     /// Finds the workload by address, as an arc.
     pub fn find_address(&self, addr: &NetworkAddress) -> Option<Arc<Workload>> {
-        self.by_addr.get(addr).cloned()
+        self.by_addr.get(&addr.to_canonical()).cloned()
     }
 
     /// Finds the workload by uid.
@@ -661,6 +755,12 @@ impl WorkloadStore {
         self.by_uid.get(uid).cloned()
     }
 
+    /// Finds the workload matching the info we were given for a dedicated proxy. This scans all
+    /// workloads, so should not be used per-connection.
+    pub fn find_info(&self, info: &WorkloadInfo) -> Option<Arc<Workload>> {
+        self.by_uid.values().find(|w| info.matches(w)).cloned()
+    }
+
     pub fn has_identity(&self, identity: &Identity) -> bool {
         self.by_identity.contains_key(identity)
     }
@@ -819,6 +919,7 @@ mod tests {
                 ports: vec![XdsPort {
                     service_port: 80,
                     target_port: 8080,
+                    ..Default::default()
                 }],
             },
         )]);
@@ -913,6 +1014,7 @@ mod tests {
                     ports: vec![XdsPort {
                         service_port: 80,
                         target_port: 80,
+                        ..Default::default()
                     }],
                     subject_alt_names: vec![],
                     waypoint: None,
@@ -946,6 +1048,7 @@ mod tests {
                     ports: vec![XdsPort {
                         service_port: 80,
                         target_port: 80,
+                        ..Default::default()
                     }],
                     subject_alt_names: vec![],
                     waypoint: None,
@@ -1002,6 +1105,7 @@ mod tests {
                     ports: vec![XdsPort {
                         service_port: 80,
                         target_port: 80,
+                        ..Default::default()
                     }],
                     subject_alt_names: vec![],
                     waypoint: None,
@@ -1182,6 +1286,7 @@ mod tests {
                 ports: vec![XdsPort {
                     service_port: 80,
                     target_port: 8080,
+                    ..Default::default()
                 }],
             },
         )]);
@@ -1253,6 +1358,8 @@ mod tests {
                 &wl,
                 "127.0.1.1:80".parse().unwrap(),
                 ServiceResolutionMode::Standard,
+                false,
+                None,
             ) {
                 let n = &workload.name; // borrow name instead of cloning
                 found.insert(n.to_string()); // insert an owned copy of the borrowed n
@@ -1309,6 +1416,8 @@ mod tests {
                 wl.as_ref().unwrap(),
                 "127.10.0.1:80".parse().unwrap(),
                 ServiceResolutionMode::Standard,
+                false,
+                None,
             )
             .expect("should get");
         // Make sure we get a valid VIP
@@ -1328,9 +1437,64 @@ mod tests {
                 wl.as_ref().unwrap(),
                 "127.10.0.2:80".parse().unwrap(),
                 ServiceResolutionMode::Standard,
+                false,
+                None,
             )
             .expect("should get");
         // Make sure we get a valid VIP
         assert_eq!(port, 8080);
     }
+
+    #[test]
+    fn service_port_names() {
+        let svc = crate::state::service::Service::try_from(&XdsService {
+            hostname: "svc1.ns.svc.cluster.local".to_string(),
+            ports: vec![
+                XdsPort {
+                    service_port: 80,
+                    target_port: 8080,
+                    name: "http".to_string(),
+                },
+                XdsPort {
+                    service_port: 9090,
+                    target_port: 9090,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(svc.port_names, HashMap::from([(80, "http".into())]));
+    }
+
+    #[test]
+    fn egress_policy() {
+        let convert = |egress_policy| {
+            Workload::try_from(XdsWorkload {
+                uid: "uid".to_string(),
+                egress_policy,
+                ..Default::default()
+            })
+            .unwrap()
+            .egress_rules
+        };
+        assert_eq!(convert(None), None);
+        // A policy without rules is kept, so that it denies everything
+        assert_eq!(
+            convert(Some(xds::istio::workload::EgressPolicy::default())),
+            Some(vec![])
+        );
+        assert_eq!(
+            convert(Some(xds::istio::workload::EgressPolicy {
+                rules: vec![xds::istio::workload::EgressRule {
+                    hosts: vec!["*.example.com".to_string()],
+                    ports: vec![443],
+                }],
+            })),
+            Some(vec![EgressRule {
+                hosts: vec!["*.example.com".to_string()],
+                ports: vec![443],
+            }])
+        );
+    }
 }
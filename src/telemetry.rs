@@ -39,6 +39,8 @@ use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFi
 use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::{filter, prelude::*, reload, Layer, Registry};
 
+pub mod targeted;
+
 pub static APPLICATION_START_TIME: Lazy<Instant> = Lazy::new(Instant::now);
 static LOG_HANDLE: OnceCell<LogHandle> = OnceCell::new();
 
@@ -77,7 +79,7 @@ fn fmt_layer(writer: NonBlocking) -> Box<dyn Layer<Registry> + Send + Sync + 'st
         plain_fmt(writer)
     };
     let filter = default_filter();
-    let (layer, reload) = reload::Layer::new(format.with_filter(filter));
+    let (layer, reload) = reload::Layer::new(format.with_filter(targeted::LogFilter::new(filter)));
     LOG_HANDLE
         .set(reload)
         .map_or_else(|_| warn!("setup log handler failed"), |_| {});
@@ -85,17 +87,20 @@ fn fmt_layer(writer: NonBlocking) -> Box<dyn Layer<Registry> + Send + Sync + 'st
 }
 
 fn default_filter() -> filter::Targets {
-    // Read from env var, but prefix with setting DNS logs to warn as they are noisy; they can be explicitly overriden
+    // Read from env var, but prefix with setting DNS logs to warn as they are noisy; they can be explicitly overriden.
+    // The structured access log is opt-in, as it duplicates the default access logs.
     let var: String = env::var("RUST_LOG")
         .map_err(|_| ())
-        .map(|v| "hickory_server::server::server_future=off,".to_string() + v.as_str())
-        .unwrap_or("hickory_server::server::server_future=off,info".to_string());
+        .map(|v| {
+            "hickory_server::server::server_future=off,access_log=off,".to_string() + v.as_str()
+        })
+        .unwrap_or("hickory_server::server::server_future=off,access_log=off,info".to_string());
     filter::Targets::from_str(&var).expect("static filter should build")
 }
 
 // a handle to get and set the log level
 type BoxLayer = Box<dyn Layer<Registry> + Send + Sync + 'static>;
-type FilteredLayer = filter::Filtered<BoxLayer, filter::Targets, Registry>;
+type FilteredLayer = filter::Filtered<BoxLayer, targeted::LogFilter, Registry>;
 type LogHandle = reload::Handle<FilteredLayer, Registry>;
 
 /// set_level dynamically updates the logging level to *include* level. If `reset` is true, it will
@@ -124,7 +129,7 @@ pub fn set_level(reset: bool, level: &str) -> Result<(), Error> {
 
         //set the new filter
         Ok(handle.modify(|layer| {
-            *layer.filter_mut() = new_filter;
+            layer.filter_mut().set_targets(new_filter);
         })?)
     } else {
         warn!("failed to get log handle");
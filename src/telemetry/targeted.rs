@@ -0,0 +1,330 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Targeted logging raises the log level for the connections matching a set of rules only, so a single
+//! misbehaving workload can be debugged without turning on verbose logging for everything.
+//!
+//! Rules are evaluated once, when a connection is opened. A matching connection's span is marked with the
+//! rule's level, and events within that span are then logged up to that level, regardless of the global filter.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
+use tracing::level_filters::LevelFilter;
+use tracing::subscriber::Interest;
+use tracing::{Level, Metadata, Span, Subscriber};
+use tracing_subscriber::layer::{Context, Filter};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{filter, Registry};
+
+use crate::identity::Identity;
+
+// The rules configured through the admin endpoint, which apply to every connection in the process.
+static RULES: Lazy<Arc<Rules>> = Lazy::new(Default::default);
+
+/// Rule selects connections to log verbosely. All of the fields that are set must match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub source_identity: Option<Identity>,
+    /// The hostname of the destination service.
+    pub destination_service: Option<String>,
+    /// Matches either the source or destination address.
+    pub cidr: Option<IpNet>,
+    pub level: Level,
+}
+
+impl Rule {
+    pub fn is_empty(&self) -> bool {
+        self.source_identity.is_none() && self.destination_service.is_none() && self.cidr.is_none()
+    }
+
+    fn matches(&self, conn: &Connection) -> bool {
+        self.source_identity
+            .as_ref()
+            .map_or(true, |id| Some(id) == conn.source_identity)
+            && self
+                .destination_service
+                .as_deref()
+                .map_or(true, |svc| Some(svc) == conn.destination_service)
+            && self.cidr.map_or(true, |cidr| {
+                cidr.contains(&conn.src.to_canonical()) || cidr.contains(&conn.dst.to_canonical())
+            })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(id) = &self.source_identity {
+            write!(f, "source_identity={id} ")?;
+        }
+        if let Some(svc) = &self.destination_service {
+            write!(f, "destination_service={svc} ")?;
+        }
+        if let Some(cidr) = &self.cidr {
+            write!(f, "cidr={cidr} ")?;
+        }
+        write!(f, "level={}", self.level.as_str().to_lowercase())
+    }
+}
+
+/// Connection is what rules are matched against.
+pub struct Connection<'a> {
+    pub source_identity: Option<&'a Identity>,
+    pub destination_service: Option<&'a str>,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+}
+
+/// Rules is a set of rules, shared between the connections they are applied to and the `LogFilter` logging them.
+#[derive(Default)]
+pub struct Rules {
+    rules: RwLock<Vec<Rule>>,
+    // Set when there are any rules, so the common case costs a single atomic load.
+    active: AtomicBool,
+}
+
+impl Rules {
+    pub fn get(&self) -> Vec<Rule> {
+        self.rules.read().expect("mutex").clone()
+    }
+
+    /// set replaces the rules. Connections already open keep the level they were opened with.
+    pub fn set(&self, rules: Vec<Rule>) {
+        let mut current = self.rules.write().expect("mutex");
+        self.active.store(!rules.is_empty(), Ordering::Release);
+        *current = rules;
+        drop(current);
+        // Callsites that were disabled outright may now be needed, or the other way around.
+        tracing_core::callsite::rebuild_interest_cache();
+    }
+
+    pub fn add(&self, rule: Rule) {
+        let mut rules = self.get();
+        rules.push(rule);
+        self.set(rules);
+    }
+
+    /// apply marks the current span with the level of the most verbose rule matching the connection, if any,
+    /// and returns whether one did.
+    pub fn apply(&self, conn: &Connection) -> bool {
+        if !self.is_active() {
+            return false;
+        }
+        let level = self
+            .rules
+            .read()
+            .expect("mutex")
+            .iter()
+            .filter(|r| r.matches(conn))
+            .map(|r| r.level)
+            .max();
+        if let Some(level) = level {
+            mark(&Span::current(), level);
+        }
+        level.is_some()
+    }
+
+    fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    fn max_level(&self) -> Option<Level> {
+        if !self.is_active() {
+            return None;
+        }
+        self.rules
+            .read()
+            .expect("mutex")
+            .iter()
+            .map(|r| r.level)
+            .max()
+    }
+}
+
+/// rules returns the current rules.
+pub fn rules() -> Vec<Rule> {
+    RULES.get()
+}
+
+/// set_rules replaces the rules.
+pub fn set_rules(rules: Vec<Rule>) {
+    RULES.set(rules)
+}
+
+/// add_rule adds a rule to the current ones.
+pub fn add_rule(rule: Rule) {
+    RULES.add(rule)
+}
+
+/// apply applies the current rules to the connection, as `Rules::apply`.
+pub fn apply(conn: &Connection) -> bool {
+    RULES.apply(conn)
+}
+
+// Targeted is stored in the extensions of a span whose events should be logged up to the given level.
+struct Targeted(Level);
+
+fn mark(span: &Span, level: Level) {
+    span.with_subscriber(|(id, dispatch)| {
+        if let Some(span) = dispatch
+            .downcast_ref::<Registry>()
+            .and_then(|registry| registry.span(id))
+        {
+            span.extensions_mut().replace(Targeted(level));
+        }
+    });
+}
+
+/// LogFilter filters by the configured targets, letting through more verbose events within targeted spans.
+pub struct LogFilter {
+    targets: filter::Targets,
+    rules: Arc<Rules>,
+}
+
+impl LogFilter {
+    pub fn new(targets: filter::Targets) -> Self {
+        Self::with_rules(targets, RULES.clone())
+    }
+
+    /// with_rules returns a filter for connections targeted by `rules`, rather than the process-wide rules.
+    pub fn with_rules(targets: filter::Targets, rules: Arc<Rules>) -> Self {
+        LogFilter { targets, rules }
+    }
+
+    pub fn set_targets(&mut self, targets: filter::Targets) {
+        self.targets = targets;
+    }
+
+    fn targeted<S>(meta: &Metadata<'_>, cx: &Context<'_, S>) -> bool
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(span) = cx.lookup_current() else {
+            return false;
+        };
+        let targeted = span
+            .scope()
+            .find_map(|s| s.extensions().get::<Targeted>().map(|t| t.0));
+        targeted.is_some_and(|level| *meta.level() <= level)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.targets.fmt(f)
+    }
+}
+
+impl<S> Filter<S> for LogFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, meta: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+        if self.targets.would_enable(meta.target(), meta.level()) {
+            return true;
+        }
+        meta.is_event() && self.rules.is_active() && Self::targeted(meta, cx)
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        if self.targets.would_enable(meta.target(), meta.level()) {
+            Interest::always()
+        } else if meta.is_event()
+            && self
+                .rules
+                .max_level()
+                .is_some_and(|level| *meta.level() <= level)
+        {
+            Interest::sometimes()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        let targets = Filter::<S>::max_level_hint(&self.targets)?;
+        Some(match self.rules.max_level() {
+            Some(level) => targets.max(LevelFilter::from_level(level)),
+            None => targets,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::atomic::AtomicUsize;
+    use tracing::{debug, info_span, trace};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Layer;
+
+    struct CountEvents(Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for CountEvents {
+        fn on_event(
+            &self,
+            _event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn targeted_connections_log_verbosely() {
+        let events = Arc::new(AtomicUsize::new(0));
+        let rules = Arc::new(Rules::default());
+        let filter =
+            LogFilter::with_rules(filter::Targets::from_str("info").unwrap(), rules.clone());
+        let subscriber =
+            tracing_subscriber::registry().with(CountEvents(events.clone()).with_filter(filter));
+        let identity = Identity::from_str("spiffe://cluster.local/ns/default/sa/debugme").unwrap();
+        rules.set(vec![Rule {
+            source_identity: Some(identity.clone()),
+            destination_service: None,
+            cidr: Some("10.0.0.0/8".parse().unwrap()),
+            level: Level::DEBUG,
+        }]);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let conn = |source_identity, src: &str| Connection {
+                source_identity,
+                destination_service: Some("echo.default.svc.cluster.local"),
+                src: src.parse().unwrap(),
+                dst: "192.168.0.1".parse().unwrap(),
+            };
+            info_span!("connection").in_scope(|| {
+                assert!(rules.apply(&conn(Some(&identity), "10.0.0.1")));
+                info_span!("phase").in_scope(|| debug!("matching"));
+                // Only up to the rule's level.
+                trace!("too verbose");
+            });
+            // Every field must match.
+            info_span!("connection").in_scope(|| {
+                rules.apply(&conn(None, "10.0.0.1"));
+                debug!("not matching");
+            });
+            info_span!("connection").in_scope(|| {
+                rules.apply(&conn(Some(&identity), "::ffff:192.168.0.2"));
+                debug!("not matching");
+            });
+        });
+        assert_eq!(events.load(Ordering::SeqCst), 1);
+    }
+}
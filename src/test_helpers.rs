@@ -50,9 +50,11 @@ use tracing::{debug, trace};
 pub mod app;
 pub mod ca;
 pub mod dns;
+pub mod faults;
 pub mod helpers;
 #[cfg(target_os = "linux")]
 pub mod inpod;
+pub mod memory;
 pub mod tcp;
 pub mod xds;
 
@@ -190,6 +192,9 @@ pub fn mock_default_service() -> Service {
         waypoint: None,
         load_balancer: None,
         ip_families: None,
+        subset_weights: None,
+        mirror: None,
+        port_names: Default::default(),
     }
 }
 
@@ -218,6 +223,9 @@ pub fn test_default_workload() -> Workload {
         native_tunnel: false,
         application_tunnel: None,
         locality: Default::default(),
+        tls_sni_override: None,
+        traffic_class: Default::default(),
+        egress_rules: Default::default(),
     }
 }
 
@@ -296,6 +304,9 @@ fn test_custom_svc(
         waypoint: None,
         load_balancer: None,
         ip_families: None,
+        subset_weights: None,
+        mirror: None,
+        port_names: Default::default(),
     })
 }
 
@@ -479,6 +490,34 @@ pub fn new_proxy_state(
     )
 }
 
+/// test_proxy_inputs returns the inputs of a proxy for `cfg`, with a mock certificate manager and fresh metrics.
+/// As for a real proxy, the connection manager enforces the connection budget, if `cfg` sets one.
+#[cfg(test)]
+pub(crate) fn test_proxy_inputs(
+    cfg: config::Config,
+    state: DemandProxyState,
+    socket_factory: Arc<dyn crate::proxy::SocketFactory + Send + Sync>,
+) -> Arc<crate::proxy::ProxyInputs> {
+    use crate::proxy::connection_manager::{ConnectionBudget, ConnectionManager};
+    use crate::proxy::destination_limiter::DestinationLimiter;
+
+    let metrics = helpers::test_proxy_metrics();
+    let connection_manager = ConnectionManager::default()
+        .with_connection_budget(ConnectionBudget::from_config(&cfg, &metrics));
+    crate::proxy::ProxyInputs::new(
+        Arc::new(cfg),
+        crate::identity::mock::new_secret_manager(Duration::from_secs(10)),
+        connection_manager,
+        state,
+        metrics.clone(),
+        socket_factory,
+        None,
+        None,
+        None,
+        Arc::new(DestinationLimiter::new(&metrics)),
+    )
+}
+
 pub async fn get_response_str(resp: Response<Full<Bytes>>) -> String {
     let resp_bytes = resp
         .body()
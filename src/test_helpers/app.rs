@@ -30,7 +30,7 @@ use hyper::{Method, Request, Response};
 use itertools::Itertools;
 use prometheus_parse::Scrape;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpSocket, TcpStream};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
 use tracing::info;
 
 use crate::app::Bound;
@@ -50,6 +50,7 @@ pub struct TestApp {
     pub tcp_dns_proxy_address: Option<SocketAddr>,
     pub udp_dns_proxy_address: Option<SocketAddr>,
     pub cert_manager: Arc<SecretManager>,
+    pub config_reloader: config::ConfigReloader,
 
     pub namespace: Option<super::netns::Namespace>,
     pub shutdown: ShutdownTrigger,
@@ -60,11 +61,12 @@ impl From<(&Bound, Arc<SecretManager>)> for TestApp {
         Self {
             admin_address: app.admin_address,
             metrics_address: app.metrics_address,
-            proxy_addresses: app.proxy_addresses.unwrap(),
+            proxy_addresses: app.proxy_addresses.clone().unwrap(),
             readiness_address: app.readiness_address,
             tcp_dns_proxy_address: app.tcp_dns_proxy_address,
             udp_dns_proxy_address: app.udp_dns_proxy_address,
             cert_manager,
+            config_reloader: app.config_reloader.clone(),
             namespace: None,
             shutdown: app.shutdown.trigger(),
         }
@@ -213,7 +215,7 @@ impl TestApp {
         // Always use IPv4 address. In theory, we can resolve `localhost` to pick to support any machine
         // However, we need to make sure the WorkloadStore knows about both families then.
         let socks_addr = with_ip(
-            self.proxy_addresses.socks5.unwrap(),
+            self.proxy_addresses.socks5[0],
             IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
         );
         // Set source IP to TEST_WORKLOAD_SOURCE
@@ -228,6 +230,28 @@ impl TestApp {
         socks5_connect(stream, addr).await.unwrap()
     }
 
+    /// socks5_udp_associate sets up a SOCKS5 UDP association from `source`. This returns the control
+    /// connection, which must be kept open for the association to remain, and a UDP socket connected to the relay.
+    pub async fn socks5_udp_associate(&self, source: IpAddr) -> (TcpStream, UdpSocket) {
+        let socks_addr = with_ip(
+            self.proxy_addresses.socks5[0],
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        );
+        let socket = TcpSocket::new_v4().unwrap();
+        socket
+            .bind(SocketAddr::from((source, 0)))
+            .map_err(|e| anyhow!("{:?}. {}", e, localhost_error_message()))
+            .unwrap();
+        let stream = socket.connect(socks_addr).await.unwrap();
+        let (stream, relay) = socks5_udp_associate(stream).await.unwrap();
+
+        let udp = UdpSocket::bind(SocketAddr::from((source, 0)))
+            .await
+            .unwrap();
+        udp.connect(relay).await.unwrap();
+        (stream, udp)
+    }
+
     pub async fn dns_request(
         &self,
         hostname: &str,
@@ -330,6 +354,59 @@ pub async fn socks5_connect(
     Ok(stream)
 }
 
+pub async fn socks5_udp_associate(
+    mut stream: TcpStream,
+) -> anyhow::Result<(TcpStream, SocketAddr)> {
+    stream
+        .write_all(&[
+            0x05u8, // socks5
+            0x1u8,  // 1 auth method
+            0x0u8,  // unauthenticated auth method
+        ])
+        .await?;
+    let mut auth = [0u8; 2];
+    stream.read_exact(&mut auth).await?;
+
+    stream
+        .write_all(&[
+            0x05u8, // socks5
+            0x3u8,  // udp associate
+            0x0u8,  // RSV
+            0x1u8,  // IPv4
+            0x0, 0x0, 0x0, 0x0, // unspecified address
+            0x0, 0x0, // unspecified port
+        ])
+        .await?;
+
+    // The response holds the relay address, which we only support as IPv4 here.
+    let mut resp = [0u8; 10];
+    stream.read_exact(&mut resp).await?;
+    if resp[1] != 0x00 || resp[3] != 0x01 {
+        return Err(anyhow!("unexpected response {resp:?}"));
+    }
+    let ip: [u8; 4] = resp[4..8].try_into()?;
+    let port = u16::from_be_bytes([resp[8], resp[9]]);
+    Ok((stream, SocketAddr::from((ip, port))))
+}
+
+/// socks5_udp_datagram frames a payload to `dest` for sending to a SOCKS5 UDP relay.
+pub fn socks5_udp_datagram(dest: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0x00u8, 0x00, 0x00]; // RSV, FRAG
+    match dest.ip() {
+        IpAddr::V4(ip) => {
+            datagram.push(1);
+            datagram.extend_from_slice(&ip.octets())
+        }
+        IpAddr::V6(ip) => {
+            datagram.push(4);
+            datagram.extend_from_slice(&ip.octets())
+        }
+    }
+    datagram.extend_from_slice(&dest.port().to_be_bytes());
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
 #[derive(Debug)]
 pub struct ParsedMetrics {
     scrape: Scrape,
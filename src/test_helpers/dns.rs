@@ -251,7 +251,7 @@ pub async fn run_dns(responses: HashMap<Name, Vec<IpAddr>>) -> anyhow::Result<Te
         Arc::new(Metrics::new(istio_registry))
     };
     let (signal, drain) = drain::new();
-    let factory = crate::proxy::DefaultSocketFactory {};
+    let factory = crate::proxy::DefaultSocketFactory::default();
 
     let state = new_proxy_state(&[], &[], &[]);
     let forwarder = Arc::new(FakeForwarder {
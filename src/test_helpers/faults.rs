@@ -0,0 +1,153 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::{TcpSocket, TcpStream};
+use tracing::debug;
+
+use crate::proxy::{DelegatingSocketFactory, SocketFactory};
+use crate::socket;
+
+#[derive(Clone, Copy, Debug)]
+enum Fault {
+    Fail(i32),
+    Delay(Duration),
+}
+
+#[derive(Debug)]
+struct Rule {
+    // If unset, the rule applies to all destinations.
+    destination: Option<SocketAddr>,
+    // The rule applies to every Nth matching connection attempt.
+    every: usize,
+    attempts: usize,
+    fault: Fault,
+}
+
+/// FaultInjectingSocketFactory wraps a SocketFactory, injecting failures and delays into outbound
+/// connection attempts. All other operations are delegated to the wrapped factory.
+///
+/// Rules may be added or cleared while the factory is in use, and are evaluated in the order they were
+/// added. Delays from all matching rules add up; if any matching rule fails the attempt, the connection
+/// fails (after the delay) with the first such error.
+///
+/// The factory also counts the connection attempts made with TCP Fast Open enabled.
+pub struct FaultInjectingSocketFactory {
+    inner: Arc<dyn SocketFactory + Send + Sync>,
+    rules: Mutex<Vec<Rule>>,
+    fast_open_connects: AtomicUsize,
+}
+
+impl FaultInjectingSocketFactory {
+    pub fn new(inner: Arc<dyn SocketFactory + Send + Sync>) -> Self {
+        Self {
+            inner,
+            rules: Mutex::new(Vec::new()),
+            fast_open_connects: AtomicUsize::new(0),
+        }
+    }
+
+    /// fast_open_connects returns how many connection attempts had TCP Fast Open enabled.
+    pub fn fast_open_connects(&self) -> usize {
+        self.fast_open_connects.load(Ordering::SeqCst)
+    }
+
+    /// fail_connect fails all connections to `destination` with the given errno, such as `libc::ECONNREFUSED`.
+    pub fn fail_connect(&self, destination: SocketAddr, errno: i32) {
+        self.add_rule(Some(destination), 1, Fault::Fail(errno))
+    }
+
+    /// delay_connect delays all connections to `destination` by `delay`.
+    pub fn delay_connect(&self, destination: SocketAddr, delay: Duration) {
+        self.add_rule(Some(destination), 1, Fault::Delay(delay))
+    }
+
+    /// drop_every fails every `m`th connection attempt, to any destination, with ECONNRESET.
+    pub fn drop_every(&self, m: usize) {
+        assert!(m > 0, "drop_every requires a positive interval");
+        self.add_rule(None, m, Fault::Fail(libc::ECONNRESET))
+    }
+
+    /// clear removes all rules, so subsequent connections are no longer affected.
+    pub fn clear(&self) {
+        self.rules.lock().unwrap().clear();
+    }
+
+    fn add_rule(&self, destination: Option<SocketAddr>, every: usize, fault: Fault) {
+        self.rules.lock().unwrap().push(Rule {
+            destination,
+            every,
+            attempts: 0,
+            fault,
+        });
+    }
+
+    // faults_for records a connection attempt to addr, returning the total delay and error to apply.
+    fn faults_for(&self, addr: SocketAddr) -> (Duration, Option<i32>) {
+        let addr = socket::to_canonical(addr);
+        let mut delay = Duration::ZERO;
+        let mut errno = None;
+        for rule in self.rules.lock().unwrap().iter_mut() {
+            if rule.destination.is_some_and(|d| d != addr) {
+                continue;
+            }
+            rule.attempts += 1;
+            if rule.attempts % rule.every != 0 {
+                continue;
+            }
+            match rule.fault {
+                Fault::Delay(d) => delay += d,
+                Fault::Fail(e) => {
+                    errno.get_or_insert(e);
+                }
+            }
+        }
+        (delay, errno)
+    }
+}
+
+impl DelegatingSocketFactory for FaultInjectingSocketFactory {
+    fn inner(&self) -> &(dyn SocketFactory + Send + Sync) {
+        self.inner.as_ref()
+    }
+
+    fn tcp_connect(
+        &self,
+        socket: TcpSocket,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send + '_>> {
+        let (delay, errno) = self.faults_for(addr);
+        if socket::fastopen_connect_enabled(&socket).unwrap_or(false) {
+            self.fast_open_connects.fetch_add(1, Ordering::SeqCst);
+        }
+        Box::pin(async move {
+            if !delay.is_zero() {
+                debug!(%addr, ?delay, "injecting connect delay");
+                tokio::time::sleep(delay).await;
+            }
+            if let Some(errno) = errno {
+                debug!(%addr, errno, "injecting connect failure");
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+            self.inner.tcp_connect(socket, addr).await
+        })
+    }
+}
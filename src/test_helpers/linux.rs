@@ -158,10 +158,11 @@ impl WorkloadManager {
             let shutdown = app.shutdown.trigger();
 
             // inpod mode doesn't have ore need these, so just put bogus values.
-            let proxy_addresses = app.proxy_addresses.unwrap_or(proxy::Addresses {
+            let proxy_addresses = app.proxy_addresses.clone().unwrap_or(proxy::Addresses {
                 inbound: "0.0.0.0:0".parse()?,
+                inbound_extra: vec![],
                 outbound: "0.0.0.0:0".parse()?,
-                socks5: Some("0.0.0.0:0".parse()?),
+                socks5: vec!["0.0.0.0:0".parse()?],
             });
 
             let ta = TestApp {
@@ -172,7 +173,16 @@ impl WorkloadManager {
                 proxy_addresses: proxy::Addresses {
                     outbound: helpers::with_ip(proxy_addresses.outbound, ip),
                     inbound: helpers::with_ip(proxy_addresses.inbound, ip),
-                    socks5: proxy_addresses.socks5.map(|i| helpers::with_ip(i, ip)),
+                    inbound_extra: proxy_addresses
+                        .inbound_extra
+                        .iter()
+                        .map(|i| helpers::with_ip(*i, ip))
+                        .collect(),
+                    socks5: proxy_addresses
+                        .socks5
+                        .into_iter()
+                        .map(|i| helpers::with_ip(i, ip))
+                        .collect(),
                 },
                 tcp_dns_proxy_address: Some(helpers::with_ip(
                     app.tcp_dns_proxy_address.unwrap_or("0.0.0.0:0".parse()?),
@@ -183,6 +193,7 @@ impl WorkloadManager {
                     ip,
                 )),
                 cert_manager,
+                config_reloader: app.config_reloader.clone(),
 
                 namespace: Some(cloned_ns),
                 shutdown,
@@ -306,6 +317,9 @@ impl<'a> TestServiceBuilder<'a> {
                 waypoint: None,
                 load_balancer: None,
                 ip_families: None,
+                subset_weights: None,
+                mirror: None,
+                port_names: Default::default(),
             },
             manager,
         }
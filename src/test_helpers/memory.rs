@@ -0,0 +1,255 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An in-memory network for tests. Listeners are registered by address, and a connection to one is a pair of
+//! in-memory duplex streams, so tests can connect components in-process without binding real ports.
+//!
+//! Note: the proxy itself is still built on tokio's TCP types (its listeners, `SocketFactory` and the
+//! original destination lookup all use real sockets), so it cannot run on this network yet. Components that
+//! work on any `AsyncRead + AsyncWrite`, such as `copy` and the HBONE HTTP/2 layer, can.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::sync::mpsc;
+
+// Enough for a few frames in flight, without hiding flow control issues behind large buffers.
+const BUFFER_SIZE: usize = 64 * 1024;
+// Connections not yet accepted, as in a listen backlog.
+const BACKLOG: usize = 128;
+const FIRST_EPHEMERAL_PORT: u16 = 32768;
+
+/// MemoryNetwork routes connections to in-memory listeners by address.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    listeners: Mutex<HashMap<SocketAddr, mpsc::Sender<MemoryStream>>>,
+    next_port: AtomicU16,
+}
+
+impl Inner {
+    fn ephemeral_port(&self) -> u16 {
+        let offset = self.next_port.fetch_add(1, Ordering::Relaxed);
+        FIRST_EPHEMERAL_PORT.wrapping_add(offset % (u16::MAX - FIRST_EPHEMERAL_PORT))
+    }
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// bind starts listening on `addr`. As with TCP, port 0 picks an unused port.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<MemoryListener> {
+        let mut listeners = self.inner.listeners.lock().unwrap();
+        let addr = if addr.port() == 0 {
+            loop {
+                let candidate = SocketAddr::new(addr.ip(), self.inner.ephemeral_port());
+                if !listeners.contains_key(&candidate) {
+                    break candidate;
+                }
+            }
+        } else {
+            addr
+        };
+        if listeners.contains_key(&addr) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+        let (tx, rx) = mpsc::channel(BACKLOG);
+        listeners.insert(addr, tx);
+        Ok(MemoryListener {
+            network: self.inner.clone(),
+            addr,
+            incoming: rx,
+        })
+    }
+
+    /// connect opens a connection from `src` to the listener on `dst`, failing as TCP would if there is none.
+    pub async fn connect(&self, src: IpAddr, dst: SocketAddr) -> io::Result<MemoryStream> {
+        let listener = self.inner.listeners.lock().unwrap().get(&dst).cloned();
+        let Some(listener) = listener else {
+            return Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+        };
+        let local = SocketAddr::new(src, self.inner.ephemeral_port());
+        let (client, server) = tokio::io::duplex(BUFFER_SIZE);
+        listener
+            .send(MemoryStream {
+                inner: server,
+                local: dst,
+                peer: local,
+            })
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(MemoryStream {
+            inner: client,
+            local,
+            peer: dst,
+        })
+    }
+}
+
+/// MemoryListener accepts connections made to its address. It stops listening when dropped.
+pub struct MemoryListener {
+    network: Arc<Inner>,
+    addr: SocketAddr,
+    incoming: mpsc::Receiver<MemoryStream>,
+}
+
+impl MemoryListener {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub async fn accept(&mut self) -> io::Result<(MemoryStream, SocketAddr)> {
+        let stream = self
+            .incoming
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        let peer = stream.peer;
+        Ok((stream, peer))
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        self.network.listeners.lock().unwrap().remove(&self.addr);
+    }
+}
+
+/// MemoryStream is one end of an in-memory connection, which knows the addresses of both ends.
+pub struct MemoryStream {
+    inner: DuplexStream,
+    local: SocketAddr,
+    peer: SocketAddr,
+}
+
+impl MemoryStream {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::metrics::{ConnectionOpen, ConnectionResult, SecurityPolicy};
+    use crate::proxy::{ConnectionId, Reporter};
+    use crate::test_helpers::helpers::test_proxy_metrics;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn client_through_proxy_to_upstream() {
+        let network = MemoryNetwork::new();
+        let upstream_addr: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+        let mut upstream = network.bind(upstream_addr).unwrap();
+        let mut proxy = network.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let proxy_addr = proxy.local_addr();
+
+        // The upstream echoes everything back.
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let (mut rd, mut wr) = tokio::io::split(&mut stream);
+            tokio::io::copy(&mut rd, &mut wr).await.unwrap();
+        });
+        // The proxy forwards to the upstream with the same copy ztunnel uses for connections.
+        let metrics = test_proxy_metrics();
+        let proxy_network = network.clone();
+        let proxied = tokio::spawn(async move {
+            let (downstream, src) = proxy.accept().await.unwrap();
+            let upstream = proxy_network
+                .connect(proxy_addr.ip(), upstream_addr)
+                .await
+                .unwrap();
+            let stats = ConnectionResult::new(
+                src,
+                upstream_addr,
+                None,
+                std::time::Instant::now(),
+                ConnectionOpen {
+                    reporter: Reporter::source,
+                    source: None,
+                    derived_source: None,
+                    destination: None,
+                    connection_security_policy: SecurityPolicy::unknown,
+                    destination_service: None,
+                    destination_service_port_name: None,
+                    connection_id: ConnectionId::next(),
+                },
+                metrics,
+            );
+            crate::copy::copy_bidirectional(downstream, upstream, &stats, false).await
+        });
+
+        let client_ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut client = network.connect(client_ip, proxy_addr).await.unwrap();
+        assert_eq!(client.local_addr().ip(), client_ip);
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        client.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"hello");
+        proxied.await.unwrap().unwrap();
+
+        // Nothing is listening once the listener is gone.
+        drop(network.bind("10.0.0.3:80".parse().unwrap()).unwrap());
+        let err = network
+            .connect(client_ip, "10.0.0.3:80".parse().unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
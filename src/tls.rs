@@ -15,16 +15,22 @@
 mod certificate;
 mod control;
 pub mod csr;
+mod handshake;
 mod lib;
 #[cfg(any(test, feature = "testing"))]
 pub mod mock;
+mod origination;
+pub mod session;
 mod workload;
 
 use std::sync::Arc;
 
 pub use crate::tls::certificate::*;
 pub use crate::tls::control::*;
+pub use crate::tls::handshake::*;
 pub use crate::tls::lib::*;
+pub use crate::tls::origination::*;
+pub use crate::tls::session::set_resumption;
 pub use crate::tls::workload::*;
 use hyper::http::uri::InvalidUri;
 use rustls::server::VerifierBuilderError;
@@ -59,6 +65,18 @@ pub enum Error {
 
     #[error("failed to build server verifier: {0}")]
     ServerVerifierBuilderError(#[from] VerifierBuilderError),
+
+    #[error("invalid SNI {0:?}: must be a DNS name")]
+    InvalidSni(String),
+
+    #[error("certificate SAN {0:?} is not a recognized identity")]
+    UnrecognizedSan(String),
+
+    #[error(
+        "certificate identity is ambiguous: found several identities {}",
+        crate::identity::log_display_all(.0)
+    )]
+    AmbiguousSan(Vec<crate::identity::Identity>),
 }
 
 impl From<InvalidUri> for Error {
@@ -12,11 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::config::{SanPolicy, SanSource};
 use crate::identity::Identity;
+use crate::strng::Strng;
+use crate::tls::session::{self, SessionResumption};
 use crate::tls::{Error, IdentityVerifier, OutboundConnector};
 use base64::engine::general_purpose::STANDARD;
 use bytes::Bytes;
 use itertools::Itertools;
+use once_cell::sync::OnceCell;
 
 use rustls::client::Resumption;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
@@ -69,7 +73,16 @@ pub fn identity_from_connection(conn: &server::ServerConnection) -> Option<Ident
             }
         })
         .and_then(|cert| match identities(cert) {
-            Ok(ids) => ids.into_iter().next(),
+            Ok(ids) => {
+                if ids.len() > 1 {
+                    // Only a strict SAN policy rejects this; otherwise, the peer is known by the first identity.
+                    warn!(
+                        "peer certificate has several identities {}, using the first",
+                        crate::identity::log_display_all(&ids)
+                    );
+                }
+                ids.into_iter().next()
+            }
             Err(e) => {
                 warn!("failed to extract identity: {}", e);
                 None
@@ -77,32 +90,140 @@ pub fn identity_from_connection(conn: &server::ServerConnection) -> Option<Ident
         })
 }
 
+// How peer identities are extracted from certificates. This is set once, at startup; until then, the default
+// policy is used.
+static SAN_POLICY: OnceCell<SanPolicy> = OnceCell::new();
+
+/// set_san_policy sets how peer identities are extracted from certificates, for the lifetime of the process.
+/// Only the first call has any effect.
+pub fn set_san_policy(policy: SanPolicy) {
+    let _ = SAN_POLICY.set(policy);
+}
+
+fn san_policy() -> SanPolicy {
+    SAN_POLICY.get().copied().unwrap_or_default()
+}
+
 pub fn identities(cert: X509Certificate) -> Result<Vec<Identity>, Error> {
+    identities_with_policy(&cert, san_policy())
+}
+
+/// expected_identity normalizes an identity a peer is expected to have in the same way as the identities
+/// extracted from its certificate, so the two can be compared.
+pub fn expected_identity(id: &Identity) -> Identity {
+    expected_identity_with_policy(id, san_policy())
+}
+
+/// expected_trust_domain normalizes a trust domain peers are expected to be in, as `expected_identity`.
+pub fn expected_trust_domain(trust_domain: &Strng) -> Strng {
+    if san_policy().normalize_trust_domain {
+        normalized_trust_domain(trust_domain)
+    } else {
+        trust_domain.clone()
+    }
+}
+
+fn expected_identity_with_policy(id: &Identity, policy: SanPolicy) -> Identity {
+    if policy.normalize_trust_domain {
+        normalize_trust_domain(id.clone())
+    } else {
+        id.clone()
+    }
+}
+
+fn identities_with_policy(
+    cert: &X509Certificate,
+    policy: SanPolicy,
+) -> Result<Vec<Identity>, Error> {
     use x509_parser::prelude::*;
     let names = cert
         .subject_alternative_name()?
-        .map(|x| &x.value.general_names);
+        .map(|x| x.value.general_names.as_slice())
+        .unwrap_or_default();
+    let uris = || {
+        names.iter().filter_map(|n| match n {
+            GeneralName::URI(uri) => Some(*uri),
+            _ => None,
+        })
+    };
+    let dns_names = || {
+        names.iter().filter_map(|n| match n {
+            GeneralName::DNSName(name) => Some(*name),
+            _ => None,
+        })
+    };
+    let mut ids = match policy.source {
+        SanSource::Uri => parse_sans(uris(), |uri| Identity::from_str(uri).ok(), policy)?,
+        SanSource::Dns => parse_sans(dns_names(), identity_from_dns, policy)?,
+        SanSource::UriOrDns => {
+            let ids = parse_sans(uris(), |uri| Identity::from_str(uri).ok(), policy)?;
+            if ids.is_empty() {
+                parse_sans(dns_names(), identity_from_dns, policy)?
+            } else {
+                ids
+            }
+        }
+    };
+    if policy.normalize_trust_domain {
+        ids = ids.into_iter().map(normalize_trust_domain).collect();
+    }
+    let ids: Vec<_> = ids.into_iter().unique().collect();
+    if policy.strict && ids.len() > 1 {
+        return Err(Error::AmbiguousSan(ids));
+    }
+    Ok(ids)
+}
 
-    if let Some(names) = names {
-        return Ok(names
-            .iter()
-            .filter_map(|n| {
-                let id = match n {
-                    GeneralName::URI(uri) => Identity::from_str(uri),
-                    _ => return None,
-                };
-
-                match id {
-                    Ok(id) => Some(id),
-                    Err(err) => {
-                        warn!("SAN {n} could not be parsed: {err}");
-                        None
-                    }
-                }
-            })
-            .collect());
+// parse_sans parses SANs into identities. Those that are not identities are skipped, unless the policy is strict.
+fn parse_sans<'a>(
+    sans: impl Iterator<Item = &'a str>,
+    parse: impl Fn(&str) -> Option<Identity>,
+    policy: SanPolicy,
+) -> Result<Vec<Identity>, Error> {
+    let mut ids = Vec::new();
+    for san in sans {
+        match parse(san) {
+            Some(id) => ids.push(id),
+            None if policy.strict => return Err(Error::UnrecognizedSan(san.to_string())),
+            None => warn!("SAN {san} could not be parsed as an identity"),
+        }
     }
-    Ok(Vec::default())
+    Ok(ids)
+}
+
+// identity_from_dns parses a DNS SAN of the form <service account>.<namespace>.<trust domain>.
+fn identity_from_dns(name: &str) -> Option<Identity> {
+    let mut labels = name.splitn(3, '.');
+    let (service_account, namespace, trust_domain) =
+        (labels.next()?, labels.next()?, labels.next()?);
+    if service_account.is_empty() || namespace.is_empty() || trust_domain.is_empty() {
+        return None;
+    }
+    Some(Identity::Spiffe {
+        trust_domain: trust_domain.into(),
+        namespace: namespace.into(),
+        service_account: service_account.into(),
+    })
+}
+
+fn normalize_trust_domain(id: Identity) -> Identity {
+    let Identity::Spiffe {
+        trust_domain,
+        namespace,
+        service_account,
+    } = id;
+    Identity::Spiffe {
+        trust_domain: normalized_trust_domain(&trust_domain),
+        namespace,
+        service_account,
+    }
+}
+
+fn normalized_trust_domain(trust_domain: &str) -> Strng {
+    trust_domain
+        .trim_end_matches('.')
+        .to_ascii_lowercase()
+        .into()
 }
 
 impl Certificate {
@@ -248,7 +369,15 @@ impl WorkloadCertificate {
     }
 
     pub fn server_config(&self) -> Result<ServerConfig, Error> {
-        let td = self.cert.identity().map(|i| match i {
+        self.server_config_with(session::global())
+    }
+
+    pub(in crate::tls) fn server_config_with(
+        &self,
+        sessions: Option<&SessionResumption>,
+    ) -> Result<ServerConfig, Error> {
+        let identity = self.cert.identity();
+        let td = identity.clone().map(|i| match i {
             Identity::Spiffe { trust_domain, .. } => trust_domain,
         });
         let raw_client_cert_verifier = WebPkiClientVerifier::builder_with_provider(
@@ -265,12 +394,27 @@ impl WorkloadCertificate {
             .with_client_cert_verifier(client_cert_verifier)
             .with_single_cert(self.cert_and_intermediates(), self.private_key.clone_key())?;
         sc.alpn_protocols = vec![b"h2".into()];
+        match sessions {
+            Some(sessions) => sessions.configure_server(&mut sc, identity.as_ref()),
+            None => session::disable_server(&mut sc),
+        }
         Ok(sc)
     }
 
     pub fn outbound_connector(&self, identity: Vec<Identity>) -> Result<OutboundConnector, Error> {
+        self.outbound_connector_with(identity, session::global())
+    }
+
+    pub(in crate::tls) fn outbound_connector_with(
+        &self,
+        identity: Vec<Identity>,
+        sessions: Option<&SessionResumption>,
+    ) -> Result<OutboundConnector, Error> {
         let roots = self.roots.clone();
-        let verifier = IdentityVerifier { roots, identity };
+        let verifier = IdentityVerifier {
+            roots,
+            identity: identity.clone(),
+        };
         let mut cc = ClientConfig::builder_with_provider(crate::tls::lib::provider())
             .with_protocol_versions(tls::TLS_VERSIONS)
             .expect("client config must be valid")
@@ -278,10 +422,14 @@ impl WorkloadCertificate {
             .with_custom_certificate_verifier(Arc::new(verifier))
             .with_client_auth_cert(self.cert_and_intermediates(), self.private_key.clone_key())?;
         cc.alpn_protocols = vec![b"h2".into()];
-        cc.resumption = Resumption::disabled();
+        match sessions {
+            Some(sessions) => sessions.configure_client(&mut cc, &identity),
+            None => cc.resumption = Resumption::disabled(),
+        }
         cc.enable_sni = false;
         Ok(OutboundConnector {
             client_config: Arc::new(cc),
+            server_name: None,
         })
     }
 
@@ -337,3 +485,113 @@ fn der_to_pem(der: &[u8], label: &str) -> String {
     ans.push_str("-----\n");
     ans
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, Ia5String, KeyPair, SanType};
+
+    fn cert_with_sans(sans: &[SanType]) -> Vec<u8> {
+        let mut p = CertificateParams::default();
+        p.subject_alt_names = sans.to_vec();
+        let kp =
+            KeyPair::from_pem(std::str::from_utf8(crate::tls::mock::TEST_PKEY).unwrap()).unwrap();
+        p.self_signed(&kp).unwrap().der().to_vec()
+    }
+
+    fn uri(s: &str) -> SanType {
+        SanType::URI(Ia5String::try_from(s).unwrap())
+    }
+
+    fn dns(s: &str) -> SanType {
+        SanType::DnsName(Ia5String::try_from(s).unwrap())
+    }
+
+    fn ids(sans: &[SanType], policy: SanPolicy) -> Result<Vec<String>, Error> {
+        let der = cert_with_sans(sans);
+        let (_, cert) = x509_parser::parse_x509_certificate(&der).unwrap();
+        identities_with_policy(&cert, policy)
+            .map(|ids| ids.iter().map(|id| id.to_string()).collect())
+    }
+
+    const ECHO: &str = "spiffe://cluster.local/ns/default/sa/echo";
+
+    #[test]
+    fn san_sources() {
+        let uri_and_dns = [uri(ECHO), dns("other.default.cluster.local")];
+        assert_eq!(ids(&uri_and_dns, SanPolicy::default()).unwrap(), vec![ECHO]);
+        let dns_policy = SanPolicy {
+            source: SanSource::Dns,
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&uri_and_dns, dns_policy).unwrap(),
+            vec!["spiffe://cluster.local/ns/default/sa/other"]
+        );
+
+        // DNS SANs are only a fallback for certificates without a URI SAN identity.
+        let fallback = SanPolicy {
+            source: SanSource::UriOrDns,
+            ..Default::default()
+        };
+        assert_eq!(ids(&uri_and_dns, fallback).unwrap(), vec![ECHO]);
+        assert_eq!(
+            ids(&[dns("echo.default.cluster.local")], fallback).unwrap(),
+            vec![ECHO]
+        );
+        assert_eq!(
+            ids(&[dns("echo.default.cluster.local")], SanPolicy::default()).unwrap(),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn strict_san_policy() {
+        let strict = SanPolicy {
+            strict: true,
+            ..Default::default()
+        };
+        let unrecognized = [uri(ECHO), uri("https://example.com")];
+        // By default, SANs that are not identities are skipped.
+        assert_eq!(
+            ids(&unrecognized, SanPolicy::default()).unwrap(),
+            vec![ECHO]
+        );
+        assert!(matches!(
+            ids(&unrecognized, strict),
+            Err(Error::UnrecognizedSan(san)) if san == "https://example.com"
+        ));
+
+        let multiple = [uri(ECHO), uri("spiffe://cluster.local/ns/default/sa/other")];
+        assert_eq!(ids(&multiple, SanPolicy::default()).unwrap().len(), 2);
+        assert!(matches!(
+            ids(&multiple, strict),
+            Err(Error::AmbiguousSan(ids)) if ids.len() == 2
+        ));
+        // The same identity twice is not ambiguous.
+        assert_eq!(ids(&[uri(ECHO), uri(ECHO)], strict).unwrap(), vec![ECHO]);
+    }
+
+    #[test]
+    fn normalized_trust_domains() {
+        let sans = [uri(ECHO), uri("spiffe://Cluster.Local./ns/default/sa/echo")];
+        assert_eq!(ids(&sans, SanPolicy::default()).unwrap().len(), 2);
+        let policy = SanPolicy {
+            strict: true,
+            normalize_trust_domain: true,
+            ..Default::default()
+        };
+        assert_eq!(ids(&sans, policy).unwrap(), vec![ECHO]);
+
+        // Identities we expect peers to have are normalized in the same way.
+        let expected = Identity::from_str("spiffe://Cluster.Local./ns/default/sa/echo").unwrap();
+        assert_eq!(
+            expected_identity_with_policy(&expected, policy).to_string(),
+            ECHO
+        );
+        assert_eq!(
+            expected_identity_with_policy(&expected, SanPolicy::default()),
+            expected
+        );
+    }
+}
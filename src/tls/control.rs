@@ -31,7 +31,7 @@ use std::time::Duration;
 
 use tonic::body::BoxBody;
 
-async fn root_to_store(root_cert: &RootCert) -> Result<rustls::RootCertStore, Error> {
+pub(super) async fn root_to_store(root_cert: &RootCert) -> Result<rustls::RootCertStore, Error> {
     let mut roots = rustls::RootCertStore::empty();
     match root_cert {
         RootCert::File(f) => {
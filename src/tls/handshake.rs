@@ -0,0 +1,138 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+
+/// HandshakeSummary is what a TLS handshake negotiated. It is kept for as long as the connection is open, so it
+/// only holds the IANA codes; these are rendered to names when displayed.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HandshakeSummary {
+    pub version: u16,
+    pub cipher_suite: u16,
+    pub alpn: Alpn,
+}
+
+/// Alpn is the application protocol negotiated with ALPN.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Alpn {
+    None,
+    H2,
+    Http11,
+    Other,
+}
+
+impl Alpn {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Alpn::None => "none",
+            Alpn::H2 => "h2",
+            Alpn::Http11 => "http/1.1",
+            Alpn::Other => "other",
+        }
+    }
+}
+
+impl HandshakeSummary {
+    /// from_connection summarizes the handshake of a connection, or returns None if it is still in progress.
+    pub fn from_connection(conn: &rustls::CommonState) -> Option<Self> {
+        let version = conn.protocol_version()?;
+        let cipher_suite = conn.negotiated_cipher_suite()?.suite();
+        let alpn = match conn.alpn_protocol() {
+            None => Alpn::None,
+            Some(b"h2") => Alpn::H2,
+            Some(b"http/1.1") => Alpn::Http11,
+            Some(_) => Alpn::Other,
+        };
+        Some(HandshakeSummary {
+            version: version.get_u16(),
+            cipher_suite: cipher_suite.get_u16(),
+            alpn,
+        })
+    }
+
+    /// version is the name of the TLS version, such as "TLSv1_3".
+    pub fn version(&self) -> String {
+        format!("{:?}", rustls::ProtocolVersion::from(self.version))
+    }
+
+    /// cipher_suite is the IANA name of the cipher suite, such as "TLS13_AES_128_GCM_SHA256".
+    pub fn cipher_suite(&self) -> String {
+        format!("{:?}", rustls::CipherSuite::from(self.cipher_suite))
+    }
+}
+
+impl Serialize for HandshakeSummary {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("HandshakeSummary", 3)?;
+        s.serialize_field("version", &self.version())?;
+        s.serialize_field("cipherSuite", &self.cipher_suite())?;
+        s.serialize_field("alpn", self.alpn.as_str())?;
+        s.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::identity::Identity;
+    use crate::tls::mock::generate_test_certs;
+
+    #[tokio::test]
+    async fn summarizes_handshake() {
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let server_config = certs.server_config().unwrap();
+        let connector = certs.outbound_connector(vec![id]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut tls = tokio_rustls::TlsAcceptor::from(Arc::new(server_config))
+                .accept(stream)
+                .await
+                .unwrap();
+            tls.write_all(b"x").await.unwrap();
+            tls.flush().await.unwrap();
+            HandshakeSummary::from_connection(tls.get_ref().1).unwrap()
+        });
+        let mut tls = connector
+            .connect(TcpStream::connect(addr).await.unwrap())
+            .await
+            .unwrap();
+        tls.read_exact(&mut [0u8; 1]).await.unwrap();
+
+        let summary = server.await.unwrap();
+        assert_eq!(
+            summary,
+            HandshakeSummary::from_connection(tls.get_ref().1).unwrap()
+        );
+        assert_eq!(summary.version(), "TLSv1_3");
+        assert_eq!(summary.alpn, Alpn::H2);
+        assert!(summary.cipher_suite().starts_with("TLS13_"));
+    }
+}
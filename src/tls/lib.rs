@@ -43,6 +43,10 @@ pub trait ServerCertProvider: Send + Sync + Clone {
 
 pub(super) static TLS_VERSIONS: &[&rustls::SupportedProtocolVersion] = &[&rustls::version::TLS13];
 
+// Servers we originate TLS to are outside the mesh, and many of them do not support TLS 1.3 yet.
+pub(super) static ORIGINATION_TLS_VERSIONS: &[&rustls::SupportedProtocolVersion] =
+    &[&rustls::version::TLS13, &rustls::version::TLS12];
+
 // Ztunnel use `rustls` with pluggable crypto modules.
 // All crypto MUST be done via the below providers.
 //
@@ -69,6 +73,33 @@ pub(super) fn provider() -> Arc<CryptoProvider> {
     })
 }
 
+// origination_provider is used for TLS origination to servers outside the mesh. These are not ours to configure,
+// so it is not limited to the ciphers used within the mesh.
+#[cfg(feature = "tls-boring")]
+pub(super) fn origination_provider() -> Arc<CryptoProvider> {
+    // A FIPS build must stay within the FIPS approved ciphers, for TLS 1.2 as well.
+    provider()
+}
+
+#[cfg(feature = "tls-ring")]
+pub(super) fn origination_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+// sha256 hashes data outside of TLS, such as identities for logs, with the crypto library of the provider.
+#[cfg(feature = "tls-boring")]
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    boring::sha::sha256(data)
+}
+
+#[cfg(feature = "tls-ring")]
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    ring::digest::digest(&ring::digest::SHA256, data)
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digests are 32 bytes")
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum TlsError {
     #[error("tls handshake error: {0:?}")]
@@ -77,10 +108,15 @@ pub enum TlsError {
     CertificateLookup(NetworkAddress),
     #[error("signing error: {0}")]
     SigningError(#[from] identity::Error),
-    #[error("san verification error: remote did not present the expected SAN ({0:?}), got {1:?}")]
+    #[error(
+        "san verification error: remote did not present the expected SAN ({}), got {}",
+        identity::log_display_all(.0),
+        identity::log_display_all(.1)
+    )]
     SanError(Vec<Identity>, Vec<Identity>),
     #[error(
-        "san verification error: remote did not present the expected trustdomain ({0}), got {1:?}"
+        "san verification error: remote did not present the expected trustdomain ({0}), got {}",
+        identity::log_display_all(.1)
     )]
     SanTrustDomainError(String, Vec<Identity>),
     #[error("failed getting ex data")]
@@ -95,11 +131,58 @@ pub enum TlsError {
 pub mod tests {
     use std::time::Duration;
 
+    use tokio::net::{TcpListener, TcpStream};
+
     use crate::identity::Identity;
-    use crate::tls::WorkloadCertificate;
+    use crate::tls::{sni_server_name, WorkloadCertificate};
 
     use crate::tls::mock::*;
 
+    // client_hello_sni connects with the connector, returning the SNI the server received.
+    async fn client_hello_sni(connector: crate::tls::OutboundConnector) -> Option<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            // The server never completes the handshake, so this fails.
+            let _ = connector.connect(stream).await;
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let start =
+            tokio_rustls::LazyConfigAcceptor::new(rustls::server::Acceptor::default(), stream)
+                .await
+                .unwrap();
+        let sni = start.client_hello().server_name().map(str::to_string);
+        drop(start);
+        client.await.unwrap();
+        sni
+    }
+
+    #[tokio::test]
+    async fn outbound_sni_override() {
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+
+        let connector = certs.outbound_connector(vec![id.clone()]).unwrap();
+        assert_eq!(client_hello_sni(connector).await, None);
+
+        let connector = certs
+            .outbound_connector(vec![id])
+            .unwrap()
+            .with_server_name(sni_server_name("gateway.example.com").unwrap());
+        assert_eq!(
+            client_hello_sni(connector).await.as_deref(),
+            Some("gateway.example.com")
+        );
+
+        assert!(sni_server_name("10.0.0.1").is_err());
+        assert!(sni_server_name("not a hostname").is_err());
+    }
+
     #[test]
     #[cfg(feature = "tls-boring")]
     fn is_fips_enabled() {
@@ -158,6 +158,26 @@ pub fn generate_test_certs(
     generate_test_certs_at(id, not_before, not_before + duration_until_expiry, None)
 }
 
+/// hostname_server_config returns a server config presenting a certificate for a DNS name, signed by the test
+/// root, as a server outside the mesh would. Only the given TLS versions are accepted.
+pub fn hostname_server_config(
+    hostname: &str,
+    versions: &[&'static rustls::SupportedProtocolVersion],
+) -> ServerConfig {
+    let mut p = CertificateParams::new(vec![hostname.to_string()]).unwrap();
+    p.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ServerAuth];
+    let kp = KeyPair::from_pem(std::str::from_utf8(TEST_PKEY).unwrap()).unwrap();
+    let ca_kp = KeyPair::from_pem(std::str::from_utf8(TEST_ROOT_KEY).unwrap()).unwrap();
+    let cert = p.signed_by(&kp, &test_ca(), &ca_kp).unwrap();
+    let key = rustls::pki_types::PrivateKeyDer::Pkcs8(kp.serialize_der().into());
+    ServerConfig::builder_with_provider(crate::tls::lib::origination_provider())
+        .with_protocol_versions(versions)
+        .expect("server config must be valid")
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.der().clone()], key)
+        .unwrap()
+}
+
 fn test_ca() -> Certificate {
     let key = KeyPair::from_pem(std::str::from_utf8(TEST_ROOT_KEY).unwrap()).unwrap();
     let ca_param =
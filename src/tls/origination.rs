@@ -0,0 +1,192 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS origination for destinations outside the mesh: the client's plaintext connection is carried to the
+//! server over a TLS session we open, validated like any other TLS client would. Unlike HBONE, no certificate
+//! is presented and the server is identified by its DNS name rather than a SPIFFE identity.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use once_cell::sync::Lazy;
+use rustls::ClientConfig;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+
+use crate::config::RootCert;
+use crate::tls::control::root_to_store;
+use crate::tls::lib::{origination_provider, ORIGINATION_TLS_VERSIONS};
+use crate::tls::{sni_server_name, Error};
+
+// Client configs by CA bundle, or None for the system roots, along with the modification time of the bundle they
+// were loaded from. Loading the roots is too slow to do per connection, and reusing the configs lets sessions with
+// the same server be resumed.
+static CLIENT_CONFIGS: Lazy<Mutex<HashMap<Option<PathBuf>, CachedConfig>>> =
+    Lazy::new(Default::default);
+
+struct CachedConfig {
+    modified: Option<SystemTime>,
+    config: Arc<ClientConfig>,
+}
+
+/// OriginationError is why TLS could not be originated to a server.
+#[derive(thiserror::Error, Debug)]
+pub enum OriginationError {
+    /// The client configuration could not be built, such as when the CA bundle cannot be read.
+    #[error("{0}")]
+    Config(#[from] Error),
+    #[error("{0}")]
+    Handshake(std::io::Error),
+}
+
+/// originate opens a TLS session over `stream`, expecting the server to present a certificate for `sni`
+/// signed by the CA bundle at `ca`, or by the system roots if there is none.
+pub async fn originate(
+    stream: TcpStream,
+    ca: Option<&Path>,
+    sni: &str,
+) -> Result<TlsStream<TcpStream>, OriginationError> {
+    let server_name = sni_server_name(sni)?;
+    let config = client_config(ca).await?;
+    tokio_rustls::TlsConnector::from(config)
+        .connect(server_name, stream)
+        .await
+        .map_err(OriginationError::Handshake)
+}
+
+// client_config returns the client config for a CA bundle, loading it again once the bundle is modified, as when
+// it is rotated.
+async fn client_config(ca: Option<&Path>) -> Result<Arc<ClientConfig>, Error> {
+    let key = ca.map(Path::to_path_buf);
+    let modified = match ca {
+        Some(path) => Some(
+            tokio::fs::metadata(path)
+                .await
+                .and_then(|m| m.modified())
+                .map_err(|e| Error::InvalidRootCert(e.to_string()))?,
+        ),
+        None => None,
+    };
+    if let Some(cached) = CLIENT_CONFIGS.lock().unwrap().get(&key) {
+        if cached.modified == modified {
+            return Ok(cached.config.clone());
+        }
+    }
+    let root_cert = match &key {
+        Some(path) => RootCert::File(path.clone()),
+        None => RootCert::Default,
+    };
+    let roots = root_to_store(&root_cert).await?;
+    if roots.is_empty() {
+        return Err(Error::InvalidRootCert(format!(
+            "no certificates found in {root_cert:?}"
+        )));
+    }
+    let config = Arc::new(
+        ClientConfig::builder_with_provider(origination_provider())
+            .with_protocol_versions(ORIGINATION_TLS_VERSIONS)?
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    );
+    CLIENT_CONFIGS.lock().unwrap().insert(
+        key,
+        CachedConfig {
+            modified,
+            config: config.clone(),
+        },
+    );
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::tls::mock::hostname_server_config;
+
+    fn test_root() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/tls/root-cert.pem")
+    }
+
+    #[tokio::test]
+    async fn tls12_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(hostname_server_config(
+            "api.example.com",
+            &[&rustls::version::TLS12],
+        )));
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut tls = acceptor.accept(stream).await.unwrap();
+            tls.write_all(b"x").await.unwrap();
+            tls.shutdown().await.unwrap();
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut tls = originate(stream, Some(&test_root()), "api.example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            tls.get_ref().1.protocol_version(),
+            Some(rustls::ProtocolVersion::TLSv1_2)
+        );
+        let mut buf = Vec::new();
+        tls.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"x");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ca_bundle_reloaded_once_modified() {
+        let dir = std::env::temp_dir().join(format!("ztunnel-origination-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca = dir.join("ca.pem");
+        std::fs::copy(test_root(), &ca).unwrap();
+        let set_modified = |at: SystemTime| {
+            std::fs::File::options()
+                .write(true)
+                .open(&ca)
+                .unwrap()
+                .set_modified(at)
+                .unwrap()
+        };
+        let now = SystemTime::now();
+        set_modified(now - Duration::from_secs(60));
+
+        let first = client_config(Some(&ca)).await.unwrap();
+        assert!(Arc::ptr_eq(
+            &first,
+            &client_config(Some(&ca)).await.unwrap()
+        ));
+
+        // The bundle is rotated.
+        set_modified(now);
+        let rotated = client_config(Some(&ca)).await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &rotated));
+        assert!(Arc::ptr_eq(
+            &rotated,
+            &client_config(Some(&ca)).await.unwrap()
+        ));
+
+        // A bundle that can no longer be read is an error, rather than served from the cache.
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(client_config(Some(&ca)).await.is_err());
+    }
+}
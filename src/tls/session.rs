@@ -0,0 +1,512 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS session resumption for HBONE connections.
+//!
+//! When enabled, a client that reconnects to a peer it recently completed a full handshake with presents a
+//! session ticket, and both sides skip the certificate exchange and signatures. Since server and client configs
+//! are rebuilt for every connection, the session stores live here, for the lifetime of the process.
+//!
+//! Sessions are scoped so resumption never weakens identity checks:
+//! * Clients only resume with a peer at the same address that was verified against the same expected identities.
+//! * Servers only resume sessions they issued for the same local identity.
+//!
+//! Resumed handshakes do not verify certificates again. The peer identity of a resumed connection is the one
+//! verified by the full handshake that issued the session, which the scoping above makes one this connection
+//! would also accept. A peer certificate that has since expired or been replaced stays trusted for resumed
+//! connections until the session expires, so the session lifetime bounds how stale that verification can be.
+//!
+//! Tickets are stateful: they are opaque keys into an in-memory store, so no ticket encryption keys are needed,
+//! and sessions cannot be resumed after a restart or past the configured lifetime.
+//!
+//! # Early data
+//!
+//! With early data (0-RTT), a resumed client sends its first requests along with its first handshake message.
+//! Early data is not protected against replay: an attacker that captured it can resend it, and each copy may be
+//! accepted as a new connection. For HBONE, this means the first CONNECT requests of a connection, and any
+//! payload sent with them, may be delivered to the application more than once. Only enable it when all
+//! applications in the mesh tolerate that.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use bytes::{Buf, Bytes};
+use once_cell::sync::OnceCell;
+use rustls::client::{ClientSessionMemoryCache, Resumption, Tls12Resumption};
+use rustls::server::{NoServerSessionStorage, StoresServerSessions};
+use rustls::{ClientConfig, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::config::TlsResumption;
+use crate::identity::Identity;
+
+// The number of peer addresses whose sessions a client keeps, per set of expected identities.
+const CLIENT_SESSIONS: usize = 256;
+// The number of sets of expected identities clients keep sessions for. Beyond this, all are forgotten.
+const CLIENT_SCOPES: usize = 1024;
+// The number of sessions servers keep, across all local identities.
+const SERVER_SESSIONS: usize = 16 * 1024;
+// The most early data a server accepts on a connection. This comfortably fits the HTTP/2 preface and a few
+// CONNECT requests.
+const MAX_EARLY_DATA: u32 = 16 * 1024;
+
+static SESSIONS: OnceCell<SessionResumption> = OnceCell::new();
+
+/// set_resumption configures session resumption for the rest of the process' lifetime. It is off until set.
+pub fn set_resumption(policy: TlsResumption) {
+    if policy.enabled {
+        let _ = SESSIONS.set(SessionResumption::new(policy));
+    }
+}
+
+pub(super) fn global() -> Option<&'static SessionResumption> {
+    SESSIONS.get()
+}
+
+/// SessionResumption holds the sessions of all HBONE connections.
+pub struct SessionResumption {
+    policy: TlsResumption,
+    clients: Mutex<HashMap<Vec<Identity>, Arc<ClientSessionMemoryCache>>>,
+    servers: Arc<ServerSessions>,
+}
+
+impl SessionResumption {
+    pub fn new(policy: TlsResumption) -> Self {
+        SessionResumption {
+            policy,
+            clients: Default::default(),
+            servers: Arc::new(ServerSessions {
+                policy,
+                entries: Default::default(),
+            }),
+        }
+    }
+
+    pub(super) fn configure_client(&self, cc: &mut ClientConfig, identities: &[Identity]) {
+        let mut scope = identities.to_vec();
+        scope.sort();
+        let store = {
+            let mut clients = self.clients.lock().unwrap();
+            if clients.len() >= CLIENT_SCOPES && !clients.contains_key(&scope) {
+                clients.clear();
+            }
+            clients
+                .entry(scope)
+                .or_insert_with(|| Arc::new(ClientSessionMemoryCache::new(CLIENT_SESSIONS)))
+                .clone()
+        };
+        cc.resumption = Resumption::store(store).tls12_resumption(Tls12Resumption::Disabled);
+        cc.enable_early_data = self.policy.early_data;
+    }
+
+    pub(super) fn configure_server(&self, sc: &mut ServerConfig, identity: Option<&Identity>) {
+        sc.session_storage = Arc::new(ScopedServerSessions {
+            scope: identity.map(|id| id.to_string()).unwrap_or_default(),
+            sessions: self.servers.clone(),
+        });
+        if self.policy.early_data {
+            sc.max_early_data_size = MAX_EARLY_DATA;
+        }
+    }
+}
+
+/// disable_server stops a server from issuing tickets, which are useless when clients do not resume.
+pub(super) fn disable_server(sc: &mut ServerConfig) {
+    sc.session_storage = Arc::new(NoServerSessionStorage {});
+    sc.send_tls13_tickets = 0;
+}
+
+struct ServerSessions {
+    policy: TlsResumption,
+    entries: Mutex<HashMap<Vec<u8>, (Instant, Vec<u8>)>>,
+}
+
+impl ServerSessions {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= SERVER_SESSIONS {
+            entries.retain(|_, (created, _)| created.elapsed() < self.policy.lifetime);
+            if entries.len() >= SERVER_SESSIONS {
+                return false;
+            }
+        }
+        entries.insert(key, (Instant::now(), value));
+        true
+    }
+
+    fn get(&self, key: &[u8], remove: bool) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        let (created, value) = if remove {
+            entries.remove(key)?
+        } else {
+            entries.get(key).cloned()?
+        };
+        if created.elapsed() >= self.policy.lifetime {
+            entries.remove(key);
+            return None;
+        }
+        Some(value)
+    }
+}
+
+/// ScopedServerSessions is the view of the server sessions of a single local identity.
+struct ScopedServerSessions {
+    scope: String,
+    sessions: Arc<ServerSessions>,
+}
+
+impl ScopedServerSessions {
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        let mut scoped = Vec::with_capacity(self.scope.len() + 1 + key.len());
+        scoped.extend_from_slice(self.scope.as_bytes());
+        scoped.push(0);
+        scoped.extend_from_slice(key);
+        scoped
+    }
+}
+
+impl fmt::Debug for ScopedServerSessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopedServerSessions")
+            .field("scope", &self.scope)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StoresServerSessions for ScopedServerSessions {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.sessions.put(self.key(&key), value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.sessions.get(&self.key(key), false)
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.sessions.get(&self.key(key), true)
+    }
+
+    fn can_cache(&self) -> bool {
+        true
+    }
+}
+
+/// WithEarlyData is an accepted connection that first yields the early data the client sent, if any.
+/// tokio-rustls only returns data received after the handshake, so early data must be read out separately.
+pub struct WithEarlyData<S> {
+    early_data: Bytes,
+    inner: S,
+}
+
+impl<IO> WithEarlyData<tokio_rustls::server::TlsStream<IO>> {
+    pub fn new(mut inner: tokio_rustls::server::TlsStream<IO>) -> Self {
+        let mut early_data = Vec::new();
+        if let Some(mut ed) = inner.get_mut().1.early_data() {
+            // Reading buffered early data cannot fail.
+            let _ = ed.read_to_end(&mut early_data);
+        }
+        WithEarlyData {
+            early_data: early_data.into(),
+            inner,
+        }
+    }
+}
+
+impl<S> WithEarlyData<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WithEarlyData<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let me = self.get_mut();
+        if me.early_data.has_remaining() {
+            let n = me.early_data.len().min(buf.remaining());
+            buf.put_slice(&me.early_data[..n]);
+            me.early_data.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut me.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WithEarlyData<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// OnHandshake calls `f` once with the state of a client connection, as soon as its handshake completes. With early
+/// data, the connection is used before the server has answered, so this is only once the first data from the server
+/// is read.
+pub struct OnHandshake<S, F> {
+    inner: S,
+    f: Option<F>,
+}
+
+impl<IO, F: FnOnce(&rustls::CommonState)> OnHandshake<tokio_rustls::client::TlsStream<IO>, F> {
+    pub fn new(inner: tokio_rustls::client::TlsStream<IO>, f: F) -> Self {
+        let mut s = OnHandshake { inner, f: Some(f) };
+        s.check();
+        s
+    }
+
+    fn check(&mut self) {
+        let conn = self.inner.get_ref().1;
+        if is_resumed(conn).is_some() {
+            if let Some(f) = self.f.take() {
+                f(conn);
+            }
+        }
+    }
+}
+
+impl<S, F> OnHandshake<S, F> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<IO, F> AsyncRead for OnHandshake<tokio_rustls::client::TlsStream<IO>, F>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(&rustls::CommonState) + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let me = self.get_mut();
+        let res = Pin::new(&mut me.inner).poll_read(cx, buf);
+        if me.f.is_some() && res.is_ready() {
+            me.check();
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin, F: Unpin> AsyncWrite for OnHandshake<S, F> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// is_resumed reports whether a connection resumed an earlier session, or None if its handshake is still in
+/// progress, as when a client sends early data.
+pub fn is_resumed(conn: &rustls::CommonState) -> Option<bool> {
+    conn.handshake_kind()
+        .map(|kind| kind == rustls::HandshakeKind::Resumed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::tls::mock::generate_test_certs;
+    use crate::tls::WorkloadCertificate;
+
+    // handshake makes a connection with configs built from scratch, as the proxy does, and returns whether the
+    // client and server each saw it as resumed.
+    async fn handshake(
+        certs: &WorkloadCertificate,
+        expected: &[Identity],
+        sessions: Option<&SessionResumption>,
+    ) -> (bool, bool) {
+        let server_config = certs.server_config_with(sessions).unwrap();
+        let connector = certs
+            .outbound_connector_with(expected.to_vec(), sessions)
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut tls = tokio_rustls::TlsAcceptor::from(Arc::new(server_config))
+                .accept(stream)
+                .await
+                .unwrap();
+            let resumed = is_resumed(tls.get_ref().1).unwrap();
+            tls.write_all(b"x").await.unwrap();
+            tls.flush().await.unwrap();
+            resumed
+        });
+        let mut tls = connector
+            .connect(TcpStream::connect(addr).await.unwrap())
+            .await
+            .unwrap();
+        // The server sends its tickets ahead of this, so they are processed by the time it is read.
+        tls.read_exact(&mut [0u8; 1]).await.unwrap();
+        let resumed = is_resumed(tls.get_ref().1).unwrap();
+        (resumed, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn resumption() {
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let sessions = SessionResumption::new(TlsResumption {
+            enabled: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            handshake(&certs, &[id.clone()], Some(&sessions)).await,
+            (false, false)
+        );
+        assert_eq!(
+            handshake(&certs, &[id.clone()], Some(&sessions)).await,
+            (true, true)
+        );
+
+        // A session is not resumed when a different identity is expected.
+        let other = Identity::Spiffe {
+            trust_domain: "cluster.local".into(),
+            namespace: "other".into(),
+            service_account: "other".into(),
+        };
+        let either = vec![other, id.clone()];
+        assert_eq!(
+            handshake(&certs, &either, Some(&sessions)).await,
+            (false, false)
+        );
+        assert_eq!(
+            handshake(&certs, &either, Some(&sessions)).await,
+            (true, true)
+        );
+    }
+
+    #[tokio::test]
+    async fn no_resumption_when_disabled() {
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        assert_eq!(handshake(&certs, &[id.clone()], None).await, (false, false));
+        assert_eq!(handshake(&certs, &[id.clone()], None).await, (false, false));
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_are_not_resumed() {
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let sessions = SessionResumption::new(TlsResumption {
+            enabled: true,
+            lifetime: Duration::from_millis(50),
+            early_data: false,
+        });
+        assert_eq!(
+            handshake(&certs, &[id.clone()], Some(&sessions)).await,
+            (false, false)
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            handshake(&certs, &[id.clone()], Some(&sessions)).await,
+            (false, false)
+        );
+    }
+
+    #[tokio::test]
+    async fn resumption_with_early_data_is_reported() {
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let sessions = SessionResumption::new(TlsResumption {
+            enabled: true,
+            early_data: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            handshake(&certs, &[id.clone()], Some(&sessions)).await,
+            (false, false)
+        );
+
+        let server_config = certs.server_config_with(Some(&sessions)).unwrap();
+        let connector = certs
+            .outbound_connector_with(vec![id.clone()], Some(&sessions))
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut tls = tokio_rustls::TlsAcceptor::from(Arc::new(server_config))
+                .accept(stream)
+                .await
+                .unwrap();
+            tls.write_all(b"x").await.unwrap();
+            tls.flush().await.unwrap();
+        });
+        let tls = connector
+            .connect(TcpStream::connect(addr).await.unwrap())
+            .await
+            .unwrap();
+        let reported = Arc::new(Mutex::new(None));
+        let mut tls = OnHandshake::new(tls, {
+            let reported = reported.clone();
+            move |conn: &rustls::CommonState| *reported.lock().unwrap() = is_resumed(conn)
+        });
+        tls.read_exact(&mut [0u8; 1]).await.unwrap();
+        server.await.unwrap();
+        assert_eq!(*reported.lock().unwrap(), Some(true));
+    }
+}
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::identity::Identity;
+use crate::identity::{self, Identity};
 
 use crate::tls::lib::provider;
 use crate::tls::{ServerCertProvider, TlsError};
@@ -67,11 +67,8 @@ impl TrustDomainVerifier {
         let (_, c) = X509Certificate::from_der(client_cert).map_err(|_e| {
             rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding)
         })?;
-        let ids = tls::certificate::identities(c).map_err(|_e| {
-            rustls::Error::InvalidCertificate(
-                rustls::CertificateError::ApplicationVerificationFailure,
-            )
-        })?;
+        let ids = tls::certificate::identities(c).map_err(san_error)?;
+        let want_trust_domain = &tls::certificate::expected_trust_domain(want_trust_domain);
         trace!(
             "verifying client identities {ids:?} against trust domain {:?}",
             want_trust_domain
@@ -92,6 +89,13 @@ impl TrustDomainVerifier {
     }
 }
 
+// san_error reports a certificate whose SANs do not hold a usable identity, keeping the reason for the logs.
+fn san_error(err: tls::Error) -> rustls::Error {
+    rustls::Error::InvalidCertificate(rustls::CertificateError::Other(rustls::OtherError(
+        Arc::new(err),
+    )))
+}
+
 // Implement our custom ClientCertVerifier logic. We only want to add an extra check, but
 // need a decent amount of boilerplate to do so.
 impl ClientCertVerifier for TrustDomainVerifier {
@@ -155,24 +159,50 @@ where
     }
 }
 
+/// sni_server_name validates a hostname to present as the TLS SNI. SNI cannot carry an IP address, so only DNS
+/// names are accepted.
+pub fn sni_server_name(sni: &str) -> Result<ServerName<'static>, tls::Error> {
+    match ServerName::try_from(sni) {
+        Ok(name @ ServerName::DnsName(_)) => Ok(name.to_owned()),
+        _ => Err(tls::Error::InvalidSni(sni.to_string())),
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct OutboundConnector {
     pub(super) client_config: Arc<ClientConfig>,
+    pub(super) server_name: Option<ServerName<'static>>,
 }
 
 impl OutboundConnector {
+    /// with_server_name presents `server_name` as the SNI. Verification is unchanged: the peer is still checked
+    /// against the expected identities, not the server name.
+    pub fn with_server_name(mut self, server_name: ServerName<'static>) -> Self {
+        let mut cc = (*self.client_config).clone();
+        cc.enable_sni = true;
+        self.client_config = Arc::new(cc);
+        self.server_name = Some(server_name);
+        self
+    }
+
     pub async fn connect(
         self,
         stream: TcpStream,
     ) -> Result<client::TlsStream<TcpStream>, io::Error> {
-        let dest = ServerName::IpAddress(
-            stream
-                .peer_addr()
-                .expect("peer_addr must be set")
-                .ip()
-                .into(),
-        );
-        let c = tokio_rustls::TlsConnector::from(self.client_config);
+        let dest = match self.server_name {
+            Some(name) => name,
+            None => ServerName::IpAddress(
+                stream
+                    .peer_addr()
+                    .expect("peer_addr must be set")
+                    .ip()
+                    .into(),
+            ),
+        };
+        // With early data, this returns as soon as the ClientHello is sent on resumed connections, so the first
+        // writes go out as 0-RTT data.
+        let early_data = self.client_config.enable_early_data;
+        let c = tokio_rustls::TlsConnector::from(self.client_config).early_data(early_data);
         c.connect(dest, stream).await
     }
 }
@@ -189,21 +219,27 @@ impl IdentityVerifier {
         let (_, c) = X509Certificate::from_der(server_cert).map_err(|_e| {
             rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding)
         })?;
-        let id = tls::certificate::identities(c).map_err(|_e| {
-            rustls::Error::InvalidCertificate(
-                rustls::CertificateError::ApplicationVerificationFailure,
-            )
-        })?;
+        let id = tls::certificate::identities(c).map_err(san_error)?;
         trace!(
-            "verifying server identities {id:?} against {:?}",
-            self.identity
+            "verifying server identities {} against {}",
+            identity::log_display_all(&id),
+            identity::log_display_all(&self.identity)
         );
+        let expected: Vec<_> = self
+            .identity
+            .iter()
+            .map(tls::certificate::expected_identity)
+            .collect();
         for ident in id.iter() {
-            if let Some(_i) = self.identity.iter().find(|id| id == &ident) {
+            if let Some(_i) = expected.iter().find(|id| id == &ident) {
                 return Ok(());
             }
         }
-        debug!("identity mismatch {id:?} != {:?}", self.identity);
+        debug!(
+            "identity mismatch {} != {}",
+            identity::log_display_all(&id),
+            identity::log_display_all(&self.identity)
+        );
         Err(rustls::Error::InvalidCertificate(
             rustls::CertificateError::ApplicationVerificationFailure,
         ))
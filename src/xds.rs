@@ -144,7 +144,8 @@ impl ProxyStateUpdateMutator {
         state.workloads.insert(workload.clone(), track);
         // Unhealthy workloads are always inserted, as we may get or receive traffic to them.
         // But we shouldn't include them in load balancing we do to Services.
-        if workload.status == HealthStatus::Healthy {
+        // Terminating workloads are included, but only selected as a last resort.
+        if workload.status != HealthStatus::Unhealthy {
             insert_service_endpoints(&workload, &services, &mut state.services)?;
         }
 
@@ -453,6 +454,12 @@ impl LocalClient {
             "load local config: {}",
             serde_yaml::to_string(&r).unwrap_or_default()
         );
+        // Validate up front, so an invalid config is rejected without clearing the current state.
+        for wl in &r.workloads {
+            if let Some(sni) = &wl.workload.tls_sni_override {
+                tls::sni_server_name(sni)?;
+            }
+        }
         let mut state = self.state.write().unwrap();
         // Clear the state
         state.workloads = Default::default();
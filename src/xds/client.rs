@@ -14,6 +14,7 @@
 
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{fmt, mem};
@@ -212,6 +213,7 @@ pub struct Config {
     handlers: HashMap<Strng, Box<dyn RawHandler>>,
     initial_requests: Vec<DeltaDiscoveryRequest>,
     on_demand: bool,
+    max_backoff: Duration,
 }
 
 pub struct State {
@@ -257,6 +259,7 @@ impl Config {
             handlers: HashMap::new(),
             initial_requests: Vec::new(),
             on_demand: config.xds_on_demand,
+            max_backoff: config.xds_max_reconnect_backoff,
             proxy_metadata: config.proxy_metadata.clone(),
         }
     }
@@ -409,6 +412,7 @@ pub struct AdsClient {
 
     pub(crate) metrics: Metrics,
     block_ready: Option<tokio::sync::watch::Sender<()>>,
+    connection_status: ConnectionStatus,
 
     connection_id: u32,
     types_to_expect: HashSet<String>,
@@ -434,6 +438,21 @@ pub struct Demander {
     demand: mpsc::Sender<(oneshot::Sender<()>, ResourceKey)>,
 }
 
+/// ConnectionStatus reports whether the client currently has a stream to the XDS server. While it does not,
+/// updates are missed, so the state received so far may be stale.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStatus(Arc<AtomicBool>);
+
+impl ConnectionStatus {
+    pub fn is_connected(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set_connected(&self, connected: bool) {
+        self.0.store(connected, Ordering::Relaxed)
+    }
+}
+
 #[derive(Debug)]
 enum XdsSignal {
     None,
@@ -465,7 +484,38 @@ impl Demander {
 }
 
 const INITIAL_BACKOFF: Duration = Duration::from_millis(10);
-const MAX_BACKOFF: Duration = Duration::from_secs(15);
+
+// Backoff is the delay before reconnecting to the XDS server. It doubles with each consecutive failure, up to a
+// maximum. The delay actually waited is jittered, so a fleet of clients that lost the server at the same time do not
+// all reconnect at the same time.
+#[derive(Clone, Copy, Debug)]
+struct Backoff {
+    current: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn new(max: Duration) -> Self {
+        Backoff {
+            current: std::cmp::min(INITIAL_BACKOFF, max),
+            max,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Backoff::new(self.max);
+    }
+
+    fn increase(&mut self) {
+        self.current = std::cmp::min(self.max, self.current * 2);
+    }
+
+    // delay is the current backoff with jitter: a uniformly random duration between half of it and all of it.
+    fn delay(&self) -> Duration {
+        let half = self.current / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+}
 
 impl AdsClient {
     fn is_initial_request_on_demand(r: &DeltaDiscoveryRequest) -> bool {
@@ -491,6 +541,7 @@ impl AdsClient {
             state,
             metrics,
             block_ready: Some(block_ready),
+            connection_status: ConnectionStatus::default(),
             connection_id: 0,
             types_to_expect,
         }
@@ -507,23 +558,32 @@ impl AdsClient {
         }
     }
 
-    async fn run_loop(&mut self, backoff: Duration) -> Duration {
-        match self.run_internal().await {
+    /// connection_status returns a handle reporting whether the client is connected to the XDS server.
+    pub fn connection_status(&self) -> ConnectionStatus {
+        self.connection_status.clone()
+    }
+
+    // run_loop runs a single connection to the XDS server, and waits out the backoff once it ends. The state
+    // received so far is kept, and keeps being served, until the next connection reconciles it.
+    async fn run_loop(&mut self, backoff: &mut Backoff) {
+        let res = self.run_internal().await;
+        self.connection_status.set_connected(false);
+        match res {
             Err(e @ Error::Connection(_)) => {
                 // For connection errors, we add backoff
-                let backoff = std::cmp::min(MAX_BACKOFF, backoff * 2);
+                backoff.increase();
+                let delay = backoff.delay();
                 warn!(
                     "XDS client connection error: {}, retrying in {:?}",
-                    e, backoff
+                    e, delay
                 );
                 self.metrics
                     .increment(&ConnectionTerminationReason::ConnectionError);
-                tokio::time::sleep(backoff).await;
-                backoff
+                tokio::time::sleep(delay).await;
             }
             Err(ref e @ Error::GrpcStatus(ref status)) => {
                 let err_detail = e.to_string();
-                let backoff = if status.code() == tonic::Code::Unknown
+                if status.code() == tonic::Code::Unknown
                     || status.code() == tonic::Code::Cancelled
                     || status.code() == tonic::Code::DeadlineExceeded
                     || (status.code() == tonic::Code::Unavailable
@@ -531,24 +591,23 @@ impl AdsClient {
                     || (status.code() == tonic::Code::Unavailable
                         && status.message().contains("received prior goaway"))
                 {
+                    backoff.reset();
+                    let delay = backoff.delay();
                     debug!(
                         "XDS client terminated: {}, retrying in {:?}",
-                        err_detail, backoff
+                        err_detail, delay
                     );
                     self.metrics
                         .increment(&ConnectionTerminationReason::Reconnect);
-                    INITIAL_BACKOFF
+                    tokio::time::sleep(delay).await;
                 } else {
-                    warn!(
-                        "XDS client error: {}, retrying in {:?}",
-                        err_detail, backoff
-                    );
-                    self.metrics.increment(&ConnectionTerminationReason::Error);
                     // For gRPC errors, we add backoff
-                    std::cmp::min(MAX_BACKOFF, backoff * 2)
-                };
-                tokio::time::sleep(backoff).await;
-                backoff
+                    backoff.increase();
+                    let delay = backoff.delay();
+                    warn!("XDS client error: {}, retrying in {:?}", err_detail, delay);
+                    self.metrics.increment(&ConnectionTerminationReason::Error);
+                    tokio::time::sleep(delay).await;
+                }
             }
             Err(e) => {
                 // For other errors, we connect immediately
@@ -556,26 +615,27 @@ impl AdsClient {
                 // But we want to reconnect from MaxConnectionAge immediately.
                 warn!("XDS client error: {}, retrying", e);
                 self.metrics.increment(&ConnectionTerminationReason::Error);
-                // Reset backoff
-                INITIAL_BACKOFF
+                backoff.reset();
             }
             Ok(_) => {
                 self.metrics
                     .increment(&ConnectionTerminationReason::Complete);
                 warn!("XDS client complete");
-                // Reset backoff
-                INITIAL_BACKOFF
+                backoff.reset();
             }
         }
     }
 
     pub async fn run(mut self) -> Result<(), Error> {
-        let mut backoff = INITIAL_BACKOFF;
+        let mut backoff = Backoff::new(self.config.max_backoff);
         loop {
             self.connection_id += 1;
             let id = self.connection_id;
-            backoff = self
-                .run_loop(backoff)
+            if id > 1 {
+                self.metrics.reconnect_attempts.inc();
+                debug!(attempt = id - 1, "reconnecting to XDS server");
+            }
+            self.run_loop(&mut backoff)
                 .instrument(info_span!("xds", id))
                 .await;
         }
@@ -633,6 +693,7 @@ impl AdsClient {
         debug!("connected established");
 
         info!("Stream established");
+        self.connection_status.set_connected(true);
         loop {
             tokio::select! {
                 _demand_event = self.state.demand.recv() => {
@@ -1187,6 +1248,7 @@ mod tests {
 
         // Setup fake xds server
         let (mut conn_receiver, client, state, _) = AdsServer::spawn(false).await;
+        let reconnects = client.metrics.reconnect_attempts.clone();
 
         tokio::spawn(async move {
             if let Err(e) = client.run().await {
@@ -1209,8 +1271,19 @@ mod tests {
         sleep(Duration::from_millis(50)).await;
         verify_address(IpAddr::V4(ip), Some(addresses[0].clone()), &state).await;
 
-        // original connection should close and client re-connect
-        let conn = conn_receiver.recv().await.unwrap();
+        // original connection should close and client re-connect, while the state we had is still served
+        let mut conn = conn_receiver.recv().await.unwrap();
+        verify_address(IpAddr::V4(ip), Some(addresses[0].clone()), &state).await;
+        assert_eq!(reconnects.get(), 1);
+
+        // the client resumes from what it already knows, so the server can send just the difference
+        let req = loop {
+            let req = conn.rx.recv().await.unwrap();
+            if req.type_url == ADDRESS_TYPE {
+                break req;
+            }
+        };
+        assert!(req.initial_resource_versions.contains_key("1.1.1.1"));
         conn.tx
             .send(removed_resource_response)
             .await
@@ -1219,6 +1292,35 @@ mod tests {
         verify_address(IpAddr::V4(ip), None, &state).await;
     }
 
+    #[test]
+    fn test_backoff_schedule() {
+        let max = Duration::from_millis(100);
+        let mut backoff = Backoff::new(max);
+        assert_eq!(backoff.current, INITIAL_BACKOFF);
+
+        let schedule: Vec<_> = (0..6)
+            .map(|_| {
+                backoff.increase();
+                backoff.current.as_millis()
+            })
+            .collect();
+        assert_eq!(schedule, vec![20, 40, 80, 100, 100, 100]);
+
+        for _ in 0..100 {
+            let delay = backoff.delay();
+            assert!(delay >= max / 2 && delay <= max, "{delay:?}");
+        }
+
+        backoff.reset();
+        assert_eq!(backoff.current, INITIAL_BACKOFF);
+
+        // The maximum applies to the initial backoff as well
+        assert_eq!(
+            Backoff::new(Duration::from_millis(1)).current,
+            Duration::from_millis(1)
+        );
+    }
+
     #[test]
     fn test_json_to_value() {
         use prost_types::value::Kind::*;
@@ -21,6 +21,7 @@ use crate::metrics::Recorder;
 
 pub struct Metrics {
     pub connection_terminations: Family<ConnectionTermination, Counter>,
+    pub reconnect_attempts: Counter,
 }
 
 #[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
@@ -45,8 +46,16 @@ impl Metrics {
             connection_terminations.clone(),
         );
 
+        let reconnect_attempts = Counter::default();
+        registry.register(
+            "xds_reconnect_attempts",
+            "The total number of attempts to reconnect to the xds server (unstable)",
+            reconnect_attempts.clone(),
+        );
+
         Self {
             connection_terminations,
+            reconnect_attempts,
         }
     }
 }
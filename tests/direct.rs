@@ -18,6 +18,7 @@ use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
+use base64::Engine;
 use bytes::Bytes;
 use http_body_util::Empty;
 use hyper::{Method, Request};
@@ -82,6 +83,30 @@ async fn test_conflicting_bind_error_inbound() {
     test_bind_conflict(|c| &mut c.inbound_addr).await;
 }
 
+#[tokio::test]
+async fn test_conflicting_bind_error_inbound_extra() {
+    test_bind_conflict(|c| {
+        c.inbound_extra_addrs.push("127.0.0.1:0".parse().unwrap());
+        c.inbound_extra_addrs.last_mut().unwrap()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_inbound_extra_addresses() {
+    helpers::initialize_telemetry();
+    let mut cfg = test_config();
+    cfg.inbound_extra_addrs = vec!["127.0.0.1:0".parse().unwrap()];
+    let app = ztunnel::app::build(Arc::new(cfg)).await.unwrap();
+
+    let addresses = app.proxy_addresses.clone().unwrap();
+    assert_eq!(addresses.inbound_extra.len(), 1);
+    assert_ne!(addresses.inbound_extra[0], addresses.inbound);
+    TcpStream::connect(addresses.inbound_extra[0])
+        .await
+        .expect("extra inbound address is listening");
+}
+
 #[tokio::test]
 async fn test_conflicting_bind_error_inbound_plaintext() {
     test_bind_conflict(|c| &mut c.inbound_plaintext_addr).await;
@@ -258,11 +283,112 @@ async fn test_tcp_request() {
     run_request_test(TEST_WORKLOAD_TCP, "").await;
 }
 
+#[tokio::test]
+async fn test_forward_proxy() {
+    initialize_telemetry();
+    let echo = tcp::TestServer::new(tcp::Mode::ReadWrite, 0).await;
+    let echo_addr = echo.address();
+    tokio::spawn(echo.run());
+    let (proxy_addr, targets) = run_forward_proxy("user:secret").await;
+    let cfg = config::Config {
+        forward_proxy: Some(config::ForwardProxy {
+            authority: proxy_addr.to_string(),
+            basic_auth: Some("user:secret".to_string()),
+        }),
+        ..test_config_with_port(echo_addr.port())
+    };
+    testapp::with_app(cfg, |app| {
+        let targets = targets.clone();
+        async move {
+            // 127.0.0.1 is not a mesh workload, so only it is reached through the proxy.
+            for target in [TEST_WORKLOAD_TCP, TEST_WORKLOAD_HBONE, "127.0.0.1"] {
+                let dst = helpers::with_ip(echo_addr, target.parse().unwrap());
+                let mut stream = app
+                    .socks5_connect(
+                        DestinationAddr::Ip(dst),
+                        TEST_WORKLOAD_SOURCE.parse().unwrap(),
+                    )
+                    .await;
+                read_write_stream(&mut stream).await;
+            }
+            let targets = targets.lock().unwrap().clone();
+            assert_eq!(
+                targets,
+                vec![helpers::with_ip(echo_addr, "127.0.0.1".parse().unwrap()).to_string()]
+            );
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_hbone_udp_request() {
+    initialize_telemetry();
+    let echo = tokio::net::UdpSocket::bind("0.0.0.0:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65_535];
+        loop {
+            let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..n], from).await.unwrap();
+        }
+    });
+    let cfg = config::Config {
+        enable_hbone_udp: true,
+        ..test_config_with_port(echo_addr.port())
+    };
+    testapp::with_app(cfg, |app| async move {
+        let dst = helpers::with_ip(echo_addr, TEST_WORKLOAD_HBONE.parse().unwrap());
+        let (_control, udp) = app
+            .socks5_udp_associate(TEST_WORKLOAD_SOURCE.parse().unwrap())
+            .await;
+        let datagram = testapp::socks5_udp_datagram(dst, b"hello world");
+        udp.send(&datagram).await.unwrap();
+        let mut buf = vec![0u8; 65_535];
+        let n = timeout(Duration::from_secs(5), udp.recv(&mut buf))
+            .await
+            .expect("timed out waiting for reply")
+            .unwrap();
+        // The reply is framed with the same header, addressed from the destination.
+        assert_eq!(&buf[..n], &datagram[..]);
+    })
+    .await;
+}
+
 #[tokio::test]
 async fn test_vip_request() {
     run_request_test(&format!("{TEST_VIP}:80"), "").await;
 }
 
+fn setup_phase_assertions(metrics: ParsedMetrics) {
+    let metric = "istio_connection_setup_phase_duration_seconds";
+    for phase in [
+        "destination_lookup",
+        "tcp_connect",
+        "tls_handshake",
+        "hbone_connect",
+    ] {
+        let labels = HashMap::from([("phase".to_string(), phase.to_string())]);
+        let m = metrics.query(metric, &labels);
+        assert!(
+            m.is_some_and(|m| !m.is_empty()),
+            "expected metric {metric} for phase {phase}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_hbone_request_setup_phases() {
+    run_requests_test(
+        TEST_WORKLOAD_HBONE,
+        "",
+        1,
+        Some(setup_phase_assertions),
+        false,
+    )
+    .await;
+}
+
 fn on_demand_dns_assertions(metrics: ParsedMetrics) {
     {
         let metric = &("istio_on_demand_dns_total");
@@ -470,6 +596,52 @@ async fn test_tcp_connections_metrics() {
     .await;
 }
 
+#[tokio::test]
+async fn test_hbone_reserved_connection_ports() {
+    let echo = tcp::TestServer::new(tcp::Mode::ReadWrite, 0).await;
+    let echo_addr = echo.address();
+    tokio::spawn(echo.run());
+    let health = tcp::TestServer::new(tcp::Mode::ReadWrite, 0).await;
+    let health_addr = health.address();
+    tokio::spawn(health.run());
+    let cfg = config::Config {
+        // One HBONE connection uses up the budget, counted once outbound and once inbound.
+        max_total_connections: Some(2),
+        reserved_connections: 2,
+        reserved_connection_ports: vec![health_addr.port()],
+        ..test_config()
+    };
+    testapp::with_app(cfg, |app| async move {
+        let dst = helpers::with_ip(echo_addr, TEST_WORKLOAD_HBONE.parse().unwrap());
+        let mut stream = app
+            .socks5_connect(
+                DestinationAddr::Ip(dst),
+                TEST_WORKLOAD_SOURCE.parse().unwrap(),
+            )
+            .await;
+        read_write_stream(&mut stream).await;
+
+        // Another source opens its own tunnel, admitted to the reserve for the port of the stream it carries.
+        let dst = helpers::with_ip(health_addr, TEST_WORKLOAD_HBONE.parse().unwrap());
+        let mut health_stream = app
+            .socks5_connect(DestinationAddr::Ip(dst), TEST_WORKLOAD_TCP.parse().unwrap())
+            .await;
+        read_write_stream(&mut health_stream).await;
+
+        let metrics = app.metrics().await.unwrap();
+        assert_eq!(
+            metrics.query_sum(
+                "istio_connection_budget_reserved_admitted_total",
+                &Default::default()
+            ),
+            2,
+            "metrics: {}",
+            metrics.dump()
+        );
+    })
+    .await;
+}
+
 #[tokio::test]
 async fn test_tcp_bytes_metrics() {
     let echo = tcp::TestServer::new(tcp::Mode::ReadWrite, 0).await;
@@ -514,6 +686,59 @@ async fn test_tcp_bytes_metrics() {
     .await;
 }
 
+#[tokio::test]
+async fn test_config_reload_keeps_connections() {
+    let echo = tcp::TestServer::new(tcp::Mode::ReadWrite, 0).await;
+    let echo_addr = echo.address();
+    tokio::spawn(echo.run());
+    testapp::with_app(test_config(), |app| async move {
+        let dst = helpers::with_ip(echo_addr, TEST_WORKLOAD_TCP.parse().unwrap());
+        let mut stream = app
+            .socks5_connect(
+                DestinationAddr::Ip(dst),
+                TEST_WORKLOAD_SOURCE.parse().unwrap(),
+            )
+            .await;
+        read_write_stream(&mut stream).await;
+
+        // Reload the configuration mid-connection
+        let current = app.config_reloader.current();
+        let reloaded = app
+            .config_reloader
+            .update(config::Config {
+                connection_timeout: Duration::from_secs(1),
+                ..(*current).clone()
+            })
+            .expect("reload should succeed");
+        assert_eq!(reloaded.connection_timeout, Duration::from_secs(1));
+
+        // The existing connection is unaffected
+        read_write_stream(&mut stream).await;
+
+        // New connections work with the new configuration
+        let mut new_stream = app
+            .socks5_connect(
+                DestinationAddr::Ip(dst),
+                TEST_WORKLOAD_SOURCE.parse().unwrap(),
+            )
+            .await;
+        read_write_stream(&mut new_stream).await;
+        read_write_stream(&mut stream).await;
+
+        // Changing listeners is not allowed, and does not impact the running configuration
+        let res = app.config_reloader.update(config::Config {
+            admin_addr: config::Address::SocketAddr("127.0.0.1:1".parse().unwrap()),
+            ..(*current).clone()
+        });
+        assert!(res.is_err());
+        assert_eq!(
+            app.config_reloader.current().connection_timeout,
+            Duration::from_secs(1)
+        );
+    })
+    .await;
+}
+
 async fn read_write_stream(stream: &mut TcpStream) -> usize {
     const BODY: &[u8] = b"hello world";
     stream.write_all(BODY).await.unwrap();
@@ -523,6 +748,53 @@ async fn read_write_stream(stream: &mut TcpStream) -> usize {
     BODY.len()
 }
 
+/// run_forward_proxy runs a minimal HTTP CONNECT proxy requiring the given Basic auth credentials. It returns the
+/// proxy's address, and the targets of the CONNECT requests it accepted.
+async fn run_forward_proxy(credentials: &str) -> (SocketAddr, Arc<std::sync::Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let targets = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let authorization = format!(
+        "Proxy-Authorization: Basic {}\r\n",
+        base64::engine::general_purpose::STANDARD.encode(credentials)
+    );
+    let accepted = targets.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let (accepted, authorization) = (accepted.clone(), authorization.clone());
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(client.read_u8().await.unwrap());
+                }
+                let head = String::from_utf8(head).unwrap();
+                let target = head
+                    .strip_prefix("CONNECT ")
+                    .and_then(|rest| rest.split_once(' '))
+                    .unwrap()
+                    .0
+                    .to_string();
+                if !head.contains(&authorization) {
+                    client
+                        .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                        .await
+                        .unwrap();
+                    return;
+                }
+                let mut upstream = TcpStream::connect(&target).await.unwrap();
+                accepted.lock().unwrap().push(target);
+                client
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await
+                    .unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+            });
+        }
+    });
+    (addr, targets)
+}
+
 /// admin_shutdown triggers a shutdown - from the admin server
 async fn admin_shutdown(addr: SocketAddr) {
     let req = Request::builder()
@@ -16,7 +16,7 @@
 mod namespaced {
     use bytes::Bytes;
     use futures::future::poll_fn;
-    use http_body_util::Empty;
+    use http_body_util::{BodyExt, Empty};
     use std::collections::HashMap;
 
     use std::net::{IpAddr, SocketAddr};
@@ -183,6 +183,20 @@ mod namespaced {
             ),
         ]);
         telemetry::testing::assert_contains(want);
+        let want = HashMap::from([
+            ("scope", "access_log"),
+            ("schema_version", "1"),
+            ("direction", "outbound"),
+            ("protocol", "tcp"),
+            ("dst.hbone_addr", &hbone_addr),
+            ("dst.addr", &dst_addr),
+            ("dst.workload", "waypoint"),
+            ("dst.workload_uid", "cluster1//v1/Pod/default/waypoint"),
+            ("bytes_sent", &sent),
+            ("bytes_recv", &recv),
+            ("message", "connection closed"),
+        ]);
+        telemetry::testing::assert_contains(want);
         Ok(())
     }
 
@@ -671,7 +685,12 @@ mod namespaced {
                 });
 
                 let response = request_sender.send_request(request).await.unwrap();
-                assert_eq!(response.status(), hyper::StatusCode::UNAUTHORIZED);
+                assert_eq!(response.status(), hyper::StatusCode::FORBIDDEN);
+                let body = response.into_body().collect().await?.to_bytes();
+                assert_eq!(
+                    serde_json::from_slice::<serde_json::Value>(&body)?,
+                    serde_json::json!({"error": "denied by authorization policy"})
+                );
                 Ok(())
             })?
             .join()